@@ -4,20 +4,39 @@
 @group(0) @binding(3) var<storage, read_write> shading_table: array<ShadingElement>;
 @group(0) @binding(4) var<storage, read> brickmap_unpack: BrickmapUnpack;
 @group(0) @binding(5) var<storage, read> brickgrid_unpack: BrickgridUnpack;
+@group(0) @binding(6) var detail_table: texture_storage_2d<r32uint, write>;
 
 struct ShadingElement {
-    albedo: u32,
+    // Index into the material table
+    material: u32,
 }
 
 struct Brickmap {
     bitmask: array<u32, 16>,
+    occupancy: array<u32, 2>, // 4x4x4 coarse mask, one bit per 2x2x2 voxels
     shading_table_offset: u32,
     lod_color: u32,
+    // World state frame the brickmap was loaded on, 0 if it shouldn't fade in
+    loaded_frame: u32,
+}
+
+// 2 bits per voxel sub-voxel shape, see util::surface_detail
+struct BrickmapDetail {
+    shapes: array<u32, 32>,
 }
 
 struct WorldState {
     brickgrid_dims: vec3<u32>,
-    _pad: u32,
+    // Counts up every frame, skipping 0
+    frame: u32,
+    // The brick at the brickgrid's minimum corner. Bricks wrap around the grid as it
+    // scrolls, see BrickmapManager::get_grid_idx
+    grid_origin: vec3<i32>,
+    // Random seed for the frame, from the app's `RandomService`
+    seed: u32,
+    // Where the volume's origin sits in the world, in bricks
+    origin: vec3<f32>,
+    // The volumes placed over the terrain come after this, but uploads don't need them
 };
 
 struct BrickmapUnpack {
@@ -31,21 +50,35 @@ struct BrickmapUnpack {
 struct BrickmapUnpackElement {
     cache_idx: u32,
     brickmap: Brickmap,
+    detail: BrickmapDetail,
     shading_element_count: u32,
     shading_elements: array<ShadingElement, 512>, // Always have space for a full map.
 }
 
+// Contiguous brickgrid entries are coalesced into runs. The first run_count * 2 words of
+// data are runs of {first grid index, offset of first value}, followed by the values.
 struct BrickgridUnpack {
     max_count: u32,
-    count: u32,
-    _pad1: u32,
-    _pad2: u32,
-    elements: array<BrickgridUnpackElement>,
+    run_count: u32,
+    value_count: u32,
+    _pad: u32,
+    data: array<u32>,
 }
 
-struct BrickgridUnpackElement {
-    grid_idx: u32,
-    grid_val: u32,
+// Finds the brickgrid index a value belongs to, by binary searching for the last run
+// starting at or before it.
+fn brickgrid_unpack_index(value_idx: u32) -> u32 {
+    var lo = 0u;
+    var hi = brickgrid_unpack.run_count - 1u;
+    while (lo < hi) {
+        let mid = (lo + hi + 1u) / 2u;
+        if (brickgrid_unpack.data[mid * 2u + 1u] <= value_idx) {
+            lo = mid;
+        } else {
+            hi = mid - 1u;
+        }
+    }
+    return brickgrid_unpack.data[lo * 2u] + value_idx - brickgrid_unpack.data[lo * 2u + 1u];
 }
 
 // Utility function. Converts a position in 3d to a 1d index.
@@ -58,15 +91,21 @@ fn compute(@builtin(global_invocation_id) global_id: vec3<u32>) {
     let unpack_idx = global_id.x;
 
     // Brickgrid unpacking
-    if (unpack_idx < brickgrid_unpack.count){
-        let element = brickgrid_unpack.elements[unpack_idx];
-        brickgrid[element.grid_idx] = element.grid_val;
+    if (unpack_idx < brickgrid_unpack.value_count){
+        let grid_idx = brickgrid_unpack_index(unpack_idx);
+        let grid_val = brickgrid_unpack.data[brickgrid_unpack.run_count * 2u + unpack_idx];
+        brickgrid[grid_idx] = grid_val;
     }
 
     // Brickmap unpacking
     if (unpack_idx < brickmap_unpack.count) {
         let element = &brickmap_unpack.elements[unpack_idx];
         brickmap_cache[(*element).cache_idx] = (*element).brickmap;
+        // Each row of the detail table holds 128 brickmaps of 32 texels
+        let detail_origin = vec2<u32>(((*element).cache_idx % 128u) * 32u, (*element).cache_idx / 128u);
+        for (var i: u32 = 0u; i < 32u; i++) {
+            textureStore(detail_table, detail_origin + vec2<u32>(i, 0u), vec4<u32>((*element).detail.shapes[i], 0u, 0u, 0u));
+        }
         let st_offset = (*element).brickmap.shading_table_offset;
         for (var i: u32 = 0u; i < (*element).shading_element_count; i++) {
             shading_table[st_offset + i] = (*element).shading_elements[i];
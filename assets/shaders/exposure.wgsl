@@ -0,0 +1,98 @@
+@group(0) @binding(0) var<uniform> params: ExposureParams;
+@group(0) @binding(1) var<storage, read_write> histogram: array<atomic<u32>, 256>;
+@group(0) @binding(2) var<storage, read_write> exposure: ExposureState;
+@group(0) @binding(3) var image: texture_2d<f32>;
+
+struct ExposureParams {
+    min_log_luminance: f32,
+    log_luminance_range: f32,
+    // Average luminance the image gets scaled to
+    target_luminance: f32,
+    min_exposure: f32,
+    max_exposure: f32,
+    // How far to move towards the target exposure this frame, 0-1
+    adaptation: f32,
+    _pad: vec2<f32>,
+}
+
+struct ExposureState {
+    exposure: f32,
+    average_luminance: f32,
+}
+
+const BIN_COUNT: u32 = 256u;
+
+var<workgroup> local_histogram: array<atomic<u32>, 256>;
+
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+// Bin 0 is kept for pixels too dark to count, so pure black doesn't drag the average down
+fn luminance_bin(color: vec3<f32>) -> u32 {
+    let lum = luminance(color);
+    if (lum < 0.0001) {
+        return 0u;
+    }
+    let t = clamp((log2(lum) - params.min_log_luminance) / params.log_luminance_range, 0.0, 1.0);
+    return u32(t * f32(BIN_COUNT - 2u)) + 1u;
+}
+
+// Counts the pixels in each log luminance bin, per workgroup first so the global
+// histogram only gets one add per bin per workgroup
+@compute @workgroup_size(16, 16, 1)
+fn build_histogram(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+    @builtin(local_invocation_index) local_idx: u32
+) {
+    atomicStore(&local_histogram[local_idx], 0u);
+    workgroupBarrier();
+
+    let dims = textureDimensions(image);
+    if (all(global_id.xy < dims)) {
+        let color = textureLoad(image, global_id.xy, 0).xyz;
+        atomicAdd(&local_histogram[luminance_bin(color)], 1u);
+    }
+    workgroupBarrier();
+
+    let count = atomicLoad(&local_histogram[local_idx]);
+    if (count > 0u) {
+        atomicAdd(&histogram[local_idx], count);
+    }
+}
+
+var<workgroup> weighted_bins: array<f32, 256>;
+
+// Averages the histogram, moves the exposure towards what it should be for that average
+// and clears the histogram for next frame. Runs as a single workgroup, one thread per bin
+@compute @workgroup_size(256, 1, 1)
+fn adapt(@builtin(local_invocation_index) local_idx: u32) {
+    let count = atomicLoad(&histogram[local_idx]);
+    atomicStore(&histogram[local_idx], 0u);
+    weighted_bins[local_idx] = f32(count) * f32(local_idx);
+    workgroupBarrier();
+
+    for (var stride: u32 = BIN_COUNT / 2u; stride > 0u; stride /= 2u) {
+        if (local_idx < stride) {
+            weighted_bins[local_idx] += weighted_bins[local_idx + stride];
+        }
+        workgroupBarrier();
+    }
+
+    if (local_idx == 0u) {
+        let dims = textureDimensions(image);
+        let counted = f32(dims.x * dims.y) - f32(count);
+        if (counted <= 0.0) {
+            return;
+        }
+
+        // Undo the bin mapping to get back to a luminance
+        let average_bin = weighted_bins[0] / counted - 1.0;
+        let log_luminance = average_bin / f32(BIN_COUNT - 2u) * params.log_luminance_range + params.min_log_luminance;
+        let average = exp2(log_luminance);
+        let target_exposure = clamp(params.target_luminance / average, params.min_exposure, params.max_exposure);
+
+        exposure.exposure = mix(exposure.exposure, target_exposure, params.adaptation);
+        exposure.average_luminance = average;
+    }
+}
@@ -0,0 +1,67 @@
+@group(0) @binding(0) var<uniform> camera: Camera;
+@group(0) @binding(1) var raycast_depth: texture_2d<f32>;
+
+struct Camera {
+    projection: mat4x4<f32>,
+    view: mat4x4<f32>,
+    pos: vec3<f32>,
+    _pad: f32,
+};
+
+struct VertexInput {
+    // In voxels
+    @location(0) position: vec3<f32>,
+    @location(1) color: vec4<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) color: vec4<f32>,
+    // In bricks, like the camera
+    @location(1) world_pos: vec3<f32>,
+    // The clip position again, as the builtin one is in window pixels by the fragment
+    @location(2) screen_pos: vec4<f32>,
+}
+
+const NEAR_PLANE: f32 = 0.01;
+// How visible the parts of the gizmo behind voxels are
+const OCCLUDED_ALPHA: f32 = 0.3;
+
+@vertex
+fn vertex(in: VertexInput) -> VertexOutput {
+    // Same projection as the debug lines, see there
+    let world_pos = in.position / 8.0;
+    let eye_to_world = mat3x3<f32>(camera.view[0].xyz, camera.view[1].xyz, camera.view[2].xyz);
+    let eye = transpose(eye_to_world) * (world_pos - camera.pos);
+    let depth = -eye.z;
+
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(
+        eye.x / camera.projection[0][0],
+        eye.y / camera.projection[1][1],
+        depth - NEAR_PLANE,
+        depth
+    );
+    out.screen_pos = out.clip_position;
+    out.color = in.color;
+    out.world_pos = world_pos;
+    return out;
+}
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    // The raycast image is flipped vertically relative to the screen, and can be smaller
+    // than it
+    let dims = textureDimensions(raycast_depth);
+    let uv = max(in.screen_pos.xy / in.screen_pos.w * 0.5 + vec2<f32>(0.5), vec2<f32>(0.0));
+    let img_coord = min(vec2<u32>(uv * vec2<f32>(dims)), dims - vec2<u32>(1u));
+
+    // Rather than being hidden, the parts behind voxels are faded so the gizmo can still
+    // be grabbed from behind a wall
+    var color = in.color;
+    let distance = length(in.world_pos - camera.pos);
+    if (distance > textureLoad(raycast_depth, img_coord, 0).x + 0.01) {
+        color.a *= OCCLUDED_ALPHA;
+    }
+    return color;
+}
@@ -0,0 +1,92 @@
+struct Camera {
+    projection: mat4x4<f32>,
+    view: mat4x4<f32>,
+    pos: vec3<f32>,
+    _pad: f32,
+};
+
+struct SunLight {
+    // Points towards the sun
+    direction: vec3<f32>,
+    intensity: f32,
+    color: vec3<f32>,
+    _pad: f32,
+};
+
+struct MeshParams {
+    sky_color: vec3<f32>,
+    _pad: f32,
+};
+
+struct VertexInput {
+    // In voxels
+    @location(0) position: vec3<f32>,
+    // +x, -x, +y, -y, +z, -z
+    @location(1) face: u32,
+    @location(2) material: u32,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) normal: vec3<f32>,
+    @location(1) @interpolate(flat) material: u32,
+}
+
+@group(0) @binding(0)
+var<uniform> camera: Camera;
+@group(0) @binding(1)
+var<uniform> sun: SunLight;
+@group(0) @binding(2)
+var<uniform> params: MeshParams;
+// Packed RGBA albedo of each material
+@group(0) @binding(3)
+var<storage, read> materials: array<u32>;
+
+const NEAR_PLANE: f32 = 0.01;
+
+var<private> NORMALS: array<vec3<f32>, 6> = array<vec3<f32>, 6>(
+    vec3<f32>(1.0, 0.0, 0.0),
+    vec3<f32>(-1.0, 0.0, 0.0),
+    vec3<f32>(0.0, 1.0, 0.0),
+    vec3<f32>(0.0, -1.0, 0.0),
+    vec3<f32>(0.0, 0.0, 1.0),
+    vec3<f32>(0.0, 0.0, -1.0),
+);
+
+fn unpack_albedo(raw_color: u32) -> vec3<f32> {
+    return vec3<f32>(
+        f32((raw_color >> 24u) & 255u) / 255.0,
+        f32((raw_color >> 16u) & 255u) / 255.0,
+        f32((raw_color >> 8u) & 255u) / 255.0,
+    );
+}
+
+@vertex
+fn vertex(in: VertexInput) -> VertexOutput {
+    // The camera uniform holds what the raycast needs: the view matrix takes eye space
+    // directions to world space, and rays are built by scaling screen positions by the
+    // projection diagonal. So we do the opposite of both, like the debug lines do.
+    // The camera is in bricks
+    let eye_to_world = mat3x3<f32>(camera.view[0].xyz, camera.view[1].xyz, camera.view[2].xyz);
+    let eye = transpose(eye_to_world) * (in.position / 8.0 - camera.pos);
+    let depth = -eye.z;
+
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(
+        eye.x / camera.projection[0][0],
+        eye.y / camera.projection[1][1],
+        depth - NEAR_PLANE,
+        depth
+    );
+    out.normal = NORMALS[in.face];
+    out.material = in.material;
+    return out;
+}
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    let albedo = unpack_albedo(materials[in.material]);
+    let direct = max(dot(in.normal, sun.direction), 0.0) * sun.intensity;
+    let ambient = params.sky_color * 0.3;
+    return vec4<f32>(albedo * (ambient + sun.color * direct), 1.0);
+}
@@ -0,0 +1,238 @@
+// Simulation bindings
+@group(0) @binding(0) var<uniform> state: ParticleState;
+@group(0) @binding(1) var<storage, read_write> particles: array<Particle>;
+
+// Drawing bindings. Vertex shaders can't have writable storage, so the draw pipeline gets
+// its own layout reusing the same slots
+@group(0) @binding(0) var<uniform> camera: Camera;
+@group(0) @binding(1) var<uniform> draw_state: ParticleState;
+@group(0) @binding(2) var<storage, read> draw_particles: array<Particle>;
+@group(0) @binding(3) var raycast_depth: texture_2d<f32>;
+@group(0) @binding(4) var<uniform> shadow_cascades: ShadowCascades;
+@group(0) @binding(5) var sun_shadow_maps: texture_2d_array<f32>;
+
+struct Camera {
+    projection: mat4x4<f32>,
+    view: mat4x4<f32>,
+    pos: vec3<f32>,
+    _pad: f32,
+};
+
+// Matches SunShadowMaps on the CPU
+struct ShadowCascades {
+    right: vec3<f32>,
+    update_cascade: u32,
+    up: vec3<f32>,
+    size: u32,
+    direction: vec3<f32>,
+    depth_range: f32,
+    // Center and half extent of each cascade, in bricks
+    cascades: array<vec4<f32>, 3>,
+};
+
+// Particles live in a box around `center`, wrapping around as the camera moves
+struct ParticleState {
+    center: vec3<f32>,
+    dt: f32,
+    time: f32,
+    kind: u32,
+    count: u32,
+    // Random seed for the frame, see `RandomService`
+    seed: u32,
+}
+
+// A seed of 0 means the particle hasn't been spawned yet
+struct Particle {
+    position: vec3<f32>,
+    seed: u32,
+    velocity: vec3<f32>,
+    _pad: f32,
+}
+
+const KIND_DUST: u32 = 0u;
+const KIND_LEAVES: u32 = 1u;
+const KIND_RAIN: u32 = 2u;
+const KIND_SNOW: u32 = 3u;
+
+// Half size of the box around the camera, in bricks
+const BOX_RADIUS: f32 = 3.0;
+const TAU: f32 = 6.2831853;
+
+// PCG hash, used to get cheap random numbers in shaders
+fn hash_u32(value: u32) -> u32 {
+    let state = value * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+fn random_f32(seed: ptr<function, u32>) -> f32 {
+    *seed = hash_u32(*seed);
+    return f32(*seed) / 4294967295.0;
+}
+
+fn particle_phase(seed: u32) -> f32 {
+    return f32(seed & 1023u) / 1023.0 * TAU;
+}
+
+@compute @workgroup_size(64, 1, 1)
+fn simulate(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let idx = global_id.x;
+    if (idx >= state.count) {
+        return;
+    }
+
+    var particle = particles[idx];
+    if (particle.seed == 0u) {
+        var seed = hash_u32((idx + 1u) ^ state.seed);
+        let offset = vec3<f32>(random_f32(&seed), random_f32(&seed), random_f32(&seed));
+        particle.position = state.center + (offset * 2.0 - vec3<f32>(1.0)) * BOX_RADIUS;
+        particle.seed = seed | 1u;
+    }
+
+    let t = state.time;
+    let phase = particle_phase(particle.seed);
+    var velocity: vec3<f32>;
+    switch (state.kind) {
+        case KIND_DUST: {
+            velocity = vec3<f32>(sin(t * 0.3 + phase), sin(t * 0.23 + phase * 2.0), cos(t * 0.27 + phase)) * 0.02;
+        }
+        case KIND_LEAVES: {
+            velocity = vec3<f32>(sin(t * 1.3 + phase) * 0.1, -0.15, cos(t * 0.9 + phase) * 0.1);
+        }
+        case KIND_SNOW: {
+            velocity = vec3<f32>(sin(t * 0.7 + phase) * 0.05, -0.4, cos(t * 0.5 + phase) * 0.05);
+        }
+        case KIND_RAIN, default: {
+            velocity = vec3<f32>(0.0, -3.0, 0.0);
+        }
+    }
+    particle.velocity = velocity;
+    particle.position += velocity * state.dt;
+
+    // Wrap around the box, so there are always particles wherever the camera goes
+    let offset = (particle.position - state.center) / (2.0 * BOX_RADIUS);
+    particle.position = state.center + (fract(offset + vec3<f32>(0.5)) - vec3<f32>(0.5)) * 2.0 * BOX_RADIUS;
+
+    particles[idx] = particle;
+}
+
+// How much sunlight reaches a point in brick space, from the nearest cascade covering it.
+// The maps are built by raycasting the same voxels the raycaster shadows with, so
+// particles and voxels agree on where the shadows are.
+fn sun_visibility(pos: vec3<f32>) -> f32 {
+    if (shadow_cascades.depth_range <= 0.0) {
+        return 1.0;
+    }
+
+    for (var i: u32 = 0u; i < 3u; i++) {
+        let cascade = shadow_cascades.cascades[i];
+        let offset = pos - cascade.xyz;
+        let uv = vec2<f32>(dot(offset, shadow_cascades.right), dot(offset, shadow_cascades.up)) / cascade.w;
+        if (any(abs(uv) >= vec2<f32>(1.0))) {
+            continue;
+        }
+
+        let size = shadow_cascades.size;
+        let texel = min(vec2<u32>((uv * 0.5 + vec2<f32>(0.5)) * f32(size)), vec2<u32>(size - 1u));
+        let occluder = textureLoad(sun_shadow_maps, texel, i, 0).x;
+        let receiver = shadow_cascades.depth_range - dot(offset, shadow_cascades.direction);
+
+        // Bias by a couple of texels so surfaces don't shadow themselves
+        let bias = 2.0 * cascade.w / f32(size);
+        return select(1.0, 0.0, receiver > occluder + bias);
+    }
+    return 1.0;
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) color: vec4<f32>,
+    @location(1) uv: vec2<f32>,
+    @location(2) distance: f32,
+    // The clip position again, as the builtin one is in window pixels by the fragment
+    @location(3) screen_pos: vec4<f32>,
+}
+
+const NEAR_PLANE: f32 = 0.01;
+
+@vertex
+fn vertex(@builtin(vertex_index) vertex_idx: u32, @builtin(instance_index) instance_idx: u32) -> VertexOutput {
+    var corners = array<vec2<f32>, 6>(
+        vec2<f32>(-1.0, -1.0),
+        vec2<f32>(1.0, -1.0),
+        vec2<f32>(1.0, 1.0),
+        vec2<f32>(-1.0, -1.0),
+        vec2<f32>(1.0, 1.0),
+        vec2<f32>(-1.0, 1.0),
+    );
+    let corner = corners[vertex_idx];
+    let particle = draw_particles[instance_idx];
+
+    // Same inversion of the raycast camera conventions as the debug lines
+    let eye_to_world = mat3x3<f32>(camera.view[0].xyz, camera.view[1].xyz, camera.view[2].xyz);
+    let world_to_eye = transpose(eye_to_world);
+    var eye = world_to_eye * (particle.position - camera.pos);
+
+    let phase = particle_phase(particle.seed);
+    var size = 0.015;
+    var color = vec4<f32>(1.0, 0.95, 0.8, 0.5);
+    var right = vec2<f32>(1.0, 0.0);
+    var up = vec2<f32>(0.0, 1.0);
+    var stretch = 1.0;
+    switch (draw_state.kind) {
+        case KIND_DUST: {}
+        case KIND_LEAVES: {
+            size = 0.04;
+            color = vec4<f32>(mix(vec3<f32>(0.3, 0.6, 0.1), vec3<f32>(0.9, 0.5, 0.1), phase / TAU), 1.0);
+        }
+        case KIND_SNOW: {
+            size = 0.02;
+            color = vec4<f32>(1.0, 1.0, 1.0, 0.9);
+        }
+        case KIND_RAIN, default: {
+            // Rain is stretched into streaks along its velocity
+            size = 0.004;
+            color = vec4<f32>(0.7, 0.8, 1.0, 0.6);
+            let streak = (world_to_eye * particle.velocity).xy;
+            if (any(streak != vec2<f32>(0.0))) {
+                up = normalize(streak);
+                right = vec2<f32>(up.y, -up.x);
+                stretch = 20.0;
+            }
+        }
+    }
+    eye = vec3<f32>(eye.xy + (corner.x * right + corner.y * up * stretch) * size, eye.z);
+    let depth = -eye.z;
+
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(
+        eye.x / camera.projection[0][0],
+        eye.y / camera.projection[1][1],
+        depth - NEAR_PLANE,
+        depth
+    );
+    out.screen_pos = out.clip_position;
+    // Shadowed particles only get the ambient light
+    let lit = sun_visibility(particle.position);
+    out.color = vec4<f32>(color.xyz * (0.4 + 0.6 * lit), color.w);
+    out.uv = corner;
+    out.distance = length(particle.position - camera.pos);
+    return out;
+}
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    if (dot(in.uv, in.uv) > 1.0) {
+        discard;
+    }
+
+    // The raycast image is flipped vertically relative to the screen, and can be smaller
+    // than it
+    let dims = textureDimensions(raycast_depth);
+    let uv = max(in.screen_pos.xy / in.screen_pos.w * 0.5 + vec2<f32>(0.5), vec2<f32>(0.0));
+    let img_coord = min(vec2<u32>(uv * vec2<f32>(dims)), dims - vec2<u32>(1u));
+    if (in.distance > textureLoad(raycast_depth, img_coord, 0).x) {
+        discard;
+    }
+    return in.color;
+}
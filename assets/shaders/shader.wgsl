@@ -26,8 +26,220 @@ fn vertex(
 var t_diffuse: texture_2d<f32>;
 @group(0) @binding(1)
 var s_diffuse: sampler;
+@group(1) @binding(0)
+var<uniform> settings: RenderSettings;
+// Depth, then the octahedral encoded normal and an id of the voxel that was hit
+@group(1) @binding(1)
+var raycast_depth: texture_2d<f32>;
+@group(1) @binding(2)
+var<storage, read> exposure: Exposure;
+// Half resolution, premultiplied by Fresnel with 1 in w wherever there's water
+@group(1) @binding(3)
+var water_reflections: texture_2d<f32>;
+@group(1) @binding(4)
+var water_sampler: sampler;
+
+struct RenderSettings {
+    variable_rate: u32,
+    full_rate_radius: f32,
+    light_probes: u32,
+    loading_progress: f32,
+    raycast_stats: u32,
+    sub_voxel_detail: u32,
+    lod_distance: f32,
+    // In bricks, 0 for no limit
+    max_ray_distance: f32,
+    // Brickgrid steps per ray, 0 to only stop at the edge of the grid
+    max_ray_steps: u32,
+    ray_budget_debug: u32,
+    quarter_res_lighting: u32,
+    // 0 off, 1 grid steps, 2 bricks visited
+    debug_heatmap: u32,
+    // 0 red-green, 1 viridis, 2 cividis, 3 greyscale
+    debug_palette: u32,
+    temporal_accumulation: u32,
+    accumulated_frames: u32,
+    emissive_bounce: u32,
+    // 0 when outlines are off
+    outline_thickness: f32,
+    outline_depth_threshold: f32,
+    outline_normal_threshold: f32,
+    outline_voxel_edges: u32,
+    baked_ao: u32,
+    water: u32,
+    water_level: f32,
+    water_clarity: f32,
+    surface_detail_intensity: f32,
+    surface_detail_scale: f32,
+    crosshair: u32,
+    sun_shadows: u32,
+    // Size of the crosshair and outlines, already applied to outline_thickness
+    ui_scale: f32,
+    high_contrast: u32,
+};
+
+// Written by the auto exposure passes, or straight from the settings when it's manual
+struct Exposure {
+    exposure: f32,
+    average_luminance: f32,
+};
+
+// Half the width of the crosshair, and of the gap in its middle, in pixels at a ui_scale
+// of 1
+const CROSSHAIR_SIZE: f32 = 8.0;
+const CROSSHAIR_GAP: f32 = 2.0;
+
+// Is the pixel inside the region that gets traced at full rate?
+fn is_full_rate(img_coord: vec2<u32>, img_dims: vec2<u32>) -> bool {
+    if (settings.variable_rate == 0u) {
+        return true;
+    }
+
+    let center = vec2<f32>(img_dims) * 0.5;
+    let dist = length(vec2<f32>(img_coord) - center) / center.y;
+    return dist <= settings.full_rate_radius;
+}
+
+// Reduced rate pixels only have every other pixel traced, so we bilinearly
+// interpolate between the 4 nearest traced pixels
+fn sample_reduced_rate(img_coord: vec2<u32>, img_dims: vec2<u32>) -> vec4<f32> {
+    let base = (img_coord / 2u) * 2u;
+    let last = ((img_dims - vec2<u32>(1u)) / 2u) * 2u;
+    let next = min(base + vec2<u32>(2u), last);
+    let w = vec2<f32>(img_coord - base) * 0.5;
+
+    let c00 = textureLoad(t_diffuse, base, 0);
+    let c10 = textureLoad(t_diffuse, vec2<u32>(next.x, base.y), 0);
+    let c01 = textureLoad(t_diffuse, vec2<u32>(base.x, next.y), 0);
+    let c11 = textureLoad(t_diffuse, next, 0);
+    return mix(mix(c00, c10, w.x), mix(c01, c11, w.x), w.y);
+}
+
+fn decode_normal(encoded: vec2<f32>) -> vec3<f32> {
+    var n = vec3<f32>(encoded, 1.0 - abs(encoded.x) - abs(encoded.y));
+    if (n.z < 0.0) {
+        n = vec3<f32>((vec2<f32>(1.0) - abs(n.yx)) * select(vec2<f32>(-1.0), vec2<f32>(1.0), n.xy >= vec2<f32>(0.0)), n.z);
+    }
+    return normalize(n);
+}
+
+// How strongly a pixel is on an edge, from comparing its surface with the ones
+// `outline_thickness` pixels away on each side
+fn outline(img_coord: vec2<u32>, img_dims: vec2<u32>) -> f32 {
+    let center = textureLoad(raycast_depth, img_coord, 0);
+    let center_normal = decode_normal(center.yz);
+    let reach = max(i32(round(settings.outline_thickness)), 1);
+    // Arrays can only be dynamically indexed through a variable
+    var offsets = array<vec2<i32>, 4>(
+        vec2<i32>(reach, 0),
+        vec2<i32>(-reach, 0),
+        vec2<i32>(0, reach),
+        vec2<i32>(0, -reach),
+    );
+
+    var edge = 0.0;
+    for (var i: u32 = 0u; i < 4u; i++) {
+        let coord = clamp(vec2<i32>(img_coord) + offsets[i], vec2<i32>(0), vec2<i32>(img_dims) - vec2<i32>(1));
+        let other = textureLoad(raycast_depth, coord, 0);
+        let depth_change = abs(other.x - center.x) / max(min(other.x, center.x), 1e-3);
+        let normal_change = 1.0 - dot(decode_normal(other.yz), center_normal);
+        if (depth_change > settings.outline_depth_threshold
+            || normal_change > settings.outline_normal_threshold
+            || (settings.outline_voxel_edges != 0u && other.w != center.w)) {
+            edge += 0.25;
+        }
+    }
+    return min(edge * 2.0, 1.0);
+}
+
+// Reflection off the water at a pixel, if it's water. Only the reflection texels that are
+// water count towards the filtered value, so nothing bleeds in from the shore
+fn water_reflection(img_coord: vec2<u32>, uv: vec2<f32>) -> vec3<f32> {
+    if (textureLoad(raycast_depth, img_coord, 0).w >= 0.0) {
+        return vec3<f32>(0.0);
+    }
+
+    let reflection = textureSampleLevel(water_reflections, water_sampler, uv, 0.0);
+    if (reflection.w < 1e-3) {
+        return vec3<f32>(0.0);
+    }
+    return reflection.xyz / reflection.w;
+}
+
+// Loading bar along the bottom of the screen, shown while the spawn area is loading
+fn loading_screen(uv: vec2<f32>) -> vec4<f32> {
+    let background = vec4<f32>(0.02, 0.02, 0.03, 1.0);
+    let bar_min = vec2<f32>(0.2, 0.9);
+    let bar_max = vec2<f32>(0.8, 0.92);
+    if (any(uv < bar_min) || any(uv > bar_max)) {
+        return background;
+    }
+
+    let fill = mix(bar_min.x, bar_max.x, settings.loading_progress);
+    if (uv.x <= fill) {
+        return vec4<f32>(0.8, 0.8, 0.8, 1.0);
+    }
+    return vec4<f32>(0.15, 0.15, 0.15, 1.0);
+}
 
 @fragment
 fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
-    return textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    if (settings.loading_progress < 1.0) {
+        return loading_screen(vec2<f32>(in.tex_coords.x, 1.0 - in.tex_coords.y));
+    }
+
+    let img_dims = textureDimensions(t_diffuse);
+    let img_coord = min(vec2<u32>(in.tex_coords * vec2<f32>(img_dims)), img_dims - vec2<u32>(1u));
+    var color: vec4<f32>;
+    if (!is_full_rate(img_coord, img_dims)) {
+        color = sample_reduced_rate(img_coord, img_dims);
+    } else {
+        color = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    }
+
+    // Heatmap colours are meant to be read as they are
+    if (settings.debug_heatmap == 0u) {
+        if (settings.water != 0u) {
+            color = vec4<f32>(color.xyz + water_reflection(img_coord, in.tex_coords), color.w);
+        }
+        color = vec4<f32>(color.xyz * exposure.exposure, color.w);
+    }
+    if (settings.outline_thickness > 0.0) {
+        color = vec4<f32>(color.xyz * (1.0 - outline(img_coord, img_dims)), color.w);
+    }
+    if (settings.crosshair != 0u) {
+        let crosshair = crosshair_coverage(img_coord, img_dims);
+        if (crosshair == 2u) {
+            // Dark on bright backgrounds and bright on dark ones, so it shows up everywhere
+            let luminance = dot(color.xyz, vec3<f32>(0.2126, 0.7152, 0.0722));
+            let core = select(1.0, 0.0, luminance > 0.5 && settings.high_contrast == 0u);
+            color = vec4<f32>(vec3<f32>(core), color.w);
+        } else if (crosshair == 1u) {
+            color = vec4<f32>(vec3<f32>(0.0), color.w);
+        }
+    }
+    return color;
+}
+
+// A small plus in the middle of the screen, with a gap so it doesn't hide what it's
+// pointing at. 2 for the plus itself, 1 for the dark border it gets in the high contrast
+// theme and 0 for anything else.
+fn crosshair_coverage(img_coord: vec2<u32>, img_dims: vec2<u32>) -> u32 {
+    let offset = vec2<f32>(abs(vec2<i32>(img_coord) - vec2<i32>(img_dims / 2u)));
+    let along = max(offset.x, offset.y);
+    let across = min(offset.x, offset.y);
+    let scale = max(settings.ui_scale, 1.0);
+    let size = CROSSHAIR_SIZE * scale;
+    let gap = CROSSHAIR_GAP * scale;
+    // Lines get thicker with the scale, one pixel wide at 1
+    let half_width = floor(scale * 0.5);
+    if (across <= half_width && along >= gap && along <= size) {
+        return 2u;
+    }
+    let border = half_width + scale;
+    if (settings.high_contrast != 0u && across <= border && along >= gap - scale
+        && along <= size + scale) {
+        return 1u;
+    }
+    return 0u;
 }
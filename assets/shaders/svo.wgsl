@@ -0,0 +1,171 @@
+struct Camera {
+    projection: mat4x4<f32>,
+    view: mat4x4<f32>,
+    pos: vec3<f32>,
+    _pad: f32,
+};
+
+struct SunLight {
+    // Points towards the sun
+    direction: vec3<f32>,
+    intensity: f32,
+    color: vec3<f32>,
+    _pad: f32,
+};
+
+struct SvoParams {
+    // Voxel the octree's lower corner is at
+    origin: vec3<i32>,
+    // Levels below the root, the octree is 2^depth voxels across
+    depth: u32,
+    sky_color: vec3<f32>,
+    // Cells a ray can step through before giving up
+    max_steps: u32,
+};
+
+struct Hit {
+    hit: bool,
+    // Distance in voxels
+    t: f32,
+    normal: vec3<f32>,
+    material: u32,
+};
+
+@group(0) @binding(0)
+var<uniform> camera: Camera;
+@group(0) @binding(1)
+var<uniform> sun: SunLight;
+@group(0) @binding(2)
+var<uniform> params: SvoParams;
+// Each node is its child mask in the low 8 bits and the index of its first child above,
+// with the voxels' material ids at the bottom level. The root is the first node
+@group(0) @binding(3)
+var<storage, read> nodes: array<u32>;
+// Packed RGBA albedo of each material
+@group(0) @binding(4)
+var<storage, read> materials: array<u32>;
+@group(1) @binding(0)
+var output: texture_storage_2d<rgba8unorm, write>;
+
+fn unpack_albedo(raw_color: u32) -> vec3<f32> {
+    return vec3<f32>(
+        f32((raw_color >> 24u) & 255u) / 255.0,
+        f32((raw_color >> 16u) & 255u) / 255.0,
+        f32((raw_color >> 8u) & 255u) / 255.0,
+    );
+}
+
+// Normal of the face of a cell a ray leaves through along whichever axis of `t` is
+// smallest, which is the face the next cell is entered through
+fn exit_normal(t: vec3<f32>, dir: vec3<f32>) -> vec3<f32> {
+    if (t.x <= t.y && t.x <= t.z) {
+        return vec3<f32>(-sign(dir.x), 0.0, 0.0);
+    }
+    if (t.y <= t.z) {
+        return vec3<f32>(0.0, -sign(dir.y), 0.0);
+    }
+    return vec3<f32>(0.0, 0.0, -sign(dir.z));
+}
+
+// Traces a ray through the octree, in the octree's voxel space. Each step descends from
+// the root to the cell the ray is in, then either stops at a voxel or skips past the
+// empty cell it found, so large empty areas are crossed in a single step.
+fn trace(origin: vec3<f32>, ray_dir: vec3<f32>, max_t: f32) -> Hit {
+    var hit = Hit(false, 0.0, vec3<f32>(0.0), 0u);
+    let size = 1u << params.depth;
+
+    // Keep clear of dividing by zero for rays along an axis
+    let dir = select(ray_dir, vec3<f32>(1e-6), abs(ray_dir) < vec3<f32>(1e-6));
+    let inv_dir = 1.0 / dir;
+
+    // Clip the ray to the octree
+    let t0 = -origin * inv_dir;
+    let t1 = (vec3<f32>(f32(size)) - origin) * inv_dir;
+    let t_near = min(t0, t1);
+    let t_far = max(t0, t1);
+    var t = max(max(max(t_near.x, t_near.y), t_near.z), 0.0);
+    let t_end = min(min(min(t_far.x, t_far.y), t_far.z), max_t);
+    if (t >= t_end) {
+        return hit;
+    }
+    // Rays starting inside have no entry face
+    var normal = vec3<f32>(0.0);
+    if (t > 0.0) {
+        normal = exit_normal(-t_near, dir);
+    }
+
+    for (var step = 0u; step < params.max_steps; step++) {
+        // Nudge the position into the cell being entered, which is behind the face it
+        // was entered through
+        let pos = origin + dir * t - normal * 0.001;
+        let cell = vec3<u32>(clamp(floor(pos), vec3<f32>(0.0), vec3<f32>(f32(size - 1u))));
+
+        var node = nodes[0];
+        var cell_min = vec3<u32>(0u);
+        var cell_size = size;
+        var found = false;
+        for (var level = 0u; level < params.depth; level++) {
+            cell_size = cell_size >> 1u;
+            let upper = cell >= cell_min + cell_size;
+            cell_min += select(vec3<u32>(0u), vec3<u32>(cell_size), upper);
+            let child = u32(upper.x) | (u32(upper.y) << 1u) | (u32(upper.z) << 2u);
+            let mask = node & 255u;
+            if ((mask & (1u << child)) == 0u) {
+                break;
+            }
+            node = nodes[(node >> 8u) + countOneBits(mask & ((1u << child) - 1u))];
+            found = level + 1u == params.depth;
+        }
+
+        if (found) {
+            hit.hit = true;
+            hit.t = t;
+            hit.normal = normal;
+            hit.material = node & 0xFFFFu;
+            return hit;
+        }
+
+        // Skip to the far side of the empty cell
+        let far = vec3<f32>(cell_min) + select(vec3<f32>(0.0), vec3<f32>(f32(cell_size)), dir > vec3<f32>(0.0));
+        let t_exit = (far - origin) * inv_dir;
+        t = min(min(t_exit.x, t_exit.y), t_exit.z);
+        normal = exit_normal(t_exit, dir);
+        if (t >= t_end) {
+            return hit;
+        }
+    }
+    return hit;
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let img_dims = textureDimensions(output);
+    if (global_id.x >= img_dims.x || global_id.y >= img_dims.y) {
+        return;
+    }
+
+    let img_coord_frac = (vec2<f32>(global_id.xy) + 0.5) / vec2<f32>(img_dims);
+    let screen_pos = img_coord_frac * 2.0 - vec2<f32>(1.0);
+    var ray_eye = camera.projection * vec4<f32>(screen_pos, -1.0, 0.0);
+    ray_eye = vec4<f32>(ray_eye.xy, -1.0, 0.0);
+    let ray_dir = normalize((camera.view * ray_eye).xyz);
+    // The camera is in bricks
+    let ray_pos = camera.pos * 8.0 - vec3<f32>(params.origin);
+
+    var color = params.sky_color;
+    let hit = trace(ray_pos, ray_dir, 1e9);
+    if (hit.hit) {
+        let albedo = unpack_albedo(materials[hit.material]);
+        var direct = max(dot(hit.normal, sun.direction), 0.0) * sun.intensity;
+        if (direct > 0.0) {
+            let surface = ray_pos + ray_dir * hit.t + hit.normal * 0.01;
+            if (trace(surface, sun.direction, 1e9).hit) {
+                direct = 0.0;
+            }
+        }
+        let ambient = params.sky_color * 0.3;
+        color = albedo * (ambient + sun.color * direct);
+    }
+
+    textureStore(output, vec2<i32>(global_id.xy), vec4<f32>(color, 1.0));
+}
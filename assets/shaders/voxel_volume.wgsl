@@ -1,19 +1,85 @@
-@group(0) @binding(0) var output: texture_storage_2d<rgba8unorm, write>;
-@group(0) @binding(1) var<uniform> world_state: WorldState;
-@group(0) @binding(2) var<storage, read_write> brickgrid: array<atomic<u32>>;
-@group(0) @binding(3) var<storage, read> brickmap_cache: array<Brickmap>;
-@group(0) @binding(4) var<storage, read> shading_table: array<ShadingElement>;
-@group(0) @binding(5) var<storage, read_write> cpu_feedback: Feedback;
-@group(0) @binding(6) var<uniform> camera: Camera;
+@group(0) @binding(0) var<uniform> world_state: WorldState;
+@group(0) @binding(1) var<storage, read_write> brickgrid: array<atomic<u32>>;
+@group(0) @binding(2) var<storage, read> brickmap_cache: array<Brickmap>;
+@group(0) @binding(3) var<storage, read> shading_table: array<ShadingElement>;
+@group(0) @binding(4) var<storage, read_write> cpu_feedback: Feedback;
+@group(0) @binding(5) var<uniform> camera: Camera;
+@group(0) @binding(6) var<uniform> settings: RenderSettings;
+@group(0) @binding(7) var<storage, read_write> light_probes: array<LightProbe>;
+@group(0) @binding(8) var<uniform> probe_grid: ProbeGridState;
+@group(0) @binding(9) var<storage, read> lights: array<Light>;
+@group(0) @binding(10) var<uniform> light_state: LightState;
+@group(0) @binding(11) var<storage, read_write> raycast_stats: RaycastStats;
+@group(0) @binding(12) var detail_table: texture_2d<u32>;
+@group(0) @binding(13) var<uniform> portals: PortalState;
+@group(0) @binding(14) var<uniform> pick: PickState;
+@group(0) @binding(15) var pick_result: texture_storage_2d<rgba32sint, write>;
+@group(0) @binding(16) var<uniform> decals: DecalState;
+@group(0) @binding(17) var<uniform> atmosphere: Atmosphere;
+@group(0) @binding(18) var brickgrid_mips: texture_3d<u32>;
+@group(0) @binding(19) var<uniform> sun: SunLight;
+@group(0) @binding(20) var sun_shadow_maps: texture_storage_2d_array<r32float, write>;
+@group(0) @binding(21) var<uniform> shadow_cascades: ShadowCascades;
+@group(0) @binding(22) var blue_noise: texture_2d<f32>;
+@group(0) @binding(23) var<uniform> materials: MaterialTable;
+// Voxels of every volume placed over the terrain, material id + 1 with 0 for empty
+@group(0) @binding(24) var volume_atlas: texture_3d<u32>;
+
+// Everything sized to the screen, which gets recreated when the window is resized
+@group(1) @binding(0) var output: texture_storage_2d<rgba16float, write>;
+@group(1) @binding(1) var<storage, read_write> reservoirs: array<Reservoir>;
+// Depth, then the octahedral encoded normal and an id of the voxel that was hit
+@group(1) @binding(2) var depth_output: texture_storage_2d<rgba32float, write>;
+// Last frame's output, which this frame gets blended with while accumulating
+@group(1) @binding(3) var accumulation_history: texture_2d<f32>;
+// The water reflection pass gets its own screen group with just these. Water pixels have
+// an id of -1 in the depth
+@group(1) @binding(4) var surface_depth: texture_2d<f32>;
+@group(1) @binding(5) var water_reflections: texture_storage_2d<rgba16float, write>;
+
+// Traversal counters for the ray currently being cast, reset before each primary ray
+var<private> trace_steps: u32;
+var<private> trace_bricks: u32;
+// Blue noise threshold for the pixel being traced, used to dither between brick
+// representations. 0 for rays that don't come from a pixel, which always get the
+// detailed one
+var<private> dither: f32;
+// Set when a ray gives up because it hit the distance or step limit
+var<private> ray_out_of_budget: bool;
+
+// Per workgroup totals, so only one invocation per workgroup touches the global stats
+var<workgroup> workgroup_stats: array<atomic<u32>, 4>;
 
 struct ShadingElement {
+    // Index into the material table in the low 16 bits, baked corner AO in the high 16.
+    // See util::corner_occlusion
+    data: u32,
+}
+
+// Matches MaterialUniform, see world::Material
+struct Material {
+    emissive: vec3<f32>,
+    roughness: f32,
+    // Packed RGBA like every other colour
     albedo: u32,
+    metallic: f32,
+    _pad: vec2<u32>,
+}
+
+struct MaterialTable {
+    entries: array<Material, 1024>,
 }
 
+// Hits that only know a colour, such as far away bricks, don't have a material
+const NO_MATERIAL: u32 = 0xFFFFFFFFu;
+
 struct Brickmap {
     bitmask: array<u32, 16>,
+    occupancy: array<u32, 2>, // 4x4x4 coarse mask, one bit per 2x2x2 voxels
     shading_table_offset: u32,
     lod_color: u32,
+    // World state frame the brickmap was loaded on, 0 if it shouldn't fade in
+    loaded_frame: u32,
 }
 
 struct Camera {
@@ -23,17 +89,228 @@ struct Camera {
     _pad: f32,
 };
 
+struct SunLight {
+    // Points towards the sun
+    direction: vec3<f32>,
+    intensity: f32,
+    color: vec3<f32>,
+    _pad: f32,
+};
+
+// Matches SunShadowMaps on the CPU
+struct ShadowCascades {
+    right: vec3<f32>,
+    update_cascade: u32,
+    up: vec3<f32>,
+    size: u32,
+    direction: vec3<f32>,
+    depth_range: f32,
+    // Center and half extent of each cascade, in bricks
+    cascades: array<vec4<f32>, 3>,
+};
+
+struct RenderSettings {
+    variable_rate: u32,
+    full_rate_radius: f32,
+    light_probes: u32,
+    loading_progress: f32,
+    raycast_stats: u32,
+    sub_voxel_detail: u32,
+    lod_distance: f32,
+    // In bricks, 0 for no limit
+    max_ray_distance: f32,
+    // Brickgrid steps per ray, 0 to only stop at the edge of the grid
+    max_ray_steps: u32,
+    ray_budget_debug: u32,
+    quarter_res_lighting: u32,
+    // 0 off, 1 grid steps, 2 bricks visited
+    debug_heatmap: u32,
+    // 0 red-green, 1 viridis, 2 cividis, 3 greyscale
+    debug_palette: u32,
+    temporal_accumulation: u32,
+    // Frames already blended into the history, 0 when it's been reset
+    accumulated_frames: u32,
+    emissive_bounce: u32,
+    // 0 when outlines are off
+    outline_thickness: f32,
+    outline_depth_threshold: f32,
+    outline_normal_threshold: f32,
+    outline_voxel_edges: u32,
+    baked_ao: u32,
+    water: u32,
+    // Height of the water surface in bricks
+    water_level: f32,
+    // Distance under the water in bricks over which the view fades to the water's colour
+    water_clarity: f32,
+    // How far surface detail brightens or darkens the albedo, 0 when it's off
+    surface_detail_intensity: f32,
+    // Width of the surface detail pattern's largest features in voxels
+    surface_detail_scale: f32,
+    crosshair: u32,
+    sun_shadows: u32,
+    // Size of the crosshair and outlines, already applied to outline_thickness
+    ui_scale: f32,
+    high_contrast: u32,
+};
+
+// Irradiance stored as an ambient cube, ordered +x, -x, +y, -y, +z, -z. The w component
+// is 0 until the probe has been updated for the first time, then tells apart the probes
+// that share its slot as the grid scrolls, see update_probes.
+struct LightProbe {
+    irradiance: array<vec4<f32>, 6>,
+}
+
+// A point light, or a box shaped area light when it has a size. Position is the centre,
+// in voxel space
+struct Light {
+    position: vec3<f32>,
+    intensity: f32,
+    color: vec3<f32>,
+    _pad0: f32,
+    half_size: vec3<f32>,
+    _pad1: f32,
+}
+
+struct LightState {
+    light_count: u32,
+    candidate_count: u32,
+    frame: u32,
+    // Shadow rays traced to an area light, point lights only ever need one
+    area_shadow_rays: u32,
+}
+
+// Weighted reservoir holding a single light sample
+struct Reservoir {
+    light_idx: u32,
+    weight_sum: f32,
+    sample_count: u32,
+    weight: f32,
+}
+
+struct ProbeGridState {
+    dims: vec3<u32>,
+    spacing: u32,
+    update_offset: u32,
+    update_count: u32,
+    frame: u32,
+    _pad: u32,
+}
+
+// A rectangle facing cross(right, up). Rays crossing its front get moved by the
+// transform and carry on from the exit portal.
+struct Portal {
+    transform: mat4x4<f32>,
+    position: vec3<f32>,
+    half_width: f32,
+    right: vec3<f32>,
+    half_height: f32,
+    up: vec3<f32>,
+    _pad: f32,
+}
+
+const MAX_PORTALS: u32 = 8u;
+
+struct PortalState {
+    count: u32,
+    _pad0: u32,
+    _pad1: u32,
+    _pad2: u32,
+    portals: array<Portal, MAX_PORTALS>,
+}
+
+// Colour blended over the faces of voxels in min..max, face_mask has a bit per face in
+// the order +X, -X, +Y, -Y, +Z, -Z
+struct Decal {
+    min: vec3<i32>,
+    color: u32,
+    max: vec3<i32>,
+    face_mask: u32,
+}
+
+const MAX_DECALS: u32 = 64u;
+
+struct DecalState {
+    count: u32,
+    _pad0: u32,
+    _pad1: u32,
+    _pad2: u32,
+    decals: array<Decal, MAX_DECALS>,
+}
+
+// Sky, fog and wetness, driven by the weather, and the procedural sky
+struct Atmosphere {
+    sky_color: vec3<f32>,
+    fog_density: f32,
+    fog_color: vec3<f32>,
+    wetness: f32,
+    // Points towards the sun
+    sun_direction: vec3<f32>,
+    turbidity: f32,
+    // 0 draws the flat sky colour instead of the procedural sky
+    sky_enabled: u32,
+    _pad0: u32,
+    _pad1: u32,
+    _pad2: u32,
+}
+
+// Pixel whose hit gets written to the pick result
+struct PickState {
+    pixel: vec2<u32>,
+    _pad: vec2<u32>,
+}
+
 // TODO: Should probably know how big the cache and shading table are etc.
 struct WorldState {
     brickgrid_dims: vec3<u32>,
-    _pad: u32,
+    // Counts up every frame, skipping 0
+    frame: u32,
+    // The brick at the brickgrid's minimum corner. Bricks wrap around the grid as it
+    // scrolls, see BrickmapManager::get_grid_idx
+    grid_origin: vec3<i32>,
+    // Random seed for the frame, from the app's `RandomService`
+    seed: u32,
+    // Where the volume's origin sits in the world, in bricks. Everything here works
+    // relative to the volume, see BrickmapManager::set_origin
+    origin: vec3<f32>,
+    _pad0: u32,
+    volume_count: u32,
+    _pad1: u32,
+    _pad2: u32,
+    _pad3: u32,
+    volumes: array<Volume, MAX_VOLUMES>,
 };
 
+const MAX_VOLUMES: u32 = 16u;
+
+// A box of voxels placed over the terrain, kept densely in the volume atlas. Matches
+// VolumeElement on the CPU
+struct Volume {
+    // Where the volume's centre sits, in voxels
+    center: vec3<f32>,
+    _pad0: u32,
+    dims: vec3<u32>,
+    _pad1: u32,
+    atlas_offset: vec3<u32>,
+    _pad2: u32,
+    // Quaternion taking directions from the world into the volume
+    inverse_rotation: vec4<f32>,
+}
+
 struct HitInfo {
     hit: bool,
     hit_pos: vec3<i32>,
     brickmap_idx: u32,
     mask: vec3<bool>,
+    albedo: u32,
+    material: u32,
+    // Baked AO of the voxel's corners, 0 where nothing was baked
+    ao: u32,
+    // Surface normal of a refined sub-voxel hit or a rotated volume's voxel, zero when
+    // the hit is on a face of the voxel's cube and the normal comes from the mask
+    normal: vec3<f32>,
+    // How far along the ray the hit is in bricks, for hits whose voxel isn't the cube at
+    // hit_pos. Negative when it's worked out from hit_pos
+    distance: f32,
 };
 
 struct AabbHitInfo {
@@ -42,12 +319,27 @@ struct AabbHitInfo {
     normal: vec3<f32>,
 };
 
+struct RaycastStats {
+    rays: atomic<u32>,
+    steps: atomic<u32>,
+    misses: atomic<u32>,
+    bricks: atomic<u32>,
+}
+
+// Requests take 4 words each, x, y, z and whether it's LOD only. After max_count of them
+// comes the visibility bitset, a bit per brickmap cache entry
 struct Feedback {
     max_count: u32,
     count: atomic<u32>,
-    _pad1: u32,
-    _pad2: u32,
-    positions: array<vec4<i32>>,
+    // Index into data of the visibility bitset
+    visibility_offset: u32,
+    _pad: u32,
+    // Distance in bricks where each band starts, nearest first
+    band_starts: array<f32, 4>,
+    // Most requests each band can make in a frame, 0 for no cap
+    band_quotas: array<u32, 4>,
+    band_counts: array<atomic<u32>, 4>,
+    data: array<atomic<u32>>,
 }
 
 struct DdaState {
@@ -90,9 +382,30 @@ fn to_1d_index(p: vec3<i32>, dims: vec3<i32>) -> u32 {
     return u32(p.x + p.y * dims.x + p.z * dims.x * dims.y);
 }
 
+// Modulo that never goes negative, for wrapping positions around a grid
+fn wrap(p: vec3<i32>, dims: vec3<i32>) -> vec3<i32> {
+    return ((p % dims) + dims) % dims;
+}
+
+// The camera's position relative to the volume, in bricks
+fn camera_pos() -> vec3<f32> {
+    return camera.pos - world_state.origin;
+}
+
+// A light's position relative to the volume. Lights are placed in the world, in voxels
+fn light_position(light: Light) -> vec3<f32> {
+    return light.position - world_state.origin * 8.0;
+}
+
+// Index of the brickgrid cell a brick is kept in
+fn brickgrid_index(map_pos: vec3<i32>) -> u32 {
+    let dims = vec3<i32>(world_state.brickgrid_dims);
+    return to_1d_index(wrap(map_pos, dims), dims);
+}
+
 fn get_shading_offset(hit: HitInfo) -> u32 {
     let brickmap = &brickmap_cache[hit.brickmap_idx];
-    let local_index = to_1d_index(hit.hit_pos % 8, vec3<i32>(8));
+    let local_index = to_1d_index(hit.hit_pos & vec3<i32>(7), vec3<i32>(8));
     let bitmask_index = local_index / 32u;
     var map_voxel_idx = 0u;
     for (var i: i32 = 0; i < i32(bitmask_index); i++) {
@@ -160,20 +473,179 @@ fn point_inside_aabb(p: vec3<i32>, min: vec3<i32>, max: vec3<i32>) -> bool {
 
 fn voxel_hit(brickmap_idx: u32, p: vec3<i32>) -> bool {
     // Convert the global position into an index within the brickmap
-    let local_index = to_1d_index(p % 8, vec3<i32>(8));
+    let local_index = to_1d_index(p & vec3<i32>(7), vec3<i32>(8));
 
     // Is the bit at local_index within the bitmask a 1?
     let bitmask_segment = brickmap_cache[brickmap_idx].bitmask[local_index / 32u];
     return (bitmask_segment >> (local_index % 32u) & 1u) != 0u;
 }
 
+fn coarse_hit(brickmap_idx: u32, p: vec3<i32>) -> bool {
+    let coarse_index = to_1d_index(p, vec3<i32>(4));
+    let occupancy_segment = brickmap_cache[brickmap_idx].occupancy[coarse_index / 32u];
+    return (occupancy_segment >> (coarse_index % 32u) & 1u) != 0u;
+}
+
+// Sub-voxel shape of a voxel, 2 bits per voxel. See util::surface_detail
+const DETAIL_NONE: u32 = 0u;
+const DETAIL_ROUNDED: u32 = 1u;
+const DETAIL_BEVELLED: u32 = 2u;
+const DETAIL_SLAB: u32 = 3u;
+
+fn voxel_detail(brickmap_idx: u32, p: vec3<i32>) -> u32 {
+    // Each row of the detail table holds 128 brickmaps of 32 texels
+    let local_index = to_1d_index(p & vec3<i32>(7), vec3<i32>(8));
+    let texel = vec2<u32>((brickmap_idx % 128u) * 32u + local_index / 16u, brickmap_idx / 128u);
+    return (textureLoad(detail_table, texel, 0).x >> ((local_index % 16u) * 2u)) & 3u;
+}
+
+struct SubVoxelHit {
+    t_enter: f32,
+    t_exit: f32,
+    normal: vec3<f32>,
+}
+
+// Narrows the span of the ray inside a convex shape to the half-space dot(n, p) <= d
+fn clip_plane(state: ptr<function, SubVoxelHit>, ray_pos: vec3<f32>, ray_dir: vec3<f32>, n: vec3<f32>, d: f32) {
+    let denom = dot(n, ray_dir);
+    let dist = d - dot(n, ray_pos);
+    if (abs(denom) < 1e-6) {
+        // Parallel to the plane, so the ray is either always inside or never
+        if (dist < 0.0) {
+            (*state).t_exit = -1e30;
+        }
+        return;
+    }
+
+    let t = dist / denom;
+    if (denom < 0.0) {
+        if (t > (*state).t_enter) {
+            (*state).t_enter = t;
+            (*state).normal = n;
+        }
+    } else {
+        (*state).t_exit = min((*state).t_exit, t);
+    }
+}
+
+fn clip_sphere(state: ptr<function, SubVoxelHit>, ray_pos: vec3<f32>, ray_dir: vec3<f32>, center: vec3<f32>, radius: f32) {
+    let oc = ray_pos - center;
+    let a = dot(ray_dir, ray_dir);
+    let b = dot(oc, ray_dir);
+    let disc = b * b - a * (dot(oc, oc) - radius * radius);
+    if (disc < 0.0) {
+        (*state).t_exit = -1e30;
+        return;
+    }
+
+    let t0 = (-b - sqrt(disc)) / a;
+    let t1 = (-b + sqrt(disc)) / a;
+    if (t0 > (*state).t_enter) {
+        (*state).t_enter = t0;
+        (*state).normal = normalize(oc + ray_dir * t0);
+    }
+    (*state).t_exit = min((*state).t_exit, t1);
+}
+
+// Intersects a ray with the refined shape of a voxel. The ray position is relative to
+// the voxel's center. Rays starting inside the shape don't count as hitting it, which
+// stops shadow rays from hitting the voxel they leave from.
+fn sub_voxel_cast(shape: u32, ray_pos: vec3<f32>, ray_dir: vec3<f32>) -> SubVoxelHit {
+    var state = SubVoxelHit(-1e30, 1e30, vec3<f32>(0.0));
+    for (var axis: u32 = 0u; axis < 3u; axis++) {
+        var n = vec3<f32>(0.0);
+        n[axis] = 1.0;
+        clip_plane(&state, ray_pos, ray_dir, n, 0.5);
+        clip_plane(&state, ray_pos, ray_dir, -n, 0.5);
+    }
+
+    if (shape == DETAIL_SLAB) {
+        // Half height
+        clip_plane(&state, ray_pos, ray_dir, vec3<f32>(0.0, 1.0, 0.0), 0.0);
+    } else if (shape == DETAIL_BEVELLED) {
+        // Chamfer the top 4 corners
+        for (var corner: u32 = 0u; corner < 4u; corner++) {
+            let x = select(-1.0, 1.0, (corner & 1u) != 0u);
+            let z = select(-1.0, 1.0, (corner & 2u) != 0u);
+            clip_plane(&state, ray_pos, ray_dir, normalize(vec3<f32>(x, 1.0, z)), 0.4);
+        }
+    } else if (shape == DETAIL_ROUNDED) {
+        // The sphere contains the bottom half of the cube, so only the top is domed
+        clip_sphere(&state, ray_pos, ray_dir, vec3<f32>(0.0, -0.25, 0.0), 0.75);
+    }
+
+    if (state.t_enter > state.t_exit || state.t_enter < 0.0) {
+        state.t_exit = -1e30;
+    }
+    return state;
+}
+
+// Casts against the voxels of a single 2x2x2 coarse cell. Positions are local to the brick,
+// ray_origin is where the ray actually started rather than where the DDA picks it up.
+fn coarse_cell_ray_cast(
+    cell_pos: vec3<i32>,
+    brickmap_idx: u32,
+    orig_ray_pos: vec3<f32>,
+    ray_origin: vec3<f32>,
+    ray_dir: vec3<f32>,
+    entry_mask: vec3<bool>
+) -> HitInfo {
+    var hit_info = HitInfo(false, vec3<i32>(0), 0u, entry_mask, 0u, NO_MATERIAL, 0u, vec3<f32>(0.0), -1.0);
+    var ray_pos = orig_ray_pos;
+
+    let min = vec3<f32>(cell_pos * 2);
+    let max = min + vec3<f32>(2.0);
+    let aabbHit = ray_intersect_aabb(ray_pos, ray_dir, min, max);
+    if (aabbHit.distance > 0.0) {
+        ray_pos += ray_dir * aabbHit.distance - aabbHit.normal * 0.0001;
+    }
+
+    var dda_state = dda_setup(ray_pos, ray_dir);
+    let max_cell_depth = 2 + 2 + 2;
+    for (var i: i32 = 0; i < max_cell_depth; i++) {
+        if (!point_inside_aabb(dda_state.map_pos, cell_pos * 2, cell_pos * 2 + vec3<i32>(2))) {
+            break;
+        }
+        trace_steps += 1u;
+
+        if (voxel_hit(brickmap_idx, dda_state.map_pos)) {
+            var shape = DETAIL_NONE;
+            if (settings.sub_voxel_detail != 0u) {
+                shape = voxel_detail(brickmap_idx, dda_state.map_pos);
+            }
+
+            var refined_hit = true;
+            var normal = vec3<f32>(0.0);
+            if (shape != DETAIL_NONE) {
+                let center = vec3<f32>(dda_state.map_pos) + vec3<f32>(0.5);
+                let sub_hit = sub_voxel_cast(shape, ray_origin - center, ray_dir);
+                refined_hit = sub_hit.t_exit >= 0.0;
+                normal = sub_hit.normal;
+            }
+
+            if (refined_hit) {
+                hit_info.hit = true;
+                hit_info.normal = normal;
+                hit_info.hit_pos = dda_state.map_pos;
+                hit_info.brickmap_idx = brickmap_idx;
+                break;
+            }
+        }
+
+        dda_step(&dda_state);
+        hit_info.mask = dda_state.side_mask;
+    }
+
+    return hit_info;
+}
+
 fn brick_ray_cast(
     chunk_pos: vec3<i32>,
     brickmap_idx: u32,
     orig_ray_pos: vec3<f32>,
     ray_dir: vec3<f32>
 ) -> HitInfo {
-    var hit_info = HitInfo(false, vec3<i32>(0), 0u, vec3<bool>(false));
+    var hit_info = HitInfo(false, vec3<i32>(0), 0u, vec3<bool>(false), 0u, NO_MATERIAL, 0u, vec3<f32>(0.0), -1.0);
     var ray_pos = orig_ray_pos * 8.0;
 
     let min = vec3<f32>(chunk_pos * 8);
@@ -186,38 +658,210 @@ fn brick_ray_cast(
             ray_pos += ray_dir * aabbHit.distance - aabbHit.normal * 0.0001;
         }
 
-        var dda_state = dda_setup(ray_pos, ray_dir);
-        dda_state.map_pos = dda_state.map_pos % 8;
+        // We first step through the coarse occupancy grid, where each cell is 2x2x2
+        // voxels. Only occupied cells need their individual voxel bits testing.
+        let local_ray_pos = ray_pos - min;
+        let ray_origin = orig_ray_pos * 8.0 - min;
+        var coarse_state = dda_setup(local_ray_pos * 0.5, ray_dir);
 
-        let max_brick_depth = 8 + 8 + 8;
-        for (var i: i32 = 0; i < max_brick_depth; i++) {
-            if (!point_inside_aabb(dda_state.map_pos, vec3<i32>(0), vec3<i32>(8))) {
+        let max_coarse_depth = 4 + 4 + 4;
+        for (var i: i32 = 0; i < max_coarse_depth; i++) {
+            if (!point_inside_aabb(coarse_state.map_pos, vec3<i32>(0), vec3<i32>(4))) {
                 // If the ray has left the brickmap AABB there's no point in continuing
                 // to trace against it
                 break;
             }
+            trace_steps += 1u;
 
-            if (voxel_hit(brickmap_idx, dda_state.map_pos)){
-                hit_info.hit = true;
-                hit_info.hit_pos = dda_state.map_pos;
-                hit_info.brickmap_idx = brickmap_idx;
-                break;
+            if (coarse_hit(brickmap_idx, coarse_state.map_pos)) {
+                let cell_hit = coarse_cell_ray_cast(
+                    coarse_state.map_pos,
+                    brickmap_idx,
+                    local_ray_pos,
+                    ray_origin,
+                    ray_dir,
+                    hit_info.mask
+                );
+                if (cell_hit.hit) {
+                    hit_info = cell_hit;
+                    break;
+                }
             }
 
-            dda_step(&dda_state);
-            hit_info.mask = dda_state.side_mask;
+            dda_step(&coarse_state);
+            hit_info.mask = coarse_state.side_mask;
         }
     }
 
     return hit_info;
 }
 
-fn grid_cast_ray(orig_ray_pos: vec3<f32>, ray_dir: vec3<f32>) -> HitInfo {
-    var hit_info = HitInfo(false, vec3<i32>(0), 0u, vec3<bool>(false));
+// Finds the voxel a ray enters a brick at, in global voxel coordinates
+fn uniform_brick_entry(chunk_pos: vec3<i32>, orig_ray_pos: vec3<f32>, ray_dir: vec3<f32>) -> vec3<i32> {
+    var ray_pos = orig_ray_pos * 8.0;
+    let min = chunk_pos * 8;
+    let aabbHit = ray_intersect_aabb(ray_pos, ray_dir, vec3<f32>(min), vec3<f32>(min + vec3<i32>(8)));
+    if (aabbHit.hit && aabbHit.distance > 0.0) {
+        ray_pos += ray_dir * aabbHit.distance - aabbHit.normal * 0.0001;
+    }
+
+    return clamp(vec3<i32>(floor(ray_pos)), min, min + vec3<i32>(7));
+}
+
+// Matches the size of the blue noise texture
+const BLUE_NOISE_SIZE: u32 = 64u;
+// Frames a newly loaded brick takes to dither in over its LOD colour
+const BRICK_FADE_FRAMES: f32 = 16.0;
+// Width in bricks of the band around the LOD distance that the switch is dithered across
+const LOD_DITHER_BAND: f32 = 8.0;
+// Frames of history a pixel gets averaged over at most while accumulating
+const MAX_ACCUMULATED_FRAMES: u32 = 256u;
+
+// Blue noise for a pixel that changes every frame. Stepping by the golden ratio keeps
+// each pixel's thresholds evenly spread over time as well as space.
+fn pixel_dither(img_coord: vec2<u32>) -> f32 {
+    let noise = textureLoad(blue_noise, img_coord % vec2<u32>(BLUE_NOISE_SIZE), 0).r;
+    return fract(noise + f32(world_state.frame & 1023u) * 0.618034);
+}
+
+// Offset of the primary ray within its pixel. While accumulating, each frame samples a
+// different spot so the history converges on an antialiased image.
+fn pixel_jitter() -> vec2<f32> {
+    if (settings.temporal_accumulation == 0u) {
+        return vec2<f32>(0.0);
+    }
+    // R2 sequence, which covers the pixel evenly however many frames have been blended
+    return fract(vec2<f32>(0.5) + f32(settings.accumulated_frames) * vec2<f32>(0.7548777, 0.5698403)) - vec2<f32>(0.5);
+}
+
+// Blends a pixel into the running average of the frames since the accumulation was last
+// reset. The history is capped so slow changes like light probes settling still show up.
+fn accumulate(img_coord: vec2<u32>, color: vec4<f32>) -> vec4<f32> {
+    if (settings.temporal_accumulation == 0u || settings.accumulated_frames == 0u) {
+        return color;
+    }
+    let history = textureLoad(accumulation_history, img_coord, 0);
+    let frames = min(settings.accumulated_frames, MAX_ACCUMULATED_FRAMES);
+    return mix(history, color, 1.0 / f32(frames + 1u));
+}
+
+// Whether a brick is far enough from the camera to be drawn as a single colour. The
+// switch is dithered across a band, so bricks fade between the two as the camera moves
+// rather than popping.
+fn is_lod_brick(map_pos: vec3<i32>) -> bool {
+    if (settings.lod_distance <= 0.0) {
+        return false;
+    }
+    let center = vec3<f32>(map_pos) + vec3<f32>(0.5);
+    let t = (distance(center, camera_pos()) - settings.lod_distance) / LOD_DITHER_BAND + 0.5;
+    return t > dither;
+}
+
+// Whether enough frames have passed since a brickmap loaded for this pixel to draw its
+// voxels instead of its LOD colour
+fn is_faded_in(brickmap_idx: u32) -> bool {
+    let loaded_frame = brickmap_cache[brickmap_idx].loaded_frame;
+    if (loaded_frame == 0u) {
+        return true;
+    }
+    let age = f32(world_state.frame - loaded_frame);
+    return age / BRICK_FADE_FRAMES > dither;
+}
+
+// Which distance band a brick's requests count towards, see RequestBands
+fn request_band(map_pos: vec3<i32>) -> u32 {
+    let dist = distance(vec3<f32>(map_pos) + 0.5, camera_pos());
+    var band = 0u;
+    for (var i = 1u; i < 4u; i++) {
+        if (dist >= cpu_feedback.band_starts[i]) {
+            band = i;
+        }
+    }
+    return band;
+}
+
+// Adds a brick to the CPU's load queue, unless it's full, the brick is already queued or
+// its distance band has used up its quota for the frame. Heavy atomic use here because
+// multiple shader dispatches might be trying to add the same brickmap
+fn request_brick(grid_idx: u32, map_pos: vec3<i32>, lod_only: bool) {
+    if (atomicLoad(&cpu_feedback.count) >= cpu_feedback.max_count) {
+        return;
+    }
+
+    // This is checking that in the time since the flags were calculated another dispatch
+    // hasn't already started loading the brickmap
+    if ((atomicOr(&brickgrid[grid_idx], 2u) & 0x2u) == 0u) {
+        // Far bricks past their band's quota wait for a later frame, so rays sweeping
+        // across the horizon can't crowd out the bricks close to the camera
+        let band = request_band(map_pos);
+        let quota = cpu_feedback.band_quotas[band];
+        if (atomicAdd(&cpu_feedback.band_counts[band], 1u) >= quota && quota != 0u) {
+            atomicSub(&cpu_feedback.band_counts[band], 1u);
+            atomicXor(&brickgrid[grid_idx], 2u);
+            return;
+        }
+
+        // If there's still space in the queue at this point, add the brickmap. Otherwise,
+        // revert any changes made
+        let index = atomicAdd(&cpu_feedback.count, 1u);
+        if (index < cpu_feedback.max_count) {
+            let request = vec4<u32>(bitcast<vec3<u32>>(map_pos), u32(lod_only));
+            for (var i: u32 = 0u; i < 4u; i++) {
+                atomicStore(&cpu_feedback.data[index * 4u + i], request[i]);
+            }
+        }
+        else {
+            atomicSub(&cpu_feedback.count, 1u);
+            atomicSub(&cpu_feedback.band_counts[band], 1u);
+            atomicXor(&brickgrid[grid_idx], 2u);
+        }
+    }
+}
+
+// Flags a brickmap as seen this frame, so the CPU knows not to evict it
+fn mark_visible(brickmap_idx: u32) {
+    let word = cpu_feedback.visibility_offset + brickmap_idx / 32u;
+    let bit = 1u << (brickmap_idx % 32u);
+    // Neighbouring rays mostly pass through the same bricks, so only write if it's new
+    if ((atomicLoad(&cpu_feedback.data[word]) & bit) == 0u) {
+        atomicOr(&cpu_feedback.data[word], bit);
+    }
+}
+
+// Matches Brickgrid::MIP_LEVELS. Level n covers 2^(n+1) bricks along each axis.
+const BRICKGRID_MIP_LEVELS: i32 = 4;
 
-    let min = vec3<f32>(0.0);
-    let max = min + vec3<f32>(world_state.brickgrid_dims);
-    let aabbHit = ray_intersect_aabb(orig_ray_pos, ray_dir, min, max);
+// Size in bricks of the largest empty mip cell around a brick, or 0 if even the smallest
+// one has something in it. The grid's size is a multiple of the largest cell, so cells
+// line up with the world however the bricks wrap around it.
+fn empty_mip_cell_size(map_pos: vec3<i32>) -> i32 {
+    let grid_pos = wrap(map_pos, vec3<i32>(world_state.brickgrid_dims));
+    for (var level: i32 = BRICKGRID_MIP_LEVELS - 1; level >= 0; level--) {
+        let cell = grid_pos >> vec3<u32>(u32(level + 1));
+        if (textureLoad(brickgrid_mips, cell, level).r == 0u) {
+            return 1i << u32(level + 1);
+        }
+    }
+    return 0;
+}
+
+// Distance along a ray to where it leaves an axis aligned cell it's inside of, along with
+// which face it leaves through
+fn cell_exit(ray_pos: vec3<f32>, ray_dir: vec3<f32>, cell_min: vec3<f32>, cell_size: f32) -> vec4<f32> {
+    let bound = cell_min + select(vec3<f32>(0.0), vec3<f32>(cell_size), ray_dir > vec3<f32>(0.0));
+    let t = select(vec3<f32>(1e30), (bound - ray_pos) / ray_dir, ray_dir != vec3<f32>(0.0));
+    let t_exit = min(t.x, min(t.y, t.z));
+    return vec4<f32>(vec3<f32>(t == vec3<f32>(t_exit)), t_exit);
+}
+
+// Primary rays set `request_bricks`, so they load the bricks they need and mark the ones
+// they pass through as visible
+fn grid_cast_ray(orig_ray_pos: vec3<f32>, ray_dir: vec3<f32>, request_bricks: bool) -> HitInfo {
+    var hit_info = HitInfo(false, vec3<i32>(0), 0u, vec3<bool>(false), 0u, NO_MATERIAL, 0u, vec3<f32>(0.0), -1.0);
+
+    let grid_min = world_state.grid_origin;
+    let grid_max = grid_min + vec3<i32>(world_state.brickgrid_dims);
+    let aabbHit = ray_intersect_aabb(orig_ray_pos, ray_dir, vec3<f32>(grid_min), vec3<f32>(grid_max));
     var ray_pos = orig_ray_pos;
     if (aabbHit.hit) {
         // distance is greater than 0 if the ray is outside of the AABB, so we need to
@@ -230,50 +874,79 @@ fn grid_cast_ray(orig_ray_pos: vec3<f32>, ray_dir: vec3<f32>) -> HitInfo {
 
         let dims = world_state.brickgrid_dims;
         let max_grid_depth = i32(dims.x + dims.y + dims.z);
-        for (var i: i32 = 0; i < max_grid_depth; i++) {
-            if (!point_inside_aabb(dda_state.map_pos, vec3<i32>(0), vec3<i32>(world_state.brickgrid_dims))) {
+        let max_steps = select(max_grid_depth, i32(settings.max_ray_steps), settings.max_ray_steps > 0u);
+        var i: i32 = 0;
+        for (; i < max_steps; i++) {
+            if (!point_inside_aabb(dda_state.map_pos, grid_min, grid_max)) {
                 // If the ray has left the brickmap AABB there's no point in continuing
                 // to trace against it
                 break;
             }
 
-            let grid_idx = to_1d_index(dda_state.map_pos, vec3<i32>(world_state.brickgrid_dims));
+            let brick_center = vec3<f32>(dda_state.map_pos) + vec3<f32>(0.5);
+            if (settings.max_ray_distance > 0.0 && distance(brick_center, orig_ray_pos) > settings.max_ray_distance) {
+                ray_out_of_budget = true;
+                break;
+            }
+
+            trace_steps += 1u;
+            let grid_idx = brickgrid_index(dda_state.map_pos);
             let brick_ptr = brickgrid[grid_idx];
             
-            // Ptr = 28 bits LOD colour / brickmap index + 4 bits load flags
+            // Ptr = 24 bits colour / material / brickmap index + 8 bits load flags
             // Flags:
             // 0 = empty
             // 1 = unloaded
             // 2 = loading
             // 4 = loaded
+            // 5 = lod, average colour only
+            // 8 = uniform, a single material
             let flags = brick_ptr & 0xFu;
+            let lod = is_lod_brick(dda_state.map_pos);
             if flags == 1u {
                 // The brickmap we're in is currently unloaded so we'll try and add it
-                // to the load queue. Heavy atomic use here because multiple shader
-                // dispatches might be trying to add the same brickmap
-                if (atomicLoad(&cpu_feedback.count) < cpu_feedback.max_count) {
-                    // This is checking that in the time since the flags were calculated
-                    // another dispatch hasn't already started loading the brickmap
-                    if ((atomicOr(&brickgrid[grid_idx], 2u) & 0x2u) == 0u) {
-                        // If there's still space in the queue at this point, add the
-                        // brickmap. Otherwise, revert any changes made
-                        let index = atomicAdd(&cpu_feedback.count, 1u);
-                        if (index < cpu_feedback.max_count) {
-                            cpu_feedback.positions[index] = vec4<i32>(dda_state.map_pos, 0);
-                        }
-                        else {
-                            atomicSub(&cpu_feedback.count, 1u);
-                            atomicXor(&brickgrid[grid_idx], 2u);
-                        }
-                    }
+                // to the load queue. Far away bricks only need their colour.
+                if (request_bricks) {
+                    request_brick(grid_idx, dda_state.map_pos, lod);
                 }
 
                 // TODO: Set hit info stuff?
                 break;
             }
+            else if (flags & ~2u) == 5u {
+                // We only know the brick's colour, which is fine until the camera gets
+                // close. Then we need its voxels, but keep drawing the colour until they
+                // arrive.
+                if (flags == 5u && !lod && request_bricks) {
+                    request_brick(grid_idx, dda_state.map_pos, false);
+                }
+
+                trace_bricks += 1u;
+                hit_info.hit = true;
+                hit_info.hit_pos = uniform_brick_entry(dda_state.map_pos, orig_ray_pos, ray_dir);
+                hit_info.albedo = ((brick_ptr >> 8u) << 8u) | 255u;
+                break;
+            }
+            else if flags == 4u && (lod || !is_faded_in(brick_ptr >> 8u)) {
+                // Loaded bricks far away are drawn as a solid block of their surface
+                // colour, as are ones that have only just loaded while they fade in
+                trace_bricks += 1u;
+                let brickmap_idx = brick_ptr >> 8u;
+                if (request_bricks) {
+                    mark_visible(brickmap_idx);
+                }
+                hit_info.hit = true;
+                hit_info.hit_pos = uniform_brick_entry(dda_state.map_pos, orig_ray_pos, ray_dir);
+                hit_info.albedo = brickmap_cache[brickmap_idx].lod_color;
+                break;
+            }
             else if flags == 4u {
                 // The brickmap is loaded so we try and cast against it
+                trace_bricks += 1u;
                 let brickmap_idx = brick_ptr >> 8u;
+                if (request_bricks) {
+                    mark_visible(brickmap_idx);
+                }
                 let tmp_voxel_hit = brick_ray_cast(dda_state.map_pos, brickmap_idx, orig_ray_pos, ray_dir);
 
                 // If we hit a voxel in the brickmap, update hitinfo and stop casting
@@ -282,39 +955,736 @@ fn grid_cast_ray(orig_ray_pos: vec3<f32>, ray_dir: vec3<f32>) -> HitInfo {
                     hit_info.hit_pos = tmp_voxel_hit.hit_pos + (dda_state.map_pos * 8);
                     hit_info.mask = tmp_voxel_hit.mask;
                     hit_info.brickmap_idx = tmp_voxel_hit.brickmap_idx;
+                    hit_info.normal = tmp_voxel_hit.normal;
+                    let shading = shading_table[get_shading_offset(hit_info)].data;
+                    hit_info.material = shading & 0xFFFFu;
+                    hit_info.ao = shading >> 16u;
+                    hit_info.albedo = materials.entries[hit_info.material].albedo;
                     break;
                 }
             }
+            else if flags == 8u {
+                // Every voxel in the brick is the same material, so whichever voxel the
+                // ray enters the brick at is our hit
+                trace_bricks += 1u;
+                hit_info.hit = true;
+                hit_info.hit_pos = uniform_brick_entry(dda_state.map_pos, orig_ray_pos, ray_dir);
+                hit_info.material = brick_ptr >> 8u;
+                hit_info.albedo = materials.entries[hit_info.material].albedo;
+                break;
+            }
+
+            else if flags == 0u {
+                // Empty bricks tend to come in big groups, so jump over the largest
+                // empty mip cell we're in rather than stepping through each brick of it
+                let cell_size = empty_mip_cell_size(dda_state.map_pos);
+                if (cell_size > 1) {
+                    let cell_min = floor(vec3<f32>(dda_state.map_pos) / f32(cell_size)) * f32(cell_size);
+                    let exit = cell_exit(ray_pos, ray_dir, cell_min, f32(cell_size));
+                    dda_state = dda_setup(ray_pos + ray_dir * (exit.w + 0.0001), ray_dir);
+                    hit_info.mask = exit.xyz > vec3<f32>(0.0);
+                    continue;
+                }
+            }
 
             dda_step(&dda_state);
             hit_info.mask = dda_state.side_mask;
         }
+
+        if (i >= max_steps) {
+            ray_out_of_budget = true;
+        }
     }
 
     return hit_info;
 }
 
-@compute @workgroup_size(8, 8, 1)
-fn compute(@builtin(global_invocation_id) global_id: vec3<u32>) {
-    let img_coord = global_id.xy;
-    let img_dims = textureDimensions(output);
+// How far along a ray the voxel it hit is, in brick units
+fn hit_distance(hit: HitInfo, ray_pos: vec3<f32>, ray_dir: vec3<f32>) -> f32 {
+    if (hit.distance >= 0.0) {
+        return hit.distance;
+    }
+    let min = vec3<f32>(hit.hit_pos);
+    let aabbHit = ray_intersect_aabb(ray_pos * 8.0, ray_dir, min, min + vec3<f32>(1.0));
+    return max(aabbHit.distance, 0.0) / 8.0;
+}
 
-    // This discards the extra pixels in cases where the image size isn't perfectly divisible by the kernel.xy
-    if (img_coord.x >= img_dims.x || img_coord.y >= img_dims.y) {
+fn rotate_by_quat(q: vec4<f32>, v: vec3<f32>) -> vec3<f32> {
+    let t = 2.0 * cross(q.xyz, v);
+    return v + q.w * t + cross(q.xyz, t);
+}
+
+// Steps a ray through one of the volumes placed over the terrain, if it meets the
+// volume's bounding box within `max_distance`. Distances are in bricks like the ray.
+// The ray is moved into the volume's own space, where it's a box from 0 to its dims, so
+// the volume can be placed and rotated however without touching its voxels
+fn volume_cast_ray(volume: Volume, ray_pos: vec3<f32>, ray_dir: vec3<f32>, max_distance: f32) -> HitInfo {
+    var hit_info = HitInfo(false, vec3<i32>(0), 0u, vec3<bool>(false), 0u, NO_MATERIAL, 0u, vec3<f32>(0.0), -1.0);
+    let half_dims = vec3<f32>(volume.dims) * 0.5;
+    let origin = rotate_by_quat(volume.inverse_rotation, ray_pos * 8.0 - volume.center) + half_dims;
+    let dir = rotate_by_quat(volume.inverse_rotation, ray_dir);
+    let aabb_hit = ray_intersect_aabb(origin, dir, vec3<f32>(0.0), half_dims * 2.0);
+    if (!aabb_hit.hit || aabb_hit.distance > max_distance * 8.0) {
+        return hit_info;
+    }
+    var pos = origin;
+    if (aabb_hit.distance > 0.0) {
+        pos += dir * aabb_hit.distance - aabb_hit.normal * 0.0001;
+    }
+
+    var dda_state = dda_setup(pos, dir);
+    hit_info.mask = aabb_hit.normal != vec3<f32>(0.0);
+    let dims = vec3<i32>(volume.dims);
+    let max_steps = dims.x + dims.y + dims.z;
+    for (var i: i32 = 0; i < max_steps; i++) {
+        let local = dda_state.map_pos;
+        if (!point_inside_aabb(local, vec3<i32>(0), dims)) {
+            break;
+        }
+        trace_steps += 1u;
+
+        let voxel = textureLoad(volume_atlas, vec3<i32>(volume.atlas_offset) + local, 0).x;
+        if (voxel != 0u) {
+            hit_info.hit = true;
+            hit_info.material = voxel - 1u;
+            hit_info.albedo = materials.entries[hit_info.material].albedo;
+            break;
+        }
+
+        dda_step(&dda_state);
+        hit_info.mask = dda_state.side_mask;
+    }
+    if (!hit_info.hit) {
+        return hit_info;
+    }
+
+    // Everything else expects world voxels, so the hit is taken back out of the volume.
+    // Rotations keep lengths, so distances come out the same
+    let local = vec3<f32>(dda_state.map_pos);
+    let voxel_hit = ray_intersect_aabb(origin, dir, local, local + vec3<f32>(1.0));
+    hit_info.distance = max(voxel_hit.distance, 0.0) / 8.0;
+    let rotation = vec4<f32>(-volume.inverse_rotation.xyz, volume.inverse_rotation.w);
+    let center = volume.center + rotate_by_quat(rotation, local + vec3<f32>(0.5) - half_dims);
+    hit_info.hit_pos = vec3<i32>(floor(center));
+    if (any(volume.inverse_rotation.xyz != vec3<f32>(0.0))) {
+        hit_info.normal = rotate_by_quat(rotation, -sign(dir) * vec3<f32>(hit_info.mask));
+    }
+    return hit_info;
+}
+
+// Casts a ray against the terrain, then against every volume whose bounding box it meets
+// before the terrain hit, keeping the nearest hit
+fn cast_ray(ray_pos: vec3<f32>, ray_dir: vec3<f32>, request_bricks: bool) -> HitInfo {
+    var hit_info = grid_cast_ray(ray_pos, ray_dir, request_bricks);
+    var nearest = MISS_DEPTH;
+    if (hit_info.hit) {
+        nearest = hit_distance(hit_info, ray_pos, ray_dir);
+    }
+
+    for (var i: u32 = 0u; i < min(world_state.volume_count, MAX_VOLUMES); i++) {
+        let volume_hit = volume_cast_ray(world_state.volumes[i], ray_pos, ray_dir, nearest);
+        if (volume_hit.hit) {
+            let distance = hit_distance(volume_hit, ray_pos, ray_dir);
+            if (distance < nearest) {
+                hit_info = volume_hit;
+                nearest = distance;
+            }
+        }
+    }
+    return hit_info;
+}
+
+struct PortalCrossing {
+    portal_idx: u32,
+    distance: f32,
+}
+
+// Finds the nearest portal whose front the ray crosses. The distance is negative if
+// there isn't one.
+fn nearest_portal(ray_pos: vec3<f32>, ray_dir: vec3<f32>) -> PortalCrossing {
+    var crossing = PortalCrossing(0u, -1.0);
+    for (var i: u32 = 0u; i < min(portals.count, MAX_PORTALS); i++) {
+        let portal = portals.portals[i];
+        let normal = cross(portal.right, portal.up);
+        let denom = dot(normal, ray_dir);
+        if (denom >= 0.0) {
+            continue;
+        }
+
+        let t = dot(portal.position - ray_pos, normal) / denom;
+        if (t <= 0.0 || (crossing.distance >= 0.0 && t >= crossing.distance)) {
+            continue;
+        }
+
+        let offset = ray_pos + ray_dir * t - portal.position;
+        if (abs(dot(offset, portal.right)) <= portal.half_width && abs(dot(offset, portal.up)) <= portal.half_height) {
+            crossing = PortalCrossing(i, t);
+        }
+    }
+    return crossing;
+}
+
+const MAX_PORTAL_CROSSINGS: u32 = 4u;
+const MISS_DEPTH: f32 = 1e30;
+
+// Depth is the distance along the primary ray in bricks. Reduced rate pixels fill their
+// whole 2x2 block, as nothing else will write the other 3.
+fn write_depth(img_coord: vec2<u32>, img_dims: vec2<u32>, depth: f32, surface: vec3<f32>) {
+    let value = vec4<f32>(depth, surface);
+    if (is_full_rate(img_coord, img_dims)) {
+        textureStore(depth_output, img_coord, value);
         return;
     }
 
-    // Construct ray
-    let img_coord_frac = vec2<f32>(img_coord) / vec2<f32>(img_dims);
+    for (var i: u32 = 0u; i < 4u; i++) {
+        let coord = img_coord + vec2<u32>(i % 2u, i / 2u);
+        if (all(coord < img_dims)) {
+            textureStore(depth_output, coord, value);
+        }
+    }
+}
+
+// Packs a unit vector into two components by folding an octahedron flat
+fn encode_normal(n: vec3<f32>) -> vec2<f32> {
+    let p = n.xy / (abs(n.x) + abs(n.y) + abs(n.z));
+    if (n.z < 0.0) {
+        return (vec2<f32>(1.0) - abs(p.yx)) * select(vec2<f32>(-1.0), vec2<f32>(1.0), p >= vec2<f32>(0.0));
+    }
+    return p;
+}
+
+// What the blit's outlines need to know about a hit: its normal and which voxel it was.
+// The id is a hash cut down to fit exactly in a float
+fn describe_surface(hit: HitInfo, ray_dir: vec3<f32>) -> vec3<f32> {
+    if (!hit.hit) {
+        return vec3<f32>(0.0);
+    }
+    let pos = vec3<u32>(hit.hit_pos);
+    let id = hash_u32(pos.x ^ hash_u32(pos.y ^ hash_u32(pos.z))) & 0x7FFFFFu;
+    return vec3<f32>(encode_normal(hit_normal(hit, ray_dir)), f32(id));
+}
+
+// Reduced rate pixels aren't traced, so picks there use the traced pixel they get
+// interpolated from
+fn is_pick_pixel(img_coord: vec2<u32>, img_dims: vec2<u32>) -> bool {
+    var pixel = pick.pixel;
+    if (!is_full_rate(pixel, img_dims)) {
+        pixel = (pixel / 2u) * 2u;
+    }
+    return all(img_coord == pixel);
+}
+
+// Result is {hit, x, y, z}, {normal x, normal y, normal z, albedo}
+fn write_pick_result(hit: HitInfo, ray_dir: vec3<f32>) {
+    let normal = vec3<i32>(-sign(ray_dir) * vec3<f32>(hit.mask));
+    textureStore(pick_result, vec2<u32>(0u, 0u), vec4<i32>(i32(hit.hit), hit.hit_pos));
+    textureStore(pick_result, vec2<u32>(1u, 0u), vec4<i32>(normal, bitcast<i32>(hit.albedo)));
+}
+
+// Is the pixel inside the region that gets traced at full rate?
+fn is_full_rate(img_coord: vec2<u32>, img_dims: vec2<u32>) -> bool {
+    if (settings.variable_rate == 0u) {
+        return true;
+    }
+
+    let center = vec2<f32>(img_dims) * 0.5;
+    let dist = length(vec2<f32>(img_coord) - center) / center.y;
+    return dist <= settings.full_rate_radius;
+}
+
+const PROBE_RAYS_PER_DIRECTION: u32 = 2u;
+const PROBE_HYSTERESIS: f32 = 0.9;
+
+fn unpack_albedo(raw_color: u32) -> vec4<f32> {
+    return vec4<f32>(
+        f32((raw_color >> 24u) & 255u) / 255.0,
+        f32((raw_color >> 16u) & 255u) / 255.0,
+        f32((raw_color >> 8u) & 255u) / 255.0,
+        f32(raw_color & 255u) / 255.0
+    );
+}
+
+fn hit_normal(hit: HitInfo, ray_dir: vec3<f32>) -> vec3<f32> {
+    if (any(hit.normal != vec3<f32>(0.0))) {
+        return hit.normal;
+    }
+    return -sign(ray_dir) * vec3<f32>(hit.mask);
+}
+
+// Blends every decal covering the hit face over its albedo, in order
+fn apply_decals(hit: HitInfo, ray_dir: vec3<f32>, albedo: vec4<f32>) -> vec4<f32> {
+    let normal = -sign(ray_dir) * vec3<f32>(hit.mask);
+    var face = 0u;
+    if (hit.mask.y) {
+        face = 2u;
+    } else if (hit.mask.z) {
+        face = 4u;
+    }
+    if (dot(normal, vec3<f32>(1.0)) < 0.0) {
+        face += 1u;
+    }
+
+    var color = albedo;
+    for (var i: u32 = 0u; i < decals.count; i++) {
+        let decal = decals.decals[i];
+        if ((decal.face_mask & (1u << face)) != 0u && point_inside_aabb(hit.hit_pos, decal.min, decal.max)) {
+            let decal_color = unpack_albedo(decal.color);
+            color = vec4<f32>(mix(color.xyz, decal_color.xyz, decal_color.w), color.w);
+        }
+    }
+    return color;
+}
+
+// Wet surfaces are darker, especially ones facing up that puddles collect on
+fn wet_albedo(albedo: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    let exposure = 0.5 + 0.5 * max(normal.y, 0.0);
+    return albedo * (1.0 - 0.4 * atmosphere.wetness * exposure);
+}
+
+// Misses are treated as being SKY_FOG_DISTANCE bricks away
+const SKY_FOG_DISTANCE: f32 = 64.0;
+// Colour the view under water fades to, before it's lit by the sky
+const WATER_COLOR: vec3<f32> = vec3<f32>(0.05, 0.2, 0.25);
+// Reflectance of water looking straight down at it
+const WATER_F0: f32 = 0.02;
+
+// Scales the sky model's luminance, which is in kcd/m², to sit alongside a sun of
+// intensity 1
+const SKY_LUMINANCE_SCALE: f32 = 0.06;
+// What's left of the sky once the sun is well below the horizon
+const NIGHT_SKY_COLOR: vec3<f32> = vec3<f32>(0.004, 0.006, 0.012);
+// Cosine of the sun disc's angular radius, a little bigger than the real sun's
+const SUN_DISC_COS: f32 = 0.99994;
+const SUN_DISC_BRIGHTNESS: f32 = 10.0;
+
+// Perez et al's sky distribution for luminance and the two chromaticities at once, from
+// the view's angle to the zenith and its angle `gamma` to the sun
+fn perez(cos_theta: f32, gamma: f32, cos_gamma: f32, a: vec3<f32>, b: vec3<f32>, c: vec3<f32>, d: vec3<f32>, e: vec3<f32>) -> vec3<f32> {
+    return (vec3<f32>(1.0) + a * exp(b / max(cos_theta, 0.01))) * (vec3<f32>(1.0) + c * exp(d * gamma) + e * cos_gamma * cos_gamma);
+}
+
+// Light from clear sky in direction `dir`, not counting the sun itself, using Preetham's
+// daylight model. It only holds with the sun above the horizon, so the sun is kept just
+// above it and the whole sky fades out as it sets. Below the horizon the sky fades
+// towards dark ground.
+fn sky_radiance(dir: vec3<f32>) -> vec3<f32> {
+    if (atmosphere.sky_enabled == 0u) {
+        return atmosphere.sky_color;
+    }
+
+    let t = atmosphere.turbidity;
+    let sun_dir = normalize(atmosphere.sun_direction);
+    let sun_above = normalize(vec3<f32>(sun_dir.x, max(sun_dir.y, 0.01), sun_dir.z));
+    let view = normalize(vec3<f32>(dir.x, max(dir.y, 0.0), dir.z) + vec3<f32>(0.0, 1e-4, 0.0));
+    let theta_s = acos(sun_above.y);
+    let cos_gamma = clamp(dot(view, sun_above), -1.0, 1.0);
+    let gamma = acos(cos_gamma);
+
+    // Coefficients for luminance, then x and y chromaticity
+    let a = vec3<f32>(0.1787 * t - 1.4630, -0.0193 * t - 0.2592, -0.0167 * t - 0.2608);
+    let b = vec3<f32>(-0.3554 * t + 0.4275, -0.0665 * t + 0.0008, -0.0950 * t + 0.0092);
+    let c = vec3<f32>(-0.0227 * t + 5.3251, -0.0004 * t + 0.2125, -0.0079 * t + 0.2102);
+    let d = vec3<f32>(0.1206 * t - 2.5771, -0.0641 * t - 0.8989, -0.0441 * t - 1.6537);
+    let e = vec3<f32>(-0.0670 * t + 0.3703, -0.0033 * t + 0.0452, -0.0109 * t + 0.0529);
+
+    // The sky straight up
+    let chi = (4.0 / 9.0 - t / 120.0) * (3.14159265 - 2.0 * theta_s);
+    let ts = vec4<f32>(theta_s * theta_s * theta_s, theta_s * theta_s, theta_s, 1.0);
+    let zenith = vec3<f32>(
+        (4.0453 * t - 4.9710) * tan(chi) - 0.2155 * t + 2.4192,
+        t * t * dot(vec4<f32>(0.00166, -0.00375, 0.00209, 0.0), ts)
+            + t * dot(vec4<f32>(-0.02903, 0.06377, -0.03202, 0.00394), ts)
+            + dot(vec4<f32>(0.11693, -0.21196, 0.06052, 0.25886), ts),
+        t * t * dot(vec4<f32>(0.00275, -0.00610, 0.00317, 0.0), ts)
+            + t * dot(vec4<f32>(-0.04214, 0.08970, -0.04153, 0.00516), ts)
+            + dot(vec4<f32>(0.15346, -0.26756, 0.06670, 0.26688), ts),
+    );
+    let xyy = zenith * perez(view.y, gamma, cos_gamma, a, b, c, d, e) / perez(1.0, theta_s, sun_above.y, a, b, c, d, e);
+
+    // xyY to XYZ to linear sRGB
+    let luminance = max(xyy.x, 0.0) * SKY_LUMINANCE_SCALE;
+    let xyz = vec3<f32>(xyy.y / xyy.z, 1.0, (1.0 - xyy.y - xyy.z) / xyy.z) * luminance;
+    let xyz_to_rgb = mat3x3<f32>(
+        vec3<f32>(3.2406, -0.9689, 0.0557),
+        vec3<f32>(-1.5372, 1.8758, -0.2040),
+        vec3<f32>(-0.4986, 0.0415, 1.0570),
+    );
+    var color = max(xyz_to_rgb * xyz, vec3<f32>(0.0));
+
+    color = color * smoothstep(-0.15, 0.05, sun_dir.y) + NIGHT_SKY_COLOR;
+    if (dir.y < 0.0) {
+        color *= mix(1.0, 0.3, smoothstep(0.0, 0.2, -dir.y));
+    }
+    return color;
+}
+
+// The sky with the sun's disc in it, for rays that escape the world
+fn sky_color(dir: vec3<f32>) -> vec3<f32> {
+    var color = sky_radiance(dir);
+    if (atmosphere.sky_enabled != 0u && dot(dir, normalize(atmosphere.sun_direction)) > SUN_DISC_COS) {
+        color += sun.color * sun.intensity * SUN_DISC_BRIGHTNESS;
+    }
+    return color;
+}
+
+fn apply_fog(color: vec3<f32>, depth: f32) -> vec3<f32> {
+    let fog = 1.0 - exp(-atmosphere.fog_density * min(depth, SKY_FOG_DISTANCE));
+    return mix(color, atmosphere.fog_color, fog);
+}
+
+// PCG hash, used to get cheap random numbers in shaders
+fn hash_u32(value: u32) -> u32 {
+    let state = value * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+fn random_f32(seed: ptr<function, u32>) -> f32 {
+    *seed = hash_u32(*seed);
+    return f32(*seed) / 4294967295.0;
+}
+
+// Random value in [0, 1] for a lattice point
+fn hash_lattice(p: vec3<i32>) -> f32 {
+    let h = hash_u32(bitcast<u32>(p.x) ^ hash_u32(bitcast<u32>(p.y) ^ hash_u32(bitcast<u32>(p.z))));
+    return f32(h) / 4294967295.0;
+}
+
+// Value noise in [0, 1], smoothly interpolated between lattice points
+fn value_noise(p: vec3<f32>) -> f32 {
+    let cell = vec3<i32>(floor(p));
+    let f = fract(p);
+    let w = f * f * (3.0 - 2.0 * f);
+    let x00 = mix(hash_lattice(cell), hash_lattice(cell + vec3<i32>(1, 0, 0)), w.x);
+    let x10 = mix(hash_lattice(cell + vec3<i32>(0, 1, 0)), hash_lattice(cell + vec3<i32>(1, 1, 0)), w.x);
+    let x01 = mix(hash_lattice(cell + vec3<i32>(0, 0, 1)), hash_lattice(cell + vec3<i32>(1, 0, 1)), w.x);
+    let x11 = mix(hash_lattice(cell + vec3<i32>(0, 1, 1)), hash_lattice(cell + vec3<i32>(1, 1, 1)), w.x);
+    return mix(mix(x00, x10, w.y), mix(x01, x11, w.y), w.z);
+}
+
+// Albedo multiplier for a point on a surface in voxels. Two octaves of value noise give
+// broad patches with finer grain on top. The point is nudged back into the voxel so
+// faces meeting at an edge agree on the pattern along it
+fn surface_detail(hit_point: vec3<f32>, normal: vec3<f32>) -> f32 {
+    let p = (hit_point - normal * 0.01) / settings.surface_detail_scale;
+    let noise = value_noise(p) * 0.65 + value_noise(p * 4.0 + 17.0) * 0.35;
+    return 1.0 + settings.surface_detail_intensity * (noise * 2.0 - 1.0);
+}
+
+fn probe_irradiance(probe_idx: u32, normal: vec3<f32>) -> vec3<f32> {
+    let probe = &light_probes[probe_idx];
+    let n2 = normal * normal;
+    let x = select((*probe).irradiance[1], (*probe).irradiance[0], normal.x >= 0.0);
+    let y = select((*probe).irradiance[3], (*probe).irradiance[2], normal.y >= 0.0);
+    let z = select((*probe).irradiance[5], (*probe).irradiance[4], normal.z >= 0.0);
+    return (x * n2.x + y * n2.y + z * n2.z).xyz;
+}
+
+// Trilinearly interpolates the irradiance of the 8 probes surrounding a position. The
+// position is in brick space. Probes wrap around their grid like bricks do the brickgrid.
+fn sample_irradiance(pos: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    let dims = vec3<i32>(probe_grid.dims);
+    let probe_pos = pos / f32(probe_grid.spacing);
+    let base = vec3<i32>(floor(probe_pos));
+    let w = fract(probe_pos);
+
+    var irradiance = vec3<f32>(0.0);
+    for (var i: u32 = 0u; i < 8u; i++) {
+        let offset = vec3<u32>(i & 1u, (i >> 1u) & 1u, (i >> 2u) & 1u);
+        let p = wrap(base + vec3<i32>(offset), dims);
+        let weights = mix(vec3<f32>(1.0) - w, w, vec3<f32>(offset));
+        let weight = weights.x * weights.y * weights.z;
+        irradiance += probe_irradiance(to_1d_index(p, dims), normal) * weight;
+    }
+
+    return irradiance;
+}
+
+// Incrementally updates a window of light probes each frame. Each probe traces a few rays
+// around each of its ambient cube directions and blends the result into its history.
+// Hits are lit from the probes themselves, so light bounces accumulate over time.
+@compute @workgroup_size(64, 1, 1)
+fn update_probes(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    if (global_id.x >= probe_grid.update_count) {
+        return;
+    }
+
+    let dims = probe_grid.dims;
+    let probe_count = dims.x * dims.y * dims.z;
+    let probe_idx = (probe_grid.update_offset + global_id.x) % probe_count;
+    let probe_cell = vec3<i32>(vec3<u32>(
+        probe_idx % dims.x,
+        (probe_idx / dims.x) % dims.y,
+        probe_idx / (dims.x * dims.y)
+    ));
+
+    // Each slot holds whichever probe inside the brickgrid wraps around onto it. A slot
+    // that's been scrolled onto a new probe starts its history over
+    let grid_dims = vec3<i32>(dims);
+    let spacing = vec3<i32>(i32(probe_grid.spacing));
+    let first_probe = (world_state.grid_origin - wrap(world_state.grid_origin, spacing)) / spacing;
+    let probe_pos = first_probe + wrap(probe_cell - first_probe, grid_dims);
+    let lap = (probe_pos - wrap(probe_pos, grid_dims)) / grid_dims;
+    let history_key = f32(u32(lap.x & 255) | (u32(lap.y & 255) << 8u) | (u32(lap.z & 255) << 16u)) + 1.0;
+    let origin = vec3<f32>(probe_pos * spacing) + vec3<f32>(0.01);
+
+    var seed = hash_u32(probe_idx ^ world_state.seed);
+    for (var dir: u32 = 0u; dir < 6u; dir++) {
+        var axis = vec3<f32>(0.0);
+        axis[dir / 2u] = select(1.0, -1.0, (dir % 2u) == 1u);
+
+        var radiance = vec3<f32>(0.0);
+        for (var i: u32 = 0u; i < PROBE_RAYS_PER_DIRECTION; i++) {
+            let jitter = vec3<f32>(random_f32(&seed), random_f32(&seed), random_f32(&seed));
+            let ray_dir = normalize(axis + (jitter * 2.0 - vec3<f32>(1.0)) * 0.9);
+
+            // Probes shouldn't pull in bricks nobody is looking at
+            let hit = cast_ray(origin, ray_dir, false);
+            if (hit.hit) {
+                let normal = hit_normal(hit, ray_dir);
+                let hit_pos = (vec3<f32>(hit.hit_pos) + vec3<f32>(0.5) + normal) / 8.0;
+                radiance += unpack_albedo(hit.albedo).xyz * sample_irradiance(hit_pos, normal) + hit_emission(hit);
+            } else {
+                radiance += sky_radiance(ray_dir);
+            }
+        }
+        radiance /= f32(PROBE_RAYS_PER_DIRECTION);
+
+        let history = light_probes[probe_idx].irradiance[dir];
+        var blended = radiance;
+        if (history.w == history_key) {
+            blended = mix(radiance, history.xyz, PROBE_HYSTERESIS);
+        }
+        light_probes[probe_idx].irradiance[dir] = vec4<f32>(blended, history_key);
+    }
+}
+
+const MAX_RESERVOIR_HISTORY: u32 = 20u;
+
+// Unshadowed contribution at a surface of a light shining from `light_pos`, which is
+// somewhere on it. Positions are in voxel space.
+fn light_radiance(light: Light, light_pos: vec3<f32>, pos: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    let to_light = light_pos - pos;
+    let dist2 = max(dot(to_light, to_light), 1.0);
+    let cos_theta = max(dot(normal, to_light * inverseSqrt(dist2)), 0.0);
+    return light.color * light.intensity * cos_theta / dist2;
+}
+
+fn luminance(c: vec3<f32>) -> f32 {
+    return dot(c, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+// Target function the reservoirs resample towards
+fn light_target_pdf(light_idx: u32, pos: vec3<f32>, normal: vec3<f32>, albedo: vec3<f32>) -> f32 {
+    let light = lights[light_idx];
+    return luminance(albedo * light_radiance(light, light_position(light), pos, normal));
+}
+
+fn reservoir_update(
+    r: ptr<function, Reservoir>,
+    light_idx: u32,
+    weight: f32,
+    count: u32,
+    seed: ptr<function, u32>
+) {
+    (*r).weight_sum += weight;
+    (*r).sample_count += count;
+    if ((*r).weight_sum > 0.0 && random_f32(seed) * (*r).weight_sum <= weight) {
+        (*r).light_idx = light_idx;
+    }
+}
+
+// Is there anything between a surface and a light? Both positions in voxel space.
+fn light_occluded(pos: vec3<f32>, light_pos: vec3<f32>) -> bool {
+    let to_light = light_pos - pos;
+    let light_dist = length(to_light);
+    let ray_dir = to_light / light_dist;
+    let hit = cast_ray(pos / 8.0, ray_dir, false);
+    if (!hit.hit) {
+        return false;
+    }
+
+    let hit_dist = length(vec3<f32>(hit.hit_pos) + vec3<f32>(0.5) - pos);
+    return hit_dist < light_dist;
+}
+
+// Shadowed contribution of a light at a surface. Area lights average shadow rays to
+// random points all over them, which is what softens their shadows.
+fn shadowed_light_radiance(light_idx: u32, pos: vec3<f32>, normal: vec3<f32>, seed: ptr<function, u32>) -> vec3<f32> {
+    let light = lights[light_idx];
+    let is_area = any(light.half_size > vec3<f32>(0.0));
+    let ray_count = select(1u, max(light_state.area_shadow_rays, 1u), is_area);
+
+    var radiance = vec3<f32>(0.0);
+    for (var i: u32 = 0u; i < ray_count; i++) {
+        let offset = vec3<f32>(random_f32(seed), random_f32(seed), random_f32(seed)) * 2.0 - vec3<f32>(1.0);
+        let light_pos = light_position(light) + offset * light.half_size;
+        if (!light_occluded(pos, light_pos)) {
+            radiance += light_radiance(light, light_pos, pos, normal);
+        }
+    }
+    return radiance / f32(ray_count);
+}
+
+// Picks a light for the pixel by resampling a few random candidates, then reusing last
+// frame's reservoir for the pixel. Returns the direct lighting from the chosen light.
+fn sample_lights(
+    pixel_idx: u32,
+    pixel_count: u32,
+    pos: vec3<f32>,
+    normal: vec3<f32>,
+    albedo: vec3<f32>
+) -> vec3<f32> {
+    let light_count = light_state.light_count;
+    let parity = light_state.frame & 1u;
+    let current_idx = pixel_idx + parity * pixel_count;
+    let previous_idx = pixel_idx + (1u - parity) * pixel_count;
+
+    var seed = hash_u32(pixel_idx ^ world_state.seed);
+    var r = Reservoir(0u, 0.0, 0u, 0.0);
+
+    // Initial candidates are picked uniformly, so the source pdf is 1 / light_count
+    for (var i: u32 = 0u; i < light_state.candidate_count; i++) {
+        let light_idx = min(u32(random_f32(&seed) * f32(light_count)), light_count - 1u);
+        let p_hat = light_target_pdf(light_idx, pos, normal, albedo);
+        reservoir_update(&r, light_idx, p_hat * f32(light_count), 1u, &seed);
+    }
+
+    // Temporal reuse. The history is clamped so stale samples can't dominate forever
+    let previous = reservoirs[previous_idx];
+    if (previous.sample_count > 0u && previous.light_idx < light_count) {
+        let history = min(previous.sample_count, MAX_RESERVOIR_HISTORY * light_state.candidate_count);
+        let p_hat = light_target_pdf(previous.light_idx, pos, normal, albedo);
+        reservoir_update(&r, previous.light_idx, p_hat * previous.weight * f32(history), history, &seed);
+    }
+
+    let p_hat = light_target_pdf(r.light_idx, pos, normal, albedo);
+    if (p_hat > 0.0) {
+        r.weight = r.weight_sum / (f32(r.sample_count) * p_hat);
+    }
+
+    // Only the selected light gets shadow rays. Fully occluded samples are dropped from
+    // the reservoir so they don't get reused next frame
+    var radiance = vec3<f32>(0.0);
+    if (r.weight > 0.0) {
+        radiance = shadowed_light_radiance(r.light_idx, pos, normal, &seed);
+        if (all(radiance == vec3<f32>(0.0))) {
+            r.weight = 0.0;
+        }
+    }
+
+    reservoirs[current_idx] = r;
+    return radiance * r.weight;
+}
+
+// Everything about a pixel's primary ray needed to shade it
+struct PixelSample {
+    // False for pixels outside the image or skipped by variable rate tracing
+    traced: bool,
+    hit: HitInfo,
+    color: vec4<f32>,
+    normal: vec3<f32>,
+    depth: f32,
+    out_of_budget: bool,
+    // Traversal cost of the primary ray, for the heatmaps
+    steps: u32,
+    bricks: u32,
+    lighting: vec3<f32>,
+    lit: bool,
+    // Direction of the ray that hit, after any portals
+    ray_dir: vec3<f32>,
+    // How much of the ambient light reaches the point that was hit, from the baked AO
+    ambient_occlusion: f32,
+    // Distance to the water surface if the ray comes down onto it from above, otherwise -1
+    water_surface: f32,
+    // How far the ray travelled under water
+    water_depth: f32,
+}
+
+// Direction of the camera ray through a point on the image, from 0-1 across it
+fn camera_ray_dir(img_coord_frac: vec2<f32>) -> vec3<f32> {
     let screen_pos = img_coord_frac * 2.0 - vec2<f32>(1.0);
     var ray_eye = camera.projection * vec4<f32>(screen_pos, -1.0, 0.0);
     ray_eye = vec4<f32>(ray_eye.xy, -1.0, 0.0);
-    let ray_dir = normalize((camera.view * ray_eye).xyz);
-    let ray_pos = camera.pos;
+    return normalize((camera.view * ray_eye).xyz);
+}
+
+// Traces a single pixel's primary ray, working out its colour and depth but not yet its
+// lighting
+fn trace_pixel(img_coord: vec2<u32>) -> PixelSample {
+    var sample = PixelSample(false, HitInfo(false, vec3<i32>(0), 0u, vec3<bool>(false), 0u, NO_MATERIAL, 0u, vec3<f32>(0.0), -1.0), vec4<f32>(atmosphere.sky_color, 1.0), vec3<f32>(0.0), MISS_DEPTH, false, 0u, 0u, vec3<f32>(1.0), false, vec3<f32>(0.0), 1.0, -1.0, 0.0);
+    let img_dims = textureDimensions(output);
+
+    // This discards the extra pixels in cases where the image size isn't perfectly divisible by the kernel.xy
+    if (img_coord.x >= img_dims.x || img_coord.y >= img_dims.y) {
+        return sample;
+    }
+
+    // Outside of the full rate region we only trace the top-left pixel of each 2x2
+    // block. The rest get interpolated when the image is drawn to the screen
+    if (!is_full_rate(img_coord, img_dims) && any(img_coord % 2u != vec2<u32>(0u))) {
+        return sample;
+    }
+    sample.traced = true;
+
+    // Construct ray
+    let img_coord_frac = (vec2<f32>(img_coord) + pixel_jitter()) / vec2<f32>(img_dims);
+    var ray_dir = camera_ray_dir(img_coord_frac);
+    var ray_pos = camera_pos();
 
     // Cast the ray
-    var hit_info = grid_cast_ray(ray_pos, ray_dir);
-    var color = vec4<f32>(0.0, 0.0, 0.0, 1.0);
+    dither = pixel_dither(img_coord);
+    trace_steps = 0u;
+    trace_bricks = 0u;
+    ray_out_of_budget = false;
+    var hit_info = cast_ray(ray_pos, ray_dir, true);
+    var travelled = 0.0;
+
+    // Rays that cross a portal before hitting anything get traced again from the other
+    // side, and are shaded as if they'd started there
+    for (var i: u32 = 0u; i < MAX_PORTAL_CROSSINGS; i++) {
+        let crossing = nearest_portal(ray_pos, ray_dir);
+        if (crossing.distance < 0.0 || (hit_info.hit && hit_distance(hit_info, ray_pos, ray_dir) < crossing.distance)) {
+            break;
+        }
+
+        travelled += crossing.distance;
+        let transform = portals.portals[crossing.portal_idx].transform;
+        ray_pos = (transform * vec4<f32>(ray_pos + ray_dir * crossing.distance, 1.0)).xyz;
+        ray_dir = normalize((transform * vec4<f32>(ray_dir, 0.0)).xyz);
+        hit_info = cast_ray(ray_pos, ray_dir, true);
+    }
+
+    if (is_pick_pixel(img_coord, img_dims)) {
+        write_pick_result(hit_info, ray_dir);
+    }
+
+    if (hit_info.hit) {
+        sample.depth = travelled + hit_distance(hit_info, ray_pos, ray_dir);
+    }
+    cross_water(&sample, camera_pos(), camera_ray_dir(img_coord_frac));
+    if (sample.water_surface >= 0.0) {
+        // The blit and anything drawn over the image see the surface, not what's under it
+        write_depth(img_coord, img_dims, sample.water_surface, vec3<f32>(encode_normal(vec3<f32>(0.0, 1.0, 0.0)), -1.0));
+    } else {
+        write_depth(img_coord, img_dims, sample.depth, describe_surface(hit_info, ray_dir));
+    }
+    if (settings.raycast_stats != 0u) {
+        atomicAdd(&workgroup_stats[0], 1u);
+        atomicAdd(&workgroup_stats[1], trace_steps);
+        atomicAdd(&workgroup_stats[2], u32(!hit_info.hit));
+        atomicAdd(&workgroup_stats[3], trace_bricks);
+    }
+
+    // Shadow rays go through the same traversal, so grab this before any get cast
+    sample.out_of_budget = ray_out_of_budget;
+    sample.steps = trace_steps;
+    sample.bricks = trace_bricks;
+    sample.hit = hit_info;
+    sample.ray_dir = ray_dir;
+    if (!hit_info.hit) {
+        sample.color = vec4<f32>(sky_color(ray_dir), 1.0);
+    }
     if (hit_info.hit){
         // if (hit_info.mask.x) {
         //     color.x = 1.0;
@@ -328,13 +1698,406 @@ fn compute(@builtin(global_invocation_id) global_id: vec3<u32>) {
         // else {
         //     color = vec4<f32>(1.0);
         // }
-        let offset = get_shading_offset(hit_info);
-        let raw_color = shading_table[offset].albedo;
-        color.x = f32((raw_color >> 24u) & 255u) / 255.0;
-        color.y = f32((raw_color >> 16u) & 255u) / 255.0;
-        color.z = f32((raw_color >> 8u) & 255u) / 255.0;
-        color.w = f32(raw_color & 255u) / 255.0;
+        let color = apply_decals(hit_info, ray_dir, unpack_albedo(hit_info.albedo));
+
+        sample.normal = hit_normal(hit_info, ray_dir);
+        var albedo = wet_albedo(color.xyz, sample.normal);
+        if (settings.surface_detail_intensity > 0.0) {
+            let hit_point = (ray_pos + ray_dir * hit_distance(hit_info, ray_pos, ray_dir)) * 8.0;
+            albedo *= surface_detail(hit_point, sample.normal);
+        }
+        sample.color = vec4<f32>(albedo, color.w);
+        if (settings.baked_ao != 0u) {
+            let hit_point = (ray_pos + ray_dir * hit_distance(hit_info, ray_pos, ray_dir)) * 8.0;
+            sample.ambient_occlusion = baked_occlusion(hit_info.ao, hit_point - vec3<f32>(hit_info.hit_pos));
+        }
+    }
+    return sample;
+}
+
+// Works out where the camera ray meets the water, if there is any. Only the first stretch
+// of the ray before any portals is considered
+fn cross_water(sample: ptr<function, PixelSample>, ray_pos: vec3<f32>, ray_dir: vec3<f32>) {
+    if (settings.water == 0u) {
+        return;
+    }
+
+    let above = ray_pos.y > settings.water_level;
+    let t = (settings.water_level - ray_pos.y) / ray_dir.y;
+    let crosses = ray_dir.y != 0.0 && t > 0.0 && t < (*sample).depth;
+    if (above && crosses) {
+        (*sample).water_surface = t;
+        (*sample).water_depth = (*sample).depth - t;
+    } else if (!above) {
+        (*sample).water_depth = select((*sample).depth, t, crosses);
+    }
+}
+
+// Fraction of the light reflected off the water surface when looking along a ray
+fn water_fresnel(ray_dir: vec3<f32>) -> f32 {
+    let cos_theta = clamp(abs(ray_dir.y), 0.0, 1.0);
+    return WATER_F0 + (1.0 - WATER_F0) * pow(1.0 - cos_theta, 5.0);
+}
+
+// Ambient occlusion at a point within a voxel, blending between the AO baked at its
+// corners. Points on a face only pick up that face's corners
+fn baked_occlusion(ao: u32, local: vec3<f32>) -> f32 {
+    let t = clamp(local, vec3<f32>(0.0), vec3<f32>(1.0));
+    var occlusion = 0.0;
+    for (var corner: u32 = 0u; corner < 8u; corner++) {
+        let side = vec3<f32>(vec3<u32>(corner, corner >> 1u, corner >> 2u) & vec3<u32>(1u));
+        let weights = mix(vec3<f32>(1.0) - t, t, side);
+        let level = f32((ao >> (corner * 2u)) & 3u);
+        occlusion += (1.0 - level * 0.2) * weights.x * weights.y * weights.z;
+    }
+    return occlusion;
+}
+
+// Light from the sun at a surface, with a hard shadow from a single ray towards it if sun
+// shadows are on. `pos` is in voxel space.
+fn sun_lighting(pos: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    let cos_theta = dot(normal, sun.direction);
+    if (sun.intensity <= 0.0 || cos_theta <= 0.0) {
+        return vec3<f32>(0.0);
+    }
+
+    if (settings.sun_shadows != 0u && cast_ray(pos / 8.0, sun.direction, false).hit) {
+        return vec3<f32>(0.0);
     }
+    return sun.color * sun.intensity * cos_theta;
+}
+
+// Light given off by whatever a ray hit
+fn hit_emission(hit: HitInfo) -> vec3<f32> {
+    if (hit.material == NO_MATERIAL) {
+        return vec3<f32>(0.0);
+    }
+    return materials.entries[hit.material].emissive;
+}
+
+// Light reaching a surface from emissive voxels, from a single cosine weighted ray. With
+// that weighting the emission of whatever the ray hits is the estimate on its own, so
+// it's noisy but converges as frames are accumulated. The probes pick up the same light
+// more smoothly but much more coarsely. `pos` is in voxel space.
+fn emissive_bounce(pixel_idx: u32, pos: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    var seed = hash_u32(pixel_idx ^ world_state.seed);
+    let r = sqrt(random_f32(&seed));
+    let phi = 6.2831853 * random_f32(&seed);
+    let up = select(vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(1.0, 0.0, 0.0), abs(normal.y) > 0.9);
+    let tangent = normalize(cross(up, normal));
+    let bitangent = cross(normal, tangent);
+    let local = vec3<f32>(r * cos(phi), r * sin(phi), sqrt(max(1.0 - r * r, 0.0)));
+    let ray_dir = normalize(tangent * local.x + bitangent * local.y + normal * local.z);
+
+    let hit = cast_ray(pos / 8.0, ray_dir, false);
+    if (!hit.hit) {
+        return vec3<f32>(0.0);
+    }
+    return hit_emission(hit);
+}
+
+// Probe, sun, point and area light lighting at the surface a pixel hit, as a multiplier
+// for its colour
+fn surface_lighting(img_coord: vec2<u32>, sample: PixelSample) -> vec3<f32> {
+    let img_dims = textureDimensions(output);
+    let normal = sample.normal;
+    var lighting = vec3<f32>(1.0);
+    if (settings.light_probes != 0u) {
+        let hit_pos = (vec3<f32>(sample.hit.hit_pos) + vec3<f32>(0.5) + normal) / 8.0;
+        lighting = sample_irradiance(hit_pos, normal);
+    }
+    // Only the ambient light is occluded, direct light gets its own shadow rays
+    lighting *= sample.ambient_occlusion;
+
+    // Offset slightly off the surface so shadow rays don't hit the voxel itself
+    let surface_pos = vec3<f32>(sample.hit.hit_pos) + vec3<f32>(0.5) + normal * 0.51;
+    lighting += sun_lighting(surface_pos, normal);
+
+    let pixel_idx = img_coord.x + img_coord.y * img_dims.x;
+    if (light_state.light_count > 0u) {
+        lighting += sample_lights(pixel_idx, img_dims.x * img_dims.y, surface_pos, normal, sample.color.xyz);
+    }
+    if (settings.emissive_bounce != 0u) {
+        lighting += emissive_bounce(pixel_idx, surface_pos, normal);
+    }
+    return lighting;
+}
 
-    textureStore(output, img_coord, color);
-}
\ No newline at end of file
+// With quarter resolution lighting, only the top-left pixel of each 2x2 block lights
+// itself. Its lighting, depth and normal get shared with the rest of the workgroup here.
+var<workgroup> shared_lighting: array<vec4<f32>, 16>;
+var<workgroup> shared_normals: array<vec4<f32>, 16>;
+
+fn is_lighting_pixel(img_coord: vec2<u32>) -> bool {
+    return settings.quarter_res_lighting == 0u || all(img_coord % 2u == vec2<u32>(0u));
+}
+
+fn share_lighting(local_id: vec2<u32>, sample: PixelSample) {
+    if (any(local_id % 2u != vec2<u32>(0u))) {
+        return;
+    }
+
+    let idx = local_id.x / 2u + (local_id.y / 2u) * 4u;
+    shared_lighting[idx] = vec4<f32>(sample.lighting, sample.depth);
+    shared_normals[idx] = vec4<f32>(sample.normal, f32(sample.lit));
+}
+
+// Bilaterally upsamples the shared lighting, only trusting samples on surfaces facing
+// the same way at a similar depth. Returns zero weight in w if nothing matched.
+fn upsample_lighting(local_id: vec2<u32>, sample: PixelSample) -> vec4<f32> {
+    let pos = vec2<f32>(local_id) * 0.5;
+    let base = vec2<i32>(local_id / 2u);
+    var total = vec4<f32>(0.0);
+    for (var y: i32 = -1; y <= 1; y++) {
+        for (var x: i32 = -1; x <= 1; x++) {
+            let cell = base + vec2<i32>(x, y);
+            if (any(cell < vec2<i32>(0)) || any(cell > vec2<i32>(3))) {
+                continue;
+            }
+
+            let idx = u32(cell.x + cell.y * 4);
+            let normal = shared_normals[idx];
+            if (normal.w == 0.0) {
+                continue;
+            }
+
+            let lighting = shared_lighting[idx];
+            let offset = vec2<f32>(cell) - pos;
+            let spatial = max(1.0 - length(offset), 0.0);
+            let depth = exp(-abs(lighting.w - sample.depth) / (0.05 * sample.depth + 0.01));
+            let facing = pow(max(dot(normal.xyz, sample.normal), 0.0), 8.0);
+            let weight = spatial * depth * facing;
+            total += vec4<f32>(lighting.xyz * weight, weight);
+        }
+    }
+
+    if (total.w < 1e-3) {
+        return vec4<f32>(0.0);
+    }
+    return vec4<f32>(total.xyz / total.w, 1.0);
+}
+
+// Grid steps and bricks visited that max out the heatmaps
+const HEATMAP_MAX_STEPS: f32 = 256.0;
+const HEATMAP_MAX_BRICKS: f32 = 32.0;
+
+// Picks a colour from five evenly spaced stops
+fn palette_ramp(stops: array<vec3<f32>, 5>, t: f32) -> vec3<f32> {
+    let x = clamp(t, 0.0, 1.0) * 4.0;
+    let i = min(u32(x), 3u);
+    // Arrays can only be dynamically indexed through a variable
+    var ramp = stops;
+    return mix(ramp[i], ramp[i + 1u], x - f32(i));
+}
+
+// Maps 0-1 onto the selected debug palette. Everything but the first is readable without
+// telling red from green
+fn debug_palette(t: f32) -> vec3<f32> {
+    switch (settings.debug_palette) {
+        case 1u: {
+            return palette_ramp(array<vec3<f32>, 5>(
+                vec3<f32>(0.267, 0.005, 0.329),
+                vec3<f32>(0.231, 0.322, 0.545),
+                vec3<f32>(0.129, 0.569, 0.549),
+                vec3<f32>(0.369, 0.788, 0.384),
+                vec3<f32>(0.992, 0.906, 0.145),
+            ), t);
+        }
+        case 2u: {
+            return palette_ramp(array<vec3<f32>, 5>(
+                vec3<f32>(0.0, 0.125, 0.302),
+                vec3<f32>(0.255, 0.302, 0.420),
+                vec3<f32>(0.486, 0.482, 0.471),
+                vec3<f32>(0.737, 0.686, 0.435),
+                vec3<f32>(1.0, 0.918, 0.275),
+            ), t);
+        }
+        case 3u: {
+            return vec3<f32>(clamp(t, 0.0, 1.0));
+        }
+        default: {
+            return palette_ramp(array<vec3<f32>, 5>(
+                vec3<f32>(0.0, 1.0, 0.0),
+                vec3<f32>(0.5, 1.0, 0.0),
+                vec3<f32>(1.0, 1.0, 0.0),
+                vec3<f32>(1.0, 0.5, 0.0),
+                vec3<f32>(1.0, 0.0, 0.0),
+            ), t);
+        }
+    }
+}
+
+// Lights a hit according to its material. Metals lose their diffuse and tint what they
+// reflect, smoother surfaces reflect more of the sky, and emissive ones add their own light
+fn shade_material(sample: PixelSample) -> vec3<f32> {
+    let lit = sample.color.xyz * sample.lighting;
+    if (sample.hit.material == NO_MATERIAL) {
+        return lit;
+    }
+
+    let material = materials.entries[sample.hit.material];
+    let f0 = mix(vec3<f32>(0.04), sample.color.xyz, material.metallic);
+    let cos_theta = max(dot(-sample.ray_dir, sample.normal), 0.0);
+    let fresnel = f0 + (vec3<f32>(1.0) - f0) * pow(1.0 - cos_theta, 5.0);
+    let smoothness = 1.0 - material.roughness;
+    let specular = fresnel * smoothness * smoothness;
+
+    // There's no reflection ray, so the sky is darkened by however much light reaches
+    // the surface to stop enclosed spaces reflecting it
+    let reflection = sky_radiance(reflect(sample.ray_dir, sample.normal)) * min(sample.lighting, vec3<f32>(1.0));
+    let diffuse = lit * (1.0 - material.metallic) * (vec3<f32>(1.0) - specular);
+    return diffuse + reflection * specular + material.emissive;
+}
+
+// Applies lighting, fog and debug tints to a traced pixel and writes it out
+fn shade_pixel(img_coord: vec2<u32>, sample: PixelSample) {
+    // Heatmaps replace the image entirely, so the cost is the only thing on screen
+    if (settings.debug_heatmap == 1u) {
+        textureStore(output, img_coord, vec4<f32>(debug_palette(f32(sample.steps) / HEATMAP_MAX_STEPS), 1.0));
+        return;
+    }
+    if (settings.debug_heatmap == 2u) {
+        textureStore(output, img_coord, vec4<f32>(debug_palette(f32(sample.bricks) / HEATMAP_MAX_BRICKS), 1.0));
+        return;
+    }
+
+    var color = sample.color;
+    if (sample.hit.hit) {
+        color = vec4<f32>(shade_material(sample), color.w);
+    }
+    var fog_depth = sample.depth;
+    if (sample.water_depth > 0.0) {
+        let transmittance = exp(-sample.water_depth / max(settings.water_clarity, 1e-3));
+        color = vec4<f32>(mix(WATER_COLOR * sky_radiance(vec3<f32>(0.0, 1.0, 0.0)), color.xyz, transmittance), color.w);
+    }
+    if (sample.water_surface >= 0.0) {
+        fog_depth = sample.water_surface;
+    }
+    color = vec4<f32>(apply_fog(color.xyz, fog_depth), color.w);
+    // What's seen through the surface makes way for the reflection, which the blit adds
+    // from the half resolution reflection pass
+    if (sample.water_surface >= 0.0) {
+        color = vec4<f32>(color.xyz * (1.0 - water_fresnel(sample.ray_dir)), color.w);
+    }
+
+    // Make it obvious where the limits are cutting the view short
+    if (settings.ray_budget_debug != 0u && sample.out_of_budget) {
+        color = vec4<f32>(mix(color.xyz, vec3<f32>(1.0, 0.0, 1.0), 0.5), color.w);
+    }
+
+    textureStore(output, img_coord, accumulate(img_coord, color));
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn compute(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+    @builtin(local_invocation_id) local_id: vec3<u32>,
+    @builtin(local_invocation_index) local_idx: u32
+) {
+    var sample = trace_pixel(global_id.xy);
+    if (sample.traced && sample.hit.hit && is_lighting_pixel(global_id.xy)) {
+        sample.lighting = surface_lighting(global_id.xy, sample);
+        sample.lit = true;
+    }
+
+    // Lighting is the expensive part of shading, so it can be worked out for a quarter
+    // of the pixels and filled in for the rest. The settings are uniform, so the whole
+    // workgroup reaches the barrier together
+    if (settings.quarter_res_lighting != 0u) {
+        share_lighting(local_id.xy, sample);
+        workgroupBarrier();
+        if (sample.traced && sample.hit.hit && !sample.lit) {
+            let upsampled = upsample_lighting(local_id.xy, sample);
+            if (upsampled.w > 0.0) {
+                sample.lighting = upsampled.xyz;
+            } else {
+                // Nothing nearby is on the same surface, e.g. at the edge of an object
+                sample.lighting = surface_lighting(global_id.xy, sample);
+            }
+        }
+    }
+
+    if (sample.traced) {
+        shade_pixel(global_id.xy, sample);
+    }
+
+    // The settings are uniform, so the whole workgroup reaches the barrier together
+    if (settings.raycast_stats != 0u) {
+        workgroupBarrier();
+        if (local_idx == 0u) {
+            atomicAdd(&raycast_stats.rays, atomicLoad(&workgroup_stats[0]));
+            atomicAdd(&raycast_stats.steps, atomicLoad(&workgroup_stats[1]));
+            atomicAdd(&raycast_stats.misses, atomicLoad(&workgroup_stats[2]));
+            atomicAdd(&raycast_stats.bricks, atomicLoad(&workgroup_stats[3]));
+        }
+    }
+}
+
+// Refreshes one of the sun's shadow cascades by casting a ray from the sun for each
+// texel, storing how far it got before hitting a voxel
+@compute @workgroup_size(8, 8, 1)
+fn update_sun_shadows(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    if (any(global_id.xy >= vec2<u32>(shadow_cascades.size))) {
+        return;
+    }
+
+    let layer = shadow_cascades.update_cascade;
+    let cascade = shadow_cascades.cascades[layer];
+    let uv = (vec2<f32>(global_id.xy) + vec2<f32>(0.5)) / f32(shadow_cascades.size) * 2.0 - vec2<f32>(1.0);
+    let direction = shadow_cascades.direction;
+    let origin = cascade.xyz
+        + (shadow_cascades.right * uv.x + shadow_cascades.up * uv.y) * cascade.w
+        + direction * shadow_cascades.depth_range;
+
+    var depth = MISS_DEPTH;
+    let hit = cast_ray(origin, -direction, false);
+    if (hit.hit) {
+        let hit_pos = (vec3<f32>(hit.hit_pos) + vec3<f32>(0.5)) / 8.0;
+        depth = dot(origin - hit_pos, direction);
+    }
+    textureStore(sun_shadow_maps, global_id.xy, layer, vec4<f32>(depth));
+}
+
+// Reflects the world in the water at half resolution, one ray per 2x2 block of pixels.
+// Only a single bounce is traced and it's lit by the probes and sun alone, which holds up
+// fine once Fresnel has scaled it down. Written premultiplied by Fresnel, with 1 in w for
+// water so the blit can ignore anything else when filtering
+@compute @workgroup_size(8, 8, 1)
+fn trace_water_reflections(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let dims = textureDimensions(water_reflections);
+    if (any(global_id.xy >= dims)) {
+        return;
+    }
+
+    let img_dims = textureDimensions(surface_depth);
+    let img_coord = min(global_id.xy * 2u, img_dims - vec2<u32>(1u));
+    let surface = textureLoad(surface_depth, img_coord, 0);
+    if (surface.w >= 0.0) {
+        textureStore(water_reflections, global_id.xy, vec4<f32>(0.0));
+        return;
+    }
+
+    let img_coord_frac = (vec2<f32>(global_id.xy * 2u) + vec2<f32>(1.0)) / vec2<f32>(img_dims);
+    let ray_dir = camera_ray_dir(img_coord_frac);
+    let ray_pos = camera_pos() + ray_dir * surface.x;
+    let reflected_dir = reflect(ray_dir, vec3<f32>(0.0, 1.0, 0.0));
+
+    dither = pixel_dither(img_coord);
+    let hit = cast_ray(ray_pos, reflected_dir, false);
+    var color = sky_color(reflected_dir);
+    var depth = MISS_DEPTH;
+    if (hit.hit) {
+        let normal = hit_normal(hit, reflected_dir);
+        var lighting = vec3<f32>(1.0);
+        if (settings.light_probes != 0u) {
+            let hit_pos = (vec3<f32>(hit.hit_pos) + vec3<f32>(0.5) + normal) / 8.0;
+            lighting = sample_irradiance(hit_pos, normal);
+        }
+        let surface_pos = vec3<f32>(hit.hit_pos) + vec3<f32>(0.5) + normal * 0.51;
+        lighting += sun_lighting(surface_pos, normal);
+        color = unpack_albedo(hit.albedo).xyz * lighting + hit_emission(hit);
+        depth = surface.x + hit_distance(hit, ray_pos, reflected_dir);
+    }
+    color = apply_fog(color, depth);
+    textureStore(water_reflections, global_id.xy, vec4<f32>(color * water_fresnel(ray_dir), 1.0));
+}
@@ -0,0 +1,74 @@
+//! Renders a camera orbiting the spawn point without opening a window, saving each frame
+//! as a PPM in `frames/`.
+
+use std::{path::Path, time::Duration};
+
+use voxel_rs::{
+    core::{Camera, CameraController, Lighting, Projection},
+    gfx,
+    voxel::brickmap::{BrickmapBudget, BrickmapRenderer},
+    Config, VoxelRenderer, WorldManager,
+};
+
+const WIDTH: u32 = 640;
+const HEIGHT: u32 = 360;
+const FRAMES: u32 = 8;
+/// Frames rendered at each step of the path before it's saved, so the image has settled
+const SETTLE_FRAMES: u32 = 16;
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+
+    let config = Config::default();
+    let context = pollster::block_on(gfx::Context::new_headless(
+        WIDTH,
+        HEIGHT,
+        wgpu::Limits::default(),
+        config.get_adapter_selection(),
+    ))?;
+
+    let mut world = WorldManager::new(config.generation, config.chunk_dims);
+    let mut camera_controller = CameraController::new(
+        &context,
+        Camera::new(glam::Vec3::ZERO, 0.0, 0.0),
+        Projection::new(WIDTH, HEIGHT, 90.0_f32.to_radians(), 0.01, 100.0),
+        config.camera_speed,
+        config.mouse_sensitivity,
+    );
+    let lighting = Lighting::new(&context, Default::default());
+    let mut renderer = BrickmapRenderer::new(
+        &context,
+        &camera_controller,
+        &lighting,
+        BrickmapBudget::auto_tune(&context),
+    )?;
+
+    let center = glam::vec3(4.0, 4.0, 20.0);
+    renderer.prewarm(&context, &mut world, center, 16)?;
+
+    let dt = Duration::from_millis(16);
+    std::fs::create_dir_all("frames")?;
+    for frame in 0..FRAMES {
+        let angle = frame as f32 / FRAMES as f32 * std::f32::consts::TAU;
+        let position = center + glam::vec3(angle.cos(), 0.25, angle.sin()) * 8.0;
+        let to_center = (center - position).normalize();
+        camera_controller.set_pose(position, to_center.z.atan2(to_center.x), to_center.y.asin());
+        camera_controller.update_buffer(&context);
+        renderer.set_focus(&context, position);
+
+        // Pipelines compile in the background, so keep going until they're ready too
+        let mut settled = 0;
+        while settled < SETTLE_FRAMES {
+            renderer.update(&dt, &context, &mut world)?;
+            renderer.render(&context)?;
+            settled += !renderer.is_loading() as u32;
+        }
+
+        let path = format!("frames/{:03}.ppm", frame);
+        renderer
+            .render_image(&context)?
+            .write_ppm(Path::new(&path))?;
+        println!("Saved {}", path);
+    }
+    Ok(())
+}
@@ -0,0 +1,33 @@
+//! Runs the app in a ring of coloured lights, set up before it starts.
+
+use voxel_rs::{
+    core::{Entity, EntityKind, Transform},
+    App, Config, SceneEntities,
+};
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+
+    let mut entities = SceneEntities::new();
+    let colors = [
+        glam::vec3(1.0, 0.3, 0.2),
+        glam::vec3(0.3, 1.0, 0.4),
+        glam::vec3(0.3, 0.4, 1.0),
+        glam::vec3(1.0, 0.9, 0.3),
+    ];
+    for (i, color) in colors.into_iter().enumerate() {
+        let angle = i as f32 / colors.len() as f32 * std::f32::consts::TAU;
+        let position =
+            glam::vec3(32.0, 40.0, 160.0) + glam::vec3(angle.cos(), 0.0, angle.sin()) * 48.0;
+        entities.spawn(Entity::new(
+            Transform::from_position(position),
+            EntityKind::PointLight {
+                color,
+                intensity: 300.0,
+            },
+        ));
+    }
+
+    let app = pollster::block_on(App::new(Config::default(), "Lights"))?;
+    app.with_entities(entities).run()
+}
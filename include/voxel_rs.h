@@ -0,0 +1,39 @@
+#ifndef VOXEL_RS_H
+#define VOXEL_RS_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/*
+ * Voxels are packed 0xAARRGGBB colours, where an alpha of 0 means empty. Positions are
+ * in world voxel space. Region buffers are laid out x first, then y, then z.
+ */
+
+typedef struct VoxelWorld VoxelWorld;
+
+VoxelWorld *voxel_world_create(int32_t seed);
+void voxel_world_destroy(VoxelWorld *world);
+
+uint32_t voxel_world_get_voxel(VoxelWorld *world, int32_t x, int32_t y, int32_t z);
+bool voxel_world_set_voxel(VoxelWorld *world, int32_t x, int32_t y, int32_t z, uint32_t color);
+
+bool voxel_world_export_region(VoxelWorld *world, const int32_t min[3], const uint32_t size[3],
+                               uint32_t *out, size_t len);
+bool voxel_world_import_region(VoxelWorld *world, const int32_t min[3], const uint32_t size[3],
+                               const uint32_t *data, size_t len);
+
+/* Renders to tightly packed RGBA8. `len` must be width * height * 4. Angles in radians. */
+bool voxel_world_render(VoxelWorld *world, const float position[3], float yaw, float pitch,
+                        float fov_y, float max_distance, uint32_t width, uint32_t height,
+                        uint8_t *out, size_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
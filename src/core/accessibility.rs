@@ -0,0 +1,29 @@
+use std::io::Write as _;
+
+/// Tells the user what the UI is doing, for anyone who can't see it. Every change of
+/// state the app shows, like a toggle being flipped or a world being switched, comes
+/// through here as a sentence in the user's language.
+///
+/// Announcements always go to the log. With them turned on they're also written to
+/// stdout, one per line and nothing else, which is what screen readers following a
+/// terminal read out.
+#[derive(Debug, Clone, Default)]
+pub struct Announcer {
+    enabled: bool,
+}
+
+impl Announcer {
+    pub fn new(enabled: bool) -> Self {
+        Self { enabled }
+    }
+
+    pub fn announce(&self, text: &str) {
+        log::info!("{}", text);
+        if self.enabled {
+            // Flushed straight away, a screen reader should hear it as it happens
+            let mut stdout = std::io::stdout().lock();
+            let _ = writeln!(stdout, "{}", text);
+            let _ = stdout.flush();
+        }
+    }
+}
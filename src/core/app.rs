@@ -1,29 +1,124 @@
-use std::{sync::Arc, time::Instant};
+use std::{
+    fmt::Display,
+    path::Path,
+    sync::{mpsc, Arc},
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
 use winit::{
     dpi::PhysicalSize,
-    event::{Event, WindowEvent},
-    event_loop::EventLoop,
+    event::{DeviceEvent, ElementState, Event, KeyEvent, MouseButton, StartCause, WindowEvent},
+    event_loop::{ControlFlow, EventLoop},
+    keyboard::{KeyCode, PhysicalKey},
+    window::Window,
 };
 
-use super::camera;
+use super::{
+    camera, config::RendererKind, crash, Announcer, AutosaveSystem, Config, DebrisSystem, Entity,
+    EntityId, EntityKind, Environment, GrassSystem, Lighting, Locale, Priority, RandomService,
+    RandomStream, Scene, SceneCamera, SceneEntities, Scheduler, SoakTest, Transform, Weather,
+    WeatherController,
+};
 use crate::{
-    gfx,
-    voxel::{self, brickmap::BrickmapRenderer, VoxelRenderer},
+    gfx::{self, GpuError, GpuErrorKind},
+    voxel::{
+        self,
+        brickmap::{
+            BrickmapBudget, BrickmapRenderer, Decal, Exposure, GizmoTransform, Outline, Portal,
+            RenderQuality, SurfaceDetail, UiTheme, Water,
+        },
+        mesh::MeshRenderer,
+        svo::SvoRenderer,
+        VoxelRenderer,
+    },
 };
 
+/// How the app behaves while its window doesn't have focus.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BackgroundSettings {
+    pub frame_rate: f32,
+    pub pause_streaming: bool,
+    pub low_power_adapter: bool,
+}
+
+impl Default for BackgroundSettings {
+    fn default() -> Self {
+        Self {
+            frame_rate: 10.0,
+            pause_streaming: true,
+            low_power_adapter: false,
+        }
+    }
+}
+
+/// What to do with the voxel a click picks, once the pick comes back from the GPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PickAction {
+    /// Just log and highlight it
+    Inspect,
+    Explode,
+    /// Set it to empty
+    Break,
+    /// Put a voxel against the face that was hit
+    Place,
+}
+
+const BRICKMAP_BUDGET_PATH: &str = "brickmap_budget.toml";
+/// Each world is saved in its own directory in here
+const WORLD_SAVE_PATH: &str = "saves";
+/// How many bricks around the camera get loaded before the first frame
+const PREWARM_RADIUS: u32 = 16;
+/// How many bricks around the spawn point are pinned so they're never evicted
+const SPAWN_PIN_RADIUS: i32 = 4;
+/// How many bricks around the camera the regenerate key rebuilds
+const REGENERATE_RADIUS: i32 = 8;
+/// How long the weather key takes to blend into the next weather
+const WEATHER_TRANSITION: Duration = Duration::from_secs(3);
+/// Hour the day cycle key starts the clock at, and how many hours pass per second
+const DAY_CYCLE_START: f32 = 7.0;
+const DAY_CYCLE_SPEED: f32 = 0.5;
+/// How much of each frame world systems (autosave, simulation) get to share
+const SIMULATION_BUDGET: Duration = Duration::from_millis(4);
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(60);
+const GRASS_INTERVAL: Duration = Duration::from_millis(250);
+/// Radius in voxels of the hole middle clicking blows in the world
+const EXPLOSION_RADIUS: f32 = 6.0;
+/// The panel the area light key hangs this many voxels above the camera
+const AREA_LIGHT_HEIGHT: f32 = 16.0;
+const AREA_LIGHT_SIZE: glam::Vec3 = glam::Vec3::new(16.0, 0.0, 16.0);
+/// How many voxels in front of the camera the volume key places a model
+const VOLUME_DISTANCE: f32 = 48.0;
+/// How fast placed models turn while they're spinning, in radians per second
+const VOLUME_SPIN_SPEED: f32 = 0.5;
+
 pub struct App<'window> {
     title: String,
+    locale: Locale,
+    announcer: Announcer,
+    config: Config,
     event_loop: EventLoop<()>,
+    window: Arc<Window>,
     render_ctx: gfx::Context<'window>,
+    background: BackgroundSettings,
+    scene: Option<Scene>,
+    entities: SceneEntities,
 }
 
 impl<'window> App<'window> {
-    pub async fn new(width: u32, height: u32, title: &str) -> Result<Self> {
+    pub async fn new(config: Config, title: &str) -> Result<Self> {
+        Self::with_event_loop(EventLoop::new()?, config, title).await
+    }
+
+    /// Some platforms (e.g. Android) need a specially built event loop, so this lets
+    /// the platform entry point supply it.
+    pub async fn with_event_loop(
+        event_loop: EventLoop<()>,
+        config: Config,
+        title: &str,
+    ) -> Result<Self> {
         log::info!("Initialising window...");
-        let size = PhysicalSize::new(width, height);
-        let event_loop = EventLoop::new()?;
+        let size = PhysicalSize::new(config.window_size.x, config.window_size.y);
         let window = Arc::new(
             winit::window::WindowBuilder::new()
                 .with_title(title)
@@ -31,68 +126,347 @@ impl<'window> App<'window> {
                 .build(&event_loop)?,
         );
 
-        let render_ctx = gfx::Context::new(
-            window,
-            wgpu::Limits {
-                max_storage_buffer_binding_size: 1 << 30,
-                max_buffer_size: 1 << 30,
-                ..Default::default()
-            },
-        )
-        .await?;
+        // Mobile GPUs can't give us the huge buffers we use on desktop, so we stick to
+        // the default limits and let the renderer size its buffers to fit
+        #[cfg(target_os = "android")]
+        let limits = wgpu::Limits::default();
+        #[cfg(not(target_os = "android"))]
+        let limits = wgpu::Limits {
+            max_storage_buffer_binding_size: 1 << 30,
+            max_buffer_size: 1 << 30,
+            ..Default::default()
+        };
+
+        let render_ctx =
+            gfx::Context::new(window.clone(), limits, config.get_adapter_selection()).await?;
 
         Ok(Self {
             title: title.to_owned(),
+            locale: Locale::from_environment(),
+            announcer: Announcer::new(config.announce),
+            background: config.background,
+            config,
             event_loop,
+            window,
             render_ctx,
+            scene: None,
+            entities: SceneEntities::new(),
         })
     }
 
+    /// Starts the app in a scene rather than wherever the saved world was left. The
+    /// scene's config overrides have to be applied before the app is created, as some
+    /// of them (e.g. the backend) are needed to set up the GPU.
+    pub fn with_scene(mut self, scene: Scene) -> Self {
+        self.scene = Some(scene);
+        self
+    }
+
+    /// Starts the app with these cameras, lights and volumes, see `SceneEntities`. If
+    /// one of the cameras is active the app starts looking through it.
+    pub fn with_entities(mut self, entities: SceneEntities) -> Self {
+        self.entities = entities;
+        self
+    }
+
+    /// Cuts the renderer down on adapters that can't keep up with it, unless the config
+    /// says otherwise, and says so in the title. Scenes have to look the same on every
+    /// GPU, so they're only cut down when the config asks for it.
+    fn apply_quality(
+        &mut self,
+        renderer: &mut BrickmapRenderer,
+        camera_controller: &camera::CameraController,
+    ) -> Result<()> {
+        let info = self.render_ctx.adapter.get_info();
+        let quality = match (self.config.quality, &self.scene) {
+            (Some(quality), _) => quality,
+            (None, Some(_)) => RenderQuality::Full,
+            (None, None) => RenderQuality::for_adapter(&info),
+        };
+        if quality == RenderQuality::Full {
+            return Ok(());
+        }
+
+        log::warn!(
+            "Rendering at {} quality on {} ({:?}), set renderer.quality = full in the \
+             config to turn it off",
+            quality.name(),
+            info.name,
+            info.device_type
+        );
+        self.title = self
+            .locale
+            .format("title.reduced_quality", &[("title", &self.title)]);
+        self.window.set_title(&self.title);
+
+        let settings = renderer.get_settings().with_quality(quality);
+        renderer.set_settings(&self.render_ctx, settings);
+        renderer.resize(&self.render_ctx, camera_controller)
+    }
+
     pub fn run(mut self) -> Result<()> {
-        let mut camera_controller = camera::CameraController::new(
-            &self.render_ctx,
-            camera::Camera::new(
-                glam::Vec3 {
-                    x: 4.01,
-                    y: 4.01,
-                    z: 20.0,
+        let mut camera_controller = self.create_camera_controller();
+        // The camera the app starts with is drawn from unless the entities already have
+        // an active camera of their own
+        let mut entities = std::mem::take(&mut self.entities);
+        if entities.get_active_camera().is_none() {
+            let transform = Transform::from_camera(camera_controller.get_camera());
+            let camera = entities.spawn(Entity::new(transform, EntityKind::Camera));
+            entities.set_active_camera(camera);
+        }
+        let start_position = camera_controller.get_position();
+        let generation = self.config.generation;
+        let chunk_dims = self.config.chunk_dims;
+
+        // The main world and a scratch world to mess around in, the renderer streams from
+        // whichever one is active
+        let mut worlds = [
+            voxel::world::WorldManager::new(generation, chunk_dims),
+            voxel::world::WorldManager::new(
+                voxel::world::GenerationSettings {
+                    seed: generation.seed + 1,
+                    ..generation
                 },
-                -90.0_f32.to_radians(),
-                0.0_f32.to_radians(),
-            ),
-            camera::Projection::new(
-                self.render_ctx.size.width,
-                self.render_ctx.size.height,
-                90.0_f32.to_radians(),
-                0.01,
-                100.0,
+                chunk_dims,
             ),
-            10.0,
-            0.25,
-        );
+        ];
+        let mut active_world = 0;
+        for world in &mut worlds {
+            world.set_voxel_size(self.config.voxel_size);
+        }
+        camera_controller.set_world_scale(worlds[active_world].get_bricks_per_metre());
 
-        let mut world = voxel::world::WorldManager::new(
-            voxel::world::GenerationSettings {
-                seed: 0,
-                frequency: 0.04,
-                octaves: 3,
-                gain: 0.5,
-                lacunarity: 2.0,
-            },
-            glam::uvec3(32, 32, 32),
-        );
+        // Soak testing makes a mess of the world that nobody wants to keep, and has to
+        // keep streaming even when it's left running in the background. It watches the
+        // brickmap renderer's memory, so the other renderers can't be soak tested
+        if self.config.soak_hours.is_some() && self.config.renderer != RendererKind::Brickmap {
+            log::warn!("Soak testing needs the brickmap renderer, ignoring soak.hours");
+            self.config.soak_hours = None;
+        }
+        let mut soak = self.config.soak_hours.map(|hours| {
+            SoakTest::new(
+                self.config.soak_seed,
+                camera_controller.get_position(),
+                Duration::from_secs_f32(hours * 3600.0),
+            )
+        });
+        if soak.is_some() {
+            self.background.pause_streaming = false;
+        }
 
-        let mut renderer = BrickmapRenderer::new(&self.render_ctx, &camera_controller)?;
+        // Edits to each world are kept between runs, except in scenes which have to look
+        // the same wherever they're run
+        let persistent = soak.is_none() && self.scene.is_none();
+        for (i, world) in worlds.iter_mut().enumerate().filter(|_| persistent) {
+            let path = Path::new(WORLD_SAVE_PATH).join(format!("world{}", i));
+            match voxel::world::WorldStorage::new(&path) {
+                Ok(storage) => world.set_storage(Some(storage)),
+                Err(e) => log::error!("World {} won't be saved: {:#}", i, e),
+            }
+        }
+        // Imported models are re-imported whenever they change on disk
+        let mut import_watcher = voxel::io::ImportWatcher::new();
+        // Whether the volume entities are spinning
+        let mut spin_volumes = false;
+        if let Some(import) = self.scene.as_ref().and_then(|s| s.import.as_ref()) {
+            let world = &mut worlds[active_world];
+            let origin = import
+                .origin
+                .unwrap_or_else(|| (start_position * 8.0).floor().as_ivec3());
+            let voxel_size = self
+                .config
+                .import_voxel_size
+                .unwrap_or(world.get_voxel_size());
+            import_watcher.import(
+                &import.path,
+                world,
+                origin,
+                voxel::io::ImportMode::Replace,
+                voxel_size,
+            )?;
+        }
+
+        let mut budget = load_budget(&self.render_ctx, &self.config);
+        let sun = self.scene.as_ref().and_then(|s| s.sun).unwrap_or_default();
+        let mut lighting = Lighting::new(&self.render_ctx, sun);
+        let mut environment = Environment::new(sun);
+        let mut renderer = create_renderer(
+            &self.render_ctx,
+            &self.config,
+            &camera_controller,
+            &lighting,
+            &mut worlds[active_world],
+            &mut budget,
+        )?;
+        if let Some(renderer) = renderer.as_brickmap_mut() {
+            self.apply_quality(renderer, &camera_controller)?;
+            let mut settings = renderer.get_settings();
+            settings.ui_scale = self.config.ui_scale;
+            settings.ui_theme = self.config.ui_theme;
+            if let Some(scene) = &self.scene {
+                scene.apply_to_render_settings(&mut settings);
+            }
+            renderer.set_settings(&self.render_ctx, settings);
+        }
+
+        // Tells the user about changes to the UI, with text from the locale
+        let (locale, announcer) = (&self.locale, &self.announcer);
+        let announce = |key: &str, args: &[(&str, &dyn Display)]| {
+            announcer.announce(&locale.format(key, args));
+        };
+        let on_off = |value: bool| locale.get(if value { "state.on" } else { "state.off" });
+        announce("announce.started", &[("title", &self.title)]);
 
         let mut cumulative_dt = 0.0;
         let mut frames_accumulated = 0.0;
         let mut last_render_time = Instant::now();
+        let mut focused = true;
+        let mut frame_index = 0u64;
+        let mut random = RandomService::new(worlds[active_world].get_settings().seed);
+        let mut frame_capture = gfx::FrameCapture::new();
+        let mut cursor_position = glam::UVec2::ZERO;
+        let mut pick_decal = None;
+        let mut pick_action = PickAction::Inspect;
+        // What placing puts down. Breaking a voxel picks up its material
+        let mut held_material = voxel::world::BuiltinMaterial::Stone.id();
+        let mut last_pick = None;
+        // Voxels to roll back to, taken with C and restored with V
+        let mut world_snapshot: Option<voxel::world::WorldSnapshot> = None;
+        // Light entity the gizmo is moving, if it's shown
+        let mut gizmo_light: Option<EntityId> = None;
+        let mut weather = WeatherController::new(Weather::Clear);
+
+        let mut scheduler = Scheduler::new(SIMULATION_BUDGET);
+        scheduler.add_system(
+            Box::new(AutosaveSystem),
+            Priority::Low,
+            Duration::from_millis(2),
+            AUTOSAVE_INTERVAL,
+        );
+        scheduler.add_system(
+            Box::new(GrassSystem::default()),
+            Priority::Low,
+            Duration::from_millis(1),
+            GRASS_INTERVAL,
+        );
+        let (debris_sender, debris_receiver) = mpsc::channel();
+        scheduler.add_system(
+            Box::new(DebrisSystem::new(debris_receiver)),
+            Priority::Normal,
+            Duration::from_millis(1),
+            Duration::ZERO,
+        );
+        let background_frame_time = Duration::from_secs_f32(1.0 / self.background.frame_rate);
         self.event_loop.run(|event, elwt| {
             match event {
-                Event::WindowEvent { window_id, event }
-                    if window_id == self.render_ctx.window.id() =>
-                {
+                Event::NewEvents(StartCause::ResumeTimeReached { .. }) => {
+                    self.window.request_redraw();
+                }
+                Event::Suspended => {
+                    // Mobile apps can be killed at any point while suspended
+                    log::info!("App suspended");
+                    self.render_ctx.suspend();
+                    save_worlds(&mut worlds);
+                }
+                Event::LoopExiting => save_worlds(&mut worlds),
+                Event::Resumed => {
+                    log::info!("App resumed");
+                    let format_changed = match self.render_ctx.resume() {
+                        Ok(format_changed) => format_changed,
+                        Err(e) => {
+                            log::error!("Failed to recreate window surface: {}", e);
+                            elwt.exit();
+                            return;
+                        }
+                    };
+                    // The renderer's pipelines were built for the old format
+                    if format_changed {
+                        if let Err(e) = rebuild_renderer(
+                            &self.render_ctx,
+                            &camera_controller,
+                            &lighting,
+                            &mut worlds[active_world],
+                            &mut budget,
+                            &mut renderer,
+                            &mut entities,
+                        ) {
+                            log::error!("Failed to rebuild renderer: {}", e);
+                            elwt.exit();
+                            return;
+                        }
+                    }
+                    self.window.request_redraw();
+                }
+                Event::DeviceEvent {
+                    event: DeviceEvent::MouseMotion { delta },
+                    ..
+                } => {
+                    camera_controller.process_mouse_motion(delta);
+                }
+                Event::WindowEvent { window_id, event } if window_id == self.window.id() => {
                     if self.render_ctx.handle_window_event(&event, elwt) {
+                        // The context has already picked up the new size
+                        if matches!(
+                            event,
+                            WindowEvent::Resized(_) | WindowEvent::ScaleFactorChanged { .. }
+                        ) {
+                            let size = self.render_ctx.size;
+                            camera_controller.resize(size.width, size.height);
+                            if let Err(e) = renderer.resize(&self.render_ctx, &camera_controller) {
+                                log::error!("Failed to resize renderer: {:#}", e);
+                            }
+                        }
+                        return;
+                    }
+
+                    if let WindowEvent::Focused(is_focused) = event {
+                        focused = is_focused;
+                        if !focused {
+                            camera_controller.set_mouse_look(&self.window, false);
+                        }
+                        log::info!(
+                            "Window {}, switching to {} mode",
+                            if focused { "focused" } else { "unfocused" },
+                            if focused { "foreground" } else { "background" },
+                        );
+
+                        if self.background.low_power_adapter {
+                            let power_preference = if focused {
+                                wgpu::PowerPreference::HighPerformance
+                            } else {
+                                wgpu::PowerPreference::LowPower
+                            };
+
+                            // Switching adapters invalidates every GPU resource, so the camera
+                            // and light buffers and renderer have to be rebuilt on the new device
+                            let result = pollster::block_on(
+                                self.render_ctx.set_power_preference(power_preference),
+                            )
+                            .and_then(|_| {
+                                camera_controller.recreate_buffer(&self.render_ctx);
+                                lighting.recreate_buffer(&self.render_ctx);
+                                budget = load_budget(&self.render_ctx, &self.config);
+                                rebuild_renderer(
+                                    &self.render_ctx,
+                                    &camera_controller,
+                                    &lighting,
+                                    &mut worlds[active_world],
+                                    &mut budget,
+                                    &mut renderer,
+                                    &mut entities,
+                                )
+                            });
+                            if let Err(e) = result {
+                                log::error!("Failed to switch GPU adapter: {}", e);
+                                elwt.exit();
+                                return;
+                            }
+                        }
+
+                        if focused {
+                            elwt.set_control_flow(ControlFlow::Wait);
+                            self.window.request_redraw();
+                        }
                         return;
                     }
 
@@ -100,34 +474,929 @@ impl<'window> App<'window> {
                         return;
                     }
 
+                    // Dropping a .vox file on the window imports it at the camera
+                    if let WindowEvent::DroppedFile(path) = &event {
+                        let world = &mut worlds[active_world];
+                        let origin = (camera_controller.get_position() * 8.0).floor().as_ivec3();
+                        let voxel_size = self
+                            .config
+                            .import_voxel_size
+                            .unwrap_or(world.get_voxel_size());
+                        let file = voxel::io::get_file_name(path);
+                        match import_watcher.import(
+                            path,
+                            world,
+                            origin,
+                            voxel::io::ImportMode::Replace,
+                            voxel_size,
+                        ) {
+                            Ok(()) => announce("announce.import_started", &[("file", &file)]),
+                            Err(e) => {
+                                log::error!("Failed to import {:?}: {:#}", path, e);
+                                announce("announce.import_failed", &[("file", &file)]);
+                            }
+                        }
+                        return;
+                    }
+
+                    // Clicking picks whatever voxel is under the cursor, and middle click
+                    // blows it up. Left clicking on the gizmo drags it instead. Right click
+                    // turns on mouse look, which aims with a crosshair: left click breaks
+                    // the voxel under it and right click places one. Escape gets out of it.
+                    let pick_position = match camera_controller.is_mouse_look() {
+                        true => glam::uvec2(
+                            self.render_ctx.size.width / 2,
+                            self.render_ctx.size.height / 2,
+                        ),
+                        false => cursor_position,
+                    };
+                    let screen_size =
+                        glam::uvec2(self.render_ctx.size.width, self.render_ctx.size.height);
+                    let ray_origin = camera_controller.get_position() * 8.0;
+                    let ray_dir = camera_controller.get_screen_ray(pick_position, screen_size);
+                    match event {
+                        WindowEvent::CursorMoved { position, .. } => {
+                            cursor_position = glam::uvec2(position.x as u32, position.y as u32);
+                            let Some(renderer) = renderer.as_brickmap_mut() else {
+                                return;
+                            };
+                            let ray_dir =
+                                camera_controller.get_screen_ray(cursor_position, screen_size);
+                            let gizmo = renderer.get_gizmo_mut();
+                            if !gizmo.is_dragging() {
+                                gizmo.hover(ray_origin, ray_dir);
+                            } else if let (Some(transform), Some(light)) =
+                                (gizmo.drag(ray_origin, ray_dir), gizmo_light)
+                            {
+                                // Lights stay lined up with the axes, so rotating doesn't
+                                // do anything to them, and point lights ignore the scale
+                                if let Some(entity) = entities.get_mut(light) {
+                                    entity.transform.position = transform.position;
+                                    entity.transform.scale = transform.scale;
+                                }
+                            }
+                            return;
+                        }
+                        WindowEvent::MouseInput {
+                            state: ElementState::Pressed,
+                            button: MouseButton::Left,
+                            ..
+                        } if renderer
+                            .as_brickmap_mut()
+                            .is_some_and(|r| r.get_gizmo_mut().begin_drag(ray_origin, ray_dir)) =>
+                        {
+                            return;
+                        }
+                        WindowEvent::MouseInput {
+                            state: ElementState::Released,
+                            button: MouseButton::Left,
+                            ..
+                        } => {
+                            let gizmo = renderer.as_brickmap_mut().map(|r| r.get_gizmo_mut());
+                            if let Some(transform) = gizmo.and_then(|gizmo| gizmo.end_drag()) {
+                                log::info!("Moved light to {}", transform.position);
+                            }
+                            return;
+                        }
+                        WindowEvent::MouseInput {
+                            state: ElementState::Pressed,
+                            button: button @ (MouseButton::Left | MouseButton::Middle),
+                            ..
+                        } => {
+                            let Some(renderer) = renderer.as_brickmap_mut() else {
+                                return;
+                            };
+                            pick_action = match button {
+                                MouseButton::Middle => PickAction::Explode,
+                                _ if camera_controller.is_mouse_look() => PickAction::Break,
+                                _ => PickAction::Inspect,
+                            };
+                            renderer.request_pick(&self.render_ctx, pick_position);
+                            return;
+                        }
+                        WindowEvent::MouseInput {
+                            state: ElementState::Pressed,
+                            button: MouseButton::Right,
+                            ..
+                        } => {
+                            match renderer.as_brickmap_mut() {
+                                Some(renderer) if camera_controller.is_mouse_look() => {
+                                    pick_action = PickAction::Place;
+                                    renderer.request_pick(&self.render_ctx, pick_position);
+                                }
+                                // The other renderers can't pick, so right click just
+                                // toggles mouse look
+                                _ => {
+                                    let enabled = !camera_controller.is_mouse_look();
+                                    camera_controller.set_mouse_look(&self.window, enabled);
+                                }
+                            }
+                            return;
+                        }
+                        WindowEvent::KeyboardInput {
+                            event:
+                                KeyEvent {
+                                    state: ElementState::Pressed,
+                                    physical_key: PhysicalKey::Code(KeyCode::Escape),
+                                    ..
+                                },
+                            ..
+                        } => {
+                            camera_controller.set_mouse_look(&self.window, false);
+                            return;
+                        }
+                        _ => (),
+                    }
+
+                    // Debug toggles, only the brickmap renderer has any
+                    if let WindowEvent::KeyboardInput {
+                        event:
+                            KeyEvent {
+                                state: ElementState::Pressed,
+                                physical_key: PhysicalKey::Code(keycode),
+                                repeat: false,
+                                ..
+                            },
+                        ..
+                    } = event
+                    {
+                        let Some(renderer) = renderer.as_brickmap_mut() else {
+                            return;
+                        };
+                        let mut settings = renderer.get_settings();
+                        match keycode {
+                            KeyCode::F1 => {
+                                weather
+                                    .set_weather(weather.get_weather().next(), WEATHER_TRANSITION);
+                                return;
+                            }
+                            KeyCode::F2 => {
+                                settings.variable_rate = !settings.variable_rate;
+                                announce(
+                                    "announce.variable_rate",
+                                    &[("value", &on_off(settings.variable_rate))],
+                                );
+                            }
+                            KeyCode::KeyH => {
+                                settings.debug_heatmap = settings.debug_heatmap.next();
+                                announce(
+                                    "announce.debug_heatmap",
+                                    &[("value", &format!("{:?}", settings.debug_heatmap))],
+                                );
+                            }
+                            KeyCode::KeyP => {
+                                settings.debug_palette = settings.debug_palette.next();
+                                announce(
+                                    "announce.debug_palette",
+                                    &[("value", &format!("{:?}", settings.debug_palette))],
+                                );
+                            }
+                            KeyCode::KeyT => {
+                                settings.temporal_accumulation = !settings.temporal_accumulation;
+                                announce(
+                                    "announce.temporal_accumulation",
+                                    &[("value", &on_off(settings.temporal_accumulation))],
+                                );
+                            }
+                            KeyCode::KeyO => {
+                                // Off, then silhouettes and creases, then every voxel
+                                settings.outline = match settings.outline {
+                                    None => Some(Outline::default()),
+                                    Some(outline) if !outline.voxel_edges => Some(Outline {
+                                        voxel_edges: true,
+                                        ..outline
+                                    }),
+                                    Some(_) => None,
+                                };
+                                let key = match settings.outline {
+                                    None => "state.off",
+                                    Some(outline) if outline.voxel_edges => "outline.voxel_edges",
+                                    Some(_) => "outline.silhouettes",
+                                };
+                                announce("announce.outline", &[("value", &locale.get(key))]);
+                            }
+                            KeyCode::KeyX => {
+                                settings.exposure = match settings.exposure {
+                                    Exposure::Manual(_) => Exposure::auto(),
+                                    Exposure::Auto { .. } => Exposure::default(),
+                                };
+                                let key = match settings.exposure {
+                                    Exposure::Manual(_) => "exposure.manual",
+                                    Exposure::Auto { .. } => "exposure.auto",
+                                };
+                                announce("announce.exposure", &[("value", &locale.get(key))]);
+                            }
+                            KeyCode::KeyK => {
+                                settings.baked_ao = !settings.baked_ao;
+                                announce(
+                                    "announce.baked_ao",
+                                    &[("value", &on_off(settings.baked_ao))],
+                                );
+                            }
+                            KeyCode::KeyU => {
+                                settings.water = match settings.water {
+                                    None => Some(Water::default()),
+                                    Some(_) => None,
+                                };
+                                announce(
+                                    "announce.water",
+                                    &[("value", &on_off(settings.water.is_some()))],
+                                );
+                            }
+                            KeyCode::KeyN => {
+                                settings.surface_detail = match settings.surface_detail {
+                                    None => Some(SurfaceDetail::default()),
+                                    Some(_) => None,
+                                };
+                                announce(
+                                    "announce.surface_detail",
+                                    &[("value", &on_off(settings.surface_detail.is_some()))],
+                                );
+                            }
+                            KeyCode::KeyI => {
+                                settings.gpu_profiling = !settings.gpu_profiling;
+                                announce(
+                                    "announce.gpu_profiling",
+                                    &[("value", &on_off(settings.gpu_profiling))],
+                                );
+                            }
+                            KeyCode::KeyB => {
+                                settings.emissive_bounce = !settings.emissive_bounce;
+                                announce(
+                                    "announce.emissive_bounce",
+                                    &[("value", &on_off(settings.emissive_bounce))],
+                                );
+                            }
+                            KeyCode::KeyL => {
+                                settings.quarter_res_lighting = !settings.quarter_res_lighting;
+                                announce(
+                                    "announce.quarter_res_lighting",
+                                    &[("value", &on_off(settings.quarter_res_lighting))],
+                                );
+                            }
+                            KeyCode::F3 => {
+                                settings.light_probes = !settings.light_probes;
+                                announce(
+                                    "announce.light_probes",
+                                    &[("value", &on_off(settings.light_probes))],
+                                );
+                            }
+                            KeyCode::F7 => {
+                                // Put the terrain around the camera back the way the
+                                // generator makes it, undoing any edits there
+                                let world = &mut worlds[active_world];
+                                let generation = world.get_settings();
+                                let center = camera_controller.get_position().floor().as_ivec3();
+                                let radius = glam::IVec3::splat(REGENERATE_RADIUS);
+                                world.regenerate_region(
+                                    (center - radius) * 8,
+                                    (center + radius) * 8,
+                                    generation,
+                                );
+                                return;
+                            }
+                            KeyCode::F8 => {
+                                log::info!("{}", worlds[active_world].get_generation_report(10));
+                                return;
+                            }
+                            KeyCode::KeyC => {
+                                let snapshot = worlds[active_world].snapshot();
+                                announce(
+                                    "announce.snapshot",
+                                    &[("chunks", &snapshot.get_chunk_count())],
+                                );
+                                world_snapshot = Some(snapshot);
+                                return;
+                            }
+                            KeyCode::KeyV => {
+                                let Some(snapshot) = &world_snapshot else {
+                                    announce("announce.no_snapshot", &[]);
+                                    return;
+                                };
+                                if let Err(e) = worlds[active_world].restore(snapshot) {
+                                    log::error!("Failed to restore snapshot: {:#}", e);
+                                }
+                                return;
+                            }
+                            KeyCode::F10 => {
+                                active_world = (active_world + 1) % worlds.len();
+                                // Tear down the old world's bricks now, so nothing this
+                                // frame streams from the new world into the old grid
+                                renderer
+                                    .get_brickmap_manager_mut()
+                                    .set_world(&self.render_ctx, &worlds[active_world]);
+                                announce("announce.world", &[("world", &active_world)]);
+                                camera_controller
+                                    .set_world_scale(worlds[active_world].get_bricks_per_metre());
+                                return;
+                            }
+                            KeyCode::F11 => {
+                                let portals = renderer.get_portal_manager_mut();
+                                if portals.get_portals().is_empty() {
+                                    // In front of the starting camera, looking out over
+                                    // the middle of the world
+                                    portals.add_portal(Portal {
+                                        position: glam::vec3(4.0, 4.0, 16.0),
+                                        rotation: glam::Quat::IDENTITY,
+                                        half_size: glam::vec2(1.5, 1.0),
+                                        exit_position: glam::vec3(128.0, 24.0, 128.0),
+                                        exit_rotation: glam::Quat::IDENTITY,
+                                    });
+                                } else {
+                                    portals.clear();
+                                }
+                                announce(
+                                    "announce.portals",
+                                    &[(
+                                        "count",
+                                        &renderer.get_portal_manager().get_portals().len(),
+                                    )],
+                                );
+                                return;
+                            }
+                            KeyCode::F12 => {
+                                settings.debug_lines = !settings.debug_lines;
+                                settings.ray_budget_debug = settings.debug_lines;
+                                announce(
+                                    "announce.debug_lines",
+                                    &[("value", &on_off(settings.debug_lines))],
+                                );
+                            }
+                            KeyCode::F9 => {
+                                if frame_capture.arm() {
+                                    log::info!("Capturing frame {}", frame_index);
+                                } else {
+                                    log::warn!("Can't capture frame, RenderDoc isn't loaded");
+                                }
+
+                                // Dump the brick that was last picked, or the one we're in
+                                let cell = match last_pick {
+                                    Some(pos) => pos,
+                                    None => camera_controller.get_position().floor().as_ivec3(),
+                                };
+                                let path = format!("brick_dump_{}.txt", frame_index);
+                                match dump_brick(&self.render_ctx, renderer, cell, &path) {
+                                    Ok(()) => log::info!("Dumped brick {} to {}", cell, path),
+                                    Err(e) => log::error!("Failed to dump brick: {:#}", e),
+                                }
+                                return;
+                            }
+                            KeyCode::KeyG => {
+                                let name = format!("frame_graph_{}", frame_index);
+                                match dump_frame_graph(renderer, &name) {
+                                    Ok(()) => log::info!("Dumped frame graph to {}.dot", name),
+                                    Err(e) => log::error!("Failed to dump frame graph: {:#}", e),
+                                }
+                                return;
+                            }
+                            KeyCode::F5 => {
+                                settings.raycast_stats = !settings.raycast_stats;
+                                announce(
+                                    "announce.raycast_stats",
+                                    &[("value", &on_off(settings.raycast_stats))],
+                                );
+                            }
+                            KeyCode::F6 => {
+                                settings.sub_voxel_detail = !settings.sub_voxel_detail;
+                                announce(
+                                    "announce.sub_voxel_detail",
+                                    &[("value", &on_off(settings.sub_voxel_detail))],
+                                );
+                            }
+                            KeyCode::F4 => {
+                                let is_point_light = |entity: &Entity| {
+                                    matches!(entity.kind, EntityKind::PointLight { .. })
+                                };
+                                let count = entities
+                                    .iter()
+                                    .filter(|(_, entity)| is_point_light(entity))
+                                    .count();
+                                let count = if count == 0 {
+                                    scatter_demo_lights(&mut entities, 256)
+                                } else {
+                                    entities.retain(|_, entity| !is_point_light(entity));
+                                    if gizmo_light.is_some_and(|id| entities.get(id).is_none()) {
+                                        gizmo_light = None;
+                                        renderer.get_gizmo_mut().set_target(None);
+                                    }
+                                    0
+                                };
+                                announce("announce.point_lights", &[("count", &count)]);
+                            }
+                            KeyCode::KeyM => {
+                                // Grabs the light closest to the camera
+                                let camera_pos = camera_controller.get_position() * 8.0;
+                                gizmo_light = match gizmo_light {
+                                    Some(_) => None,
+                                    None => entities
+                                        .iter()
+                                        .filter(|(_, entity)| entity.kind.is_light())
+                                        .min_by(|(_, a), (_, b)| {
+                                            let a =
+                                                a.transform.position.distance_squared(camera_pos);
+                                            let b =
+                                                b.transform.position.distance_squared(camera_pos);
+                                            a.total_cmp(&b)
+                                        })
+                                        .map(|(id, _)| id),
+                                };
+                                let light = gizmo_light.and_then(|id| entities.get(id));
+                                renderer
+                                    .get_gizmo_mut()
+                                    .set_target(light.map(light_transform));
+                                match (gizmo_light, light.map(|light| &light.kind)) {
+                                    (Some(id), Some(EntityKind::AreaLight { .. })) => {
+                                        announce("announce.gizmo_area_light", &[("light", &id)])
+                                    }
+                                    (Some(id), Some(_)) => {
+                                        announce("announce.gizmo_light", &[("light", &id)])
+                                    }
+                                    _ => announce("announce.gizmo_hidden", &[]),
+                                }
+                                return;
+                            }
+                            KeyCode::KeyJ => {
+                                // Hangs a panel light above the camera, ready to be moved
+                                let position = camera_controller.get_position() * 8.0
+                                    + glam::Vec3::Y * AREA_LIGHT_HEIGHT;
+                                if renderer.get_light_manager().is_full() {
+                                    announce("announce.lights_full", &[]);
+                                    return;
+                                }
+                                let light = Entity::new(
+                                    Transform::from_position(position).with_scale(AREA_LIGHT_SIZE),
+                                    EntityKind::AreaLight {
+                                        color: glam::vec3(1.0, 0.95, 0.85),
+                                        intensity: 400.0,
+                                    },
+                                );
+                                renderer
+                                    .get_gizmo_mut()
+                                    .set_target(Some(light_transform(&light)));
+                                let id = entities.spawn(light);
+                                gizmo_light = Some(id);
+                                announce("announce.area_light", &[("light", &id)]);
+                                return;
+                            }
+                            KeyCode::KeyY => {
+                                match environment.get_time_of_day() {
+                                    Some(_) => environment.stop_clock(),
+                                    None => {
+                                        environment.set_time_of_day(DAY_CYCLE_START);
+                                        environment.set_time_scale(DAY_CYCLE_SPEED);
+                                    }
+                                }
+                                announce(
+                                    "announce.day_cycle",
+                                    &[("value", &on_off(environment.get_time_of_day().is_some()))],
+                                );
+                                return;
+                            }
+                            KeyCode::KeyF => {
+                                // Places a movable copy of the last imported model where
+                                // the camera's looking
+                                let world = &mut worlds[active_world];
+                                let Some(file) = import_watcher.get_latest(world.get_id()) else {
+                                    announce("announce.no_model", &[]);
+                                    return;
+                                };
+                                let mut added = 0;
+                                for model in &file.models {
+                                    let (dims, voxels) =
+                                        model.to_dense(&file.palette, world.get_materials_mut());
+                                    let center = ray_origin + ray_dir * VOLUME_DISTANCE;
+                                    match voxel::brickmap::VolumeModel::new(dims, voxels) {
+                                        Ok(model) => {
+                                            entities.spawn(Entity::new(
+                                                Transform::from_position(center),
+                                                EntityKind::Volume(Arc::new(model)),
+                                            ));
+                                            added += 1;
+                                        }
+                                        Err(e) => log::error!("Failed to add volume: {:#}", e),
+                                    }
+                                }
+                                let count = entities
+                                    .iter()
+                                    .filter(|(_, e)| matches!(e.kind, EntityKind::Volume(_)))
+                                    .count();
+                                announce(
+                                    "announce.volumes",
+                                    &[("added", &added), ("count", &count)],
+                                );
+                                return;
+                            }
+                            KeyCode::Period => {
+                                spin_volumes = !spin_volumes;
+                                announce(
+                                    "announce.spin_volumes",
+                                    &[("value", &on_off(spin_volumes))],
+                                );
+                                return;
+                            }
+                            KeyCode::KeyZ => {
+                                let cancelled = import_watcher.cancel_all();
+                                if cancelled.is_empty() {
+                                    announce("announce.no_imports", &[]);
+                                }
+                                for path in cancelled {
+                                    let file = voxel::io::get_file_name(&path);
+                                    announce("announce.import_cancelled", &[("file", &file)]);
+                                }
+                                return;
+                            }
+                            KeyCode::KeyR => {
+                                let gizmo = renderer.get_gizmo_mut();
+                                gizmo.set_mode(gizmo.get_mode().next());
+                                let mode = format!("{:?}", gizmo.get_mode());
+                                announce("announce.gizmo_mode", &[("mode", &mode)]);
+                                return;
+                            }
+                            _ => return,
+                        }
+                        renderer.set_settings(&self.render_ctx, settings);
+                        return;
+                    }
+
                     if let WindowEvent::RedrawRequested = event {
+                        // No surface to present to, we'll be woken up again by `Resumed`
+                        if self.render_ctx.is_suspended() {
+                            return;
+                        }
+
                         let now = Instant::now();
                         let dt = now - last_render_time;
                         last_render_time = now;
+                        random.begin_frame(worlds[active_world].get_settings().seed, frame_index);
+                        if let Some(renderer) = renderer.as_brickmap_mut() {
+                            renderer.set_frame_seed(&self.render_ctx, random.get_frame_seed());
+                        }
                         camera_controller.update(dt);
+                        let camera = *camera_controller.get_camera();
+                        let world_seed = worlds[active_world].get_settings().seed;
+                        crash::update_state(|state| {
+                            state.frame = frame_index;
+                            state.camera_position = camera.position;
+                            state.camera_yaw = camera.yaw;
+                            state.camera_pitch = camera.pitch;
+                            state.world_seed = world_seed;
+                        });
+                        if let (Some(test), Some(renderer)) = (&mut soak, renderer.as_brickmap()) {
+                            let world = &mut worlds[active_world];
+                            if !test.update(&dt, &mut camera_controller, world, renderer) {
+                                elwt.exit();
+                                return;
+                            }
+                        }
+                        if spin_volumes {
+                            let spin =
+                                glam::Quat::from_rotation_y(VOLUME_SPIN_SPEED * dt.as_secs_f32());
+                            let volumes: Vec<EntityId> = entities
+                                .iter()
+                                .filter(|(_, e)| matches!(e.kind, EntityKind::Volume(_)))
+                                .map(|(id, _)| id)
+                                .collect();
+                            for id in volumes {
+                                if let Some(entity) = entities.get_mut(id) {
+                                    entity.transform.rotation = spin * entity.transform.rotation;
+                                }
+                            }
+                        }
+                        entities.sync(&self.render_ctx, renderer.as_mut(), &mut camera_controller);
                         camera_controller.update_buffer(&self.render_ctx);
+                        environment.update(&dt);
+                        lighting.set_sun(environment.get_sun());
+                        lighting.update_buffer(&self.render_ctx);
+                        weather.update(&dt);
+                        if let Some(renderer) = renderer.as_brickmap_mut() {
+                            let sky = Some(environment.get_sky());
+                            if renderer.get_sky() != sky {
+                                renderer.set_sky(&self.render_ctx, sky);
+                            }
+                            renderer.update_sun_shadows(
+                                &self.render_ctx,
+                                lighting.get_sun(),
+                                camera_controller.get_position(),
+                            );
+                            apply_weather(&self.render_ctx, &weather, renderer);
+                            let mut settings = renderer.get_settings();
+                            if settings.crosshair != camera_controller.is_mouse_look() {
+                                settings.crosshair = camera_controller.is_mouse_look();
+                                renderer.set_settings(&self.render_ctx, settings);
+                            }
+                            renderer.update_particles(
+                                &self.render_ctx,
+                                &dt,
+                                camera_controller.get_position(),
+                            );
+                            renderer.update_exposure(&self.render_ctx, &dt);
+                            renderer.update_debug_lines(
+                                &self.render_ctx,
+                                &mut worlds[active_world],
+                                camera_controller.get_position(),
+                            );
+                            renderer
+                                .get_gizmo_mut()
+                                .update(&self.render_ctx, camera_controller.get_position() * 8.0);
+                        }
+                        for event in import_watcher.update(&mut worlds[active_world]) {
+                            match event {
+                                voxel::io::ImportEvent::Finished { path, changed } => {
+                                    let file = voxel::io::get_file_name(&path);
+                                    announce(
+                                        "announce.import_finished",
+                                        &[("file", &file), ("voxels", &changed)],
+                                    );
+                                }
+                                voxel::io::ImportEvent::Failed { path, .. } => {
+                                    let file = voxel::io::get_file_name(&path);
+                                    announce("announce.import_failed", &[("file", &file)]);
+                                }
+                            }
+                        }
+                        scheduler.run(
+                            &mut worlds[active_world],
+                            camera_controller.get_position(),
+                            &dt,
+                            &random,
+                        );
+
+                        // We can't propagate errors out of here, so GPU errors get handled
+                        // below and anything else just costs us the frame
+                        let mut results = Vec::new();
+                        if let Some(renderer) = renderer.as_brickmap_mut() {
+                            renderer.update_accumulation(&self.render_ctx, &camera_controller);
+                        }
+                        frame_capture.begin_frame();
+                        if let Some(renderer) = renderer.as_brickmap_mut() {
+                            results.push(renderer.update_bind_groups(
+                                &self.render_ctx,
+                                &camera_controller,
+                                &lighting,
+                            ));
+                        }
+                        results.push(renderer.render(&self.render_ctx));
+                        if focused || !self.background.pause_streaming {
+                            let position = camera_controller.get_position();
+                            worlds[active_world].set_focus(position * 8.0);
+                            match renderer.as_brickmap_mut() {
+                                Some(renderer) => {
+                                    renderer.set_focus(&self.render_ctx, position);
+                                    renderer.prefetch_brickmaps(
+                                        &mut worlds[active_world],
+                                        &camera_controller,
+                                    );
+                                }
+                                None => renderer.set_focus(position),
+                            }
+                            results.push(renderer.update(
+                                &dt,
+                                &self.render_ctx,
+                                &mut worlds[active_world],
+                            ));
+                        }
+                        let mut description = format!(
+                            "Frame: {}\nFrame time: {:.2}ms\nAdapter: {}\nCamera: {}",
+                            frame_index,
+                            dt.as_secs_f32() * 1000.0,
+                            self.render_ctx.adapter.get_info().name,
+                            camera_controller.get_position(),
+                        );
+                        if let Some(renderer) = renderer.as_brickmap() {
+                            description += &format!(
+                                "\nLoaded brickmaps: {}\nSettings: {:?}",
+                                renderer.get_brickmap_manager().get_num_loaded_brickmaps(),
+                                renderer.get_settings(),
+                            );
+                        }
+                        frame_capture.end_frame(&description);
+                        frame_index += 1;
+
+                        let mut gpu_errors = self.render_ctx.poll_errors();
+                        for result in results {
+                            if let Err(e) = result {
+                                match e.downcast::<GpuError>() {
+                                    Ok(error) => gpu_errors.push(error),
+                                    Err(e) => log::debug!("Skipped frame: {}", e),
+                                }
+                            }
+                        }
 
-                        // !Hack: As far as I know I can't propagate errors out of here. So for now just ignore them
-                        let _ = renderer.render(&self.render_ctx);
-                        let _ = renderer.update(&dt, &self.render_ctx, &mut world);
+                        let mut needs_rebuild = false;
+                        for error in &gpu_errors {
+                            needs_rebuild |= recover_from_gpu_error(
+                                error,
+                                &self.render_ctx,
+                                renderer.as_mut(),
+                                &mut budget,
+                            );
+                        }
+                        if gpu_errors
+                            .iter()
+                            .any(|e| e.kind == GpuErrorKind::DeviceLost)
+                        {
+                            let result =
+                                pollster::block_on(self.render_ctx.recreate_device()).map(|_| {
+                                    camera_controller.recreate_buffer(&self.render_ctx);
+                                    lighting.recreate_buffer(&self.render_ctx);
+                                    budget = load_budget(&self.render_ctx, &self.config);
+                                });
+                            if let Err(e) = result {
+                                log::error!("Failed to recover from device loss: {:#}", e);
+                                elwt.exit();
+                                return;
+                            }
+                        }
+                        if needs_rebuild {
+                            if let Err(e) = rebuild_renderer(
+                                &self.render_ctx,
+                                &camera_controller,
+                                &lighting,
+                                &mut worlds[active_world],
+                                &mut budget,
+                                &mut renderer,
+                                &mut entities,
+                            ) {
+                                log::error!("Failed to rebuild renderer: {}", e);
+                                elwt.exit();
+                                return;
+                            }
+                        }
 
                         // Simple framerate tracking
-                        self.render_ctx.window.set_title(&format!(
-                            "{}: {} fps",
-                            self.title,
-                            (1.0 / dt.as_secs_f32()).floor()
-                        ));
+                        let frame_fps = (1.0 / dt.as_secs_f32()).floor();
+                        // Only the brickmap renderer can pick
+                        if let Some(renderer) = renderer.as_brickmap_mut() {
+                            if let Some(pick) = renderer.take_pick_result() {
+                                match pick.hit {
+                                    Some(hit) => log::info!(
+                                        "Picked voxel {} (normal {}, albedo {:08x}) at {}",
+                                        hit.position,
+                                        hit.normal,
+                                        hit.albedo,
+                                        pick.cursor
+                                    ),
+                                    None => log::info!("Picked nothing at {}", pick.cursor),
+                                }
+
+                                if let Some(hit) = pick.hit {
+                                    last_pick =
+                                        Some(hit.position.div_euclid(glam::IVec3::splat(8)));
+                                }
+
+                                let world = &mut worlds[active_world];
+                                match (pick_action, pick.hit) {
+                                    (PickAction::Explode, Some(hit)) => {
+                                        let explosion = voxel::world::Explosion {
+                                            seed: random
+                                                .frame_rng(RandomStream::Explosions, 0)
+                                                .next_u32()
+                                                as i32,
+                                            ..voxel::world::Explosion::new(
+                                                hit.position,
+                                                EXPLOSION_RADIUS,
+                                            )
+                                        };
+                                        let result = explosion.detonate(world);
+                                        // The debris system lives as long as the loop does
+                                        let _ = debris_sender.send(result.debris);
+                                    }
+                                    (PickAction::Break, Some(hit)) => {
+                                        if let voxel::world::Voxel::Material(material) =
+                                            world.get_voxel(hit.position)
+                                        {
+                                            held_material = material;
+                                        }
+                                        edit_voxel(
+                                            world,
+                                            renderer,
+                                            hit.position,
+                                            voxel::world::Voxel::Empty,
+                                        );
+                                    }
+                                    (PickAction::Place, Some(hit)) => {
+                                        // Don't wall the camera in
+                                        let target = hit.position + hit.normal;
+                                        let camera_voxel = (camera_controller.get_position() * 8.0)
+                                            .floor()
+                                            .as_ivec3();
+                                        if target != camera_voxel {
+                                            edit_voxel(
+                                                world,
+                                                renderer,
+                                                target,
+                                                voxel::world::Voxel::Material(held_material),
+                                            );
+                                        }
+                                    }
+                                    _ => {}
+                                }
+                                pick_action = PickAction::Inspect;
+
+                                // Highlight the picked face. Rebuilding the renderer loses
+                                // its decals, so the old highlight might already be gone
+                                let decals = renderer.get_decal_manager_mut();
+                                if let Some(index) = pick_decal.take() {
+                                    if index < decals.get_decals().len() {
+                                        decals.remove_decal(index);
+                                    }
+                                }
+                                if let Some(hit) = pick.hit {
+                                    pick_decal = decals.add_decal(Decal {
+                                        min: hit.position,
+                                        max: hit.position + 1,
+                                        color: match self.config.ui_theme {
+                                            UiTheme::Default => [255, 255, 255, 128],
+                                            UiTheme::HighContrast => [255, 255, 0, 255],
+                                        },
+                                        normal: Some(hit.normal),
+                                    });
+                                }
+                            }
+                        }
+
+                        let raycast_stats =
+                            renderer.as_brickmap().and_then(|r| r.get_raycast_stats());
+                        // Running imports show how far along they are in the title
+                        let base_title = match import_watcher.get_progress() {
+                            Some(progress) => self.locale.format(
+                                "title.importing",
+                                &[
+                                    ("title", &self.title),
+                                    ("file", &progress.get_file_name()),
+                                    ("percent", &format!("{:.0}", progress.fraction * 100.0)),
+                                    ("running", &progress.running),
+                                ],
+                            ),
+                            None => self.title.clone(),
+                        };
+                        let title = match raycast_stats {
+                            Some(stats) => self.locale.format(
+                                "title.fps_raycast",
+                                &[
+                                    ("title", &base_title),
+                                    ("fps", &frame_fps),
+                                    ("rays", &stats.rays),
+                                    ("steps", &format!("{:.1}", stats.get_average_steps())),
+                                    ("bricks", &format!("{:.1}", stats.get_average_bricks())),
+                                    ("miss", &format!("{:.0}", stats.get_miss_rate() * 100.0)),
+                                ],
+                            ),
+                            None => self.locale.format(
+                                "title.fps",
+                                &[("title", &base_title), ("fps", &frame_fps)],
+                            ),
+                        };
+                        self.window.set_title(&title);
                         cumulative_dt += dt.as_secs_f32();
                         frames_accumulated += 1.0;
                         if cumulative_dt >= 1.0 {
                             let fps = frames_accumulated * 1.0 / cumulative_dt;
                             let frame_time = cumulative_dt * 1000.0 / frames_accumulated;
                             log::info!("FPS: {}, Frame Time: {}", fps.floor(), frame_time);
+                            if let Some(stats) = raycast_stats {
+                                log::info!("Raycast stats: {:?}", stats);
+                            }
+                            let brickmap = renderer.as_brickmap();
+                            if let Some(timings) = brickmap.and_then(|r| r.get_gpu_timings()) {
+                                log::info!("GPU time: {}", timings);
+                            }
+                            let world = &worlds[active_world];
+                            log::debug!("World IO: {:?}", world.get_io_stats());
+                            let brickmaps =
+                                brickmap.map(|r| r.get_brickmap_manager().get_memory_stats());
+                            crash::update_state(|state| {
+                                state.fps = fps;
+                                state.chunks = world.get_chunk_count();
+                                state.pending_chunks = world.get_pending_chunk_count();
+                                state.io = Some(world.get_io_stats());
+                                state.brickmaps = brickmaps;
+                            });
+                            if let Some(renderer) = brickmap {
+                                let buckets =
+                                    renderer.get_brickmap_manager().get_shading_bucket_stats();
+                                log::debug!(
+                                    "Shading table buckets: {}",
+                                    buckets
+                                        .iter()
+                                        .map(|bucket| bucket.to_string())
+                                        .collect::<Vec<_>>()
+                                        .join(", ")
+                                );
+                            }
                             cumulative_dt = 0.0;
                             frames_accumulated = 0.0;
                         }
 
-                        self.render_ctx.window.request_redraw();
+                        // In the background we only redraw at a reduced rate so we aren't
+                        // keeping the GPU busy for a window nobody is looking at
+                        if focused {
+                            self.window.request_redraw();
+                        } else {
+                            elwt.set_control_flow(ControlFlow::WaitUntil(
+                                now + background_frame_time,
+                            ));
+                        }
                     }
                 }
                 _ => (),
@@ -136,4 +1405,278 @@ impl<'window> App<'window> {
 
         Ok(())
     }
+
+    /// Creates the camera where the scene puts it, or at the usual spawn point.
+    fn create_camera_controller(&self) -> camera::CameraController {
+        let start = self
+            .scene
+            .as_ref()
+            .and_then(|s| s.camera)
+            .unwrap_or(SceneCamera {
+                position: glam::vec3(4.01, 4.01, 20.0),
+                yaw: -90.0_f32.to_radians(),
+                pitch: 0.0_f32.to_radians(),
+            });
+        camera::CameraController::new(
+            &self.render_ctx,
+            camera::Camera::new(start.position, start.yaw, start.pitch),
+            camera::Projection::new(
+                self.render_ctx.size.width,
+                self.render_ctx.size.height,
+                90.0_f32.to_radians(),
+                0.01,
+                100.0,
+            ),
+            self.config.camera_speed,
+            self.config.mouse_sensitivity,
+        )
+    }
+}
+
+/// Changes a single voxel, and gets the bricks it touches re-culled straight away rather
+/// than waiting on the world's dirty blocks. Returns whether anything changed.
+fn edit_voxel(
+    world: &mut voxel::world::WorldManager,
+    renderer: &mut BrickmapRenderer,
+    pos: glam::IVec3,
+    voxel: voxel::world::Voxel,
+) -> bool {
+    if !world.set_voxel(pos, voxel) {
+        return false;
+    }
+    renderer.get_brickmap_manager_mut().mark_dirty(pos);
+    true
+}
+
+/// Creates whichever renderer the config asks for on the current device.
+fn create_renderer(
+    context: &gfx::Context,
+    config: &Config,
+    camera_controller: &camera::CameraController,
+    lighting: &Lighting,
+    world: &mut voxel::world::WorldManager,
+    budget: &mut BrickmapBudget,
+) -> Result<Box<dyn VoxelRenderer>> {
+    Ok(match config.renderer {
+        RendererKind::Brickmap => Box::new(create_brickmap_renderer(
+            context,
+            camera_controller,
+            lighting,
+            world,
+            budget,
+        )?),
+        RendererKind::Svo => Box::new(SvoRenderer::new(
+            context,
+            camera_controller,
+            lighting,
+            config.svo_depth,
+        )?),
+        RendererKind::Mesh => Box::new(MeshRenderer::new(context, camera_controller, lighting)?),
+    })
+}
+
+/// Creates a brickmap renderer on the current device and loads the area around the
+/// camera. If the GPU runs out of memory we keep shrinking the brickmap budget until it
+/// fits.
+fn create_brickmap_renderer(
+    context: &gfx::Context,
+    camera_controller: &camera::CameraController,
+    lighting: &Lighting,
+    world: &mut voxel::world::WorldManager,
+    budget: &mut BrickmapBudget,
+) -> Result<BrickmapRenderer> {
+    loop {
+        let result = BrickmapRenderer::new(context, camera_controller, lighting, *budget).and_then(
+            |mut renderer| {
+                let position = camera_controller.get_position();
+                let center = position.floor().as_ivec3();
+                renderer.set_focus(context, position);
+                renderer.get_brickmap_manager_mut().set_pinned_region(
+                    center - SPAWN_PIN_RADIUS,
+                    center + SPAWN_PIN_RADIUS + 1,
+                    true,
+                );
+                renderer.prewarm(context, world, position, PREWARM_RADIUS)?;
+                Ok(renderer)
+            },
+        );
+
+        let Err(e) = result else {
+            return result;
+        };
+        let out_of_memory = e
+            .downcast_ref::<GpuError>()
+            .is_some_and(|e| e.kind == GpuErrorKind::OutOfMemory);
+        match budget.shrink() {
+            Some(smaller) if out_of_memory => {
+                log::warn!("{}, retrying with a smaller brickmap budget", e);
+                *budget = smaller;
+                budget.save(context, Path::new(BRICKMAP_BUDGET_PATH));
+            }
+            _ => return Err(e),
+        }
+    }
+}
+
+fn save_worlds(worlds: &mut [voxel::world::WorldManager]) {
+    for world in worlds {
+        if let Err(e) = world.save() {
+            log::error!("Failed to save world: {:#}", e);
+        }
+    }
+}
+
+/// Pushes the weather's atmosphere and particles to the renderer, if they've changed.
+fn apply_weather(
+    context: &gfx::Context,
+    weather: &WeatherController,
+    renderer: &mut BrickmapRenderer,
+) {
+    let atmosphere = weather.get_atmosphere();
+    if renderer.get_atmosphere() != atmosphere {
+        renderer.set_atmosphere(context, atmosphere);
+    }
+
+    let mut settings = renderer.get_settings();
+    if settings.particles != weather.get_particles() {
+        settings.particles = weather.get_particles();
+        renderer.set_settings(context, settings);
+    }
+}
+
+/// Loads the brickmap budget tuned for the current adapter, with any overrides from the
+/// config on top.
+fn load_budget(context: &gfx::Context, config: &Config) -> BrickmapBudget {
+    let mut budget = BrickmapBudget::load_or_tune(context, Path::new(BRICKMAP_BUDGET_PATH));
+    config.apply_to_budget(&mut budget);
+    budget
+}
+
+/// Replaces the renderer with a fresh one, keeping its settings.
+fn rebuild_renderer(
+    context: &gfx::Context,
+    camera_controller: &camera::CameraController,
+    lighting: &Lighting,
+    world: &mut voxel::world::WorldManager,
+    budget: &mut BrickmapBudget,
+    renderer: &mut Box<dyn VoxelRenderer>,
+    entities: &mut SceneEntities,
+) -> Result<()> {
+    let Some(renderer) = renderer.as_brickmap_mut() else {
+        return renderer.rebuild(context, camera_controller, lighting);
+    };
+    let settings = renderer.get_settings();
+    *renderer = create_brickmap_renderer(context, camera_controller, lighting, world, budget)?;
+    renderer.set_settings(context, settings);
+    // The new renderer starts out without the entities' volumes and lights
+    entities.invalidate();
+    // The render scale only takes effect on resize
+    renderer.resize(context, camera_controller)
+}
+
+/// Writes everything the renderer knows about a brickgrid cell to a text file.
+fn dump_brick(
+    context: &gfx::Context,
+    renderer: &mut BrickmapRenderer,
+    cell: glam::IVec3,
+    path: &str,
+) -> Result<()> {
+    let manager = renderer.get_brickmap_manager_mut();
+    let min = manager.get_grid_origin();
+    let max = min + manager.get_brickgrid_dims().as_ivec3();
+    let Some(dump) = manager.dump_cell(context, cell) else {
+        anyhow::bail!(
+            "Brick {} is outside of the brickgrid, {}..{}",
+            cell,
+            min,
+            max
+        );
+    };
+    std::fs::write(path, dump.to_string())?;
+    Ok(())
+}
+
+/// Writes the passes of the next frame to `<name>.dot` and `<name>.json`.
+fn dump_frame_graph(renderer: &BrickmapRenderer, name: &str) -> Result<()> {
+    let graph = renderer.get_frame_graph();
+    std::fs::write(format!("{}.dot", name), graph.to_dot())?;
+    std::fs::write(format!("{}.json", name), graph.to_json())?;
+    Ok(())
+}
+
+/// Responds to a GPU error by turning off whatever caused it, or shrinking the brickmap
+/// budget if we ran out of memory. Returns true if the renderer needs rebuilding, which
+/// a lost device always does once it's been recreated. Only the brickmap renderer has
+/// anything to turn off or shrink.
+fn recover_from_gpu_error(
+    error: &GpuError,
+    context: &gfx::Context,
+    renderer: &mut dyn VoxelRenderer,
+    budget: &mut BrickmapBudget,
+) -> bool {
+    log::error!("{}", error);
+    let Some(renderer) = renderer.as_brickmap_mut() else {
+        return error.kind == GpuErrorKind::DeviceLost;
+    };
+    match error.kind {
+        GpuErrorKind::OutOfMemory => match budget.shrink() {
+            Some(smaller) => {
+                log::warn!("Shrinking brickmap budget to {:?}", smaller);
+                *budget = smaller;
+                budget.save(context, Path::new(BRICKMAP_BUDGET_PATH));
+                true
+            }
+            None => false,
+        },
+        GpuErrorKind::Validation if error.label == "light probes" => {
+            let mut settings = renderer.get_settings();
+            if settings.light_probes {
+                log::warn!("Disabling light probes");
+                settings.light_probes = false;
+                renderer.set_settings(context, settings);
+            }
+            false
+        }
+        GpuErrorKind::Validation => false,
+        GpuErrorKind::DeviceLost => true,
+    }
+}
+
+/// Where the gizmo goes for a light entity. An area light's size is the gizmo's scale,
+/// so scaling it resizes the light.
+fn light_transform(light: &Entity) -> GizmoTransform {
+    match light.kind {
+        EntityKind::AreaLight { .. } => GizmoTransform {
+            position: light.transform.position,
+            scale: light.transform.scale,
+            ..Default::default()
+        },
+        _ => GizmoTransform::from_position(light.transform.position),
+    }
+}
+
+/// Fills the start of the world with randomly placed and coloured point light entities.
+/// Returns how many there are.
+fn scatter_demo_lights(entities: &mut SceneEntities, count: usize) -> usize {
+    // Small xorshift so the scattering is the same every time
+    let mut state = 0x2545F491_u32;
+    let mut random = || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as f32 / u32::MAX as f32
+    };
+
+    for _ in 0..count {
+        let position = glam::vec3(random() * 512.0, random() * 256.0, random() * 512.0);
+        let color = glam::vec3(random(), random(), random()).normalize();
+        entities.spawn(Entity::new(
+            Transform::from_position(position),
+            EntityKind::PointLight {
+                color,
+                intensity: 200.0,
+            },
+        ));
+    }
+    count
 }
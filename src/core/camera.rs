@@ -1,8 +1,10 @@
 use std::time::Duration;
 use wgpu::util::DeviceExt;
 use winit::{
-    event::{ElementState, KeyEvent, WindowEvent},
+    dpi::PhysicalPosition,
+    event::{ElementState, KeyEvent, Touch, TouchPhase, WindowEvent},
     keyboard::{KeyCode, PhysicalKey},
+    window::{CursorGrabMode, Window},
 };
 
 use crate::gfx::Context;
@@ -104,10 +106,16 @@ pub struct CameraController {
     projection: Projection,
     uniform: CameraUniform,
     buffer: wgpu::Buffer,
+    /// Metres per second
     move_speed: f32,
+    /// Bricks per metre of the world being looked at
+    world_scale: f32,
     mouse_sensitivity: f32,
     move_dirs_pressed: glam::IVec3,
     rot_dirs_pressed: glam::IVec2,
+    look_touch: Option<(u64, PhysicalPosition<f64>)>,
+    move_touch: Option<u64>,
+    mouse_look: bool,
 }
 
 impl CameraController {
@@ -139,18 +147,38 @@ impl CameraController {
             uniform,
             buffer,
             move_speed,
+            world_scale: 1.0,
             mouse_sensitivity,
             move_dirs_pressed: glam::ivec3(0, 0, 0),
             rot_dirs_pressed: glam::ivec2(0, 0),
+            look_touch: None,
+            move_touch: None,
+            mouse_look: false,
         }
     }
 
+    /// Moves the camera somewhere directly, e.g. when it's being driven by a script.
+    pub fn set_pose(&mut self, position: glam::Vec3, yaw: f32, pitch: f32) {
+        self.camera = Camera::new(position, yaw, pitch);
+    }
+
+    /// Matches the projection to a new window size. Takes effect on the next
+    /// `update_buffer`.
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.projection.resize(width, height);
+    }
+
+    /// Sets how many bricks make up a metre, so the camera moves at the same physical
+    /// speed through worlds with different voxel sizes.
+    pub fn set_world_scale(&mut self, bricks_per_metre: f32) {
+        self.world_scale = bricks_per_metre;
+    }
+
     pub fn process_events(&mut self, event: &WindowEvent) -> bool {
         let mut handled = true;
         match event {
             WindowEvent::Resized(physical_size) => {
-                self.projection
-                    .resize(physical_size.width, physical_size.height);
+                self.resize(physical_size.width, physical_size.height);
             }
             WindowEvent::KeyboardInput {
                 event:
@@ -200,12 +228,101 @@ impl CameraController {
                     _ => handled = false,
                 }
             }
+            WindowEvent::Touch(Touch {
+                phase,
+                location,
+                id,
+                ..
+            }) => {
+                self.process_touch(*id, *phase, *location);
+            }
             _ => handled = false,
         }
 
         handled
     }
 
+    /// The first finger down drags to look around, holding a second finger down moves
+    /// the camera forwards.
+    fn process_touch(&mut self, id: u64, phase: TouchPhase, location: PhysicalPosition<f64>) {
+        match phase {
+            TouchPhase::Started => {
+                if self.look_touch.is_none() {
+                    self.look_touch = Some((id, location));
+                } else if self.move_touch.is_none() {
+                    self.move_touch = Some(id);
+                    self.move_dirs_pressed.z = 1;
+                }
+            }
+            TouchPhase::Moved => {
+                if let Some((look_id, last_location)) = self.look_touch {
+                    if look_id == id {
+                        let delta = glam::vec2(
+                            (location.x - last_location.x) as f32,
+                            (location.y - last_location.y) as f32,
+                        );
+                        self.apply_look_delta(delta);
+                        self.look_touch = Some((id, location));
+                    }
+                }
+            }
+            TouchPhase::Ended | TouchPhase::Cancelled => {
+                if self.look_touch.is_some_and(|(look_id, _)| look_id == id) {
+                    self.look_touch = None;
+                }
+                if self.move_touch == Some(id) {
+                    self.move_touch = None;
+                    self.move_dirs_pressed.z = 0;
+                }
+            }
+        }
+    }
+
+    /// Grabs and hides the cursor so raw mouse motion turns the camera, or gives the
+    /// cursor back. Not every platform can confine the cursor, so we fall back to locking
+    /// it, and leave mouse look off if neither works.
+    pub fn set_mouse_look(&mut self, window: &Window, enabled: bool) {
+        if enabled == self.mouse_look {
+            return;
+        }
+
+        if enabled {
+            let grabbed = window
+                .set_cursor_grab(CursorGrabMode::Confined)
+                .or_else(|_| window.set_cursor_grab(CursorGrabMode::Locked));
+            if let Err(e) = grabbed {
+                log::warn!("Couldn't grab cursor for mouse look: {}", e);
+                return;
+            }
+        } else if let Err(e) = window.set_cursor_grab(CursorGrabMode::None) {
+            log::warn!("Couldn't release cursor: {}", e);
+        }
+
+        window.set_cursor_visible(!enabled);
+        self.mouse_look = enabled;
+        log::info!("Mouse look: {}", enabled);
+    }
+
+    pub fn is_mouse_look(&self) -> bool {
+        self.mouse_look
+    }
+
+    /// Turns the camera by a raw mouse delta from `DeviceEvent::MouseMotion`. Ignored
+    /// unless mouse look is on, so moving the cursor around the window does nothing.
+    pub fn process_mouse_motion(&mut self, delta: (f64, f64)) {
+        if self.mouse_look {
+            self.apply_look_delta(glam::vec2(delta.0 as f32, delta.1 as f32));
+        }
+    }
+
+    /// Rotates the camera by a screen-space delta in pixels.
+    fn apply_look_delta(&mut self, delta: glam::Vec2) {
+        let max_pitch = 85_f32.to_radians();
+        self.camera.yaw += (delta.x * self.mouse_sensitivity).to_radians();
+        self.camera.pitch -= (delta.y * self.mouse_sensitivity).to_radians();
+        self.camera.pitch = self.camera.pitch.clamp(-max_pitch, max_pitch);
+    }
+
     pub fn update(&mut self, dt: Duration) {
         let dt = dt.as_secs_f32();
 
@@ -222,7 +339,7 @@ impl CameraController {
         let up = right.cross(front).normalize();
 
         // Apply movement
-        let ms = self.move_speed * dt;
+        let ms = self.move_speed * self.world_scale * dt;
         self.camera.position += front * ms * self.move_dirs_pressed.z as f32;
         self.camera.position += right * ms * self.move_dirs_pressed.x as f32;
         self.camera.position += up * ms * self.move_dirs_pressed.y as f32;
@@ -253,6 +370,46 @@ impl CameraController {
             .write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[self.uniform]));
     }
 
+    /// Creates a fresh uniform buffer, for use after the GPU device has been replaced.
+    pub fn recreate_buffer(&mut self, context: &Context) {
+        self.buffer = context
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: None,
+                contents: bytemuck::cast_slice(&[self.uniform]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            });
+    }
+
+    pub fn get_position(&self) -> glam::Vec3 {
+        self.camera.position
+    }
+
+    pub fn get_camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    pub fn get_view_projection(&self) -> glam::Mat4 {
+        self.projection.get_matrix() * self.camera.get_view_matrix()
+    }
+
+    /// Direction (in world space) of the ray through a pixel of a `size` sized screen,
+    /// built the same way the raycast builds its rays. The ray starts at the camera.
+    pub fn get_screen_ray(&self, pixel: glam::UVec2, size: glam::UVec2) -> glam::Vec3 {
+        let ndc = glam::vec2(
+            (pixel.x as f32 + 0.5) / size.x as f32 * 2.0 - 1.0,
+            1.0 - (pixel.y as f32 + 0.5) / size.y as f32 * 2.0,
+        );
+        let projection = self.projection.get_matrix();
+        let eye_dir = glam::vec3(
+            ndc.x * projection.x_axis.x,
+            ndc.y * projection.y_axis.y,
+            -1.0,
+        );
+        let eye_to_world = glam::Mat3::from_mat4(self.camera.get_view_matrix());
+        (eye_to_world * eye_dir).normalize()
+    }
+
     pub fn get_buffer(&self) -> &wgpu::Buffer {
         &self.buffer
     }
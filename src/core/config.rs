@@ -0,0 +1,588 @@
+use std::{borrow::Cow, fs, path::Path};
+
+use anyhow::{Context as _, Result};
+
+use super::app::BackgroundSettings;
+use crate::{
+    gfx::{self, AdapterChoice, AdapterSelection},
+    voxel::{
+        brickmap::{BrickmapBudget, RenderQuality, UiTheme},
+        svo::{Svo, SvoRenderer},
+        world::{GenerationSettings, Terrain, WorldManager},
+    },
+};
+
+/// Which renderer draws the world.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RendererKind {
+    Brickmap,
+    /// Sparse voxel octree, for comparing against the brickmap renderer. It only draws
+    /// the world around the camera and skips most of the brickmap renderer's features
+    Svo,
+    /// Greedy meshed triangles, for comparing against and for devices with weak compute
+    Mesh,
+}
+
+impl RendererKind {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "brickmap" => Some(Self::Brickmap),
+            "svo" => Some(Self::Svo),
+            "mesh" => Some(Self::Mesh),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Brickmap => "brickmap",
+            Self::Svo => "svo",
+            Self::Mesh => "mesh",
+        }
+    }
+}
+
+/// Startup settings read from a subset of TOML: `key = value` lines grouped under
+/// `[section]` headers. Anything missing or unreadable keeps its default, so an old or
+/// hand-edited file never stops the app from starting.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub window_size: glam::UVec2,
+    pub renderer: RendererKind,
+    /// Levels in the SVO renderer's octree, which is 2^depth voxels across
+    pub svo_depth: u32,
+    /// How much work the brickmap renderer does per frame, `None` to pick it from the
+    /// adapter
+    pub quality: Option<RenderQuality>,
+    /// Overrides the tuned brickmap budget's grid size
+    pub brickgrid_dims: Option<glam::UVec3>,
+    /// Overrides the tuned brickmap budget's cache size
+    pub brickmap_cache_size: Option<usize>,
+    pub chunk_dims: glam::UVec3,
+    pub generation: GenerationSettings,
+    /// Width of the world's voxels in metres
+    pub voxel_size: f32,
+    /// Width of the voxels in dropped .vox files in metres, `None` to match the world
+    pub import_voxel_size: Option<f32>,
+    /// Metres per second
+    pub camera_speed: f32,
+    pub mouse_sensitivity: f32,
+    /// Size of the crosshair, outlines and gizmo, see `RenderSettings::ui_scale`
+    pub ui_scale: f32,
+    pub ui_theme: UiTheme,
+    /// Write announcements of what the UI is doing to stdout for screen readers, see
+    /// `Announcer`
+    pub announce: bool,
+    /// Runs the soak test for this many hours instead of taking input
+    pub soak_hours: Option<f32>,
+    pub soak_seed: u32,
+    /// Graphics backend to use, `None` for the platform's default. Scenes can override it
+    pub backend: Option<wgpu::Backends>,
+    /// GPU to use, `None` to pick one by power preference
+    pub adapter: Option<AdapterChoice>,
+    /// How the app behaves while its window doesn't have focus
+    pub background: BackgroundSettings,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            window_size: glam::uvec2(1280, 720),
+            renderer: RendererKind::Brickmap,
+            svo_depth: SvoRenderer::DEFAULT_DEPTH,
+            quality: None,
+            brickgrid_dims: None,
+            brickmap_cache_size: None,
+            chunk_dims: WorldManager::DEFAULT_CHUNK_DIMS,
+            generation: GenerationSettings::default(),
+            voxel_size: WorldManager::DEFAULT_VOXEL_SIZE,
+            import_voxel_size: None,
+            camera_speed: 10.0,
+            mouse_sensitivity: 0.25,
+            ui_scale: 1.0,
+            ui_theme: UiTheme::Default,
+            announce: false,
+            soak_hours: None,
+            soak_seed: 1,
+            backend: None,
+            adapter: None,
+            background: BackgroundSettings::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config, writing out the defaults if there isn't one yet so there's
+    /// something to edit.
+    pub fn load_or_default(path: &Path) -> Self {
+        if !path.exists() {
+            let config = Self::default();
+            match config.write(path) {
+                Ok(_) => log::info!("Wrote default config to {}", path.display()),
+                Err(e) => log::warn!("Failed to write default config: {:#}", e),
+            }
+            return config;
+        }
+
+        match Self::load(path) {
+            Ok(config) => {
+                log::info!("Loaded config from {}", path.display());
+                config
+            }
+            Err(e) => {
+                log::warn!("Using default config: {:#}", e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Ok(Self::parse(&contents))
+    }
+
+    /// Parses a config, logging and skipping anything it doesn't understand.
+    pub fn parse(contents: &str) -> Self {
+        let mut config = Self::default();
+        let mut section = String::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = name.trim().to_owned();
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                log::warn!("Config line {}: expected `key = value`", i + 1);
+                continue;
+            };
+            let key = format!("{}.{}", section, key.trim());
+            let Some(value) = unquote(value.trim()) else {
+                log::warn!("Config line {}: malformed string", i + 1);
+                continue;
+            };
+            if !config.set(&key, &value) {
+                log::warn!("Config line {}: ignoring {} = {}", i + 1, key, value);
+            }
+        }
+        config
+    }
+
+    /// Sets a single `section.key`, returning false if it isn't a key or the value
+    /// doesn't fit it.
+    fn set(&mut self, key: &str, value: &str) -> bool {
+        let generation = &mut self.generation;
+        let result = match key {
+            "window.width" => value.parse().map(|v| self.window_size.x = v).ok(),
+            "window.height" => value.parse().map(|v| self.window_size.y = v).ok(),
+            "renderer.type" => RendererKind::parse(value).map(|v| self.renderer = v),
+            "renderer.svo_depth" => value
+                .parse()
+                .ok()
+                .filter(|v| (Svo::MIN_DEPTH..=SvoRenderer::MAX_DEPTH).contains(v))
+                .map(|v| self.svo_depth = v),
+            "renderer.quality" => match value {
+                "auto" => Some(None),
+                _ => RenderQuality::parse(value).map(Some),
+            }
+            .map(|v| self.quality = v),
+            "renderer.backend" => match value {
+                "auto" => Some(None),
+                _ => gfx::parse_backend(value).map(Some),
+            }
+            .map(|v| self.backend = v),
+            "renderer.adapter" => match value {
+                "" => None,
+                "auto" => Some(None),
+                value => Some(Some(AdapterChoice::parse(value))),
+            }
+            .map(|v| self.adapter = v),
+            "brickmap.brickgrid_dims" => parse_dims(value).map(|v| self.brickgrid_dims = Some(v)),
+            "brickmap.cache_size" => value
+                .parse()
+                .ok()
+                .filter(|v| *v > 0)
+                .map(|v| self.brickmap_cache_size = Some(v)),
+            "world.chunk_dims" => parse_dims(value).map(|v| self.chunk_dims = v),
+            "world.seed" => value.parse().map(|v| generation.seed = v).ok(),
+            "world.frequency" => value.parse().map(|v| generation.frequency = v).ok(),
+            "world.octaves" => value.parse().map(|v| generation.octaves = v).ok(),
+            "world.gain" => value.parse().map(|v| generation.gain = v).ok(),
+            "world.lacunarity" => value.parse().map(|v| generation.lacunarity = v).ok(),
+            "world.terrain" => Terrain::parse(value).map(|v| generation.terrain = v),
+            "world.ground_height" => value.parse().map(|v| generation.ground_height = v).ok(),
+            "world.cave_frequency" => value.parse().map(|v| generation.cave_frequency = v).ok(),
+            "world.cave_width" => value.parse().map(|v| generation.cave_width = v).ok(),
+            "world.structure_density" => {
+                value.parse().map(|v| generation.structure_density = v).ok()
+            }
+            "world.height_amplitude" => value.parse().map(|v| generation.height_amplitude = v).ok(),
+            "world.voxel_size" => parse_positive(value).map(|v| self.voxel_size = v),
+            "import.voxel_size" => parse_positive(value).map(|v| self.import_voxel_size = Some(v)),
+            "camera.speed" => value.parse().map(|v| self.camera_speed = v).ok(),
+            "camera.sensitivity" => value.parse().map(|v| self.mouse_sensitivity = v).ok(),
+            "ui.scale" => parse_positive(value).map(|v| self.ui_scale = v),
+            "ui.theme" => UiTheme::parse(value).map(|v| self.ui_theme = v),
+            "ui.announce" => value.parse().map(|v| self.announce = v).ok(),
+            "soak.hours" => parse_positive(value).map(|v| self.soak_hours = Some(v)),
+            "soak.seed" => value.parse().map(|v| self.soak_seed = v).ok(),
+            "background.frame_rate" => {
+                parse_positive(value).map(|v| self.background.frame_rate = v)
+            }
+            "background.pause_streaming" => value
+                .parse()
+                .map(|v| self.background.pause_streaming = v)
+                .ok(),
+            "background.low_power_adapter" => value
+                .parse()
+                .map(|v| self.background.low_power_adapter = v)
+                .ok(),
+            _ => None,
+        };
+        result.is_some()
+    }
+
+    /// Which GPU the config asks for, see `gfx::Context::new`.
+    pub fn get_adapter_selection(&self) -> AdapterSelection {
+        AdapterSelection {
+            backends: self.backend,
+            adapter: self.adapter.clone(),
+        }
+    }
+
+    /// Replaces the parts of a tuned budget that the config overrides.
+    pub fn apply_to_budget(&self, budget: &mut BrickmapBudget) {
+        if let Some(dims) = self.brickgrid_dims {
+            budget.brickgrid_dims = dims;
+        }
+        if let Some(size) = self.brickmap_cache_size {
+            budget.brickmap_cache_size = size;
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_toml()).context("Failed to write config")
+    }
+
+    /// The config as `write` saves it, commented and with every default filled in.
+    fn to_toml(&self) -> String {
+        let optional = |value: Option<String>, example: &str| match value {
+            Some(value) => value,
+            // Commented out, so the tuned value gets used
+            None => format!("# {}", example),
+        };
+        let generation = self.generation;
+        format!(
+            "# voxel-rs settings. Delete this file to restore the defaults.\n\
+             \n\
+             [window]\n\
+             width = {}\n\
+             height = {}\n\
+             \n\
+             [renderer]\n\
+             # brickmap, or to compare against it svo (sparse voxel octree) or mesh (greedy\n\
+             # meshed triangles)\n\
+             type = {}\n\
+             # The octree is 2^svo_depth voxels across, centred on the camera\n\
+             svo_depth = {}\n\
+             # full, reduced (for integrated GPUs) or auto to pick from the GPU\n\
+             quality = {}\n\
+             # vulkan, dx12, metal, gl or auto for the platform's default. Other backends are\n\
+             # tried if it has no usable GPU\n\
+             backend = {}\n\
+             # Which GPU to use, by its position in the list logged at startup or part of its\n\
+             # name. Picked by power preference if unset or not found\n\
+             {}\n\
+             \n\
+             # Overrides for the automatically tuned sizes in brickmap_budget.toml\n\
+             [brickmap]\n\
+             {}\n\
+             {}\n\
+             \n\
+             [world]\n\
+             # In bricks\n\
+             chunk_dims = {}\n\
+             seed = {}\n\
+             frequency = {:?}\n\
+             octaves = {}\n\
+             gain = {:?}\n\
+             lacunarity = {:?}\n\
+             # density (3D noise, with overhangs and floating islands) or heightmap (2D\n\
+             # noise, a solid ground)\n\
+             terrain = {}\n\
+             # Heightmap ground level and how far it varies, in bricks\n\
+             ground_height = {:?}\n\
+             height_amplitude = {:?}\n\
+             # Tunnels through the ground, a width of 0 turns them off\n\
+             cave_frequency = {:?}\n\
+             cave_width = {:?}\n\
+             # How many trees and boulders there are, 0 for none\n\
+             structure_density = {:?}\n\
+             # In metres\n\
+             voxel_size = {:?}\n\
+             \n\
+             # Voxel size of dropped .vox files in metres, defaults to the world's\n\
+             [import]\n\
+             {}\n\
+             \n\
+             [camera]\n\
+             # Metres per second\n\
+             speed = {:?}\n\
+             sensitivity = {:?}\n\
+             \n\
+             [ui]\n\
+             # Size of the crosshair, outlines and gizmo, 2 for twice as big\n\
+             scale = {:?}\n\
+             # default or high_contrast\n\
+             theme = {}\n\
+             # Read out what the UI is doing by writing it to stdout, for screen readers\n\
+             announce = {}\n\
+             \n\
+             # Flies around editing the world on a loop for hours, checking nothing leaks.\n\
+             # Edits made while soak testing aren't saved\n\
+             [soak]\n\
+             {}\n\
+             seed = {}\n\
+             \n\
+             # What the app does while its window doesn't have focus\n\
+             [background]\n\
+             # Redraws per second\n\
+             frame_rate = {:?}\n\
+             # Stop loading and unloading the world around the camera\n\
+             pause_streaming = {}\n\
+             # Switch to the power saving GPU, if there is one\n\
+             low_power_adapter = {}\n",
+            self.window_size.x,
+            self.window_size.y,
+            quote(self.renderer.name()),
+            self.svo_depth,
+            quote(self.quality.map_or("auto", |q| q.name())),
+            quote(self.backend.and_then(gfx::backend_name).unwrap_or("auto")),
+            optional(
+                self.adapter.as_ref().map(|a| match a {
+                    AdapterChoice::Index(index) => format!("adapter = {}", index),
+                    AdapterChoice::Name(name) => format!("adapter = {}", quote(name)),
+                }),
+                "adapter = 0",
+            ),
+            optional(
+                self.brickgrid_dims
+                    .map(|d| format!("brickgrid_dims = {}", format_dims(d))),
+                "brickgrid_dims = [512, 64, 512]",
+            ),
+            optional(
+                self.brickmap_cache_size
+                    .map(|s| format!("cache_size = {}", s)),
+                "cache_size = 262144",
+            ),
+            format_dims(self.chunk_dims),
+            generation.seed,
+            generation.frequency,
+            generation.octaves,
+            generation.gain,
+            generation.lacunarity,
+            quote(generation.terrain.name()),
+            generation.ground_height,
+            generation.height_amplitude,
+            generation.cave_frequency,
+            generation.cave_width,
+            generation.structure_density,
+            self.voxel_size,
+            optional(
+                self.import_voxel_size
+                    .map(|s| format!("voxel_size = {:?}", s)),
+                "voxel_size = 0.1",
+            ),
+            self.camera_speed,
+            self.mouse_sensitivity,
+            self.ui_scale,
+            quote(self.ui_theme.name()),
+            self.announce,
+            optional(
+                self.soak_hours.map(|h| format!("hours = {:?}", h)),
+                "hours = 4.0",
+            ),
+            self.soak_seed,
+            self.background.frame_rate,
+            self.background.pause_streaming,
+            self.background.low_power_adapter,
+        )
+    }
+}
+
+/// Parses `[x, y, z]`, none of which can be zero.
+fn parse_dims(value: &str) -> Option<glam::UVec3> {
+    let dims: Vec<u32> = value
+        .trim_matches(|c| c == '[' || c == ']')
+        .split(',')
+        .map(|v| v.trim().parse().ok())
+        .collect::<Option<_>>()?;
+    match dims[..] {
+        [x, y, z] if x > 0 && y > 0 && z > 0 => Some(glam::uvec3(x, y, z)),
+        _ => None,
+    }
+}
+
+/// Parses a number that has to be positive, e.g. a size in metres.
+fn parse_positive(value: &str) -> Option<f32> {
+    value
+        .parse()
+        .ok()
+        .filter(|v: &f32| *v > 0.0 && v.is_finite())
+}
+
+fn format_dims(dims: glam::UVec3) -> String {
+    format!("[{}, {}, {}]", dims.x, dims.y, dims.z)
+}
+
+/// Cuts a line off at the first `#` that isn't inside a quoted string.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Takes the quotes and escapes off a quoted value, leaving anything else as it is.
+/// Returns `None` if the string is never closed or has something after it.
+fn unquote(value: &str) -> Option<Cow<'_, str>> {
+    let Some(inner) = value.strip_prefix('"') else {
+        return Some(Cow::Borrowed(value));
+    };
+
+    let mut unquoted = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.push(chars.next()?),
+            '"' => {
+                return chars
+                    .as_str()
+                    .trim()
+                    .is_empty()
+                    .then_some(Cow::Owned(unquoted))
+            }
+            c => unquoted.push(c),
+        }
+    }
+    None
+}
+
+/// Quotes a string value, escaping anything that would end it early.
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_config_round_trips() {
+        let config = Config::default();
+        assert_eq!(Config::parse(&config.to_toml()), config);
+    }
+
+    #[test]
+    fn edited_config_round_trips() {
+        let mut config = Config {
+            window_size: glam::uvec2(640, 480),
+            renderer: RendererKind::Mesh,
+            quality: Some(RenderQuality::Reduced),
+            brickgrid_dims: Some(glam::uvec3(64, 32, 64)),
+            brickmap_cache_size: Some(4096),
+            chunk_dims: glam::uvec3(8, 4, 8),
+            import_voxel_size: Some(0.05),
+            ui_theme: UiTheme::HighContrast,
+            announce: true,
+            soak_hours: Some(0.5),
+            backend: Some(wgpu::Backends::VULKAN),
+            // Everything the quoting has to get past
+            adapter: Some(AdapterChoice::Name(r#"GPU #2 "fast" \ slow"#.to_owned())),
+            background: BackgroundSettings {
+                frame_rate: 2.5,
+                pause_streaming: false,
+                low_power_adapter: true,
+            },
+            ..Default::default()
+        };
+        config.generation.terrain = Terrain::Heightmap;
+        config.generation.frequency = 1e-5;
+        config.generation.seed = -7;
+        assert_eq!(Config::parse(&config.to_toml()), config);
+
+        config.adapter = Some(AdapterChoice::Index(1));
+        assert_eq!(Config::parse(&config.to_toml()), config);
+    }
+
+    #[test]
+    fn strings_are_quoted() {
+        let contents = Config::default().to_toml();
+        assert!(contents.contains("type = \"brickmap\"\n"));
+        assert!(contents.contains("quality = \"auto\"\n"));
+        assert!(contents.contains("backend = \"auto\"\n"));
+        assert!(contents.contains("terrain = \"density\"\n"));
+        assert!(contents.contains("theme = \"default\"\n"));
+    }
+
+    #[test]
+    fn comments_are_only_stripped_outside_strings() {
+        let config = Config::parse(
+            "[renderer] # the GPU\n\
+             adapter = \"GPU #2\" # not the first one\n\
+             type = mesh# bare values still work\n",
+        );
+        assert_eq!(
+            config.adapter,
+            Some(AdapterChoice::Name("GPU #2".to_owned()))
+        );
+        assert_eq!(config.renderer, RendererKind::Mesh);
+    }
+
+    #[test]
+    fn unknown_keys_are_skipped() {
+        let config = Config::parse(
+            "[window]\n\
+             width = 800\n\
+             depth = 12\n\
+             [nonsense]\n\
+             width = 5\n\
+             [camera]\n\
+             speed = 3.0\n",
+        );
+        assert_eq!(config.window_size, glam::uvec2(800, 720));
+        assert_eq!(config.camera_speed, 3.0);
+    }
+
+    #[test]
+    fn malformed_values_keep_their_defaults() {
+        let config = Config::parse(
+            "[window]\n\
+             width = 12abc\n\
+             height = -5\n\
+             [world]\n\
+             chunk_dims = [4, 0, 4]\n\
+             frequency = fast\n\
+             voxel_size = -0.1\n\
+             [renderer]\n\
+             adapter = \"never closed\n\
+             type = \"mesh\" trailing\n\
+             [background]\n\
+             frame_rate = inf\n\
+             pause_streaming = 1\n",
+        );
+        assert_eq!(config, Config::default());
+    }
+}
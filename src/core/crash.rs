@@ -0,0 +1,166 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    fs,
+    panic::PanicHookInfo,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock, TryLockError},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::voxel::{brickmap::BrickmapMemoryStats, world::IoStats};
+
+/// Crash reports are written in here, one file per crash
+const CRASH_REPORT_PATH: &str = "crashes";
+/// How much of the log goes into a crash report
+const CRASH_LOG_LINES: usize = 200;
+
+/// Where the app was at, as of the last frame. Kept up to date by the app so a crash
+/// report can say what was going on at the time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CrashState {
+    pub frame: u64,
+    pub fps: f32,
+    /// In bricks
+    pub camera_position: glam::Vec3,
+    pub camera_yaw: f32,
+    pub camera_pitch: f32,
+    pub world_seed: i32,
+    pub chunks: usize,
+    pub pending_chunks: usize,
+    pub io: Option<IoStats>,
+    pub brickmaps: Option<BrickmapMemoryStats>,
+}
+
+#[derive(Default)]
+struct CrashRecord {
+    state: CrashState,
+    log: VecDeque<String>,
+}
+
+fn record() -> &'static Mutex<CrashRecord> {
+    static RECORD: OnceLock<Mutex<CrashRecord>> = OnceLock::new();
+    RECORD.get_or_init(Default::default)
+}
+
+fn start_time() -> Instant {
+    static START: OnceLock<Instant> = OnceLock::new();
+    *START.get_or_init(Instant::now)
+}
+
+/// Passes everything on to env_logger, keeping the last few lines for crash reports.
+struct RecordingLogger {
+    inner: env_logger::Logger,
+}
+
+impl log::Log for RecordingLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.inner.matches(record) {
+            return;
+        }
+        self.inner.log(record);
+
+        let line = format!(
+            "[{:>9.3}s {:<5} {}] {}",
+            start_time().elapsed().as_secs_f32(),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        // Never wait on the lock, whoever holds it might be the one logging
+        if let Some(mut crash) = record_lock() {
+            if crash.log.len() == CRASH_LOG_LINES {
+                crash.log.pop_front();
+            }
+            crash.log.push_back(line);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Sets up env_logger as usual, but with the last few lines kept for crash reports.
+pub fn init_logging() {
+    start_time();
+    let inner = env_logger::Builder::from_default_env().build();
+    let max_level = inner.filter();
+    match log::set_boxed_logger(Box::new(RecordingLogger { inner })) {
+        Ok(()) => log::set_max_level(max_level),
+        Err(e) => eprintln!("Failed to set up logging: {}", e),
+    }
+}
+
+/// Writes a crash report whenever anything panics, on any thread, before the usual
+/// panic message. Worlds save whatever they can as the panic unwinds them.
+pub fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        match write_report(info) {
+            Ok(path) => eprintln!("Crash report written to {:?}", path),
+            Err(e) => eprintln!("Failed to write crash report: {}", e),
+        }
+        default_hook(info);
+    }));
+}
+
+/// Updates what a crash report would say the app was doing.
+pub fn update_state(f: impl FnOnce(&mut CrashState)) {
+    if let Some(mut crash) = record_lock() {
+        f(&mut crash.state);
+    }
+}
+
+/// Takes the record without blocking. A poisoned lock is still fine to read, a crash
+/// report is exactly when we want whatever it holds.
+fn record_lock() -> Option<std::sync::MutexGuard<'static, CrashRecord>> {
+    match record().try_lock() {
+        Ok(crash) => Some(crash),
+        Err(TryLockError::Poisoned(e)) => Some(e.into_inner()),
+        Err(TryLockError::WouldBlock) => None,
+    }
+}
+
+fn write_report(info: &PanicHookInfo) -> std::io::Result<PathBuf> {
+    let mut report = format!("voxel-rs {} crash report\n\n", env!("CARGO_PKG_VERSION"));
+
+    // Writing to a String can't fail
+    let thread = std::thread::current();
+    let _ = writeln!(report, "Thread: {}", thread.name().unwrap_or("unnamed"));
+    let _ = writeln!(report, "Panic: {}", info);
+    let _ = writeln!(
+        report,
+        "Uptime: {:.1}s\n",
+        start_time().elapsed().as_secs_f32()
+    );
+
+    match record_lock() {
+        Some(crash) => {
+            let _ = writeln!(report, "State: {:#?}\n", crash.state);
+            let _ = writeln!(report, "Last {} log lines:", crash.log.len());
+            for line in &crash.log {
+                let _ = writeln!(report, "  {}", line);
+            }
+        }
+        None => report += "State and log unavailable, the panic happened while updating them\n",
+    }
+
+    let _ = writeln!(
+        report,
+        "\nBacktrace:\n{}",
+        std::backtrace::Backtrace::force_capture()
+    );
+
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |t| t.as_secs());
+    fs::create_dir_all(CRASH_REPORT_PATH)?;
+    let path = Path::new(CRASH_REPORT_PATH).join(format!("crash-{}.txt", seconds));
+    fs::write(&path, report)?;
+    Ok(path)
+}
@@ -0,0 +1,105 @@
+use std::{sync::mpsc, time::Duration};
+
+use crate::voxel::world::{Debris, Voxel};
+
+use super::{TickContext, WorldSystem};
+
+/// Voxels per second squared
+const GRAVITY: f32 = 60.0;
+/// Debris still flying after this long is removed
+const MAX_LIFETIME: Duration = Duration::from_secs(5);
+/// Longest step we simulate at once, so a slow frame doesn't fling debris through walls
+const MAX_STEP: f32 = 0.1;
+
+#[derive(Debug)]
+struct Piece {
+    debris: Debris,
+    /// Where the piece was last written into the world, if it was
+    drawn_at: Option<glam::IVec3>,
+    age: Duration,
+}
+
+/// Simulates debris as single voxels moving through the world. Flying debris is drawn by
+/// writing it into the world each tick, so it streams through the same edit path as any
+/// other change, and comes to rest as a normal voxel wherever it lands.
+#[derive(Debug)]
+pub struct DebrisSystem {
+    pieces: Vec<Piece>,
+    spawner: mpsc::Receiver<Vec<Debris>>,
+}
+
+impl DebrisSystem {
+    /// Debris sent over the channel gets picked up the next time the system runs.
+    pub fn new(spawner: mpsc::Receiver<Vec<Debris>>) -> Self {
+        Self {
+            pieces: vec![],
+            spawner,
+        }
+    }
+}
+
+impl WorldSystem for DebrisSystem {
+    fn get_name(&self) -> &str {
+        "debris"
+    }
+
+    fn tick(&mut self, ctx: &mut TickContext) -> bool {
+        for debris in self.spawner.try_iter().flatten() {
+            self.pieces.push(Piece {
+                debris,
+                drawn_at: None,
+                age: Duration::ZERO,
+            });
+        }
+        if self.pieces.is_empty() {
+            return false;
+        }
+
+        // Take every piece out of the world before moving them so they can't collide with
+        // themselves, unless something else has been put there since
+        let mut erased = vec![];
+        for piece in self.pieces.iter_mut() {
+            if let Some(pos) = piece.drawn_at.take() {
+                if ctx.world.try_get_voxel(pos) == Some(piece.debris.voxel) {
+                    erased.push((pos, Voxel::Empty));
+                }
+            }
+        }
+        ctx.world.set_voxels(&erased);
+
+        let dt = ctx.elapsed.as_secs_f32().min(MAX_STEP);
+        let mut edits = vec![];
+        self.pieces.retain_mut(|piece| {
+            piece.age += ctx.elapsed;
+            if piece.age > MAX_LIFETIME {
+                return false;
+            }
+
+            // Move in steps of under a voxel so we can't skip over anything solid
+            let debris = &mut piece.debris;
+            debris.velocity.y -= GRAVITY * dt;
+            let motion = debris.velocity * dt;
+            let steps = motion.abs().max_element().ceil().max(1.0) as usize;
+            for _ in 0..steps {
+                let next = debris.position + motion / steps as f32;
+                let next_voxel = next.floor().as_ivec3();
+                match ctx.world.try_get_voxel(next_voxel) {
+                    Some(Voxel::Empty) => debris.position = next,
+                    // Hit something (or an unloaded chunk), so it settles where it is
+                    _ => {
+                        edits.push((debris.position.floor().as_ivec3(), debris.voxel));
+                        return false;
+                    }
+                }
+            }
+
+            let pos = debris.position.floor().as_ivec3();
+            edits.push((pos, debris.voxel));
+            piece.drawn_at = Some(pos);
+            true
+        });
+
+        ctx.world.set_voxels(&edits);
+        !self.pieces.is_empty()
+    }
+}
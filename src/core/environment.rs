@@ -0,0 +1,126 @@
+use std::time::Duration;
+
+use crate::voxel::brickmap::Sky;
+
+use super::SunLight;
+
+/// Tilt of the sun's path away from straight overhead, in radians. Stops the noon sun
+/// shadows from collapsing to nothing.
+const SUN_PATH_TILT: f32 = 0.4;
+/// Clear air is about 2, hazy summer days 5 and up. The sky model falls apart outside this
+const TURBIDITY_RANGE: (f32, f32) = (1.7, 10.0);
+/// Sun colour right at the horizon, blended towards the sun's own colour as it rises
+const HORIZON_SUN_COLOR: glam::Vec3 = glam::vec3(1.0, 0.45, 0.2);
+
+/// The sky and where the sun is in it. Starts out with the sun wherever it's given, and
+/// only follows the clock once a time of day is set, so scenes that place the sun
+/// themselves keep it there.
+///
+/// The sun rises in +x at 6:00, sets in -x at 18:00 and is highest at noon.
+#[derive(Debug, Clone)]
+pub struct Environment {
+    sun: SunLight,
+    /// Hours since midnight, `None` while the sun is fixed
+    time_of_day: Option<f32>,
+    /// In game hours passing per real second
+    time_scale: f32,
+    turbidity: f32,
+}
+
+impl Environment {
+    pub fn new(sun: SunLight) -> Self {
+        Self {
+            sun,
+            time_of_day: None,
+            time_scale: 0.0,
+            turbidity: 2.5,
+        }
+    }
+
+    pub fn get_time_of_day(&self) -> Option<f32> {
+        self.time_of_day
+    }
+
+    /// Puts the sun where it would be at `hours` past midnight. Wraps around, so 25.0 is
+    /// 1:00.
+    pub fn set_time_of_day(&mut self, hours: f32) {
+        self.time_of_day = Some(hours.rem_euclid(24.0));
+    }
+
+    /// Goes back to a fixed sun, wherever the clock last put it.
+    pub fn stop_clock(&mut self) {
+        self.sun = self.get_sun();
+        self.time_of_day = None;
+    }
+
+    pub fn get_time_scale(&self) -> f32 {
+        self.time_scale
+    }
+
+    /// How many in game hours pass per real second. 0 stops the sun moving.
+    pub fn set_time_scale(&mut self, hours_per_second: f32) {
+        self.time_scale = hours_per_second;
+    }
+
+    pub fn get_turbidity(&self) -> f32 {
+        self.turbidity
+    }
+
+    /// Haziness of the air, 2 for a clear day and higher for murkier ones.
+    pub fn set_turbidity(&mut self, turbidity: f32) {
+        self.turbidity = turbidity.clamp(TURBIDITY_RANGE.0, TURBIDITY_RANGE.1);
+    }
+
+    /// Moves the clock on, if there is one.
+    pub fn update(&mut self, dt: &Duration) {
+        if let Some(hours) = self.time_of_day {
+            self.set_time_of_day(hours + self.time_scale * dt.as_secs_f32());
+        }
+    }
+
+    /// Direction from the ground towards the sun.
+    pub fn get_sun_direction(&self) -> glam::Vec3 {
+        let Some(hours) = self.time_of_day else {
+            return self.sun.direction.normalize_or_zero();
+        };
+
+        let angle = (hours - 6.0) / 24.0 * std::f32::consts::TAU;
+        glam::vec3(
+            angle.cos(),
+            angle.sin() * SUN_PATH_TILT.cos(),
+            angle.sin() * SUN_PATH_TILT.sin(),
+        )
+    }
+
+    /// The sun as it should light the world. Once it follows the clock it reddens
+    /// towards the horizon and goes out below it.
+    pub fn get_sun(&self) -> SunLight {
+        let direction = self.get_sun_direction();
+        if self.time_of_day.is_none() {
+            return SunLight {
+                direction,
+                ..self.sun
+            };
+        }
+
+        let height = direction.y;
+        let warmth = 1.0 - smoothstep(0.0, 0.3, height);
+        SunLight {
+            direction,
+            color: self.sun.color.lerp(HORIZON_SUN_COLOR, warmth),
+            intensity: self.sun.intensity * smoothstep(-0.05, 0.1, height),
+        }
+    }
+
+    pub fn get_sky(&self) -> Sky {
+        Sky {
+            sun_direction: self.get_sun_direction(),
+            turbidity: self.turbidity,
+        }
+    }
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
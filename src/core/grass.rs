@@ -0,0 +1,200 @@
+use std::collections::{HashSet, VecDeque};
+
+use crate::voxel::world::{BuiltinMaterial, Material, MaterialId, Voxel, WorldManager};
+
+use super::{random::Rng, TickContext, WorldSystem};
+
+/// How far from the camera (in voxels) new grass can take root
+const SEED_RADIUS: i32 = 256;
+/// How far above and below the camera we look for the surface when seeding
+const SEED_DEPTH: i32 = 128;
+/// Random columns tried each tick when looking for somewhere to seed grass
+const SEEDS_PER_TICK: usize = 2;
+/// Chance of grass spreading to a given neighbour each time its source is visited
+const SPREAD_CHANCE: f32 = 0.15;
+/// Grass that still has dirt around it, capped so a huge meadow can't grow forever
+const MAX_FRONTIER: usize = 16384;
+
+/// Slightly different shades of grass, so large patches don't look flat
+const GRASS_SHADES: usize = 4;
+
+/// Slowly grows grass over exposed dirt. A few random surface voxels near the camera take
+/// root each tick, then spread to neighbouring surface voxels, up and down single voxel
+/// steps. Every change goes through the world's edit path, so the renderer picks it up
+/// the same way as any other edit.
+///
+/// Only dirt with empty space above it can turn to grass. Grown grass uses a few shades
+/// of the built in grass material, which get added to the world's material table.
+#[derive(Debug)]
+pub struct GrassSystem {
+    frontier: VecDeque<glam::IVec3>,
+    queued: HashSet<glam::IVec3>,
+    /// This tick's numbers, from the scheduler
+    rng: Rng,
+    grown: usize,
+    /// Materials of each shade, added to the world when grass first grows
+    shades: Vec<MaterialId>,
+}
+
+impl Default for GrassSystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GrassSystem {
+    pub fn new() -> Self {
+        Self {
+            frontier: VecDeque::new(),
+            queued: HashSet::new(),
+            rng: Rng::new(0),
+            grown: 0,
+            shades: Vec::new(),
+        }
+    }
+
+    /// How many voxels have turned to grass so far.
+    pub fn get_grown_count(&self) -> usize {
+        self.grown
+    }
+
+    /// Grass in a random shade.
+    fn grass_voxel(&mut self, world: &mut WorldManager) -> Voxel {
+        if self.shades.is_empty() {
+            let grass = BuiltinMaterial::Grass.material();
+            self.shades = (0..GRASS_SHADES)
+                .map(|i| {
+                    let shade = 0.85 + 0.3 * i as f32 / (GRASS_SHADES - 1) as f32;
+                    let albedo = grass.albedo.map(|c| (c as f32 * shade).min(255.0) as u8);
+                    world
+                        .get_materials_mut()
+                        .find_or_add(Material { albedo, ..grass })
+                })
+                .collect();
+        }
+
+        let shade = (self.rng.next_f32() * GRASS_SHADES as f32) as usize;
+        Voxel::Material(self.shades[shade.min(GRASS_SHADES - 1)])
+    }
+
+    fn is_grass(&self, voxel: Voxel) -> bool {
+        match voxel {
+            Voxel::Material(id) => id == BuiltinMaterial::Grass.id() || self.shades.contains(&id),
+            Voxel::Empty => false,
+        }
+    }
+
+    fn grow(&mut self, world: &mut WorldManager, pos: glam::IVec3) {
+        let voxel = self.grass_voxel(world);
+        if world.set_voxel(pos, voxel) {
+            self.grown += 1;
+            self.enqueue(pos);
+        }
+    }
+
+    fn enqueue(&mut self, pos: glam::IVec3) {
+        if self.frontier.len() < MAX_FRONTIER && self.queued.insert(pos) {
+            self.frontier.push_back(pos);
+        }
+    }
+
+    /// Finds the top surface voxel of a random column near the camera and turns it to grass
+    /// if it's dirt.
+    fn seed(&mut self, world: &mut WorldManager, camera_voxel: glam::IVec3) {
+        let x = camera_voxel.x + self.rng.range_i32(-SEED_RADIUS, SEED_RADIUS);
+        let z = camera_voxel.z + self.rng.range_i32(-SEED_RADIUS, SEED_RADIUS);
+        let top = camera_voxel.y + SEED_DEPTH;
+        let bottom = camera_voxel.y - SEED_DEPTH;
+
+        let mut above = None;
+        for y in (bottom..top).rev() {
+            let Some(voxel) = world.try_get_voxel(glam::ivec3(x, y, z)) else {
+                return;
+            };
+            if above == Some(Voxel::Empty) && voxel != Voxel::Empty {
+                if is_dirt(voxel) {
+                    self.grow(world, glam::ivec3(x, y, z));
+                }
+                return;
+            }
+            above = Some(voxel);
+        }
+    }
+
+    /// Tries to spread from a grass voxel to its neighbours. Returns whether any of them
+    /// are still dirt, in which case it should be visited again.
+    fn spread(&mut self, world: &mut WorldManager, pos: glam::IVec3) -> bool {
+        let mut has_dirt = false;
+        for offset in [
+            glam::IVec3::X,
+            glam::IVec3::NEG_X,
+            glam::IVec3::Z,
+            glam::IVec3::NEG_Z,
+        ] {
+            for step in [0, 1, -1] {
+                let target = pos + offset + glam::ivec3(0, step, 0);
+                if !is_exposed_dirt(world, target) {
+                    continue;
+                }
+
+                has_dirt = true;
+                if self.rng.next_f32() < SPREAD_CHANCE {
+                    self.grow(world, target);
+                }
+                break;
+            }
+        }
+        has_dirt
+    }
+}
+
+impl WorldSystem for GrassSystem {
+    fn get_name(&self) -> &str {
+        "grass"
+    }
+
+    fn tick(&mut self, ctx: &mut TickContext) -> bool {
+        self.rng = ctx.random.clone();
+
+        // The camera is in bricks, which are 8 voxels across
+        let camera_voxel = (ctx.camera_position * 8.0).floor().as_ivec3();
+        for _ in 0..SEEDS_PER_TICK {
+            self.seed(ctx.world, camera_voxel);
+        }
+
+        // Visit each grass voxel that was on the frontier when we started at most once, so
+        // newly grown grass waits for the next tick
+        let count = self.frontier.len();
+        for _ in 0..count {
+            if !ctx.has_time() {
+                break;
+            }
+            let Some(pos) = self.frontier.pop_front() else {
+                break;
+            };
+            self.queued.remove(&pos);
+
+            // It may have been dug up or painted over since it grew
+            let still_grass = ctx
+                .world
+                .try_get_voxel(pos)
+                .is_some_and(|v| self.is_grass(v));
+            if still_grass && self.spread(ctx.world, pos) {
+                self.enqueue(pos);
+            }
+        }
+
+        // Growth is meant to be slow, so we never ask for extra frames
+        false
+    }
+}
+
+fn is_dirt(voxel: Voxel) -> bool {
+    voxel == Voxel::Material(BuiltinMaterial::Dirt.id())
+}
+
+fn is_exposed_dirt(world: &mut WorldManager, pos: glam::IVec3) -> bool {
+    let voxel = world.try_get_voxel(pos);
+    let above = world.try_get_voxel(pos + glam::IVec3::Y);
+    matches!((voxel, above), (Some(v), Some(Voxel::Empty)) if is_dirt(v))
+}
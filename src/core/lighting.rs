@@ -0,0 +1,114 @@
+use wgpu::util::DeviceExt;
+
+use crate::gfx::Context;
+
+/// A directional light infinitely far away, e.g. the sun or moon.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SunLight {
+    /// Points from the ground towards the sun
+    pub direction: glam::Vec3,
+    pub color: glam::Vec3,
+    /// 0 turns the sun off, skipping its shadow rays entirely
+    pub intensity: f32,
+}
+
+impl Default for SunLight {
+    fn default() -> Self {
+        Self {
+            direction: glam::vec3(0.4, 1.0, 0.3).normalize(),
+            color: glam::vec3(1.0, 0.95, 0.85),
+            intensity: 1.0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct SunUniform {
+    direction: [f32; 3],
+    intensity: f32,
+    color: [f32; 3],
+    _pad: f32,
+}
+
+impl From<SunLight> for SunUniform {
+    fn from(value: SunLight) -> Self {
+        Self {
+            direction: value.direction.normalize_or_zero().to_array(),
+            intensity: value.intensity,
+            color: value.color.to_array(),
+            _pad: 0.0,
+        }
+    }
+}
+
+/// Global lights that aren't part of the world, along with the uniform buffer the
+/// renderer reads them from. Voxels lit by the sun get a shadow ray traced towards it.
+#[derive(Debug)]
+pub struct Lighting {
+    sun: SunLight,
+    buffer: wgpu::Buffer,
+    dirty: bool,
+}
+
+impl Lighting {
+    pub fn new(context: &Context, sun: SunLight) -> Self {
+        Self {
+            sun,
+            buffer: Self::create_buffer(context, sun),
+            dirty: false,
+        }
+    }
+
+    fn create_buffer(context: &Context, sun: SunLight) -> wgpu::Buffer {
+        context
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Sun Light"),
+                contents: bytemuck::cast_slice(&[SunUniform::from(sun)]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            })
+    }
+
+    pub fn get_sun(&self) -> SunLight {
+        self.sun
+    }
+
+    pub fn set_sun(&mut self, sun: SunLight) {
+        if sun != self.sun {
+            self.sun = sun;
+            self.dirty = true;
+        }
+    }
+
+    /// Moves the sun, e.g. for a day/night cycle. Cheap enough to call every frame.
+    pub fn set_sun_direction(&mut self, direction: glam::Vec3) {
+        self.set_sun(SunLight {
+            direction,
+            ..self.sun
+        });
+    }
+
+    /// Uploads any changes since the last upload.
+    pub fn update_buffer(&mut self, context: &Context) {
+        if !self.dirty {
+            return;
+        }
+        context.queue.write_buffer(
+            &self.buffer,
+            0,
+            bytemuck::cast_slice(&[SunUniform::from(self.sun)]),
+        );
+        self.dirty = false;
+    }
+
+    /// Creates a fresh uniform buffer, for use after the GPU device has been replaced.
+    pub fn recreate_buffer(&mut self, context: &Context) {
+        self.buffer = Self::create_buffer(context, self.sun);
+        self.dirty = false;
+    }
+
+    pub fn get_buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+}
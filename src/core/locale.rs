@@ -0,0 +1,88 @@
+use std::{collections::HashMap, fmt::Display, fs, path::Path};
+
+use anyhow::{Context as _, Result};
+
+/// English, built in so there's always something to fall back to
+const DEFAULT_LOCALE: &str = include_str!("../../assets/locale/en.txt");
+/// Other languages are read from here at startup, as `<language>.txt`
+const LOCALE_PATH: &str = "assets/locale";
+
+/// Every piece of text the app shows, looked up by key so it can be translated without
+/// touching the code. Files are `key = text` lines, with `{name}` wherever the app fills
+/// something in.
+#[derive(Debug, Clone, Default)]
+pub struct Locale {
+    strings: HashMap<String, String>,
+}
+
+impl Locale {
+    /// Parses a locale, logging and skipping anything it doesn't understand.
+    pub fn parse(contents: &str) -> Self {
+        let mut strings = HashMap::new();
+        for (i, line) in contents.lines().enumerate() {
+            // Only whole line comments, as `#` is fair game in text
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once('=') {
+                Some((key, text)) => {
+                    strings.insert(key.trim().to_owned(), text.trim().to_owned());
+                }
+                None => log::warn!("Locale line {}: expected `key = text`", i + 1),
+            }
+        }
+        Self { strings }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Ok(Self::parse(&contents))
+    }
+
+    /// English with the language from `LANG` over the top, so anything it's missing stays
+    /// readable. `LANG=pt_BR.UTF-8` tries pt_BR.txt, then pt.txt.
+    pub fn from_environment() -> Self {
+        let mut locale = Self::parse(DEFAULT_LOCALE);
+        let Ok(lang) = std::env::var("LANG") else {
+            return locale;
+        };
+
+        let region = lang.split(['.', '@']).next().unwrap_or_default();
+        let language = region.split('_').next().unwrap_or_default();
+        for name in [region, language] {
+            if name.is_empty() || name == "en" || name == "C" || name == "POSIX" {
+                continue;
+            }
+            let path = Path::new(LOCALE_PATH).join(format!("{}.txt", name));
+            if !path.exists() {
+                continue;
+            }
+            match Self::load(&path) {
+                Ok(translated) => {
+                    log::info!("Using {} text", name);
+                    locale.strings.extend(translated.strings);
+                    break;
+                }
+                Err(e) => log::warn!("Keeping English text: {:#}", e),
+            }
+        }
+        locale
+    }
+
+    /// The text for `key`, or the key itself if there isn't any, so missing text shows
+    /// up as something that can be searched for rather than nothing.
+    pub fn get<'a>(&'a self, key: &'a str) -> &'a str {
+        self.strings.get(key).map_or(key, String::as_str)
+    }
+
+    /// The text for `key` with each `{name}` replaced by its value.
+    pub fn format(&self, key: &str, args: &[(&str, &dyn Display)]) -> String {
+        let mut text = self.get(key).to_owned();
+        for (name, value) in args {
+            text = text.replace(&format!("{{{}}}", name), &value.to_string());
+        }
+        text
+    }
+}
@@ -1,4 +1,32 @@
+mod accessibility;
 mod app;
 mod camera;
+mod config;
+pub mod crash;
+mod debris;
+mod environment;
+mod grass;
+mod lighting;
+mod locale;
+mod random;
+mod scene;
+mod scheduler;
+mod soak;
+mod weather;
 
-pub use self::{app::App, camera::*};
+pub use self::{
+    accessibility::Announcer,
+    app::App,
+    camera::*,
+    config::Config,
+    debris::DebrisSystem,
+    environment::Environment,
+    grass::GrassSystem,
+    lighting::{Lighting, SunLight},
+    locale::Locale,
+    random::{RandomService, RandomStream},
+    scene::{Entity, EntityId, EntityKind, Scene, SceneCamera, SceneEntities, Transform},
+    scheduler::{AutosaveSystem, Priority, Scheduler, TickContext, WorldSystem},
+    soak::SoakTest,
+    weather::{Weather, WeatherController},
+};
@@ -0,0 +1,98 @@
+/// PCG hash, the same one the shaders use, so CPU and GPU seeds mix the same way.
+pub fn hash_u32(value: u32) -> u32 {
+    let state = value.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+/// A small xorshift generator. Cheap to create, so systems get a fresh one from the
+/// `RandomService` whenever they need one rather than keeping their own around.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u32,
+}
+
+impl Rng {
+    pub fn new(seed: u32) -> Self {
+        // Xorshift gets stuck on 0, and similar seeds give similar first values
+        Self {
+            state: hash_u32(seed).max(1),
+        }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state
+    }
+
+    /// Uniform in `0.0..=1.0`.
+    pub fn next_f32(&mut self) -> f32 {
+        self.next_u32() as f32 / u32::MAX as f32
+    }
+
+    /// Uniform in `min..max`.
+    pub fn range_i32(&mut self, min: i32, max: i32) -> i32 {
+        min + (self.next_f32() * (max - min) as f32) as i32
+    }
+}
+
+/// Who's asking for random numbers. Each stream is independent of the others, so one
+/// system drawing more numbers than before doesn't change what another one gets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RandomStream {
+    Explosions,
+    Particles,
+    /// World systems, offset by the system's index in the scheduler
+    Systems,
+}
+
+/// The one place random seeds come from. Everything is derived from the world's seed and
+/// the frame number, so a run that replays the same inputs over the same frames makes
+/// the same random choices, on both the CPU and the GPU.
+#[derive(Debug, Clone, Default)]
+pub struct RandomService {
+    world_seed: u32,
+    frame: u64,
+    frame_seed: u32,
+}
+
+impl RandomService {
+    pub fn new(world_seed: i32) -> Self {
+        let mut random = Self::default();
+        random.begin_frame(world_seed, 0);
+        random
+    }
+
+    /// Moves on to a new frame. Called once per frame, before anything uses random numbers.
+    pub fn begin_frame(&mut self, world_seed: i32, frame: u64) {
+        self.world_seed = hash_u32(world_seed as u32);
+        self.frame = frame;
+        self.frame_seed = hash_u32(self.world_seed ^ hash_u32(frame as u32 ^ (frame >> 32) as u32));
+    }
+
+    pub fn get_frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Seed for this frame, as given to the shaders.
+    pub fn get_frame_seed(&self) -> u32 {
+        self.frame_seed
+    }
+
+    /// Numbers for `stream` that are different every frame.
+    pub fn frame_rng(&self, stream: RandomStream, index: u32) -> Rng {
+        Rng::new(self.frame_seed ^ Self::stream_seed(stream, index))
+    }
+
+    /// Numbers for `stream` that are the same every frame, for things that should only
+    /// depend on the world.
+    pub fn world_rng(&self, stream: RandomStream, index: u32) -> Rng {
+        Rng::new(self.world_seed ^ Self::stream_seed(stream, index))
+    }
+
+    fn stream_seed(stream: RandomStream, index: u32) -> u32 {
+        hash_u32((stream as u32) << 24 ^ index)
+    }
+}
@@ -0,0 +1,402 @@
+use std::sync::Arc;
+
+use crate::{
+    core::{Camera, CameraController},
+    gfx,
+    voxel::{
+        brickmap::{
+            AreaLight, BrickmapRenderer, PointLight, VolumeHandle, VolumeModel, VolumeTransform,
+        },
+        VoxelRenderer,
+    },
+};
+
+/// Where an entity is, in world voxels. Point lights only use the position, area lights
+/// fill the box `scale` voxels across around it, and cameras look down their -Z.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    pub position: glam::Vec3,
+    pub rotation: glam::Quat,
+    pub scale: glam::Vec3,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            position: glam::Vec3::ZERO,
+            rotation: glam::Quat::IDENTITY,
+            scale: glam::Vec3::ONE,
+        }
+    }
+}
+
+impl Transform {
+    pub fn from_position(position: glam::Vec3) -> Self {
+        Self {
+            position,
+            ..Default::default()
+        }
+    }
+
+    /// Where a camera is, which is kept in bricks rather than voxels.
+    pub fn from_camera(camera: &Camera) -> Self {
+        let rotation = glam::Quat::from_rotation_y(-camera.yaw - std::f32::consts::FRAC_PI_2)
+            * glam::Quat::from_rotation_x(camera.pitch);
+        Self::from_position(camera.position * 8.0).with_rotation(rotation)
+    }
+
+    pub fn with_rotation(mut self, rotation: glam::Quat) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    pub fn with_scale(mut self, scale: glam::Vec3) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    pub fn get_forward(&self) -> glam::Vec3 {
+        self.rotation * glam::Vec3::NEG_Z
+    }
+
+    /// The camera looking down this transform's -Z. Cameras can't roll, so any roll is
+    /// lost.
+    pub fn to_camera(self) -> Camera {
+        let forward = self.get_forward().normalize_or_zero();
+        Camera::new(
+            self.position / 8.0,
+            forward.z.atan2(forward.x),
+            forward.y.clamp(-1.0, 1.0).asin(),
+        )
+    }
+}
+
+/// What an entity is. Each kind is handed to the part of the renderer that draws it.
+#[derive(Debug, Clone)]
+pub enum EntityKind {
+    /// A viewpoint. Only the active camera is drawn from, see
+    /// `SceneEntities::set_active_camera`.
+    Camera,
+    PointLight {
+        color: glam::Vec3,
+        intensity: f32,
+    },
+    AreaLight {
+        color: glam::Vec3,
+        intensity: f32,
+    },
+    /// A model placed over the terrain. Shared so it can be placed many times without
+    /// keeping a copy per placement, though each still takes its own room on the GPU.
+    Volume(Arc<VolumeModel>),
+}
+
+impl EntityKind {
+    pub fn is_light(&self) -> bool {
+        matches!(self, Self::PointLight { .. } | Self::AreaLight { .. })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Entity {
+    pub transform: Transform,
+    pub kind: EntityKind,
+}
+
+impl Entity {
+    pub fn new(transform: Transform, kind: EntityKind) -> Self {
+        Self { transform, kind }
+    }
+}
+
+/// Refers to an entity in a `SceneEntities`. Ids of despawned entities are ignored, even
+/// once their slot has been reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EntityId {
+    index: usize,
+    generation: u32,
+}
+
+impl std::fmt::Display for EntityId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.index)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Slot {
+    entity: Option<Entity>,
+    generation: u32,
+    /// Changed since it was last handed to the renderer
+    dirty: bool,
+    /// The renderer's copy of a volume, once it's been uploaded
+    volume: Option<VolumeHandle>,
+}
+
+/// The cameras, lights and volumes in the running scene, each with a transform. It's a
+/// flat list rather than a hierarchy, and `sync` hands whatever changed to the renderer
+/// once a frame, so nothing else has to keep renderer handles or light indices around.
+///
+/// The entities own every light in the renderer, so lights added to its light manager
+/// directly are cleared the next time a light changes. The active camera goes both
+/// ways: moving it here moves the camera controller, and otherwise it follows the
+/// controller.
+#[derive(Debug, Default)]
+pub struct SceneEntities {
+    slots: Vec<Slot>,
+    active_camera: Option<EntityId>,
+    camera_dirty: bool,
+    lights_dirty: bool,
+    /// Volumes despawned since the last sync, to take back out of the renderer
+    removed_volumes: Vec<VolumeHandle>,
+    /// Volumes are placed relative to the terrain, so they're moved when it is
+    world_origin: glam::Vec3,
+}
+
+impl SceneEntities {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn spawn(&mut self, entity: Entity) -> EntityId {
+        self.lights_dirty |= entity.kind.is_light();
+        let index = match self.slots.iter().position(|slot| slot.entity.is_none()) {
+            Some(index) => index,
+            None => {
+                self.slots.push(Slot::default());
+                self.slots.len() - 1
+            }
+        };
+        let slot = &mut self.slots[index];
+        slot.entity = Some(entity);
+        slot.dirty = true;
+        EntityId {
+            index,
+            generation: slot.generation,
+        }
+    }
+
+    /// Returns the entity, or `None` if it had already been despawned.
+    pub fn despawn(&mut self, id: EntityId) -> Option<Entity> {
+        self.get(id)?;
+        let slot = &mut self.slots[id.index];
+        let entity = slot.entity.take()?;
+        slot.generation = slot.generation.wrapping_add(1);
+        slot.dirty = false;
+        self.removed_volumes.extend(slot.volume.take());
+        self.lights_dirty |= entity.kind.is_light();
+        if self.active_camera == Some(id) {
+            self.active_camera = None;
+        }
+        Some(entity)
+    }
+
+    /// Despawns every entity `keep` returns false for.
+    pub fn retain(&mut self, mut keep: impl FnMut(EntityId, &Entity) -> bool) {
+        let removed: Vec<EntityId> = self
+            .iter()
+            .filter(|(id, e)| !keep(*id, e))
+            .map(|(id, _)| id)
+            .collect();
+        for id in removed {
+            self.despawn(id);
+        }
+    }
+
+    pub fn get(&self, id: EntityId) -> Option<&Entity> {
+        let slot = self.slots.get(id.index)?;
+        if slot.generation != id.generation {
+            return None;
+        }
+        slot.entity.as_ref()
+    }
+
+    /// Marks the entity as changed, so it's handed to the renderer again on the next
+    /// sync whether it was changed or not. Changing its kind isn't picked up, despawn it
+    /// and spawn another instead.
+    pub fn get_mut(&mut self, id: EntityId) -> Option<&mut Entity> {
+        self.get(id)?;
+        self.mark_dirty(id);
+        self.slots[id.index].entity.as_mut()
+    }
+
+    pub fn get_transform(&self, id: EntityId) -> Option<Transform> {
+        self.get(id).map(|entity| entity.transform)
+    }
+
+    /// Moves an entity, cheap enough to animate it every frame. Returns false if it had
+    /// been despawned.
+    pub fn set_transform(&mut self, id: EntityId, transform: Transform) -> bool {
+        match self.get_mut(id) {
+            Some(entity) => {
+                entity.transform = transform;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (EntityId, &Entity)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            let id = EntityId {
+                index,
+                generation: slot.generation,
+            };
+            slot.entity.as_ref().map(|entity| (id, entity))
+        })
+    }
+
+    pub fn get_count(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| slot.entity.is_some())
+            .count()
+    }
+
+    pub fn get_active_camera(&self) -> Option<EntityId> {
+        self.active_camera
+    }
+
+    /// Draws from a camera entity from the next sync on. Returns false if `id` isn't a
+    /// camera.
+    pub fn set_active_camera(&mut self, id: EntityId) -> bool {
+        if !matches!(
+            self.get(id),
+            Some(Entity {
+                kind: EntityKind::Camera,
+                ..
+            })
+        ) {
+            return false;
+        }
+        self.active_camera = Some(id);
+        self.camera_dirty = true;
+        true
+    }
+
+    /// Hands everything to the renderer again on the next sync, for when it's been
+    /// rebuilt and lost its volumes and lights.
+    pub fn invalidate(&mut self) {
+        for slot in &mut self.slots {
+            slot.dirty = slot.entity.is_some();
+            slot.volume = None;
+        }
+        self.removed_volumes.clear();
+        self.lights_dirty = true;
+    }
+
+    /// Hands whatever changed since the last sync to the renderer and camera controller.
+    /// Call once a frame, after the controller has been updated. Volumes and lights that
+    /// don't fit in the renderer are logged and left out. Only the brickmap renderer
+    /// draws volumes and lights, the others just get the camera.
+    pub fn sync(
+        &mut self,
+        context: &gfx::Context,
+        renderer: &mut dyn VoxelRenderer,
+        camera_controller: &mut CameraController,
+    ) {
+        self.sync_camera(camera_controller);
+        let Some(renderer) = renderer.as_brickmap_mut() else {
+            return;
+        };
+
+        for handle in self.removed_volumes.drain(..) {
+            renderer.remove_volume(context, handle);
+        }
+        let world_origin = renderer.get_world_origin();
+        if world_origin != self.world_origin {
+            self.world_origin = world_origin;
+            for slot in &mut self.slots {
+                slot.dirty |= slot.volume.is_some();
+            }
+        }
+        for slot in &mut self.slots {
+            let Some(Entity {
+                transform,
+                kind: EntityKind::Volume(model),
+            }) = &slot.entity
+            else {
+                slot.dirty = false;
+                continue;
+            };
+            if !slot.dirty {
+                continue;
+            }
+            slot.dirty = false;
+
+            let transform = VolumeTransform::from_position(transform.position - world_origin * 8.0)
+                .with_rotation(transform.rotation);
+            match slot.volume {
+                Some(handle) => {
+                    renderer.set_volume_transform(context, handle, transform);
+                }
+                None => match renderer.add_volume(context, model, transform) {
+                    Ok(handle) => slot.volume = Some(handle),
+                    Err(e) => log::error!("Failed to add volume: {:#}", e),
+                },
+            }
+        }
+
+        if self.lights_dirty {
+            self.lights_dirty = false;
+            self.sync_lights(renderer);
+        }
+    }
+
+    fn sync_camera(&mut self, camera_controller: &mut CameraController) {
+        let Some(id) = self.active_camera else {
+            return;
+        };
+        if self.camera_dirty {
+            self.camera_dirty = false;
+            if let Some(transform) = self.get_transform(id) {
+                let camera = transform.to_camera();
+                camera_controller.set_pose(camera.position, camera.yaw, camera.pitch);
+            }
+        } else if let Some(slot) = self.slots.get_mut(id.index) {
+            if let Some(entity) = &mut slot.entity {
+                entity.transform = Transform::from_camera(camera_controller.get_camera());
+            }
+        }
+    }
+
+    fn sync_lights(&self, renderer: &mut BrickmapRenderer) {
+        let lights = renderer.get_light_manager_mut();
+        lights.clear();
+        let mut dropped = 0;
+        for (_, entity) in self.iter() {
+            let Transform {
+                position, scale, ..
+            } = entity.transform;
+            let index = match entity.kind {
+                EntityKind::PointLight { color, intensity } => lights.add_point_light(PointLight {
+                    position,
+                    color,
+                    intensity,
+                }),
+                EntityKind::AreaLight { color, intensity } => lights.add_area_light(AreaLight {
+                    position,
+                    size: scale,
+                    color,
+                    intensity,
+                }),
+                _ => continue,
+            };
+            dropped += index.is_none() as usize;
+        }
+        if dropped > 0 {
+            log::warn!("No room for {} of the scene's lights", dropped);
+        }
+    }
+
+    fn mark_dirty(&mut self, id: EntityId) {
+        let slot = &mut self.slots[id.index];
+        slot.dirty = true;
+        let Some(entity) = &slot.entity else {
+            return;
+        };
+        self.lights_dirty |= entity.kind.is_light();
+        if self.active_camera == Some(id) {
+            self.camera_dirty = true;
+        }
+    }
+}
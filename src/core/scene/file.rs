@@ -0,0 +1,871 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context as _, Result};
+
+use crate::{
+    core::{Config, SunLight},
+    gfx,
+    voxel::brickmap::{Exposure, RenderSettings},
+};
+
+/// A .vox model to import into the world when the scene starts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SceneImport {
+    pub path: PathBuf,
+    /// Voxel the model's corner goes at, `None` to put it at the camera like a dropped file
+    pub origin: Option<glam::IVec3>,
+}
+
+/// Where the camera starts, in bricks, with its angles in radians.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SceneCamera {
+    pub position: glam::Vec3,
+    pub yaw: f32,
+    pub pitch: f32,
+}
+
+/// Renderer settings a scene can pin down. Anything left as `None` keeps the default.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SceneQuality {
+    pub brickgrid_dims: Option<glam::UVec3>,
+    pub brickmap_cache_size: Option<usize>,
+    pub variable_rate: Option<bool>,
+    pub light_probes: Option<bool>,
+    pub sub_voxel_detail: Option<bool>,
+    pub baked_ao: Option<bool>,
+    pub quarter_res_lighting: Option<bool>,
+    pub temporal_accumulation: Option<bool>,
+    pub emissive_bounce: Option<bool>,
+    pub lod_distance: Option<f32>,
+    pub prefetch_distance: Option<f32>,
+    pub max_ray_distance: Option<f32>,
+    pub max_ray_steps: Option<u32>,
+}
+
+/// Everything needed to reproduce a view of a world in one file, for attaching to bug
+/// reports and setting up demos. Scenes are written in a subset of RON:
+///
+/// ```ron
+/// Scene(
+///     world: (seed: 42, import: Some((path: "castle.vox", origin: Some((0, 8, 0))))),
+///     camera: (position: (4.0, 4.0, 20.0), yaw: -90.0, pitch: 0.0),
+///     lighting: (
+///         sun: (direction: (0.4, 1.0, 0.3), color: (1.0, 0.95, 0.85), intensity: 1.0),
+///         exposure: Manual(1.0),
+///     ),
+///     backend: Vulkan,
+///     quality: (brickmap_cache_size: Some(65536), lod_distance: Some(64.0)),
+/// )
+/// ```
+///
+/// Every section and field is optional, whatever's missing keeps the config's value or
+/// the default. Camera angles are in degrees in the file. Import paths are relative to
+/// the scene file.
+///
+/// Saved edits would make a scene look different on every machine, so worlds aren't
+/// loaded from or saved to disk while a scene is running.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Scene {
+    pub seed: Option<i32>,
+    pub import: Option<SceneImport>,
+    pub camera: Option<SceneCamera>,
+    pub sun: Option<SunLight>,
+    pub exposure: Option<Exposure>,
+    pub backend: Option<wgpu::Backends>,
+    pub quality: SceneQuality,
+}
+
+impl Scene {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut scene =
+            Self::parse(&contents).with_context(|| format!("Invalid scene {}", path.display()))?;
+        if let (Some(import), Some(directory)) = (&mut scene.import, path.parent()) {
+            import.path = directory.join(&import.path);
+        }
+        Ok(scene)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut parser = Parser::new(contents);
+        let value = parser.parse_value()?;
+        if let Some(offset) = parser.peek() {
+            return Err(parser.error_at(offset, "unexpected text after the scene"));
+        }
+
+        let mut fields = value.into_fields("Scene")?;
+        let mut scene = Self::default();
+        if let Some(world) = fields.take("world") {
+            let mut world = world.into_fields("world")?;
+            scene.seed = world.take_with("seed", Value::into_i32)?;
+            scene.import = world.take_with("import", parse_import)?;
+            world.finish()?;
+        }
+        if let Some(camera) = fields.take("camera") {
+            let mut camera = camera.into_fields("camera")?;
+            let degrees = |angle: Option<f32>| angle.unwrap_or(0.0).to_radians();
+            scene.camera = Some(SceneCamera {
+                position: camera
+                    .take_with("position", Value::into_vec3)?
+                    .unwrap_or(glam::Vec3::ZERO),
+                yaw: degrees(camera.take_with("yaw", Value::into_f32)?),
+                pitch: degrees(camera.take_with("pitch", Value::into_f32)?),
+            });
+            camera.finish()?;
+        }
+        if let Some(lighting) = fields.take("lighting") {
+            let mut lighting = lighting.into_fields("lighting")?;
+            scene.sun = lighting.take_with("sun", parse_sun)?;
+            scene.exposure = lighting.take_with("exposure", parse_exposure)?;
+            lighting.finish()?;
+        }
+        scene.backend = fields.take_with("backend", parse_backend)?;
+        if let Some(quality) = fields.take("quality") {
+            scene.quality = parse_quality(quality)?;
+        }
+        fields.finish()?;
+        Ok(scene)
+    }
+
+    /// Replaces the parts of the startup config that the scene sets.
+    pub fn apply_to_config(&self, config: &mut Config) {
+        if let Some(seed) = self.seed {
+            config.generation.seed = seed;
+        }
+        if let Some(backend) = self.backend {
+            config.backend = Some(backend);
+        }
+        if let Some(dims) = self.quality.brickgrid_dims {
+            config.brickgrid_dims = Some(dims);
+        }
+        if let Some(size) = self.quality.brickmap_cache_size {
+            config.brickmap_cache_size = Some(size);
+        }
+    }
+
+    /// Replaces the render settings that the scene sets.
+    pub fn apply_to_render_settings(&self, settings: &mut RenderSettings) {
+        let quality = self.quality;
+        let flags = [
+            (&mut settings.variable_rate, quality.variable_rate),
+            (&mut settings.light_probes, quality.light_probes),
+            (&mut settings.sub_voxel_detail, quality.sub_voxel_detail),
+            (&mut settings.baked_ao, quality.baked_ao),
+            (
+                &mut settings.quarter_res_lighting,
+                quality.quarter_res_lighting,
+            ),
+            (
+                &mut settings.temporal_accumulation,
+                quality.temporal_accumulation,
+            ),
+            (&mut settings.emissive_bounce, quality.emissive_bounce),
+        ];
+        for (setting, value) in flags {
+            if let Some(value) = value {
+                *setting = value;
+            }
+        }
+        let distances = [
+            (&mut settings.lod_distance, quality.lod_distance),
+            (&mut settings.prefetch_distance, quality.prefetch_distance),
+            (&mut settings.max_ray_distance, quality.max_ray_distance),
+        ];
+        for (setting, value) in distances {
+            if let Some(value) = value {
+                *setting = value;
+            }
+        }
+        if let Some(steps) = quality.max_ray_steps {
+            settings.max_ray_steps = steps;
+        }
+        if let Some(exposure) = self.exposure {
+            settings.exposure = exposure;
+        }
+    }
+}
+
+fn parse_import(value: Value) -> Result<SceneImport> {
+    // Just the path is fine too
+    if let Value::String(path) = value {
+        return Ok(SceneImport {
+            path: path.into(),
+            origin: None,
+        });
+    }
+
+    let mut fields = value.into_fields("import")?;
+    let import = SceneImport {
+        path: fields
+            .take_with("path", Value::into_string)?
+            .context("import needs a path")?
+            .into(),
+        origin: fields.take_with("origin", Value::into_ivec3)?,
+    };
+    fields.finish()?;
+    Ok(import)
+}
+
+fn parse_sun(value: Value) -> Result<SunLight> {
+    let mut fields = value.into_fields("sun")?;
+    let default = SunLight::default();
+    let sun = SunLight {
+        direction: fields
+            .take_with("direction", Value::into_vec3)?
+            .map_or(default.direction, |d| d.normalize_or_zero()),
+        color: fields
+            .take_with("color", Value::into_vec3)?
+            .unwrap_or(default.color),
+        intensity: fields
+            .take_with("intensity", Value::into_f32)?
+            .unwrap_or(default.intensity),
+    };
+    fields.finish()?;
+    Ok(sun)
+}
+
+fn parse_exposure(value: Value) -> Result<Exposure> {
+    match value {
+        Value::Ident(name) if name == "Auto" => Ok(Exposure::auto()),
+        Value::Tuple(Some(name), mut values) if name == "Manual" && values.len() == 1 => {
+            Ok(Exposure::Manual(values.remove(0).into_f32()?))
+        }
+        Value::Struct(Some(name), fields) if name == "Auto" => {
+            let mut fields = Fields::new("Auto", fields);
+            let mut exposure = Exposure::auto();
+            if let Exposure::Auto {
+                min,
+                max,
+                target,
+                speed,
+            } = &mut exposure
+            {
+                for (name, value) in [
+                    ("min", min),
+                    ("max", max),
+                    ("target", target),
+                    ("speed", speed),
+                ] {
+                    if let Some(v) = fields.take_with(name, Value::into_f32)? {
+                        *value = v;
+                    }
+                }
+            }
+            fields.finish()?;
+            Ok(exposure)
+        }
+        _ => bail!("Expected Auto, Auto(...) or Manual(exposure)"),
+    }
+}
+
+fn parse_backend(value: Value) -> Result<wgpu::Backends> {
+    let Value::Ident(name) = value else {
+        bail!("Expected a backend name");
+    };
+    gfx::parse_backend(&name).with_context(|| {
+        format!(
+            "Unknown backend {}, expected Vulkan, Dx12, Metal or Gl",
+            name
+        )
+    })
+}
+
+fn parse_quality(value: Value) -> Result<SceneQuality> {
+    let mut fields = value.into_fields("quality")?;
+    let quality = SceneQuality {
+        brickgrid_dims: fields.take_with("brickgrid_dims", Value::into_uvec3)?,
+        brickmap_cache_size: fields
+            .take_with("brickmap_cache_size", Value::into_u32)?
+            .map(|size| size as usize),
+        variable_rate: fields.take_with("variable_rate", Value::into_bool)?,
+        light_probes: fields.take_with("light_probes", Value::into_bool)?,
+        sub_voxel_detail: fields.take_with("sub_voxel_detail", Value::into_bool)?,
+        baked_ao: fields.take_with("baked_ao", Value::into_bool)?,
+        quarter_res_lighting: fields.take_with("quarter_res_lighting", Value::into_bool)?,
+        temporal_accumulation: fields.take_with("temporal_accumulation", Value::into_bool)?,
+        emissive_bounce: fields.take_with("emissive_bounce", Value::into_bool)?,
+        lod_distance: fields.take_with("lod_distance", Value::into_f32)?,
+        prefetch_distance: fields.take_with("prefetch_distance", Value::into_f32)?,
+        max_ray_distance: fields.take_with("max_ray_distance", Value::into_f32)?,
+        max_ray_steps: fields.take_with("max_ray_steps", Value::into_u32)?,
+    };
+    fields.finish()?;
+    Ok(quality)
+}
+
+/// A parsed RON value. Enum variants and `Some`/`None` come out as named tuples and
+/// identifiers, it's up to whoever reads the value to make sense of them.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Number(f64),
+    Bool(bool),
+    String(String),
+    /// A bare name, e.g. `None` or a variant with no fields
+    Ident(String),
+    /// `(a, b)` or `Name(a, b)`
+    Tuple(Option<String>, Vec<Value>),
+    /// `(key: value)` or `Name(key: value)`
+    Struct(Option<String>, Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Takes the fields out of a struct, with `what` to name it in errors. `()` counts as
+    /// a struct with no fields.
+    fn into_fields(self, what: &str) -> Result<Fields> {
+        match self {
+            Value::Struct(_, fields) => Ok(Fields::new(what, fields)),
+            Value::Tuple(_, values) if values.is_empty() => Ok(Fields::new(what, vec![])),
+            _ => bail!("Expected {} to be a struct", what),
+        }
+    }
+
+    /// `None` for `None`, otherwise whatever's inside the `Some`. Values without a `Some`
+    /// around them are taken as they are.
+    fn into_option(self) -> Option<Value> {
+        match self {
+            Value::Ident(name) if name == "None" => None,
+            Value::Tuple(Some(name), mut values) if name == "Some" && values.len() == 1 => {
+                values.pop()
+            }
+            value => Some(value),
+        }
+    }
+
+    fn into_f32(self) -> Result<f32> {
+        match self {
+            Value::Number(value) => Ok(value as f32),
+            _ => bail!("Expected a number"),
+        }
+    }
+
+    fn into_i32(self) -> Result<i32> {
+        match self {
+            Value::Number(value) if value.fract() == 0.0 && value.abs() <= i32::MAX as f64 => {
+                Ok(value as i32)
+            }
+            _ => bail!("Expected a whole number"),
+        }
+    }
+
+    fn into_u32(self) -> Result<u32> {
+        match self {
+            Value::Number(value)
+                if value.fract() == 0.0 && (0.0..=u32::MAX as f64).contains(&value) =>
+            {
+                Ok(value as u32)
+            }
+            _ => bail!("Expected a positive whole number"),
+        }
+    }
+
+    fn into_bool(self) -> Result<bool> {
+        match self {
+            Value::Bool(value) => Ok(value),
+            _ => bail!("Expected true or false"),
+        }
+    }
+
+    fn into_string(self) -> Result<String> {
+        match self {
+            Value::String(value) => Ok(value),
+            _ => bail!("Expected a string"),
+        }
+    }
+
+    fn into_triple<T>(self, convert: impl Fn(Value) -> Result<T>) -> Result<[T; 3]> {
+        match self {
+            Value::Tuple(None, values) if values.len() == 3 => {
+                let mut values = values.into_iter().map(convert);
+                let mut next = || values.next().unwrap();
+                Ok([next()?, next()?, next()?])
+            }
+            _ => bail!("Expected (x, y, z)"),
+        }
+    }
+
+    fn into_vec3(self) -> Result<glam::Vec3> {
+        self.into_triple(Value::into_f32)
+            .map(glam::Vec3::from_array)
+    }
+
+    fn into_ivec3(self) -> Result<glam::IVec3> {
+        self.into_triple(Value::into_i32)
+            .map(glam::IVec3::from_array)
+    }
+
+    /// Dimensions, none of which can be zero.
+    fn into_uvec3(self) -> Result<glam::UVec3> {
+        match self.into_triple(Value::into_u32)? {
+            [x, y, z] if x > 0 && y > 0 && z > 0 => Ok(glam::uvec3(x, y, z)),
+            _ => bail!("Dimensions can't be zero"),
+        }
+    }
+}
+
+/// The fields of a struct, taken out one at a time so any left over at the end can be
+/// reported as unknown rather than silently ignored.
+struct Fields {
+    what: String,
+    fields: Vec<(String, Value)>,
+}
+
+impl Fields {
+    fn new(what: &str, fields: Vec<(String, Value)>) -> Self {
+        Self {
+            what: what.to_owned(),
+            fields,
+        }
+    }
+
+    fn take(&mut self, name: &str) -> Option<Value> {
+        let index = self.fields.iter().position(|(key, _)| key == name)?;
+        Some(self.fields.remove(index).1)
+    }
+
+    /// Takes a field and converts it, naming the field in any error. Optional fields
+    /// can be written with or without `Some`, and `None` is the same as leaving them out.
+    fn take_with<T>(
+        &mut self,
+        name: &str,
+        convert: impl FnOnce(Value) -> Result<T>,
+    ) -> Result<Option<T>> {
+        match self.take(name).and_then(Value::into_option) {
+            Some(value) => convert(value)
+                .map(Some)
+                .with_context(|| format!("In {}.{}", self.what, name)),
+            None => Ok(None),
+        }
+    }
+
+    fn finish(self) -> Result<()> {
+        match self.fields.first() {
+            Some((name, _)) => bail!("Unknown field {} in {}", name, self.what),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Just enough of a RON parser for scene files: numbers, strings, booleans, names,
+/// tuples and structs, with `//` comments.
+struct Parser<'a> {
+    source: &'a str,
+    offset: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str) -> Self {
+        Self { source, offset: 0 }
+    }
+
+    fn error_at(&self, offset: usize, message: &str) -> anyhow::Error {
+        let line = self.source[..offset].matches('\n').count() + 1;
+        anyhow!("Line {}: {}", line, message)
+    }
+
+    /// Skips whitespace and comments, returning the offset of the next character if
+    /// there is one.
+    fn peek(&mut self) -> Option<usize> {
+        loop {
+            let rest = &self.source[self.offset..];
+            let trimmed = rest.trim_start();
+            self.offset += rest.len() - trimmed.len();
+            if !trimmed.starts_with("//") {
+                break;
+            }
+            self.offset += trimmed.find('\n').unwrap_or(trimmed.len());
+        }
+        (self.offset < self.source.len()).then_some(self.offset)
+    }
+
+    fn next_char(&mut self) -> Option<char> {
+        self.peek()?;
+        self.source[self.offset..].chars().next()
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        match self.next_char() {
+            Some(c) if c == expected => {
+                self.offset += 1;
+                Ok(())
+            }
+            _ => Err(self.error_at(self.offset, &format!("expected '{}'", expected))),
+        }
+    }
+
+    /// Takes characters while `predicate` holds, returning them.
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> &'a str {
+        let start = self.offset;
+        let rest = &self.source[start..];
+        let length = rest.find(|c| !predicate(c)).unwrap_or(rest.len());
+        self.offset += length;
+        &self.source[start..start + length]
+    }
+
+    fn parse_value(&mut self) -> Result<Value> {
+        let start = self.offset;
+        match self.next_char() {
+            Some('"') => self.parse_string(),
+            Some('(') => self.parse_body(None),
+            Some(c) if c.is_ascii_digit() || c == '-' || c == '+' || c == '.' => {
+                let text = self.take_while(|c| {
+                    c.is_ascii_alphanumeric() || c == '-' || c == '+' || c == '.' || c == '_'
+                });
+                text.replace('_', "")
+                    .parse()
+                    .map(Value::Number)
+                    .map_err(|_| self.error_at(start, &format!("invalid number {}", text)))
+            }
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                let name = self.take_while(|c| c.is_alphanumeric() || c == '_');
+                // Names directly followed by brackets are named tuples and structs
+                if self.source[self.offset..].starts_with('(') {
+                    return self.parse_body(Some(name.to_owned()));
+                }
+                Ok(match name {
+                    "true" => Value::Bool(true),
+                    "false" => Value::Bool(false),
+                    _ => Value::Ident(name.to_owned()),
+                })
+            }
+            _ => Err(self.error_at(start, "expected a value")),
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<Value> {
+        let start = self.offset;
+        self.expect('"')?;
+        let mut value = String::new();
+        let mut chars = self.source[self.offset..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.offset += i + 1;
+                    return Ok(Value::String(value));
+                }
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, 't')) => value.push('\t'),
+                    Some((_, c @ ('"' | '\\'))) => value.push(c),
+                    _ => return Err(self.error_at(start, "invalid escape in string")),
+                },
+                c => value.push(c),
+            }
+        }
+        Err(self.error_at(start, "unterminated string"))
+    }
+
+    /// Parses the bracketed part of a tuple or struct, which is a struct if it starts
+    /// with `name:`.
+    fn parse_body(&mut self, name: Option<String>) -> Result<Value> {
+        self.expect('(')?;
+        let start = self.offset;
+        self.peek();
+        let is_struct = {
+            let key = self.take_while(|c| c.is_alphanumeric() || c == '_');
+            let is_struct = !key.is_empty() && self.next_char() == Some(':');
+            self.offset = start;
+            is_struct
+        };
+
+        let mut values = vec![];
+        let mut fields = vec![];
+        while self.next_char() != Some(')') {
+            if is_struct {
+                let key_start = self.offset;
+                let key = self.take_while(|c| c.is_alphanumeric() || c == '_');
+                if key.is_empty() {
+                    return Err(self.error_at(key_start, "expected a field name"));
+                }
+                self.expect(':')?;
+                fields.push((key.to_owned(), self.parse_value()?));
+            } else {
+                values.push(self.parse_value()?);
+            }
+            match self.next_char() {
+                Some(',') => self.offset += 1,
+                Some(')') => {}
+                _ => return Err(self.error_at(self.offset, "expected ',' or ')'")),
+            }
+        }
+        self.expect(')')?;
+
+        Ok(match is_struct {
+            true => Value::Struct(name, fields),
+            false => Value::Tuple(name, values),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The error and everything it was wrapped in, as `{:#}` prints it.
+    fn parse_error(contents: &str) -> String {
+        format!("{:#}", Scene::parse(contents).unwrap_err())
+    }
+
+    #[test]
+    fn complete_scene() {
+        let scene = Scene::parse(
+            r#"Scene(
+                world: (seed: -42, import: Some((path: "castle.vox", origin: Some((0, 8, -3))))),
+                camera: (position: (4.0, 4.5, 20), yaw: -90.0, pitch: 0.0),
+                lighting: (
+                    sun: (direction: (0.0, 2.0, 0.0), color: (1.0, 0.95, 0.85), intensity: 2.0),
+                    exposure: Manual(1.5),
+                ),
+                backend: Vulkan,
+                quality: (
+                    brickgrid_dims: (64, 32, 64),
+                    brickmap_cache_size: Some(65_536),
+                    variable_rate: false,
+                    lod_distance: Some(64.0),
+                    max_ray_steps: 512,
+                ),
+            )"#,
+        )
+        .unwrap();
+
+        assert_eq!(scene.seed, Some(-42));
+        assert_eq!(
+            scene.import,
+            Some(SceneImport {
+                path: "castle.vox".into(),
+                origin: Some(glam::ivec3(0, 8, -3)),
+            })
+        );
+        assert_eq!(
+            scene.camera,
+            Some(SceneCamera {
+                position: glam::vec3(4.0, 4.5, 20.0),
+                yaw: (-90.0f32).to_radians(),
+                pitch: 0.0,
+            })
+        );
+        assert_eq!(
+            scene.sun,
+            Some(SunLight {
+                direction: glam::Vec3::Y,
+                color: glam::vec3(1.0, 0.95, 0.85),
+                intensity: 2.0,
+            })
+        );
+        assert_eq!(scene.exposure, Some(Exposure::Manual(1.5)));
+        assert_eq!(scene.backend, Some(wgpu::Backends::VULKAN));
+        assert_eq!(
+            scene.quality,
+            SceneQuality {
+                brickgrid_dims: Some(glam::uvec3(64, 32, 64)),
+                brickmap_cache_size: Some(65536),
+                variable_rate: Some(false),
+                lod_distance: Some(64.0),
+                max_ray_steps: Some(512),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn missing_fields_keep_their_defaults() {
+        assert_eq!(Scene::parse("Scene()").unwrap(), Scene::default());
+        assert_eq!(Scene::parse("()").unwrap(), Scene::default());
+
+        let scene = Scene::parse(
+            "(camera: (), lighting: (sun: (intensity: 3.0)), world: (import: \"a.vox\"))",
+        )
+        .unwrap();
+        assert_eq!(
+            scene.camera,
+            Some(SceneCamera {
+                position: glam::Vec3::ZERO,
+                yaw: 0.0,
+                pitch: 0.0,
+            })
+        );
+        let default_sun = SunLight::default();
+        assert_eq!(
+            scene.sun,
+            Some(SunLight {
+                intensity: 3.0,
+                ..default_sun
+            })
+        );
+        assert_eq!(scene.exposure, None);
+        assert_eq!(
+            scene.import,
+            Some(SceneImport {
+                path: "a.vox".into(),
+                origin: None,
+            })
+        );
+        assert_eq!(scene.seed, None);
+        assert_eq!(scene.quality, SceneQuality::default());
+    }
+
+    #[test]
+    fn none_is_the_same_as_leaving_a_field_out() {
+        let scene = Scene::parse("(world: (seed: None), quality: (lod_distance: None))").unwrap();
+        assert_eq!(scene, Scene::default());
+    }
+
+    #[test]
+    fn auto_exposure_fields() {
+        let scene = Scene::parse("(lighting: (exposure: Auto))").unwrap();
+        assert_eq!(scene.exposure, Some(Exposure::auto()));
+
+        let scene = Scene::parse("(lighting: (exposure: Auto(min: 0.25, speed: 4.0)))").unwrap();
+        let Some(Exposure::Auto {
+            min, max, speed, ..
+        }) = scene.exposure
+        else {
+            panic!("expected auto exposure, got {:?}", scene.exposure);
+        };
+        let Exposure::Auto {
+            max: default_max, ..
+        } = Exposure::auto()
+        else {
+            unreachable!();
+        };
+        assert_eq!((min, max, speed), (0.25, default_max, 4.0));
+    }
+
+    #[test]
+    fn nested_tuples_and_structs() {
+        let mut parser = Parser::new("Outer((1, (2, -3.5)), Inner(a: (b: \"c\"), d: None))");
+        assert_eq!(
+            parser.parse_value().unwrap(),
+            Value::Tuple(
+                Some("Outer".to_owned()),
+                vec![
+                    Value::Tuple(
+                        None,
+                        vec![
+                            Value::Number(1.0),
+                            Value::Tuple(None, vec![Value::Number(2.0), Value::Number(-3.5)]),
+                        ]
+                    ),
+                    Value::Struct(
+                        Some("Inner".to_owned()),
+                        vec![
+                            (
+                                "a".to_owned(),
+                                Value::Struct(
+                                    None,
+                                    vec![("b".to_owned(), Value::String("c".to_owned()))]
+                                )
+                            ),
+                            ("d".to_owned(), Value::Ident("None".to_owned())),
+                        ]
+                    ),
+                ]
+            )
+        );
+        assert_eq!(parser.peek(), None);
+    }
+
+    #[test]
+    fn trailing_commas_and_comments() {
+        let scene = Scene::parse(
+            "// A scene\n\
+             Scene( // the whole thing\n\
+                 world: (seed: 7,), // trailing comma in a struct\n\
+                 camera: (\n\
+                     // comment before a field\n\
+                     position: (1.0, 2.0, 3.0,),\n\
+                 ),\n\
+             ) // and after\n",
+        )
+        .unwrap();
+        assert_eq!(scene.seed, Some(7));
+        assert_eq!(
+            scene.camera.map(|c| c.position),
+            Some(glam::vec3(1.0, 2.0, 3.0))
+        );
+    }
+
+    #[test]
+    fn strings_with_escapes() {
+        let scene =
+            Scene::parse(r#"(world: (import: "dir/\"quoted\" \\ // not a comment.vox"))"#).unwrap();
+        assert_eq!(
+            scene.import.map(|i| i.path),
+            Some(PathBuf::from(r#"dir/"quoted" \ // not a comment.vox"#))
+        );
+    }
+
+    #[test]
+    fn syntax_errors_name_the_line() {
+        assert_eq!(
+            parse_error("(\n    world: (seed: 1)\n    camera: ()\n)"),
+            "Line 3: expected ',' or ')'"
+        );
+        assert_eq!(
+            parse_error("(\nworld: (seed: 1x))"),
+            "Line 2: invalid number 1x"
+        );
+        assert_eq!(
+            parse_error("(world: (import: \"a.vox))"),
+            "Line 1: unterminated string"
+        );
+        assert_eq!(
+            parse_error("(world: (import: \"\\q\"))"),
+            "Line 1: invalid escape in string"
+        );
+        assert_eq!(parse_error("(world: (seed: ))"), "Line 1: expected a value");
+        assert_eq!(
+            parse_error("(a: 1, \"b\": 2)"),
+            "Line 1: expected a field name"
+        );
+        assert_eq!(
+            parse_error("()\n\n)"),
+            "Line 3: unexpected text after the scene"
+        );
+        assert_eq!(
+            parse_error("(world: (seed: 1)"),
+            "Line 1: expected ',' or ')'"
+        );
+    }
+
+    #[test]
+    fn value_errors_name_the_field() {
+        assert_eq!(parse_error("(sky: Blue)"), "Unknown field sky in Scene");
+        assert_eq!(
+            parse_error("(camera: (position: (1, 2, 3), roll: 0))"),
+            "Unknown field roll in camera"
+        );
+        assert_eq!(
+            parse_error("(world: (seed: 1.5))"),
+            "In world.seed: Expected a whole number"
+        );
+        assert_eq!(
+            parse_error("(camera: (position: (1, 2)))"),
+            "In camera.position: Expected (x, y, z)"
+        );
+        assert_eq!(
+            parse_error("(quality: (brickgrid_dims: (64, 0, 64)))"),
+            "In quality.brickgrid_dims: Dimensions can't be zero"
+        );
+        assert_eq!(
+            parse_error("(quality: (baked_ao: 1))"),
+            "In quality.baked_ao: Expected true or false"
+        );
+        assert_eq!(
+            parse_error("(backend: Glide)"),
+            "In Scene.backend: Unknown backend Glide, expected Vulkan, Dx12, Metal or Gl"
+        );
+        assert_eq!(
+            parse_error("(lighting: (exposure: Manual))"),
+            "In lighting.exposure: Expected Auto, Auto(...) or Manual(exposure)"
+        );
+        assert_eq!(
+            parse_error("(world: (import: (origin: (0, 0, 0))))"),
+            "In world.import: import needs a path"
+        );
+        assert_eq!(parse_error("(world: 5)"), "Expected world to be a struct");
+    }
+}
@@ -0,0 +1,7 @@
+mod entities;
+mod file;
+
+pub use self::{
+    entities::{Entity, EntityId, EntityKind, SceneEntities, Transform},
+    file::{Scene, SceneCamera},
+};
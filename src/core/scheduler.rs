@@ -0,0 +1,207 @@
+use std::time::{Duration, Instant};
+
+use crate::voxel::world::WorldManager;
+
+use super::random::{RandomService, RandomStream, Rng};
+
+/// How many frames in a row a system can be skipped for lack of time before it gets run
+/// regardless of the frame budget.
+const MAX_SKIPPED_FRAMES: u32 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+/// What a system gets to work with during its slice of the frame.
+pub struct TickContext<'a> {
+    pub world: &'a mut WorldManager,
+    pub camera_position: glam::Vec3,
+    /// Time since this system last ran
+    pub elapsed: Duration,
+    /// Random numbers for this system and frame. Systems should draw from this rather
+    /// than keep their own state, so runs can be replayed
+    pub random: Rng,
+    deadline: Instant,
+}
+
+impl TickContext<'_> {
+    /// Whether there's any of the system's slice left. Systems should check this between
+    /// units of work and stop once it's false.
+    pub fn has_time(&self) -> bool {
+        Instant::now() < self.deadline
+    }
+}
+
+/// Simulation work that can be spread over frames, e.g. water flow or grass growth.
+pub trait WorldSystem {
+    fn get_name(&self) -> &str;
+
+    /// Does as much work as fits in the slice. Returns whether there's work left, in which
+    /// case the system runs again next frame instead of waiting for its interval.
+    fn tick(&mut self, ctx: &mut TickContext) -> bool;
+}
+
+#[derive(Debug, Clone)]
+pub struct SystemStats {
+    pub name: String,
+    pub priority: Priority,
+    pub last_run_time: Duration,
+    pub skipped_frames: u32,
+}
+
+struct ScheduledSystem {
+    system: Box<dyn WorldSystem>,
+    priority: Priority,
+    budget: Duration,
+    interval: Duration,
+    since_last_run: Duration,
+    has_work: bool,
+    skipped_frames: u32,
+    last_run_time: Duration,
+}
+
+impl ScheduledSystem {
+    fn is_due(&self) -> bool {
+        self.has_work || self.since_last_run >= self.interval
+    }
+
+    fn is_starved(&self) -> bool {
+        self.skipped_frames >= MAX_SKIPPED_FRAMES
+    }
+}
+
+/// Shares a fixed slice of each frame between world systems. Systems run in priority
+/// order, each limited to its own budget, until the frame's budget is used up. Anything
+/// that doesn't fit waits for the next frame, so adding systems costs throughput rather
+/// than frame time.
+pub struct Scheduler {
+    systems: Vec<ScheduledSystem>,
+    frame_budget: Duration,
+}
+
+impl Scheduler {
+    pub fn new(frame_budget: Duration) -> Self {
+        Self {
+            systems: vec![],
+            frame_budget,
+        }
+    }
+
+    /// Adds a system that runs every `interval` (or every frame if zero) for at most
+    /// `budget` per frame.
+    pub fn add_system(
+        &mut self,
+        system: Box<dyn WorldSystem>,
+        priority: Priority,
+        budget: Duration,
+        interval: Duration,
+    ) {
+        log::info!(
+            "Scheduling {} at {:?} priority, {:.2}ms every {:.1}s",
+            system.get_name(),
+            priority,
+            budget.as_secs_f32() * 1000.0,
+            interval.as_secs_f32()
+        );
+        self.systems.push(ScheduledSystem {
+            system,
+            priority,
+            budget,
+            interval,
+            since_last_run: Duration::ZERO,
+            has_work: false,
+            skipped_frames: 0,
+            last_run_time: Duration::ZERO,
+        });
+    }
+
+    pub fn get_frame_budget(&self) -> Duration {
+        self.frame_budget
+    }
+
+    pub fn set_frame_budget(&mut self, frame_budget: Duration) {
+        self.frame_budget = frame_budget;
+    }
+
+    pub fn get_stats(&self) -> Vec<SystemStats> {
+        self.systems
+            .iter()
+            .map(|s| SystemStats {
+                name: s.system.get_name().to_owned(),
+                priority: s.priority,
+                last_run_time: s.last_run_time,
+                skipped_frames: s.skipped_frames,
+            })
+            .collect()
+    }
+
+    /// Runs whichever systems are due and fit in this frame's budget.
+    pub fn run(
+        &mut self,
+        world: &mut WorldManager,
+        camera_position: glam::Vec3,
+        dt: &Duration,
+        random: &RandomService,
+    ) {
+        for system in self.systems.iter_mut() {
+            system.since_last_run += *dt;
+        }
+
+        // Starved systems go first so low priorities can't be put off forever
+        let mut order: Vec<usize> = (0..self.systems.len())
+            .filter(|i| self.systems[*i].is_due())
+            .collect();
+        order.sort_by_key(|i| {
+            let system = &self.systems[*i];
+            std::cmp::Reverse((system.is_starved(), system.priority))
+        });
+
+        let mut remaining = self.frame_budget;
+        for i in order {
+            let system = &mut self.systems[i];
+            if remaining.is_zero() && !system.is_starved() {
+                system.skipped_frames += 1;
+                continue;
+            }
+
+            let slice = match system.is_starved() {
+                true => system.budget,
+                false => system.budget.min(remaining),
+            };
+            let start = Instant::now();
+            let mut ctx = TickContext {
+                world,
+                camera_position,
+                elapsed: system.since_last_run,
+                random: random.frame_rng(RandomStream::Systems, i as u32),
+                deadline: start + slice,
+            };
+            system.has_work = system.system.tick(&mut ctx);
+            system.last_run_time = start.elapsed();
+            system.since_last_run = Duration::ZERO;
+            system.skipped_frames = 0;
+            remaining = remaining.saturating_sub(system.last_run_time);
+        }
+    }
+}
+
+/// Periodically saves the world's edits, so a crash doesn't lose everything since the
+/// world was opened.
+#[derive(Debug, Default)]
+pub struct AutosaveSystem;
+
+impl WorldSystem for AutosaveSystem {
+    fn get_name(&self) -> &str {
+        "autosave"
+    }
+
+    fn tick(&mut self, ctx: &mut TickContext) -> bool {
+        if let Err(e) = ctx.world.queue_save() {
+            log::error!("Autosave failed: {:#}", e);
+        }
+        false
+    }
+}
@@ -0,0 +1,299 @@
+use std::time::Duration;
+
+use crate::voxel::{
+    brickmap::{BrickmapMemoryStats, BrickmapRenderer},
+    world::{BuiltinMaterial, Explosion, Voxel, WorldManager},
+};
+
+use super::CameraController;
+
+/// How far from where it started the soak test wanders, in bricks
+const WANDER_RADIUS: f32 = 48.0;
+/// Waypoints in the script that gets played back on a loop
+const WAYPOINT_COUNT: usize = 24;
+/// Bricks per second
+const FLY_SPEED: f32 = 24.0;
+/// Loops played before the baseline is taken, so everything along the route has been
+/// generated and the caches have filled up
+const WARMUP_LOOPS: u32 = 2;
+/// How far past the baseline anything can grow before it counts as a leak
+const GROWTH_TOLERANCE: f64 = 1.25;
+/// Leeway for the process's memory on top of the tolerance, for allocator noise
+const MEMORY_SLACK: u64 = 64 << 20;
+/// Loops in a row a queue has to grow for before it counts as a leak
+const QUEUE_GROWTH_LOOPS: usize = 4;
+
+#[derive(Debug, Clone, Copy)]
+enum SoakAction {
+    /// Fly to a position in bricks, looking where it's going
+    FlyTo(glam::Vec3),
+    /// Blow a hole in the world, in voxels
+    Explode(Explosion),
+    /// Fill a box of voxels, max exclusive
+    Fill {
+        min: glam::IVec3,
+        max: glam::IVec3,
+        voxel: Voxel,
+    },
+}
+
+/// Everything that gets checked for growth, taken at the end of each loop.
+#[derive(Debug, Clone, Copy)]
+struct SoakSample {
+    loop_index: u32,
+    chunks: usize,
+    brickmaps: BrickmapMemoryStats,
+    resident_bytes: Option<u64>,
+}
+
+/// Flies the camera around a seeded loop of waypoints for hours, blowing up and filling
+/// in bits of the world along the way. The same script plays every loop, so once the
+/// route has been streamed in nothing should keep growing; if it does, or the shading
+/// table allocator goes wrong, the test panics with the samples it took.
+///
+/// Edits are real, so the world shouldn't be saved while this is running.
+#[derive(Debug)]
+pub struct SoakTest {
+    script: Vec<SoakAction>,
+    step: usize,
+    duration: Duration,
+    elapsed: Duration,
+    loop_index: u32,
+    baseline: Option<SoakSample>,
+    samples: Vec<SoakSample>,
+}
+
+impl SoakTest {
+    pub fn new(seed: u32, origin: glam::Vec3, duration: Duration) -> Self {
+        log::info!(
+            "Soak testing for {:.1} hours with seed {}",
+            duration.as_secs_f32() / 3600.0,
+            seed
+        );
+        Self {
+            script: generate_script(seed, origin),
+            step: 0,
+            duration,
+            elapsed: Duration::ZERO,
+            loop_index: 0,
+            baseline: None,
+            samples: Vec::new(),
+        }
+    }
+
+    /// Plays the script forward by a frame and checks the invariants. Returns false once
+    /// the test has run for long enough.
+    pub fn update(
+        &mut self,
+        dt: &Duration,
+        camera_controller: &mut CameraController,
+        world: &mut WorldManager,
+        renderer: &BrickmapRenderer,
+    ) -> bool {
+        self.elapsed += *dt;
+        if self.elapsed >= self.duration {
+            log::info!(
+                "Soak test passed after {} loops: {:?}",
+                self.loop_index,
+                self.samples.last()
+            );
+            return false;
+        }
+
+        let manager = renderer.get_brickmap_manager();
+        if let Err(e) = manager.validate_shading_table() {
+            self.fail(&format!("shading table allocator is broken: {}", e));
+        }
+        let allocator_errors = manager.get_memory_stats().allocator_errors;
+        if allocator_errors > 0 {
+            self.fail(&format!("{} shading table frees failed", allocator_errors));
+        }
+
+        match self.script[self.step] {
+            SoakAction::FlyTo(target) => {
+                let position = camera_controller.get_position();
+                let offset = target - position;
+                let distance = FLY_SPEED * dt.as_secs_f32();
+                let direction = offset.normalize_or_zero();
+                if direction != glam::Vec3::ZERO {
+                    let yaw = direction.z.atan2(direction.x);
+                    let pitch = direction.y.clamp(-0.9, 0.9).asin();
+                    let position = match offset.length() > distance {
+                        true => position + direction * distance,
+                        false => target,
+                    };
+                    camera_controller.set_pose(position, yaw, pitch);
+                }
+                if offset.length() <= distance {
+                    self.advance(world, renderer);
+                }
+            }
+            SoakAction::Explode(explosion) => {
+                explosion.detonate(world);
+                self.advance(world, renderer);
+            }
+            SoakAction::Fill { min, max, voxel } => {
+                world.set_region(min, max, voxel);
+                self.advance(world, renderer);
+            }
+        }
+        true
+    }
+
+    fn advance(&mut self, world: &WorldManager, renderer: &BrickmapRenderer) {
+        self.step = (self.step + 1) % self.script.len();
+        if self.step > 0 {
+            return;
+        }
+
+        self.loop_index += 1;
+        let sample = SoakSample {
+            loop_index: self.loop_index,
+            chunks: world.get_chunk_count(),
+            brickmaps: renderer.get_brickmap_manager().get_memory_stats(),
+            resident_bytes: resident_bytes(),
+        };
+        log::info!("Soak loop {} finished: {:?}", self.loop_index, sample);
+        self.samples.push(sample);
+
+        let Some(baseline) = self.baseline else {
+            if self.loop_index >= WARMUP_LOOPS {
+                self.baseline = Some(sample);
+            }
+            return;
+        };
+        if let Some(growth) = find_growth(&baseline, &self.samples) {
+            self.fail(&growth);
+        }
+    }
+
+    fn fail(&self, reason: &str) -> ! {
+        for sample in &self.samples {
+            log::error!("{:?}", sample);
+        }
+        panic!(
+            "Soak test failed on loop {} after {:.0}s: {}",
+            self.loop_index,
+            self.elapsed.as_secs_f32(),
+            reason
+        );
+    }
+}
+
+/// Describes whatever has grown too far past the baseline, if anything. Queues go up
+/// and down with whatever's streaming at the time, so rather than comparing them to the
+/// baseline they only count if they've grown every loop for a while.
+fn find_growth(baseline: &SoakSample, samples: &[SoakSample]) -> Option<String> {
+    let sample = samples.last()?;
+    let totals = [
+        ("chunks", baseline.chunks, sample.chunks),
+        (
+            "tracked chunk versions",
+            baseline.brickmaps.tracked_chunks,
+            sample.brickmaps.tracked_chunks,
+        ),
+    ];
+    for (name, before, after) in totals {
+        if after as f64 > before.max(1) as f64 * GROWTH_TOLERANCE {
+            return Some(format!(
+                "{} grew from {} on loop {} to {}",
+                name, before, baseline.loop_index, after
+            ));
+        }
+    }
+
+    if let (Some(before), Some(after)) = (baseline.resident_bytes, sample.resident_bytes) {
+        if after as f64 > before as f64 * GROWTH_TOLERANCE + MEMORY_SLACK as f64 {
+            return Some(format!(
+                "resident memory grew from {}MB on loop {} to {}MB",
+                before >> 20,
+                baseline.loop_index,
+                after >> 20
+            ));
+        }
+    }
+
+    let recent = &samples[samples.len().saturating_sub(QUEUE_GROWTH_LOOPS)..];
+    if recent.len() < QUEUE_GROWTH_LOOPS {
+        return None;
+    }
+    let names = ["pending reloads", "waiting requests", "staged uploads"];
+    for (i, name) in names.into_iter().enumerate() {
+        let sizes: Vec<usize> = recent
+            .iter()
+            .map(|s| {
+                let stats = &s.brickmaps;
+                [
+                    stats.pending_reloads,
+                    stats.waiting_requests,
+                    stats.staged_uploads,
+                ][i]
+            })
+            .collect();
+        if sizes.windows(2).all(|pair| pair[1] > pair[0]) {
+            return Some(format!("{} kept growing: {:?}", name, sizes));
+        }
+    }
+    None
+}
+
+/// A loop of waypoints around `origin`, with an explosion or a filled box at each one.
+fn generate_script(seed: u32, origin: glam::Vec3) -> Vec<SoakAction> {
+    // Small xorshift so the same seed always plays the same script
+    let mut state = seed.max(1);
+    let mut random = move || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as f32 / u32::MAX as f32
+    };
+
+    let mut script = Vec::with_capacity(WAYPOINT_COUNT * 2 + 1);
+    for i in 0..WAYPOINT_COUNT {
+        let angle = random() * std::f32::consts::TAU;
+        let distance = random() * WANDER_RADIUS;
+        let height = (random() - 0.5) * 16.0;
+        let waypoint = origin + glam::vec3(angle.cos() * distance, height, angle.sin() * distance);
+        script.push(SoakAction::FlyTo(waypoint));
+
+        // Just below and ahead of the camera, so the edits are on screen
+        let center = (waypoint * 8.0).floor().as_ivec3() + glam::ivec3(0, -16, 24);
+        let size = 4 + (random() * 12.0) as i32;
+        script.push(match i % 2 {
+            0 => SoakAction::Explode(Explosion {
+                seed: i as i32,
+                ..Explosion::new(center, size as f32)
+            }),
+            _ => SoakAction::Fill {
+                min: center - size / 2,
+                max: center + size / 2,
+                voxel: {
+                    let materials = BuiltinMaterial::ALL;
+                    let material =
+                        materials[(random() * materials.len() as f32) as usize % materials.len()];
+                    Voxel::Material(material.id())
+                },
+            },
+        });
+    }
+
+    // Finish back where we started so the loop joins up
+    script.push(SoakAction::FlyTo(origin));
+    script
+}
+
+/// Resident set size of the process, on platforms where it's easy to get.
+fn resident_bytes() -> Option<u64> {
+    #[cfg(target_os = "linux")]
+    {
+        // The second field is the resident page count. Pages are assumed to be 4KB,
+        // which is fine for comparing against earlier samples
+        let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+        let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+        Some(pages * 4096)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        None
+    }
+}
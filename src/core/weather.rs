@@ -0,0 +1,146 @@
+use std::time::Duration;
+
+use crate::voxel::brickmap::{Atmosphere, ParticleKind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Weather {
+    Clear,
+    Rain,
+    Snow,
+    Fog,
+}
+
+impl Weather {
+    pub fn get_atmosphere(&self) -> Atmosphere {
+        let clear = Atmosphere::default();
+        match self {
+            Self::Clear => clear,
+            Self::Rain => Atmosphere {
+                sky_color: glam::vec3(0.35, 0.38, 0.45),
+                fog_color: glam::vec3(0.4, 0.42, 0.48),
+                fog_density: 0.02,
+                wetness: 1.0,
+                ..clear
+            },
+            Self::Snow => Atmosphere {
+                sky_color: glam::vec3(0.75, 0.78, 0.85),
+                fog_color: glam::vec3(0.85, 0.87, 0.9),
+                fog_density: 0.03,
+                wetness: 0.3,
+                ..clear
+            },
+            Self::Fog => Atmosphere {
+                sky_color: glam::vec3(0.6, 0.62, 0.65),
+                fog_color: glam::vec3(0.65, 0.67, 0.7),
+                fog_density: 0.15,
+                wetness: 0.2,
+                particle_density: 0.3,
+            },
+        }
+    }
+
+    pub fn get_particles(&self) -> Option<ParticleKind> {
+        match self {
+            Self::Clear => None,
+            Self::Rain => Some(ParticleKind::Rain),
+            Self::Snow => Some(ParticleKind::Snow),
+            Self::Fog => Some(ParticleKind::Dust),
+        }
+    }
+
+    /// The next weather in the cycle, for flicking through them.
+    pub fn next(&self) -> Self {
+        match self {
+            Self::Clear => Self::Rain,
+            Self::Rain => Self::Snow,
+            Self::Snow => Self::Fog,
+            Self::Fog => Self::Clear,
+        }
+    }
+}
+
+/// Blends between weather states over time. The atmosphere is interpolated, while the
+/// particles fade out to nothing before fading back in as the new kind.
+#[derive(Debug)]
+pub struct WeatherController {
+    current: Weather,
+    target: Weather,
+    transition_time: Duration,
+    elapsed: Duration,
+}
+
+impl WeatherController {
+    pub fn new(weather: Weather) -> Self {
+        Self {
+            current: weather,
+            target: weather,
+            transition_time: Duration::ZERO,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    /// The weather we're currently transitioning to, or are in if not transitioning.
+    pub fn get_weather(&self) -> Weather {
+        self.target
+    }
+
+    pub fn is_transitioning(&self) -> bool {
+        self.current != self.target
+    }
+
+    /// Starts transitioning to a new weather. Any transition in progress snaps to its
+    /// end first.
+    pub fn set_weather(&mut self, weather: Weather, transition_time: Duration) {
+        self.current = self.target;
+        self.target = weather;
+        self.transition_time = transition_time;
+        self.elapsed = Duration::ZERO;
+        log::info!(
+            "Weather changing from {:?} to {:?} over {:.1}s",
+            self.current,
+            self.target,
+            transition_time.as_secs_f32()
+        );
+    }
+
+    pub fn update(&mut self, dt: &Duration) {
+        if !self.is_transitioning() {
+            return;
+        }
+
+        self.elapsed += *dt;
+        if self.elapsed >= self.transition_time {
+            self.current = self.target;
+        }
+    }
+
+    fn get_progress(&self) -> f32 {
+        if !self.is_transitioning() || self.transition_time.is_zero() {
+            return 1.0;
+        }
+        (self.elapsed.as_secs_f32() / self.transition_time.as_secs_f32()).min(1.0)
+    }
+
+    pub fn get_atmosphere(&self) -> Atmosphere {
+        let t = self.get_progress();
+        let from = self.current.get_atmosphere();
+        let to = self.target.get_atmosphere();
+        let mut atmosphere = from.lerp(&to, t);
+
+        // Switching particle kinds happens halfway through, when there are none visible
+        if self.current.get_particles() != self.target.get_particles() {
+            atmosphere.particle_density = match t < 0.5 {
+                true => from.particle_density * (1.0 - t * 2.0),
+                false => to.particle_density * (t * 2.0 - 1.0),
+            };
+        }
+        atmosphere
+    }
+
+    pub fn get_particles(&self) -> Option<ParticleKind> {
+        match self.get_progress() < 0.5 {
+            true => self.current.get_particles(),
+            false => self.target.get_particles(),
+        }
+    }
+}
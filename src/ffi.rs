@@ -0,0 +1,259 @@
+//! C ABI for driving the world from other languages, e.g. for batch processing content
+//! with external tools. See `include/voxel_rs.h` for the matching header.
+//!
+//! Voxels are passed around as packed `0xAARRGGBB` colours, where an alpha of 0 means
+//! the voxel is empty. Colours read back are the albedo of the voxel's material, and
+//! colours written get a plain material added to the world for them if there isn't one
+//! already. Positions are in world voxel space.
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use crate::{
+    math,
+    voxel::world::{GenerationSettings, Voxel, WorldManager},
+};
+
+fn voxel_to_color(world: &WorldManager, voxel: Voxel) -> u32 {
+    match voxel {
+        Voxel::Empty => 0,
+        Voxel::Material(id) => {
+            let [r, g, b] = world.get_materials().get(id).albedo;
+            0xFF000000 | ((r as u32) << 16) | ((g as u32) << 8) | b as u32
+        }
+    }
+}
+
+fn color_to_voxel(world: &mut WorldManager, color: u32) -> Voxel {
+    if color >> 24 == 0 {
+        return Voxel::Empty;
+    }
+
+    let (r, g, b) = ((color >> 16) as u8, (color >> 8) as u8, color as u8);
+    Voxel::Material(world.get_materials_mut().find_or_add_color(r, g, b))
+}
+
+/// Runs `f`, turning any panic into `default` so it doesn't unwind into foreign code.
+fn guard<T>(default: T, f: impl FnOnce() -> T) -> T {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|_| {
+        log::error!("Panic caught at FFI boundary");
+        default
+    })
+}
+
+/// Checks a region and buffer line up, returning the voxel count of the region.
+fn region_len(size: [u32; 3], len: usize) -> Option<usize> {
+    let count = (size[0] as usize)
+        .checked_mul(size[1] as usize)?
+        .checked_mul(size[2] as usize)?;
+    (count == len).then_some(count)
+}
+
+/// Creates a world generated with the given seed. Free it with `voxel_world_destroy`.
+#[no_mangle]
+pub extern "C" fn voxel_world_create(seed: i32) -> *mut WorldManager {
+    guard(std::ptr::null_mut(), || {
+        let settings = GenerationSettings {
+            seed,
+            ..Default::default()
+        };
+        let world = WorldManager::new(settings, WorldManager::DEFAULT_CHUNK_DIMS);
+        Box::into_raw(Box::new(world))
+    })
+}
+
+/// # Safety
+/// `world` must be null or have come from `voxel_world_create`, and not be used again.
+#[no_mangle]
+pub unsafe extern "C" fn voxel_world_destroy(world: *mut WorldManager) {
+    if !world.is_null() {
+        drop(Box::from_raw(world));
+    }
+}
+
+/// Returns the colour of the voxel at `(x, y, z)`, or 0 if it's empty or `world` is null.
+///
+/// # Safety
+/// `world` must be null or a live world from `voxel_world_create`.
+#[no_mangle]
+pub unsafe extern "C" fn voxel_world_get_voxel(
+    world: *mut WorldManager,
+    x: i32,
+    y: i32,
+    z: i32,
+) -> u32 {
+    let Some(world) = world.as_mut() else {
+        return 0;
+    };
+    guard(0, || {
+        let voxel = world.get_voxel(glam::ivec3(x, y, z));
+        voxel_to_color(world, voxel)
+    })
+}
+
+/// Sets the voxel at `(x, y, z)`. Returns false on failure.
+///
+/// # Safety
+/// `world` must be null or a live world from `voxel_world_create`.
+#[no_mangle]
+pub unsafe extern "C" fn voxel_world_set_voxel(
+    world: *mut WorldManager,
+    x: i32,
+    y: i32,
+    z: i32,
+    color: u32,
+) -> bool {
+    let Some(world) = world.as_mut() else {
+        return false;
+    };
+    guard(false, || {
+        let voxel = color_to_voxel(world, color);
+        world.set_voxel(glam::ivec3(x, y, z), voxel);
+        true
+    })
+}
+
+/// Copies a box of voxels starting at `min` into `out`, x first then y then z. `len` must
+/// be exactly `size_x * size_y * size_z`. Returns false on failure.
+///
+/// # Safety
+/// `world` must be null or a live world from `voxel_world_create`, and `out` must be
+/// null or valid for writing `len` colours.
+#[no_mangle]
+pub unsafe extern "C" fn voxel_world_export_region(
+    world: *mut WorldManager,
+    min: *const i32,
+    size: *const u32,
+    out: *mut u32,
+    len: usize,
+) -> bool {
+    let (Some(world), Some(min), Some(size)) = (
+        world.as_mut(),
+        min.cast::<[i32; 3]>().as_ref(),
+        size.cast::<[u32; 3]>().as_ref(),
+    ) else {
+        return false;
+    };
+    let Some(count) = region_len(*size, len).filter(|_| !out.is_null()) else {
+        return false;
+    };
+
+    let out = std::slice::from_raw_parts_mut(out, count);
+    guard(false, || {
+        let min = glam::IVec3::from_array(*min);
+        let size = glam::UVec3::from_array(*size);
+        for (i, color) in out.iter_mut().enumerate() {
+            let offset = math::to_3d_index(i, size).as_ivec3();
+            let voxel = world.get_voxel(min + offset);
+            *color = voxel_to_color(world, voxel);
+        }
+        true
+    })
+}
+
+/// Writes a box of voxels starting at `min` from `data`, laid out as in
+/// `voxel_world_export_region`. Returns false on failure.
+///
+/// # Safety
+/// `world` must be null or a live world from `voxel_world_create`, and `data` must be
+/// null or valid for reading `len` colours.
+#[no_mangle]
+pub unsafe extern "C" fn voxel_world_import_region(
+    world: *mut WorldManager,
+    min: *const i32,
+    size: *const u32,
+    data: *const u32,
+    len: usize,
+) -> bool {
+    let (Some(world), Some(min), Some(size)) = (
+        world.as_mut(),
+        min.cast::<[i32; 3]>().as_ref(),
+        size.cast::<[u32; 3]>().as_ref(),
+    ) else {
+        return false;
+    };
+    let Some(count) = region_len(*size, len).filter(|_| !data.is_null()) else {
+        return false;
+    };
+
+    let data = std::slice::from_raw_parts(data, count);
+    guard(false, || {
+        let min = glam::IVec3::from_array(*min);
+        let size = glam::UVec3::from_array(*size);
+        for (i, color) in data.iter().enumerate() {
+            let offset = math::to_3d_index(i, size).as_ivec3();
+            let voxel = color_to_voxel(world, *color);
+            world.set_voxel(min + offset, voxel);
+        }
+        true
+    })
+}
+
+/// Renders the world on the CPU into `out` as tightly packed RGBA8, `width * height * 4`
+/// bytes long. The camera uses the same yaw/pitch convention as the app, with angles
+/// in radians. Rays give up after `max_distance` voxels. Returns false on failure.
+///
+/// # Safety
+/// `world` must be null or a live world from `voxel_world_create`, `position` must be
+/// null or point to 3 floats, and `out` must be null or valid for writing `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn voxel_world_render(
+    world: *mut WorldManager,
+    position: *const f32,
+    yaw: f32,
+    pitch: f32,
+    fov_y: f32,
+    max_distance: f32,
+    width: u32,
+    height: u32,
+    out: *mut u8,
+    len: usize,
+) -> bool {
+    let (Some(world), Some(position)) = (world.as_mut(), position.cast::<[f32; 3]>().as_ref())
+    else {
+        return false;
+    };
+    if out.is_null() || len != width as usize * height as usize * 4 {
+        return false;
+    }
+
+    let out = std::slice::from_raw_parts_mut(out, len);
+    guard(false, || {
+        let origin = glam::Vec3::from_array(*position);
+        let front = glam::vec3(
+            pitch.cos() * yaw.cos(),
+            pitch.sin(),
+            pitch.cos() * yaw.sin(),
+        )
+        .normalize();
+        let right = front.cross(glam::Vec3::Y).normalize();
+        let up = right.cross(front);
+        let half_height = (fov_y * 0.5).tan();
+        let half_width = half_height * width as f32 / height as f32;
+        let sun = glam::vec3(0.3, 1.0, 0.5).normalize();
+
+        for (i, pixel) in out.chunks_exact_mut(4).enumerate() {
+            let x = (i as u32 % width) as f32 + 0.5;
+            let y = (i as u32 / width) as f32 + 0.5;
+            let u = (x / width as f32 * 2.0 - 1.0) * half_width;
+            let v = (1.0 - y / height as f32 * 2.0) * half_height;
+            let direction = front + right * u + up * v;
+
+            let color = match world.raycast(origin, direction, max_distance) {
+                Some(hit) => {
+                    // Rays only stop at solid voxels, so this would be a raycast bug
+                    let Voxel::Material(id) = hit.voxel else {
+                        log::error!("Ray hit an empty voxel at {}", hit.position);
+                        return false;
+                    };
+                    let material = world.get_materials().get(id);
+                    let [r, g, b] = material.albedo.map(|c| c as f32);
+                    let light = 0.4 + 0.6 * hit.normal.as_vec3().dot(sun).max(0.0);
+                    glam::vec3(r, g, b) * light + material.get_emission() * 255.0
+                }
+                None => glam::vec3(153.0, 204.0, 255.0),
+            };
+            pixel.copy_from_slice(&[color.x as u8, color.y as u8, color.z as u8, 255]);
+        }
+        true
+    })
+}
@@ -0,0 +1,142 @@
+use std::fmt::Display;
+
+/// Which GPU to render with, see `Context::new`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AdapterSelection {
+    /// Backends to look for adapters on, `None` for the platform's default. If none of
+    /// them has a usable adapter every other backend is tried before giving up.
+    pub backends: Option<wgpu::Backends>,
+    /// `None` picks one by power preference
+    pub adapter: Option<AdapterChoice>,
+}
+
+impl AdapterSelection {
+    pub fn with_backends(mut self, backends: wgpu::Backends) -> Self {
+        self.backends = Some(backends);
+        self
+    }
+
+    pub fn with_adapter(mut self, adapter: AdapterChoice) -> Self {
+        self.adapter = Some(adapter);
+        self
+    }
+
+    /// The backends to try first.
+    pub(super) fn get_preferred_backends(&self) -> wgpu::Backends {
+        // Not every Android device has a usable Vulkan driver, so allow falling back to GLES
+        #[cfg(target_os = "android")]
+        let default_backends = wgpu::Backends::VULKAN | wgpu::Backends::GL;
+        #[cfg(not(target_os = "android"))]
+        let default_backends = wgpu::Backends::VULKAN;
+        self.backends.unwrap_or(default_backends)
+    }
+}
+
+/// A specific adapter, as listed in the log at startup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdapterChoice {
+    /// Position in the list of usable adapters
+    Index(usize),
+    /// Any adapter whose name contains this, ignoring case
+    Name(String),
+}
+
+impl AdapterChoice {
+    /// A number is taken as an index, anything else as a name.
+    pub fn parse(value: &str) -> Self {
+        match value.parse() {
+            Ok(index) => Self::Index(index),
+            Err(_) => Self::Name(value.to_owned()),
+        }
+    }
+
+    fn matches(&self, index: usize, info: &wgpu::AdapterInfo) -> bool {
+        match self {
+            Self::Index(i) => *i == index,
+            Self::Name(name) => info.name.to_lowercase().contains(&name.to_lowercase()),
+        }
+    }
+}
+
+impl Display for AdapterChoice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Index(index) => write!(f, "{}", index),
+            Self::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Parses a backend name as written in the config, e.g. `vulkan`. Case is ignored.
+pub fn parse_backend(name: &str) -> Option<wgpu::Backends> {
+    match name.to_lowercase().as_str() {
+        "vulkan" => Some(wgpu::Backends::VULKAN),
+        "dx12" => Some(wgpu::Backends::DX12),
+        "metal" => Some(wgpu::Backends::METAL),
+        "gl" => Some(wgpu::Backends::GL),
+        _ => None,
+    }
+}
+
+/// The config name of a single backend, or `None` for a mix of them.
+pub fn backend_name(backends: wgpu::Backends) -> Option<&'static str> {
+    [
+        (wgpu::Backends::VULKAN, "vulkan"),
+        (wgpu::Backends::DX12, "dx12"),
+        (wgpu::Backends::METAL, "metal"),
+        (wgpu::Backends::GL, "gl"),
+    ]
+    .into_iter()
+    .find(|(b, _)| *b == backends)
+    .map(|(_, name)| name)
+}
+
+/// Finds the adapter to use among those on `instance` that can draw to `surface`, or
+/// `None` if there aren't any. If the chosen adapter isn't there, this says so and
+/// picks one by power preference instead.
+pub(super) async fn select_adapter(
+    instance: &wgpu::Instance,
+    backends: wgpu::Backends,
+    surface: Option<&wgpu::Surface<'_>>,
+    power_preference: wgpu::PowerPreference,
+    choice: Option<&AdapterChoice>,
+) -> Option<wgpu::Adapter> {
+    let mut adapters: Vec<wgpu::Adapter> = instance
+        .enumerate_adapters(backends)
+        .into_iter()
+        .filter(|adapter| surface.is_none_or(|s| adapter.is_surface_supported(s)))
+        .collect();
+    for (i, adapter) in adapters.iter().enumerate() {
+        let info = adapter.get_info();
+        log::info!(
+            "Adapter {}: {} ({}, {:?})",
+            i,
+            info.name,
+            info.backend.to_str(),
+            info.device_type
+        );
+    }
+    if adapters.is_empty() {
+        return None;
+    }
+
+    if let Some(choice) = choice {
+        match adapters
+            .iter()
+            .enumerate()
+            .position(|(i, adapter)| choice.matches(i, &adapter.get_info()))
+        {
+            Some(i) => return Some(adapters.swap_remove(i)),
+            None => log::warn!("No adapter {}, picking one instead", choice),
+        }
+    }
+
+    instance
+        .request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference,
+            force_fallback_adapter: false,
+            compatible_surface: surface,
+        })
+        .await
+        .or_else(|| adapters.into_iter().next())
+}
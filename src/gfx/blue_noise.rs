@@ -0,0 +1,119 @@
+/// Width of the gaussian used to measure how clustered texels are
+const SIGMA: f32 = 1.5;
+
+/// Generates a tileable `size` x `size` blue noise threshold map using the void and
+/// cluster method. Every value appears equally often and nearby texels are as different
+/// as possible, so thresholding it dithers evenly without any visible pattern.
+///
+/// This is O(texels²), which is fine for the small textures shaders tile across the
+/// screen but gets slow past 64x64.
+pub fn blue_noise(size: usize, seed: u32) -> Vec<u8> {
+    let count = size * size;
+
+    // How much a texel adds to the energy of every other texel, wrapping around the edges
+    let kernel: Vec<f32> = (0..count)
+        .map(|i| {
+            let dx = (i % size).min(size - i % size) as f32;
+            let dy = (i / size).min(size - i / size) as f32;
+            (-(dx * dx + dy * dy) / (2.0 * SIGMA * SIGMA)).exp()
+        })
+        .collect();
+    let mut pattern = Pattern {
+        size,
+        kernel: &kernel,
+        set: vec![false; count],
+        energy: vec![0.0; count],
+    };
+
+    // Small xorshift so the same seed always gives the same noise
+    let mut state = seed.max(1);
+    let mut random = move || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state
+    };
+
+    // Start from a sparse random pattern, then even it out by repeatedly moving the most
+    // clustered texel into the biggest gap until that's where it already is
+    let initial_count = count / 10;
+    let mut placed = 0;
+    while placed < initial_count {
+        let idx = random() as usize % count;
+        if !pattern.set[idx] {
+            pattern.toggle(idx);
+            placed += 1;
+        }
+    }
+    for _ in 0..count {
+        let cluster = pattern.tightest_cluster();
+        pattern.toggle(cluster);
+        let void = pattern.largest_void();
+        pattern.toggle(void);
+        if void == cluster {
+            break;
+        }
+    }
+
+    // Texels removed from the tightest clusters first get the lowest of the initial
+    // ranks, then the gaps get filled in from biggest to smallest for the rest
+    let mut ranks = vec![0; count];
+    let mut removing = pattern.clone();
+    for rank in (0..initial_count).rev() {
+        let cluster = removing.tightest_cluster();
+        removing.toggle(cluster);
+        ranks[cluster] = rank;
+    }
+    for rank in initial_count..count {
+        let void = pattern.largest_void();
+        pattern.toggle(void);
+        ranks[void] = rank;
+    }
+
+    ranks
+        .into_iter()
+        .map(|rank| (rank * 256 / count) as u8)
+        .collect()
+}
+
+#[derive(Clone)]
+struct Pattern<'a> {
+    size: usize,
+    kernel: &'a [f32],
+    set: Vec<bool>,
+    /// Sum of the kernel from every set texel
+    energy: Vec<f32>,
+}
+
+impl Pattern<'_> {
+    fn toggle(&mut self, idx: usize) {
+        self.set[idx] = !self.set[idx];
+        let sign = if self.set[idx] { 1.0 } else { -1.0 };
+        let (cx, cy) = (idx % self.size, idx / self.size);
+        for (i, energy) in self.energy.iter_mut().enumerate() {
+            let dx = (i % self.size + self.size - cx) % self.size;
+            let dy = (i / self.size + self.size - cy) % self.size;
+            *energy += sign * self.kernel[dx + dy * self.size];
+        }
+    }
+
+    /// The set texel with the most set texels around it.
+    fn tightest_cluster(&self) -> usize {
+        self.extreme(true, |a, b| a > b)
+    }
+
+    /// The unset texel with the fewest set texels around it.
+    fn largest_void(&self) -> usize {
+        self.extreme(false, |a, b| a < b)
+    }
+
+    fn extreme(&self, set: bool, better: impl Fn(f32, f32) -> bool) -> usize {
+        let mut best: Option<usize> = None;
+        for (i, &energy) in self.energy.iter().enumerate() {
+            if self.set[i] == set && best.is_none_or(|b| better(energy, self.energy[b])) {
+                best = Some(i);
+            }
+        }
+        best.expect("pattern has no texels in the requested state")
+    }
+}
@@ -13,6 +13,12 @@ pub struct BulkBufferBuilder<'a> {
     current_usage: wgpu::BufferUsages,
 }
 
+impl<'a> Default for BulkBufferBuilder<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<'a> BulkBufferBuilder<'a> {
     pub fn new() -> Self {
         Self {
@@ -80,6 +86,11 @@ pub trait BufferExt {
         context: &Context,
         bounds: S,
     ) -> Vec<T>;
+
+    /// Copies part of a buffer back to the CPU through a temporary buffer, so it works for
+    /// buffers that can't be mapped. The buffer needs `COPY_SRC` usage. This waits for the
+    /// GPU to catch up, so it's only meant for debugging.
+    fn read_back<T: bytemuck::Pod>(&self, context: &Context, offset: u64, size: u64) -> Vec<T>;
 }
 
 impl BufferExt for wgpu::Buffer {
@@ -96,4 +107,22 @@ impl BufferExt for wgpu::Buffer {
 
         data
     }
+
+    fn read_back<T: bytemuck::Pod>(&self, context: &Context, offset: u64, size: u64) -> Vec<T> {
+        let staging = context.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Read Back"),
+            size,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        let mut encoder = context
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Read Back"),
+            });
+        encoder.copy_buffer_to_buffer(self, offset, &staging, 0, size);
+        context.queue.submit(Some(encoder.finish()));
+
+        staging.get_mapped_range(context, ..)
+    }
 }
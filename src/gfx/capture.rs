@@ -0,0 +1,69 @@
+use renderdoc::{RenderDoc, V141};
+
+/// Grabs single frame GPU captures through RenderDoc's in-application API. This only does
+/// anything when the app is running under RenderDoc, otherwise arming is ignored.
+pub struct FrameCapture {
+    renderdoc: Option<RenderDoc<V141>>,
+    armed: bool,
+    capturing: bool,
+}
+
+impl FrameCapture {
+    pub fn new() -> Self {
+        let renderdoc = RenderDoc::new().ok();
+        if renderdoc.is_some() {
+            log::info!("RenderDoc detected, frame captures available");
+        }
+
+        Self {
+            renderdoc,
+            armed: false,
+            capturing: false,
+        }
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.renderdoc.is_some()
+    }
+
+    /// Captures the next frame. Returns false if RenderDoc isn't available.
+    pub fn arm(&mut self) -> bool {
+        self.armed = self.renderdoc.is_some();
+        self.armed
+    }
+
+    /// Starts capturing if a capture was armed. Call before any GPU work for the frame.
+    pub fn begin_frame(&mut self) {
+        let Some(renderdoc) = self.renderdoc.as_mut().filter(|_| self.armed) else {
+            return;
+        };
+
+        // Null device and window handles capture whatever we're rendering with
+        renderdoc.start_frame_capture(std::ptr::null(), std::ptr::null());
+        self.armed = false;
+        self.capturing = true;
+    }
+
+    /// Finishes an in-progress capture, attaching `metadata` as the capture comments.
+    pub fn end_frame(&mut self, metadata: &str) {
+        let Some(renderdoc) = self.renderdoc.as_mut().filter(|_| self.capturing) else {
+            return;
+        };
+
+        renderdoc.end_frame_capture(std::ptr::null(), std::ptr::null());
+        renderdoc.set_capture_file_comments(None, metadata);
+        self.capturing = false;
+
+        let latest = renderdoc.get_num_captures().checked_sub(1);
+        match latest.and_then(|i| renderdoc.get_capture(i)) {
+            Some((path, _)) => log::info!("Saved frame capture to {}", path.display()),
+            None => log::warn!("Frame capture finished but RenderDoc didn't save it"),
+        }
+    }
+}
+
+impl Default for FrameCapture {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,77 +1,458 @@
-use std::sync::Arc;
+use std::sync::{mpsc, Arc};
 
-use anyhow::{Context as _, Result};
+use anyhow::{bail, Context as _, Result};
 use winit::{
     dpi::PhysicalSize, event::WindowEvent, event_loop::EventLoopWindowTarget, window::Window,
 };
 
+use super::{
+    adapter::{select_adapter, AdapterSelection},
+    Frame, FrameImage, GpuError,
+};
+
+/// Format of the offscreen target headless contexts render into.
+const HEADLESS_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
 pub struct Context<'window> {
-    pub window: Arc<Window>,
+    /// `None` for headless contexts
+    pub window: Option<Arc<Window>>,
     pub instance: wgpu::Instance,
     pub size: PhysicalSize<u32>,
-    pub surface: wgpu::Surface<'window>,
+    pub surface: Option<wgpu::Surface<'window>>,
     pub surface_config: wgpu::SurfaceConfiguration,
     pub adapter: wgpu::Adapter,
-    pub device: wgpu::Device,
+    /// Shared so pipelines can be built on background threads
+    pub device: Arc<wgpu::Device>,
     pub queue: wgpu::Queue,
+    pub limits: wgpu::Limits,
+    pub power_preference: wgpu::PowerPreference,
+    selection: AdapterSelection,
+    /// What frames are drawn to without a window, see `new_headless`
+    headless_target: Option<wgpu::Texture>,
+    error_sender: mpsc::Sender<GpuError>,
+    error_receiver: mpsc::Receiver<GpuError>,
 }
 
 impl<'window> Context<'window> {
-    pub async fn new(window: Arc<Window>, limits: wgpu::Limits) -> Result<Self> {
+    /// Renders to `window` with the adapter `selection` picks, see `AdapterSelection`.
+    pub async fn new(
+        window: Arc<Window>,
+        limits: wgpu::Limits,
+        selection: AdapterSelection,
+    ) -> Result<Self> {
         log::info!("Initialising WGPU context...");
-        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
-            backends: wgpu::Backends::VULKAN,
-            dx12_shader_compiler: Default::default(),
-            ..Default::default()
-        });
 
         // To be able to start drawing we need a few things:
         // - A surface
         // - A GPU device to draw to the surface
         // - A draw command queue
-        log::info!("Initialising window surface...");
-        let surface = instance.create_surface(window.clone())?;
-
-        log::info!("Requesting GPU adapter...");
-        let adapter = instance
-            .request_adapter(&wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::HighPerformance,
-                force_fallback_adapter: false,
-                compatible_surface: Some(&surface),
-            })
-            .await
-            .context("Failed to find suitable GPU adapter")?;
+        // On Android the native window doesn't exist until the app is first resumed, so
+        // the surface gets created later by `resume`
+        #[cfg(target_os = "android")]
+        let surface_window = None;
+        #[cfg(not(target_os = "android"))]
+        let surface_window = Some(&window);
 
+        let power_preference = wgpu::PowerPreference::HighPerformance;
+        let (instance, surface, adapter) =
+            Self::create_instance(&selection, surface_window, power_preference).await?;
+        let (error_sender, error_receiver) = mpsc::channel();
+        let (device, queue) = Self::request_device(&adapter, &limits, &error_sender).await?;
+
+        log::info!("Configuring window surface...");
+        let size = window.inner_size();
+        let surface_config = match &surface {
+            Some(surface) => {
+                let config = surface
+                    .get_default_config(&adapter, size.width, size.height)
+                    .context("Surface configuration unsupported by adapter")?;
+                surface.configure(&device, &config);
+                config
+            }
+            // A placeholder until `resume` creates the surface and asks it what it wants
+            None => wgpu::SurfaceConfiguration {
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+                format: wgpu::TextureFormat::Rgba8UnormSrgb,
+                width: size.width.max(1),
+                height: size.height.max(1),
+                present_mode: wgpu::PresentMode::Fifo,
+                desired_maximum_frame_latency: 2,
+                alpha_mode: wgpu::CompositeAlphaMode::Auto,
+                view_formats: vec![],
+            },
+        };
+
+        Ok(Self {
+            window: Some(window),
+            instance,
+            size,
+            surface,
+            surface_config,
+            adapter,
+            device: Arc::new(device),
+            queue,
+            limits,
+            power_preference,
+            selection,
+            headless_target: None,
+            error_sender,
+            error_receiver,
+        })
+    }
+
+    /// A context without a window, for rendering in CI or batch jobs. Frames are drawn to
+    /// an offscreen texture of the given size instead of a surface, and can be read back
+    /// with `read_frame`.
+    pub async fn new_headless(
+        width: u32,
+        height: u32,
+        limits: wgpu::Limits,
+        selection: AdapterSelection,
+    ) -> Result<Self> {
+        log::info!("Initialising headless WGPU context...");
+        let power_preference = wgpu::PowerPreference::HighPerformance;
+        let (instance, _, adapter) =
+            Self::create_instance(&selection, None, power_preference).await?;
+        let (error_sender, error_receiver) = mpsc::channel();
+        let (device, queue) = Self::request_device(&adapter, &limits, &error_sender).await?;
+
+        let size = PhysicalSize::new(width.max(1), height.max(1));
+        let surface_config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            format: HEADLESS_FORMAT,
+            width: size.width,
+            height: size.height,
+            present_mode: wgpu::PresentMode::Fifo,
+            desired_maximum_frame_latency: 2,
+            alpha_mode: wgpu::CompositeAlphaMode::Auto,
+            view_formats: vec![],
+        };
+        let headless_target = Some(Self::create_headless_target(&device, &surface_config));
+
+        Ok(Self {
+            window: None,
+            instance,
+            size,
+            surface: None,
+            surface_config,
+            adapter,
+            device: Arc::new(device),
+            queue,
+            limits,
+            power_preference,
+            selection,
+            headless_target,
+            error_sender,
+            error_receiver,
+        })
+    }
+
+    /// Creates an instance on the preferred backends and finds an adapter on it that can
+    /// draw to `window`, if there is one. If the preferred backends don't have any, the
+    /// rest are tried before giving up.
+    async fn create_instance(
+        selection: &AdapterSelection,
+        window: Option<&Arc<Window>>,
+        power_preference: wgpu::PowerPreference,
+    ) -> Result<(
+        wgpu::Instance,
+        Option<wgpu::Surface<'window>>,
+        wgpu::Adapter,
+    )> {
+        let preferred = selection.get_preferred_backends();
+        let fallback = wgpu::Backends::all() - preferred;
+        for backends in [preferred, fallback] {
+            if backends.is_empty() {
+                continue;
+            }
+            if backends == fallback {
+                log::warn!(
+                    "No usable adapter on {:?}, trying {:?}",
+                    preferred,
+                    fallback
+                );
+            }
+
+            let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+                backends,
+                dx12_shader_compiler: Default::default(),
+                ..Default::default()
+            });
+            let surface = match window {
+                Some(window) => {
+                    log::info!("Initialising window surface...");
+                    Some(instance.create_surface(window.clone())?)
+                }
+                None => None,
+            };
+
+            log::info!("Requesting GPU adapter...");
+            let adapter = select_adapter(
+                &instance,
+                backends,
+                surface.as_ref(),
+                power_preference,
+                selection.adapter.as_ref(),
+            )
+            .await;
+            if let Some(adapter) = adapter {
+                let info = adapter.get_info();
+                log::info!("Using {} ({})", info.name, info.backend.to_str());
+                return Ok((instance, surface, adapter));
+            }
+        }
+        bail!("Failed to find suitable GPU adapter")
+    }
+
+    fn create_headless_target(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+    ) -> wgpu::Texture {
+        device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Headless Target"),
+            size: wgpu::Extent3d {
+                width: config.width,
+                height: config.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: config.format,
+            usage: config.usage,
+            view_formats: &[],
+        })
+    }
+
+    async fn request_device(
+        adapter: &wgpu::Adapter,
+        limits: &wgpu::Limits,
+        error_sender: &mpsc::Sender<GpuError>,
+    ) -> Result<(wgpu::Device, wgpu::Queue)> {
         log::info!("Checking GPU adapter meets requirements");
+        // We ask for big buffers, but can make do with smaller ones. Buffers get sized
+        // from the device limits we actually end up with.
+        let adapter_limits = adapter.limits();
+        let limits = wgpu::Limits {
+            max_storage_buffer_binding_size: u32::min(
+                limits.max_storage_buffer_binding_size,
+                adapter_limits.max_storage_buffer_binding_size,
+            ),
+            max_buffer_size: u64::min(limits.max_buffer_size, adapter_limits.max_buffer_size),
+            ..limits.clone()
+        };
+
+        // Timestamp queries are only used for profiling, so they're optional
+        let features = adapter.features() & wgpu::Features::TIMESTAMP_QUERY;
+
         log::info!("Requesting GPU device...");
         let (device, queue) = adapter
             .request_device(
                 &wgpu::DeviceDescriptor {
                     label: None,
-                    required_features: wgpu::Features::empty(),
+                    required_features: features,
                     required_limits: limits,
                 },
                 None,
             )
             .await?;
 
-        log::info!("Configuring window surface...");
+        // By default wgpu panics on any error we don't catch with an error scope. We'd
+        // rather hand them to the app so it can decide what to do
+        let error_sender = error_sender.clone();
+        let lost_sender = error_sender.clone();
+        device.on_uncaptured_error(Box::new(move |error| {
+            let _ = error_sender.send(GpuError::new("uncaptured operation", error));
+        }));
+        // Devices we drop ourselves report being lost too, which isn't worth recovering from
+        device.set_device_lost_callback(move |reason, message| {
+            if matches!(
+                reason,
+                wgpu::DeviceLostReason::Unknown | wgpu::DeviceLostReason::Destroyed
+            ) {
+                let _ = lost_sender.send(GpuError::device_lost(message));
+            }
+        });
+
+        Ok((device, queue))
+    }
+
+    /// Re-requests the adapter and device with a different power preference. Any GPU
+    /// resources created from the old device are invalid afterwards and need rebuilding.
+    /// Adapters picked by index or name stay picked.
+    pub async fn set_power_preference(
+        &mut self,
+        power_preference: wgpu::PowerPreference,
+    ) -> Result<()> {
+        if power_preference == self.power_preference {
+            return Ok(());
+        }
+        self.replace_device(power_preference).await
+    }
+
+    /// Re-requests the adapter and device after the device was lost (see
+    /// `GpuErrorKind::DeviceLost`). As with `set_power_preference`, every GPU resource
+    /// has to be rebuilt on the new device afterwards.
+    pub async fn recreate_device(&mut self) -> Result<()> {
+        log::warn!("Recreating lost GPU device...");
+        self.replace_device(self.power_preference).await
+    }
+
+    async fn replace_device(&mut self, power_preference: wgpu::PowerPreference) -> Result<()> {
+        // The instance is already on whichever backends had a usable adapter
+        let adapter = select_adapter(
+            &self.instance,
+            wgpu::Backends::all(),
+            self.surface.as_ref(),
+            power_preference,
+            self.selection.adapter.as_ref(),
+        )
+        .await
+        .context("Failed to find suitable GPU adapter")?;
+        let (device, queue) =
+            Self::request_device(&adapter, &self.limits, &self.error_sender).await?;
+        log::info!("Switched to GPU adapter: {}", adapter.get_info().name);
+
+        self.adapter = adapter;
+        self.device = Arc::new(device);
+        self.queue = queue;
+        self.power_preference = power_preference;
+
+        if let Some(surface) = &self.surface {
+            log::info!("Reconfiguring window surface...");
+            surface.configure(&self.device, &self.surface_config);
+        }
+        if self.headless_target.is_some() {
+            self.headless_target = Some(Self::create_headless_target(
+                &self.device,
+                &self.surface_config,
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn is_headless(&self) -> bool {
+        self.window.is_none()
+    }
+
+    /// The next frame to draw to, from the window's surface or the offscreen target of a
+    /// headless context. `None` while the app is suspended and there's nothing to draw to,
+    /// or when the surface couldn't give us a texture and the frame has to be skipped.
+    /// Only errors if the surface has run out of memory.
+    pub fn get_current_frame(&self) -> Result<Option<Frame>> {
+        let Some(surface) = &self.surface else {
+            return Ok(self.headless_target.as_ref().map(Frame::from_texture));
+        };
+
+        let surface_texture = match surface.get_current_texture() {
+            Ok(surface_texture) => surface_texture,
+            // The window changed in a way the surface didn't keep up with, e.g. it moved
+            // to another display, so it needs configuring again
+            Err(e @ (wgpu::SurfaceError::Outdated | wgpu::SurfaceError::Lost)) => {
+                log::warn!("{}, reconfiguring window surface", e);
+                surface.configure(&self.device, &self.surface_config);
+                match surface.get_current_texture() {
+                    Ok(surface_texture) => surface_texture,
+                    Err(e) => {
+                        log::warn!("Skipped frame: {}", e);
+                        return Ok(None);
+                    }
+                }
+            }
+            Err(wgpu::SurfaceError::Timeout) => {
+                log::warn!("Timed out waiting for the window surface, skipped frame");
+                return Ok(None);
+            }
+            Err(e @ wgpu::SurfaceError::OutOfMemory) => return Err(e.into()),
+        };
+        Ok(Some(Frame::from_surface(surface_texture)))
+    }
+
+    /// Copies the last frame drawn by a headless context back to the CPU. This waits for
+    /// the GPU to finish drawing it. Errors for windowed contexts, whose frames are gone
+    /// once they're presented.
+    pub fn read_frame(&self) -> Result<FrameImage> {
+        let target = self
+            .headless_target
+            .as_ref()
+            .context("Only headless contexts can read frames back")?;
+        Ok(FrameImage::read_back(self, target))
+    }
+
+    /// Runs `f` inside out of memory and validation error scopes, returning the first
+    /// error wgpu reported while it ran.
+    pub fn error_scope<T>(&self, label: &str, f: impl FnOnce() -> T) -> Result<T, GpuError> {
+        self.device.push_error_scope(wgpu::ErrorFilter::OutOfMemory);
+        self.device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let value = f();
+
+        // Scopes are a stack, so they have to be popped in reverse order
+        let validation = pollster::block_on(self.device.pop_error_scope());
+        let out_of_memory = pollster::block_on(self.device.pop_error_scope());
+        match out_of_memory.or(validation) {
+            Some(error) => Err(GpuError::new(label, error)),
+            None => Ok(value),
+        }
+    }
+
+    /// Takes every GPU error that happened outside of an error scope since the last call.
+    pub fn poll_errors(&self) -> Vec<GpuError> {
+        self.error_receiver.try_iter().collect()
+    }
+
+    /// Drops the window surface. Platforms like Android destroy the native window while
+    /// the app is suspended, so we can't hold onto anything created from it.
+    pub fn suspend(&mut self) {
+        if self.surface.take().is_some() {
+            log::info!("Dropping window surface...");
+        }
+    }
+
+    /// Creates the window surface if there isn't one yet or it was dropped by `suspend`.
+    /// Returns true if the surface's format changed, so anything drawing to it has to
+    /// be rebuilt.
+    pub fn resume(&mut self) -> Result<bool> {
+        let Some(window) = &self.window else {
+            return Ok(false);
+        };
+        if self.surface.is_some() {
+            return Ok(false);
+        }
+
+        log::info!("Recreating window surface...");
+        let surface = self.instance.create_surface(window.clone())?;
+
+        // The window may have changed size while we were suspended
         let size = window.inner_size();
-        let surface_config = surface
-            .get_default_config(&adapter, size.width, size.height)
+        if size.width > 0 && size.height > 0 {
+            self.size = size;
+        }
+
+        // Without a surface at startup (e.g. on Android) the old config is a placeholder,
+        // so the format and alpha mode come from the surface. Only the present mode was
+        // picked by us
+        let mut config = surface
+            .get_default_config(&self.adapter, self.size.width, self.size.height)
             .context("Surface configuration unsupported by adapter")?;
-        surface.configure(&device, &surface_config);
+        let present_mode = self.surface_config.present_mode;
+        if surface
+            .get_capabilities(&self.adapter)
+            .present_modes
+            .contains(&present_mode)
+        {
+            config.present_mode = present_mode;
+        }
+        let format_changed = config.format != self.surface_config.format;
+        surface.configure(&self.device, &config);
 
-        Ok(Self {
-            window,
-            instance,
-            size,
-            surface,
-            surface_config,
-            adapter,
-            device,
-            queue,
-        })
+        self.surface_config = config;
+        self.surface = Some(surface);
+        Ok(format_changed)
+    }
+
+    /// Headless contexts are never suspended.
+    pub fn is_suspended(&self) -> bool {
+        self.surface.is_none() && self.headless_target.is_none()
     }
 
     pub fn resize_surface(&mut self, new_size: PhysicalSize<u32>) {
@@ -79,7 +460,15 @@ impl<'window> Context<'window> {
             self.size = new_size;
             self.surface_config.width = new_size.width;
             self.surface_config.height = new_size.height;
-            self.surface.configure(&self.device, &self.surface_config);
+            if let Some(surface) = &self.surface {
+                surface.configure(&self.device, &self.surface_config);
+            }
+            if self.headless_target.is_some() {
+                self.headless_target = Some(Self::create_headless_target(
+                    &self.device,
+                    &self.surface_config,
+                ));
+            }
         }
     }
 
@@ -97,7 +486,9 @@ impl<'window> Context<'window> {
                 self.resize_surface(*physical_size);
             }
             WindowEvent::ScaleFactorChanged { .. } => {
-                self.resize_surface(self.window.inner_size());
+                if let Some(window) = &self.window {
+                    self.resize_surface(window.inner_size());
+                }
             }
 
             _ => handled = false,
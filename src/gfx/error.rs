@@ -0,0 +1,54 @@
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpuErrorKind {
+    OutOfMemory,
+    Validation,
+    /// The device stopped working, e.g. the driver was reset or the GPU unplugged.
+    /// Everything created from it is gone, see `Context::recreate_device`
+    DeviceLost,
+}
+
+/// An error reported by wgpu, tagged with what we were doing when it happened.
+#[derive(Debug, Clone)]
+pub struct GpuError {
+    pub kind: GpuErrorKind,
+    pub label: String,
+    pub message: String,
+}
+
+impl GpuError {
+    pub fn new(label: &str, error: wgpu::Error) -> Self {
+        let kind = match error {
+            wgpu::Error::OutOfMemory { .. } => GpuErrorKind::OutOfMemory,
+            wgpu::Error::Validation { .. } => GpuErrorKind::Validation,
+        };
+
+        Self {
+            kind,
+            label: label.to_owned(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn device_lost(message: String) -> Self {
+        Self {
+            kind: GpuErrorKind::DeviceLost,
+            label: "rendering".to_owned(),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for GpuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            GpuErrorKind::OutOfMemory => "Out of memory",
+            GpuErrorKind::Validation => "Validation error",
+            GpuErrorKind::DeviceLost => "Device lost",
+        };
+        write!(f, "{} during {}: {}", kind, self.label, self.message)
+    }
+}
+
+impl std::error::Error for GpuError {}
@@ -0,0 +1,125 @@
+use std::{io::Write, path::Path};
+
+use anyhow::{Context as _, Result};
+
+use super::{BufferExt, Context};
+
+/// Rows copied out of a texture have to start on this many bytes.
+const ROW_ALIGNMENT: u32 = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+
+/// A frame being drawn, either a window surface's next texture or a headless context's
+/// offscreen target. See `Context::get_current_frame`.
+pub struct Frame {
+    pub view: wgpu::TextureView,
+    /// `None` when drawing offscreen, where there's nothing to present
+    surface_texture: Option<wgpu::SurfaceTexture>,
+}
+
+impl Frame {
+    pub(super) fn from_surface(surface_texture: wgpu::SurfaceTexture) -> Self {
+        let view = surface_texture
+            .texture
+            .create_view(&wgpu::TextureViewDescriptor::default());
+        Self {
+            view,
+            surface_texture: Some(surface_texture),
+        }
+    }
+
+    pub(super) fn from_texture(texture: &wgpu::Texture) -> Self {
+        Self {
+            view: texture.create_view(&wgpu::TextureViewDescriptor::default()),
+            surface_texture: None,
+        }
+    }
+
+    /// Shows the frame in the window. Call after submitting the commands drawing it.
+    /// Offscreen frames stay in their target until the next one is drawn.
+    pub fn present(self) {
+        if let Some(surface_texture) = self.surface_texture {
+            surface_texture.present();
+        }
+    }
+}
+
+/// A frame copied back to the CPU, as tightly packed rows of 8 bit RGBA pixels, top row
+/// first.
+#[derive(Debug, Clone)]
+pub struct FrameImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl FrameImage {
+    /// Copies a 4 byte per pixel texture back, waiting for the GPU to finish with it.
+    /// The texture needs `COPY_SRC` usage.
+    pub(super) fn read_back(context: &Context, texture: &wgpu::Texture) -> Self {
+        let (width, height) = (texture.width(), texture.height());
+        let row_bytes = width * 4;
+        let padded_row_bytes = row_bytes.div_ceil(ROW_ALIGNMENT) * ROW_ALIGNMENT;
+
+        let staging = context.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Frame Read Back"),
+            size: (padded_row_bytes * height) as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        let mut encoder = context
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Frame Read Back"),
+            });
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &staging,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_row_bytes),
+                    rows_per_image: Some(height),
+                },
+            },
+            texture.size(),
+        );
+        context.queue.submit(Some(encoder.finish()));
+
+        let padded: Vec<u8> = staging.get_mapped_range(context, ..);
+        let pixels = padded
+            .chunks_exact(padded_row_bytes as usize)
+            .flat_map(|row| &row[..row_bytes as usize])
+            .copied()
+            .collect();
+
+        Self {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn get_pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let i = ((y * self.width + x) * 4) as usize;
+        [
+            self.pixels[i],
+            self.pixels[i + 1],
+            self.pixels[i + 2],
+            self.pixels[i + 3],
+        ]
+    }
+
+    /// Saves the image as a binary PPM, which almost anything can open or convert.
+    /// Alpha is dropped.
+    pub fn write_ppm(&self, path: &Path) -> Result<()> {
+        let mut data = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        data.reserve((self.width * self.height * 3) as usize);
+        for pixel in self.pixels.chunks_exact(4) {
+            data.extend_from_slice(&pixel[..3]);
+        }
+
+        let mut file = std::fs::File::create(path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        file.write_all(&data)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
@@ -0,0 +1,207 @@
+use std::fmt::Write as _;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PassKind {
+    Compute,
+    Render,
+    Copy,
+}
+
+/// A single pass within a frame, along with the resources it touches.
+#[derive(Debug, Clone)]
+pub struct FramePass {
+    pub name: String,
+    pub kind: PassKind,
+    pub reads: Vec<String>,
+    pub writes: Vec<String>,
+}
+
+impl FramePass {
+    pub fn new(name: &str, kind: PassKind) -> Self {
+        Self {
+            name: name.to_owned(),
+            kind,
+            reads: Vec::new(),
+            writes: Vec::new(),
+        }
+    }
+
+    pub fn with_read(mut self, resource: &str) -> Self {
+        self.reads.push(resource.to_owned());
+        self
+    }
+
+    pub fn with_write(mut self, resource: &str) -> Self {
+        self.writes.push(resource.to_owned());
+        self
+    }
+
+    /// Resources that are both read and written, e.g. read_write storage buffers.
+    pub fn with_read_write(self, resource: &str) -> Self {
+        self.with_read(resource).with_write(resource)
+    }
+
+    fn touches(&self, resource: &str) -> bool {
+        self.reads.iter().chain(&self.writes).any(|r| r == resource)
+    }
+
+    fn writes_to(&self, resource: &str) -> bool {
+        self.writes.iter().any(|r| r == resource)
+    }
+}
+
+/// A point where one pass has to finish with a resource before a later one can use it.
+/// wgpu inserts the actual barriers, this is just where they end up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Barrier {
+    pub resource: String,
+    /// Index of the pass that used the resource first
+    pub from: usize,
+    pub to: usize,
+}
+
+/// The passes a frame is made of, in submission order. Renderers describe their frames
+/// with one so the ordering and resource usage can be dumped to DOT or JSON and
+/// checked, e.g. when slotting in a custom pass.
+#[derive(Debug, Clone, Default)]
+pub struct FrameGraph {
+    passes: Vec<FramePass>,
+}
+
+impl FrameGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_pass(&mut self, pass: FramePass) {
+        self.passes.push(pass);
+    }
+
+    /// The pass a wgpu error message points at. wgpu notes the label of the pass at
+    /// fault, so this only works for passes labelled with their name here.
+    pub fn find_labelled_pass(&self, message: &str) -> Option<&FramePass> {
+        self.passes
+            .iter()
+            .find(|pass| message.contains(&format!("label = `{}`", pass.name)))
+    }
+
+    /// Every resource touched by the frame, in the order they're first used.
+    pub fn get_resources(&self) -> Vec<&str> {
+        let mut resources: Vec<&str> = Vec::new();
+        for pass in &self.passes {
+            for resource in pass.reads.iter().chain(&pass.writes) {
+                if !resources.contains(&resource.as_str()) {
+                    resources.push(resource);
+                }
+            }
+        }
+        resources
+    }
+
+    /// Hazards between consecutive uses of each resource. Reading after reading needs
+    /// nothing, anything involving a write has to wait for the earlier pass.
+    pub fn get_barriers(&self) -> Vec<Barrier> {
+        let mut barriers = Vec::new();
+        for resource in self.get_resources() {
+            let mut last: Option<usize> = None;
+            for (i, pass) in self.passes.iter().enumerate() {
+                if !pass.touches(resource) {
+                    continue;
+                }
+                if let Some(from) = last {
+                    if self.passes[from].writes_to(resource) || pass.writes_to(resource) {
+                        barriers.push(Barrier {
+                            resource: resource.to_owned(),
+                            from,
+                            to: i,
+                        });
+                    }
+                }
+                last = Some(i);
+            }
+        }
+        barriers
+    }
+
+    /// Graphviz source with passes as boxes, resources as ellipses and the barriers as
+    /// dashed edges between passes.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph frame {\n    rankdir=LR;\n");
+        for resource in self.get_resources() {
+            let _ = writeln!(dot, "    {:?} [shape=ellipse];", resource);
+        }
+        for (i, pass) in self.passes.iter().enumerate() {
+            let label = format!("{}: {} ({:?})", i, pass.name, pass.kind);
+            let _ = writeln!(dot, "    pass{} [shape=box, label={:?}];", i, label);
+            for read in &pass.reads {
+                let _ = writeln!(dot, "    {:?} -> pass{};", read, i);
+            }
+            for write in &pass.writes {
+                let _ = writeln!(dot, "    pass{} -> {:?};", i, write);
+            }
+        }
+        for barrier in self.get_barriers() {
+            let _ = writeln!(
+                dot,
+                "    pass{} -> pass{} [style=dashed, label={:?}];",
+                barrier.from, barrier.to, barrier.resource
+            );
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    pub fn to_json(&self) -> String {
+        let list = |items: &[String]| {
+            let quoted: Vec<String> = items.iter().map(|i| json_string(i)).collect();
+            format!("[{}]", quoted.join(", "))
+        };
+
+        let passes: Vec<String> = self
+            .passes
+            .iter()
+            .map(|pass| {
+                format!(
+                    "    {{\"name\": {}, \"kind\": \"{:?}\", \"reads\": {}, \"writes\": {}}}",
+                    json_string(&pass.name),
+                    pass.kind,
+                    list(&pass.reads),
+                    list(&pass.writes)
+                )
+            })
+            .collect();
+        let barriers: Vec<String> = self
+            .get_barriers()
+            .iter()
+            .map(|barrier| {
+                format!(
+                    "    {{\"resource\": {}, \"from\": {}, \"to\": {}}}",
+                    json_string(&barrier.resource),
+                    barrier.from,
+                    barrier.to
+                )
+            })
+            .collect();
+        format!(
+            "{{\n  \"passes\": [\n{}\n  ],\n  \"barriers\": [\n{}\n  ]\n}}\n",
+            passes.join(",\n"),
+            barriers.join(",\n")
+        )
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
@@ -1,11 +1,31 @@
+mod adapter;
 mod bind_group;
+mod blue_noise;
 mod buffer;
+mod capture;
 mod context;
+mod error;
+mod frame;
+mod frame_graph;
+mod pipeline;
+mod profiler;
+mod readback;
+mod shader;
 mod texture;
 
 pub use self::{
+    adapter::{backend_name, parse_backend, AdapterChoice, AdapterSelection},
     bind_group::{BindGroupBuilder, BindGroupLayoutBuilder},
+    blue_noise::blue_noise,
     buffer::{BufferExt, BulkBufferBuilder},
+    capture::FrameCapture,
     context::Context,
+    error::{GpuError, GpuErrorKind},
+    frame::{Frame, FrameImage},
+    frame_graph::{FrameGraph, FramePass, PassKind},
+    pipeline::PipelineTask,
+    profiler::{GpuProfiler, GpuTimings},
+    readback::ReadbackRing,
+    shader::{ShaderManager, ShaderSource},
     texture::{Texture, TextureBuilder},
 };
@@ -0,0 +1,57 @@
+use std::{sync::mpsc, time::Instant};
+
+use anyhow::{anyhow, Result};
+
+use super::Context;
+
+/// Builds pipelines on a background thread. Compiling a big shader can take long enough
+/// to stall the frame loop, so callers keep drawing something else until `poll` hands
+/// over the result.
+#[derive(Debug)]
+pub struct PipelineTask<T> {
+    label: String,
+    started: Instant,
+    receiver: mpsc::Receiver<T>,
+}
+
+impl<T: Send + 'static> PipelineTask<T> {
+    pub fn spawn<F>(context: &Context, label: &str, build: F) -> Result<Self>
+    where
+        F: FnOnce(&wgpu::Device) -> T + Send + 'static,
+    {
+        log::info!("Building {} pipelines in the background...", label);
+        let device = context.device.clone();
+        let (sender, receiver) = mpsc::channel();
+        std::thread::Builder::new()
+            .name(format!("{} pipelines", label))
+            .spawn(move || {
+                // The receiver is only gone if whoever wanted the pipelines was dropped
+                let _ = sender.send(build(&device));
+            })?;
+
+        Ok(Self {
+            label: label.to_owned(),
+            started: Instant::now(),
+            receiver,
+        })
+    }
+
+    /// Returns the pipelines once they're built. They're only returned once, after that
+    /// this will error.
+    pub fn poll(&self) -> Result<Option<T>> {
+        match self.receiver.try_recv() {
+            Ok(result) => {
+                log::info!(
+                    "Built {} pipelines in {:.2}ms",
+                    self.label,
+                    self.started.elapsed().as_secs_f32() * 1000.0
+                );
+                Ok(Some(result))
+            }
+            Err(mpsc::TryRecvError::Empty) => Ok(None),
+            Err(mpsc::TryRecvError::Disconnected) => {
+                Err(anyhow!("Building {} pipelines failed", self.label))
+            }
+        }
+    }
+}
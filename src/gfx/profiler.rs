@@ -0,0 +1,167 @@
+use std::{fmt, sync::Mutex};
+
+use super::{BulkBufferBuilder, Context, ReadbackRing};
+
+/// How long each profiled pass of a frame took on the GPU.
+#[derive(Debug, Clone, Default)]
+pub struct GpuTimings {
+    /// Pass names and their times in milliseconds, in the order they ran
+    pub passes: Vec<(&'static str, f32)>,
+}
+
+impl GpuTimings {
+    /// Combined time of every profiled pass in milliseconds. Anything between the passes
+    /// isn't counted.
+    pub fn get_total(&self) -> f32 {
+        self.passes.iter().map(|(_, ms)| ms).sum()
+    }
+}
+
+impl fmt::Display for GpuTimings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.2}ms", self.get_total())?;
+        for (name, ms) in &self.passes {
+            write!(f, ", {} {:.2}ms", name, ms)?;
+        }
+        Ok(())
+    }
+}
+
+/// Times passes on the GPU with timestamp queries written at the start and end of each
+/// one. Passes ask for their timestamp writes as they're encoded, then the queries get
+/// resolved into a free readback slot at the end of the frame and read back a few frames
+/// later without stalling, like the raycast stats.
+///
+/// Only exists on devices with timestamp query support.
+#[derive(Debug)]
+pub struct GpuProfiler {
+    query_set: wgpu::QuerySet,
+    max_passes: u32,
+    resolve_buffer: wgpu::Buffer,
+    readback: ReadbackRing,
+    /// Names of the passes in each slot, in query order
+    slot_passes: Vec<Mutex<Vec<&'static str>>>,
+    /// Passes given timestamp writes so far this frame
+    passes: Mutex<Vec<&'static str>>,
+    /// Nanoseconds per timestamp tick
+    timestamp_period: f32,
+    latest: Option<GpuTimings>,
+}
+
+impl GpuProfiler {
+    /// Returns `None` if the device can't do timestamp queries.
+    pub fn new(context: &Context, max_passes: u32, slot_count: usize) -> Option<Self> {
+        if !context
+            .device
+            .features()
+            .contains(wgpu::Features::TIMESTAMP_QUERY)
+        {
+            log::info!("GPU profiling unavailable, the device has no timestamp queries");
+            return None;
+        }
+
+        let query_set = context.device.create_query_set(&wgpu::QuerySetDescriptor {
+            label: Some("GPU Profiler Timestamps"),
+            ty: wgpu::QueryType::Timestamp,
+            count: max_passes * 2,
+        });
+        let size = max_passes as u64 * 2 * wgpu::QUERY_SIZE as u64;
+        let resolve_buffer = BulkBufferBuilder::new()
+            .set_usage(wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC)
+            .with_buffer("GPU Profiler Resolve", size, false)
+            .build(context)
+            .remove(0);
+
+        Some(Self {
+            query_set,
+            max_passes,
+            resolve_buffer,
+            readback: ReadbackRing::new(context, "GPU Profiler Readback", size, slot_count),
+            slot_passes: (0..slot_count).map(|_| Mutex::new(Vec::new())).collect(),
+            passes: Mutex::new(Vec::new()),
+            timestamp_period: context.queue.get_timestamp_period(),
+            latest: None,
+        })
+    }
+
+    /// Reserves a pair of queries for a pass, returning the index of the first. Passes
+    /// past `max_passes` in a frame don't get timed.
+    fn reserve(&self, name: &'static str) -> Option<u32> {
+        let mut passes = self.passes.lock().unwrap();
+        if passes.len() as u32 >= self.max_passes {
+            return None;
+        }
+        passes.push(name);
+        Some((passes.len() as u32 - 1) * 2)
+    }
+
+    /// Timestamp writes for a compute pass, to go in its descriptor.
+    pub fn compute_pass(&self, name: &'static str) -> Option<wgpu::ComputePassTimestampWrites<'_>> {
+        let index = self.reserve(name)?;
+        Some(wgpu::ComputePassTimestampWrites {
+            query_set: &self.query_set,
+            beginning_of_pass_write_index: Some(index),
+            end_of_pass_write_index: Some(index + 1),
+        })
+    }
+
+    /// Timestamp writes for a render pass, to go in its descriptor.
+    pub fn render_pass(&self, name: &'static str) -> Option<wgpu::RenderPassTimestampWrites<'_>> {
+        let index = self.reserve(name)?;
+        Some(wgpu::RenderPassTimestampWrites {
+            query_set: &self.query_set,
+            beginning_of_pass_write_index: Some(index),
+            end_of_pass_write_index: Some(index + 1),
+        })
+    }
+
+    /// Resolves this frame's queries into a free readback slot, returning the slot to
+    /// map once submitted. Returns `None` if nothing was timed or every slot is still
+    /// waiting on the GPU, in which case the frame goes unreported.
+    pub fn end_frame(&self, encoder: &mut wgpu::CommandEncoder) -> Option<usize> {
+        let passes = std::mem::take(&mut *self.passes.lock().unwrap());
+        if passes.is_empty() {
+            return None;
+        }
+        let slot = self.readback.find_free_slot()?;
+
+        let query_count = passes.len() as u32 * 2;
+        encoder.resolve_query_set(&self.query_set, 0..query_count, &self.resolve_buffer, 0);
+        encoder.copy_buffer_to_buffer(
+            &self.resolve_buffer,
+            0,
+            self.readback.get_buffer(slot),
+            0,
+            query_count as u64 * wgpu::QUERY_SIZE as u64,
+        );
+        *self.slot_passes[slot].lock().unwrap() = passes;
+        Some(slot)
+    }
+
+    /// Requests mapping of the slot. Must be called after the frame has been submitted.
+    pub fn map_slot(&self, slot: usize) {
+        self.readback.map_slot(slot);
+    }
+
+    /// Picks up the timings from any slots that have finished mapping.
+    pub fn poll(&mut self, context: &Context) {
+        self.readback.poll(context, |slot, data| {
+            let passes = std::mem::take(self.slot_passes[slot].get_mut().unwrap());
+            let timestamps: &[u64] = bytemuck::cast_slice(data);
+            let passes = passes
+                .iter()
+                .zip(timestamps.chunks_exact(2))
+                .map(|(name, pair)| {
+                    let ticks = pair[1].saturating_sub(pair[0]);
+                    (*name, ticks as f32 * self.timestamp_period / 1_000_000.0)
+                })
+                .collect();
+            self.latest = Some(GpuTimings { passes });
+        });
+    }
+
+    /// The most recent timings that have made it back from the GPU.
+    pub fn get_latest(&self) -> Option<&GpuTimings> {
+        self.latest.as_ref()
+    }
+}
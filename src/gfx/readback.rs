@@ -0,0 +1,86 @@
+use std::sync::{
+    atomic::{AtomicU8, Ordering},
+    Arc,
+};
+
+use super::{BulkBufferBuilder, Context};
+
+const SLOT_IDLE: u8 = 0;
+const SLOT_MAPPING: u8 = 1;
+const SLOT_MAPPED: u8 = 2;
+
+/// A ring of buffers for reading results back from the GPU without stalling. Each frame
+/// copies its results into a free slot, which gets mapped once the copy is submitted and
+/// read a frame or two later once the mapping has finished.
+#[derive(Debug)]
+pub struct ReadbackRing {
+    buffers: Vec<wgpu::Buffer>,
+    slot_states: Vec<Arc<AtomicU8>>,
+}
+
+impl ReadbackRing {
+    pub fn new(context: &Context, label: &str, size: u64, slot_count: usize) -> Self {
+        let mut builder = BulkBufferBuilder::new()
+            .set_usage(wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ);
+        for _ in 0..slot_count {
+            builder = builder.with_buffer(label, size, false);
+        }
+
+        Self {
+            buffers: builder.build(context),
+            slot_states: (0..slot_count)
+                .map(|_| Arc::new(AtomicU8::new(SLOT_IDLE)))
+                .collect(),
+        }
+    }
+
+    /// A slot that's free to copy into, or `None` if every slot is still waiting on the
+    /// GPU.
+    pub fn find_free_slot(&self) -> Option<usize> {
+        self.slot_states
+            .iter()
+            .position(|s| s.load(Ordering::Acquire) == SLOT_IDLE)
+    }
+
+    pub fn get_buffer(&self, slot: usize) -> &wgpu::Buffer {
+        &self.buffers[slot]
+    }
+
+    /// Has the slot been copied into but not read yet?
+    pub fn is_pending(&self, slot: usize) -> bool {
+        self.slot_states[slot].load(Ordering::Acquire) != SLOT_IDLE
+    }
+
+    /// Requests mapping of the slot. Must be called after the copy has been submitted.
+    pub fn map_slot(&self, slot: usize) {
+        let state = self.slot_states[slot].clone();
+        state.store(SLOT_MAPPING, Ordering::Release);
+        self.buffers[slot]
+            .slice(..)
+            .map_async(wgpu::MapMode::Read, move |result| {
+                // If mapping failed there's nothing to read, so the slot can be reused
+                let next = if result.is_ok() {
+                    SLOT_MAPPED
+                } else {
+                    SLOT_IDLE
+                };
+                state.store(next, Ordering::Release);
+            });
+    }
+
+    /// Hands the contents of every slot that's finished mapping to `read`, along with the
+    /// slot, then frees them.
+    pub fn poll(&self, context: &Context, mut read: impl FnMut(usize, &[u8])) {
+        context.device.poll(wgpu::Maintain::Poll);
+        for (slot, buffer) in self.buffers.iter().enumerate() {
+            let state = &self.slot_states[slot];
+            if state.load(Ordering::Acquire) != SLOT_MAPPED {
+                continue;
+            }
+
+            read(slot, &buffer.slice(..).get_mapped_range());
+            buffer.unmap();
+            state.store(SLOT_IDLE, Ordering::Release);
+        }
+    }
+}
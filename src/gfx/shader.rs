@@ -0,0 +1,140 @@
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::mpsc,
+};
+
+use anyhow::{anyhow, Context as _, Result};
+use notify::Watcher;
+
+/// A WGSL shader that can be loaded from the assets directory at runtime, with a copy
+/// baked into the binary for when the assets aren't around (e.g. on Android).
+#[derive(Debug, Clone, Copy)]
+pub struct ShaderSource {
+    /// File name within the shader directory
+    pub name: &'static str,
+    pub embedded: &'static str,
+}
+
+impl ShaderSource {
+    pub const fn new(name: &'static str, embedded: &'static str) -> Self {
+        Self { name, embedded }
+    }
+}
+
+/// Loads shaders from disk and watches them for changes, so pipelines can be rebuilt
+/// without recompiling. Shaders are parsed and validated before they're handed out, so
+/// a typo gets logged instead of taking down the device.
+pub struct ShaderManager {
+    directory: PathBuf,
+    // Never read, but dropping it stops the watching
+    _watcher: Option<notify::RecommendedWatcher>,
+    events: mpsc::Receiver<notify::Result<notify::Event>>,
+}
+
+impl std::fmt::Debug for ShaderManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShaderManager")
+            .field("directory", &self.directory)
+            .field("watching", &self._watcher.is_some())
+            .finish()
+    }
+}
+
+impl ShaderManager {
+    /// Watches the shaders in `directory`. If it can't be watched we still load from it,
+    /// there's just no hot reloading.
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        let directory = directory.into();
+        let (sender, events) = mpsc::channel();
+        let watcher = notify::recommended_watcher(sender).and_then(|mut watcher| {
+            watcher.watch(&directory, notify::RecursiveMode::NonRecursive)?;
+            Ok(watcher)
+        });
+        let watcher = match watcher {
+            Ok(watcher) => {
+                log::info!("Watching {} for shader changes", directory.display());
+                Some(watcher)
+            }
+            Err(e) => {
+                log::warn!("Shader hot reloading disabled: {}", e);
+                None
+            }
+        };
+
+        Self {
+            directory,
+            _watcher: watcher,
+            events,
+        }
+    }
+
+    /// The shader directory of the source tree, which is where they get edited.
+    pub fn with_default_directory() -> Self {
+        Self::new(Path::new(env!("CARGO_MANIFEST_DIR")).join("assets/shaders"))
+    }
+
+    /// Reads and validates a shader from disk.
+    pub fn load(&self, source: &ShaderSource) -> Result<String> {
+        let path = self.directory.join(source.name);
+        let code = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        validate(source.name, &code)?;
+        Ok(code)
+    }
+
+    /// Reads a shader from disk, falling back to the embedded copy if it's missing or
+    /// broken.
+    pub fn load_or_embedded(&self, source: &ShaderSource) -> Cow<'static, str> {
+        match self.load(source) {
+            Ok(code) => Cow::Owned(code),
+            Err(e) => {
+                log::warn!("Using embedded {}: {:#}", source.name, e);
+                Cow::Borrowed(source.embedded)
+            }
+        }
+    }
+
+    /// Names of the shader files that have changed since this was last called.
+    pub fn take_changed(&self) -> HashSet<String> {
+        let mut changed = HashSet::new();
+        for event in self.events.try_iter() {
+            let event = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    log::warn!("Shader watcher error: {}", e);
+                    continue;
+                }
+            };
+
+            // Editors tend to save by writing a new file and renaming it over the old one,
+            // so anything but a read or delete counts as a change
+            if event.kind.is_access() || event.kind.is_remove() {
+                continue;
+            }
+            for path in event.paths {
+                if path.extension().is_some_and(|e| e == "wgsl") {
+                    if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+                        changed.insert(name.to_owned());
+                    }
+                }
+            }
+        }
+        changed
+    }
+}
+
+fn validate(name: &str, code: &str) -> Result<()> {
+    use wgpu::naga;
+
+    let module = naga::front::wgsl::parse_str(code)
+        .map_err(|e| anyhow!("{}", e.emit_to_string_with_path(code, name)))?;
+    naga::valid::Validator::new(
+        naga::valid::ValidationFlags::all(),
+        naga::valid::Capabilities::all(),
+    )
+    .validate(&module)
+    .map_err(|e| anyhow!("{}", e.emit_to_string_with_path(code, name)))?;
+    Ok(())
+}
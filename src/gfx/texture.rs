@@ -41,6 +41,12 @@ pub struct TextureBuilder {
     pub attributes: TextureAttributes,
 }
 
+impl Default for TextureBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl TextureBuilder {
     pub fn new() -> Self {
         Self {
@@ -0,0 +1,52 @@
+//! A voxel engine built on wgpu: worlds that generate and stream chunks as they're
+//! explored, and renderers that raycast them on the GPU.
+//!
+//! `App` runs everything in a window, which is all the `voxel-rs` binary does. Projects
+//! that want more control can put together their own loop from a `WorldManager`, one
+//! of the `VoxelRenderer`s and the `gfx` builders.
+//!
+//! The library also provides the Android entry point, as Android apps are loaded as a
+//! shared library by the activity rather than being run as an executable, and holds the
+//! C ABI when the `ffi` feature is enabled.
+
+pub mod core;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod gfx;
+pub mod math;
+pub mod voxel;
+
+pub use crate::{
+    core::{App, Config, Scene, SceneEntities},
+    voxel::{world::WorldManager, VoxelRenderer},
+};
+
+#[cfg(target_os = "android")]
+use winit::{
+    event_loop::EventLoopBuilder,
+    platform::android::{activity::AndroidApp, EventLoopBuilderExtAndroid},
+};
+
+#[cfg(target_os = "android")]
+#[no_mangle]
+fn android_main(app: AndroidApp) {
+    android_logger::init_once(
+        android_logger::Config::default().with_max_level(log::LevelFilter::Info),
+    );
+
+    let result = EventLoopBuilder::new()
+        .with_android_app(app)
+        .build()
+        .map_err(anyhow::Error::from)
+        .and_then(|event_loop| {
+            // The window always fills the screen on Android, so the size is ignored. There's
+            // no config file to edit either, so we stick to the defaults
+            let config = core::Config::default();
+            pollster::block_on(core::App::with_event_loop(event_loop, config, "Epic"))
+        })
+        .and_then(|app| app.run());
+
+    if let Err(e) = result {
+        log::error!("{}", e);
+    }
+}
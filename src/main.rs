@@ -1,12 +1,43 @@
-mod core;
-mod gfx;
-mod math;
-mod voxel;
+// The app itself lives in the library, this just picks what to run from the command line
+use std::path::Path;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use voxel_rs::{core, voxel};
+
+const CONFIG_PATH: &str = "config.toml";
 
 fn main() -> Result<()> {
-    env_logger::init();
-    pollster::block_on(core::App::new(1280, 720, "Epic"))?.run()?;
+    core::crash::init_logging();
+    core::crash::install_panic_hook();
+    let mut config = core::Config::load_or_default(Path::new(CONFIG_PATH));
+
+    // `stats <world directory>` reports on a saved world instead of opening the app, and
+    // `scene <file>` opens it in a scene
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut scene = None;
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        [] => {}
+        ["scene", path] => {
+            let loaded = core::Scene::load(Path::new(path))?;
+            loaded.apply_to_config(&mut config);
+            scene = Some(loaded);
+        }
+        ["stats", directory] => {
+            let stats = voxel::world::WorldStats::scan(
+                Path::new(directory),
+                config.chunk_dims,
+                config.voxel_size,
+            )?;
+            print!("{}", stats);
+            return Ok(());
+        }
+        _ => bail!("{}", core::Locale::from_environment().get("usage")),
+    }
+
+    let mut app = pollster::block_on(core::App::new(config, "Epic"))?;
+    if let Some(scene) = scene {
+        app = app.with_scene(scene);
+    }
+    app.run()?;
     Ok(())
 }
@@ -82,3 +82,9 @@ pub fn tri_lerp_block(p: &[f32], dims: &[u32], vals: &mut [f32]) {
 pub fn to_1d_index(p: glam::UVec3, dim: glam::UVec3) -> usize {
     (p.x + p.y * dim.x + p.z * dim.x * dim.y) as usize
 }
+
+/// Maps a 1d index back to a 3d index
+pub fn to_3d_index(i: usize, dim: glam::UVec3) -> glam::UVec3 {
+    let i = i as u32;
+    glam::uvec3(i % dim.x, (i / dim.x) % dim.y, i / (dim.x * dim.y))
+}
@@ -1,6 +1,10 @@
-use std::collections::HashSet;
+use std::collections::BTreeSet;
 
-use crate::gfx::{BulkBufferBuilder, Context};
+use crate::{
+    gfx::{BufferExt, BulkBufferBuilder, Context},
+    math,
+    voxel::world::MaterialId,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum BrickgridFlag {
@@ -8,6 +12,10 @@ pub enum BrickgridFlag {
     Unloaded = 1,
     Loading = 2,
     Loaded = 4,
+    /// Only the brick's average colour is known. Kept clear of the loading bit so it can be
+    /// requested again for its full data once the camera gets close.
+    Lod = 5,
+    Uniform = 8,
 }
 
 impl From<u32> for BrickgridFlag {
@@ -16,6 +24,8 @@ impl From<u32> for BrickgridFlag {
             x if x == Self::Unloaded as u32 => Self::Unloaded,
             x if x == Self::Loading as u32 => Self::Loading,
             x if x == Self::Loaded as u32 => Self::Loaded,
+            x if x == Self::Lod as u32 => Self::Lod,
+            x if x == Self::Uniform as u32 => Self::Uniform,
             _ => Self::Empty,
         }
     }
@@ -30,6 +40,18 @@ impl BrickgridElement {
         Self(((brickmap_cache_idx as u32) << 8) + flag as u32)
     }
 
+    /// A brick where every voxel is the same material, stored inline in place of the
+    /// brickmap pointer.
+    pub fn new_uniform(material: MaterialId) -> Self {
+        Self(((material as u32) << 8) + BrickgridFlag::Uniform as u32)
+    }
+
+    /// A brick too far away to need its voxels, drawn as a solid block of its average
+    /// colour, stored inline as 24-bit RGB like a uniform brick.
+    pub fn new_lod(color: u32) -> Self {
+        Self(((color & 0xFFFFFF) << 8) + BrickgridFlag::Lod as u32)
+    }
+
     pub fn get_pointer(&self) -> usize {
         (self.0 >> 8) as usize
     }
@@ -39,40 +61,119 @@ impl BrickgridElement {
     }
 }
 
+/// One level of the brickgrid's occupancy mip chain. Each cell covers 2x2x2 cells of the
+/// level below it (or bricks for the first level) and is 0 if they're all empty.
+#[derive(Debug)]
+struct BrickgridMip {
+    dimensions: glam::UVec3,
+    data: Vec<u8>,
+    /// Region of the level changed since it was last uploaded
+    dirty: Option<(glam::UVec3, glam::UVec3)>,
+}
+
+impl BrickgridMip {
+    fn mark_dirty(&mut self, pos: glam::UVec3) {
+        self.dirty = Some(match self.dirty {
+            Some((min, max)) => (min.min(pos), max.max(pos + 1)),
+            None => (pos, pos + 1),
+        });
+    }
+}
+
 #[derive(Debug)]
 pub struct Brickgrid {
     dimensions: glam::UVec3,
     data: Vec<BrickgridElement>,
-    staged: HashSet<usize>,
+    /// Kept sorted so neighbouring entries can be uploaded as runs
+    staged: BTreeSet<usize>,
     max_upload_count: usize,
     buffer: wgpu::Buffer,
     upload_buffer: wgpu::Buffer,
+    /// A plane of unloaded elements, copied over cells to unload them in bulk
+    fill_buffer: wgpu::Buffer,
+    mips: Vec<BrickgridMip>,
+    mip_texture: wgpu::Texture,
+    mip_view: wgpu::TextureView,
 }
 
 impl Brickgrid {
+    /// Number of occupancy mip levels, the largest covering 16x16x16 bricks
+    pub const MIP_LEVELS: u32 = 4;
+
     pub fn new(context: &Context, dimensions: glam::UVec3, max_upload_count: usize) -> Self {
         let element_count = (dimensions.x * dimensions.y * dimensions.z) as usize;
         let data = vec![BrickgridElement::new(0, BrickgridFlag::Unloaded); element_count];
 
         // TODO: change type of upload data. Will need some messyness with bytemucking probably
         // but should lead to clearer data definitions
-        let mut upload_data = vec![0u32; 4 + 4 * max_upload_count];
+        // Worst case every value is its own run, so a 2 word run header per value
+        let mut upload_data = vec![0u32; 4 + 3 * max_upload_count];
         upload_data[0] = max_upload_count as u32;
+        let fill_data = vec![
+            BrickgridElement::new(0, BrickgridFlag::Unloaded);
+            (dimensions.x * dimensions.y) as usize
+        ];
 
         let mut buffers = BulkBufferBuilder::new()
-            .set_usage(wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST)
+            .set_usage(
+                wgpu::BufferUsages::STORAGE
+                    | wgpu::BufferUsages::COPY_DST
+                    | wgpu::BufferUsages::COPY_SRC,
+            )
             .with_init_buffer_bm("Brickgrid", &data)
             .with_init_buffer_bm("Brickgrid Upload", &upload_data)
+            .with_init_buffer_bm("Brickgrid Fill", &fill_data)
             .build(context);
 
-        Self {
+        // The mips live in a texture rather than a buffer as the raycast pass is already
+        // using every storage buffer binding the default limits give us. The first level is
+        // padded so every level halves exactly, padding counts as empty.
+        let level_scale = 1 << Self::MIP_LEVELS;
+        let base_dims = (dimensions + level_scale - 1) / level_scale * (level_scale / 2);
+        let mips: Vec<BrickgridMip> = (0..Self::MIP_LEVELS)
+            .map(|level| {
+                let dims = base_dims >> level;
+                BrickgridMip {
+                    dimensions: dims,
+                    data: vec![0; dims.element_product() as usize],
+                    dirty: None,
+                }
+            })
+            .collect();
+        let mip_texture = context.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Brickgrid Mips"),
+            size: wgpu::Extent3d {
+                width: base_dims.x,
+                height: base_dims.y,
+                depth_or_array_layers: base_dims.z,
+            },
+            mip_level_count: Self::MIP_LEVELS,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D3,
+            format: wgpu::TextureFormat::R8Uint,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        let mip_view = mip_texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let mut brickgrid = Self {
             dimensions,
             data,
-            staged: HashSet::new(),
+            staged: BTreeSet::new(),
             max_upload_count,
             buffer: buffers.remove(0),
             upload_buffer: buffers.remove(0),
-        }
+            fill_buffer: buffers.remove(0),
+            mips,
+            mip_texture,
+            mip_view,
+        };
+        brickgrid.rebuild_mips();
+        brickgrid
+    }
+
+    pub fn get_mip_view(&self) -> &wgpu::TextureView {
+        &self.mip_view
     }
 
     pub fn get_buffer(&self) -> &wgpu::Buffer {
@@ -83,48 +184,242 @@ impl Brickgrid {
         &self.upload_buffer
     }
 
+    pub fn get_staged_count(&self) -> usize {
+        self.staged.len()
+    }
+
+    /// Marks every brick as unloaded and drops anything waiting to be uploaded.
+    pub fn reset(&mut self, context: &Context) {
+        self.data
+            .fill(BrickgridElement::new(0, BrickgridFlag::Unloaded));
+        self.staged.clear();
+        self.rebuild_mips();
+        context
+            .queue
+            .write_buffer(&self.buffer, 0, bytemuck::cast_slice(&self.data));
+        context
+            .queue
+            .write_buffer(&self.upload_buffer, 4, bytemuck::cast_slice(&[0u32, 0]));
+    }
+
+    /// Marks a batch of cells as unloaded on the GPU straight away, rather than over the
+    /// next few frames' uploads like `set`. Indices must be sorted. Returns what each cell
+    /// held before.
+    pub fn unload(&mut self, context: &Context, indices: &[usize]) -> Vec<BrickgridElement> {
+        let unloaded = BrickgridElement::new(0, BrickgridFlag::Unloaded);
+        let mut old = Vec::with_capacity(indices.len());
+        for &index in indices {
+            let current = std::mem::replace(&mut self.data[index], unloaded);
+            self.staged.remove(&index);
+            if current.get_flag() == BrickgridFlag::Empty {
+                self.update_mips(math::to_3d_index(index, self.dimensions));
+            }
+            old.push(current);
+        }
+
+        // Copies are only recorded, so one per run is cheap where a write per run would
+        // need a staging buffer each
+        let mut encoder = context
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Brickgrid Unload"),
+            });
+        let fill_len = self.fill_buffer.size() / 4;
+        let mut i = 0;
+        while i < indices.len() {
+            let start = indices[i];
+            let mut len = 1;
+            while i + len < indices.len() && indices[i + len] == start + len {
+                len += 1;
+            }
+            i += len;
+
+            let mut offset = start as u64;
+            let end = (start + len) as u64;
+            while offset < end {
+                let count = (end - offset).min(fill_len);
+                encoder.copy_buffer_to_buffer(
+                    &self.fill_buffer,
+                    0,
+                    &self.buffer,
+                    offset * 4,
+                    count * 4,
+                );
+                offset += count;
+            }
+        }
+        context.queue.submit(Some(encoder.finish()));
+        old
+    }
+
     /// Panics if index out of range
     pub fn set(&mut self, index: usize, value: BrickgridElement) -> BrickgridElement {
         let current = self.data[index];
         self.data[index] = value;
         self.staged.insert(index);
+
+        let was_empty = current.get_flag() == BrickgridFlag::Empty;
+        if was_empty != (value.get_flag() == BrickgridFlag::Empty) {
+            self.update_mips(math::to_3d_index(index, self.dimensions));
+        }
         current
     }
 
+    /// Reads the whole brickgrid back from the GPU, for debugging. Unlike our copy this
+    /// includes the loading flags the raycast sets.
+    pub fn read_back(&self, context: &Context) -> Vec<BrickgridElement> {
+        self.buffer.read_back(context, 0, self.buffer.size())
+    }
+
     /// Panics if index out of range
     pub fn get(&mut self, index: usize) -> BrickgridElement {
         self.data[index]
     }
 
-    pub fn upload(&mut self, context: &Context) {
-        let mut upload_data = Vec::new();
-        let mut idx = 0;
-        self.staged.retain(|e| {
-            // We have a limit of how many elements to upload each frame. So we need
-            // to keep any excess
-            if idx >= self.max_upload_count {
-                return true;
+    /// Is anything in the 2x2x2 cells below `pos` in the level below `level` occupied?
+    fn is_occupied(&self, level: usize, pos: glam::UVec3) -> bool {
+        for z in 0..2 {
+            for y in 0..2 {
+                for x in 0..2 {
+                    let child = pos * 2 + glam::uvec3(x, y, z);
+                    let occupied = match level {
+                        0 if child.cmplt(self.dimensions).all() => {
+                            let index = math::to_1d_index(child, self.dimensions);
+                            self.data[index].get_flag() != BrickgridFlag::Empty
+                        }
+                        0 => false,
+                        _ => {
+                            let below = &self.mips[level - 1];
+                            below.data[math::to_1d_index(child, below.dimensions)] != 0
+                        }
+                    };
+                    if occupied {
+                        return true;
+                    }
+                }
             }
+        }
+        false
+    }
 
-            // Index of the brickgrid element, and the value of it
-            upload_data.push(*e as u32);
-            upload_data.push(self.data[*e].0);
+    /// Updates the mip cells above a brick whose occupancy changed, stopping as soon as a
+    /// level doesn't change.
+    fn update_mips(&mut self, grid_pos: glam::UVec3) {
+        let mut pos = grid_pos;
+        for level in 0..self.mips.len() {
+            pos /= 2;
+            let occupied = self.is_occupied(level, pos) as u8;
+            let mip = &mut self.mips[level];
+            let index = math::to_1d_index(pos, mip.dimensions);
+            if mip.data[index] == occupied {
+                break;
+            }
+            mip.data[index] = occupied;
+            mip.mark_dirty(pos);
+        }
+    }
 
-            idx += 1;
-            false
-        });
+    /// Recalculates every mip level from scratch.
+    fn rebuild_mips(&mut self) {
+        for level in 0..self.mips.len() {
+            let dims = self.mips[level].dimensions;
+            for i in 0..self.mips[level].data.len() {
+                let occupied = self.is_occupied(level, math::to_3d_index(i, dims)) as u8;
+                self.mips[level].data[i] = occupied;
+            }
+            self.mips[level].dirty = Some((glam::UVec3::ZERO, dims));
+        }
+    }
+
+    /// Uploads the changed region of each mip level.
+    fn upload_mips(&mut self, context: &Context) {
+        for (level, mip) in self.mips.iter_mut().enumerate() {
+            let Some((min, max)) = mip.dirty.take() else {
+                continue;
+            };
+
+            let size = max - min;
+            let mut data = Vec::with_capacity(size.element_product() as usize);
+            for z in min.z..max.z {
+                for y in min.y..max.y {
+                    let start = math::to_1d_index(glam::uvec3(min.x, y, z), mip.dimensions);
+                    data.extend_from_slice(&mip.data[start..start + size.x as usize]);
+                }
+            }
+
+            context.queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: &self.mip_texture,
+                    mip_level: level as u32,
+                    origin: wgpu::Origin3d {
+                        x: min.x,
+                        y: min.y,
+                        z: min.z,
+                    },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                &data,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(size.x),
+                    rows_per_image: Some(size.y),
+                },
+                wgpu::Extent3d {
+                    width: size.x,
+                    height: size.y,
+                    depth_or_array_layers: size.z,
+                },
+            );
+        }
+    }
+
+    /// Uploads staged entries, coalescing contiguous indices into runs so mass
+    /// invalidations (which tend to hit whole rows of the grid) only pay for the values.
+    pub fn upload(&mut self, context: &Context) {
+        self.upload_mips(context);
+
+        // We have a limit of how many elements to upload each frame. So we need
+        // to keep any excess
+        let indices: Vec<usize> = self
+            .staged
+            .iter()
+            .take(self.max_upload_count)
+            .copied()
+            .collect();
+        for index in &indices {
+            self.staged.remove(index);
+        }
+
+        // Runs are {first brickgrid index, offset of its first value}, the length of a
+        // run is the distance to the next run's offset
+        let mut runs = Vec::new();
+        let mut values = Vec::with_capacity(indices.len());
+        for (i, &index) in indices.iter().enumerate() {
+            if i == 0 || indices[i - 1] + 1 != index {
+                runs.push(index as u32);
+                runs.push(i as u32);
+            }
+            values.push(self.data[index].0);
+        }
+        let run_count = runs.len() / 2;
 
-        // Upload buffer is {max_count, count, pad, pad, bricks[]}. So we need to add
-        // the count and pads, and upload at an offset to skip max_count
-        let data = [&[upload_data.len() as u32, 0, 0], &upload_data[..]].concat();
+        // Upload buffer is {max_count, run_count, value_count, pad, runs[], values[]}. So
+        // we need to add the counts and pad, and upload at an offset to skip max_count
+        let data = [
+            &[run_count as u32, values.len() as u32, 0],
+            &runs[..],
+            &values[..],
+        ]
+        .concat();
         context
             .queue
             .write_buffer(&self.upload_buffer, 4, bytemuck::cast_slice(&data));
 
-        if idx != 0 {
-            log::info!(
-                "Uploading {} brickgrid entries. ({} remaining)",
-                idx,
+        if !indices.is_empty() {
+            log::trace!(
+                "Uploading {} brickgrid entries in {} runs. ({} remaining)",
+                indices.len(),
+                run_count,
                 self.staged.len()
             );
         }
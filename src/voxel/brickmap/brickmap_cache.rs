@@ -1,17 +1,34 @@
-use crate::gfx::{BulkBufferBuilder, Context};
+use crate::gfx::{BufferExt, BulkBufferBuilder, Context};
+
+use super::util;
+
+/// Frames an entry is kept around for after primary rays last passed through it, as long
+/// as there's anything else to replace
+const RECENTLY_VISIBLE_FRAMES: u32 = 60;
 
 #[derive(Debug, Default, Copy, Clone)]
 pub struct BrickmapCacheEntry {
     pub grid_idx: usize,
     pub shading_table_offset: u32,
+    /// Shading table elements the brickmap actually uses, which can be less than its
+    /// allocation holds
+    pub shading_element_count: u32,
+    /// Pinned entries are skipped over when looking for an entry to replace
+    pub pinned: bool,
+    /// Frame the raycast last reported the entry as visible, or when it was loaded
+    pub last_visible: u32,
 }
 
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
-struct Brickmap {
-    bitmask: [u32; 16],
-    shading_table_offset: u32,
-    lod_color: u32,
+pub struct Brickmap {
+    pub bitmask: [u32; 16],
+    pub occupancy: [u32; 2],
+    pub shading_table_offset: u32,
+    pub lod_color: u32,
+    /// Value of the world state's frame counter when the brickmap was loaded, which the
+    /// raycast uses to dither it in over its LOD colour. 0 shows it straight away
+    pub loaded_frame: u32,
 }
 
 #[repr(C)]
@@ -19,6 +36,7 @@ struct Brickmap {
 struct BrickmapUploadElement {
     cache_idx: u32, // TODO: Change to usize?
     brickmap: Brickmap,
+    detail: [u32; 32],
     shading_element_count: u32,
     shading_elements: [u32; 512], // TODO: Replace u32 with custom type?
 }
@@ -28,34 +46,70 @@ pub struct BrickmapCache {
     cache: Vec<Option<BrickmapCacheEntry>>,
     pub index: usize,
     pub num_loaded: u32,
+    /// The world state's frame counter, which visibility is measured against
+    frame: u32,
     staged: Vec<BrickmapUploadElement>,
     max_upload_count: usize,
     buffer: wgpu::Buffer,
+    detail_view: wgpu::TextureView,
     upload_buffer: wgpu::Buffer,
 }
 
 impl BrickmapCache {
+    /// Size in bytes of a single brickmap on the GPU
+    pub const BRICKMAP_SIZE: usize = std::mem::size_of::<Brickmap>();
+    /// Number of brickmaps stored in each row of the detail texture. Each one takes 32
+    /// texels, 2 bits per voxel.
+    pub const DETAIL_ROW_BRICKMAPS: usize = 128;
+    /// Size in bytes of a single brickmap upload element on the GPU
+    pub const UPLOAD_ELEMENT_SIZE: usize = std::mem::size_of::<BrickmapUploadElement>();
+
     pub fn new(context: &Context, size: usize, max_upload_count: usize) -> Self {
         let data = vec![Brickmap::default(); size];
 
         // TODO: change type of upload data. Will need some messyness with bytemucking probably
         // but should lead to clearer data definitions
-        let mut upload_data = vec![0u32; 4 + 532 * max_upload_count];
+        let element_size = Self::UPLOAD_ELEMENT_SIZE / 4;
+        let mut upload_data = vec![0u32; 4 + element_size * max_upload_count];
         upload_data[0] = max_upload_count as u32;
 
         let mut buffers = BulkBufferBuilder::new()
-            .set_usage(wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST)
+            .set_usage(
+                wgpu::BufferUsages::STORAGE
+                    | wgpu::BufferUsages::COPY_DST
+                    | wgpu::BufferUsages::COPY_SRC,
+            )
             .with_init_buffer_bm("Brickmap Cache", &data)
             .with_init_buffer_bm("Brickmap Unpack", &upload_data)
             .build(context);
 
+        // The detail table lives in a texture rather than a buffer as the raycast pass is
+        // already using every storage buffer binding the default limits give us
+        let detail_texture = context.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Brickmap Detail"),
+            size: wgpu::Extent3d {
+                width: (Self::DETAIL_ROW_BRICKMAPS * 32) as u32,
+                height: size.div_ceil(Self::DETAIL_ROW_BRICKMAPS) as u32,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R32Uint,
+            usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+        let detail_view = detail_texture.create_view(&wgpu::TextureViewDescriptor::default());
+
         Self {
             cache: vec![None; size],
             index: 0,
             num_loaded: 0,
+            frame: 0,
             staged: vec![],
             max_upload_count,
             buffer: buffers.remove(0),
+            detail_view,
             upload_buffer: buffers.remove(0),
         }
     }
@@ -64,47 +118,73 @@ impl BrickmapCache {
         &self.buffer
     }
 
+    pub fn get_detail_view(&self) -> &wgpu::TextureView {
+        &self.detail_view
+    }
+
     pub fn get_upload_buffer(&self) -> &wgpu::Buffer {
         &self.upload_buffer
     }
 
+    pub fn get_staged_count(&self) -> usize {
+        self.staged.len()
+    }
+
     /// Adds a brickmap entry and returns the entry that was overwritten.
     pub fn add_entry(
         &mut self,
-        grid_idx: usize,
-        shading_table_offset: u32,
+        entry: BrickmapCacheEntry,
         bitmask: [u32; 16],
-        albedo_data: Vec<u32>,
+        material_data: Vec<u32>,
+        detail: [u32; 32],
+        lod_color: u32,
+        loaded_frame: u32,
     ) -> Option<BrickmapCacheEntry> {
         // We do this first because we want this to be the index of the most recently added entry
         // This has the side effect of meaning that on the first loop through the cache the first
         // entry is empty, but it's fine.
         self.index = (self.index + 1) % self.cache.len();
 
+        // Pinned entries can't be replaced, so skip past them. Ones in view are skipped
+        // too unless that's all there is, as they'd only get requested again straight away
+        let start = self.index;
+        let found = [true, false].into_iter().find_map(|skip_visible| {
+            (0..self.cache.len())
+                .map(|offset| (start + offset) % self.cache.len())
+                .find(|&index| self.is_replaceable(self.cache[index], skip_visible))
+        });
+        self.index = found.unwrap_or(start);
+        if self.cache[self.index].is_some_and(|entry| entry.pinned) {
+            log::warn!(
+                "Every brickmap cache entry is pinned, replacing pinned entry {}",
+                self.index
+            );
+        }
+
         let existing_entry = self.cache[self.index];
         if existing_entry.is_none() {
             self.num_loaded += 1;
         }
 
-        self.cache[self.index] = Some(BrickmapCacheEntry {
-            grid_idx,
-            shading_table_offset,
-        });
+        self.cache[self.index] = Some(entry);
 
         // Need to stage this entry
         let brickmap = Brickmap {
             bitmask,
-            shading_table_offset,
-            lod_color: 0,
+            occupancy: util::coarse_occupancy(&bitmask),
+            shading_table_offset: entry.shading_table_offset,
+            lod_color,
+            loaded_frame,
         };
 
-        let shading_element_count = albedo_data.len();
+        let shading_element_count = material_data.len();
         let mut shading_elements = [0u32; 512];
-        shading_elements[..shading_element_count].copy_from_slice(&albedo_data);
+        shading_elements[..shading_element_count].copy_from_slice(&material_data);
 
         let staged_brickmap = BrickmapUploadElement {
             cache_idx: self.index as u32,
             brickmap,
+            detail,
             shading_element_count: shading_element_count as u32,
             shading_elements,
         };
@@ -113,6 +193,101 @@ impl BrickmapCache {
         existing_entry
     }
 
+    /// Stages new data for an entry that's already loaded, keeping its cache slot. The
+    /// material data has to fit in the shading table allocation at `shading_table_offset`,
+    /// which can be the entry's current one or somewhere it's being moved to.
+    pub fn update_entry(
+        &mut self,
+        index: usize,
+        shading_table_offset: u32,
+        bitmask: [u32; 16],
+        material_data: Vec<u32>,
+        detail: [u32; 32],
+        lod_color: u32,
+    ) {
+        let Some(entry) = &mut self.cache[index] else {
+            log::error!(
+                "Tried to update brickmap cache entry {} which isn't loaded",
+                index
+            );
+            return;
+        };
+        entry.shading_table_offset = shading_table_offset;
+        entry.shading_element_count = material_data.len() as u32;
+
+        // Edited bricks are already on screen, so they show up straight away rather than
+        // dithering in
+        let brickmap = Brickmap {
+            bitmask,
+            occupancy: util::coarse_occupancy(&bitmask),
+            shading_table_offset,
+            lod_color,
+            loaded_frame: 0,
+        };
+
+        let shading_element_count = material_data.len();
+        let mut shading_elements = [0u32; 512];
+        shading_elements[..shading_element_count].copy_from_slice(&material_data);
+
+        // The latest upload of this entry that hasn't gone out yet would only be
+        // overwritten by this one, so it's replaced rather than uploaded twice
+        let element = BrickmapUploadElement {
+            cache_idx: index as u32,
+            brickmap,
+            detail,
+            shading_element_count: shading_element_count as u32,
+            shading_elements,
+        };
+        match self
+            .staged
+            .iter_mut()
+            .rev()
+            .find(|staged| staged.cache_idx == index as u32)
+        {
+            Some(staged) => *staged = element,
+            None => self.staged.push(element),
+        }
+    }
+
+    fn is_replaceable(&self, entry: Option<BrickmapCacheEntry>, keep_visible: bool) -> bool {
+        match entry {
+            Some(entry) => {
+                let visible = self.frame.wrapping_sub(entry.last_visible) < RECENTLY_VISIBLE_FRAMES;
+                !(entry.pinned || keep_visible && visible)
+            }
+            None => true,
+        }
+    }
+
+    /// Sets the frame visibility is measured against. Call whenever the frame advances.
+    pub fn set_frame(&mut self, frame: u32) {
+        self.frame = frame;
+    }
+
+    /// Marks the entries set in a visibility bitset read back from the raycast as seen
+    /// this frame. Entries replaced since the raycast ran get marked too, which is no
+    /// worse than them having just been loaded.
+    pub fn mark_visible(&mut self, visible: &[u32]) {
+        for (word_idx, word) in visible.iter().enumerate() {
+            let mut bits = *word;
+            while bits != 0 {
+                let index = word_idx * 32 + bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                if let Some(Some(entry)) = self.cache.get_mut(index) {
+                    entry.last_visible = self.frame;
+                }
+            }
+        }
+    }
+
+    /// How many entries could be replaced without evicting anything pinned or in view.
+    pub fn get_evictable_count(&self) -> usize {
+        self.cache
+            .iter()
+            .filter(|entry| self.is_replaceable(**entry, true))
+            .count()
+    }
+
     /// Remove an entry from the cache and return it
     pub fn remove_entry(&mut self, index: usize) -> Option<BrickmapCacheEntry> {
         let entry = self.cache[index];
@@ -124,10 +299,45 @@ impl BrickmapCache {
         entry
     }
 
+    /// Empties the cache and drops anything waiting to be uploaded. The GPU side doesn't
+    /// need clearing as nothing in a reset brickgrid points at it.
+    pub fn reset(&mut self, context: &Context) {
+        self.cache.fill(None);
+        self.index = 0;
+        self.num_loaded = 0;
+        self.staged.clear();
+        context
+            .queue
+            .write_buffer(&self.upload_buffer, 4, bytemuck::cast_slice(&[0u32]));
+    }
+
+    /// Reads a brickmap back from the GPU, for debugging.
+    pub fn read_back(&self, context: &Context, index: usize) -> Brickmap {
+        let offset = (index * Self::BRICKMAP_SIZE) as u64;
+        self.buffer
+            .read_back(context, offset, Self::BRICKMAP_SIZE as u64)[0]
+    }
+
+    /// Number of entries the cache has room for.
+    pub fn get_size(&self) -> usize {
+        self.cache.len()
+    }
+
     pub fn get_entry(&self, index: usize) -> Option<BrickmapCacheEntry> {
         self.cache[index]
     }
 
+    /// Pins or unpins a loaded entry. Does nothing if the entry isn't loaded.
+    pub fn set_pinned(&mut self, index: usize, pinned: bool) {
+        if let Some(entry) = &mut self.cache[index] {
+            entry.pinned = pinned;
+        }
+    }
+
+    pub fn get_pinned_count(&self) -> usize {
+        self.cache.iter().flatten().filter(|e| e.pinned).count()
+    }
+
     pub fn upload(&mut self, context: &Context) {
         // Takes up to max_upload_count upload elements
         let count = usize::min(self.max_upload_count, self.staged.len());
@@ -145,7 +355,7 @@ impl BrickmapCache {
         drop(iter);
 
         if count > 0 {
-            log::info!(
+            log::trace!(
                 "Uploading {} brickmap entries. ({} remaining)",
                 count,
                 self.staged.len()
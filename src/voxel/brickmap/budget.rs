@@ -0,0 +1,210 @@
+use std::{fs, path::Path};
+
+use anyhow::{Context as _, Result};
+
+use crate::gfx::Context;
+
+use super::brickmap_cache::BrickmapCache;
+
+/// Sizes of the brickmap buffers. These are picked to fit the GPU on first run and saved
+/// to disk, so they can be tweaked by hand afterwards.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BrickmapBudget {
+    pub brickgrid_dims: glam::UVec3,
+    pub brickmap_cache_size: usize,
+    pub shading_table_bucket_size: u32,
+    pub max_requested_brickmaps: u32,
+    pub max_uploaded_brickmaps: u32,
+}
+
+impl BrickmapBudget {
+    /// Loads the budget saved for the current adapter, or tunes and saves a new one.
+    pub fn load_or_tune(context: &Context, path: &Path) -> Self {
+        let adapter_name = context.adapter.get_info().name;
+        if let Some(budget) = Self::load(path, &adapter_name) {
+            log::info!("Loaded brickmap budget from {}", path.display());
+            return budget;
+        }
+
+        let budget = Self::auto_tune(context);
+        budget.save(context, path);
+        budget
+    }
+
+    /// Saves the budget for the current adapter. Failing to save isn't fatal, we'll just
+    /// have to tune again next time.
+    pub fn save(&self, context: &Context, path: &Path) {
+        let adapter_name = context.adapter.get_info().name;
+        match self.write(path, &adapter_name) {
+            Ok(_) => log::info!("Saved brickmap budget to {}", path.display()),
+            Err(e) => log::warn!("Failed to save brickmap budget: {}", e),
+        }
+    }
+
+    /// Halves the memory hungry parts of the budget, for when the GPU can't fit what we
+    /// asked for. Returns `None` once there's nothing sensible left to shrink.
+    pub fn shrink(&self) -> Option<Self> {
+        if self.shading_table_bucket_size <= 1 << 16 {
+            return None;
+        }
+
+        Some(Self {
+            brickmap_cache_size: self.brickmap_cache_size / 2,
+            shading_table_bucket_size: self.shading_table_bucket_size / 2,
+            max_requested_brickmaps: (self.max_requested_brickmaps / 2).max(128),
+            max_uploaded_brickmaps: (self.max_uploaded_brickmaps / 2).max(256),
+            ..*self
+        })
+    }
+
+    /// Picks buffer sizes based on the device limits and how much memory we think we have.
+    pub fn auto_tune(context: &Context) -> Self {
+        let limits = context.device.limits();
+        let info = context.adapter.get_info();
+        let max_binding = u64::min(
+            limits.max_storage_buffer_binding_size as u64,
+            limits.max_buffer_size,
+        );
+
+        // wgpu can't tell us how much VRAM there is. Integrated GPUs share system memory
+        // so we can at least take a fraction of what's free there, otherwise we guess.
+        let memory_budget = match info.device_type {
+            wgpu::DeviceType::IntegratedGpu | wgpu::DeviceType::Cpu => {
+                available_system_memory().map_or(512 << 20, |m| m / 4)
+            }
+            _ => 2 << 30,
+        };
+        log::info!(
+            "Tuning brickmap budget for {} ({:?}): max binding {}MiB, memory budget {}MiB",
+            info.name,
+            info.device_type,
+            max_binding >> 20,
+            memory_budget >> 20
+        );
+
+        // The shading table is by far the largest buffer, so it gets half the budget. It
+        // has 4 buckets of u32 elements.
+        let shading_bytes = u64::min(max_binding, memory_budget / 2);
+        let shading_table_bucket_size = prev_power_of_two(shading_bytes / 16).min(1 << 26) as u32;
+
+        // Brickgrid elements are a single u32
+        let grid_bytes = u64::min(max_binding, memory_budget / 8);
+        let mut brickgrid_dims = glam::uvec3(512, 64, 512);
+        while brickgrid_dims.x > 32 && (brickgrid_dims.element_product() as u64) * 4 > grid_bytes {
+            brickgrid_dims.x /= 2;
+            brickgrid_dims.z /= 2;
+        }
+
+        // There's no point having more brickmaps than the shading table can hold, assuming
+        // an average of 128 surface voxels per brickmap
+        let brickmap_cache_size = usize::min(
+            usize::pow(64, 3),
+            usize::min(
+                (4 * shading_table_bucket_size / 128) as usize,
+                (max_binding / BrickmapCache::BRICKMAP_SIZE as u64) as usize,
+            )
+            .min(limits.max_texture_dimension_2d as usize * BrickmapCache::DETAIL_ROW_BRICKMAPS),
+        );
+
+        let upload_bytes = u64::min(max_binding, memory_budget / 64);
+        let max_uploaded_brickmaps =
+            prev_power_of_two(upload_bytes / BrickmapCache::UPLOAD_ELEMENT_SIZE as u64)
+                .clamp(256, 8192) as u32;
+        let max_requested_brickmaps = max_uploaded_brickmaps / 2;
+
+        let budget = Self {
+            brickgrid_dims,
+            brickmap_cache_size,
+            shading_table_bucket_size,
+            max_requested_brickmaps,
+            max_uploaded_brickmaps,
+        };
+        log::info!("Tuned brickmap budget: {:?}", budget);
+        budget
+    }
+
+    fn load(path: &Path, adapter_name: &str) -> Option<Self> {
+        let contents = fs::read_to_string(path).ok()?;
+        let mut adapter = None;
+        let mut brickgrid_dims = None;
+        let mut brickmap_cache_size = None;
+        let mut shading_table_bucket_size = None;
+        let mut max_requested_brickmaps = None;
+        let mut max_uploaded_brickmaps = None;
+        for line in contents.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+
+            let value = value.trim();
+            match key.trim() {
+                "adapter" => adapter = Some(value.trim_matches('"').to_owned()),
+                "brickgrid_dims" => {
+                    let dims: Vec<u32> = value
+                        .trim_matches(|c| c == '[' || c == ']')
+                        .split(',')
+                        .filter_map(|v| v.trim().parse().ok())
+                        .collect();
+                    if dims.len() == 3 {
+                        brickgrid_dims = Some(glam::uvec3(dims[0], dims[1], dims[2]));
+                    }
+                }
+                "brickmap_cache_size" => brickmap_cache_size = value.parse().ok(),
+                "shading_table_bucket_size" => shading_table_bucket_size = value.parse().ok(),
+                "max_requested_brickmaps" => max_requested_brickmaps = value.parse().ok(),
+                "max_uploaded_brickmaps" => max_uploaded_brickmaps = value.parse().ok(),
+                _ => (),
+            }
+        }
+
+        // A budget tuned for a different GPU isn't any use to us
+        if adapter? != adapter_name {
+            return None;
+        }
+
+        Some(Self {
+            brickgrid_dims: brickgrid_dims?,
+            brickmap_cache_size: brickmap_cache_size?,
+            shading_table_bucket_size: shading_table_bucket_size?,
+            max_requested_brickmaps: max_requested_brickmaps?,
+            max_uploaded_brickmaps: max_uploaded_brickmaps?,
+        })
+    }
+
+    fn write(&self, path: &Path, adapter_name: &str) -> Result<()> {
+        let dims = self.brickgrid_dims;
+        let contents = format!(
+            "# Automatically tuned brickmap buffer sizes. Delete this file to re-tune.\n\
+             adapter = \"{}\"\n\
+             brickgrid_dims = [{}, {}, {}]\n\
+             brickmap_cache_size = {}\n\
+             shading_table_bucket_size = {}\n\
+             max_requested_brickmaps = {}\n\
+             max_uploaded_brickmaps = {}\n",
+            adapter_name,
+            dims.x,
+            dims.y,
+            dims.z,
+            self.brickmap_cache_size,
+            self.shading_table_bucket_size,
+            self.max_requested_brickmaps,
+            self.max_uploaded_brickmaps,
+        );
+        fs::write(path, contents).context("Failed to write brickmap budget")
+    }
+}
+
+fn prev_power_of_two(value: u64) -> u64 {
+    if value == 0 {
+        return 0;
+    }
+    1 << (63 - value.leading_zeros())
+}
+
+/// Free system memory in bytes, where we know how to find it.
+fn available_system_memory() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
@@ -0,0 +1,256 @@
+use anyhow::Result;
+
+use crate::{
+    gfx::{self, BulkBufferBuilder, Context},
+    voxel::world::WorldManager,
+};
+
+use super::util;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct LineVertex {
+    position: [f32; 3],
+    color: [u8; 4],
+}
+
+const CHUNK_COLOR: [u8; 4] = [255, 220, 40, 255];
+const BRICK_COLOR: [u8; 4] = [40, 200, 255, 255];
+
+/// Draws chunk borders and brick boundaries around a point as 3D lines over the raycast
+/// image, hidden behind voxels using the raycast's depth. Chunk borders are found with
+/// the same grid to world conversion the brickmap loading uses, so any mistakes in it
+/// show up as lines in the wrong place.
+#[derive(Debug)]
+pub struct DebugLines {
+    pipeline: wgpu::RenderPipeline,
+    layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    vertex_buffer: wgpu::Buffer,
+    max_vertices: usize,
+    vertex_count: u32,
+    center: Option<glam::IVec3>,
+}
+
+impl DebugLines {
+    /// How far around the center brick boundaries are drawn, in bricks
+    const BRICK_RADIUS: i32 = 4;
+    /// How far around the center chunk borders are drawn, in bricks
+    const CHUNK_RADIUS: i32 = 64;
+
+    pub fn new(
+        context: &Context,
+        camera_buffer: &wgpu::Buffer,
+        depth_view: &wgpu::TextureView,
+    ) -> Result<Self> {
+        // Every brick edge in the brick region, plus a generous number of chunk edges
+        let brick_lattice = (2 * Self::BRICK_RADIUS + 1) as usize;
+        let max_vertices = 2 * 3 * brick_lattice * brick_lattice + 2 * 3 * 16 * 16;
+        let vertex_buffer = BulkBufferBuilder::new()
+            .set_usage(wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST)
+            .with_buffer(
+                "Debug Lines",
+                (max_vertices * std::mem::size_of::<LineVertex>()) as u64,
+                false,
+            )
+            .build(context)
+            .remove(0);
+
+        let layout = gfx::BindGroupLayoutBuilder::new()
+            .with_label("Debug Lines BGL")
+            .with_uniform_entry(wgpu::ShaderStages::VERTEX_FRAGMENT)
+            .with_entry(
+                wgpu::ShaderStages::FRAGMENT,
+                wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                None,
+            )
+            .build(context);
+        let bind_group = Self::create_bind_group(context, &layout, camera_buffer, depth_view)?;
+
+        // TODO: Load the shader better
+        let shader_descriptor = wgpu::include_wgsl!("../../../assets/shaders/debug_lines.wgsl");
+        let shader = context.device.create_shader_module(shader_descriptor);
+        let pipeline = context
+            .device
+            .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("Debug Lines Pipeline"),
+                layout: Some(&context.device.create_pipeline_layout(
+                    &wgpu::PipelineLayoutDescriptor {
+                        label: Some("Debug Lines PL"),
+                        bind_group_layouts: &[&layout],
+                        push_constant_ranges: &[],
+                    },
+                )),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vertex",
+                    buffers: &[wgpu::VertexBufferLayout {
+                        array_stride: std::mem::size_of::<LineVertex>() as u64,
+                        step_mode: wgpu::VertexStepMode::Vertex,
+                        attributes: &wgpu::vertex_attr_array![
+                            0 => Float32x3,
+                            1 => Unorm8x4
+                        ],
+                    }],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fragment",
+                    targets: &[Some(context.surface_config.format.into())],
+                }),
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::LineList,
+                    ..Default::default()
+                },
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            });
+
+        Ok(Self {
+            pipeline,
+            layout,
+            bind_group,
+            vertex_buffer,
+            max_vertices,
+            vertex_count: 0,
+            center: None,
+        })
+    }
+
+    fn create_bind_group(
+        context: &Context,
+        layout: &wgpu::BindGroupLayout,
+        camera_buffer: &wgpu::Buffer,
+        depth_view: &wgpu::TextureView,
+    ) -> Result<wgpu::BindGroup> {
+        gfx::BindGroupBuilder::new()
+            .with_label("Debug Lines BG")
+            .with_layout(layout)
+            .with_entry(camera_buffer.as_entire_binding())
+            .with_entry(wgpu::BindingResource::TextureView(depth_view))
+            .build(context)
+    }
+
+    /// Points the lines at a new depth texture, after the screen has been resized.
+    pub fn set_depth_view(
+        &mut self,
+        context: &Context,
+        camera_buffer: &wgpu::Buffer,
+        depth_view: &wgpu::TextureView,
+    ) -> Result<()> {
+        self.bind_group =
+            Self::create_bind_group(context, &self.layout, camera_buffer, depth_view)?;
+        Ok(())
+    }
+
+    /// Rebuilds the lines if the brick `position` (in brick units) is in has changed.
+    pub fn update(&mut self, context: &Context, world: &mut WorldManager, position: glam::Vec3) {
+        let center = position.floor().as_ivec3();
+        if self.center == Some(center) {
+            return;
+        }
+        self.center = Some(center);
+
+        let mut vertices = Vec::new();
+        let radius = glam::IVec3::splat(Self::BRICK_RADIUS);
+        add_lattice(
+            &mut vertices,
+            center - radius,
+            center + radius,
+            &[
+                (center.x - radius.x..=center.x + radius.x).collect(),
+                (center.y - radius.y..=center.y + radius.y).collect(),
+                (center.z - radius.z..=center.z + radius.z).collect(),
+            ],
+            BRICK_COLOR,
+        );
+
+        // A chunk border is wherever neighbouring bricks end up in different chunks
+        let radius = glam::IVec3::splat(Self::CHUNK_RADIUS);
+        let min = center - radius;
+        let max = center + radius;
+        let mut borders: [Vec<i32>; 3] = Default::default();
+        for (axis, axis_borders) in borders.iter_mut().enumerate() {
+            for i in min[axis]..=max[axis] {
+                let mut pos = center;
+                pos[axis] = i;
+                let mut prev = pos;
+                prev[axis] -= 1;
+                let chunk = util::grid_pos_to_world_pos(world, pos).0;
+                let prev_chunk = util::grid_pos_to_world_pos(world, prev).0;
+                if chunk[axis] != prev_chunk[axis] {
+                    axis_borders.push(i);
+                }
+            }
+        }
+        add_lattice(&mut vertices, min, max, &borders, CHUNK_COLOR);
+
+        vertices.truncate(self.max_vertices);
+        self.vertex_count = vertices.len() as u32;
+        context
+            .queue
+            .write_buffer(&self.vertex_buffer, 0, bytemuck::cast_slice(&vertices));
+    }
+
+    pub fn encode(&self, encoder: &mut wgpu::CommandEncoder, view: &wgpu::TextureView) {
+        if self.vertex_count == 0 {
+            return;
+        }
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("debug lines"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            ..Default::default()
+        });
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.draw(0..self.vertex_count, 0..1);
+    }
+}
+
+/// Adds lines along each axis through every pair of coordinates on the other two axes,
+/// spanning `min..=max`.
+fn add_lattice(
+    vertices: &mut Vec<LineVertex>,
+    min: glam::IVec3,
+    max: glam::IVec3,
+    coords: &[Vec<i32>; 3],
+    color: [u8; 4],
+) {
+    for axis in 0..3 {
+        let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+        for &i in &coords[a] {
+            for &j in &coords[b] {
+                let mut start = glam::Vec3::ZERO;
+                start[axis] = min[axis] as f32;
+                start[a] = i as f32;
+                start[b] = j as f32;
+                let mut end = start;
+                end[axis] = max[axis] as f32;
+
+                vertices.push(LineVertex {
+                    position: start.to_array(),
+                    color,
+                });
+                vertices.push(LineVertex {
+                    position: end.to_array(),
+                    color,
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use crate::gfx::{BulkBufferBuilder, Context};
+
+/// A coloured patch drawn over the faces of every voxel in a box, without touching the
+/// voxels themselves. Bounds are in world voxel space, `min..max`.
+#[derive(Debug, Clone, Copy)]
+pub struct Decal {
+    pub min: glam::IVec3,
+    pub max: glam::IVec3,
+    /// RGBA, alpha is how strongly it's blended over the voxel colour
+    pub color: [u8; 4],
+    /// Only faces pointing this way get the decal, or all of them if `None`
+    pub normal: Option<glam::IVec3>,
+}
+
+impl Decal {
+    /// One bit per face, in the order +X, -X, +Y, -Y, +Z, -Z
+    fn get_face_mask(&self) -> u32 {
+        let Some(normal) = self.normal else {
+            return 0x3F;
+        };
+
+        let mut mask = 0;
+        for axis in 0..3 {
+            if normal[axis] > 0 {
+                mask |= 1 << (axis * 2);
+            } else if normal[axis] < 0 {
+                mask |= 1 << (axis * 2 + 1);
+            }
+        }
+        mask
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct DecalElement {
+    min: [i32; 3],
+    color: u32,
+    max: [i32; 3],
+    face_mask: u32,
+}
+
+impl From<Decal> for DecalElement {
+    fn from(value: Decal) -> Self {
+        Self {
+            min: value.min.to_array(),
+            color: u32::from_be_bytes(value.color),
+            max: value.max.to_array(),
+            face_mask: value.get_face_mask(),
+        }
+    }
+}
+
+/// Owns the decals in the scene. Like portals, they all live in a single uniform buffer,
+/// {count, pad, pad, pad, decals[]}, and are applied in order while shading.
+#[derive(Debug)]
+pub struct DecalManager {
+    decals: Vec<Decal>,
+    dirty: bool,
+    buffer: wgpu::Buffer,
+}
+
+impl DecalManager {
+    /// Must match the decal array size in the raycast shader
+    pub const MAX_DECALS: usize = 64;
+
+    pub fn new(context: &Context) -> Self {
+        let size = 16 + Self::MAX_DECALS * std::mem::size_of::<DecalElement>();
+        let mut buffers = BulkBufferBuilder::new()
+            .set_usage(wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST)
+            .with_init_buffer("Decals", &vec![0u8; size])
+            .build(context);
+
+        Self {
+            decals: vec![],
+            dirty: false,
+            buffer: buffers.remove(0),
+        }
+    }
+
+    pub fn get_buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    pub fn get_decals(&self) -> &[Decal] {
+        &self.decals
+    }
+
+    /// Adds a decal and returns its index, or `None` if there's no space left.
+    pub fn add_decal(&mut self, decal: Decal) -> Option<usize> {
+        if self.decals.len() >= Self::MAX_DECALS {
+            return None;
+        }
+
+        self.decals.push(decal);
+        self.dirty = true;
+        Some(self.decals.len() - 1)
+    }
+
+    /// Replaces a decal, handy for things like edit previews that follow the cursor.
+    /// Panics if index out of range
+    pub fn set_decal(&mut self, index: usize, decal: Decal) {
+        self.decals[index] = decal;
+        self.dirty = true;
+    }
+
+    /// Removes a decal, moving the last decal into its index.
+    /// Panics if index out of range
+    pub fn remove_decal(&mut self, index: usize) -> Decal {
+        self.dirty = true;
+        self.decals.swap_remove(index)
+    }
+
+    pub fn clear(&mut self) {
+        self.decals.clear();
+        self.dirty = true;
+    }
+
+    pub fn update(&mut self, context: &Context) {
+        if !self.dirty {
+            return;
+        }
+
+        let data: Vec<DecalElement> = self.decals.iter().map(|d| (*d).into()).collect();
+        let count = [self.decals.len() as u32, 0, 0, 0];
+        context
+            .queue
+            .write_buffer(&self.buffer, 0, bytemuck::cast_slice(&count));
+        if !data.is_empty() {
+            context
+                .queue
+                .write_buffer(&self.buffer, 16, bytemuck::cast_slice(&data));
+        }
+        self.dirty = false;
+    }
+}
@@ -0,0 +1,139 @@
+use std::fmt::{self, Write};
+
+use super::{
+    brickgrid::{BrickgridElement, BrickgridFlag},
+    brickmap_cache::{Brickmap, BrickmapCacheEntry},
+};
+
+/// Everything the CPU and GPU know about a single brickgrid cell, captured for offline
+/// inspection of bricks that render incorrectly. Formats as a readable report.
+#[derive(Debug, Clone)]
+pub struct BrickDump {
+    /// In world brick space
+    pub brick_pos: glam::IVec3,
+    pub grid_idx: usize,
+    pub pinned: bool,
+    pub waiting: bool,
+    pub cpu_element: BrickgridElement,
+    pub gpu_element: BrickgridElement,
+    /// GPU elements of the 3x3x3 cells around this one, `None` outside the grid
+    pub neighbourhood: Vec<(glam::IVec3, Option<BrickgridElement>)>,
+    /// How many GPU cells have each flag, and how many disagree with the CPU
+    pub flag_counts: Vec<(String, usize)>,
+    pub mismatched_cells: usize,
+    pub cache_entry: Option<BrickmapCacheEntry>,
+    pub brickmap: Option<Brickmap>,
+    pub shading: Vec<u32>,
+}
+
+fn describe(element: BrickgridElement) -> String {
+    let raw = element.0;
+    match raw & 0xF {
+        // The loading bit only ever gets set on the GPU
+        3 => format!("{:08x} Unloaded+Loading", raw),
+        7 => format!("{:08x} Lod+Loading #{:06x}", raw, raw >> 8),
+        _ => match element.get_flag() {
+            BrickgridFlag::Loaded => format!("{:08x} Loaded -> brickmap {}", raw, raw >> 8),
+            BrickgridFlag::Uniform => format!("{:08x} Uniform material {}", raw, raw >> 8),
+            BrickgridFlag::Lod => format!("{:08x} Lod #{:06x}", raw, raw >> 8),
+            flag => format!("{:08x} {:?}", raw, flag),
+        },
+    }
+}
+
+impl fmt::Display for BrickDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Brick {} (grid index {})", self.brick_pos, self.grid_idx)?;
+        writeln!(
+            f,
+            "Pinned: {}, waiting on generation: {}",
+            self.pinned, self.waiting
+        )?;
+        writeln!(f, "CPU element: {}", describe(self.cpu_element))?;
+        writeln!(f, "GPU element: {}", describe(self.gpu_element))?;
+
+        writeln!(f, "\nBrickgrid")?;
+        for (flag, count) in &self.flag_counts {
+            writeln!(f, "  {}: {}", flag, count)?;
+        }
+        writeln!(
+            f,
+            "  Cells where CPU and GPU disagree: {}",
+            self.mismatched_cells
+        )?;
+
+        writeln!(f, "\nNeighbourhood")?;
+        for (pos, element) in &self.neighbourhood {
+            match element {
+                Some(element) => writeln!(f, "  {}: {}", pos, describe(*element))?,
+                None => writeln!(f, "  {}: outside of grid", pos)?,
+            }
+        }
+
+        writeln!(f, "\nBrickmap cache entry")?;
+        match &self.cache_entry {
+            Some(entry) => {
+                writeln!(
+                    f,
+                    "  Grid index {}, shading table offset {}, pinned {}, last visible on frame {}",
+                    entry.grid_idx, entry.shading_table_offset, entry.pinned, entry.last_visible
+                )?;
+                if entry.grid_idx != self.grid_idx {
+                    writeln!(f, "  MISMATCH: entry belongs to a different cell")?;
+                }
+            }
+            None => writeln!(f, "  None")?,
+        }
+
+        let Some(brickmap) = &self.brickmap else {
+            return Ok(());
+        };
+        writeln!(f, "\nGPU brickmap")?;
+        writeln!(
+            f,
+            "  Shading table offset {}, LOD colour {:08x}, loaded on frame {}",
+            brickmap.shading_table_offset, brickmap.lod_color, brickmap.loaded_frame
+        )?;
+        if let Some(entry) = &self.cache_entry {
+            if entry.shading_table_offset != brickmap.shading_table_offset {
+                writeln!(f, "  MISMATCH: CPU and GPU shading table offsets differ")?;
+            }
+        }
+        writeln!(
+            f,
+            "  Occupancy {:08x} {:08x}",
+            brickmap.occupancy[0], brickmap.occupancy[1]
+        )?;
+
+        // One 8x8 slice per z, y going up the page like the world does
+        writeln!(f, "  Bitmask (# solid, . empty)")?;
+        for z in 0..8 {
+            writeln!(f, "  z = {}", z)?;
+            for y in (0..8).rev() {
+                let mut row = String::from("    ");
+                for x in 0..8 {
+                    let idx = x + y * 8 + z * 64;
+                    let solid = (brickmap.bitmask[idx / 32] >> (idx % 32)) & 1 == 1;
+                    row.push(if solid { '#' } else { '.' });
+                }
+                writeln!(f, "{}", row)?;
+            }
+        }
+
+        writeln!(
+            f,
+            "\nShading table slice ({} material ids and corner AO)",
+            self.shading.len()
+        )?;
+        let mut line = String::new();
+        for (i, element) in self.shading.iter().enumerate() {
+            write!(line, " {:5}/{:04x}", element & 0xFFFF, element >> 16)?;
+            if i % 8 == 7 || i == self.shading.len() - 1 {
+                writeln!(f, " {:5}:{}", i - i % 8, line)?;
+                line.clear();
+            }
+        }
+
+        Ok(())
+    }
+}
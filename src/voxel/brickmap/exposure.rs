@@ -0,0 +1,271 @@
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::gfx::{self, BulkBufferBuilder, Context};
+
+/// Range of luminances the histogram covers, as powers of two. Anything outside lands
+/// in the first or last bin.
+const MIN_LOG_LUMINANCE: f32 = -10.0;
+const MAX_LOG_LUMINANCE: f32 = 6.0;
+/// Must match the bin count in the exposure shader
+const HISTOGRAM_BINS: u64 = 256;
+
+/// How the raycast image gets brightened or darkened before it's shown.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Exposure {
+    /// A fixed multiplier on the image
+    Manual(f32),
+    /// Measure the image's average luminance every frame and ease the exposure towards
+    /// whatever brings it to `target`, never leaving `min..=max`.
+    Auto {
+        min: f32,
+        max: f32,
+        target: f32,
+        /// How quickly the exposure catches up, roughly the fraction of the way it moves
+        /// per second
+        speed: f32,
+    },
+}
+
+impl Default for Exposure {
+    fn default() -> Self {
+        Self::Manual(1.0)
+    }
+}
+
+impl Exposure {
+    /// Auto exposure that copes with both daylight and unlit caves.
+    pub fn auto() -> Self {
+        Self::Auto {
+            min: 0.25,
+            max: 8.0,
+            target: 0.35,
+            speed: 1.5,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct ExposureParams {
+    min_log_luminance: f32,
+    log_luminance_range: f32,
+    target_luminance: f32,
+    min_exposure: f32,
+    max_exposure: f32,
+    adaptation: f32,
+    _pad: [f32; 2],
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct ExposureState {
+    exposure: f32,
+    average_luminance: f32,
+}
+
+/// Keeps the image's brightness in a watchable range. A compute pass bins the raycast
+/// image's pixels by log luminance and a second one averages the bins and moves the
+/// exposure towards its target, all on the GPU so nothing has to be read back. The blit
+/// multiplies the image by the result.
+///
+/// Manual exposure skips both passes and writes the exposure straight to the buffer.
+#[derive(Debug)]
+pub struct AutoExposure {
+    histogram_pipeline: wgpu::ComputePipeline,
+    adapt_pipeline: wgpu::ComputePipeline,
+    layout: wgpu::BindGroupLayout,
+    /// One per render texture
+    bind_groups: [wgpu::BindGroup; 2],
+    params_buffer: wgpu::Buffer,
+    histogram_buffer: wgpu::Buffer,
+    exposure_buffer: wgpu::Buffer,
+    /// The manual exposure last written to the buffer, if it's in manual mode
+    manual: Option<f32>,
+}
+
+impl AutoExposure {
+    pub fn new(context: &Context, render_textures: &[gfx::Texture; 2]) -> Result<Self> {
+        let state = ExposureState {
+            exposure: 1.0,
+            average_luminance: 0.0,
+        };
+        let mut buffers = BulkBufferBuilder::new()
+            .set_usage(wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST)
+            .with_init_buffer_bm("Exposure Params", &[ExposureParams::default()])
+            .set_usage(wgpu::BufferUsages::STORAGE)
+            .with_buffer("Luminance Histogram", HISTOGRAM_BINS * 4, false)
+            .set_usage(wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST)
+            .with_init_buffer_bm("Exposure", &[state])
+            .build(context);
+        let params_buffer = buffers.remove(0);
+        let histogram_buffer = buffers.remove(0);
+        let exposure_buffer = buffers.remove(0);
+
+        // TODO: Load the shader better
+        let shader_descriptor = wgpu::include_wgsl!("../../../assets/shaders/exposure.wgsl");
+        let shader = context.device.create_shader_module(shader_descriptor);
+
+        let layout = gfx::BindGroupLayoutBuilder::new()
+            .with_label("Exposure BGL")
+            .with_uniform_entry(wgpu::ShaderStages::COMPUTE)
+            .with_rw_storage_entry(wgpu::ShaderStages::COMPUTE)
+            .with_rw_storage_entry(wgpu::ShaderStages::COMPUTE)
+            .with_entry(
+                wgpu::ShaderStages::COMPUTE,
+                wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                None,
+            )
+            .build(context);
+        let pipeline_layout =
+            context
+                .device
+                .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: Some("Exposure PL"),
+                    bind_group_layouts: &[&layout],
+                    push_constant_ranges: &[],
+                });
+        let create_pipeline = |label, entry_point| {
+            context
+                .device
+                .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                    label: Some(label),
+                    layout: Some(&pipeline_layout),
+                    module: &shader,
+                    entry_point,
+                })
+        };
+        let histogram_pipeline = create_pipeline("Luminance Histogram Pipeline", "build_histogram");
+        let adapt_pipeline = create_pipeline("Exposure Adapt Pipeline", "adapt");
+
+        let bind_groups = Self::create_bind_groups(
+            context,
+            &layout,
+            &params_buffer,
+            &histogram_buffer,
+            &exposure_buffer,
+            render_textures,
+        )?;
+
+        Ok(Self {
+            histogram_pipeline,
+            adapt_pipeline,
+            layout,
+            bind_groups,
+            params_buffer,
+            histogram_buffer,
+            exposure_buffer,
+            manual: None,
+        })
+    }
+
+    fn create_bind_groups(
+        context: &Context,
+        layout: &wgpu::BindGroupLayout,
+        params_buffer: &wgpu::Buffer,
+        histogram_buffer: &wgpu::Buffer,
+        exposure_buffer: &wgpu::Buffer,
+        render_textures: &[gfx::Texture; 2],
+    ) -> Result<[wgpu::BindGroup; 2]> {
+        let create = |texture: &gfx::Texture| {
+            gfx::BindGroupBuilder::new()
+                .with_label("Exposure BG")
+                .with_layout(layout)
+                .with_entry(params_buffer.as_entire_binding())
+                .with_entry(histogram_buffer.as_entire_binding())
+                .with_entry(exposure_buffer.as_entire_binding())
+                .with_entry(wgpu::BindingResource::TextureView(&texture.view))
+                .build(context)
+        };
+        Ok([create(&render_textures[0])?, create(&render_textures[1])?])
+    }
+
+    /// Points the histogram at new render textures, e.g. after a resize.
+    pub fn set_render_textures(
+        &mut self,
+        context: &Context,
+        render_textures: &[gfx::Texture; 2],
+    ) -> Result<()> {
+        self.bind_groups = Self::create_bind_groups(
+            context,
+            &self.layout,
+            &self.params_buffer,
+            &self.histogram_buffer,
+            &self.exposure_buffer,
+            render_textures,
+        )?;
+        Ok(())
+    }
+
+    /// Sets how far the exposure can move this frame, or the exposure itself if it's
+    /// manual. Call once per frame.
+    pub fn update(&mut self, context: &Context, dt: &Duration, exposure: Exposure) {
+        match exposure {
+            Exposure::Manual(value) => {
+                if self.manual != Some(value) {
+                    let state = ExposureState {
+                        exposure: value,
+                        average_luminance: 0.0,
+                    };
+                    context.queue.write_buffer(
+                        &self.exposure_buffer,
+                        0,
+                        bytemuck::cast_slice(&[state]),
+                    );
+                    self.manual = Some(value);
+                }
+            }
+            Exposure::Auto {
+                min,
+                max,
+                target,
+                speed,
+            } => {
+                // Picks up from wherever the manual exposure left it
+                self.manual = None;
+                let params = ExposureParams {
+                    min_log_luminance: MIN_LOG_LUMINANCE,
+                    log_luminance_range: MAX_LOG_LUMINANCE - MIN_LOG_LUMINANCE,
+                    target_luminance: target,
+                    min_exposure: min.min(max),
+                    max_exposure: max,
+                    // Frame rate independent exponential smoothing
+                    adaptation: 1.0 - (-dt.as_secs_f32() * speed.max(0.0)).exp(),
+                    _pad: [0.0; 2],
+                };
+                context
+                    .queue
+                    .write_buffer(&self.params_buffer, 0, bytemuck::cast_slice(&[params]));
+            }
+        }
+    }
+
+    /// Measures the render texture at `target` and adapts the exposure to it.
+    pub fn encode(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        target: usize,
+        size: wgpu::Extent3d,
+        timestamp_writes: Option<wgpu::ComputePassTimestampWrites>,
+    ) {
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("auto exposure"),
+            timestamp_writes,
+        });
+        compute_pass.set_bind_group(0, &self.bind_groups[target], &[]);
+        compute_pass.set_pipeline(&self.histogram_pipeline);
+        compute_pass.dispatch_workgroups(size.width.div_ceil(16), size.height.div_ceil(16), 1);
+        compute_pass.set_pipeline(&self.adapt_pipeline);
+        compute_pass.dispatch_workgroups(1, 1, 1);
+    }
+
+    /// The current exposure followed by the average luminance it was adapted to.
+    pub fn get_buffer(&self) -> &wgpu::Buffer {
+        &self.exposure_buffer
+    }
+}
@@ -0,0 +1,157 @@
+use crate::gfx::{Context, ReadbackRing};
+
+/// Words before the requests in the feedback buffer, see `FeedbackReadback`
+pub const FEEDBACK_HEADER_WORDS: usize = 16;
+/// Distance bands the raycast's requests are counted and capped in
+pub const REQUEST_BANDS: usize = 4;
+/// Byte offset of the per band request counts in the feedback header
+pub const BAND_COUNTS_OFFSET: u64 = (4 + REQUEST_BANDS as u64 * 2) * 4;
+
+/// A brick the raycast asked for, in world brick coordinates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BrickRequest {
+    pub brick_pos: glam::IVec3,
+    /// Set for bricks far enough away to only need a colour
+    pub lod_only: bool,
+}
+
+/// Caps on how many bricks the raycast can ask for each frame by how far they are from
+/// the camera. Rays sweeping across the horizon pass through far more distant bricks than
+/// near ones, and without a cap they fill the request queue and leave the bricks right in
+/// front of the camera waiting behind them. The nearest band is never capped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RequestBands {
+    /// Distance in bricks where each band after the nearest starts
+    pub starts: [f32; REQUEST_BANDS - 1],
+    /// Most bricks each band after the nearest can ask for in a frame
+    pub quotas: [u32; REQUEST_BANDS - 1],
+}
+
+impl RequestBands {
+    /// Bands starting at 16, 48 and 128 bricks, each allowed a smaller share of the
+    /// request queue than the last.
+    pub fn new(max_requests: u32) -> Self {
+        Self {
+            starts: [16.0, 48.0, 128.0],
+            quotas: [max_requests / 2, max_requests / 4, max_requests / 8].map(|q| q.max(1)),
+        }
+    }
+
+    /// The bands as they're laid out in the feedback header, starts then quotas. The
+    /// nearest band starts at 0 and has a quota of 0, which the shader reads as no cap.
+    pub fn to_header(self) -> [u32; REQUEST_BANDS * 2] {
+        let mut header = [0; REQUEST_BANDS * 2];
+        for i in 1..REQUEST_BANDS {
+            header[i] = self.starts[i - 1].to_bits();
+            header[REQUEST_BANDS + i] = self.quotas[i - 1];
+        }
+        header
+    }
+}
+
+/// What the raycast fed back over a frame.
+#[derive(Debug, Clone, Default)]
+pub struct RaycastFeedback {
+    pub requests: Vec<BrickRequest>,
+    /// A bit per brickmap cache entry, set for the ones primary rays passed through
+    pub visible: Vec<u32>,
+    /// Bricks asked for from each distance band, nearest first
+    pub band_requests: [u32; REQUEST_BANDS],
+}
+
+/// Reads the raycast's brick requests and visibility back without stalling. Each frame
+/// the feedback buffer gets copied into a free slot and its request count and visibility
+/// cleared, and the slot is picked up a frame or two later once it's mapped. While every
+/// slot is busy the feedback stays in the buffer and goes out with the next free one.
+///
+/// The buffer is a header of {max requests, request count, visibility offset, pad, band
+/// starts, band quotas, band request counts}, then the requests, then the visibility
+/// bitset at the offset in words after the header. See `RequestBands` for the bands.
+#[derive(Debug)]
+pub struct FeedbackReadback {
+    /// Byte range of the visibility bitset in the feedback buffer
+    visibility: std::ops::Range<u64>,
+    readback: ReadbackRing,
+    /// Slots copied before the brickgrid was last reset, whose requests are for bricks
+    /// that aren't there any more
+    stale: Vec<bool>,
+}
+
+impl FeedbackReadback {
+    pub fn new(
+        context: &Context,
+        size: u64,
+        visibility: std::ops::Range<u64>,
+        slot_count: usize,
+    ) -> Self {
+        Self {
+            visibility,
+            readback: ReadbackRing::new(context, "Feedback Read", size, slot_count),
+            stale: vec![false; slot_count],
+        }
+    }
+
+    /// Copies the feedback buffer into a free slot and clears its request counts and
+    /// visibility for the next frame. Returns the slot to map once submitted, or `None`
+    /// if every slot is still waiting on the GPU.
+    pub fn encode_copy(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        feedback_buffer: &wgpu::Buffer,
+    ) -> Option<usize> {
+        let slot = self.readback.find_free_slot()?;
+        let buffer = self.readback.get_buffer(slot);
+        encoder.copy_buffer_to_buffer(feedback_buffer, 0, buffer, 0, buffer.size());
+        encoder.clear_buffer(feedback_buffer, 4, Some(4));
+        encoder.clear_buffer(
+            feedback_buffer,
+            BAND_COUNTS_OFFSET,
+            Some(REQUEST_BANDS as u64 * 4),
+        );
+        encoder.clear_buffer(
+            feedback_buffer,
+            self.visibility.start,
+            Some(self.visibility.end - self.visibility.start),
+        );
+        Some(slot)
+    }
+
+    /// Requests mapping of the slot. Must be called after the copy has been submitted.
+    pub fn map_slot(&self, slot: usize) {
+        self.readback.map_slot(slot);
+    }
+
+    /// Throws away the feedback in every slot that's been copied but not read yet.
+    pub fn discard_pending(&mut self) {
+        for (slot, stale) in self.stale.iter_mut().enumerate() {
+            *stale = self.readback.is_pending(slot);
+        }
+    }
+
+    /// Collects the feedback from any slots that have finished mapping.
+    pub fn poll(&mut self, context: &Context) -> Vec<RaycastFeedback> {
+        let mut frames = Vec::new();
+        self.readback.poll(context, |slot, data| {
+            if !std::mem::take(&mut self.stale[slot]) {
+                let data: &[u32] = bytemuck::cast_slice(data);
+                let count = (data[1] as usize).min(data[0] as usize);
+                let visibility =
+                    self.visibility.start as usize / 4..self.visibility.end as usize / 4;
+                let bands = BAND_COUNTS_OFFSET as usize / 4;
+                frames.push(RaycastFeedback {
+                    requests: data[FEEDBACK_HEADER_WORDS..]
+                        .chunks_exact(4)
+                        .take(count)
+                        .map(|r| BrickRequest {
+                            brick_pos: glam::uvec3(r[0], r[1], r[2]).as_ivec3(),
+                            lod_only: r[3] != 0,
+                        })
+                        .collect(),
+                    visible: data[visibility].to_vec(),
+                    band_requests: data[bands..bands + REQUEST_BANDS].try_into().unwrap(),
+                });
+            }
+        });
+        frames
+    }
+}
@@ -0,0 +1,507 @@
+use anyhow::Result;
+
+use super::UiTheme;
+use crate::gfx::{self, BulkBufferBuilder, Context};
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct GizmoVertex {
+    position: [f32; 3],
+    color: [u8; 4],
+}
+
+const AXIS_COLORS: [[u8; 4]; 3] = [[230, 60, 60, 255], [60, 210, 60, 255], [70, 110, 240, 255]];
+const ACTIVE_COLOR: [u8; 4] = [255, 220, 40, 255];
+/// Orange, cyan and magenta, which colour blind eyes can still tell apart
+const HIGH_CONTRAST_AXIS_COLORS: [[u8; 4]; 3] =
+    [[255, 140, 0, 255], [0, 255, 255, 255], [255, 0, 255, 255]];
+const HIGH_CONTRAST_ACTIVE_COLOR: [u8; 4] = [255, 255, 255, 255];
+/// Segments in each rotation ring
+const RING_SEGMENTS: usize = 48;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum GizmoMode {
+    #[default]
+    Translate,
+    Rotate,
+    Scale,
+}
+
+impl GizmoMode {
+    pub fn next(self) -> Self {
+        match self {
+            Self::Translate => Self::Rotate,
+            Self::Rotate => Self::Scale,
+            Self::Scale => Self::Translate,
+        }
+    }
+}
+
+/// Where the gizmo is and what it's been dragged to, in world voxel space.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GizmoTransform {
+    pub position: glam::Vec3,
+    pub rotation: glam::Quat,
+    pub scale: glam::Vec3,
+}
+
+impl Default for GizmoTransform {
+    fn default() -> Self {
+        Self {
+            position: glam::Vec3::ZERO,
+            rotation: glam::Quat::IDENTITY,
+            scale: glam::Vec3::ONE,
+        }
+    }
+}
+
+impl GizmoTransform {
+    pub fn from_position(position: glam::Vec3) -> Self {
+        Self {
+            position,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Drag {
+    axis: usize,
+    start: GizmoTransform,
+    /// Distance along the axis the drag started at for translating and scaling, or the
+    /// direction from the center it started in for rotating
+    anchor: glam::Vec3,
+}
+
+/// Translate, rotate and scale handles for moving things around the world with the mouse,
+/// drawn as lines over the raycast image. Parts of the gizmo behind voxels are faded out
+/// using the raycast's depth, like the debug lines, so it's clear what's in front while
+/// still being usable from behind a wall.
+///
+/// The gizmo doesn't know what it's moving. Give it a transform with `set_target`, feed it
+/// rays from the cursor, and read the transform back while dragging. Handles are aligned
+/// to the world axes and stay the same size on screen.
+#[derive(Debug)]
+pub struct Gizmo {
+    pipeline: wgpu::RenderPipeline,
+    layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    vertex_buffer: wgpu::Buffer,
+    max_vertices: usize,
+    vertex_count: u32,
+    mode: GizmoMode,
+    target: Option<GizmoTransform>,
+    hovered: Option<usize>,
+    drag: Option<Drag>,
+    /// Length of the handles the last time they were built, in voxels
+    size: f32,
+    ui_scale: f32,
+    theme: UiTheme,
+}
+
+impl Gizmo {
+    /// Length of the handles as a fraction of their distance from the camera
+    const SCREEN_SIZE: f32 = 0.15;
+    /// How close a ray has to pass to a handle to grab it, as a fraction of its length
+    const PICK_TOLERANCE: f32 = 0.08;
+
+    pub fn new(
+        context: &Context,
+        camera_buffer: &wgpu::Buffer,
+        depth_view: &wgpu::TextureView,
+    ) -> Result<Self> {
+        // The rings are the most lines of any mode
+        let max_vertices = 3 * RING_SEGMENTS * 2;
+        let vertex_buffer = BulkBufferBuilder::new()
+            .set_usage(wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST)
+            .with_buffer(
+                "Gizmo",
+                (max_vertices * std::mem::size_of::<GizmoVertex>()) as u64,
+                false,
+            )
+            .build(context)
+            .remove(0);
+
+        let layout = gfx::BindGroupLayoutBuilder::new()
+            .with_label("Gizmo BGL")
+            .with_uniform_entry(wgpu::ShaderStages::VERTEX_FRAGMENT)
+            .with_entry(
+                wgpu::ShaderStages::FRAGMENT,
+                wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                None,
+            )
+            .build(context);
+        let bind_group = Self::create_bind_group(context, &layout, camera_buffer, depth_view)?;
+
+        // TODO: Load the shader better
+        let shader_descriptor = wgpu::include_wgsl!("../../../assets/shaders/gizmo.wgsl");
+        let shader = context.device.create_shader_module(shader_descriptor);
+        let pipeline = context
+            .device
+            .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("Gizmo Pipeline"),
+                layout: Some(&context.device.create_pipeline_layout(
+                    &wgpu::PipelineLayoutDescriptor {
+                        label: Some("Gizmo PL"),
+                        bind_group_layouts: &[&layout],
+                        push_constant_ranges: &[],
+                    },
+                )),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vertex",
+                    buffers: &[wgpu::VertexBufferLayout {
+                        array_stride: std::mem::size_of::<GizmoVertex>() as u64,
+                        step_mode: wgpu::VertexStepMode::Vertex,
+                        attributes: &wgpu::vertex_attr_array![
+                            0 => Float32x3,
+                            1 => Unorm8x4
+                        ],
+                    }],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fragment",
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format: context.surface_config.format,
+                        blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })],
+                }),
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::LineList,
+                    ..Default::default()
+                },
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            });
+
+        Ok(Self {
+            pipeline,
+            layout,
+            bind_group,
+            vertex_buffer,
+            max_vertices,
+            vertex_count: 0,
+            mode: GizmoMode::default(),
+            target: None,
+            hovered: None,
+            drag: None,
+            size: 0.0,
+            ui_scale: 1.0,
+            theme: UiTheme::Default,
+        })
+    }
+
+    fn create_bind_group(
+        context: &Context,
+        layout: &wgpu::BindGroupLayout,
+        camera_buffer: &wgpu::Buffer,
+        depth_view: &wgpu::TextureView,
+    ) -> Result<wgpu::BindGroup> {
+        gfx::BindGroupBuilder::new()
+            .with_label("Gizmo BG")
+            .with_layout(layout)
+            .with_entry(camera_buffer.as_entire_binding())
+            .with_entry(wgpu::BindingResource::TextureView(depth_view))
+            .build(context)
+    }
+
+    /// Points the gizmo at a new depth texture, after the screen has been resized.
+    pub fn set_depth_view(
+        &mut self,
+        context: &Context,
+        camera_buffer: &wgpu::Buffer,
+        depth_view: &wgpu::TextureView,
+    ) -> Result<()> {
+        self.bind_group =
+            Self::create_bind_group(context, &self.layout, camera_buffer, depth_view)?;
+        Ok(())
+    }
+
+    pub fn get_mode(&self) -> GizmoMode {
+        self.mode
+    }
+
+    /// Switches mode, dropping any drag in progress.
+    pub fn set_mode(&mut self, mode: GizmoMode) {
+        self.mode = mode;
+        self.drag = None;
+        self.hovered = None;
+    }
+
+    pub fn get_target(&self) -> Option<GizmoTransform> {
+        self.target
+    }
+
+    /// Shows the gizmo at a transform, or hides it with `None`. Dropping any drag in
+    /// progress.
+    pub fn set_target(&mut self, target: Option<GizmoTransform>) {
+        self.target = target;
+        self.drag = None;
+        self.hovered = None;
+    }
+
+    pub fn is_dragging(&self) -> bool {
+        self.drag.is_some()
+    }
+
+    /// Highlights whichever handle a ray (world voxel space) passes over. Returns whether
+    /// there was one, in which case clicking should start a drag rather than anything else.
+    pub fn hover(&mut self, ray_origin: glam::Vec3, ray_dir: glam::Vec3) -> bool {
+        if self.drag.is_none() {
+            self.hovered = self.pick(ray_origin, ray_dir.normalize());
+        }
+        self.hovered.is_some()
+    }
+
+    /// Grabs the handle under a ray. Returns whether there was one.
+    pub fn begin_drag(&mut self, ray_origin: glam::Vec3, ray_dir: glam::Vec3) -> bool {
+        let ray_dir = ray_dir.normalize();
+        let (Some(target), Some(axis)) = (self.target, self.pick(ray_origin, ray_dir)) else {
+            return false;
+        };
+        let anchor = match self.mode {
+            GizmoMode::Translate | GizmoMode::Scale => {
+                let along = closest_on_axis(target.position, axis, ray_origin, ray_dir);
+                glam::Vec3::splat(along)
+            }
+            GizmoMode::Rotate => match ring_direction(target.position, axis, ray_origin, ray_dir) {
+                Some(direction) => direction,
+                None => return false,
+            },
+        };
+        self.hovered = Some(axis);
+        self.drag = Some(Drag {
+            axis,
+            start: target,
+            anchor,
+        });
+        true
+    }
+
+    /// Moves the grabbed handle to follow a ray, returning the new transform.
+    pub fn drag(&mut self, ray_origin: glam::Vec3, ray_dir: glam::Vec3) -> Option<GizmoTransform> {
+        let ray_dir = ray_dir.normalize();
+        let drag = self.drag?;
+        let start = drag.start;
+        let mut transform = start;
+        match self.mode {
+            GizmoMode::Translate => {
+                let along = closest_on_axis(start.position, drag.axis, ray_origin, ray_dir);
+                transform.position[drag.axis] += along - drag.anchor.x;
+            }
+            GizmoMode::Scale => {
+                // Dragging out by a handle's length doubles the scale
+                let along = closest_on_axis(start.position, drag.axis, ray_origin, ray_dir);
+                let factor = 1.0 + (along - drag.anchor.x) / self.size.max(f32::EPSILON);
+                transform.scale[drag.axis] = (start.scale[drag.axis] * factor).max(0.01);
+            }
+            GizmoMode::Rotate => {
+                let direction = ring_direction(start.position, drag.axis, ray_origin, ray_dir)?;
+                let axis = glam::Vec3::AXES[drag.axis];
+                let angle = f32::atan2(
+                    axis.dot(drag.anchor.cross(direction)),
+                    drag.anchor.dot(direction),
+                );
+                transform.rotation = glam::Quat::from_axis_angle(axis, angle) * start.rotation;
+            }
+        }
+        self.target = Some(transform);
+        Some(transform)
+    }
+
+    /// Lets go of the grabbed handle, returning the final transform.
+    pub fn end_drag(&mut self) -> Option<GizmoTransform> {
+        self.drag.take().and(self.target)
+    }
+
+    /// Sizes the handles relative to their usual size, and colours them.
+    pub fn set_style(&mut self, ui_scale: f32, theme: UiTheme) {
+        self.ui_scale = ui_scale;
+        self.theme = theme;
+    }
+
+    /// Rebuilds the handles so they stay the same size on screen from a camera position
+    /// in world voxel space. Call once per frame.
+    pub fn update(&mut self, context: &Context, camera_pos: glam::Vec3) {
+        let Some(target) = self.target else {
+            self.vertex_count = 0;
+            return;
+        };
+        let screen_size = Self::SCREEN_SIZE * self.ui_scale;
+        self.size = (target.position.distance(camera_pos) * screen_size).max(1.0);
+
+        let (axis_colors, active_color) = match self.theme {
+            UiTheme::Default => (AXIS_COLORS, ACTIVE_COLOR),
+            UiTheme::HighContrast => (HIGH_CONTRAST_AXIS_COLORS, HIGH_CONTRAST_ACTIVE_COLOR),
+        };
+        let mut vertices = Vec::new();
+        let center = target.position;
+        for (axis, &axis_color) in axis_colors.iter().enumerate() {
+            let color = match self.hovered {
+                Some(hovered) if hovered == axis => active_color,
+                _ => axis_color,
+            };
+            let dir = glam::Vec3::AXES[axis] * self.size;
+            let (u, v) = (
+                glam::Vec3::AXES[(axis + 1) % 3] * self.size,
+                glam::Vec3::AXES[(axis + 2) % 3] * self.size,
+            );
+            match self.mode {
+                GizmoMode::Translate => {
+                    // A shaft and an arrowhead of four lines back from the tip
+                    let tip = center + dir;
+                    add_line(&mut vertices, center, tip, color);
+                    for side in [u, -u, v, -v] {
+                        add_line(&mut vertices, tip, tip - dir * 0.2 + side * 0.08, color);
+                    }
+                }
+                GizmoMode::Scale => {
+                    // A shaft with a square cap, which reads as a box from most angles
+                    let tip = center + dir;
+                    add_line(&mut vertices, center, tip, color);
+                    let corners = [u + v, u - v, -u - v, -u + v].map(|c| tip + c * 0.08);
+                    for i in 0..4 {
+                        add_line(&mut vertices, corners[i], corners[(i + 1) % 4], color);
+                    }
+                }
+                GizmoMode::Rotate => {
+                    let point = |i: usize| {
+                        let angle = i as f32 / RING_SEGMENTS as f32 * std::f32::consts::TAU;
+                        center + u * angle.cos() + v * angle.sin()
+                    };
+                    for i in 0..RING_SEGMENTS {
+                        add_line(&mut vertices, point(i), point(i + 1), color);
+                    }
+                }
+            }
+        }
+
+        vertices.truncate(self.max_vertices);
+        self.vertex_count = vertices.len() as u32;
+        context
+            .queue
+            .write_buffer(&self.vertex_buffer, 0, bytemuck::cast_slice(&vertices));
+    }
+
+    pub fn encode(&self, encoder: &mut wgpu::CommandEncoder, view: &wgpu::TextureView) {
+        if self.target.is_none() || self.vertex_count == 0 {
+            return;
+        }
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("gizmo"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            ..Default::default()
+        });
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.draw(0..self.vertex_count, 0..1);
+    }
+
+    /// The handle a ray passes closest to, if it's within reach of any.
+    fn pick(&self, ray_origin: glam::Vec3, ray_dir: glam::Vec3) -> Option<usize> {
+        let target = self.target?;
+        let center = target.position;
+        let tolerance = self.size * Self::PICK_TOLERANCE;
+        let mut best = None;
+        for axis in 0..3 {
+            let miss = match self.mode {
+                GizmoMode::Translate | GizmoMode::Scale => {
+                    let along = closest_on_axis(center, axis, ray_origin, ray_dir);
+                    if !(0.0..=self.size * 1.1).contains(&along) {
+                        continue;
+                    }
+                    let mut point = center;
+                    point[axis] += along;
+                    distance_to_ray(point, ray_origin, ray_dir)
+                }
+                GizmoMode::Rotate => {
+                    let Some(hit) = ray_plane(center, axis, ray_origin, ray_dir) else {
+                        continue;
+                    };
+                    (hit.distance(center) - self.size).abs()
+                }
+            };
+            if miss < tolerance && best.is_none_or(|(_, best_miss)| miss < best_miss) {
+                best = Some((axis, miss));
+            }
+        }
+        best.map(|(axis, _)| axis)
+    }
+}
+
+fn add_line(vertices: &mut Vec<GizmoVertex>, start: glam::Vec3, end: glam::Vec3, color: [u8; 4]) {
+    vertices.push(GizmoVertex {
+        position: start.to_array(),
+        color,
+    });
+    vertices.push(GizmoVertex {
+        position: end.to_array(),
+        color,
+    });
+}
+
+/// How far along a world axis through `center` the point closest to a ray is.
+fn closest_on_axis(
+    center: glam::Vec3,
+    axis: usize,
+    ray_origin: glam::Vec3,
+    ray_dir: glam::Vec3,
+) -> f32 {
+    let axis_dir = glam::Vec3::AXES[axis];
+    let offset = center - ray_origin;
+    let b = axis_dir.dot(ray_dir);
+    let denom = 1.0 - b * b;
+    // Looking straight down the axis, so there's no one closest point
+    if denom < 1e-6 {
+        return 0.0;
+    }
+    (b * offset.dot(ray_dir) - offset.dot(axis_dir)) / denom
+}
+
+fn distance_to_ray(point: glam::Vec3, ray_origin: glam::Vec3, ray_dir: glam::Vec3) -> f32 {
+    let offset = point - ray_origin;
+    (offset - ray_dir * offset.dot(ray_dir).max(0.0)).length()
+}
+
+/// Where a ray crosses the plane through `center` facing along a world axis.
+fn ray_plane(
+    center: glam::Vec3,
+    axis: usize,
+    ray_origin: glam::Vec3,
+    ray_dir: glam::Vec3,
+) -> Option<glam::Vec3> {
+    let normal = glam::Vec3::AXES[axis];
+    let denom = normal.dot(ray_dir);
+    if denom.abs() < 1e-6 {
+        return None;
+    }
+    let t = normal.dot(center - ray_origin) / denom;
+    (t > 0.0).then(|| ray_origin + ray_dir * t)
+}
+
+/// Direction from `center` to where a ray crosses a rotation ring's plane.
+fn ring_direction(
+    center: glam::Vec3,
+    axis: usize,
+    ray_origin: glam::Vec3,
+    ray_dir: glam::Vec3,
+) -> Option<glam::Vec3> {
+    ray_plane(center, axis, ray_origin, ray_dir).and_then(|hit| (hit - center).try_normalize())
+}
@@ -0,0 +1,87 @@
+use crate::gfx::{BulkBufferBuilder, Context};
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct ProbeGridState {
+    dims: [u32; 3],
+    spacing: u32,
+    update_offset: u32,
+    update_count: u32,
+    frame: u32,
+    _pad: u32,
+}
+
+/// A grid of irradiance probes spread over the brickgrid, wrapping around as it scrolls.
+/// Probes are updated on the GPU a window at a time, so the cost of keeping them up to
+/// date is spread over many frames.
+#[derive(Debug)]
+pub struct LightProbeGrid {
+    state: ProbeGridState,
+    state_buffer: wgpu::Buffer,
+    probe_buffer: wgpu::Buffer,
+}
+
+impl LightProbeGrid {
+    /// `spacing` is the distance between probes in bricks.
+    pub fn new(
+        context: &Context,
+        brickgrid_dims: glam::UVec3,
+        spacing: u32,
+        updates_per_frame: u32,
+    ) -> Self {
+        // Probes wrap around like the bricks do, so they have to tile the grid exactly
+        let dims = brickgrid_dims / spacing;
+        let probe_count = dims.x * dims.y * dims.z;
+        log::info!(
+            "Creating light probe grid: dims({}), probe_count({})",
+            dims,
+            probe_count
+        );
+
+        let state = ProbeGridState {
+            dims: dims.to_array(),
+            spacing,
+            update_count: u32::min(updates_per_frame, probe_count),
+            ..Default::default()
+        };
+
+        // Each probe is an ambient cube of 6 vec4<f32>
+        let probe_size = 6 * 16;
+        let mut buffers = BulkBufferBuilder::new()
+            .set_usage(wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST)
+            .with_init_buffer_bm("Light Probe Grid State", &[state])
+            .set_usage(wgpu::BufferUsages::STORAGE)
+            .with_buffer("Light Probes", (probe_count * probe_size) as u64, false)
+            .build(context);
+
+        Self {
+            state,
+            state_buffer: buffers.remove(0),
+            probe_buffer: buffers.remove(0),
+        }
+    }
+
+    pub fn get_state_buffer(&self) -> &wgpu::Buffer {
+        &self.state_buffer
+    }
+
+    pub fn get_probe_buffer(&self) -> &wgpu::Buffer {
+        &self.probe_buffer
+    }
+
+    pub fn get_update_count(&self) -> u32 {
+        self.state.update_count
+    }
+
+    /// Moves the window of probes to be updated along for the next frame.
+    pub fn advance(&mut self, context: &Context) {
+        let dims = self.state.dims;
+        let probe_count = dims[0] * dims[1] * dims[2];
+        self.state.update_offset =
+            (self.state.update_offset + self.state.update_count) % probe_count;
+        self.state.frame = self.state.frame.wrapping_add(1);
+        context
+            .queue
+            .write_buffer(&self.state_buffer, 0, bytemuck::cast_slice(&[self.state]));
+    }
+}
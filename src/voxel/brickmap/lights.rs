@@ -0,0 +1,268 @@
+use crate::gfx::{BulkBufferBuilder, Context};
+
+/// A point light, positioned in voxel space.
+#[derive(Debug, Clone, Copy)]
+pub struct PointLight {
+    pub position: glam::Vec3,
+    pub color: glam::Vec3,
+    pub intensity: f32,
+}
+
+/// A glowing box, positioned in voxel space and lit from all over, so it casts soft
+/// shadows. Flat on one axis it's a panel, like a light set into a ceiling.
+#[derive(Debug, Clone, Copy)]
+pub struct AreaLight {
+    /// The centre of the box
+    pub position: glam::Vec3,
+    /// In voxels along each axis
+    pub size: glam::Vec3,
+    pub color: glam::Vec3,
+    pub intensity: f32,
+}
+
+impl AreaLight {
+    /// A light filling the box between two corners, in voxel space.
+    pub fn from_corners(
+        min: glam::Vec3,
+        max: glam::Vec3,
+        color: glam::Vec3,
+        intensity: f32,
+    ) -> Self {
+        Self {
+            position: (min + max) * 0.5,
+            size: (max - min).abs(),
+            color,
+            intensity,
+        }
+    }
+}
+
+/// Point and area lights share a buffer, a point light being an area light with no size.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct LightElement {
+    position: [f32; 3],
+    intensity: f32,
+    color: [f32; 3],
+    _pad0: f32,
+    half_size: [f32; 3],
+    _pad1: f32,
+}
+
+impl From<PointLight> for LightElement {
+    fn from(value: PointLight) -> Self {
+        Self {
+            position: value.position.to_array(),
+            intensity: value.intensity,
+            color: value.color.to_array(),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<AreaLight> for LightElement {
+    fn from(value: AreaLight) -> Self {
+        Self {
+            position: value.position.to_array(),
+            intensity: value.intensity,
+            color: value.color.to_array(),
+            half_size: (value.size * 0.5).to_array(),
+            ..Default::default()
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct LightState {
+    light_count: u32,
+    candidate_count: u32,
+    frame: u32,
+    area_shadow_rays: u32,
+}
+
+/// Owns the point and area lights in the scene. Lights are shaded by resampling a handful
+/// of candidate lights per pixel into a reservoir, which is reused across frames, so only a
+/// single light needs shadow rays per pixel no matter how many lights there are. Point
+/// lights take one shadow ray, area lights a few spread over them.
+#[derive(Debug)]
+pub struct LightManager {
+    lights: Vec<PointLight>,
+    area_lights: Vec<AreaLight>,
+    /// Between point and area lights
+    max_lights: usize,
+    dirty: bool,
+    state: LightState,
+    state_buffer: wgpu::Buffer,
+    light_buffer: wgpu::Buffer,
+    reservoir_buffer: wgpu::Buffer,
+}
+
+impl LightManager {
+    /// Shadow rays traced to an area light per pixel, unless changed
+    pub const DEFAULT_AREA_SHADOW_RAYS: u32 = 4;
+
+    pub fn new(
+        context: &Context,
+        max_lights: usize,
+        candidate_count: u32,
+        pixel_count: usize,
+    ) -> Self {
+        let state = LightState {
+            candidate_count,
+            area_shadow_rays: Self::DEFAULT_AREA_SHADOW_RAYS,
+            ..Default::default()
+        };
+
+        let mut buffers = BulkBufferBuilder::new()
+            .set_usage(wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST)
+            .with_init_buffer_bm("Light State", &[state])
+            .set_usage(wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST)
+            .with_init_buffer_bm("Lights", &vec![LightElement::default(); max_lights.max(1)])
+            .build(context);
+
+        Self {
+            lights: vec![],
+            area_lights: vec![],
+            max_lights,
+            dirty: false,
+            state,
+            state_buffer: buffers.remove(0),
+            light_buffer: buffers.remove(0),
+            reservoir_buffer: Self::create_reservoir_buffer(context, pixel_count),
+        }
+    }
+
+    fn create_reservoir_buffer(context: &Context, pixel_count: usize) -> wgpu::Buffer {
+        // Reservoirs are {light_idx, weight_sum, sample_count, weight}. We keep two per
+        // pixel so we can read last frame's while writing this frame's
+        let reservoir_size = 16;
+        BulkBufferBuilder::new()
+            .set_usage(wgpu::BufferUsages::STORAGE)
+            .with_buffer(
+                "Light Reservoirs",
+                (2 * pixel_count * reservoir_size) as u64,
+                false,
+            )
+            .build(context)
+            .remove(0)
+    }
+
+    /// Reallocates the per-pixel reservoirs for a new screen size. Their history is lost,
+    /// so lighting takes a few frames to converge again.
+    pub fn resize(&mut self, context: &Context, pixel_count: usize) {
+        self.reservoir_buffer = Self::create_reservoir_buffer(context, pixel_count);
+    }
+
+    pub fn get_state_buffer(&self) -> &wgpu::Buffer {
+        &self.state_buffer
+    }
+
+    pub fn get_light_buffer(&self) -> &wgpu::Buffer {
+        &self.light_buffer
+    }
+
+    pub fn get_reservoir_buffer(&self) -> &wgpu::Buffer {
+        &self.reservoir_buffer
+    }
+
+    pub fn get_point_lights(&self) -> &[PointLight] {
+        &self.lights
+    }
+
+    pub fn get_area_lights(&self) -> &[AreaLight] {
+        &self.area_lights
+    }
+
+    pub fn get_area_shadow_rays(&self) -> u32 {
+        self.state.area_shadow_rays
+    }
+
+    /// How many shadow rays each pixel traces to an area light. More soften its shadows
+    /// with less noise, at the cost of a ray each.
+    pub fn set_area_shadow_rays(&mut self, rays: u32) {
+        self.state.area_shadow_rays = rays.max(1);
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.lights.len() + self.area_lights.len() >= self.max_lights
+    }
+
+    /// Adds a light and returns its index, or `None` if there's no space left.
+    pub fn add_point_light(&mut self, light: PointLight) -> Option<usize> {
+        if self.is_full() {
+            return None;
+        }
+
+        self.lights.push(light);
+        self.dirty = true;
+        Some(self.lights.len() - 1)
+    }
+
+    /// Panics if index out of range
+    pub fn set_point_light(&mut self, index: usize, light: PointLight) {
+        self.lights[index] = light;
+        self.dirty = true;
+    }
+
+    /// Removes a light, moving the last light into its index.
+    /// Panics if index out of range
+    pub fn remove_point_light(&mut self, index: usize) -> PointLight {
+        self.dirty = true;
+        self.lights.swap_remove(index)
+    }
+
+    /// Adds an area light and returns its index, or `None` if there's no space left.
+    pub fn add_area_light(&mut self, light: AreaLight) -> Option<usize> {
+        if self.is_full() {
+            return None;
+        }
+
+        self.area_lights.push(light);
+        self.dirty = true;
+        Some(self.area_lights.len() - 1)
+    }
+
+    /// Panics if index out of range
+    pub fn set_area_light(&mut self, index: usize, light: AreaLight) {
+        self.area_lights[index] = light;
+        self.dirty = true;
+    }
+
+    /// Removes an area light, moving the last area light into its index.
+    /// Panics if index out of range
+    pub fn remove_area_light(&mut self, index: usize) -> AreaLight {
+        self.dirty = true;
+        self.area_lights.swap_remove(index)
+    }
+
+    /// Removes every point and area light.
+    pub fn clear(&mut self) {
+        self.lights.clear();
+        self.area_lights.clear();
+        self.dirty = true;
+    }
+
+    pub fn update(&mut self, context: &Context) {
+        if self.dirty {
+            let data: Vec<LightElement> = self
+                .lights
+                .iter()
+                .map(|l| (*l).into())
+                .chain(self.area_lights.iter().map(|l| (*l).into()))
+                .collect();
+            if !data.is_empty() {
+                context
+                    .queue
+                    .write_buffer(&self.light_buffer, 0, bytemuck::cast_slice(&data));
+            }
+            self.state.light_count = data.len() as u32;
+            self.dirty = false;
+        }
+
+        self.state.frame = self.state.frame.wrapping_add(1);
+        context
+            .queue
+            .write_buffer(&self.state_buffer, 0, bytemuck::cast_slice(&[self.state]));
+    }
+}
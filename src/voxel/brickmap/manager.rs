@@ -1,33 +1,122 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+
 use crate::{
     gfx::{self, BufferExt},
     math,
-    voxel::world::WorldManager,
+    voxel::world::{MaterialTable, WorldId, WorldManager},
 };
 
 use super::{
     brickgrid::{Brickgrid, BrickgridElement, BrickgridFlag},
-    brickmap_cache::BrickmapCache,
-    shading_table::ShadingTableAllocator,
+    brickmap_cache::{BrickmapCache, BrickmapCacheEntry},
+    dump::BrickDump,
+    feedback::{
+        FeedbackReadback, RequestBands, BAND_COUNTS_OFFSET, FEEDBACK_HEADER_WORDS, REQUEST_BANDS,
+    },
+    shading_table::{ShadingBucketStats, ShadingTableAllocator},
+    volumes::{
+        VolumeElement, VolumeHandle, VolumeModel, VolumeTable, VolumeTransform, MAX_VOLUMES,
+    },
 };
 
+/// Frames of brick requests that can be in flight back to the CPU at once
+const FEEDBACK_SLOTS: usize = 3;
+/// Cache entries looked over for shading table compaction each frame
+const COMPACTION_SCAN: usize = 1024;
+/// Most shading table allocations moved to a better fitting bucket each frame
+const MAX_COMPACTION_MOVES: usize = 16;
+/// The camera can stray from the middle of the brickgrid by its size over this before
+/// the grid scrolls to recenter on it
+const SCROLL_MARGIN: i32 = 8;
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 struct WorldState {
     brickgrid_dims: [u32; 3],
-    _pad: u32,
+    frame: u32,
+    /// The brick at the brickgrid's minimum corner, see `BrickmapManager::set_focus`
+    grid_origin: [i32; 3],
+    seed: u32,
+    /// Where the volume sits in the world, see `BrickmapManager::set_origin`
+    origin: [f32; 3],
+    _pad0: u32,
+    volume_count: u32,
+    _pad1: [u32; 3],
+    /// The volumes placed over the terrain, see `VolumeTable`
+    volumes: [VolumeElement; MAX_VOLUMES],
+}
+
+/// A material as the raycast shader sees it, see `Material`.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct MaterialUniform {
+    emissive: [f32; 3],
+    roughness: f32,
+    albedo: u32,
+    metallic: f32,
+    _pad: [u32; 2],
+}
+
+/// How much the manager is holding on to, for spotting leaks in long sessions.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BrickmapMemoryStats {
+    pub loaded_brickmaps: u32,
+    pub shading_elements: u32,
+    pub staged_uploads: usize,
+    pub tracked_chunks: usize,
+    pub pending_reloads: usize,
+    pub waiting_requests: usize,
+    /// Shading table frees that failed, which leak the allocation
+    pub allocator_errors: usize,
+    /// Allocations moved to a smaller bucket by compaction
+    pub shading_relocations: usize,
+    /// Elements the shading table has room for, after any growth
+    pub shading_capacity: u32,
 }
 
 #[derive(Debug)]
 pub struct BrickmapManager {
     state_uniform: WorldState,
     state_buffer: wgpu::Buffer,
+    material_buffer: wgpu::Buffer,
+    /// World and table version the material buffer was last filled from
+    material_version: Option<(WorldId, u64)>,
+    has_emissive: bool,
     brickgrid: Brickgrid,
     brickmap_cache: BrickmapCache,
+    volumes: VolumeTable,
     shading_table_buffer: wgpu::Buffer,
     shading_table_allocator: ShadingTableAllocator,
     feedback_buffer: wgpu::Buffer,
-    feedback_result_buffer: wgpu::Buffer,
+    feedback_readback: FeedbackReadback,
+    request_bands: RequestBands,
+    /// Bricks asked for from each distance band in the last frame of feedback read back
+    band_requests: [u32; REQUEST_BANDS],
     unpack_max_count: usize,
+    chunk_versions: HashMap<glam::IVec3, u64>,
+    pending_reloads: HashSet<usize>,
+    /// Edited bricks to re-cull and upload this frame, see `mark_dirty`
+    dirty_bricks: HashSet<usize>,
+    /// Requested bricks waiting on chunk generation, and whether they only need a colour
+    waiting_requests: HashMap<usize, bool>,
+    /// Bricks whose brickmaps are never evicted from the cache, in world brick space
+    pinned: HashSet<glam::IVec3>,
+    max_reloads: usize,
+    /// The view the last prefetch walked, while there was nothing left to prefetch in it
+    prefetched_view: Option<glam::Mat4>,
+    world_id: Option<WorldId>,
+    allocator_errors: usize,
+    /// Next cache entry compaction looks at
+    compaction_cursor: usize,
+    shading_relocations: usize,
+    /// Largest the shading table can grow to, in elements
+    max_shading_elements: u32,
+    /// Set when the shading table buffer is replaced, see `take_shading_table_resized`
+    shading_table_resized: bool,
+    /// Whether we've already warned about the shading table being unable to grow
+    shading_table_full: bool,
 }
 
 // TODO:
@@ -41,8 +130,23 @@ impl BrickmapManager {
         max_requested_brickmaps: u32,
         max_uploaded_brickmaps: u32,
     ) -> Self {
+        // Cells wrap around as the grid scrolls, which only keeps the mips lined up with
+        // the world if every level divides the grid evenly
+        let align = 1 << Brickgrid::MIP_LEVELS;
+        let rounded = (brickgrid_dims / align).max(glam::UVec3::ONE) * align;
+        if rounded != brickgrid_dims {
+            log::warn!(
+                "Brickgrid dims {} aren't a multiple of {}, using {}",
+                brickgrid_dims,
+                align,
+                rounded
+            );
+        }
+        let brickgrid_dims = rounded;
+
         let state_uniform = WorldState {
             brickgrid_dims: [brickgrid_dims.x, brickgrid_dims.y, brickgrid_dims.z],
+            frame: 1,
             ..Default::default()
         };
 
@@ -56,26 +160,43 @@ impl BrickmapManager {
         let shading_table_allocator = ShadingTableAllocator::new(4, shading_table_bucket_size);
         let shading_table = vec![0u32; shading_table_allocator.total_elements as usize];
 
-        let mut feedback_data = vec![0u32; 4 + 4 * max_requested_brickmaps as usize];
+        let materials = vec![MaterialUniform::default(); MaterialTable::MAX_MATERIALS];
+
+        // Requests, then a visibility bit per cache entry. See `FeedbackReadback`
+        let visibility_offset = 4 * max_requested_brickmaps as usize;
+        let visibility_words = brickmap_cache_size.div_ceil(32);
+        let request_bands = RequestBands::new(max_requested_brickmaps);
+        let mut feedback_data =
+            vec![0u32; FEEDBACK_HEADER_WORDS + visibility_offset + visibility_words];
         feedback_data[0] = max_requested_brickmaps;
+        feedback_data[2] = visibility_offset as u32;
+        feedback_data[4..4 + REQUEST_BANDS * 2].copy_from_slice(&request_bands.to_header());
         let feedback_data_u8 = bytemuck::cast_slice(&feedback_data);
-
-        let mut brickmap_upload_data = vec![0u32; 4 + 532 * max_uploaded_brickmaps as usize];
-        brickmap_upload_data[0] = max_uploaded_brickmaps;
+        let visibility_start = (FEEDBACK_HEADER_WORDS + visibility_offset) as u64 * 4;
 
         let mut buffers = gfx::BulkBufferBuilder::new()
+            .set_usage(wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST)
             .with_init_buffer_bm("Brick World State", &[state_uniform])
-            .set_usage(wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST)
-            .with_init_buffer_bm("Shading Table", &shading_table)
+            .with_init_buffer_bm("Materials", &materials)
             .set_usage(
                 wgpu::BufferUsages::STORAGE
                     | wgpu::BufferUsages::COPY_DST
                     | wgpu::BufferUsages::COPY_SRC,
             )
+            .with_init_buffer_bm("Shading Table", &shading_table)
             .with_init_buffer("Feedback", feedback_data_u8)
-            .set_usage(wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ)
-            .with_buffer("Feedback Read", feedback_data_u8.len() as u64, false)
             .build(context);
+        let feedback_readback = FeedbackReadback::new(
+            context,
+            feedback_data_u8.len() as u64,
+            visibility_start..feedback_data_u8.len() as u64,
+            FEEDBACK_SLOTS,
+        );
+
+        let limits = context.device.limits();
+        let max_shading_bytes =
+            (limits.max_storage_buffer_binding_size as u64).min(limits.max_buffer_size);
+        let max_shading_elements = (max_shading_bytes / 4).min(u32::MAX as u64) as u32;
 
         Self {
             state_uniform,
@@ -83,36 +204,221 @@ impl BrickmapManager {
             brickmap_cache,
             shading_table_allocator,
             unpack_max_count: max_uploaded_brickmaps as usize,
+            chunk_versions: HashMap::new(),
+            pending_reloads: HashSet::new(),
+            dirty_bricks: HashSet::new(),
+            waiting_requests: HashMap::new(),
+            pinned: HashSet::new(),
+            max_reloads: max_requested_brickmaps as usize,
+            prefetched_view: None,
+            world_id: None,
+            allocator_errors: 0,
+            compaction_cursor: 0,
+            shading_relocations: 0,
+            max_shading_elements,
+            shading_table_resized: false,
+            shading_table_full: false,
+            material_version: None,
+            volumes: VolumeTable::new(context),
+            has_emissive: false,
 
             state_buffer: buffers.remove(0),
+            material_buffer: buffers.remove(0),
             shading_table_buffer: buffers.remove(0),
             feedback_buffer: buffers.remove(0),
-            feedback_result_buffer: buffers.remove(0),
+            feedback_readback,
+            request_bands,
+            band_requests: [0; REQUEST_BANDS],
         }
     }
 
+    pub fn get_brickgrid_dims(&self) -> glam::UVec3 {
+        glam::UVec3::from_array(self.state_uniform.brickgrid_dims)
+    }
+
+    /// Where the volume's (0, 0, 0) sits in the world, in bricks.
+    pub fn get_origin(&self) -> glam::Vec3 {
+        glam::Vec3::from_array(self.state_uniform.origin)
+    }
+
+    /// Moves the whole volume so its (0, 0, 0) sits at `origin` in the world (in bricks).
+    /// The camera and lights stay where they are in the world, so this only changes where
+    /// the volume's voxels are drawn. Bricks, picks and edits are all still relative to
+    /// the volume, positions have to go through `to_volume` to reach them.
+    pub fn set_origin(&mut self, context: &gfx::Context, origin: glam::Vec3) {
+        self.state_uniform.origin = origin.to_array();
+        self.write_state(context);
+        self.prefetched_view = None;
+    }
+
+    /// Takes a position in the world (in bricks) to the same position relative to the
+    /// volume.
+    pub fn to_volume(&self, position: glam::Vec3) -> glam::Vec3 {
+        position - self.get_origin()
+    }
+
+    /// The brick at the minimum corner of the region the brickgrid covers.
+    pub fn get_grid_origin(&self) -> glam::IVec3 {
+        glam::IVec3::from_array(self.state_uniform.grid_origin)
+    }
+
+    /// Whether a brick (world brick space) is inside the region the brickgrid covers.
+    pub fn contains_brick(&self, brick_pos: glam::IVec3) -> bool {
+        self.get_grid_idx(brick_pos).is_some()
+    }
+
+    /// The cell a brick (world brick space) is kept in, if it's inside the region the
+    /// brickgrid covers. Bricks wrap around the grid, so a cell keeps its brick however
+    /// far the grid scrolls until the brick falls out of the other side.
+    fn get_grid_idx(&self, brick_pos: glam::IVec3) -> Option<usize> {
+        grid_idx_of(brick_pos, self.get_grid_origin(), self.get_brickgrid_dims())
+    }
+
+    /// The brick (world brick space) a cell currently holds.
+    fn get_brick_pos(&self, grid_idx: usize) -> glam::IVec3 {
+        brick_pos_of(grid_idx, self.get_grid_origin(), self.get_brickgrid_dims())
+    }
+
+    pub fn get_num_loaded_brickmaps(&self) -> u32 {
+        self.brickmap_cache.num_loaded
+    }
+
     pub fn get_brickgrid_buffer(&self) -> &wgpu::Buffer {
         self.brickgrid.get_buffer()
     }
 
+    pub fn get_brickgrid_mip_view(&self) -> &wgpu::TextureView {
+        self.brickgrid.get_mip_view()
+    }
+
+    fn write_state(&self, context: &gfx::Context) {
+        context.queue.write_buffer(
+            &self.state_buffer,
+            0,
+            bytemuck::cast_slice(&[self.state_uniform]),
+        );
+    }
+
+    /// Places a model over the terrain as its own volume. Errors if there's no room for
+    /// it.
+    pub fn add_volume(
+        &mut self,
+        context: &gfx::Context,
+        model: &VolumeModel,
+        transform: VolumeTransform,
+    ) -> Result<VolumeHandle> {
+        let handle = self.volumes.add(context, model, transform)?;
+        self.write_volumes(context);
+        Ok(handle)
+    }
+
+    /// Returns false if the volume had already been removed.
+    pub fn remove_volume(&mut self, context: &gfx::Context, handle: VolumeHandle) -> bool {
+        let removed = self.volumes.remove(handle);
+        if removed {
+            self.write_volumes(context);
+        }
+        removed
+    }
+
+    pub fn get_volume_transform(&self, handle: VolumeHandle) -> Option<VolumeTransform> {
+        self.volumes.get_transform(handle)
+    }
+
+    /// Moves or rotates a volume, cheap enough to animate it every frame. Returns false
+    /// if the volume had been removed.
+    pub fn set_volume_transform(
+        &mut self,
+        context: &gfx::Context,
+        handle: VolumeHandle,
+        transform: VolumeTransform,
+    ) -> bool {
+        let moved = self.volumes.set_transform(handle, transform);
+        if moved {
+            self.write_volumes(context);
+        }
+        moved
+    }
+
+    pub fn get_volume_count(&self) -> usize {
+        self.volumes.get_count()
+    }
+
+    pub fn get_volume_atlas_view(&self) -> &wgpu::TextureView {
+        self.volumes.get_atlas_view()
+    }
+
+    fn write_volumes(&mut self, context: &gfx::Context) {
+        let (volumes, count) = self.volumes.get_elements();
+        self.state_uniform.volumes = volumes;
+        self.state_uniform.volume_count = count;
+        self.write_state(context);
+    }
+
     pub fn get_worldstate_buffer(&self) -> &wgpu::Buffer {
         &self.state_buffer
     }
 
+    /// Whether the world has any glowing materials, as of the last upload.
+    pub fn has_emissive_materials(&self) -> bool {
+        self.has_emissive
+    }
+
+    pub fn get_material_buffer(&self) -> &wgpu::Buffer {
+        &self.material_buffer
+    }
+
     pub fn get_brickmap_buffer(&self) -> &wgpu::Buffer {
         self.brickmap_cache.get_buffer()
     }
 
+    pub fn get_detail_view(&self) -> &wgpu::TextureView {
+        self.brickmap_cache.get_detail_view()
+    }
+
     pub fn get_shading_buffer(&self) -> &wgpu::Buffer {
         &self.shading_table_buffer
     }
 
+    /// Whether the shading table buffer has been replaced by a bigger one since this was
+    /// last called, meaning bind groups using it need rebuilding.
+    pub fn take_shading_table_resized(&mut self) -> bool {
+        std::mem::take(&mut self.shading_table_resized)
+    }
+
     pub fn get_feedback_buffer(&self) -> &wgpu::Buffer {
         &self.feedback_buffer
     }
 
-    pub fn get_feedback_result_buffer(&self) -> &wgpu::Buffer {
-        &self.feedback_result_buffer
+    /// Copies this frame's brick requests into a free readback slot, see
+    /// `FeedbackReadback::encode_copy`.
+    pub fn encode_feedback_copy(&self, encoder: &mut wgpu::CommandEncoder) -> Option<usize> {
+        self.feedback_readback
+            .encode_copy(encoder, &self.feedback_buffer)
+    }
+
+    /// Starts reading back a slot filled by `encode_feedback_copy`, once it's submitted.
+    pub fn map_feedback_slot(&self, slot: usize) {
+        self.feedback_readback.map_slot(slot);
+    }
+
+    pub fn get_request_bands(&self) -> RequestBands {
+        self.request_bands
+    }
+
+    pub fn set_request_bands(&mut self, context: &gfx::Context, bands: RequestBands) {
+        self.request_bands = bands;
+        context.queue.write_buffer(
+            &self.feedback_buffer,
+            16,
+            bytemuck::cast_slice(&bands.to_header()),
+        );
+    }
+
+    /// Bricks the raycast asked for from each distance band, nearest first, in the last
+    /// frame of feedback read back. Bands at their quota are being throttled.
+    pub fn get_band_requests(&self) -> [u32; REQUEST_BANDS] {
+        self.band_requests
     }
 
     pub fn get_brickmap_unpack_buffer(&self) -> &wgpu::Buffer {
@@ -127,59 +433,856 @@ impl BrickmapManager {
         self.unpack_max_count
     }
 
-    pub fn process_feedback_buffer(&mut self, context: &gfx::Context, world: &mut WorldManager) {
-        let data: Vec<u32> = self.feedback_result_buffer.get_mapped_range(context, 0..16);
-        let request_count = data[1] as usize;
+    /// Points the manager at the world to stream bricks from. Switching from another
+    /// world tears down everything loaded from it so the new one streams in from scratch,
+    /// so call it as soon as the world changes. Returns whether that happened.
+    pub fn set_world(&mut self, context: &gfx::Context, world: &WorldManager) -> bool {
+        let world_id = world.get_id();
+        let switched = match self.world_id {
+            Some(id) if id == world_id => return false,
+            Some(_) => {
+                self.reset(context);
+                true
+            }
+            None => false,
+        };
 
-        if request_count > 0 {
-            // Reset the request count for next frame
-            context
-                .queue
-                .write_buffer(&self.feedback_buffer, 4, &[0, 0, 0, 0]);
+        self.world_id = Some(world_id);
+        switched
+    }
+
+    /// Unloads every brick, leaving the manager as it was when created. Pinned bricks are
+    /// kept, they'll pin whatever gets loaded into them from the new world.
+    fn reset(&mut self, context: &gfx::Context) {
+        log::info!(
+            "Resetting brickmap manager, unloading {} brickmaps",
+            self.brickmap_cache.num_loaded
+        );
+        self.brickgrid.reset(context);
+        self.brickmap_cache.reset(context);
+        // The shading table keeps any growth, the new world is probably just as big
+        self.shading_table_allocator.reset();
+        self.shading_table_full = false;
+        self.chunk_versions.clear();
+        self.pending_reloads.clear();
+        self.dirty_bricks.clear();
+        self.waiting_requests.clear();
+        self.prefetched_view = None;
+
+        // Drop any requests made while rendering the old world, whether they've been
+        // copied back yet or not
+        self.feedback_readback.discard_pending();
+        context
+            .queue
+            .write_buffer(&self.feedback_buffer, 4, &[0, 0, 0, 0]);
+        context.queue.write_buffer(
+            &self.feedback_buffer,
+            BAND_COUNTS_OFFSET,
+            &[0; REQUEST_BANDS * 4],
+        );
+    }
+
+    /// Scrolls the brickgrid to keep it centred on `position` (in bricks, in the world
+    /// rather than the volume), so the world can be explored however far it goes. The grid
+    /// only moves once the camera strays a fair way from the middle, as each move unloads
+    /// the cells that wrap around to the other side. Returns whether it moved.
+    pub fn set_focus(&mut self, context: &gfx::Context, position: glam::Vec3) -> bool {
+        let position = self.to_volume(position);
+        let dims = self.get_brickgrid_dims().as_ivec3();
+        let origin = self.get_grid_origin();
+        let center = position.floor().as_ivec3();
+        let offset = center - (origin + dims / 2);
+        let margin = dims / SCROLL_MARGIN;
+
+        let mut new_origin = origin;
+        for axis in 0..3 {
+            if offset[axis].abs() > margin[axis] {
+                new_origin[axis] = center[axis] - dims[axis] / 2;
+            }
+        }
+        if new_origin == origin {
+            return false;
+        }
+
+        self.scroll(context, new_origin);
+        true
+    }
+
+    /// Moves the brickgrid to cover the region starting at `origin`. Bricks in both the old
+    /// and new regions stay in their cells, every other cell is unloaded ready for the brick
+    /// that's wrapped around into it.
+    fn scroll(&mut self, context: &gfx::Context, origin: glam::IVec3) {
+        let dims = self.get_brickgrid_dims();
+        let old_origin = self.get_grid_origin();
+        log::debug!("Scrolling brickgrid from {} to {}", old_origin, origin);
+        self.state_uniform.grid_origin = origin.to_array();
+        self.write_state(context);
+        self.prefetched_view = None;
+
+        // Nothing is left where it was, so it's quicker to start from scratch
+        let Some(wrapped) = wrapped_cells(old_origin, origin, dims) else {
+            self.reset(context);
+            return;
+        };
+        // A cell changes if it does along any axis
+        let is_wrapped = |grid_idx: usize| {
+            let cell = math::to_3d_index(grid_idx, dims);
+            (0..3).any(|axis| wrapped[axis][cell[axis] as usize])
+        };
 
-            let range = 16..(16 + 16 * request_count as u64);
-            let data = self.feedback_result_buffer.get_mapped_range(context, range);
-            for i in 0..request_count {
-                let request_data = &data[(i * 4)..(i * 4 + 3)];
-                self.handle_request(world, request_data);
+        let mut indices = Vec::new();
+        for z in 0..dims.z as usize {
+            for y in 0..dims.y as usize {
+                let row = (y + z * dims.y as usize) * dims.x as usize;
+                if wrapped[2][z] || wrapped[1][y] {
+                    indices.extend(row..row + dims.x as usize);
+                } else {
+                    indices.extend(
+                        (0..dims.x as usize)
+                            .filter(|x| wrapped[0][*x])
+                            .map(|x| row + x),
+                    );
+                }
             }
         }
 
+        let old = self.brickgrid.unload(context, &indices);
+        for (grid_idx, element) in indices.iter().zip(old) {
+            self.free_brick(*grid_idx, element);
+        }
+        self.pending_reloads
+            .retain(|grid_idx| !is_wrapped(*grid_idx));
+        self.dirty_bricks.retain(|grid_idx| !is_wrapped(*grid_idx));
+        self.waiting_requests
+            .retain(|grid_idx, _| !is_wrapped(*grid_idx));
+        log::debug!("Unloaded {} brickgrid cells", indices.len());
+    }
+
+    /// Clamps the region `min..max` (world brick space) to the one the brickgrid covers.
+    fn clamp_to_grid(&self, min: glam::IVec3, max: glam::IVec3) -> (glam::IVec3, glam::IVec3) {
+        let origin = self.get_grid_origin();
+        let end = origin + self.get_brickgrid_dims().as_ivec3();
+        (min.clamp(origin, end), max.clamp(origin, end))
+    }
+
+    pub fn process_feedback_buffer(&mut self, context: &gfx::Context, world: &mut WorldManager) {
+        // Usually a no-op, as whatever switches worlds calls `set_world` straight away.
+        // Switching throws away any requests made against the old brickgrid
+        self.set_world(context, world);
+        self.upload_materials(context, world);
+        self.advance_frame(context);
+        world.process_generated_chunks();
+        self.process_waiting_requests(world);
+
+        // Feedback arrives a frame or two late, so some requests will be for bricks that
+        // have been loaded since, e.g. by the prefetcher, or that the grid has scrolled
+        // away from. Visibility goes in first so the bricks in view are kept when the
+        // requests make room
+        for feedback in self.feedback_readback.poll(context) {
+            self.brickmap_cache.mark_visible(&feedback.visible);
+            self.band_requests = feedback.band_requests;
+            for request in feedback.requests {
+                let Some(grid_idx) = self.get_grid_idx(request.brick_pos) else {
+                    continue;
+                };
+                let needed = match self.brickgrid.get(grid_idx).get_flag() {
+                    BrickgridFlag::Unloaded => true,
+                    BrickgridFlag::Lod => !request.lod_only,
+                    _ => false,
+                };
+                if needed {
+                    self.request_brick(world, request.brick_pos, request.lod_only);
+                }
+            }
+        }
+
+        self.check_chunk_versions(world);
+        self.check_dirty_blocks(world);
+        self.process_dirty_bricks(world);
+        self.process_reloads(world);
+        self.compact_shading_table(world);
+
         // TODO: Why do we call this here rather than doing it outside of here?
         self.upload_unpack_buffers(context);
 
-        log::info!("Num loaded brickmaps: {}", self.brickmap_cache.num_loaded);
+        log::trace!("Num loaded brickmaps: {}", self.brickmap_cache.num_loaded);
+    }
+
+    /// Copies the world's material table to the GPU if it's changed. Bricks already drawn
+    /// as a single colour keep the albedo they were loaded with until they reload.
+    fn upload_materials(&mut self, context: &gfx::Context, world: &WorldManager) {
+        let table = world.get_materials();
+        let version = Some((world.get_id(), table.get_version()));
+        if self.material_version == version {
+            return;
+        }
+
+        let materials: Vec<MaterialUniform> = table
+            .get_materials()
+            .iter()
+            .map(|material| MaterialUniform {
+                emissive: material.get_emission().to_array(),
+                roughness: material.roughness,
+                albedo: material.get_packed_albedo(),
+                metallic: material.metallic,
+                _pad: [0; 2],
+            })
+            .collect();
+        context
+            .queue
+            .write_buffer(&self.material_buffer, 0, bytemuck::cast_slice(&materials));
+        self.material_version = version;
+        self.has_emissive = table.has_emissive();
+    }
+
+    pub fn get_frame_seed(&self) -> u32 {
+        self.state_uniform.seed
+    }
+
+    /// Sets the random seed the shaders use this frame, see `RandomService`.
+    pub fn set_frame_seed(&mut self, context: &gfx::Context, seed: u32) {
+        self.state_uniform.seed = seed;
+        self.write_state(context);
     }
 
-    fn handle_request(&mut self, world: &mut WorldManager, data: &[u32]) {
-        let grid_dims = self.state_uniform.brickgrid_dims;
+    /// Bumps the frame counter the raycast times brick fades with. 0 is skipped as it
+    /// marks brickmaps that don't fade.
+    fn advance_frame(&mut self, context: &gfx::Context) {
+        self.state_uniform.frame = self.state_uniform.frame.wrapping_add(1).max(1);
+        self.brickmap_cache.set_frame(self.state_uniform.frame);
+        self.write_state(context);
+    }
+
+    /// Loads a brick if the chunks it's built from are ready, otherwise it waits for them
+    /// to finish generating. The brick stays flagged as loading on the GPU until then, so
+    /// it won't get requested again.
+    fn request_brick(&mut self, world: &mut WorldManager, brick_pos: glam::IVec3, lod_only: bool) {
+        if Self::request_brick_chunks(world, brick_pos, lod_only) {
+            self.handle_request(world, brick_pos, lod_only);
+        } else if let Some(grid_idx) = self.get_grid_idx(brick_pos) {
+            self.waiting_requests.insert(grid_idx, lod_only);
+        }
+    }
+
+    /// Loads any waiting bricks whose chunks have finished generating.
+    fn process_waiting_requests(&mut self, world: &mut WorldManager) {
+        let ready: Vec<(usize, bool)> = self
+            .waiting_requests
+            .iter()
+            .map(|(grid_idx, lod_only)| (*grid_idx, *lod_only))
+            .filter(|(grid_idx, lod_only)| {
+                Self::request_brick_chunks(world, self.get_brick_pos(*grid_idx), *lod_only)
+            })
+            .collect();
+
+        for (grid_idx, lod_only) in ready {
+            self.waiting_requests.remove(&grid_idx);
+            self.handle_request(world, self.get_brick_pos(grid_idx), lod_only);
+        }
+    }
+
+    /// Requests every chunk a brick reads from, which is its own and those of its
+    /// cardinal neighbours, or just its own for a LOD brick. Returns whether they're all
+    /// ready.
+    fn request_brick_chunks(
+        world: &mut WorldManager,
+        brick_pos: glam::IVec3,
+        lod_only: bool,
+    ) -> bool {
+        let chunk_dims = world.get_chunk_dims().as_ivec3();
+        if lod_only {
+            return world.request_chunk(brick_pos.div_euclid(chunk_dims));
+        }
+
+        let offsets = [
+            glam::IVec3::ZERO,
+            glam::IVec3::X,
+            glam::IVec3::NEG_X,
+            glam::IVec3::Y,
+            glam::IVec3::NEG_Y,
+            glam::IVec3::Z,
+            glam::IVec3::NEG_Z,
+        ];
+
+        // Request all of them even once one isn't ready, so they generate in parallel
+        let mut ready = true;
+        for offset in offsets {
+            let chunk_pos = (brick_pos + offset).div_euclid(chunk_dims);
+            ready &= world.request_chunk(chunk_pos);
+        }
+        ready
+    }
+
+    /// Loads every unloaded brick in the region `min..max` (world brick space), clamped to
+    /// the region the brickgrid covers. Returns how many bricks were loaded.
+    pub fn load_region(
+        &mut self,
+        world: &mut WorldManager,
+        min: glam::IVec3,
+        max: glam::IVec3,
+    ) -> usize {
+        let (min, max) = self.clamp_to_grid(min, max);
+
+        let mut count = 0;
+        for z in min.z..max.z {
+            for y in min.y..max.y {
+                for x in min.x..max.x {
+                    let brick_pos = glam::ivec3(x, y, z);
+                    let Some(grid_idx) = self.get_grid_idx(brick_pos) else {
+                        continue;
+                    };
+                    if self.brickgrid.get(grid_idx).get_flag() != BrickgridFlag::Unloaded {
+                        continue;
+                    }
+
+                    self.handle_request(world, brick_pos, false);
+                    count += 1;
+                }
+            }
+        }
 
-        // Extract brickgrid position of the requested brickmap
-        let grid_pos = glam::uvec3(data[0], data[1], data[2]);
-        let grid_idx = math::to_1d_index(
-            grid_pos,
-            glam::uvec3(grid_dims[0], grid_dims[1], grid_dims[2]),
+        count
+    }
+
+    /// Loads unloaded bricks inside the camera's frustum within `distance` bricks of
+    /// `position` (in the world, like the camera), nearest first, before any rays miss them. Bricks only drawn as a colour
+    /// get their full data too. At most `max_count` are requested, and never more than
+    /// would push bricks the rays can see out of the cache. The rest get picked up on later
+    /// frames. Returns how many were requested.
+    pub fn prefetch(
+        &mut self,
+        world: &mut WorldManager,
+        view_projection: glam::Mat4,
+        position: glam::Vec3,
+        distance: f32,
+        max_count: usize,
+    ) -> usize {
+        // Bricks only come from the world the grid was set up for, see `set_world`
+        if self.world_id != Some(world.get_id())
+            || distance <= 0.0
+            || self.prefetched_view == Some(view_projection)
+        {
+            return 0;
+        }
+
+        // Left, right, bottom, top and near planes, pointing inwards. Anything past the
+        // distance is left to the rays, so the far plane doesn't matter
+        let rows = [0, 1, 2, 3].map(|i| view_projection.row(i));
+        let planes = [
+            rows[3] + rows[0],
+            rows[3] - rows[0],
+            rows[3] + rows[1],
+            rows[3] - rows[1],
+            rows[2],
+        ]
+        .map(|plane| plane / plane.truncate().length());
+        // Bricks are tested as their bounding sphere
+        let radius = 3f32.sqrt() * 0.5;
+        let origin = self.get_origin();
+        let position = self.to_volume(position);
+
+        let (min, max) = self.clamp_to_grid(
+            (position - distance).floor().as_ivec3(),
+            (position + distance).ceil().as_ivec3() + 1,
         );
 
+        let mut candidates = Vec::new();
+        for z in min.z..max.z {
+            for y in min.y..max.y {
+                for x in min.x..max.x {
+                    let brick_pos = glam::ivec3(x, y, z);
+                    let center = brick_pos.as_vec3() + 0.5;
+                    let distance_squared = center.distance_squared(position);
+                    if distance_squared > distance * distance
+                        || planes
+                            .iter()
+                            .any(|p| p.dot((center + origin).extend(1.0)) < -radius)
+                    {
+                        continue;
+                    }
+
+                    let Some(grid_idx) = self.get_grid_idx(brick_pos) else {
+                        continue;
+                    };
+                    match self.brickgrid.get(grid_idx).get_flag() {
+                        BrickgridFlag::Unloaded | BrickgridFlag::Lod => (),
+                        _ => continue,
+                    }
+                    if !self.waiting_requests.contains_key(&grid_idx) {
+                        candidates.push((distance_squared, brick_pos));
+                    }
+                }
+            }
+        }
+
+        candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let count = candidates
+            .len()
+            .min(max_count)
+            .min(self.brickmap_cache.get_evictable_count());
+        for (_, brick_pos) in candidates.drain(..count) {
+            self.request_brick(world, brick_pos, false);
+        }
+
+        // Nothing changes in view until the camera moves, short of bricks being evicted
+        // or edited, which the rays will pick up
+        self.prefetched_view = candidates.is_empty().then_some(view_projection);
+        count
+    }
+
+    /// Pins or unpins every brick in `min..max` (world brick space). Pinned brickmaps are
+    /// never evicted to make space for others, though they're still reloaded when edited.
+    /// Bricks don't need to be loaded or even inside the brickgrid to be pinned, their
+    /// brickmap is pinned whenever it does get loaded. Returns how many bricks changed.
+    pub fn set_pinned_region(&mut self, min: glam::IVec3, max: glam::IVec3, pinned: bool) -> usize {
+        let mut count = 0;
+        for z in min.z..max.z {
+            for y in min.y..max.y {
+                for x in min.x..max.x {
+                    let brick_pos = glam::ivec3(x, y, z);
+                    let changed = match pinned {
+                        true => self.pinned.insert(brick_pos),
+                        false => self.pinned.remove(&brick_pos),
+                    };
+                    if !changed {
+                        continue;
+                    }
+
+                    if let Some(grid_idx) = self.get_grid_idx(brick_pos) {
+                        let element = self.brickgrid.get(grid_idx);
+                        if element.get_flag() == BrickgridFlag::Loaded {
+                            self.brickmap_cache
+                                .set_pinned(element.get_pointer(), pinned);
+                        }
+                    }
+                    count += 1;
+                }
+            }
+        }
+
+        count
+    }
+
+    pub fn is_pinned(&self, brick_pos: glam::IVec3) -> bool {
+        self.pinned.contains(&brick_pos)
+    }
+
+    /// Unpins every brick.
+    pub fn clear_pinned(&mut self) {
+        let pinned: Vec<glam::IVec3> = self.pinned.drain().collect();
+        for brick_pos in pinned {
+            let Some(grid_idx) = self.get_grid_idx(brick_pos) else {
+                continue;
+            };
+            let element = self.brickgrid.get(grid_idx);
+            if element.get_flag() == BrickgridFlag::Loaded {
+                self.brickmap_cache.set_pinned(element.get_pointer(), false);
+            }
+        }
+    }
+
+    /// How many pinned cells currently have a brickmap loaded.
+    pub fn get_pinned_brickmap_count(&self) -> usize {
+        self.brickmap_cache.get_pinned_count()
+    }
+
+    /// Captures the CPU and GPU state of the brickgrid cell holding a brick (world brick
+    /// space), along with its brickmap and shading table slice if it's loaded. `None` if
+    /// the brick is outside the brickgrid. Reads everything back from the GPU, so this
+    /// stalls and is only meant for debugging.
+    pub fn dump_cell(
+        &mut self,
+        context: &gfx::Context,
+        brick_pos: glam::IVec3,
+    ) -> Option<BrickDump> {
+        let grid_idx = self.get_grid_idx(brick_pos)?;
+        let gpu_grid = self.brickgrid.read_back(context);
+
+        let mut flag_counts: HashMap<String, usize> = HashMap::new();
+        let mut mismatched_cells = 0;
+        for (i, element) in gpu_grid.iter().enumerate() {
+            *flag_counts
+                .entry(format!("{:?}", element.get_flag()))
+                .or_default() += 1;
+
+            // The GPU sets the loading bit on requested bricks, that's expected
+            if (element.0 & !2) != self.brickgrid.get(i).0 {
+                mismatched_cells += 1;
+            }
+        }
+        let mut flag_counts: Vec<(String, usize)> = flag_counts.into_iter().collect();
+        flag_counts.sort();
+
+        let mut neighbourhood = vec![];
+        for z in -1..=1 {
+            for y in -1..=1 {
+                for x in -1..=1 {
+                    let pos = brick_pos + glam::ivec3(x, y, z);
+                    let element = self.get_grid_idx(pos).map(|i| gpu_grid[i]);
+                    neighbourhood.push((pos, element));
+                }
+            }
+        }
+
+        let cpu_element = self.brickgrid.get(grid_idx);
+        let mut cache_entry = None;
+        let mut brickmap = None;
+        let mut shading = vec![];
+        if cpu_element.get_flag() == BrickgridFlag::Loaded {
+            let cache_idx = cpu_element.get_pointer();
+            cache_entry = self.brickmap_cache.get_entry(cache_idx);
+            let map = self.brickmap_cache.read_back(context, cache_idx);
+            let count: u32 = map.bitmask.iter().map(|b| b.count_ones()).sum();
+            if count > 0 {
+                shading = self.shading_table_buffer.read_back(
+                    context,
+                    map.shading_table_offset as u64 * 4,
+                    count as u64 * 4,
+                );
+            }
+            brickmap = Some(map);
+        }
+
+        Some(BrickDump {
+            brick_pos,
+            grid_idx,
+            pinned: self.pinned.contains(&brick_pos),
+            waiting: self.waiting_requests.contains_key(&grid_idx),
+            cpu_element,
+            gpu_element: gpu_grid[grid_idx],
+            neighbourhood,
+            flag_counts,
+            mismatched_cells,
+            cache_entry,
+            brickmap,
+            shading,
+        })
+    }
+
+    /// Queues a reload of every resident brick in chunks that have been modified since we
+    /// loaded from them.
+    fn check_chunk_versions(&mut self, world: &WorldManager) {
+        // Chunks the brickgrid has scrolled away from don't need watching any more
+        let chunk_dims = world.get_chunk_dims().as_ivec3();
+        let grid_min = self.get_grid_origin();
+        let grid_max = grid_min + self.get_brickgrid_dims().as_ivec3();
+        self.chunk_versions.retain(|chunk_pos, _| {
+            let min = *chunk_pos * chunk_dims;
+            min.cmplt(grid_max).all() && (min + chunk_dims).cmpgt(grid_min).all()
+        });
+
+        let mut changed_chunks = Vec::new();
+        for (chunk_pos, version) in self.chunk_versions.iter_mut() {
+            let current = world.get_chunk_version(*chunk_pos);
+            if current != *version {
+                *version = current;
+                changed_chunks.push(*chunk_pos);
+            }
+        }
+
+        // Bricks map 1:1 to chunk blocks, so a chunk covers a box of bricks
+        for chunk_pos in changed_chunks {
+            let (min, max) =
+                self.clamp_to_grid(chunk_pos * chunk_dims, (chunk_pos + 1) * chunk_dims);
+            for z in min.z..max.z {
+                for y in min.y..max.y {
+                    for x in min.x..max.x {
+                        // Unloaded bricks will get the new data whenever they're requested
+                        let Some(grid_idx) = self.get_grid_idx(glam::ivec3(x, y, z)) else {
+                            continue;
+                        };
+                        match self.brickgrid.get(grid_idx).get_flag() {
+                            BrickgridFlag::Unloaded | BrickgridFlag::Loading => (),
+                            _ => {
+                                self.pending_reloads.insert(grid_idx);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Marks the brick holding a voxel (world voxel space) as edited, so it's re-culled and
+    /// uploaded next frame. Voxels on the brick's faces can expose or hide voxels in the
+    /// brick next door, so those neighbours are marked too. Only bricks that are resident
+    /// are touched, unloaded ones get the new data whenever they're requested.
+    pub fn mark_dirty(&mut self, world_pos: glam::IVec3) {
+        let brick_pos = world_pos.div_euclid(glam::IVec3::splat(8));
+        let local = world_pos.rem_euclid(glam::IVec3::splat(8));
+        self.mark_brick_dirty(brick_pos);
+        for axis in 0..3 {
+            let mut offset = glam::IVec3::ZERO;
+            offset[axis] = match local[axis] {
+                0 => -1,
+                7 => 1,
+                _ => continue,
+            };
+            self.mark_brick_dirty(brick_pos + offset);
+        }
+    }
+
+    fn mark_brick_dirty(&mut self, brick_pos: glam::IVec3) {
+        let Some(grid_idx) = self.get_grid_idx(brick_pos) else {
+            return;
+        };
+        match self.brickgrid.get(grid_idx).get_flag() {
+            BrickgridFlag::Unloaded | BrickgridFlag::Loading => (),
+            _ => {
+                self.dirty_bricks.insert(grid_idx);
+            }
+        }
+    }
+
+    /// Marks every brick that's been edited in the world since the last frame as dirty.
+    fn check_dirty_blocks(&mut self, world: &mut WorldManager) {
+        for block_pos in world.take_dirty_blocks() {
+            self.mark_brick_dirty(block_pos);
+        }
+    }
+
+    /// Re-culls every dirty brick. Loaded bricks whose surface voxels still fit in their
+    /// shading table allocation are updated in place, which only uploads that one brickmap.
+    /// Anything else is reloaded from scratch.
+    fn process_dirty_bricks(&mut self, world: &mut WorldManager) {
+        for grid_idx in std::mem::take(&mut self.dirty_bricks) {
+            // A full reload that was queued anyway would only redo this work
+            self.pending_reloads.remove(&grid_idx);
+
+            let brick_pos = self.get_brick_pos(grid_idx);
+            let element = self.brickgrid.get(grid_idx);
+            match element.get_flag() {
+                BrickgridFlag::Unloaded | BrickgridFlag::Loading => continue,
+                BrickgridFlag::Loaded => (),
+                flag => {
+                    self.handle_request(world, brick_pos, flag == BrickgridFlag::Lod);
+                    continue;
+                }
+            }
+
+            if super::util::uniform_brick_material(world, brick_pos).is_some() {
+                self.handle_request(world, brick_pos, false);
+                continue;
+            }
+
+            let cache_idx = element.get_pointer();
+            let allocation = self.brickmap_cache.get_entry(cache_idx).and_then(|entry| {
+                let offset = entry.shading_table_offset;
+                let size = self.shading_table_allocator.get_slot_size(offset)?;
+                Some((offset, size))
+            });
+            let (bitmask_data, material_data, detail_data, lod_color) =
+                super::util::cull_interior_voxels(world, brick_pos);
+            match allocation {
+                Some((offset, size))
+                    if !material_data.is_empty() && material_data.len() <= size as usize =>
+                {
+                    self.brickmap_cache.update_entry(
+                        cache_idx,
+                        offset,
+                        bitmask_data,
+                        material_data,
+                        detail_data,
+                        lod_color,
+                    );
+                }
+                _ => self.handle_request(world, brick_pos, false),
+            }
+        }
+    }
+
+    /// Reloads a limited number of queued bricks. The stale brick stays visible until
+    /// its reload, so edits never leave holes.
+    fn process_reloads(&mut self, world: &mut WorldManager) {
+        let batch: Vec<usize> = self
+            .pending_reloads
+            .iter()
+            .take(self.max_reloads)
+            .copied()
+            .collect();
+
+        for grid_idx in batch {
+            self.pending_reloads.remove(&grid_idx);
+            let lod_only = self.brickgrid.get(grid_idx).get_flag() == BrickgridFlag::Lod;
+            self.handle_request(world, self.get_brick_pos(grid_idx), lod_only);
+        }
+    }
+
+    pub fn get_memory_stats(&self) -> BrickmapMemoryStats {
+        BrickmapMemoryStats {
+            loaded_brickmaps: self.get_num_loaded_brickmaps(),
+            shading_elements: self.shading_table_allocator.get_used_elements(),
+            staged_uploads: self.brickgrid.get_staged_count()
+                + self.brickmap_cache.get_staged_count(),
+            tracked_chunks: self.chunk_versions.len(),
+            pending_reloads: self.pending_reloads.len(),
+            waiting_requests: self.waiting_requests.len(),
+            allocator_errors: self.allocator_errors,
+            shading_relocations: self.shading_relocations,
+            shading_capacity: self.shading_table_allocator.total_elements,
+        }
+    }
+
+    /// Occupancy of each shading table bucket, smallest slots first.
+    pub fn get_shading_bucket_stats(&self) -> Vec<ShadingBucketStats> {
+        self.shading_table_allocator.get_bucket_stats()
+    }
+
+    /// Moves a few shading table allocations into the smallest bucket they fit, where
+    /// there's room. Allocations spill into bigger buckets when their own is full, and
+    /// would otherwise stay there wasting space long after it's freed up. Moving one
+    /// means re-culling its brick, as the CPU doesn't keep the shading data around, and
+    /// staging it with the new offset.
+    fn compact_shading_table(&mut self, world: &mut WorldManager) {
+        let cache_size = self.brickmap_cache.get_size();
+        let mut moves = 0;
+        for _ in 0..COMPACTION_SCAN.min(cache_size) {
+            let cache_idx = self.compaction_cursor;
+            self.compaction_cursor = (self.compaction_cursor + 1) % cache_size;
+            let Some(entry) = self.brickmap_cache.get_entry(cache_idx) else {
+                continue;
+            };
+
+            // Bricks about to reload get a new allocation anyway
+            if self.dirty_bricks.contains(&entry.grid_idx)
+                || self.pending_reloads.contains(&entry.grid_idx)
+            {
+                continue;
+            }
+            let allocator = &self.shading_table_allocator;
+            let current = allocator.get_slot_size(entry.shading_table_offset);
+            let best = allocator.get_best_slot_size(entry.shading_element_count);
+            let (Some(current), Some(best)) = (current, best) else {
+                continue;
+            };
+            if best >= current || !allocator.has_free_slot(best) {
+                continue;
+            }
+
+            // The brick might have been edited since it was loaded, in which case it could
+            // need more room now
+            let brick_pos = self.get_brick_pos(entry.grid_idx);
+            let (bitmask_data, material_data, detail_data, lod_color) =
+                super::util::cull_interior_voxels(world, brick_pos);
+            if material_data.is_empty() || material_data.len() as u32 > best {
+                continue;
+            }
+            let Some(offset) = self
+                .shading_table_allocator
+                .try_alloc(material_data.len() as u32)
+            else {
+                continue;
+            };
+            if let Err(e) = self
+                .shading_table_allocator
+                .try_dealloc(entry.shading_table_offset)
+            {
+                log::warn!("{}", e);
+                self.allocator_errors += 1;
+            }
+            self.brickmap_cache.update_entry(
+                cache_idx,
+                offset,
+                bitmask_data,
+                material_data,
+                detail_data,
+                lod_color,
+            );
+            self.shading_relocations += 1;
+
+            moves += 1;
+            if moves == MAX_COMPACTION_MOVES {
+                break;
+            }
+        }
+    }
+
+    /// Checks the shading table allocator's bookkeeping is still consistent.
+    pub fn validate_shading_table(&self) -> Result<(), String> {
+        self.shading_table_allocator.validate()
+    }
+
+    /// Are there brickgrid or brickmap changes waiting to be uploaded?
+    pub fn has_staged_uploads(&self) -> bool {
+        self.brickgrid.get_staged_count() > 0 || self.brickmap_cache.get_staged_count() > 0
+    }
+
+    /// Loads a brick (world brick space) into its brickgrid cell. Bricks outside the
+    /// brickgrid are ignored.
+    fn handle_request(&mut self, world: &mut WorldManager, brick_pos: glam::IVec3, lod_only: bool) {
+        let Some(grid_idx) = self.get_grid_idx(brick_pos) else {
+            return;
+        };
+
+        // Remember which version of the chunk this brick came from. If the chunk was already
+        // tracked then either its version is what we loaded, or it's older and the brick
+        // will get reloaded anyway
+        let chunk_pos = brick_pos.div_euclid(world.get_chunk_dims().as_ivec3());
+        let version = world.get_chunk_version(chunk_pos);
+        self.chunk_versions.entry(chunk_pos).or_insert(version);
+
+        let uniform_material = super::util::uniform_brick_material(world, brick_pos);
+        let mut brickgrid_element = BrickgridElement::default();
+
+        if let Some(material) = uniform_material {
+            // Solid single material bricks are stored inline in the brickgrid, so they
+            // don't need a cache slot or any shading table space
+            brickgrid_element = BrickgridElement::new_uniform(material);
+        } else if lod_only {
+            // Distant bricks are drawn as a single colour, so that's all we need to work out
+            if let Some(albedo) = super::util::average_brick_color(world, brick_pos) {
+                brickgrid_element = BrickgridElement::new_lod(albedo >> 8);
+            }
+        } else {
+            self.load_brickmap(world, grid_idx, brick_pos, &mut brickgrid_element);
+        }
+
+        self.set_brick(grid_idx, brickgrid_element);
+    }
+
+    /// Culls a brick down to its surface voxels and adds it to the brickmap cache, pointing
+    /// `brickgrid_element` at it. Bricks with no surface voxels are left empty.
+    fn load_brickmap(
+        &mut self,
+        world: &mut WorldManager,
+        grid_idx: usize,
+        brick_pos: glam::IVec3,
+        brickgrid_element: &mut BrickgridElement,
+    ) {
         // We only want to upload voxels that are on the surface, so we cull anything
         // that is surrounded by solid voxels
-        let grid_pos = grid_pos.as_ivec3();
-        let (bitmask_data, albedo_data) = super::util::cull_interior_voxels(world, grid_pos);
+        let (bitmask_data, material_data, detail_data, lod_color) =
+            super::util::cull_interior_voxels(world, brick_pos);
 
-        let mut brickgrid_element = BrickgridElement::default();
+        if !material_data.is_empty() {
+            // Bricks appearing for the first time fade in, but edits to ones already on
+            // screen should show up straight away
+            let loaded_frame = match self.brickgrid.get(grid_idx).get_flag() {
+                BrickgridFlag::Loaded => 0,
+                _ => self.state_uniform.frame,
+            };
 
-        // We have voxel data so we have a brickmap to upload
-        if !albedo_data.is_empty() {
-            let shading_idx = self
-                .shading_table_allocator
-                .try_alloc(albedo_data.len() as u32)
-                .unwrap() as usize;
+            // We have voxel data so we have a brickmap to upload. If there's no room left
+            // for it, the best we can do is draw it as a single colour
+            let Some(shading_idx) = self.alloc_shading(material_data.len() as u32) else {
+                *brickgrid_element = BrickgridElement::new_lod(lod_color >> 8);
+                return;
+            };
 
-            if let Some(entry) = self.brickmap_cache.add_entry(
+            let entry = BrickmapCacheEntry {
                 grid_idx,
-                shading_idx as u32,
+                shading_table_offset: shading_idx,
+                shading_element_count: material_data.len() as u32,
+                pinned: self.pinned.contains(&brick_pos),
+                last_visible: self.state_uniform.frame,
+            };
+            if let Some(entry) = self.brickmap_cache.add_entry(
+                entry,
                 bitmask_data,
-                albedo_data,
+                material_data,
+                detail_data,
+                lod_color,
+                loaded_frame,
             ) {
                 // An entry got removed so we need to deallocate it's shading table elements
                 // and mark the relevant brickgrid as unloaded
@@ -187,7 +1290,8 @@ impl BrickmapManager {
                     .shading_table_allocator
                     .try_dealloc(entry.shading_table_offset)
                 {
-                    log::warn!("{}", e)
+                    log::warn!("{}", e);
+                    self.allocator_errors += 1;
                 }
                 self.brickgrid.set(
                     entry.grid_idx,
@@ -195,36 +1299,237 @@ impl BrickmapManager {
                 );
             }
 
-            brickgrid_element =
+            *brickgrid_element =
                 BrickgridElement::new(self.brickmap_cache.index, BrickgridFlag::Loaded);
         }
+    }
 
+    /// Replaces a brickgrid element, freeing whatever the old one was using.
+    fn set_brick(&mut self, grid_idx: usize, brickgrid_element: BrickgridElement) {
         let old = self.brickgrid.set(grid_idx, brickgrid_element);
-        if old.get_flag() == BrickgridFlag::Loaded {
-            // The brickgrid element was previously loaded so we need to unload any of
-            // the data that was associated with it
-            if let Some(entry) = self.brickmap_cache.remove_entry(old.get_pointer()) {
-                if entry.grid_idx != grid_idx {
-                    log::error!(
-                        "Mismatch between brickgrid index and brickmap grid index: {} vs {}",
-                        grid_idx,
-                        entry.grid_idx
-                    );
-                }
+        self.free_brick(grid_idx, old);
+    }
 
-                // We need to deallocate the removed entries shading table elements
-                if let Err(e) = self
-                    .shading_table_allocator
-                    .try_dealloc(entry.shading_table_offset)
-                {
-                    log::warn!("{}", e)
+    /// Frees whatever a brickgrid element that's just been replaced was using.
+    fn free_brick(&mut self, grid_idx: usize, old: BrickgridElement) {
+        if old.get_flag() != BrickgridFlag::Loaded {
+            return;
+        }
+
+        // The brickgrid element was previously loaded so we need to unload any of the
+        // data that was associated with it
+        if let Some(entry) = self.brickmap_cache.remove_entry(old.get_pointer()) {
+            if entry.grid_idx != grid_idx {
+                log::error!(
+                    "Mismatch between brickgrid index and brickmap grid index: {} vs {}",
+                    grid_idx,
+                    entry.grid_idx
+                );
+            }
+
+            // We need to deallocate the removed entries shading table elements
+            if let Err(e) = self
+                .shading_table_allocator
+                .try_dealloc(entry.shading_table_offset)
+            {
+                log::warn!("{}", e);
+                self.allocator_errors += 1;
+            }
+        }
+    }
+
+    /// Allocates shading table space, growing the table if it's full. Growing only
+    /// happens on the CPU side here, the buffer catches up in `grow_shading_buffer`
+    /// before anything is uploaded into the new space.
+    fn alloc_shading(&mut self, size: u32) -> Option<u32> {
+        let allocator = &mut self.shading_table_allocator;
+        loop {
+            if let Some(address) = allocator.try_alloc(size) {
+                return Some(address);
+            }
+
+            let grown = allocator.total_elements as u64 + allocator.get_page_elements() as u64;
+            if grown > self.max_shading_elements as u64 {
+                if !self.shading_table_full {
+                    log::warn!(
+                        "Shading table is full at {} elements and can't grow any further, \
+                         new bricks will be drawn as a single colour",
+                        allocator.total_elements
+                    );
+                    self.shading_table_full = true;
                 }
+                return None;
             }
+            allocator.grow();
+            log::info!(
+                "Growing shading table to {} elements",
+                allocator.total_elements
+            );
+        }
+    }
+
+    /// Replaces the shading table buffer with one big enough for the allocator, copying
+    /// the existing data across on the GPU.
+    fn grow_shading_buffer(&mut self, context: &gfx::Context) {
+        let size = self.shading_table_allocator.total_elements as u64 * 4;
+        if size <= self.shading_table_buffer.size() {
+            return;
         }
+
+        let buffer = context.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Shading Table"),
+            size,
+            usage: self.shading_table_buffer.usage(),
+            mapped_at_creation: false,
+        });
+        let mut encoder = context
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Shading Table Growth"),
+            });
+        encoder.copy_buffer_to_buffer(
+            &self.shading_table_buffer,
+            0,
+            &buffer,
+            0,
+            self.shading_table_buffer.size(),
+        );
+        context.queue.submit(Some(encoder.finish()));
+
+        // The old buffer lives on until the bind groups using it are rebuilt
+        self.shading_table_buffer = buffer;
+        self.shading_table_resized = true;
     }
 
-    fn upload_unpack_buffers(&mut self, context: &gfx::Context) {
+    pub fn upload_unpack_buffers(&mut self, context: &gfx::Context) {
+        self.grow_shading_buffer(context);
         self.brickgrid.upload(context);
         self.brickmap_cache.upload(context);
     }
 }
+
+/// The cell a brick (world brick space) is kept in while the brickgrid covers `dims`
+/// bricks from `origin`, `None` if it's outside that region. Bricks wrap around the grid,
+/// so a cell is picked by the brick's position alone.
+fn grid_idx_of(brick_pos: glam::IVec3, origin: glam::IVec3, dims: glam::UVec3) -> Option<usize> {
+    let local = brick_pos - origin;
+    if local.cmplt(glam::IVec3::ZERO).any() || local.cmpge(dims.as_ivec3()).any() {
+        return None;
+    }
+    let cell = brick_pos.rem_euclid(dims.as_ivec3()).as_uvec3();
+    Some(math::to_1d_index(cell, dims))
+}
+
+/// The brick (world brick space) a cell holds while the brickgrid covers `dims` bricks
+/// from `origin`. Undoes `grid_idx_of`.
+fn brick_pos_of(grid_idx: usize, origin: glam::IVec3, dims: glam::UVec3) -> glam::IVec3 {
+    let cell = math::to_3d_index(grid_idx, dims).as_ivec3();
+    let dims = dims.as_ivec3();
+    glam::ivec3(
+        held_brick(origin.x, cell.x, dims.x),
+        held_brick(origin.y, cell.y, dims.y),
+        held_brick(origin.z, cell.z, dims.z),
+    )
+}
+
+/// Along one axis, the brick a cell holds while the grid starts at `origin`.
+fn held_brick(origin: i32, cell: i32, dims: i32) -> i32 {
+    origin + (cell - origin).rem_euclid(dims)
+}
+
+/// Along each axis, which cells hold a different brick once the brickgrid has scrolled
+/// from `old_origin` to `origin`. `None` if it's moved its whole size or more along any
+/// axis, so no cell keeps its brick.
+fn wrapped_cells(
+    old_origin: glam::IVec3,
+    origin: glam::IVec3,
+    dims: glam::UVec3,
+) -> Option<[Vec<bool>; 3]> {
+    let dims = dims.as_ivec3();
+    if (origin - old_origin).abs().cmpge(dims).any() {
+        return None;
+    }
+    Some(std::array::from_fn(|axis| {
+        (0..dims[axis])
+            .map(|cell| {
+                held_brick(old_origin[axis], cell, dims[axis])
+                    != held_brick(origin[axis], cell, dims[axis])
+            })
+            .collect()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIMS: glam::UVec3 = glam::uvec3(4, 3, 5);
+
+    /// The cells along each axis that `wrapped_cells` says changed.
+    fn changed_cells(old_origin: glam::IVec3, origin: glam::IVec3) -> [Vec<i32>; 3] {
+        let wrapped = wrapped_cells(old_origin, origin, DIMS).unwrap();
+        wrapped.map(|axis| {
+            (0..axis.len() as i32)
+                .filter(|cell| axis[*cell as usize])
+                .collect()
+        })
+    }
+
+    #[test]
+    fn scrolling_by_one_wraps_one_row() {
+        let old_origin = glam::ivec3(-7, 2, 13);
+        for axis in 0..3 {
+            let step = glam::IVec3::AXES[axis];
+            let dims = DIMS.as_ivec3()[axis];
+
+            // The cell holding the brick that's left behind takes the one coming in
+            let mut expected: [Vec<i32>; 3] = Default::default();
+            expected[axis] = vec![old_origin[axis].rem_euclid(dims)];
+            assert_eq!(changed_cells(old_origin, old_origin + step), expected);
+
+            expected[axis] = vec![(old_origin[axis] - 1).rem_euclid(dims)];
+            assert_eq!(changed_cells(old_origin, old_origin - step), expected);
+        }
+    }
+
+    #[test]
+    fn scrolling_by_the_grid_size_keeps_nothing() {
+        let old_origin = glam::ivec3(-7, 2, 13);
+        for axis in 0..3 {
+            let step = glam::IVec3::AXES[axis] * DIMS.as_ivec3()[axis];
+            assert!(wrapped_cells(old_origin, old_origin + step, DIMS).is_none());
+            assert!(wrapped_cells(old_origin, old_origin - step, DIMS).is_none());
+
+            // One short of it, only one row along the axis keeps its brick
+            let changed = changed_cells(old_origin, old_origin + step - glam::IVec3::AXES[axis]);
+            assert_eq!(changed[axis].len() as u32, DIMS[axis] - 1);
+        }
+    }
+
+    #[test]
+    fn cells_hold_the_bricks_put_in_them() {
+        for origin in [
+            glam::IVec3::ZERO,
+            glam::ivec3(-10, -3, -20),
+            glam::ivec3(-1, 5, -6),
+        ] {
+            let mut seen = HashSet::new();
+            for z in 0..DIMS.z as i32 {
+                for y in 0..DIMS.y as i32 {
+                    for x in 0..DIMS.x as i32 {
+                        let brick_pos = origin + glam::ivec3(x, y, z);
+                        let grid_idx = grid_idx_of(brick_pos, origin, DIMS).unwrap();
+                        assert_eq!(brick_pos_of(grid_idx, origin, DIMS), brick_pos);
+                        assert!(seen.insert(grid_idx));
+                    }
+                }
+            }
+
+            assert_eq!(grid_idx_of(origin - glam::IVec3::X, origin, DIMS), None);
+            assert_eq!(
+                grid_idx_of(origin + DIMS.as_ivec3() * glam::IVec3::Z, origin, DIMS),
+                None
+            );
+        }
+    }
+}
@@ -1,9 +1,40 @@
 mod brickgrid;
 mod brickmap_cache;
+mod budget;
+mod debug_lines;
+mod decal;
+mod dump;
+mod exposure;
+mod feedback;
+mod gizmo;
+mod light_probes;
+mod lights;
 mod manager;
+mod particles;
+mod picking;
+mod portal;
 mod renderer;
 mod shading_table;
+mod stats;
+mod sun_shadows;
 mod util;
+pub mod volumes;
+mod water;
 
-pub use manager::BrickmapManager;
-pub use renderer::BrickmapRenderer;
+pub use brickmap_cache::BrickmapCache;
+pub use budget::BrickmapBudget;
+pub use decal::{Decal, DecalManager};
+pub use exposure::Exposure;
+pub use gizmo::GizmoTransform;
+pub use lights::{AreaLight, LightManager, PointLight};
+pub use manager::{BrickmapManager, BrickmapMemoryStats};
+pub use particles::ParticleKind;
+pub use portal::{Portal, PortalManager};
+pub use renderer::{
+    Atmosphere, BrickmapRenderer, Outline, RenderQuality, RenderSettings, Sky, SurfaceDetail,
+    UiTheme,
+};
+pub use volumes::{VolumeHandle, VolumeModel, VolumeTransform};
+pub use water::Water;
+
+pub(crate) use util::cull_interior_voxels;
@@ -0,0 +1,275 @@
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::gfx::{self, BulkBufferBuilder, Context};
+
+use super::sun_shadows::SunShadowMaps;
+
+/// What the ambient particles look like and how they move. Must match the kinds in the
+/// particle shader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParticleKind {
+    Dust = 0,
+    Leaves = 1,
+    Rain = 2,
+    Snow = 3,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct ParticleState {
+    center: [f32; 3],
+    dt: f32,
+    time: f32,
+    kind: u32,
+    count: u32,
+    seed: u32,
+}
+
+/// Position and seed, velocity and padding
+const PARTICLE_SIZE: u64 = 32;
+
+/// Ambient particles simulated in a box around the camera, wrapping around as it moves.
+/// Simulation happens in a compute pass and the particles are drawn as camera facing
+/// sprites over the raycast image, hidden behind voxels using the raycast's depth and
+/// shadowed using the sun's shadow cascades.
+#[derive(Debug)]
+pub struct ParticleSystem {
+    simulate_pipeline: wgpu::ComputePipeline,
+    simulate_bind_group: wgpu::BindGroup,
+    draw_pipeline: wgpu::RenderPipeline,
+    draw_layout: wgpu::BindGroupLayout,
+    draw_bind_group: wgpu::BindGroup,
+    state_buffer: wgpu::Buffer,
+    particle_buffer: wgpu::Buffer,
+    state: ParticleState,
+}
+
+impl ParticleSystem {
+    pub fn new(
+        context: &Context,
+        camera_buffer: &wgpu::Buffer,
+        depth_view: &wgpu::TextureView,
+        sun_shadows: &SunShadowMaps,
+        max_particles: u32,
+    ) -> Result<Self> {
+        let state = ParticleState {
+            count: max_particles,
+            ..Default::default()
+        };
+        let mut buffers = BulkBufferBuilder::new()
+            .set_usage(wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST)
+            .with_init_buffer_bm("Particle State", &[state])
+            .set_usage(wgpu::BufferUsages::STORAGE)
+            .with_buffer("Particles", max_particles as u64 * PARTICLE_SIZE, false)
+            .build(context);
+        let state_buffer = buffers.remove(0);
+        let particle_buffer = buffers.remove(0);
+
+        // TODO: Load the shader better
+        let shader_descriptor = wgpu::include_wgsl!("../../../assets/shaders/particles.wgsl");
+        let shader = context.device.create_shader_module(shader_descriptor);
+
+        let simulate_layout = gfx::BindGroupLayoutBuilder::new()
+            .with_label("Particle Simulate BGL")
+            .with_uniform_entry(wgpu::ShaderStages::COMPUTE)
+            .with_rw_storage_entry(wgpu::ShaderStages::COMPUTE)
+            .build(context);
+        let simulate_bind_group = gfx::BindGroupBuilder::new()
+            .with_label("Particle Simulate BG")
+            .with_layout(&simulate_layout)
+            .with_entry(state_buffer.as_entire_binding())
+            .with_entry(particle_buffer.as_entire_binding())
+            .build(context)?;
+        let simulate_pipeline =
+            context
+                .device
+                .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                    label: Some("Particle Simulate Pipeline"),
+                    layout: Some(&context.device.create_pipeline_layout(
+                        &wgpu::PipelineLayoutDescriptor {
+                            label: Some("Particle Simulate PL"),
+                            bind_group_layouts: &[&simulate_layout],
+                            push_constant_ranges: &[],
+                        },
+                    )),
+                    module: &shader,
+                    entry_point: "simulate",
+                });
+
+        let draw_layout = gfx::BindGroupLayoutBuilder::new()
+            .with_label("Particle Draw BGL")
+            .with_uniform_entry(wgpu::ShaderStages::VERTEX)
+            .with_uniform_entry(wgpu::ShaderStages::VERTEX)
+            .with_ro_storage_entry(wgpu::ShaderStages::VERTEX)
+            .with_entry(
+                wgpu::ShaderStages::FRAGMENT,
+                wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                None,
+            )
+            .with_uniform_entry(wgpu::ShaderStages::VERTEX)
+            .with_entry(
+                wgpu::ShaderStages::VERTEX,
+                wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    view_dimension: wgpu::TextureViewDimension::D2Array,
+                    multisampled: false,
+                },
+                None,
+            )
+            .build(context);
+        let draw_bind_group = Self::create_draw_bind_group(
+            context,
+            &draw_layout,
+            camera_buffer,
+            &state_buffer,
+            &particle_buffer,
+            depth_view,
+            sun_shadows,
+        )?;
+        let draw_pipeline =
+            context
+                .device
+                .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some("Particle Draw Pipeline"),
+                    layout: Some(&context.device.create_pipeline_layout(
+                        &wgpu::PipelineLayoutDescriptor {
+                            label: Some("Particle Draw PL"),
+                            bind_group_layouts: &[&draw_layout],
+                            push_constant_ranges: &[],
+                        },
+                    )),
+                    vertex: wgpu::VertexState {
+                        module: &shader,
+                        entry_point: "vertex",
+                        buffers: &[],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &shader,
+                        entry_point: "fragment",
+                        targets: &[Some(wgpu::ColorTargetState {
+                            format: context.surface_config.format,
+                            blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                            write_mask: wgpu::ColorWrites::ALL,
+                        })],
+                    }),
+                    primitive: wgpu::PrimitiveState::default(),
+                    depth_stencil: None,
+                    multisample: wgpu::MultisampleState::default(),
+                    multiview: None,
+                });
+
+        Ok(Self {
+            simulate_pipeline,
+            simulate_bind_group,
+            draw_pipeline,
+            draw_layout,
+            draw_bind_group,
+            state_buffer,
+            particle_buffer,
+            state,
+        })
+    }
+
+    fn create_draw_bind_group(
+        context: &Context,
+        layout: &wgpu::BindGroupLayout,
+        camera_buffer: &wgpu::Buffer,
+        state_buffer: &wgpu::Buffer,
+        particle_buffer: &wgpu::Buffer,
+        depth_view: &wgpu::TextureView,
+        sun_shadows: &SunShadowMaps,
+    ) -> Result<wgpu::BindGroup> {
+        gfx::BindGroupBuilder::new()
+            .with_label("Particle Draw BG")
+            .with_layout(layout)
+            .with_entry(camera_buffer.as_entire_binding())
+            .with_entry(state_buffer.as_entire_binding())
+            .with_entry(particle_buffer.as_entire_binding())
+            .with_entry(wgpu::BindingResource::TextureView(depth_view))
+            .with_entry(sun_shadows.get_buffer().as_entire_binding())
+            .with_entry(wgpu::BindingResource::TextureView(sun_shadows.get_view()))
+            .build(context)
+    }
+
+    /// Points the particles at a new depth texture, after the screen has been resized.
+    pub fn set_depth_view(
+        &mut self,
+        context: &Context,
+        camera_buffer: &wgpu::Buffer,
+        depth_view: &wgpu::TextureView,
+        sun_shadows: &SunShadowMaps,
+    ) -> Result<()> {
+        self.draw_bind_group = Self::create_draw_bind_group(
+            context,
+            &self.draw_layout,
+            camera_buffer,
+            &self.state_buffer,
+            &self.particle_buffer,
+            depth_view,
+            sun_shadows,
+        )?;
+        Ok(())
+    }
+
+    /// Advances the simulation clock and recenters the particle box on `position` (in
+    /// brick units). New particles are scattered using `seed`.
+    pub fn update(
+        &mut self,
+        context: &Context,
+        dt: &Duration,
+        position: glam::Vec3,
+        kind: ParticleKind,
+        seed: u32,
+    ) {
+        self.state.center = position.to_array();
+        self.state.seed = seed;
+        self.state.dt = dt.as_secs_f32();
+        self.state.time += self.state.dt;
+        self.state.kind = kind as u32;
+        context
+            .queue
+            .write_buffer(&self.state_buffer, 0, bytemuck::cast_slice(&[self.state]));
+    }
+
+    /// Simulates every particle, but only draws `density` of them.
+    pub fn encode(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+        density: f32,
+    ) {
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("particles"),
+                ..Default::default()
+            });
+            compute_pass.set_pipeline(&self.simulate_pipeline);
+            compute_pass.set_bind_group(0, &self.simulate_bind_group, &[]);
+            compute_pass.dispatch_workgroups(self.state.count.div_ceil(64), 1, 1);
+        }
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("particles"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            ..Default::default()
+        });
+        render_pass.set_pipeline(&self.draw_pipeline);
+        render_pass.set_bind_group(0, &self.draw_bind_group, &[]);
+        let draw_count = (self.state.count as f32 * density.clamp(0.0, 1.0)) as u32;
+        render_pass.draw(0..6, 0..draw_count);
+    }
+}
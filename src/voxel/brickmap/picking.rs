@@ -0,0 +1,200 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicU8, Ordering},
+    Arc,
+};
+
+use crate::gfx::{BulkBufferBuilder, Context};
+
+/// The voxel under a picked pixel, exactly as the raycast shader saw it.
+#[derive(Debug, Clone, Copy)]
+pub struct PickHit {
+    /// Position in world voxel space
+    pub position: glam::IVec3,
+    /// Axis aligned normal of the face that was hit
+    pub normal: glam::IVec3,
+    /// Packed RGBA albedo of the voxel
+    pub albedo: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PickResult {
+    /// Pixel that was picked, in window coordinates
+    pub cursor: glam::UVec2,
+    pub hit: Option<PickHit>,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct PickState {
+    pixel: [u32; 2],
+    _pad: [u32; 2],
+}
+
+const READBACK_IDLE: u8 = 0;
+const READBACK_MAPPING: u8 = 1;
+const READBACK_MAPPED: u8 = 2;
+
+/// Picks voxels using the raycast shader itself, so results always match what's on
+/// screen. The shader writes whatever the ray through the pick pixel hit into a tiny
+/// texture every frame, which gets copied back whenever a pick has been requested.
+#[derive(Debug)]
+pub struct GpuPicker {
+    state_buffer: wgpu::Buffer,
+    texture: wgpu::Texture,
+    view: wgpu::TextureView,
+    readback_buffer: wgpu::Buffer,
+    readback_state: Arc<AtomicU8>,
+    cursor: glam::UVec2,
+    requested: AtomicBool,
+    result: Option<PickResult>,
+}
+
+impl GpuPicker {
+    /// Texels are {hit, x, y, z}, {normal x, normal y, normal z, albedo}
+    const TEXEL_COUNT: u32 = 2;
+    const TEXEL_SIZE: u64 = 16;
+
+    pub fn new(context: &Context) -> Self {
+        let mut buffers = BulkBufferBuilder::new()
+            .set_usage(wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST)
+            .with_init_buffer_bm("Pick State", &[PickState::default()])
+            .set_usage(wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ)
+            .with_buffer(
+                "Pick Readback",
+                Self::TEXEL_COUNT as u64 * Self::TEXEL_SIZE,
+                false,
+            )
+            .build(context);
+
+        let texture = context.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Pick Result"),
+            size: wgpu::Extent3d {
+                width: Self::TEXEL_COUNT,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba32Sint,
+            usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        Self {
+            state_buffer: buffers.remove(0),
+            texture,
+            view,
+            readback_buffer: buffers.remove(0),
+            readback_state: Arc::new(AtomicU8::new(READBACK_IDLE)),
+            cursor: glam::UVec2::ZERO,
+            requested: AtomicBool::new(false),
+            result: None,
+        }
+    }
+
+    pub fn get_state_buffer(&self) -> &wgpu::Buffer {
+        &self.state_buffer
+    }
+
+    pub fn get_view(&self) -> &wgpu::TextureView {
+        &self.view
+    }
+
+    /// Picks whatever is under a window pixel. The result is ready a frame or two later.
+    /// `image_height` is the height of the raycast image, which is flipped relative to
+    /// the window.
+    pub fn request(&mut self, context: &Context, cursor: glam::UVec2, image_height: u32) {
+        let state = PickState {
+            pixel: [cursor.x, image_height.saturating_sub(cursor.y + 1)],
+            ..Default::default()
+        };
+        context
+            .queue
+            .write_buffer(&self.state_buffer, 0, bytemuck::cast_slice(&[state]));
+        self.cursor = cursor;
+        self.requested.store(true, Ordering::Release);
+    }
+
+    /// Whether the next frame will copy a pick result back.
+    pub fn is_pending(&self) -> bool {
+        self.readback_state.load(Ordering::Acquire) == READBACK_IDLE
+            && self.requested.load(Ordering::Acquire)
+    }
+
+    /// Copies the pick texture for readback if a pick is waiting on this frame. Returns
+    /// whether it did, in which case `map` must be called once the copy is submitted.
+    pub fn encode_copy(&self, encoder: &mut wgpu::CommandEncoder) -> bool {
+        if self.readback_state.load(Ordering::Acquire) != READBACK_IDLE
+            || !self.requested.swap(false, Ordering::AcqRel)
+        {
+            return false;
+        }
+
+        encoder.copy_texture_to_buffer(
+            self.texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &self.readback_buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: None,
+                    rows_per_image: None,
+                },
+            },
+            wgpu::Extent3d {
+                width: Self::TEXEL_COUNT,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+        );
+        true
+    }
+
+    pub fn map(&self) {
+        let state = self.readback_state.clone();
+        state.store(READBACK_MAPPING, Ordering::Release);
+        self.readback_buffer
+            .slice(..)
+            .map_async(wgpu::MapMode::Read, move |result| {
+                let next = if result.is_ok() {
+                    READBACK_MAPPED
+                } else {
+                    READBACK_IDLE
+                };
+                state.store(next, Ordering::Release);
+            });
+    }
+
+    /// Picks up the result once the readback has finished mapping.
+    pub fn poll(&mut self, context: &Context) {
+        if self.readback_state.load(Ordering::Acquire) == READBACK_IDLE {
+            return;
+        }
+
+        context.device.poll(wgpu::Maintain::Poll);
+        if self.readback_state.load(Ordering::Acquire) != READBACK_MAPPED {
+            return;
+        }
+
+        let data: [i32; 8] =
+            bytemuck::pod_read_unaligned(&self.readback_buffer.slice(..).get_mapped_range());
+        self.readback_buffer.unmap();
+        self.readback_state.store(READBACK_IDLE, Ordering::Release);
+
+        let hit = (data[0] != 0).then(|| PickHit {
+            position: glam::ivec3(data[1], data[2], data[3]),
+            normal: glam::ivec3(data[4], data[5], data[6]),
+            albedo: data[7] as u32,
+        });
+        self.result = Some(PickResult {
+            cursor: self.cursor,
+            hit,
+        });
+    }
+
+    /// Takes the latest pick result, if one has arrived since the last call.
+    pub fn take_result(&mut self) -> Option<PickResult> {
+        self.result.take()
+    }
+}
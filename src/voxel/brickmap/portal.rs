@@ -0,0 +1,129 @@
+use crate::gfx::{BulkBufferBuilder, Context};
+
+/// A rectangular window onto another part of the world. Rays crossing the front of the
+/// rectangle carry on from the same spot on the exit rectangle, coming out of its front.
+/// Rectangles lie on their local XY plane and face local +Z. Positions are in brick units.
+#[derive(Debug, Clone, Copy)]
+pub struct Portal {
+    pub position: glam::Vec3,
+    pub rotation: glam::Quat,
+    pub half_size: glam::Vec2,
+    pub exit_position: glam::Vec3,
+    pub exit_rotation: glam::Quat,
+}
+
+impl Portal {
+    /// Maps points and directions in front of the portal to where they end up after
+    /// passing through it.
+    pub fn get_transform(&self) -> glam::Mat4 {
+        let entry = glam::Mat4::from_rotation_translation(self.rotation, self.position);
+        let exit = glam::Mat4::from_rotation_translation(self.exit_rotation, self.exit_position);
+
+        // Going into the front of the entry means coming out of the front of the exit
+        let turn = glam::Mat4::from_rotation_y(std::f32::consts::PI);
+        exit * turn * entry.inverse()
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct PortalElement {
+    transform: [f32; 16],
+    position: [f32; 3],
+    half_width: f32,
+    right: [f32; 3],
+    half_height: f32,
+    up: [f32; 3],
+    _pad: f32,
+}
+
+impl From<Portal> for PortalElement {
+    fn from(value: Portal) -> Self {
+        Self {
+            transform: value.get_transform().to_cols_array(),
+            position: value.position.to_array(),
+            half_width: value.half_size.x,
+            right: (value.rotation * glam::Vec3::X).to_array(),
+            half_height: value.half_size.y,
+            up: (value.rotation * glam::Vec3::Y).to_array(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Owns the portals in the scene. There are few enough of them that they all live in a
+/// single uniform buffer, {count, pad, pad, pad, portals[]}.
+#[derive(Debug)]
+pub struct PortalManager {
+    portals: Vec<Portal>,
+    dirty: bool,
+    buffer: wgpu::Buffer,
+}
+
+impl PortalManager {
+    /// Must match the portal array size in the raycast shader
+    pub const MAX_PORTALS: usize = 8;
+
+    pub fn new(context: &Context) -> Self {
+        let size = 16 + Self::MAX_PORTALS * std::mem::size_of::<PortalElement>();
+        let mut buffers = BulkBufferBuilder::new()
+            .set_usage(wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST)
+            .with_init_buffer("Portals", &vec![0u8; size])
+            .build(context);
+
+        Self {
+            portals: vec![],
+            dirty: false,
+            buffer: buffers.remove(0),
+        }
+    }
+
+    pub fn get_buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    pub fn get_portals(&self) -> &[Portal] {
+        &self.portals
+    }
+
+    /// Adds a portal and returns its index, or `None` if there's no space left.
+    pub fn add_portal(&mut self, portal: Portal) -> Option<usize> {
+        if self.portals.len() >= Self::MAX_PORTALS {
+            return None;
+        }
+
+        self.portals.push(portal);
+        self.dirty = true;
+        Some(self.portals.len() - 1)
+    }
+
+    /// Removes a portal, moving the last portal into its index.
+    /// Panics if index out of range
+    pub fn remove_portal(&mut self, index: usize) -> Portal {
+        self.dirty = true;
+        self.portals.swap_remove(index)
+    }
+
+    pub fn clear(&mut self) {
+        self.portals.clear();
+        self.dirty = true;
+    }
+
+    pub fn update(&mut self, context: &Context) {
+        if !self.dirty {
+            return;
+        }
+
+        let data: Vec<PortalElement> = self.portals.iter().map(|p| (*p).into()).collect();
+        let count = [self.portals.len() as u32, 0, 0, 0];
+        context
+            .queue
+            .write_buffer(&self.buffer, 0, bytemuck::cast_slice(&count));
+        if !data.is_empty() {
+            context
+                .queue
+                .write_buffer(&self.buffer, 16, bytemuck::cast_slice(&data));
+        }
+        self.dirty = false;
+    }
+}
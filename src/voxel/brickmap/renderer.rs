@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 
 use anyhow::Result;
 
@@ -7,82 +7,791 @@ use crate::{
     voxel::{renderer::VoxelRenderer, world::WorldManager},
 };
 
-use super::BrickmapManager;
+use super::{
+    debug_lines::DebugLines,
+    exposure::{AutoExposure, Exposure},
+    gizmo::Gizmo,
+    light_probes::LightProbeGrid,
+    particles::{ParticleKind, ParticleSystem},
+    picking::{GpuPicker, PickResult},
+    stats::{RaycastStats, RaycastStatsReader},
+    sun_shadows::SunShadowMaps,
+    volumes::{VolumeHandle, VolumeModel, VolumeTransform},
+    water::{Water, WaterReflections},
+    BrickmapBudget, BrickmapManager, DecalManager, LightManager, PortalManager,
+};
+
+const BLIT_SHADER: gfx::ShaderSource = gfx::ShaderSource::new(
+    "shader.wgsl",
+    include_str!("../../../assets/shaders/shader.wgsl"),
+);
+const UNPACK_SHADER: gfx::ShaderSource = gfx::ShaderSource::new(
+    "brickmap_upload.wgsl",
+    include_str!("../../../assets/shaders/brickmap_upload.wgsl"),
+);
+/// Most bricks the prefetcher requests per frame, on top of what the rays ask for
+const MAX_PREFETCH_COUNT: usize = 64;
+/// Matches BLUE_NOISE_SIZE in the raycast shader
+const BLUE_NOISE_SIZE: u32 = 64;
+
+const RAYCAST_SHADER: gfx::ShaderSource = gfx::ShaderSource::new(
+    "voxel_volume.wgsl",
+    include_str!("../../../assets/shaders/voxel_volume.wgsl"),
+);
+
+/// The voxel volume shader is by far the slowest to compile, so its pipelines are built in
+/// the background while the loading screen is shown.
+#[derive(Debug)]
+struct RaycastPipelines {
+    raycast: wgpu::ComputePipeline,
+    probes: wgpu::ComputePipeline,
+    sun_shadows: wgpu::ComputePipeline,
+    water_reflections: wgpu::ComputePipeline,
+}
+
+impl RaycastPipelines {
+    fn new(
+        device: &wgpu::Device,
+        layout: &wgpu::PipelineLayout,
+        water_layout: &wgpu::PipelineLayout,
+        code: &str,
+    ) -> Self {
+        let cs = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(RAYCAST_SHADER.name),
+            source: wgpu::ShaderSource::Wgsl(code.into()),
+        });
+        let raycast = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Voxel Raycast Pipeline"),
+            layout: Some(layout),
+            module: &cs,
+            entry_point: "compute",
+        });
+
+        // Probe updates share all of the raycasting code and bindings, just with a
+        // different entry point
+        let probes = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Light Probe Update Pipeline"),
+            layout: Some(layout),
+            module: &cs,
+            entry_point: "update_probes",
+        });
+        let sun_shadows = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Sun Shadow Pipeline"),
+            layout: Some(layout),
+            module: &cs,
+            entry_point: "update_sun_shadows",
+        });
+        // Swaps the screen bindings for the reflection texture and the raycast depth
+        let water_reflections = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Water Reflections Pipeline"),
+            layout: Some(water_layout),
+            module: &cs,
+            entry_point: "trace_water_reflections",
+        });
+
+        Self {
+            raycast,
+            probes,
+            sun_shadows,
+            water_reflections,
+        }
+    }
+}
+
+/// Traversal cost visualisations that replace the rendered image. Must match the shader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugHeatmap {
+    Off = 0,
+    /// Brickgrid cells each primary ray stepped through
+    Steps = 1,
+    /// Loaded bricks each primary ray had to look inside
+    Bricks = 2,
+}
+
+impl DebugHeatmap {
+    pub fn next(self) -> Self {
+        match self {
+            Self::Off => Self::Steps,
+            Self::Steps => Self::Bricks,
+            Self::Bricks => Self::Off,
+        }
+    }
+}
+
+/// Colour ramps for the heatmaps. Must match the shader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugPalette {
+    /// Green through yellow to red
+    RedGreen = 0,
+    /// Perceptually uniform and readable with any colour vision deficiency
+    Viridis = 1,
+    /// Like viridis, but tuned to look the same with red-green colour blindness
+    Cividis = 2,
+    Greyscale = 3,
+}
+
+impl DebugPalette {
+    pub fn next(self) -> Self {
+        match self {
+            Self::RedGreen => Self::Viridis,
+            Self::Viridis => Self::Cividis,
+            Self::Cividis => Self::Greyscale,
+            Self::Greyscale => Self::RedGreen,
+        }
+    }
+}
+
+/// Dark lines drawn by the blit wherever depth or normals change sharply between
+/// neighbouring pixels, for a stylised look.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Outline {
+    /// Width of the lines in pixels
+    pub thickness: f32,
+    /// Change in depth that counts as an edge, as a fraction of the nearer depth
+    pub depth_threshold: f32,
+    /// Change in normal that counts as an edge, as 1 - the cosine of the angle between them
+    pub normal_threshold: f32,
+    /// Outline every voxel, not just silhouettes and creases
+    pub voxel_edges: bool,
+}
+
+impl Default for Outline {
+    fn default() -> Self {
+        Self {
+            thickness: 1.0,
+            depth_threshold: 0.05,
+            normal_threshold: 0.3,
+            voxel_edges: false,
+        }
+    }
+}
+
+/// Brightness variation across voxel faces from a world space noise pattern, so big
+/// surfaces of one material don't look completely flat. The pattern is fixed to the world
+/// rather than to each voxel, so it carries on smoothly across neighbouring voxels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SurfaceDetail {
+    /// How far the albedo can be brightened or darkened, as a fraction of itself
+    pub intensity: f32,
+    /// Width of the pattern's largest features in voxels
+    pub scale: f32,
+}
+
+impl Default for SurfaceDetail {
+    fn default() -> Self {
+        Self {
+            intensity: 0.15,
+            scale: 4.0,
+        }
+    }
+}
+
+/// Runtime settings for the raycast and blit passes.
+#[derive(Debug, Clone, Copy)]
+pub struct RenderSettings {
+    /// Trace the edges of the screen at a reduced rate (one ray per 2x2 pixel block),
+    /// interpolating the missing pixels.
+    pub variable_rate: bool,
+    /// Radius of the full rate region around the screen center, as a fraction of half
+    /// the screen height.
+    pub full_rate_radius: f32,
+    /// Light voxels with ambient bounce lighting from the light probe grid.
+    pub light_probes: bool,
+    /// Accumulate traversal statistics for primary rays and read them back.
+    pub raycast_stats: bool,
+    /// Round off and bevel exposed voxels using the per-voxel detail table.
+    pub sub_voxel_detail: bool,
+    /// Darken the ambient light in creases and corners with the AO baked into each
+    /// brick when it's loaded.
+    pub baked_ao: bool,
+    /// Distance from the camera (in bricks) past which bricks are drawn as a single
+    /// colour and only their colour is loaded. 0 draws everything at full detail.
+    pub lod_distance: f32,
+    /// Distance from the camera (in bricks) within which bricks in view are loaded ahead
+    /// of the rays reaching them, to cut down on pop-in. 0 only loads what rays ask for.
+    pub prefetch_distance: f32,
+    /// Distance from the camera (in bricks) past which rays give up. 0 for no limit.
+    pub max_ray_distance: f32,
+    /// Brickgrid cells a ray can step through before giving up. 0 for no limit beyond
+    /// the size of the grid.
+    pub max_ray_steps: u32,
+    /// Tint pixels whose ray ran out of distance or steps.
+    pub ray_budget_debug: bool,
+    /// Light only one pixel in each 2x2 block and fill in the others from whichever of
+    /// their neighbours are on the same surface. Visibility is still traced per pixel.
+    pub quarter_res_lighting: bool,
+    /// Draw how expensive each pixel's ray was instead of the world.
+    pub debug_heatmap: DebugHeatmap,
+    pub debug_palette: DebugPalette,
+    /// Average each pixel over the frames since the camera last moved, jittering the rays
+    /// within their pixels. Smooths out noise and aliasing while standing still.
+    pub temporal_accumulation: bool,
+    /// Light surfaces with emissive voxels around them, by tracing one random bounce ray
+    /// per pixel. Noisy on its own, so it's best with temporal accumulation. Skipped
+    /// while the world has no emissive materials.
+    pub emissive_bounce: bool,
+    /// Draw chunk borders and brick boundaries around the camera.
+    pub debug_lines: bool,
+    /// Time the renderer's passes on the GPU, if the device supports timestamp queries.
+    /// See `get_gpu_timings`.
+    pub gpu_profiling: bool,
+    /// Ambient particles drawn around the camera, if any.
+    pub particles: Option<ParticleKind>,
+    pub outline: Option<Outline>,
+    pub exposure: Exposure,
+    /// Sea level water with reflections, if any.
+    pub water: Option<Water>,
+    pub surface_detail: Option<SurfaceDetail>,
+    /// Draw a crosshair in the middle of the screen, for aiming while the mouse is
+    /// grabbed.
+    pub crosshair: bool,
+    /// Cast a shadow ray towards the sun from every lit surface. Without them the sun
+    /// reaches anything facing it, even underground.
+    pub sun_shadows: bool,
+    /// Fraction of the window's resolution the world is traced at, stretched to fit by
+    /// the blit. Below 1 trades sharpness for speed. Takes effect on the next `resize`.
+    pub render_scale: f32,
+    /// Size of the crosshair, outlines and gizmo handles, 2 drawing them twice as big.
+    pub ui_scale: f32,
+    pub ui_theme: UiTheme,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            variable_rate: false,
+            full_rate_radius: 0.6,
+            light_probes: true,
+            raycast_stats: false,
+            sub_voxel_detail: true,
+            baked_ao: true,
+            lod_distance: 96.0,
+            prefetch_distance: 24.0,
+            max_ray_distance: 0.0,
+            max_ray_steps: 0,
+            ray_budget_debug: false,
+            quarter_res_lighting: false,
+            debug_heatmap: DebugHeatmap::Off,
+            debug_palette: DebugPalette::RedGreen,
+            temporal_accumulation: false,
+            emissive_bounce: true,
+            debug_lines: false,
+            gpu_profiling: false,
+            particles: None,
+            outline: None,
+            exposure: Exposure::default(),
+            water: None,
+            surface_detail: None,
+            crosshair: false,
+            sun_shadows: true,
+            render_scale: 1.0,
+            ui_scale: 1.0,
+            ui_theme: UiTheme::Default,
+        }
+    }
+}
+
+impl RenderSettings {
+    /// These settings cut down for `quality`. `RenderQuality::Full` leaves them as they
+    /// are.
+    pub fn with_quality(self, quality: RenderQuality) -> Self {
+        match quality {
+            RenderQuality::Full => self,
+            RenderQuality::Reduced => Self {
+                max_ray_steps: RenderQuality::REDUCED_MAX_RAY_STEPS,
+                sun_shadows: false,
+                emissive_bounce: false,
+                render_scale: RenderQuality::REDUCED_RENDER_SCALE,
+                ..self
+            },
+        }
+    }
+}
+
+/// How much work the renderer puts into each frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderQuality {
+    Full,
+    /// Shorter rays, no sun shadows or emissive bounce, and fewer pixels, for GPUs that
+    /// would otherwise manage a few frames a second at best
+    Reduced,
+}
+
+impl RenderQuality {
+    /// Enough steps to cross the default brickgrid about halfway
+    const REDUCED_MAX_RAY_STEPS: u32 = 256;
+    const REDUCED_RENDER_SCALE: f32 = 0.5;
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "full" => Some(Self::Full),
+            "reduced" => Some(Self::Reduced),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Full => "full",
+            Self::Reduced => "reduced",
+        }
+    }
+
+    /// Reduced for integrated and software adapters, full for anything else.
+    pub fn for_adapter(info: &wgpu::AdapterInfo) -> Self {
+        match info.device_type {
+            wgpu::DeviceType::IntegratedGpu | wgpu::DeviceType::Cpu => Self::Reduced,
+            _ => Self::Full,
+        }
+    }
+}
+
+/// Colours of everything the renderer draws over the world.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum UiTheme {
+    #[default]
+    Default,
+    /// Pure, bright colours that stay apart for colour blind eyes, and a crosshair with
+    /// a dark border so it shows up against anything
+    HighContrast,
+}
+
+impl UiTheme {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "default" => Some(Self::Default),
+            "high_contrast" => Some(Self::HighContrast),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Default => "default",
+            Self::HighContrast => "high_contrast",
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct RenderSettingsUniform {
+    variable_rate: u32,
+    full_rate_radius: f32,
+    light_probes: u32,
+    /// Anything below 1.0 draws the loading screen instead of the world.
+    loading_progress: f32,
+    raycast_stats: u32,
+    sub_voxel_detail: u32,
+    lod_distance: f32,
+    max_ray_distance: f32,
+    max_ray_steps: u32,
+    ray_budget_debug: u32,
+    quarter_res_lighting: u32,
+    debug_heatmap: u32,
+    debug_palette: u32,
+    temporal_accumulation: u32,
+    /// Frames already blended into the accumulation history, 0 when it's been reset.
+    accumulated_frames: u32,
+    emissive_bounce: u32,
+    /// 0 when outlines are off
+    outline_thickness: f32,
+    outline_depth_threshold: f32,
+    outline_normal_threshold: f32,
+    outline_voxel_edges: u32,
+    baked_ao: u32,
+    water: u32,
+    water_level: f32,
+    water_clarity: f32,
+    /// 0 when surface detail is off
+    surface_detail_intensity: f32,
+    surface_detail_scale: f32,
+    crosshair: u32,
+    sun_shadows: u32,
+    ui_scale: f32,
+    high_contrast: u32,
+}
+
+impl From<RenderSettings> for RenderSettingsUniform {
+    fn from(value: RenderSettings) -> Self {
+        let outline = value.outline.unwrap_or(Outline {
+            thickness: 0.0,
+            ..Default::default()
+        });
+        let water = value.water.unwrap_or_default();
+        let surface_detail = value.surface_detail.unwrap_or(SurfaceDetail {
+            intensity: 0.0,
+            ..Default::default()
+        });
+        Self {
+            variable_rate: value.variable_rate as u32,
+            full_rate_radius: value.full_rate_radius,
+            light_probes: value.light_probes as u32,
+            loading_progress: 1.0,
+            raycast_stats: value.raycast_stats as u32,
+            sub_voxel_detail: value.sub_voxel_detail as u32,
+            lod_distance: value.lod_distance,
+            max_ray_distance: value.max_ray_distance,
+            max_ray_steps: value.max_ray_steps,
+            ray_budget_debug: value.ray_budget_debug as u32,
+            quarter_res_lighting: value.quarter_res_lighting as u32,
+            debug_heatmap: value.debug_heatmap as u32,
+            debug_palette: value.debug_palette as u32,
+            temporal_accumulation: value.temporal_accumulation as u32,
+            accumulated_frames: 0,
+            emissive_bounce: value.emissive_bounce as u32,
+            outline_thickness: outline.thickness * value.ui_scale,
+            outline_depth_threshold: outline.depth_threshold,
+            outline_normal_threshold: outline.normal_threshold,
+            outline_voxel_edges: outline.voxel_edges as u32,
+            baked_ao: value.baked_ao as u32,
+            water: value.water.is_some() as u32,
+            water_level: water.level,
+            water_clarity: water.clarity,
+            surface_detail_intensity: surface_detail.intensity,
+            surface_detail_scale: surface_detail.scale,
+            crosshair: value.crosshair as u32,
+            sun_shadows: value.sun_shadows as u32,
+            ui_scale: value.ui_scale,
+            high_contrast: (value.ui_theme == UiTheme::HighContrast) as u32,
+        }
+    }
+}
+
+/// Sky, fog and weather effects. Unlike the settings these are expected to change over
+/// time, usually driven by the weather.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Atmosphere {
+    /// Colour of rays that miss everything, and of the sky light reaching light probes
+    pub sky_color: glam::Vec3,
+    pub fog_color: glam::Vec3,
+    /// Fog extinction per brick travelled
+    pub fog_density: f32,
+    /// How wet surfaces are, 0 is dry and 1 is soaked. Wet surfaces are darker
+    pub wetness: f32,
+    /// Fraction of the ambient particles that get drawn
+    pub particle_density: f32,
+}
+
+impl Default for Atmosphere {
+    fn default() -> Self {
+        Self {
+            sky_color: glam::vec3(0.6, 0.7, 0.9),
+            fog_color: glam::vec3(0.7, 0.75, 0.8),
+            fog_density: 0.0,
+            wetness: 0.0,
+            particle_density: 1.0,
+        }
+    }
+}
+
+impl Atmosphere {
+    pub fn lerp(&self, other: &Self, t: f32) -> Self {
+        Self {
+            sky_color: self.sky_color.lerp(other.sky_color, t),
+            fog_color: self.fog_color.lerp(other.fog_color, t),
+            fog_density: self.fog_density + (other.fog_density - self.fog_density) * t,
+            wetness: self.wetness + (other.wetness - self.wetness) * t,
+            particle_density: self.particle_density
+                + (other.particle_density - self.particle_density) * t,
+        }
+    }
+}
+
+/// Inputs to the procedural sky, see `core::Environment`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sky {
+    /// Points from the ground towards the sun
+    pub sun_direction: glam::Vec3,
+    /// Haziness of the air, 2 is a clear day
+    pub turbidity: f32,
+}
+
+impl Default for Sky {
+    fn default() -> Self {
+        Self {
+            sun_direction: glam::vec3(0.4, 1.0, 0.3).normalize(),
+            turbidity: 2.5,
+        }
+    }
+}
+
+/// The atmosphere and the procedural sky share a uniform, the raycast is already using
+/// every uniform binding the default limits give us.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct AtmosphereUniform {
+    sky_color: [f32; 3],
+    fog_density: f32,
+    fog_color: [f32; 3],
+    wetness: f32,
+    sun_direction: [f32; 3],
+    turbidity: f32,
+    sky_enabled: u32,
+    _pad: [u32; 3],
+}
+
+impl AtmosphereUniform {
+    fn new(atmosphere: Atmosphere, sky: Option<Sky>) -> Self {
+        let procedural = sky.unwrap_or_default();
+        Self {
+            sky_color: atmosphere.sky_color.to_array(),
+            fog_density: atmosphere.fog_density,
+            fog_color: atmosphere.fog_color.to_array(),
+            wetness: atmosphere.wetness,
+            sun_direction: procedural.sun_direction.normalize_or_zero().to_array(),
+            turbidity: procedural.turbidity,
+            sky_enabled: sky.is_some() as u32,
+            _pad: [0; 3],
+        }
+    }
+}
+
+/// Progress of the temporal accumulation. The raycast writes one of the two render
+/// textures while reading the other as its history, and they swap every frame.
+#[derive(Debug, Default)]
+struct Accumulation {
+    /// Render texture the next frame gets written to
+    target: usize,
+    /// Frames blended into the history so far
+    frames: u32,
+    /// Camera the history was rendered from
+    view_projection: glam::Mat4,
+    /// Upcoming frames that have to start from scratch
+    resets: u32,
+}
+
+/// Readback slots a frame's commands copy into, mapped once they've been submitted.
+#[derive(Debug, Default)]
+struct FrameReadbacks {
+    stats: Option<usize>,
+    picked: bool,
+    feedback: Option<usize>,
+    profiler: Option<usize>,
+}
 
 #[derive(Debug)]
 pub struct BrickmapRenderer {
     clear_color: wgpu::Color,
-    render_texture: gfx::Texture,
+    settings: RenderSettings,
+    atmosphere: Atmosphere,
+    atmosphere_buffer: wgpu::Buffer,
+    /// `None` draws the atmosphere's flat sky colour instead
+    sky: Option<Sky>,
+    settings_buffer: wgpu::Buffer,
+    settings_layout: wgpu::BindGroupLayout,
+    /// The settings along with the raycast depth, for the blit
+    settings_bind_group: wgpu::BindGroup,
+    render_textures: [gfx::Texture; 2],
+    accumulation: Accumulation,
     render_pipeline: wgpu::RenderPipeline,
+    render_pipeline_layout: wgpu::PipelineLayout,
     brickmap_manager: BrickmapManager,
-    raycast_pipeline: wgpu::ComputePipeline,
+    /// What the brickmap buffers were sized with, to size them the same on `rebuild`
+    budget: BrickmapBudget,
+    light_probes: LightProbeGrid,
+    light_manager: LightManager,
+    portal_manager: PortalManager,
+    decal_manager: DecalManager,
+    picker: GpuPicker,
+    raycast_stats: RaycastStatsReader,
+    raycast_depth: wgpu::Texture,
+    raycast_depth_view: wgpu::TextureView,
+    debug_lines: DebugLines,
+    gizmo: Gizmo,
+    particles: ParticleSystem,
+    sun_shadows: SunShadowMaps,
+    water_reflections: WaterReflections,
+    exposure: AutoExposure,
+    profiler: Option<gfx::GpuProfiler>,
+    blue_noise: gfx::Texture,
+    raycast_pipelines: Option<RaycastPipelines>,
+    raycast_task: Option<gfx::PipelineTask<RaycastPipelines>>,
     raycast_bind_group: wgpu::BindGroup,
+    raycast_layout: wgpu::BindGroupLayout,
+    /// Set when something the raycast bind group uses is replaced, see
+    /// `update_bind_groups`
+    raycast_bind_group_stale: bool,
+    screen_layout: wgpu::BindGroupLayout,
+    /// One per accumulation target
+    screen_bind_groups: [wgpu::BindGroup; 2],
+    raycast_pipeline_layout: Arc<wgpu::PipelineLayout>,
+    water_pipeline_layout: Arc<wgpu::PipelineLayout>,
     unpack_pipeline: wgpu::ComputePipeline,
+    unpack_pipeline_layout: wgpu::PipelineLayout,
     unpack_bind_group: wgpu::BindGroup,
+    unpack_layout: wgpu::BindGroupLayout,
+    shaders: gfx::ShaderManager,
+}
+
+/// Everything the raycast bind group points at, some of which the renderer doesn't own.
+struct RaycastResources<'a> {
+    brickmap_manager: &'a BrickmapManager,
+    camera_controller: &'a core::CameraController,
+    lighting: &'a core::Lighting,
+    settings_buffer: &'a wgpu::Buffer,
+    atmosphere_buffer: &'a wgpu::Buffer,
+    light_probes: &'a LightProbeGrid,
+    light_manager: &'a LightManager,
+    portal_manager: &'a PortalManager,
+    decal_manager: &'a DecalManager,
+    picker: &'a GpuPicker,
+    raycast_stats: &'a RaycastStatsReader,
+    sun_shadows: &'a SunShadowMaps,
+    blue_noise: &'a gfx::Texture,
 }
 
 impl BrickmapRenderer {
-    pub fn new(context: &gfx::Context, camera_controller: &core::CameraController) -> Result<Self> {
-        log::info!("Creating render shader...");
-        // TODO: Load the shader better
-        let shader_descriptor = wgpu::include_wgsl!("../../../assets/shaders/shader.wgsl");
-        let shader = context.device.create_shader_module(shader_descriptor);
-
-        log::info!("Creating render texture...");
-        let render_texture = gfx::TextureBuilder::new()
-            .with_size(context.size.width, context.size.height, 1)
-            .with_format(wgpu::TextureFormat::Rgba8Unorm)
-            .with_usage(
-                wgpu::TextureUsages::TEXTURE_BINDING
-                    | wgpu::TextureUsages::COPY_DST
-                    | wgpu::TextureUsages::STORAGE_BINDING,
+    pub fn new(
+        context: &gfx::Context,
+        camera_controller: &core::CameraController,
+        lighting: &core::Lighting,
+        budget: BrickmapBudget,
+    ) -> Result<Self> {
+        // Running out of memory here most likely means the budget is too big for the GPU,
+        // so the error gets passed back up for the app to deal with
+        context.error_scope("brickmap renderer creation", || {
+            Self::create(context, camera_controller, lighting, budget)
+        })?
+    }
+
+    fn create(
+        context: &gfx::Context,
+        camera_controller: &core::CameraController,
+        lighting: &core::Lighting,
+        budget: BrickmapBudget,
+    ) -> Result<Self> {
+        log::info!("Loading shaders...");
+        let shaders = gfx::ShaderManager::with_default_directory();
+
+        log::info!("Creating render textures...");
+        let render_size = Self::get_render_size(context, RenderSettings::default());
+        let render_textures = [
+            Self::create_render_texture(context, render_size)?,
+            Self::create_render_texture(context, render_size)?,
+        ];
+        let (raycast_depth, raycast_depth_view) =
+            Self::create_raycast_depth(context, render_textures[0].attributes.size);
+
+        log::info!("Creating water reflections...");
+        let water_reflections = WaterReflections::new(
+            context,
+            render_textures[0].attributes.size,
+            &raycast_depth_view,
+        )?;
+
+        log::info!("Creating auto exposure...");
+        let exposure = AutoExposure::new(context, &render_textures)?;
+
+        log::info!("Creating render settings...");
+        let settings = RenderSettings::default();
+        let atmosphere = Atmosphere::default();
+        let sky = Some(Sky::default());
+        let mut buffers = gfx::BulkBufferBuilder::new()
+            .set_usage(wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST)
+            .with_init_buffer_bm("Render Settings", &[RenderSettingsUniform::from(settings)])
+            .with_init_buffer_bm("Atmosphere", &[AtmosphereUniform::new(atmosphere, sky)])
+            .build(context);
+        let settings_buffer = buffers.remove(0);
+        let atmosphere_buffer = buffers.remove(0);
+        let settings_layout = gfx::BindGroupLayoutBuilder::new()
+            .with_label("Render Settings BGL")
+            .with_uniform_entry(wgpu::ShaderStages::FRAGMENT)
+            .with_entry(
+                wgpu::ShaderStages::FRAGMENT,
+                wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                None,
             )
-            .with_shader_visibility(wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE)
-            .build(context)?;
+            .with_ro_storage_entry(wgpu::ShaderStages::FRAGMENT)
+            .with_entry(
+                wgpu::ShaderStages::FRAGMENT,
+                wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                None,
+            )
+            .with_entry(
+                wgpu::ShaderStages::FRAGMENT,
+                wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                None,
+            )
+            .build(context);
+        let settings_bind_group = Self::create_settings_bind_group(
+            context,
+            &settings_layout,
+            &settings_buffer,
+            &raycast_depth_view,
+            &exposure,
+            &water_reflections,
+        )?;
 
         log::info!("Creating render pipeline...");
-        let render_pipeline =
+        let render_pipeline_layout =
             context
                 .device
-                .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-                    label: Some("Raycast Quad"),
-                    layout: Some(&context.device.create_pipeline_layout(
-                        &wgpu::PipelineLayoutDescriptor {
-                            label: Some("draw"),
-                            bind_group_layouts: &[&render_texture.bind_group_layout],
-                            push_constant_ranges: &[],
-                        },
-                    )),
-                    vertex: wgpu::VertexState {
-                        module: &shader,
-                        entry_point: "vertex",
-                        buffers: &[],
-                    },
-                    fragment: Some(wgpu::FragmentState {
-                        module: &shader,
-                        entry_point: "fragment",
-                        targets: &[Some(context.surface_config.format.into())],
-                    }),
-                    primitive: wgpu::PrimitiveState::default(),
-                    depth_stencil: None,
-                    multisample: wgpu::MultisampleState::default(),
-                    multiview: None,
+                .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: Some("draw"),
+                    bind_group_layouts: &[&render_textures[0].bind_group_layout, &settings_layout],
+                    push_constant_ranges: &[],
                 });
+        let render_pipeline = Self::create_render_pipeline(
+            context,
+            &render_pipeline_layout,
+            &shaders.load_or_embedded(&BLIT_SHADER),
+        );
 
         log::info!("Creating brickmap manager...");
         let brickmap_manager = BrickmapManager::new(
             context,
-            glam::uvec3(512, 64, 512),
-            usize::pow(64, 3),
-            u32::pow(2, 26),
-            4096,
-            8192,
+            budget.brickgrid_dims,
+            budget.brickmap_cache_size,
+            budget.shading_table_bucket_size,
+            budget.max_requested_brickmaps,
+            budget.max_uploaded_brickmaps,
         );
 
+        log::info!("Creating light probes...");
+        let light_probes =
+            LightProbeGrid::new(context, brickmap_manager.get_brickgrid_dims(), 4, 2048);
+
+        log::info!("Creating light manager...");
+        let pixel_count = (render_size.width * render_size.height) as usize;
+        let light_manager = LightManager::new(context, 1024, 8, pixel_count);
+
+        log::info!("Creating portal manager...");
+        let portal_manager = PortalManager::new(context);
+
+        log::info!("Creating decal manager...");
+        let decal_manager = DecalManager::new(context);
+
+        log::info!("Creating sun shadow maps...");
+        let sun_shadows = SunShadowMaps::new(context);
+
+        log::info!("Generating blue noise...");
+        let blue_noise = gfx::TextureBuilder::new()
+            .with_size(BLUE_NOISE_SIZE, BLUE_NOISE_SIZE, 1)
+            .with_format(wgpu::TextureFormat::Rgba8Unorm)
+            .with_shader_visibility(wgpu::ShaderStages::COMPUTE)
+            .build(context)?;
+        let noise: Vec<u8> = gfx::blue_noise(BLUE_NOISE_SIZE as usize, 0xB1DE)
+            .into_iter()
+            .flat_map(|v| [v, v, v, 255])
+            .collect();
+        blue_noise.update(context, &noise);
+
+        log::info!("Creating GPU picker...");
+        let picker = GpuPicker::new(context);
+
+        log::info!("Creating raycast stats...");
+        let raycast_stats = RaycastStatsReader::new(context, 3);
+
         log::info!("Creating compute pipelines...");
-        // TODO: Load the shader better
-        let cs_descriptor = wgpu::include_wgsl!("../../../assets/shaders/brickmap_upload.wgsl");
-        let cs = context.device.create_shader_module(cs_descriptor);
         let unpack_layout = gfx::BindGroupLayoutBuilder::new()
             .with_label("GPU Unpack BGL")
             .with_uniform_entry(wgpu::ShaderStages::COMPUTE)
@@ -91,131 +800,1278 @@ impl BrickmapRenderer {
             .with_rw_storage_entry(wgpu::ShaderStages::COMPUTE)
             .with_ro_storage_entry(wgpu::ShaderStages::COMPUTE)
             .with_ro_storage_entry(wgpu::ShaderStages::COMPUTE)
-            .build(context);
-        let unpack_bind_group = gfx::BindGroupBuilder::new()
-            .with_label("GPU Unpack BG")
-            .with_layout(&unpack_layout)
-            .with_entry(brickmap_manager.get_worldstate_buffer().as_entire_binding())
-            .with_entry(brickmap_manager.get_brickgrid_buffer().as_entire_binding())
-            .with_entry(brickmap_manager.get_brickmap_buffer().as_entire_binding())
-            .with_entry(brickmap_manager.get_shading_buffer().as_entire_binding())
-            .with_entry(
-                brickmap_manager
-                    .get_brickmap_unpack_buffer()
-                    .as_entire_binding(),
-            )
             .with_entry(
-                brickmap_manager
-                    .get_brickgrid_unpack_buffer()
-                    .as_entire_binding(),
+                wgpu::ShaderStages::COMPUTE,
+                wgpu::BindingType::StorageTexture {
+                    access: wgpu::StorageTextureAccess::WriteOnly,
+                    format: wgpu::TextureFormat::R32Uint,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                },
+                None,
             )
-            .build(context)?;
-        let unpack_pipeline =
+            .build(context);
+        let unpack_bind_group =
+            Self::create_unpack_bind_group(context, &unpack_layout, &brickmap_manager)?;
+        let unpack_pipeline_layout =
             context
                 .device
-                .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-                    label: Some("GPU Unpack Pipeline"),
-                    layout: Some(&context.device.create_pipeline_layout(
-                        &wgpu::PipelineLayoutDescriptor {
-                            label: Some("GPU Unpack PL"),
-                            bind_group_layouts: &[&unpack_layout],
-                            push_constant_ranges: &[],
-                        },
-                    )),
-                    module: &cs,
-                    entry_point: "compute",
+                .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: Some("GPU Unpack PL"),
+                    bind_group_layouts: &[&unpack_layout],
+                    push_constant_ranges: &[],
                 });
+        let unpack_pipeline = Self::create_unpack_pipeline(
+            context,
+            &unpack_pipeline_layout,
+            &shaders.load_or_embedded(&UNPACK_SHADER),
+        );
 
-        // TODO: Load the shader better
-        let cs_descriptor = wgpu::include_wgsl!("../../../assets/shaders/voxel_volume.wgsl");
-        let cs = context.device.create_shader_module(cs_descriptor);
         let raycast_layout = gfx::BindGroupLayoutBuilder::new()
             .with_label("Voxel Raycast BGL")
+            .with_uniform_entry(wgpu::ShaderStages::COMPUTE)
+            .with_rw_storage_entry(wgpu::ShaderStages::COMPUTE)
+            .with_ro_storage_entry(wgpu::ShaderStages::COMPUTE)
+            .with_ro_storage_entry(wgpu::ShaderStages::COMPUTE)
+            .with_rw_storage_entry(wgpu::ShaderStages::COMPUTE)
+            .with_uniform_entry(wgpu::ShaderStages::COMPUTE)
+            .with_uniform_entry(wgpu::ShaderStages::COMPUTE)
+            .with_rw_storage_entry(wgpu::ShaderStages::COMPUTE)
+            .with_uniform_entry(wgpu::ShaderStages::COMPUTE)
+            .with_ro_storage_entry(wgpu::ShaderStages::COMPUTE)
+            .with_uniform_entry(wgpu::ShaderStages::COMPUTE)
+            .with_rw_storage_entry(wgpu::ShaderStages::COMPUTE)
+            .with_entry(
+                wgpu::ShaderStages::COMPUTE,
+                wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Uint,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                None,
+            )
+            .with_uniform_entry(wgpu::ShaderStages::COMPUTE)
+            .with_uniform_entry(wgpu::ShaderStages::COMPUTE)
             .with_entry(
                 wgpu::ShaderStages::COMPUTE,
                 wgpu::BindingType::StorageTexture {
                     access: wgpu::StorageTextureAccess::WriteOnly,
-                    format: render_texture.attributes.format,
+                    format: wgpu::TextureFormat::Rgba32Sint,
                     view_dimension: wgpu::TextureViewDimension::D2,
                 },
                 None,
             )
             .with_uniform_entry(wgpu::ShaderStages::COMPUTE)
-            .with_rw_storage_entry(wgpu::ShaderStages::COMPUTE)
-            .with_ro_storage_entry(wgpu::ShaderStages::COMPUTE)
-            .with_ro_storage_entry(wgpu::ShaderStages::COMPUTE)
-            .with_rw_storage_entry(wgpu::ShaderStages::COMPUTE)
             .with_uniform_entry(wgpu::ShaderStages::COMPUTE)
+            .with_entry(
+                wgpu::ShaderStages::COMPUTE,
+                wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Uint,
+                    view_dimension: wgpu::TextureViewDimension::D3,
+                    multisampled: false,
+                },
+                None,
+            )
+            .with_uniform_entry(wgpu::ShaderStages::COMPUTE)
+            .with_entry(
+                wgpu::ShaderStages::COMPUTE,
+                wgpu::BindingType::StorageTexture {
+                    access: wgpu::StorageTextureAccess::WriteOnly,
+                    format: wgpu::TextureFormat::R32Float,
+                    view_dimension: wgpu::TextureViewDimension::D2Array,
+                },
+                None,
+            )
+            .with_uniform_entry(wgpu::ShaderStages::COMPUTE)
+            .with_entry(
+                wgpu::ShaderStages::COMPUTE,
+                wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                None,
+            )
+            .with_uniform_entry(wgpu::ShaderStages::COMPUTE)
+            .with_entry(
+                wgpu::ShaderStages::COMPUTE,
+                wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Uint,
+                    view_dimension: wgpu::TextureViewDimension::D3,
+                    multisampled: false,
+                },
+                None,
+            )
             .build(context);
-        let raycast_bind_group = gfx::BindGroupBuilder::new()
-            .with_label("Voxel Raycast BG")
-            .with_layout(&raycast_layout)
-            .with_entry(wgpu::BindingResource::TextureView(&render_texture.view))
-            .with_entry(brickmap_manager.get_worldstate_buffer().as_entire_binding())
-            .with_entry(brickmap_manager.get_brickgrid_buffer().as_entire_binding())
-            .with_entry(brickmap_manager.get_brickmap_buffer().as_entire_binding())
-            .with_entry(brickmap_manager.get_shading_buffer().as_entire_binding())
-            .with_entry(brickmap_manager.get_feedback_buffer().as_entire_binding())
-            .with_entry(camera_controller.get_buffer().as_entire_binding())
-            .build(context)?;
-        let raycast_pipeline =
-            context
-                .device
-                .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-                    label: Some("Voxel Raycast Pipeline"),
-                    layout: Some(&context.device.create_pipeline_layout(
-                        &wgpu::PipelineLayoutDescriptor {
-                            label: Some("Voxel Raycast PL"),
-                            bind_group_layouts: &[&raycast_layout],
-                            push_constant_ranges: &[],
-                        },
-                    )),
-                    module: &cs,
-                    entry_point: "compute",
-                });
+        let raycast_bind_group = Self::create_raycast_bind_group(
+            context,
+            &raycast_layout,
+            &RaycastResources {
+                brickmap_manager: &brickmap_manager,
+                camera_controller,
+                lighting,
+                settings_buffer: &settings_buffer,
+                atmosphere_buffer: &atmosphere_buffer,
+                light_probes: &light_probes,
+                light_manager: &light_manager,
+                portal_manager: &portal_manager,
+                decal_manager: &decal_manager,
+                picker: &picker,
+                raycast_stats: &raycast_stats,
+                sun_shadows: &sun_shadows,
+                blue_noise: &blue_noise,
+            },
+        )?;
+        let screen_layout = gfx::BindGroupLayoutBuilder::new()
+            .with_label("Voxel Raycast Screen BGL")
+            .with_entry(
+                wgpu::ShaderStages::COMPUTE,
+                wgpu::BindingType::StorageTexture {
+                    access: wgpu::StorageTextureAccess::WriteOnly,
+                    format: render_textures[0].attributes.format,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                },
+                None,
+            )
+            .with_rw_storage_entry(wgpu::ShaderStages::COMPUTE)
+            .with_entry(
+                wgpu::ShaderStages::COMPUTE,
+                wgpu::BindingType::StorageTexture {
+                    access: wgpu::StorageTextureAccess::WriteOnly,
+                    format: wgpu::TextureFormat::Rgba32Float,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                },
+                None,
+            )
+            .with_entry(
+                wgpu::ShaderStages::COMPUTE,
+                wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                None,
+            )
+            .build(context);
+        let screen_bind_groups = Self::create_screen_bind_groups(
+            context,
+            &screen_layout,
+            &render_textures,
+            &raycast_depth_view,
+            &light_manager,
+        )?;
+        let raycast_pipeline_layout = Arc::new(context.device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor {
+                label: Some("Voxel Raycast PL"),
+                bind_group_layouts: &[&raycast_layout, &screen_layout],
+                push_constant_ranges: &[],
+            },
+        ));
+        let water_pipeline_layout = Arc::new(context.device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor {
+                label: Some("Water Reflections PL"),
+                bind_group_layouts: &[&raycast_layout, water_reflections.get_layout()],
+                push_constant_ranges: &[],
+            },
+        ));
+        let raycast_code = shaders.load_or_embedded(&RAYCAST_SHADER);
+        let layout = raycast_pipeline_layout.clone();
+        let water_layout = water_pipeline_layout.clone();
+        let raycast_task = gfx::PipelineTask::spawn(context, "voxel raycast", move |device| {
+            RaycastPipelines::new(device, &layout, &water_layout, &raycast_code)
+        })?;
+
+        log::info!("Creating debug lines...");
+        let debug_lines =
+            DebugLines::new(context, camera_controller.get_buffer(), &raycast_depth_view)?;
+
+        log::info!("Creating gizmo...");
+        let gizmo = Gizmo::new(context, camera_controller.get_buffer(), &raycast_depth_view)?;
+
+        log::info!("Creating particle system...");
+        let particles = ParticleSystem::new(
+            context,
+            camera_controller.get_buffer(),
+            &raycast_depth_view,
+            &sun_shadows,
+            4096,
+        )?;
 
         Ok(Self {
             clear_color: wgpu::Color::BLACK,
-            render_texture,
+            settings,
+            atmosphere,
+            atmosphere_buffer,
+            sky,
+            settings_buffer,
+            settings_layout,
+            settings_bind_group,
+            render_textures,
+            accumulation: Accumulation::default(),
             render_pipeline,
+            render_pipeline_layout,
             brickmap_manager,
-            raycast_pipeline,
+            budget,
+            light_probes,
+            light_manager,
+            portal_manager,
+            decal_manager,
+            picker,
+            raycast_stats,
+            raycast_depth,
+            raycast_depth_view,
+            debug_lines,
+            gizmo,
+            particles,
+            sun_shadows,
+            water_reflections,
+            exposure,
+            profiler: gfx::GpuProfiler::new(context, 16, 3),
+            blue_noise,
+            raycast_pipelines: None,
+            raycast_task: Some(raycast_task),
             raycast_bind_group,
+            raycast_layout,
+            raycast_bind_group_stale: false,
+            screen_layout,
+            screen_bind_groups,
+            raycast_pipeline_layout,
+            water_pipeline_layout,
             unpack_pipeline,
+            unpack_pipeline_layout,
             unpack_bind_group,
+            unpack_layout,
+            shaders,
         })
     }
-}
 
-impl VoxelRenderer for BrickmapRenderer {
-    fn render(&self, context: &gfx::Context) -> Result<()> {
-        let frame = context.surface.get_current_texture()?;
-        let view = frame
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
+    fn create_unpack_bind_group(
+        context: &gfx::Context,
+        layout: &wgpu::BindGroupLayout,
+        brickmap_manager: &BrickmapManager,
+    ) -> Result<wgpu::BindGroup> {
+        gfx::BindGroupBuilder::new()
+            .with_label("GPU Unpack BG")
+            .with_layout(layout)
+            .with_entry(brickmap_manager.get_worldstate_buffer().as_entire_binding())
+            .with_entry(brickmap_manager.get_brickgrid_buffer().as_entire_binding())
+            .with_entry(brickmap_manager.get_brickmap_buffer().as_entire_binding())
+            .with_entry(brickmap_manager.get_shading_buffer().as_entire_binding())
+            .with_entry(
+                brickmap_manager
+                    .get_brickmap_unpack_buffer()
+                    .as_entire_binding(),
+            )
+            .with_entry(
+                brickmap_manager
+                    .get_brickgrid_unpack_buffer()
+                    .as_entire_binding(),
+            )
+            .with_entry(wgpu::BindingResource::TextureView(
+                brickmap_manager.get_detail_view(),
+            ))
+            .build(context)
+    }
+
+    fn create_raycast_bind_group(
+        context: &gfx::Context,
+        layout: &wgpu::BindGroupLayout,
+        resources: &RaycastResources,
+    ) -> Result<wgpu::BindGroup> {
+        let brickmap_manager = resources.brickmap_manager;
+        gfx::BindGroupBuilder::new()
+            .with_label("Voxel Raycast BG")
+            .with_layout(layout)
+            .with_entry(brickmap_manager.get_worldstate_buffer().as_entire_binding())
+            .with_entry(brickmap_manager.get_brickgrid_buffer().as_entire_binding())
+            .with_entry(brickmap_manager.get_brickmap_buffer().as_entire_binding())
+            .with_entry(brickmap_manager.get_shading_buffer().as_entire_binding())
+            .with_entry(brickmap_manager.get_feedback_buffer().as_entire_binding())
+            .with_entry(resources.camera_controller.get_buffer().as_entire_binding())
+            .with_entry(resources.settings_buffer.as_entire_binding())
+            .with_entry(
+                resources
+                    .light_probes
+                    .get_probe_buffer()
+                    .as_entire_binding(),
+            )
+            .with_entry(
+                resources
+                    .light_probes
+                    .get_state_buffer()
+                    .as_entire_binding(),
+            )
+            .with_entry(
+                resources
+                    .light_manager
+                    .get_light_buffer()
+                    .as_entire_binding(),
+            )
+            .with_entry(
+                resources
+                    .light_manager
+                    .get_state_buffer()
+                    .as_entire_binding(),
+            )
+            .with_entry(resources.raycast_stats.get_buffer().as_entire_binding())
+            .with_entry(wgpu::BindingResource::TextureView(
+                brickmap_manager.get_detail_view(),
+            ))
+            .with_entry(resources.portal_manager.get_buffer().as_entire_binding())
+            .with_entry(resources.picker.get_state_buffer().as_entire_binding())
+            .with_entry(wgpu::BindingResource::TextureView(
+                resources.picker.get_view(),
+            ))
+            .with_entry(resources.decal_manager.get_buffer().as_entire_binding())
+            .with_entry(resources.atmosphere_buffer.as_entire_binding())
+            .with_entry(wgpu::BindingResource::TextureView(
+                brickmap_manager.get_brickgrid_mip_view(),
+            ))
+            .with_entry(resources.lighting.get_buffer().as_entire_binding())
+            .with_entry(wgpu::BindingResource::TextureView(
+                resources.sun_shadows.get_view(),
+            ))
+            .with_entry(resources.sun_shadows.get_buffer().as_entire_binding())
+            .with_entry(wgpu::BindingResource::TextureView(
+                &resources.blue_noise.view,
+            ))
+            .with_entry(brickmap_manager.get_material_buffer().as_entire_binding())
+            .with_entry(wgpu::BindingResource::TextureView(
+                brickmap_manager.get_volume_atlas_view(),
+            ))
+            .build(context)
+    }
+
+    /// Points the bind groups at a new shading table buffer if it's grown. The unpack
+    /// pass writes the table, so it has to see the new one straight away, while the
+    /// raycast's bind group waits for `update_bind_groups`.
+    fn check_shading_table_resized(&mut self, context: &gfx::Context) -> Result<()> {
+        if self.brickmap_manager.take_shading_table_resized() {
+            self.unpack_bind_group = Self::create_unpack_bind_group(
+                context,
+                &self.unpack_layout,
+                &self.brickmap_manager,
+            )?;
+            self.raycast_bind_group_stale = true;
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the raycast bind group if any of the buffers in it have been replaced,
+    /// e.g. by the shading table growing. Has to be called before `render`, as some of
+    /// what it binds lives outside the renderer.
+    pub fn update_bind_groups(
+        &mut self,
+        context: &gfx::Context,
+        camera_controller: &core::CameraController,
+        lighting: &core::Lighting,
+    ) -> Result<()> {
+        if !self.raycast_bind_group_stale {
+            return Ok(());
+        }
+
+        self.raycast_bind_group = Self::create_raycast_bind_group(
+            context,
+            &self.raycast_layout,
+            &RaycastResources {
+                brickmap_manager: &self.brickmap_manager,
+                camera_controller,
+                lighting,
+                settings_buffer: &self.settings_buffer,
+                atmosphere_buffer: &self.atmosphere_buffer,
+                light_probes: &self.light_probes,
+                light_manager: &self.light_manager,
+                portal_manager: &self.portal_manager,
+                decal_manager: &self.decal_manager,
+                picker: &self.picker,
+                raycast_stats: &self.raycast_stats,
+                sun_shadows: &self.sun_shadows,
+                blue_noise: &self.blue_noise,
+            },
+        )?;
+        self.raycast_bind_group_stale = false;
+        Ok(())
+    }
+
+    /// Half floats so the accumulated average doesn't get stuck rounding to the same
+    /// 8-bit value.
+    fn create_render_texture(context: &gfx::Context, size: wgpu::Extent3d) -> Result<gfx::Texture> {
+        gfx::TextureBuilder::new()
+            .with_size(size.width, size.height, 1)
+            .with_format(wgpu::TextureFormat::Rgba16Float)
+            .with_usage(
+                wgpu::TextureUsages::TEXTURE_BINDING
+                    | wgpu::TextureUsages::COPY_DST
+                    | wgpu::TextureUsages::STORAGE_BINDING,
+            )
+            .with_shader_visibility(wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE)
+            .build(context)
+    }
+
+    /// The window's size scaled by the render scale.
+    fn get_render_size(context: &gfx::Context, settings: RenderSettings) -> wgpu::Extent3d {
+        let scale = settings.render_scale.clamp(0.1, 1.0);
+        wgpu::Extent3d {
+            width: ((context.size.width as f32 * scale) as u32).max(1),
+            height: ((context.size.height as f32 * scale) as u32).max(1),
+            depth_or_array_layers: 1,
+        }
+    }
+
+    /// Distance along each primary ray to whatever it hit, for anything drawn on top of
+    /// the raycast image. The other channels describe the surface that was hit for
+    /// outlines: its octahedral encoded normal, then an id for the voxel
+    fn create_raycast_depth(
+        context: &gfx::Context,
+        size: wgpu::Extent3d,
+    ) -> (wgpu::Texture, wgpu::TextureView) {
+        let texture = context.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Raycast Depth"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba32Float,
+            usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        (texture, view)
+    }
+
+    fn create_settings_bind_group(
+        context: &gfx::Context,
+        layout: &wgpu::BindGroupLayout,
+        settings_buffer: &wgpu::Buffer,
+        depth_view: &wgpu::TextureView,
+        exposure: &AutoExposure,
+        water_reflections: &WaterReflections,
+    ) -> Result<wgpu::BindGroup> {
+        let reflections = water_reflections.get_texture();
+        gfx::BindGroupBuilder::new()
+            .with_label("Render Settings BG")
+            .with_layout(layout)
+            .with_entry(settings_buffer.as_entire_binding())
+            .with_entry(wgpu::BindingResource::TextureView(depth_view))
+            .with_entry(exposure.get_buffer().as_entire_binding())
+            .with_entry(wgpu::BindingResource::TextureView(&reflections.view))
+            .with_entry(wgpu::BindingResource::Sampler(&reflections.sampler))
+            .build(context)
+    }
+
+    /// The raycast bindings that depend on the screen size, kept in their own group so
+    /// they can be swapped out on resize without touching the rest. There's one for each
+    /// render texture being the target, with the other one as the accumulation history.
+    fn create_screen_bind_groups(
+        context: &gfx::Context,
+        layout: &wgpu::BindGroupLayout,
+        render_textures: &[gfx::Texture; 2],
+        depth_view: &wgpu::TextureView,
+        light_manager: &LightManager,
+    ) -> Result<[wgpu::BindGroup; 2]> {
+        let create = |target: &gfx::Texture, history: &gfx::Texture| {
+            gfx::BindGroupBuilder::new()
+                .with_label("Voxel Raycast Screen BG")
+                .with_layout(layout)
+                .with_entry(wgpu::BindingResource::TextureView(&target.view))
+                .with_entry(light_manager.get_reservoir_buffer().as_entire_binding())
+                .with_entry(wgpu::BindingResource::TextureView(depth_view))
+                .with_entry(wgpu::BindingResource::TextureView(&history.view))
+                .build(context)
+        };
+        Ok([
+            create(&render_textures[0], &render_textures[1])?,
+            create(&render_textures[1], &render_textures[0])?,
+        ])
+    }
+
+    fn create_render_pipeline(
+        context: &gfx::Context,
+        layout: &wgpu::PipelineLayout,
+        code: &str,
+    ) -> wgpu::RenderPipeline {
+        let shader = context
+            .device
+            .create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some(BLIT_SHADER.name),
+                source: wgpu::ShaderSource::Wgsl(code.into()),
+            });
+        context
+            .device
+            .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("Raycast Quad"),
+                layout: Some(layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vertex",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fragment",
+                    targets: &[Some(context.surface_config.format.into())],
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            })
+    }
+
+    fn create_unpack_pipeline(
+        context: &gfx::Context,
+        layout: &wgpu::PipelineLayout,
+        code: &str,
+    ) -> wgpu::ComputePipeline {
+        let cs = context
+            .device
+            .create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some(UNPACK_SHADER.name),
+                source: wgpu::ShaderSource::Wgsl(code.into()),
+            });
+        context
+            .device
+            .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some("GPU Unpack Pipeline"),
+                layout: Some(layout),
+                module: &cs,
+                entry_point: "compute",
+            })
+    }
+
+    /// Rebuilds the pipelines of any shaders that changed on disk. A shader that fails to
+    /// load or build is logged and the old pipeline is kept.
+    fn reload_shaders(&mut self, context: &gfx::Context) {
+        for name in self.shaders.take_changed() {
+            let source = match name.as_str() {
+                n if n == BLIT_SHADER.name => BLIT_SHADER,
+                n if n == UNPACK_SHADER.name => UNPACK_SHADER,
+                n if n == RAYCAST_SHADER.name => RAYCAST_SHADER,
+                _ => continue,
+            };
+            let code = match self.shaders.load(&source) {
+                Ok(code) => code,
+                Err(e) => {
+                    log::error!("Failed to reload {}: {:#}", name, e);
+                    continue;
+                }
+            };
+
+            // The shader is valid by itself, but it can still disagree with our layouts.
+            // Building here rather than in the background lets us catch that, and only
+            // swap the pipeline in if it built
+            let label = format!("reloading {}", name);
+            let result = match source.name {
+                n if n == BLIT_SHADER.name => context
+                    .error_scope(&label, || {
+                        Self::create_render_pipeline(context, &self.render_pipeline_layout, &code)
+                    })
+                    .map(|pipeline| self.render_pipeline = pipeline),
+                n if n == UNPACK_SHADER.name => context
+                    .error_scope(&label, || {
+                        Self::create_unpack_pipeline(context, &self.unpack_pipeline_layout, &code)
+                    })
+                    .map(|pipeline| self.unpack_pipeline = pipeline),
+                _ => context
+                    .error_scope(&label, || {
+                        RaycastPipelines::new(
+                            &context.device,
+                            &self.raycast_pipeline_layout,
+                            &self.water_pipeline_layout,
+                            &code,
+                        )
+                    })
+                    .map(|pipelines| {
+                        // Anything still building from before the change is out of date
+                        self.raycast_pipelines = Some(pipelines);
+                        self.raycast_task = None;
+                        self.set_settings(context, self.settings);
+                    }),
+            };
+            match result {
+                Ok(()) => log::info!("Reloaded {}", name),
+                Err(e) => log::error!("Failed to reload {}: {}", name, e),
+            }
+        }
+    }
+
+    pub fn get_brickmap_manager(&self) -> &BrickmapManager {
+        &self.brickmap_manager
+    }
+
+    pub fn get_brickmap_manager_mut(&mut self) -> &mut BrickmapManager {
+        &mut self.brickmap_manager
+    }
+
+    pub fn get_light_manager(&self) -> &LightManager {
+        &self.light_manager
+    }
+
+    pub fn get_light_manager_mut(&mut self) -> &mut LightManager {
+        &mut self.light_manager
+    }
+
+    pub fn get_portal_manager(&self) -> &PortalManager {
+        &self.portal_manager
+    }
+
+    pub fn get_portal_manager_mut(&mut self) -> &mut PortalManager {
+        &mut self.portal_manager
+    }
+
+    pub fn get_decal_manager(&self) -> &DecalManager {
+        &self.decal_manager
+    }
+
+    pub fn get_decal_manager_mut(&mut self) -> &mut DecalManager {
+        &mut self.decal_manager
+    }
+
+    /// Rebuilds the debug lines around a position in brick units, if they're enabled.
+    pub fn update_debug_lines(
+        &mut self,
+        context: &gfx::Context,
+        world: &mut WorldManager,
+        position: glam::Vec3,
+    ) {
+        if self.settings.debug_lines {
+            self.debug_lines.update(context, world, position);
+        }
+    }
+
+    pub fn get_gizmo(&self) -> &Gizmo {
+        &self.gizmo
+    }
+
+    pub fn get_gizmo_mut(&mut self) -> &mut Gizmo {
+        &mut self.gizmo
+    }
+
+    /// Moves the sun's shadow cascades to follow the camera. Call once per frame.
+    pub fn update_sun_shadows(
+        &mut self,
+        context: &gfx::Context,
+        sun: core::SunLight,
+        camera_position: glam::Vec3,
+    ) {
+        // The cascades are looked up by surfaces in the volume
+        let camera_position = self.brickmap_manager.to_volume(camera_position);
+        self.sun_shadows.update(context, sun, camera_position);
+    }
+
+    /// Moves the ambient particles along and keeps them around a position in brick units,
+    /// if they're enabled.
+    pub fn update_particles(
+        &mut self,
+        context: &gfx::Context,
+        dt: &Duration,
+        position: glam::Vec3,
+    ) {
+        if let Some(kind) = self.settings.particles {
+            let seed = self.brickmap_manager.get_frame_seed();
+            self.particles.update(context, dt, position, kind, seed);
+        }
+    }
+
+    /// Sets the random seed for this frame's shaders. Call once per frame, before
+    /// anything else is updated.
+    pub fn set_frame_seed(&mut self, context: &gfx::Context, seed: u32) {
+        self.brickmap_manager.set_frame_seed(context, seed);
+    }
+
+    /// Moves the volume so its (0, 0, 0) sits at `origin` in the world (in bricks), see
+    /// `BrickmapManager::set_origin`.
+    pub fn set_world_origin(&mut self, context: &gfx::Context, origin: glam::Vec3) {
+        self.brickmap_manager.set_origin(context, origin);
+        self.reset_accumulation();
+    }
+
+    pub fn get_world_origin(&self) -> glam::Vec3 {
+        self.brickmap_manager.get_origin()
+    }
+
+    /// Places a model over the terrain as its own volume, see
+    /// `BrickmapManager::add_volume`.
+    pub fn add_volume(
+        &mut self,
+        context: &gfx::Context,
+        model: &VolumeModel,
+        transform: VolumeTransform,
+    ) -> Result<VolumeHandle> {
+        let handle = self
+            .brickmap_manager
+            .add_volume(context, model, transform)?;
+        self.reset_accumulation();
+        Ok(handle)
+    }
+
+    /// Returns false if the volume had already been removed.
+    pub fn remove_volume(&mut self, context: &gfx::Context, handle: VolumeHandle) -> bool {
+        let removed = self.brickmap_manager.remove_volume(context, handle);
+        if removed {
+            self.reset_accumulation();
+        }
+        removed
+    }
+
+    pub fn get_volume_transform(&self, handle: VolumeHandle) -> Option<VolumeTransform> {
+        self.brickmap_manager.get_volume_transform(handle)
+    }
+
+    /// Moves or rotates a volume, see `BrickmapManager::set_volume_transform`. Returns
+    /// false if the volume had been removed.
+    pub fn set_volume_transform(
+        &mut self,
+        context: &gfx::Context,
+        handle: VolumeHandle,
+        transform: VolumeTransform,
+    ) -> bool {
+        let moved = self
+            .brickmap_manager
+            .set_volume_transform(context, handle, transform);
+        if moved {
+            self.reset_accumulation();
+        }
+        moved
+    }
+
+    pub fn get_volume_count(&self) -> usize {
+        self.brickmap_manager.get_volume_count()
+    }
+
+    /// Scrolls the brickgrid to stay around `position` (brick space), see
+    /// `BrickmapManager::set_focus`. Call once per frame while streaming.
+    pub fn set_focus(&mut self, context: &gfx::Context, position: glam::Vec3) {
+        if self.brickmap_manager.set_focus(context, position) {
+            self.reset_accumulation();
+        }
+    }
+
+    /// Requests the bricks in front of the camera that rays are likely to hit soon. Call
+    /// once per frame while streaming.
+    pub fn prefetch_brickmaps(
+        &mut self,
+        world: &mut WorldManager,
+        camera_controller: &core::CameraController,
+    ) {
+        // Bricks past the LOD distance only need a colour, which rays load quickly enough
+        let mut distance = self.settings.prefetch_distance;
+        if self.settings.lod_distance > 0.0 {
+            distance = distance.min(self.settings.lod_distance);
+        }
+        self.brickmap_manager.prefetch(
+            world,
+            camera_controller.get_view_projection(),
+            camera_controller.get_position(),
+            distance,
+            MAX_PREFETCH_COUNT,
+        );
+    }
+
+    /// Moves the exposure towards its target, or sets it if it's manual. Call once per
+    /// frame.
+    pub fn update_exposure(&mut self, context: &gfx::Context, dt: &Duration) {
+        self.exposure.update(context, dt, self.settings.exposure);
+    }
+
+    /// Picks the voxel under a window pixel on the GPU, so it's exactly what was drawn.
+    /// The result can be collected with `take_pick_result` a frame or two later.
+    pub fn request_pick(&mut self, context: &gfx::Context, cursor: glam::UVec2) {
+        // The cursor is in window pixels, which aren't the same as traced pixels when the
+        // render scale is below 1
+        let size = self.render_textures[0].attributes.size;
+        let scale = glam::vec2(size.width as f32, size.height as f32)
+            / glam::vec2(context.size.width as f32, context.size.height as f32);
+        let cursor = (cursor.as_vec2() * scale)
+            .as_uvec2()
+            .min(glam::uvec2(size.width, size.height) - 1);
+        self.picker.request(context, cursor, size.height);
+    }
+
+    /// Latest GPU pass timings, if profiling is enabled and supported.
+    pub fn get_gpu_timings(&self) -> Option<&gfx::GpuTimings> {
+        self.profiler
+            .as_ref()
+            .filter(|_| self.settings.gpu_profiling)
+            .and_then(|p| p.get_latest())
+    }
+
+    pub fn take_pick_result(&mut self) -> Option<PickResult> {
+        self.picker.take_result()
+    }
+
+    /// Latest raycast stats read back from the GPU, if they're enabled.
+    pub fn get_raycast_stats(&self) -> Option<RaycastStats> {
+        self.raycast_stats
+            .get_latest()
+            .filter(|_| self.settings.raycast_stats)
+    }
+
+    pub fn get_settings(&self) -> RenderSettings {
+        self.settings
+    }
+
+    pub fn set_settings(&mut self, context: &gfx::Context, settings: RenderSettings) {
+        self.settings = settings;
+        self.gizmo.set_style(settings.ui_scale, settings.ui_theme);
+        self.reset_accumulation();
+        context.queue.write_buffer(
+            &self.settings_buffer,
+            0,
+            bytemuck::cast_slice(&[RenderSettingsUniform::from(settings)]),
+        );
+    }
+
+    /// Throws away the accumulated history, for changes to the scene that the renderer
+    /// can't see for itself.
+    pub fn reset_accumulation(&mut self) {
+        self.accumulation.resets = self.accumulation.resets.max(1);
+    }
+
+    /// Swaps the render textures and counts the last frame towards the accumulation,
+    /// starting over if the camera has moved. Call once per frame before rendering.
+    pub fn update_accumulation(
+        &mut self,
+        context: &gfx::Context,
+        camera_controller: &core::CameraController,
+    ) {
+        let accumulation = &mut self.accumulation;
+        accumulation.target = 1 - accumulation.target;
+
+        let view_projection = camera_controller.get_view_projection();
+        let reset = accumulation.resets > 0
+            || view_projection != accumulation.view_projection
+            || self.raycast_pipelines.is_none();
+        accumulation.resets = accumulation.resets.saturating_sub(1);
+        accumulation.view_projection = view_projection;
+        accumulation.frames = match reset || !self.settings.temporal_accumulation {
+            true => 0,
+            false => accumulation.frames.saturating_add(1),
+        };
+
+        let uniform = RenderSettingsUniform {
+            accumulated_frames: accumulation.frames,
+            emissive_bounce: (self.settings.emissive_bounce
+                && self.brickmap_manager.has_emissive_materials())
+                as u32,
+            ..self.settings.into()
+        };
+        context
+            .queue
+            .write_buffer(&self.settings_buffer, 0, bytemuck::cast_slice(&[uniform]));
+    }
+
+    pub fn get_atmosphere(&self) -> Atmosphere {
+        self.atmosphere
+    }
+
+    pub fn set_atmosphere(&mut self, context: &gfx::Context, atmosphere: Atmosphere) {
+        self.atmosphere = atmosphere;
+        self.write_atmosphere(context);
+    }
+
+    pub fn get_sky(&self) -> Option<Sky> {
+        self.sky
+    }
+
+    /// Changes the procedural sky, or with `None` goes back to the atmosphere's flat sky
+    /// colour. Cheap enough to call every frame, as long as it's only when it changes.
+    pub fn set_sky(&mut self, context: &gfx::Context, sky: Option<Sky>) {
+        self.sky = sky;
+        self.write_atmosphere(context);
+    }
+
+    fn write_atmosphere(&mut self, context: &gfx::Context) {
+        self.reset_accumulation();
+        context.queue.write_buffer(
+            &self.atmosphere_buffer,
+            0,
+            bytemuck::cast_slice(&[AtmosphereUniform::new(self.atmosphere, self.sky)]),
+        );
+    }
+
+    /// Synchronously loads the bricks within `radius` bricks of `position` (in bricks, in
+    /// the world), drawing a loading bar as it goes. Run before the first frame so we don't
+    /// start out looking at empty space.
+    pub fn prewarm(
+        &mut self,
+        context: &gfx::Context,
+        world: &mut WorldManager,
+        position: glam::Vec3,
+        radius: u32,
+    ) -> Result<()> {
+        log::info!("Prewarming bricks around {}...", position);
+        self.brickmap_manager.set_world(context, world);
+        let grid_min = self.brickmap_manager.get_grid_origin();
+        let grid_max = grid_min + self.brickmap_manager.get_brickgrid_dims().as_ivec3();
+        let center = self.brickmap_manager.to_volume(position).floor().as_ivec3();
+        let radius = radius as i32;
+
+        // We load whole columns so terrain above and below the camera is there too
+        let min = glam::ivec3(center.x - radius, grid_min.y, center.z - radius).max(grid_min);
+        let max =
+            glam::ivec3(center.x + radius + 1, grid_max.y, center.z + radius + 1).min(grid_max);
+        let slice_count = (max.x - min.x).max(1);
+
+        let mut loaded = 0;
+        for (i, x) in (min.x..max.x).enumerate() {
+            loaded += self.brickmap_manager.load_region(
+                world,
+                glam::ivec3(x, min.y, min.z),
+                glam::ivec3(x + 1, max.y, max.z),
+            );
+            self.draw_loading_frame(context, (i + 1) as f32 / slice_count as f32)?;
+        }
+
+        // Anything over the per-frame upload limits is still staged
+        while self.brickmap_manager.has_staged_uploads() {
+            self.draw_loading_frame(context, 1.0)?;
+        }
+
+        log::info!("Prewarmed {} bricks", loaded);
+        self.set_settings(context, self.settings);
+        Ok(())
+    }
+
+    /// Are the raycast pipelines still being built? The loading screen is shown until
+    /// they're done.
+    pub fn is_loading(&self) -> bool {
+        self.raycast_pipelines.is_none()
+    }
+
+    /// Picks up the raycast pipelines if they've finished building.
+    fn poll_pipelines(&mut self, context: &gfx::Context) -> Result<()> {
+        let Some(task) = &self.raycast_task else {
+            return Ok(());
+        };
+
+        if let Some(pipelines) = task.poll()? {
+            self.raycast_pipelines = Some(pipelines);
+            self.raycast_task = None;
+            self.set_settings(context, self.settings);
+        }
+        Ok(())
+    }
+
+    /// Uploads and unpacks any staged bricks, and draws the loading screen.
+    fn draw_loading_frame(&mut self, context: &gfx::Context, progress: f32) -> Result<()> {
+        self.poll_pipelines(context)?;
+        self.brickmap_manager.upload_unpack_buffers(context);
+        self.check_shading_table_resized(context)?;
+
+        // Only the blit reads the progress, and it never reaches 1.0 here so the loading
+        // screen stays up until prewarming is done
+        let uniform = RenderSettingsUniform {
+            loading_progress: progress.min(0.999),
+            ..self.settings.into()
+        };
+        context
+            .queue
+            .write_buffer(&self.settings_buffer, 0, bytemuck::cast_slice(&[uniform]));
 
         let mut encoder = context
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+        self.encode_unpack_pass(&mut encoder);
+
+        // Without a surface we still want the uploads to happen, we just can't show them
+        let frame = context.get_current_frame()?;
+        if let Some(frame) = &frame {
+            self.encode_blit_pass(&mut encoder, &frame.view);
+        }
+
+        context.queue.submit(Some(encoder.finish()));
+        if let Some(frame) = frame {
+            frame.present();
+        }
+        Ok(())
+    }
+
+    /// The passes `render` will encode next frame with the current settings, and what
+    /// they read and write. This mirrors `render` rather than being recorded by it, so
+    /// the two have to be kept in step.
+    pub fn get_frame_graph(&self) -> gfx::FrameGraph {
+        use gfx::{FramePass, PassKind};
+
+        // The render textures swap before the frame is drawn
+        let mut graph = gfx::FrameGraph::new();
+        let target = format!("render texture {}", 1 - self.accumulation.target);
+        let history = format!("render texture {}", self.accumulation.target);
+        let unpack = FramePass::new("brickmap unpack", PassKind::Compute)
+            .with_read("brickmap unpack buffers")
+            .with_read_write("brickgrid")
+            .with_write("brickmap cache")
+            .with_write("shading table")
+            .with_write("detail table");
+        let mut blit = FramePass::new("blit", PassKind::Render)
+            .with_read(&target)
+            .with_read("exposure")
+            .with_write("surface");
+        if self.settings.outline.is_some() || self.settings.water.is_some() {
+            blit = blit.with_read("raycast depth");
+        }
+        if self.settings.water.is_some() {
+            blit = blit.with_read("water reflections");
+        }
+
+        if self.raycast_pipelines.is_none() {
+            graph.add_pass(unpack);
+            graph.add_pass(blit);
+            return graph;
+        }
+
+        if self.settings.light_probes {
+            graph.add_pass(
+                FramePass::new("light probes", PassKind::Compute)
+                    .with_read_write("brickgrid")
+                    .with_read("brickmap cache")
+                    .with_read("shading table")
+                    .with_read("lights")
+                    .with_read_write("light probes")
+                    .with_write("cpu feedback"),
+            );
+        }
+        if self.settings.particles.is_some() && self.sun_shadows.is_active() {
+            graph.add_pass(
+                FramePass::new("sun shadows", PassKind::Compute)
+                    .with_read("brickgrid")
+                    .with_read("brickmap cache")
+                    .with_write("sun shadow maps"),
+            );
+        }
+        if self.settings.raycast_stats {
+            graph.add_pass(
+                FramePass::new("raycast stats clear", PassKind::Copy).with_write("raycast stats"),
+            );
+        }
+
+        let mut raycast = FramePass::new("raycast", PassKind::Compute)
+            .with_read_write("brickgrid")
+            .with_read("brickgrid mips")
+            .with_read("brickmap cache")
+            .with_read("shading table")
+            .with_read("materials")
+            .with_read("detail table")
+            .with_read("light probes")
+            .with_read("lights")
+            .with_read("blue noise")
+            .with_read_write("reservoirs")
+            .with_write("cpu feedback")
+            .with_write(&target)
+            .with_write("raycast depth")
+            .with_write("pick result");
+        if self.settings.temporal_accumulation {
+            raycast = raycast.with_read(&history);
+        }
+        if self.settings.raycast_stats {
+            raycast = raycast.with_read_write("raycast stats");
+        }
+        graph.add_pass(raycast);
+
+        if self.settings.raycast_stats {
+            graph.add_pass(
+                FramePass::new("raycast stats readback", PassKind::Copy)
+                    .with_read("raycast stats")
+                    .with_write("raycast stats readback"),
+            );
+        }
+        if self.settings.water.is_some() {
+            graph.add_pass(
+                FramePass::new("water reflections", PassKind::Compute)
+                    .with_read_write("brickgrid")
+                    .with_read("brickgrid mips")
+                    .with_read("brickmap cache")
+                    .with_read("light probes")
+                    .with_read("raycast depth")
+                    .with_write("water reflections"),
+            );
+        }
+        if self.picker.is_pending() {
+            graph.add_pass(
+                FramePass::new("pick readback", PassKind::Copy)
+                    .with_read("pick result")
+                    .with_write("pick readback"),
+            );
+        }
+        if matches!(self.settings.exposure, Exposure::Auto { .. }) {
+            graph.add_pass(
+                FramePass::new("auto exposure", PassKind::Compute)
+                    .with_read(&target)
+                    .with_read_write("luminance histogram")
+                    .with_read_write("exposure"),
+            );
+        }
+        graph.add_pass(unpack);
+        graph.add_pass(blit);
+        if self.settings.particles.is_some() {
+            graph.add_pass(
+                FramePass::new("particles", PassKind::Render)
+                    .with_read("particles")
+                    .with_read("raycast depth")
+                    .with_read("sun shadow maps")
+                    .with_write("surface"),
+            );
+        }
+        if self.settings.debug_lines {
+            graph.add_pass(
+                FramePass::new("debug lines", PassKind::Render)
+                    .with_read("debug line vertices")
+                    .with_read("raycast depth")
+                    .with_write("surface"),
+            );
+        }
+        if self.gizmo.get_target().is_some() {
+            graph.add_pass(
+                FramePass::new("gizmo", PassKind::Render)
+                    .with_read("gizmo vertices")
+                    .with_read("raycast depth")
+                    .with_write("surface"),
+            );
+        }
+        graph.add_pass(
+            FramePass::new("feedback readback", PassKind::Copy)
+                .with_read_write("cpu feedback")
+                .with_write("feedback readback"),
+        );
+        graph
+    }
+
+    /// The profiler, while profiling is on.
+    fn get_profiler(&self) -> Option<&gfx::GpuProfiler> {
+        self.profiler
+            .as_ref()
+            .filter(|_| self.settings.gpu_profiling)
+    }
+
+    /// Encodes everything drawn in a frame, returning the readback slots it copies into.
+    fn encode_frame(
+        &self,
+        context: &gfx::Context,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+    ) -> FrameReadbacks {
+        // Keep streaming bricks in behind the loading screen until we can raycast them
+        let Some(pipelines) = &self.raycast_pipelines else {
+            let uniform = RenderSettingsUniform {
+                loading_progress: 0.999,
+                ..self.settings.into()
+            };
+            context
+                .queue
+                .write_buffer(&self.settings_buffer, 0, bytemuck::cast_slice(&[uniform]));
+            self.encode_unpack_pass(encoder);
+            self.encode_blit_pass(encoder, view);
+            return FrameReadbacks::default();
+        };
+
+        let screen_bind_group = &self.screen_bind_groups[self.accumulation.target];
+        if self.settings.light_probes {
+            let probe_count = self.light_probes.get_update_count();
+            let mut compute_pass = self.begin_compute_pass(encoder, "light probes");
+            compute_pass.set_pipeline(&pipelines.probes);
+            compute_pass.set_bind_group(0, &self.raycast_bind_group, &[]);
+            compute_pass.set_bind_group(1, screen_bind_group, &[]);
+            compute_pass.dispatch_workgroups(probe_count.div_ceil(64), 1, 1);
+        }
+
+        // Only raster content uses the shadow maps, and particles are the only raster
+        // content that gets lit
+        if self.settings.particles.is_some() && self.sun_shadows.is_active() {
+            let size = self.sun_shadows.get_dispatch_size();
+            let mut compute_pass = self.begin_compute_pass(encoder, "sun shadows");
+            compute_pass.set_pipeline(&pipelines.sun_shadows);
+            compute_pass.set_bind_group(0, &self.raycast_bind_group, &[]);
+            compute_pass.set_bind_group(1, screen_bind_group, &[]);
+            compute_pass.dispatch_workgroups(size, size, 1);
+        }
+
+        let stats = match self.settings.raycast_stats {
+            true => self.raycast_stats.begin_frame(encoder),
+            false => None,
+        };
+
+        {
+            let size = self.render_textures[0].attributes.size;
+            let mut compute_pass = self.begin_compute_pass(encoder, "raycast");
+            compute_pass.set_pipeline(&pipelines.raycast);
+            compute_pass.set_bind_group(0, &self.raycast_bind_group, &[]);
+            compute_pass.set_bind_group(1, screen_bind_group, &[]);
+            compute_pass.dispatch_workgroups(size.width.div_ceil(8), size.height.div_ceil(8), 1);
+        }
+
+        if let Some(slot) = stats {
+            self.raycast_stats.end_frame(encoder, slot);
+        }
+        if self.settings.water.is_some() {
+            let (x, y) = self.water_reflections.get_dispatch_size();
+            let mut compute_pass = self.begin_compute_pass(encoder, "water reflections");
+            compute_pass.set_pipeline(&pipelines.water_reflections);
+            compute_pass.set_bind_group(0, &self.raycast_bind_group, &[]);
+            compute_pass.set_bind_group(1, self.water_reflections.get_bind_group(), &[]);
+            compute_pass.dispatch_workgroups(x, y, 1);
+        }
+        let picked = self.picker.encode_copy(encoder);
+        if matches!(self.settings.exposure, Exposure::Auto { .. }) {
+            let size = self.render_textures[0].attributes.size;
+            let timestamp_writes = self
+                .get_profiler()
+                .and_then(|p| p.compute_pass("auto exposure"));
+            self.exposure
+                .encode(encoder, self.accumulation.target, size, timestamp_writes);
+        }
+
+        self.encode_unpack_pass(encoder);
+        self.encode_blit_pass(encoder, view);
+        if self.settings.particles.is_some() {
+            let density = self.atmosphere.particle_density;
+            self.particles.encode(encoder, view, density);
+        }
+        if self.settings.debug_lines {
+            self.debug_lines.encode(encoder, view);
+        }
+        self.gizmo.encode(encoder, view);
+
+        FrameReadbacks {
+            stats,
+            picked,
+            feedback: self.brickmap_manager.encode_feedback_copy(encoder),
+            profiler: self.get_profiler().and_then(|p| p.end_frame(encoder)),
+        }
+    }
+
+    /// Works out which pass an error from a frame came from. Every pass is labelled with
+    /// its name in the frame graph, and wgpu notes the label of the pass at fault.
+    fn attribute_gpu_error(&self, mut error: gfx::GpuError) -> gfx::GpuError {
+        if let Some(pass) = self.get_frame_graph().find_labelled_pass(&error.message) {
+            error.label = pass.name.clone();
+        }
+        error
+    }
 
-        let size = self.render_texture.attributes.size;
-        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor::default());
-        compute_pass.set_pipeline(&self.raycast_pipeline);
-        compute_pass.set_bind_group(0, &self.raycast_bind_group, &[]);
-        compute_pass.dispatch_workgroups(size.width / 8, size.height / 8, 1);
-        drop(compute_pass);
+    /// A compute pass, timed if profiling is on.
+    fn begin_compute_pass<'a>(
+        &'a self,
+        encoder: &'a mut wgpu::CommandEncoder,
+        name: &'static str,
+    ) -> wgpu::ComputePass<'a> {
+        encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some(name),
+            timestamp_writes: self.get_profiler().and_then(|p| p.compute_pass(name)),
+        })
+    }
 
+    fn encode_unpack_pass(&self, encoder: &mut wgpu::CommandEncoder) {
         let unpack_max_count = self.brickmap_manager.get_unpack_max_count() as u32;
-        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor::default());
+        let mut compute_pass = self.begin_compute_pass(encoder, "brickmap unpack");
         compute_pass.set_pipeline(&self.unpack_pipeline);
         compute_pass.set_bind_group(0, &self.unpack_bind_group, &[]);
         compute_pass.dispatch_workgroups(unpack_max_count / 8, 1, 1);
-        drop(compute_pass);
+    }
 
+    fn encode_blit_pass(&self, encoder: &mut wgpu::CommandEncoder, view: &wgpu::TextureView) {
         let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-            label: Some("Render Pass"),
+            label: Some("blit"),
             color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                view: &view,
+                view,
                 resolve_target: None,
                 ops: wgpu::Operations {
                     load: wgpu::LoadOp::Clear(self.clear_color),
@@ -223,23 +2079,129 @@ impl VoxelRenderer for BrickmapRenderer {
                 },
             })],
             depth_stencil_attachment: None,
-            ..Default::default()
+            timestamp_writes: self.get_profiler().and_then(|p| p.render_pass("blit")),
+            occlusion_query_set: None,
         });
         render_pass.set_pipeline(&self.render_pipeline);
-        render_pass.set_bind_group(0, &self.render_texture.bind_group, &[]);
+        let render_texture = &self.render_textures[self.accumulation.target];
+        render_pass.set_bind_group(0, &render_texture.bind_group, &[]);
+        render_pass.set_bind_group(1, &self.settings_bind_group, &[]);
         render_pass.draw(0..6, 0..1);
+    }
+}
 
-        drop(render_pass);
+impl VoxelRenderer for BrickmapRenderer {
+    /// Keeps the settings, but volumes and lights have to be added again, and the
+    /// brickgrid starts out empty and streams back in around the camera.
+    fn rebuild(
+        &mut self,
+        context: &gfx::Context,
+        camera_controller: &core::CameraController,
+        lighting: &core::Lighting,
+    ) -> Result<()> {
+        let settings = self.settings;
+        *self = Self::new(context, camera_controller, lighting, self.budget)?;
+        self.set_settings(context, settings);
+        self.set_focus(context, camera_controller.get_position());
+        self.resize(context, camera_controller)
+    }
 
-        encoder.copy_buffer_to_buffer(
-            self.brickmap_manager.get_feedback_buffer(),
-            0,
-            self.brickmap_manager.get_feedback_result_buffer(),
-            0,
-            self.brickmap_manager.get_feedback_result_buffer().size(),
+    fn resize(
+        &mut self,
+        context: &gfx::Context,
+        camera_controller: &core::CameraController,
+    ) -> Result<()> {
+        let render_size = Self::get_render_size(context, self.settings);
+        if self.render_textures[0].attributes.size == render_size {
+            return Ok(());
+        }
+        log::info!(
+            "Resizing render texture to {}x{}",
+            render_size.width,
+            render_size.height
         );
 
-        context.queue.submit(Some(encoder.finish()));
+        context.error_scope("brickmap renderer resize", || {
+            self.render_textures = [
+                Self::create_render_texture(context, render_size)?,
+                Self::create_render_texture(context, render_size)?,
+            ];
+            self.reset_accumulation();
+            let (depth, depth_view) =
+                Self::create_raycast_depth(context, self.render_textures[0].attributes.size);
+            self.raycast_depth = depth;
+            self.raycast_depth_view = depth_view;
+            self.water_reflections.resize(
+                context,
+                self.render_textures[0].attributes.size,
+                &self.raycast_depth_view,
+            )?;
+            self.exposure
+                .set_render_textures(context, &self.render_textures)?;
+            self.settings_bind_group = Self::create_settings_bind_group(
+                context,
+                &self.settings_layout,
+                &self.settings_buffer,
+                &self.raycast_depth_view,
+                &self.exposure,
+                &self.water_reflections,
+            )?;
+
+            let pixel_count = (render_size.width * render_size.height) as usize;
+            self.light_manager.resize(context, pixel_count);
+            self.screen_bind_groups = Self::create_screen_bind_groups(
+                context,
+                &self.screen_layout,
+                &self.render_textures,
+                &self.raycast_depth_view,
+                &self.light_manager,
+            )?;
+
+            let camera_buffer = camera_controller.get_buffer();
+            self.debug_lines
+                .set_depth_view(context, camera_buffer, &self.raycast_depth_view)?;
+            self.gizmo
+                .set_depth_view(context, camera_buffer, &self.raycast_depth_view)?;
+            self.particles.set_depth_view(
+                context,
+                camera_buffer,
+                &self.raycast_depth_view,
+                &self.sun_shadows,
+            )
+        })?
+    }
+
+    fn render(&self, context: &gfx::Context) -> Result<()> {
+        // There's nothing to draw to while the app is suspended
+        let Some(frame) = context.get_current_frame()? else {
+            return Ok(());
+        };
+
+        // Validation errors can turn up anywhere from encoding to submission, so one scope
+        // covers the whole frame and the frame graph says which pass caused them
+        let readbacks = context
+            .error_scope("frame", || {
+                let mut encoder = context
+                    .device
+                    .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+                let readbacks = self.encode_frame(context, &mut encoder, &frame.view);
+                context.queue.submit(Some(encoder.finish()));
+                readbacks
+            })
+            .map_err(|error| self.attribute_gpu_error(error))?;
+
+        if let Some(slot) = readbacks.stats {
+            self.raycast_stats.map_slot(slot);
+        }
+        if readbacks.picked {
+            self.picker.map();
+        }
+        if let Some(slot) = readbacks.feedback {
+            self.brickmap_manager.map_feedback_slot(slot);
+        }
+        if let (Some(profiler), Some(slot)) = (&self.profiler, readbacks.profiler) {
+            profiler.map_slot(slot);
+        }
         frame.present();
         Ok(())
     }
@@ -250,8 +2212,41 @@ impl VoxelRenderer for BrickmapRenderer {
         context: &gfx::Context,
         world: &mut WorldManager,
     ) -> Result<()> {
-        self.brickmap_manager
-            .process_feedback_buffer(context, world);
+        self.poll_pipelines(context)?;
+        self.reload_shaders(context);
+        context.error_scope("brickmap upload", || {
+            self.brickmap_manager
+                .process_feedback_buffer(context, world)
+        })?;
+        self.check_shading_table_resized(context)?;
+        // New bricks only reach the raycast after the next frame's unpack pass, so the
+        // frame after that has to start over too
+        if self.brickmap_manager.has_staged_uploads() {
+            self.accumulation.resets = 2;
+        }
+        context.error_scope("light probes", || self.light_probes.advance(context))?;
+        context.error_scope("light upload", || self.light_manager.update(context))?;
+        context.error_scope("portal upload", || self.portal_manager.update(context))?;
+        context.error_scope("decal upload", || self.decal_manager.update(context))?;
+        if self.settings.raycast_stats {
+            self.raycast_stats.poll(context);
+        }
+        self.picker.poll(context);
+        if let Some(profiler) = self
+            .profiler
+            .as_mut()
+            .filter(|_| self.settings.gpu_profiling)
+        {
+            profiler.poll(context);
+        }
         Ok(())
     }
+
+    fn as_brickmap(&self) -> Option<&BrickmapRenderer> {
+        Some(self)
+    }
+
+    fn as_brickmap_mut(&mut self) -> Option<&mut BrickmapRenderer> {
+        Some(self)
+    }
 }
@@ -1,3 +1,42 @@
+/// A single allocator operation and what came of it. With the `allocator-checks` feature
+/// the allocator records every operation, so a broken sequence can be replayed exactly.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AllocatorOp {
+    Alloc {
+        size: u32,
+        result: Option<u32>,
+    },
+    Dealloc {
+        address: u32,
+        result: Result<(), String>,
+    },
+    /// Added a page, growing the table to `total_elements`
+    Grow {
+        total_elements: u32,
+    },
+}
+
+/// How full one of the allocator's buckets is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShadingBucketStats {
+    pub slot_size: u32,
+    pub slot_count: u32,
+    pub used_slots: u32,
+}
+
+impl std::fmt::Display for ShadingBucketStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {}/{} ({:.0}%)",
+            self.slot_size,
+            self.used_slots,
+            self.slot_count,
+            self.used_slots as f32 / self.slot_count.max(1) as f32 * 100.0
+        )
+    }
+}
+
 #[derive(Debug)]
 pub struct ShadingBucket {
     global_offset: u32,
@@ -47,7 +86,7 @@ impl ShadingBucket {
         }
 
         let local_address = address - self.global_offset;
-        if local_address % self.slot_size != 0 {
+        if !local_address.is_multiple_of(self.slot_size) {
             return Err("Address is not aligned to bucket element size.".to_string());
         }
 
@@ -62,6 +101,38 @@ impl ShadingBucket {
         self.free.push(bucket_index);
         Ok(())
     }
+
+    /// Every slot must be either free or used, exactly once.
+    fn validate(&self) -> Result<(), String> {
+        let tracked = self.free.len() + self.used.len();
+        if tracked != self.slot_count as usize {
+            return Err(format!(
+                "Bucket at {} tracks {} slots but has {}",
+                self.global_offset, tracked, self.slot_count
+            ));
+        }
+
+        let mut seen = vec![false; self.slot_count as usize];
+        for &index in self.free.iter().chain(self.used.iter()) {
+            match seen.get_mut(index as usize) {
+                Some(true) => {
+                    return Err(format!(
+                        "Bucket at {} has slot {} listed twice",
+                        self.global_offset, index
+                    ))
+                }
+                Some(slot) => *slot = true,
+                None => {
+                    return Err(format!(
+                        "Bucket at {} has out of range slot {}",
+                        self.global_offset, index
+                    ))
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -69,69 +140,345 @@ pub struct ShadingTableAllocator {
     buckets: Vec<ShadingBucket>,
     bucket_count: u32,
     elements_per_bucket: u32,
+    /// Each page is a full set of buckets, one per slot size
+    page_count: u32,
     pub total_elements: u32,
     used_elements: u32,
+    #[cfg(feature = "allocator-checks")]
+    operations: Vec<AllocatorOp>,
 }
 
 impl ShadingTableAllocator {
     pub fn new(bucket_count: u32, elements_per_bucket: u32) -> Self {
-        let total_elements = bucket_count * elements_per_bucket;
-        let used_elements = 0;
+        let mut allocator = Self {
+            buckets: Vec::with_capacity(bucket_count as usize),
+            bucket_count,
+            elements_per_bucket,
+            page_count: 0,
+            total_elements: 0,
+            used_elements: 0,
+            #[cfg(feature = "allocator-checks")]
+            operations: Vec::new(),
+        };
+        allocator.add_page();
+        allocator
+    }
 
-        // Build the buckets. Ordered in ascending size
-        let mut buckets = Vec::with_capacity(bucket_count as usize);
-        for i in (0..bucket_count).rev() {
-            let global_offset = i * elements_per_bucket;
+    /// Elements in a single page of buckets.
+    pub fn get_page_elements(&self) -> u32 {
+        self.bucket_count * self.elements_per_bucket
+    }
+
+    /// Adds another page of buckets after the existing ones, without moving any existing
+    /// allocations. The shading table buffer has to grow to the new `total_elements`
+    /// before any of it is handed out.
+    pub fn grow(&mut self) {
+        self.add_page();
+        self.record(AllocatorOp::Grow {
+            total_elements: self.total_elements,
+        });
+    }
+
+    fn add_page(&mut self) {
+        let page_offset = self.total_elements;
+        for i in (0..self.bucket_count).rev() {
+            let global_offset = page_offset + i * self.elements_per_bucket;
             let slot_size = u32::pow(2, 9 - i);
-            let slot_count = elements_per_bucket / slot_size;
+            let slot_count = self.elements_per_bucket / slot_size;
             log::info!(
                 "Creating bucket: offset({}), slot_size({}), slot_count({})",
                 global_offset,
                 slot_size,
                 slot_count
             );
-            buckets.push(ShadingBucket::new(global_offset, slot_count, slot_size));
+            self.buckets
+                .push(ShadingBucket::new(global_offset, slot_count, slot_size));
         }
 
-        Self {
-            buckets,
-            bucket_count,
-            elements_per_bucket,
-            total_elements,
-            used_elements,
+        // Keep the buckets in ascending size. The sort is stable, so earlier pages still
+        // fill up first
+        self.buckets.sort_by_key(|bucket| bucket.slot_size);
+        self.page_count += 1;
+        self.total_elements += self.get_page_elements();
+    }
+
+    pub fn get_used_elements(&self) -> u32 {
+        self.used_elements
+    }
+
+    /// Frees every allocation at once. The table keeps its size.
+    pub fn reset(&mut self) {
+        let page_count = self.page_count;
+        *self = Self::new(self.bucket_count, self.elements_per_bucket);
+        for _ in 1..page_count {
+            self.add_page();
         }
     }
 
     pub fn try_alloc(&mut self, size: u32) -> Option<u32> {
-        for i in 0..self.bucket_count as usize {
-            let bucket = &mut self.buckets[i];
+        let result = self.alloc(size);
+        self.record(AllocatorOp::Alloc { size, result });
+        result
+    }
+
+    /// Occupancy of each bucket, smallest slots first.
+    pub fn get_bucket_stats(&self) -> Vec<ShadingBucketStats> {
+        self.buckets
+            .iter()
+            .map(|bucket| ShadingBucketStats {
+                slot_size: bucket.slot_size,
+                slot_count: bucket.slot_count,
+                used_slots: bucket.used.len() as u32,
+            })
+            .collect()
+    }
+
+    /// Slot size of the smallest bucket an allocation of `size` elements fits in, whether
+    /// or not it has room. Allocations spill into bigger buckets when it's full.
+    pub fn get_best_slot_size(&self, size: u32) -> Option<u32> {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.slot_size)
+            .find(|&slot_size| slot_size >= size)
+    }
+
+    /// Does the bucket with `slot_size` slots have any free?
+    pub fn has_free_slot(&self, slot_size: u32) -> bool {
+        self.buckets
+            .iter()
+            .any(|bucket| bucket.slot_size == slot_size && !bucket.free.is_empty())
+    }
+
+    /// Number of elements the allocation at `address` has room for, or `None` if the
+    /// address isn't in the table.
+    pub fn get_slot_size(&self, address: u32) -> Option<u32> {
+        self.buckets
+            .iter()
+            .find(|bucket| bucket.contains_address(address))
+            .map(|bucket| bucket.slot_size)
+    }
+
+    pub fn try_dealloc(&mut self, address: u32) -> Result<(), String> {
+        let result = self.dealloc(address);
+        self.record(AllocatorOp::Dealloc {
+            address,
+            result: result.clone(),
+        });
+        result
+    }
+
+    /// Checks the buckets don't overlap, every slot is accounted for exactly once, and the
+    /// used element count matches the slots actually in use.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut used_elements = 0;
+        let mut ranges = Vec::with_capacity(self.buckets.len());
+        for bucket in &self.buckets {
+            bucket.validate()?;
+            used_elements += bucket.used.len() as u32 * bucket.slot_size;
+            let end = bucket.global_offset + bucket.slot_count * bucket.slot_size;
+            if end > self.total_elements {
+                return Err(format!(
+                    "Bucket at {} extends past the end of the table",
+                    bucket.global_offset
+                ));
+            }
+            ranges.push((bucket.global_offset, end));
+        }
+
+        ranges.sort();
+        for pair in ranges.windows(2) {
+            if pair[0].1 > pair[1].0 {
+                return Err(format!(
+                    "Buckets at {} and {} overlap",
+                    pair[0].0, pair[1].0
+                ));
+            }
+        }
+
+        if used_elements != self.used_elements {
+            return Err(format!(
+                "Used element count is {} but slots in use cover {}",
+                self.used_elements, used_elements
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "allocator-checks")]
+    fn record(&mut self, op: AllocatorOp) {
+        self.operations.push(op);
+        if let Err(e) = self.validate() {
+            panic!(
+                "Shading table allocator invariant broken: {}\nOperations: {:?}",
+                e, self.operations
+            );
+        }
+    }
+
+    #[cfg(not(feature = "allocator-checks"))]
+    fn record(&mut self, _op: AllocatorOp) {}
+
+    fn alloc(&mut self, size: u32) -> Option<u32> {
+        for bucket in self.buckets.iter_mut() {
             if bucket.slot_size < size {
                 continue;
             }
 
-            let idx = bucket.try_alloc();
-            if idx.is_some() {
+            if let Some(idx) = bucket.try_alloc() {
                 self.used_elements += bucket.slot_size;
                 log::trace!(
                     "Allocated to shader table at {}. {}/{} ({}%)",
-                    idx.unwrap(),
+                    idx,
                     self.used_elements,
                     self.total_elements,
                     ((self.used_elements as f32 / self.total_elements as f32) * 100.0).floor()
                 );
-                return idx;
+                return Some(idx);
             }
         }
 
         None
     }
 
-    pub fn try_dealloc(&mut self, address: u32) -> Result<(), String> {
-        // Buckets are reverse order of their global offset so we need to reverse our idx
-        let mut bucket_idx = address / self.elements_per_bucket;
-        bucket_idx = self.bucket_count - bucket_idx - 1;
-        let bucket = &mut self.buckets[bucket_idx as usize];
+    fn dealloc(&mut self, address: u32) -> Result<(), String> {
+        if address >= self.total_elements {
+            return Err(format!(
+                "Address ({}) is outside the shading table.",
+                address
+            ));
+        }
+
+        let bucket = self
+            .buckets
+            .iter_mut()
+            .find(|bucket| bucket.contains_address(address))
+            .ok_or_else(|| format!("Address ({}) is not within any bucket.", address))?;
+        bucket.try_dealloc(address)?;
         self.used_elements -= bucket.slot_size;
-        bucket.try_dealloc(address)
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Small xorshift so the same seed always gives the same sequence
+    fn xorshift(seed: u32) -> impl FnMut() -> u32 {
+        let mut state = seed.max(1);
+        move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        }
+    }
+
+    /// Runs a seeded sequence of allocs and deallocs, including invalid ones, checking the
+    /// invariants after each. Returns the allocator along with its live addresses.
+    fn stress(seed: u32, op_count: usize) -> (ShadingTableAllocator, Vec<u32>) {
+        let mut allocator = ShadingTableAllocator::new(4, 4096);
+        let mut allocated = Vec::new();
+        let mut random = xorshift(seed);
+
+        for _ in 0..op_count {
+            match random() % 8 {
+                0..=3 => {
+                    let size = random() % 520;
+                    match allocator.try_alloc(size) {
+                        Some(address) => allocated.push(address),
+                        // Grow like the manager does, but only a few times
+                        None if allocator.page_count < 4 => allocator.grow(),
+                        None => {}
+                    }
+                }
+                4..=6 if !allocated.is_empty() => {
+                    let address = allocated.swap_remove(random() as usize % allocated.len());
+                    if let Err(e) = allocator.try_dealloc(address) {
+                        panic!("Failed to deallocate live address {}: {}", address, e);
+                    }
+                }
+                _ => {
+                    // Garbage addresses must be rejected without disturbing anything
+                    let address = random() % (allocator.total_elements + 1024);
+                    if !allocated.contains(&address) {
+                        let _ = allocator.try_dealloc(address);
+                    }
+                }
+            }
+
+            if let Err(e) = allocator.validate() {
+                panic!("Invariant broken: {}", e);
+            }
+        }
+
+        (allocator, allocated)
+    }
+
+    #[test]
+    fn live_allocations_never_overlap() {
+        let (allocator, allocated) = stress(0x5EED, 100_000);
+        let mut ranges: Vec<(u32, u32)> = allocated
+            .iter()
+            .map(|&address| (address, address + allocator.get_slot_size(address).unwrap()))
+            .collect();
+        ranges.sort();
+        for pair in ranges.windows(2) {
+            assert!(
+                pair[0].1 <= pair[1].0,
+                "Allocations at {} and {} overlap",
+                pair[0].0,
+                pair[1].0
+            );
+        }
+    }
+
+    #[test]
+    fn used_count_matches_allocations() {
+        let (allocator, allocated) = stress(0xC0FFEE, 10_000);
+        let expected: u32 = allocated
+            .iter()
+            .map(|&address| allocator.get_slot_size(address).unwrap())
+            .sum();
+        assert_eq!(allocator.get_used_elements(), expected);
+    }
+
+    #[test]
+    fn dealloc_of_free_address_fails() {
+        let mut allocator = ShadingTableAllocator::new(4, 4096);
+        let address = allocator.try_alloc(100).unwrap();
+        let used = allocator.get_used_elements();
+
+        // Never allocated, then allocated once and freed twice
+        assert!(allocator.try_dealloc(address + 512).is_err());
+        assert!(allocator.try_dealloc(address).is_ok());
+        assert!(allocator.try_dealloc(address).is_err());
+        assert_eq!(allocator.get_used_elements(), used - 128);
+        assert!(allocator.validate().is_ok());
+    }
+
+    /// Runs an operation from a recorded log, returning whether it gave the same result.
+    #[cfg(feature = "allocator-checks")]
+    fn replay(allocator: &mut ShadingTableAllocator, op: &AllocatorOp) -> bool {
+        match op {
+            AllocatorOp::Alloc { size, result } => allocator.try_alloc(*size) == *result,
+            AllocatorOp::Dealloc { address, result } => allocator.try_dealloc(*address) == *result,
+            AllocatorOp::Grow { total_elements } => {
+                allocator.grow();
+                allocator.total_elements == *total_elements
+            }
+        }
+    }
+
+    /// Replaying the log on a fresh allocator has to give identical results
+    #[cfg(feature = "allocator-checks")]
+    #[test]
+    fn replay_matches_log() {
+        let (allocator, _) = stress(0x5EED, 10_000);
+        let mut replayed = ShadingTableAllocator::new(4, 4096);
+        for op in &allocator.operations {
+            assert!(replay(&mut replayed, op), "Replay diverged at {:?}", op);
+        }
     }
 }
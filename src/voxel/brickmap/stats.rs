@@ -0,0 +1,95 @@
+use crate::gfx::{BulkBufferBuilder, Context, ReadbackRing};
+
+/// Totals accumulated by the raycast shader over a single frame of primary rays.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct RaycastStats {
+    pub rays: u32,
+    /// Grid, coarse cell and voxel DDA steps combined
+    pub steps: u32,
+    pub misses: u32,
+    pub bricks: u32,
+}
+
+impl RaycastStats {
+    pub fn get_average_steps(&self) -> f32 {
+        self.steps as f32 / self.rays.max(1) as f32
+    }
+
+    pub fn get_average_bricks(&self) -> f32 {
+        self.bricks as f32 / self.rays.max(1) as f32
+    }
+
+    pub fn get_miss_rate(&self) -> f32 {
+        self.misses as f32 / self.rays.max(1) as f32
+    }
+}
+
+/// Reads the raycast stats back without stalling. Each frame's stats get copied into a
+/// free readback slot, and are picked up a few frames later once the slot is mapped.
+#[derive(Debug)]
+pub struct RaycastStatsReader {
+    stats_buffer: wgpu::Buffer,
+    readback: ReadbackRing,
+    latest: Option<RaycastStats>,
+}
+
+impl RaycastStatsReader {
+    pub fn new(context: &Context, slot_count: usize) -> Self {
+        let size = std::mem::size_of::<RaycastStats>() as u64;
+        let stats_buffer = BulkBufferBuilder::new()
+            .set_usage(
+                wgpu::BufferUsages::STORAGE
+                    | wgpu::BufferUsages::COPY_SRC
+                    | wgpu::BufferUsages::COPY_DST,
+            )
+            .with_buffer("Raycast Stats", size, false)
+            .build(context)
+            .remove(0);
+
+        Self {
+            stats_buffer,
+            readback: ReadbackRing::new(context, "Raycast Stats Readback", size, slot_count),
+            latest: None,
+        }
+    }
+
+    pub fn get_buffer(&self) -> &wgpu::Buffer {
+        &self.stats_buffer
+    }
+
+    /// The most recent stats that have made it back from the GPU.
+    pub fn get_latest(&self) -> Option<RaycastStats> {
+        self.latest
+    }
+
+    /// Clears the stats ready for the raycast pass, returning the readback slot to copy
+    /// into afterwards. Returns `None` if every slot is still waiting on the GPU.
+    pub fn begin_frame(&self, encoder: &mut wgpu::CommandEncoder) -> Option<usize> {
+        let slot = self.readback.find_free_slot()?;
+        encoder.clear_buffer(&self.stats_buffer, 0, None);
+        Some(slot)
+    }
+
+    pub fn end_frame(&self, encoder: &mut wgpu::CommandEncoder, slot: usize) {
+        encoder.copy_buffer_to_buffer(
+            &self.stats_buffer,
+            0,
+            self.readback.get_buffer(slot),
+            0,
+            self.stats_buffer.size(),
+        );
+    }
+
+    /// Requests mapping of the slot. Must be called after the copy has been submitted.
+    pub fn map_slot(&self, slot: usize) {
+        self.readback.map_slot(slot);
+    }
+
+    /// Picks up the stats from any slots that have finished mapping.
+    pub fn poll(&mut self, context: &Context) {
+        self.readback.poll(context, |_, data| {
+            self.latest = Some(bytemuck::pod_read_unaligned(data));
+        });
+    }
+}
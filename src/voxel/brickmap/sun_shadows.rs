@@ -0,0 +1,128 @@
+use crate::{
+    core::SunLight,
+    gfx::{BulkBufferBuilder, Context},
+};
+
+/// Texels along each side of a cascade
+const CASCADE_SIZE: u32 = 512;
+/// Half the width of each cascade in bricks, nearest first
+const CASCADE_EXTENTS: [f32; 3] = [8.0, 32.0, 128.0];
+/// How far towards the sun from each cascade's center its rays start, in bricks
+const SHADOW_DEPTH_RANGE: f32 = 512.0;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct ShadowCascadesUniform {
+    right: [f32; 3],
+    update_cascade: u32,
+    up: [f32; 3],
+    size: u32,
+    direction: [f32; 3],
+    /// 0 when the sun is off or below the horizon
+    depth_range: f32,
+    /// Center and half extent of each cascade
+    cascades: [[f32; 4]; 3],
+}
+
+/// Cascaded shadow maps looking down from the sun, for raster content drawn over the
+/// raycast image. Voxels trace their own shadow rays, so rather than rasterising them the
+/// maps are filled by raycasting the brickmap from the sun, which keeps raster and voxel
+/// shadows in agreement. Each map stores how far from the sun the first voxel is.
+///
+/// One cascade is refreshed per frame, so a moving sun or camera takes a few frames to
+/// settle in the outer cascades.
+#[derive(Debug)]
+pub struct SunShadowMaps {
+    view: wgpu::TextureView,
+    buffer: wgpu::Buffer,
+    uniform: ShadowCascadesUniform,
+}
+
+impl SunShadowMaps {
+    pub const CASCADE_COUNT: u32 = CASCADE_EXTENTS.len() as u32;
+
+    pub fn new(context: &Context) -> Self {
+        let texture = context.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Sun Shadow Cascades"),
+            size: wgpu::Extent3d {
+                width: CASCADE_SIZE,
+                height: CASCADE_SIZE,
+                depth_or_array_layers: Self::CASCADE_COUNT,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R32Float,
+            usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+
+        let uniform = ShadowCascadesUniform {
+            size: CASCADE_SIZE,
+            ..Default::default()
+        };
+        let buffer = BulkBufferBuilder::new()
+            .set_usage(wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST)
+            .with_init_buffer_bm("Sun Shadow Cascades", &[uniform])
+            .build(context)
+            .remove(0);
+
+        Self {
+            view,
+            buffer,
+            uniform,
+        }
+    }
+
+    /// Moves the cascades to follow the camera and picks the next one to refresh.
+    pub fn update(&mut self, context: &Context, sun: SunLight, camera_position: glam::Vec3) {
+        let direction = sun.direction.normalize_or_zero();
+        let uniform = &mut self.uniform;
+        uniform.update_cascade = (uniform.update_cascade + 1) % Self::CASCADE_COUNT;
+        uniform.depth_range = match sun.intensity > 0.0 && direction.y > 0.0 {
+            true => SHADOW_DEPTH_RANGE,
+            false => 0.0,
+        };
+
+        let right = direction.any_orthonormal_vector();
+        let up = direction.cross(right);
+        uniform.right = right.to_array();
+        uniform.up = up.to_array();
+        uniform.direction = direction.to_array();
+
+        // Snapping the centers to whole texels stops the shadow edges crawling as the
+        // camera moves
+        for (cascade, extent) in uniform.cascades.iter_mut().zip(CASCADE_EXTENTS) {
+            let texel = extent * 2.0 / CASCADE_SIZE as f32;
+            let snap = |axis: glam::Vec3| (camera_position.dot(axis) / texel).round() * texel;
+            let center =
+                right * snap(right) + up * snap(up) + direction * camera_position.dot(direction);
+            *cascade = center.extend(extent).to_array();
+        }
+
+        context
+            .queue
+            .write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[*uniform]));
+    }
+
+    /// Whether there's a sun to cast shadows from.
+    pub fn is_active(&self) -> bool {
+        self.uniform.depth_range > 0.0
+    }
+
+    pub fn get_dispatch_size(&self) -> u32 {
+        CASCADE_SIZE.div_ceil(8)
+    }
+
+    pub fn get_view(&self) -> &wgpu::TextureView {
+        &self.view
+    }
+
+    pub fn get_buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+}
@@ -1,12 +1,23 @@
-use crate::voxel::world::{Voxel, WorldManager};
+use crate::voxel::world::{MaterialId, Voxel, WorldManager};
 
+/// Sub-voxel shapes, stored as 2 bits per voxel in the brickmap detail table
+pub const DETAIL_NONE: u32 = 0;
+pub const DETAIL_ROUNDED: u32 = 1;
+pub const DETAIL_BEVELLED: u32 = 2;
+pub const DETAIL_SLAB: u32 = 3;
+
+/// Returns the surface bitmask, shading data and detail of a brick, along with the average
+/// albedo of its surface to draw it with at a distance. Each surface voxel's shading data
+/// is its material id in the low 16 bits and its baked corner AO in the high 16.
 pub fn cull_interior_voxels(
     world: &mut WorldManager,
     grid_pos: glam::IVec3,
-) -> ([u32; 16], Vec<u32>) {
+) -> ([u32; 16], Vec<u32>, [u32; 32], u32) {
     // This is the data we want to return
     let mut bitmask_data = [0xFFFFFFFF_u32; 16];
-    let mut albedo_data = Vec::<u32>::new();
+    let mut material_data = Vec::<u32>::new();
+    let mut detail_data = [0u32; 32];
+    let mut color_sum = glam::UVec3::ZERO;
 
     // Calculate world chunk and block positions for each that may be accessed
     let center_pos = grid_pos_to_world_pos(world, grid_pos);
@@ -26,6 +37,36 @@ pub fn cull_interior_voxels(
     let up_block = world.get_block(up_pos.0, up_pos.1);
     let down_block = world.get_block(down_pos.0, down_pos.1);
 
+    // Whether each voxel in and around the brick is solid, for baking AO. Voxels off the
+    // brick's edges and corners aren't in any of the blocks above and their chunks might
+    // not be loaded, in which case they count as empty
+    let mut solid = [false; 1000];
+    for z in -1..9 {
+        for y in -1..9 {
+            for x in -1..9 {
+                let pos = glam::ivec3(x, y, z);
+                let local = pos.rem_euclid(glam::IVec3::splat(8)).as_uvec3();
+                let idx = (local.x + local.y * 8 + local.z * 64) as usize;
+                let outside = pos.cmplt(glam::IVec3::ZERO) | pos.cmpge(glam::IVec3::splat(8));
+                let block = match outside.bitmask() {
+                    0 => Some(&center_block),
+                    1 if x == 8 => Some(&forward_block),
+                    1 => Some(&backward_block),
+                    2 if y == 8 => Some(&up_block),
+                    2 => Some(&down_block),
+                    4 if z == 8 => Some(&right_block),
+                    4 => Some(&left_block),
+                    _ => None,
+                };
+                let voxel = match block {
+                    Some(block) => Some(block[idx]),
+                    None => world.try_get_voxel(grid_pos * 8 + pos),
+                };
+                solid[neighbourhood_index(pos)] = matches!(voxel, Some(Voxel::Material(_)));
+            }
+        }
+    }
+
     //  Reusable array of whether cardinal neighbours are empty
     let mut neighbours = [false; 6];
     for z in 0..8 {
@@ -39,7 +80,7 @@ pub fn cull_interior_voxels(
 
                 match center_block[idx] {
                     Voxel::Empty => continue,
-                    Voxel::Color(r, g, b) => {
+                    Voxel::Material(material) => {
                         // A voxel is on the surface if at least one of it's
                         // cardinal neighbours is non-solid.
                         neighbours[0] = if x == 7 {
@@ -83,11 +124,12 @@ pub fn cull_interior_voxels(
                         let surface_voxel = neighbours.iter().any(|v| *v);
                         if surface_voxel {
                             entry += 1 << (x + y * 8);
-                            let albedo = ((r as u32) << 24)
-                                + ((g as u32) << 16)
-                                + ((b as u32) << 8)
-                                + 255u32;
-                            albedo_data.push(albedo);
+                            let ao =
+                                corner_occlusion(&solid, glam::ivec3(x as i32, y as i32, z as i32));
+                            material_data.push(material as u32 | (ao << 16));
+                            color_sum += material_albedo(world, material);
+                            detail_data[idx / 16] |=
+                                surface_detail(&neighbours) << ((idx % 16) * 2);
                         }
                     }
                 }
@@ -98,7 +140,120 @@ pub fn cull_interior_voxels(
         bitmask_data[offset + 1] = ((entry >> 32) & 0xFFFFFFFF).try_into().unwrap();
     }
 
-    (bitmask_data, albedo_data)
+    let lod_color = match material_data.len() {
+        0 => 0,
+        count => pack_albedo(color_sum / count as u32),
+    };
+    (bitmask_data, material_data, detail_data, lod_color)
+}
+
+fn neighbourhood_index(pos: glam::IVec3) -> usize {
+    ((pos.x + 1) + (pos.y + 1) * 10 + (pos.z + 1) * 100) as usize
+}
+
+/// Bakes ambient occlusion for each corner of a voxel from how many of the 8 voxels
+/// around the corner are solid. Half of them are behind whichever face the corner is on,
+/// so only the solid ones past 4 occlude it. 2 bits per corner, with the corners ordered
+/// by x, then y, then z.
+pub fn corner_occlusion(solid: &[bool; 1000], pos: glam::IVec3) -> u32 {
+    let mut occlusion = 0;
+    for corner in 0..8 {
+        let min = pos + glam::ivec3(corner & 1, (corner >> 1) & 1, corner >> 2) - 1;
+        let mut count = 0u32;
+        for i in 0..8 {
+            let offset = glam::ivec3(i & 1, (i >> 1) & 1, i >> 2);
+            count += solid[neighbourhood_index(min + offset)] as u32;
+        }
+        occlusion |= count.saturating_sub(4).min(3) << (corner * 2);
+    }
+    occlusion
+}
+
+/// Returns the average colour of every solid voxel in a brick as a packed albedo, or
+/// `None` if the brick is empty. Unlike `cull_interior_voxels` this only reads the brick
+/// itself, so it's cheap enough for bricks that are only ever seen from far away.
+pub fn average_brick_color(world: &mut WorldManager, grid_pos: glam::IVec3) -> Option<u32> {
+    let (chunk_pos, block_pos) = grid_pos_to_world_pos(world, grid_pos);
+    let block = world.get_block(chunk_pos, block_pos);
+    let mut color_sum = glam::UVec3::ZERO;
+    let mut count = 0;
+    for voxel in block {
+        if let Voxel::Material(material) = voxel {
+            color_sum += material_albedo(world, material);
+            count += 1;
+        }
+    }
+
+    match count {
+        0 => None,
+        _ => Some(pack_albedo(color_sum / count)),
+    }
+}
+
+/// Packs a colour the same way as materials' albedo, RGBA with full alpha.
+fn pack_albedo(color: glam::UVec3) -> u32 {
+    (color.x << 24) + (color.y << 16) + (color.z << 8) + 255
+}
+
+fn material_albedo(world: &WorldManager, material: MaterialId) -> glam::UVec3 {
+    let [r, g, b] = world.get_materials().get(material).albedo;
+    glam::uvec3(r as u32, g as u32, b as u32)
+}
+
+/// Picks a sub-voxel shape for a surface voxel from which of its neighbours are empty,
+/// ordered +x, -x, +z, -z, +y, -y. Only voxels with open space above are refined, so
+/// the tops of terrain get softened while walls stay crisp.
+pub fn surface_detail(neighbours: &[bool; 6]) -> u32 {
+    if !neighbours[4] {
+        return DETAIL_NONE;
+    }
+
+    // Thin layers open on both sides get thinned out further
+    if neighbours[5] {
+        return DETAIL_SLAB;
+    }
+
+    match neighbours[..4].iter().filter(|n| **n).count() {
+        3..=4 => DETAIL_ROUNDED,
+        1..=2 => DETAIL_BEVELLED,
+        _ => DETAIL_NONE,
+    }
+}
+
+/// Returns the material of a brick if every voxel in it is the same solid material.
+/// These bricks don't need a brickmap, the material can live in the brickgrid.
+pub fn uniform_brick_material(
+    world: &mut WorldManager,
+    grid_pos: glam::IVec3,
+) -> Option<MaterialId> {
+    let (chunk_pos, block_pos) = grid_pos_to_world_pos(world, grid_pos);
+    let block = world.get_block(chunk_pos, block_pos);
+    let first = block[0];
+    match first {
+        Voxel::Material(material) if block.iter().all(|v| *v == first) => Some(material),
+        _ => None,
+    }
+}
+
+/// Builds a coarse occupancy mask from a brickmap bitmask. Each bit of the coarse mask
+/// covers a 2x2x2 region of voxels, giving a 4x4x4 grid of 64 bits.
+pub fn coarse_occupancy(bitmask: &[u32; 16]) -> [u32; 2] {
+    let mut occupancy = [0u32; 2];
+    for z in 0..8 {
+        for y in 0..8 {
+            for x in 0..8 {
+                let idx = x + y * 8 + z * 8 * 8;
+                if (bitmask[idx / 32] >> (idx % 32)) & 1 == 0 {
+                    continue;
+                }
+
+                let coarse_idx = x / 2 + (y / 2) * 4 + (z / 2) * 4 * 4;
+                occupancy[coarse_idx / 32] |= 1 << (coarse_idx % 32);
+            }
+        }
+    }
+
+    occupancy
 }
 
 pub fn grid_pos_to_world_pos(
@@ -0,0 +1,288 @@
+use anyhow::{bail, Result};
+
+use crate::{gfx, voxel::world::Voxel};
+
+/// Most volumes that can be placed over the terrain at once.
+pub const MAX_VOLUMES: usize = 16;
+/// Size of the texture every volume's voxels are packed into. Volumes are stacked along
+/// z, so each can be at most this wide and tall and they share its depth.
+const ATLAS_DIMS: glam::UVec3 = glam::UVec3::new(128, 128, 256);
+
+/// A volume as the raycast shader sees it, see `VolumeTable`.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(super) struct VolumeElement {
+    center: [f32; 3],
+    _pad0: u32,
+    dims: [u32; 3],
+    _pad1: u32,
+    atlas_offset: [u32; 3],
+    _pad2: u32,
+    /// Takes rays from the world into the volume, so the shader doesn't have to invert it
+    inverse_rotation: [f32; 4],
+}
+
+/// A box of voxels to place in the world as its own volume, like a prop that can be
+/// moved around without editing the terrain under it.
+#[derive(Debug, Clone)]
+pub struct VolumeModel {
+    dims: glam::UVec3,
+    voxels: Vec<Voxel>,
+}
+
+impl VolumeModel {
+    /// `voxels` are laid out x first, then y, then z.
+    pub fn new(dims: glam::UVec3, voxels: Vec<Voxel>) -> Result<Self> {
+        if voxels.len() != dims.element_product() as usize {
+            bail!(
+                "Volume of size {} needs {} voxels, got {}",
+                dims,
+                dims.element_product(),
+                voxels.len()
+            );
+        }
+        Ok(Self { dims, voxels })
+    }
+
+    pub fn get_dims(&self) -> glam::UVec3 {
+        self.dims
+    }
+}
+
+/// Where a volume sits, in voxels relative to the terrain like picks and edits. The model
+/// is rotated about its centre, which is placed at `position`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VolumeTransform {
+    pub position: glam::Vec3,
+    pub rotation: glam::Quat,
+}
+
+impl Default for VolumeTransform {
+    fn default() -> Self {
+        Self {
+            position: glam::Vec3::ZERO,
+            rotation: glam::Quat::IDENTITY,
+        }
+    }
+}
+
+impl VolumeTransform {
+    pub fn from_position(position: glam::Vec3) -> Self {
+        Self {
+            position,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_rotation(mut self, rotation: glam::Quat) -> Self {
+        self.rotation = rotation;
+        self
+    }
+}
+
+/// Refers to a volume added to the renderer. Handles to removed volumes are ignored, even
+/// once their slot has been reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct VolumeHandle {
+    index: usize,
+    generation: u32,
+}
+
+#[derive(Debug)]
+struct Volume {
+    transform: VolumeTransform,
+    dims: glam::UVec3,
+    /// First slice of the atlas the volume's voxels are in
+    atlas_z: u32,
+}
+
+/// The volumes placed over the terrain. Each keeps its voxels densely in a 3D texture
+/// atlas, as they're small and the raycast pass is already using every storage buffer
+/// binding the default limits give us. Their positions go in the world state uniform, so
+/// the raycast can test their bounding boxes before stepping through them. Transforms are
+/// applied to the rays rather than the voxels, so moving or spinning a volume every frame
+/// only rewrites the uniform, never the atlas.
+///
+/// Voxels are material ids from the active world's material table. Volumes sit in the
+/// same space as the terrain, so they move with it when its origin changes.
+#[derive(Debug)]
+pub(super) struct VolumeTable {
+    slots: Vec<Option<Volume>>,
+    generations: Vec<u32>,
+    atlas: wgpu::Texture,
+    atlas_view: wgpu::TextureView,
+}
+
+impl VolumeTable {
+    pub fn new(context: &gfx::Context) -> Self {
+        let atlas = context.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Volume Atlas"),
+            size: wgpu::Extent3d {
+                width: ATLAS_DIMS.x,
+                height: ATLAS_DIMS.y,
+                depth_or_array_layers: ATLAS_DIMS.z,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D3,
+            format: wgpu::TextureFormat::R16Uint,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        let atlas_view = atlas.create_view(&wgpu::TextureViewDescriptor::default());
+
+        Self {
+            slots: (0..MAX_VOLUMES).map(|_| None).collect(),
+            generations: vec![0; MAX_VOLUMES],
+            atlas,
+            atlas_view,
+        }
+    }
+
+    pub fn get_atlas_view(&self) -> &wgpu::TextureView {
+        &self.atlas_view
+    }
+
+    pub fn get_count(&self) -> usize {
+        self.slots.iter().flatten().count()
+    }
+
+    /// Uploads a model's voxels and places it with `transform`.
+    pub fn add(
+        &mut self,
+        context: &gfx::Context,
+        model: &VolumeModel,
+        transform: VolumeTransform,
+    ) -> Result<VolumeHandle> {
+        let dims = model.dims;
+        if dims.cmpeq(glam::UVec3::ZERO).any()
+            || dims.x > ATLAS_DIMS.x
+            || dims.y > ATLAS_DIMS.y
+            || dims.z > ATLAS_DIMS.z
+        {
+            bail!(
+                "Volumes can be at most {}, this one is {}",
+                ATLAS_DIMS,
+                dims
+            );
+        }
+        let Some(index) = self.slots.iter().position(Option::is_none) else {
+            bail!("No room for more than {} volumes", MAX_VOLUMES);
+        };
+        let Some(atlas_z) = self.find_atlas_space(dims.z) else {
+            bail!("No room left in the volume atlas for one of size {}", dims);
+        };
+
+        // Empty is 0, so materials are stored one up
+        let data: Vec<u16> = model
+            .voxels
+            .iter()
+            .map(|voxel| match voxel {
+                Voxel::Empty => 0,
+                Voxel::Material(id) => id.saturating_add(1),
+            })
+            .collect();
+        context.queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &self.atlas,
+                mip_level: 0,
+                origin: wgpu::Origin3d {
+                    x: 0,
+                    y: 0,
+                    z: atlas_z,
+                },
+                aspect: wgpu::TextureAspect::All,
+            },
+            bytemuck::cast_slice(&data),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(dims.x * 2),
+                rows_per_image: Some(dims.y),
+            },
+            wgpu::Extent3d {
+                width: dims.x,
+                height: dims.y,
+                depth_or_array_layers: dims.z,
+            },
+        );
+
+        self.slots[index] = Some(Volume {
+            transform,
+            dims,
+            atlas_z,
+        });
+        Ok(VolumeHandle {
+            index,
+            generation: self.generations[index],
+        })
+    }
+
+    /// Returns false if the volume had already been removed.
+    pub fn remove(&mut self, handle: VolumeHandle) -> bool {
+        if self.get(handle).is_none() {
+            return false;
+        }
+        self.slots[handle.index] = None;
+        self.generations[handle.index] = self.generations[handle.index].wrapping_add(1);
+        true
+    }
+
+    pub fn get_transform(&self, handle: VolumeHandle) -> Option<VolumeTransform> {
+        self.get(handle).map(|volume| volume.transform)
+    }
+
+    /// Returns false if the volume had been removed.
+    pub fn set_transform(&mut self, handle: VolumeHandle, transform: VolumeTransform) -> bool {
+        if self.get(handle).is_none() {
+            return false;
+        }
+        if let Some(volume) = &mut self.slots[handle.index] {
+            volume.transform = transform;
+        }
+        true
+    }
+
+    /// Every volume as the shader sees them, packed to the front, and how many there are.
+    pub fn get_elements(&self) -> ([VolumeElement; MAX_VOLUMES], u32) {
+        let mut elements = [VolumeElement::default(); MAX_VOLUMES];
+        let mut count = 0;
+        for volume in self.slots.iter().flatten() {
+            elements[count] = VolumeElement {
+                center: volume.transform.position.to_array(),
+                dims: volume.dims.to_array(),
+                atlas_offset: [0, 0, volume.atlas_z],
+                inverse_rotation: volume.transform.rotation.normalize().inverse().to_array(),
+                ..Default::default()
+            };
+            count += 1;
+        }
+        (elements, count as u32)
+    }
+
+    fn get(&self, handle: VolumeHandle) -> Option<&Volume> {
+        if self.generations.get(handle.index) != Some(&handle.generation) {
+            return None;
+        }
+        self.slots[handle.index].as_ref()
+    }
+
+    /// First slice of the atlas with `depth` free slices from it.
+    fn find_atlas_space(&self, depth: u32) -> Option<u32> {
+        let mut used: Vec<(u32, u32)> = self
+            .slots
+            .iter()
+            .flatten()
+            .map(|volume| (volume.atlas_z, volume.atlas_z + volume.dims.z))
+            .collect();
+        used.sort_unstable();
+
+        let mut start = 0;
+        for (used_start, used_end) in used {
+            if used_start.saturating_sub(start) >= depth {
+                return Some(start);
+            }
+            start = start.max(used_end);
+        }
+        (ATLAS_DIMS.z - start >= depth).then_some(start)
+    }
+}
@@ -0,0 +1,141 @@
+use anyhow::Result;
+
+use crate::gfx::{self, Context};
+
+/// A flat sea covering everything below a height. It isn't made of voxels, the raycast
+/// just tints whatever it sees through the surface and the surface reflects the world.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Water {
+    /// Height of the surface in bricks
+    pub level: f32,
+    /// Distance under the surface in bricks over which the view fades to the water's colour
+    pub clarity: f32,
+}
+
+impl Default for Water {
+    fn default() -> Self {
+        Self {
+            level: 2.0,
+            clarity: 4.0,
+        }
+    }
+}
+
+/// Reflections off the water, traced at half resolution as a pixel per reflection ray
+/// would be far too much for a sea filling most of the screen. The pass finds the water
+/// from the raycast depth, and the blit blends the result in by Fresnel.
+#[derive(Debug)]
+pub struct WaterReflections {
+    texture: gfx::Texture,
+    layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+}
+
+impl WaterReflections {
+    pub fn new(
+        context: &Context,
+        size: wgpu::Extent3d,
+        depth_view: &wgpu::TextureView,
+    ) -> Result<Self> {
+        // Only the bindings the reflection pass needs, numbered to follow on from the
+        // raycast's screen bindings as they share a shader
+        let layout = context
+            .device
+            .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Water Reflections BGL"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 5,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::StorageTexture {
+                            access: wgpu::StorageTextureAccess::WriteOnly,
+                            format: wgpu::TextureFormat::Rgba16Float,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                        },
+                        count: None,
+                    },
+                ],
+            });
+        let texture = Self::create_texture(context, size)?;
+        let bind_group = Self::create_bind_group(context, &layout, &texture, depth_view);
+
+        Ok(Self {
+            texture,
+            layout,
+            bind_group,
+        })
+    }
+
+    fn create_texture(context: &Context, size: wgpu::Extent3d) -> Result<gfx::Texture> {
+        gfx::TextureBuilder::new()
+            .with_size(size.width.div_ceil(2), size.height.div_ceil(2), 1)
+            .with_format(wgpu::TextureFormat::Rgba16Float)
+            .with_usage(wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::STORAGE_BINDING)
+            .with_filter_mode(wgpu::FilterMode::Linear)
+            .build(context)
+    }
+
+    fn create_bind_group(
+        context: &Context,
+        layout: &wgpu::BindGroupLayout,
+        texture: &gfx::Texture,
+        depth_view: &wgpu::TextureView,
+    ) -> wgpu::BindGroup {
+        context
+            .device
+            .create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Water Reflections BG"),
+                layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 4,
+                        resource: wgpu::BindingResource::TextureView(depth_view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 5,
+                        resource: wgpu::BindingResource::TextureView(&texture.view),
+                    },
+                ],
+            })
+    }
+
+    /// Recreates the reflections to match a new screen size.
+    pub fn resize(
+        &mut self,
+        context: &Context,
+        size: wgpu::Extent3d,
+        depth_view: &wgpu::TextureView,
+    ) -> Result<()> {
+        self.texture = Self::create_texture(context, size)?;
+        self.bind_group = Self::create_bind_group(context, &self.layout, &self.texture, depth_view);
+        Ok(())
+    }
+
+    /// Workgroups to dispatch in x and y, one thread per reflection texel.
+    pub fn get_dispatch_size(&self) -> (u32, u32) {
+        let size = self.texture.attributes.size;
+        (size.width.div_ceil(8), size.height.div_ceil(8))
+    }
+
+    pub fn get_layout(&self) -> &wgpu::BindGroupLayout {
+        &self.layout
+    }
+
+    pub fn get_bind_group(&self) -> &wgpu::BindGroup {
+        &self.bind_group
+    }
+
+    pub fn get_texture(&self) -> &gfx::Texture {
+        &self.texture
+    }
+}
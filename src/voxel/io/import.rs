@@ -0,0 +1,329 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::Instant,
+};
+
+use anyhow::{Context, Result};
+
+use crate::voxel::world::{MaterialId, Voxel, WorldId, WorldManager};
+
+use super::{ImportMode, VoxFile};
+
+/// A piece of an import that lands in a single chunk, small enough to write in one frame.
+#[derive(Debug)]
+struct ImportBatch {
+    /// Emptied before the voxels are written, for `ImportMode::Replace`
+    clear: Option<(glam::IVec3, glam::IVec3)>,
+    /// World voxel positions and the palette index they're filled with
+    voxels: Vec<(glam::IVec3, u8)>,
+    /// The share of the file's voxels this batch stands for, for progress
+    weight: f32,
+}
+
+#[derive(Debug)]
+enum WorkerMessage {
+    /// The file parsed, and has `voxels` filled voxels across all of its models
+    Loaded {
+        palette: Box<[[u8; 4]; 256]>,
+        voxels: usize,
+    },
+    Batch(ImportBatch),
+    /// Every batch has been sent
+    Finished(Box<VoxFile>),
+    Failed(anyhow::Error),
+}
+
+/// What happened to an import since the last update.
+#[derive(Debug)]
+pub enum ImportEvent {
+    Finished { path: PathBuf, changed: usize },
+    Failed { path: PathBuf, error: anyhow::Error },
+}
+
+/// How far along the oldest running import is.
+#[derive(Debug, Clone)]
+pub struct ImportProgress {
+    pub path: PathBuf,
+    /// From 0 to 1, 0 until the file has been parsed
+    pub fraction: f32,
+    /// Including this one
+    pub running: usize,
+}
+
+/// One .vox file being read and converted on a worker thread, with its voxels streamed
+/// into the world a chunk at a time as they arrive.
+#[derive(Debug)]
+pub(super) struct ImportJob {
+    pub path: PathBuf,
+    pub world: WorldId,
+    pub origin: glam::IVec3,
+    pub mode: ImportMode,
+    pub voxel_size: f32,
+    /// Re-importing a changed file, so the previous version comes out once this one parses
+    pub reload: bool,
+    cancelled: Arc<AtomicBool>,
+    messages: mpsc::Receiver<WorkerMessage>,
+    /// Batches that have arrived but haven't been written yet
+    batches: VecDeque<ImportBatch>,
+    palette: Option<Box<[[u8; 4]; 256]>>,
+    /// Materials already looked up for each palette index
+    materials: [Option<MaterialId>; 256],
+    /// In the file's voxels, see `ImportBatch::weight`
+    total: usize,
+    applied: f32,
+    changed: usize,
+    /// The converted file, once the worker is done with it
+    file: Option<VoxFile>,
+    failed: Option<anyhow::Error>,
+}
+
+impl ImportJob {
+    /// Starts reading `path` on a new thread. `path` should already be canonical.
+    pub fn start(
+        path: PathBuf,
+        world: &WorldManager,
+        origin: glam::IVec3,
+        mode: ImportMode,
+        voxel_size: f32,
+    ) -> Result<Self> {
+        let (sender, messages) = mpsc::channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let scale = voxel_size / world.get_voxel_size();
+        let chunk_voxel_dims = world.get_chunk_dims().as_ivec3() * 8;
+        {
+            let path = path.clone();
+            let cancelled = cancelled.clone();
+            thread::Builder::new()
+                .name("vox import".to_string())
+                .spawn(move || {
+                    let message = match convert(
+                        &path,
+                        origin,
+                        mode,
+                        scale,
+                        chunk_voxel_dims,
+                        &cancelled,
+                        &sender,
+                    ) {
+                        Ok(Some(file)) => WorkerMessage::Finished(Box::new(file)),
+                        Ok(None) => return,
+                        Err(e) => WorkerMessage::Failed(e),
+                    };
+                    let _ = sender.send(message);
+                })
+                .context("Failed to spawn import thread")?;
+        }
+
+        log::info!("Importing {:?} at {}", path, origin);
+        Ok(Self {
+            path,
+            world: world.get_id(),
+            origin,
+            mode,
+            voxel_size,
+            reload: false,
+            cancelled,
+            messages,
+            batches: VecDeque::new(),
+            palette: None,
+            materials: [None; 256],
+            total: 0,
+            applied: 0.0,
+            changed: 0,
+            file: None,
+            failed: None,
+        })
+    }
+
+    /// Stops the worker after its current batch. Anything already written stays in the
+    /// world.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.palette.is_some()
+    }
+
+    pub fn get_fraction(&self) -> f32 {
+        match self.total {
+            0 if self.file.is_some() => 1.0,
+            0 => 0.0,
+            total => (self.applied / total as f32).min(1.0),
+        }
+    }
+
+    /// Takes whatever the worker has sent since the last call. Returns true once the file
+    /// has parsed, the first time it's called after that, which is the last moment
+    /// anything it replaces can be taken out before its voxels go in.
+    pub fn receive(&mut self) -> bool {
+        let was_loaded = self.is_loaded();
+        for message in self.messages.try_iter() {
+            match message {
+                WorkerMessage::Loaded { palette, voxels } => {
+                    self.palette = Some(palette);
+                    self.total = voxels;
+                }
+                WorkerMessage::Batch(batch) => self.batches.push_back(batch),
+                WorkerMessage::Finished(file) => self.file = Some(*file),
+                WorkerMessage::Failed(error) => self.failed = Some(error),
+            }
+        }
+        !was_loaded && self.is_loaded()
+    }
+
+    /// Writes received batches into the world until `deadline`, always writing at least
+    /// one.
+    pub fn apply(&mut self, world: &mut WorldManager, deadline: Instant) {
+        let Some(palette) = &self.palette else {
+            return;
+        };
+        while let Some(batch) = self.batches.pop_front() {
+            if let Some((min, max)) = batch.clear {
+                self.changed += world.set_region(min, max, Voxel::Empty);
+            }
+
+            let voxels: Vec<_> = batch
+                .voxels
+                .iter()
+                .map(|&(pos, index)| {
+                    let material = *self.materials[index as usize].get_or_insert_with(|| {
+                        let [r, g, b, _] = palette[index as usize];
+                        world.get_materials_mut().find_or_add_color(r, g, b)
+                    });
+                    (pos, Voxel::Material(material))
+                })
+                .collect();
+            self.changed += world.set_voxels(&voxels);
+            self.applied += batch.weight;
+
+            if Instant::now() >= deadline {
+                break;
+            }
+        }
+    }
+
+    /// The finished file and how many voxels changed, once every batch has been written.
+    /// Errors if the worker failed.
+    pub fn take_result(&mut self) -> Option<Result<(VoxFile, usize)>> {
+        if let Some(error) = self.failed.take() {
+            return Some(Err(error));
+        }
+        if !self.batches.is_empty() {
+            return None;
+        }
+        self.file.take().map(|file| Ok((file, self.changed)))
+    }
+}
+
+impl Drop for ImportJob {
+    fn drop(&mut self) {
+        // The worker also stops when it finds nobody listening, but that's only checked
+        // between batches
+        self.cancel();
+    }
+}
+
+impl ImportProgress {
+    pub(super) fn from_jobs(jobs: &[ImportJob]) -> Option<Self> {
+        let job = jobs.first()?;
+        Some(Self {
+            path: job.path.clone(),
+            fraction: job.get_fraction(),
+            running: jobs.len(),
+        })
+    }
+
+    /// Just the file's name, for showing to the user.
+    pub fn get_file_name(&self) -> String {
+        get_file_name(&self.path)
+    }
+}
+
+/// The file's name without the directory it's in.
+pub fn get_file_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or(path.as_os_str())
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Reads and converts a file on the worker thread, sending each model's batches as soon
+/// as it's converted. Returns `None` if it was cancelled or nobody is listening anymore.
+fn convert(
+    path: &Path,
+    origin: glam::IVec3,
+    mode: ImportMode,
+    scale: f32,
+    chunk_voxel_dims: glam::IVec3,
+    cancelled: &AtomicBool,
+    sender: &mpsc::Sender<WorkerMessage>,
+) -> Result<Option<VoxFile>> {
+    let file = VoxFile::load(path)?;
+    let palette = Box::new(file.palette);
+    let voxels = file.models.iter().map(|m| m.voxels.len()).sum();
+    if sender
+        .send(WorkerMessage::Loaded { palette, voxels })
+        .is_err()
+    {
+        return Ok(None);
+    }
+
+    // Models are written one after the other like `VoxFile::import` does, so a replaced
+    // model can't empty one written before it
+    for model in &file.models {
+        let mut chunks: HashMap<glam::IVec3, Vec<(glam::IVec3, u8)>> = HashMap::new();
+        for &(pos, index) in &model.voxels {
+            model.for_each_world_voxel(pos, origin, scale, |pos| {
+                chunks
+                    .entry(pos.div_euclid(chunk_voxel_dims))
+                    .or_default()
+                    .push((pos, index));
+            });
+        }
+
+        // Replacing empties every chunk the bounding box touches, filled or not
+        let (min, max) = (origin, origin + model.get_world_dims(scale));
+        if mode == ImportMode::Replace {
+            let min_chunk = min.div_euclid(chunk_voxel_dims);
+            let max_chunk = (max - 1).div_euclid(chunk_voxel_dims);
+            for z in min_chunk.z..=max_chunk.z {
+                for y in min_chunk.y..=max_chunk.y {
+                    for x in min_chunk.x..=max_chunk.x {
+                        chunks.entry(glam::ivec3(x, y, z)).or_default();
+                    }
+                }
+            }
+        }
+
+        // Bottom up, so the model is built like it would be by hand
+        let mut chunks: Vec<_> = chunks.into_iter().collect();
+        chunks.sort_by_key(|(pos, _)| (pos.y, pos.z, pos.x));
+        let weight = model.voxels.len() as f32 / chunks.len().max(1) as f32;
+        for (chunk_pos, voxels) in chunks {
+            if cancelled.load(Ordering::Relaxed) {
+                return Ok(None);
+            }
+
+            let clear = (mode == ImportMode::Replace).then(|| {
+                let chunk_min = chunk_pos * chunk_voxel_dims;
+                (min.max(chunk_min), max.min(chunk_min + chunk_voxel_dims))
+            });
+            let batch = ImportBatch {
+                clear,
+                voxels,
+                weight,
+            };
+            if sender.send(WorkerMessage::Batch(batch)).is_err() {
+                return Ok(None);
+            }
+        }
+    }
+    Ok(Some(file))
+}
@@ -0,0 +1,7 @@
+pub mod import;
+mod vox;
+mod watch;
+
+pub use import::{get_file_name, ImportEvent};
+pub use vox::{ImportMode, VoxFile};
+pub use watch::ImportWatcher;
@@ -0,0 +1,308 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::{
+    math,
+    voxel::world::{MaterialTable, Voxel, WorldManager},
+};
+
+/// A single model from a .vox file, in MagicaVoxel's Z-up coordinates.
+#[derive(Debug, Clone)]
+pub struct VoxModel {
+    pub size: glam::UVec3,
+    /// Position and palette index of every filled voxel
+    pub voxels: Vec<(glam::UVec3, u8)>,
+}
+
+/// The models and palette of a MagicaVoxel .vox file. The scene graph (transforms,
+/// groups, layers) isn't read, every model sits at the origin.
+#[derive(Debug, Clone)]
+pub struct VoxFile {
+    pub models: Vec<VoxModel>,
+    /// RGBA colours, index 0 is unused as it means empty
+    pub palette: [[u8; 4]; 256],
+}
+
+/// How imported voxels interact with what's already in the world.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportMode {
+    /// Empties each model's bounding box before writing it, so the model fully replaces
+    /// whatever terrain was there
+    Replace,
+    /// Only writes the model's filled voxels, leaving the terrain around them
+    Overlay,
+}
+
+impl VoxFile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+        Self::parse(&data).with_context(|| format!("Failed to parse {:?}", path))
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut reader = Reader { data, offset: 0 };
+        if reader.bytes(4)? != b"VOX " {
+            bail!("Not a .vox file");
+        }
+        let version = reader.u32()?;
+        log::info!("Parsing .vox file version {}", version);
+
+        // Everything lives in the children of the MAIN chunk
+        let main = reader.chunk()?;
+        if main.id != *b"MAIN" {
+            bail!("Expected MAIN chunk, found {:?}", main.id);
+        }
+
+        let mut models = vec![];
+        let mut palette = default_palette();
+        let mut size = None;
+        let mut children = Reader {
+            data: main.children,
+            offset: 0,
+        };
+        while !children.is_empty() {
+            let chunk = children.chunk()?;
+            let mut content = Reader {
+                data: chunk.content,
+                offset: 0,
+            };
+            match &chunk.id {
+                b"SIZE" => {
+                    size = Some(glam::uvec3(content.u32()?, content.u32()?, content.u32()?));
+                }
+                b"XYZI" => {
+                    let size = size
+                        .take()
+                        .ok_or_else(|| anyhow!("XYZI chunk without a SIZE chunk before it"))?;
+                    let count = content.u32()? as usize;
+                    let mut voxels = Vec::with_capacity(count);
+                    for _ in 0..count {
+                        let v = content.bytes(4)?;
+                        let pos = glam::uvec3(v[0] as u32, v[1] as u32, v[2] as u32);
+                        if pos.cmpge(size).any() {
+                            bail!("Voxel {} outside of model size {}", pos, size);
+                        }
+                        voxels.push((pos, v[3]));
+                    }
+                    models.push(VoxModel { size, voxels });
+                }
+                b"RGBA" => {
+                    // Palette indices are 1 based, the last colour in the chunk is unused
+                    for i in 0..255 {
+                        let c = content.bytes(4)?;
+                        palette[i + 1] = [c[0], c[1], c[2], c[3]];
+                    }
+                }
+                // Scene graph, materials, etc. we don't care about
+                _ => (),
+            }
+        }
+
+        log::info!(
+            "Parsed {} models with {} voxels",
+            models.len(),
+            models.iter().map(|m| m.voxels.len()).sum::<usize>()
+        );
+        Ok(Self { models, palette })
+    }
+
+    /// Writes every model into the world with its minimum corner at `origin` (world voxel
+    /// space). Models are rotated from MagicaVoxel's Z-up to our Y-up. Returns how many
+    /// voxels changed.
+    ///
+    /// `voxel_size` is the width of the model's voxels in metres. Models are resampled to
+    /// the world's voxel size so they keep their physical size, each model voxel covering
+    /// however many world voxels it overlaps (or sharing one with its neighbours when the
+    /// model is finer than the world).
+    pub fn import(
+        &self,
+        world: &mut WorldManager,
+        origin: glam::IVec3,
+        mode: ImportMode,
+        voxel_size: f32,
+    ) -> usize {
+        let scale = voxel_size / world.get_voxel_size();
+        let mut changed = 0;
+        for model in &self.models {
+            if mode == ImportMode::Replace {
+                let dims = model.get_world_dims(scale);
+                changed += world.set_region(origin, origin + dims, Voxel::Empty);
+            }
+
+            let mut voxels = vec![];
+            for &(pos, index) in &model.voxels {
+                let [r, g, b, _] = self.palette[index as usize];
+                let voxel = Voxel::Material(world.get_materials_mut().find_or_add_color(r, g, b));
+                model.for_each_world_voxel(pos, origin, scale, |pos| voxels.push((pos, voxel)));
+            }
+            changed += world.set_voxels(&voxels);
+        }
+
+        log::info!("Imported .vox at {}, {} voxels changed", origin, changed);
+        changed
+    }
+
+    /// Empties everything an `import` with the same arguments wrote to, for taking a model
+    /// back out before importing a new version of it. Replaced models have their whole
+    /// bounding box emptied, overlaid ones just their filled voxels. Whatever terrain was
+    /// there before the import is gone either way. Returns how many voxels changed.
+    pub fn remove(
+        &self,
+        world: &mut WorldManager,
+        origin: glam::IVec3,
+        mode: ImportMode,
+        voxel_size: f32,
+    ) -> usize {
+        let scale = voxel_size / world.get_voxel_size();
+        let mut changed = 0;
+        for model in &self.models {
+            match mode {
+                ImportMode::Replace => {
+                    let dims = model.get_world_dims(scale);
+                    changed += world.set_region(origin, origin + dims, Voxel::Empty);
+                }
+                ImportMode::Overlay => {
+                    let mut voxels = vec![];
+                    for &(pos, _) in &model.voxels {
+                        model.for_each_world_voxel(pos, origin, scale, |pos| {
+                            voxels.push((pos, Voxel::Empty))
+                        });
+                    }
+                    changed += world.set_voxels(&voxels);
+                }
+            }
+        }
+        changed
+    }
+}
+
+impl VoxModel {
+    /// Every voxel of the model in a dense box, rotated to Y-up like `VoxFile::import`
+    /// does, with the palette's colours found or added in `materials`. Returns the size of
+    /// the box and its voxels, x first.
+    pub fn to_dense(
+        &self,
+        palette: &[[u8; 4]; 256],
+        materials: &mut MaterialTable,
+    ) -> (glam::UVec3, Vec<Voxel>) {
+        let dims = glam::uvec3(self.size.x, self.size.z, self.size.y);
+        let mut voxels = vec![Voxel::Empty; dims.element_product() as usize];
+        for &(pos, index) in &self.voxels {
+            let [r, g, b, _] = palette[index as usize];
+            let voxel = Voxel::Material(materials.find_or_add_color(r, g, b));
+            self.for_each_world_voxel(pos, glam::IVec3::ZERO, 1.0, |pos| {
+                voxels[math::to_1d_index(pos.as_uvec3(), dims)] = voxel;
+            });
+        }
+        (dims, voxels)
+    }
+
+    /// Size of the model once it's in the world, in world voxels. `scale` is the size of
+    /// the model's voxels in world voxels.
+    pub(super) fn get_world_dims(&self, scale: f32) -> glam::IVec3 {
+        let dims = glam::vec3(self.size.x as f32, self.size.z as f32, self.size.y as f32);
+        (dims * scale).ceil().as_ivec3().max(glam::IVec3::ONE)
+    }
+
+    /// Calls `f` with every world voxel a model voxel covers once imported at `origin`.
+    pub(super) fn for_each_world_voxel(
+        &self,
+        pos: glam::UVec3,
+        origin: glam::IVec3,
+        scale: f32,
+        mut f: impl FnMut(glam::IVec3),
+    ) {
+        // Z-up to Y-up, keeping the handedness by flipping what becomes Z
+        let to_world_scale = |pos: u32| (pos as f32 * scale).floor() as i32;
+        let pos = glam::uvec3(pos.x, pos.z, self.size.y - 1 - pos.y);
+        let min = glam::IVec3::from_array(pos.to_array().map(to_world_scale));
+        let max = glam::IVec3::from_array((pos + 1).to_array().map(to_world_scale)).max(min + 1);
+        for z in min.z..max.z {
+            for y in min.y..max.y {
+                for x in min.x..max.x {
+                    f(origin + glam::ivec3(x, y, z));
+                }
+            }
+        }
+    }
+}
+
+struct Chunk<'a> {
+    id: [u8; 4],
+    content: &'a [u8],
+    children: &'a [u8],
+}
+
+/// Little endian reader over a byte slice that errors instead of panicking on truncated
+/// files.
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.offset >= self.data.len()
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8]> {
+        let end = self.offset + count;
+        if end > self.data.len() {
+            bail!("Unexpected end of file at byte {}", self.offset);
+        }
+        let bytes = &self.data[self.offset..end];
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Chunks are {id, content size, children size, content, children}
+    fn chunk(&mut self) -> Result<Chunk<'a>> {
+        let id = self.bytes(4)?;
+        let content_size = self.u32()? as usize;
+        let children_size = self.u32()? as usize;
+        Ok(Chunk {
+            id: [id[0], id[1], id[2], id[3]],
+            content: self.bytes(content_size)?,
+            children: self.bytes(children_size)?,
+        })
+    }
+}
+
+/// The palette MagicaVoxel uses for files without an RGBA chunk. It's a 6x6x6 colour cube
+/// without black, followed by red, green, blue and grey ramps.
+fn default_palette() -> [[u8; 4]; 256] {
+    const CUBE_STEPS: [u8; 6] = [0xFF, 0xCC, 0x99, 0x66, 0x33, 0x00];
+    const RAMP_STEPS: [u8; 10] = [0xEE, 0xDD, 0xBB, 0xAA, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+
+    let mut palette = [[0u8; 4]; 256];
+    let mut i = 1;
+    for r in CUBE_STEPS {
+        for g in CUBE_STEPS {
+            for b in CUBE_STEPS {
+                if r == 0 && g == 0 && b == 0 {
+                    continue;
+                }
+                palette[i] = [r, g, b, 0xFF];
+                i += 1;
+            }
+        }
+    }
+    for channel in 0..4 {
+        for step in RAMP_STEPS {
+            let mut color = [0, 0, 0, 0xFF];
+            match channel {
+                3 => color[..3].fill(step),
+                _ => color[channel] = step,
+            }
+            palette[i] = color;
+            i += 1;
+        }
+    }
+    palette
+}
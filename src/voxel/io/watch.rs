@@ -0,0 +1,297 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use notify::Watcher;
+
+use crate::voxel::world::{WorldId, WorldManager};
+
+use super::{
+    import::{ImportEvent, ImportJob, ImportProgress},
+    ImportMode, VoxFile,
+};
+
+/// How long a file has to go without changing before it's re-imported. Saving often
+/// takes a few writes, and reading in the middle of them gets half a file.
+const SETTLE_TIME: Duration = Duration::from_millis(250);
+/// How long a frame can spend writing imported voxels into the world. At least one batch
+/// per import is always written so imports finish however slow the frame is.
+const APPLY_BUDGET: Duration = Duration::from_millis(4);
+
+/// A model imported into a world, and everything needed to import it again.
+#[derive(Debug)]
+struct WatchedImport {
+    /// Canonical, so it matches the paths in file events
+    path: PathBuf,
+    world: WorldId,
+    origin: glam::IVec3,
+    mode: ImportMode,
+    voxel_size: f32,
+    /// What was last imported, so it can be taken back out
+    file: VoxFile,
+}
+
+/// Imports .vox files and watches them on disk, re-importing whenever they change so
+/// models can be edited in MagicaVoxel and show up in the world live. Re-importing takes
+/// the old version back out first, then the world's edit tracking streams the changes
+/// to the renderer like any other edit.
+///
+/// Files are read and converted on worker threads, then written into the world a chunk
+/// at a time over the following frames, so even huge models don't stall rendering.
+/// Files that fail to parse (usually because they're still being written) are left as
+/// they were, and picked up again on their next change.
+pub struct ImportWatcher {
+    imports: Vec<WatchedImport>,
+    /// Imports still being read or written, oldest first
+    jobs: Vec<ImportJob>,
+    /// `None` if the platform can't watch files
+    watcher: Option<notify::RecommendedWatcher>,
+    events: mpsc::Receiver<notify::Result<notify::Event>>,
+    watched_directories: HashSet<PathBuf>,
+    /// Changed files and when they last changed, waiting to settle
+    changed: HashMap<PathBuf, Instant>,
+}
+
+impl std::fmt::Debug for ImportWatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ImportWatcher")
+            .field("imports", &self.imports.len())
+            .field("jobs", &self.jobs.len())
+            .field("watching", &self.watcher.is_some())
+            .field("changed", &self.changed.len())
+            .finish()
+    }
+}
+
+impl Default for ImportWatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ImportWatcher {
+    /// If files can't be watched, imports still work, they just don't reload.
+    pub fn new() -> Self {
+        let (sender, events) = mpsc::channel();
+        let watcher = match notify::recommended_watcher(sender) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                log::warn!("Import hot reloading disabled: {}", e);
+                None
+            }
+        };
+
+        Self {
+            imports: vec![],
+            jobs: vec![],
+            watcher,
+            events,
+            watched_directories: HashSet::new(),
+            changed: HashMap::new(),
+        }
+    }
+
+    /// Starts importing a .vox file into a world (see `VoxFile::import`), and keeps
+    /// watching it once it's in. Importing the same file into the same world again
+    /// cancels any import of it still running and replaces the old watch, leaving the
+    /// earlier copy where it is. Only errors if the import can't be started, anything
+    /// wrong with the file itself comes back from `update`.
+    pub fn import(
+        &mut self,
+        path: &Path,
+        world: &WorldManager,
+        origin: glam::IVec3,
+        mode: ImportMode,
+        voxel_size: f32,
+    ) -> Result<()> {
+        let path =
+            std::fs::canonicalize(path).with_context(|| format!("Failed to resolve {:?}", path))?;
+        let job = ImportJob::start(path, world, origin, mode, voxel_size)?;
+        self.jobs
+            .retain(|other| other.path != job.path || other.world != job.world);
+        self.jobs.push(job);
+        Ok(())
+    }
+
+    /// Stops every running import. Whatever they'd already written stays in the world,
+    /// and their files aren't watched. Returns the files that were being imported.
+    pub fn cancel_all(&mut self) -> Vec<PathBuf> {
+        // Dropping a job stops its worker
+        self.jobs.drain(..).map(|job| job.path.clone()).collect()
+    }
+
+    /// The file most recently imported into a world, as it was last imported.
+    pub fn get_latest(&self, world: WorldId) -> Option<&VoxFile> {
+        self.imports
+            .iter()
+            .rev()
+            .find(|import| import.world == world)
+            .map(|import| &import.file)
+    }
+
+    pub fn get_progress(&self) -> Option<ImportProgress> {
+        ImportProgress::from_jobs(&self.jobs)
+    }
+
+    /// Starts re-importing any of the files imported into `world` that have changed and
+    /// settled, then writes as much of the running imports into it as fits in the frame.
+    /// Call once per frame. Returns the imports that finished or failed.
+    pub fn update(&mut self, world: &mut WorldManager) -> Vec<ImportEvent> {
+        self.reimport_changed(world);
+
+        // Imports into other worlds wait until those are active again
+        let world_id = world.get_id();
+        let deadline = Instant::now() + APPLY_BUDGET;
+        let mut events = vec![];
+        let mut i = 0;
+        while i < self.jobs.len() {
+            let job = &mut self.jobs[i];
+            if job.world != world_id {
+                i += 1;
+                continue;
+            }
+
+            if job.receive() && job.reload {
+                if let Some(import) = self
+                    .imports
+                    .iter()
+                    .find(|import| import.path == job.path && import.world == world_id)
+                {
+                    import
+                        .file
+                        .remove(world, import.origin, import.mode, import.voxel_size);
+                }
+            }
+            job.apply(world, deadline);
+            let Some(result) = job.take_result() else {
+                i += 1;
+                continue;
+            };
+
+            let job = self.jobs.remove(i);
+            match result {
+                Ok((file, changed)) => {
+                    log::info!("Imported {:?}, {} voxels changed", job.path, changed);
+                    self.watch_directory(&job.path);
+                    self.imports
+                        .retain(|import| import.path != job.path || import.world != world_id);
+                    self.imports.push(WatchedImport {
+                        path: job.path.clone(),
+                        world: world_id,
+                        origin: job.origin,
+                        mode: job.mode,
+                        voxel_size: job.voxel_size,
+                        file,
+                    });
+                    events.push(ImportEvent::Finished {
+                        path: job.path.clone(),
+                        changed,
+                    });
+                }
+                Err(error) => {
+                    log::error!("Failed to import {:?}: {:#}", job.path, error);
+                    events.push(ImportEvent::Failed {
+                        path: job.path.clone(),
+                        error,
+                    });
+                }
+            }
+        }
+        events
+    }
+
+    /// Starts re-importing any of the files imported into `world` that have changed and
+    /// settled since they were last imported.
+    fn reimport_changed(&mut self, world: &WorldManager) {
+        self.collect_events();
+
+        let now = Instant::now();
+        let settled: Vec<PathBuf> = self
+            .changed
+            .iter()
+            .filter(|(_, &changed_at)| now - changed_at >= SETTLE_TIME)
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        // Files imported into other worlds wait until those are active again
+        let world_id = world.get_id();
+        for path in settled {
+            let mut pending = false;
+            for import in self.imports.iter().filter(|i| i.path == path) {
+                if import.world != world_id {
+                    pending = true;
+                    continue;
+                }
+
+                let job = ImportJob::start(
+                    import.path.clone(),
+                    world,
+                    import.origin,
+                    import.mode,
+                    import.voxel_size,
+                );
+                match job {
+                    Ok(mut job) => {
+                        log::info!("Reloading {:?}", import.path);
+                        job.reload = true;
+                        self.jobs
+                            .retain(|other| other.path != job.path || other.world != world_id);
+                        self.jobs.push(job);
+                    }
+                    Err(e) => log::error!("Failed to reload {:?}: {:#}", import.path, e),
+                }
+            }
+            if !pending {
+                self.changed.remove(&path);
+            }
+        }
+    }
+
+    fn watch_directory(&mut self, path: &Path) {
+        let (Some(watcher), Some(directory)) = (&mut self.watcher, path.parent()) else {
+            return;
+        };
+        if self.watched_directories.contains(directory) {
+            return;
+        }
+
+        // Editors tend to save by writing a new file and renaming it over the old one,
+        // which loses a watch on the file itself, so its directory is watched instead
+        match watcher.watch(directory, notify::RecursiveMode::NonRecursive) {
+            Ok(()) => {
+                log::info!("Watching {} for model changes", directory.display());
+                self.watched_directories.insert(directory.to_owned());
+            }
+            Err(e) => log::warn!("Can't watch {}: {}", directory.display(), e),
+        }
+    }
+
+    fn collect_events(&mut self) {
+        for event in self.events.try_iter() {
+            let event = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    log::warn!("Import watcher error: {}", e);
+                    continue;
+                }
+            };
+            if event.kind.is_access() || event.kind.is_remove() {
+                continue;
+            }
+
+            for path in event.paths {
+                // Files that have gone again by the time we look don't need reloading
+                let Ok(path) = std::fs::canonicalize(&path) else {
+                    continue;
+                };
+                if self.imports.iter().any(|import| import.path == path) {
+                    self.changed.insert(path, Instant::now());
+                }
+            }
+        }
+    }
+}
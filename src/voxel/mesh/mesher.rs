@@ -0,0 +1,225 @@
+use crate::voxel::world::{Voxel, WorldManager};
+
+/// Width of a mesh section in voxels, padded by one on each side for the neighbours
+const PADDED: usize = ChunkMesh::SIZE + 2;
+
+/// A face of a voxel quad as the mesh shader sees it.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MeshVertex {
+    /// In world voxel space
+    pub position: [f32; 3],
+    /// Which way the face points, +x, -x, +y, -y, +z, -z
+    pub face: u32,
+    pub material: u32,
+}
+
+/// Greedy meshed faces of a section of the world, 4 bricks across. Sections are much
+/// smaller than the world's chunks so edits only remesh a small part of the world.
+///
+/// Faces between two solid voxels are left out, and neighbouring faces with the same
+/// material are merged into as few quads as possible. Voxels in neighbouring sections
+/// are taken into account so faces against them are left out too.
+#[derive(Debug, Clone, Default)]
+pub struct ChunkMesh {
+    pub vertices: Vec<MeshVertex>,
+    pub indices: Vec<u32>,
+}
+
+impl ChunkMesh {
+    /// Width of a section in bricks
+    pub const SIZE_BRICKS: i32 = 4;
+    /// Width of a section in voxels
+    pub const SIZE: usize = Self::SIZE_BRICKS as usize * 8;
+
+    /// Meshes the section at `section_pos`, in sections. Generates any chunks it or its
+    /// neighbours are in that aren't loaded yet.
+    pub fn build(world: &mut WorldManager, section_pos: glam::IVec3) -> Self {
+        let mut mesh = Self::default();
+        let min_brick = section_pos * Self::SIZE_BRICKS;
+        let Some(voxels) = gather_voxels(world, min_brick) else {
+            return mesh;
+        };
+
+        let origin = (min_brick * 8).as_vec3();
+        let mut mask = vec![0u16; Self::SIZE * Self::SIZE];
+        for axis in 0..3 {
+            for positive in [true, false] {
+                for slice in 0..Self::SIZE {
+                    fill_face_mask(&voxels, axis, positive, slice, &mut mask);
+                    mesh.merge_faces(&mut mask, axis, positive, slice, origin);
+                }
+            }
+        }
+        mesh
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    /// Greedily merges the faces in a slice's mask into quads, clearing it as it goes.
+    fn merge_faces(
+        &mut self,
+        mask: &mut [u16],
+        axis: usize,
+        positive: bool,
+        slice: usize,
+        origin: glam::Vec3,
+    ) {
+        let size = Self::SIZE;
+        for j in 0..size {
+            let mut i = 0;
+            while i < size {
+                let material = mask[i + j * size];
+                if material == 0 {
+                    i += 1;
+                    continue;
+                }
+
+                // Grow along the first axis, then add whole rows along the second
+                let mut width = 1;
+                while i + width < size && mask[i + width + j * size] == material {
+                    width += 1;
+                }
+                let mut height = 1;
+                'rows: while j + height < size {
+                    for k in 0..width {
+                        if mask[i + k + (j + height) * size] != material {
+                            break 'rows;
+                        }
+                    }
+                    height += 1;
+                }
+                for row in j..j + height {
+                    mask[i + row * size..i + width + row * size].fill(0);
+                }
+
+                self.push_quad(
+                    axis,
+                    positive,
+                    slice,
+                    (i, j, width, height),
+                    material as u32 - 1,
+                    origin,
+                );
+                i += width;
+            }
+        }
+    }
+
+    fn push_quad(
+        &mut self,
+        axis: usize,
+        positive: bool,
+        slice: usize,
+        (i, j, width, height): (usize, usize, usize, usize),
+        material: u32,
+        origin: glam::Vec3,
+    ) {
+        let u = (axis + 1) % 3;
+        let v = (axis + 2) % 3;
+        let mut corner = glam::Vec3::ZERO;
+        corner[axis] = (slice + positive as usize) as f32;
+        corner[u] = i as f32;
+        corner[v] = j as f32;
+        let mut du = glam::Vec3::ZERO;
+        du[u] = width as f32;
+        let mut dv = glam::Vec3::ZERO;
+        dv[v] = height as f32;
+
+        // u cross v points along the axis, so the winding is flipped for faces pointing
+        // the other way to keep them counter-clockwise from the front
+        let face = axis as u32 * 2 + !positive as u32;
+        let first = self.vertices.len() as u32;
+        for position in [corner, corner + du, corner + du + dv, corner + dv] {
+            self.vertices.push(MeshVertex {
+                position: (origin + position).to_array(),
+                face,
+                material,
+            });
+        }
+        let order: [u32; 6] = match positive {
+            true => [0, 1, 2, 0, 2, 3],
+            false => [0, 2, 1, 0, 3, 2],
+        };
+        self.indices.extend(order.iter().map(|i| first + i));
+    }
+}
+
+/// Material id + 1 of every voxel in a section and the layer of voxels around it, or 0 for
+/// empty ones. Returns `None` if the section itself is empty.
+fn gather_voxels(world: &mut WorldManager, min_brick: glam::IVec3) -> Option<Vec<u16>> {
+    let chunk_dims = world.get_chunk_dims().as_ivec3();
+    let mut voxels = vec![0u16; PADDED * PADDED * PADDED];
+    let mut any_solid = false;
+    for z in -1..=ChunkMesh::SIZE_BRICKS {
+        for y in -1..=ChunkMesh::SIZE_BRICKS {
+            for x in -1..=ChunkMesh::SIZE_BRICKS {
+                let brick = glam::ivec3(x, y, z);
+                let outside = brick.cmplt(glam::IVec3::ZERO)
+                    | brick.cmpge(glam::IVec3::splat(ChunkMesh::SIZE_BRICKS));
+                // Faces only care about the neighbours they touch, so the bricks off the
+                // section's edges and corners aren't needed
+                if outside.bitmask().count_ones() > 1 {
+                    continue;
+                }
+                let grid_pos = min_brick + brick;
+                if world.is_block_empty(grid_pos) {
+                    continue;
+                }
+                any_solid |= outside.bitmask() == 0;
+
+                let block = world.get_block(
+                    grid_pos.div_euclid(chunk_dims),
+                    grid_pos.rem_euclid(chunk_dims).as_uvec3(),
+                );
+                for (i, voxel) in block.iter().enumerate() {
+                    let Voxel::Material(id) = voxel else {
+                        continue;
+                    };
+                    let local = glam::ivec3(i as i32 % 8, (i as i32 / 8) % 8, i as i32 / 64);
+                    // Offset by one for the padding
+                    let pos = brick * 8 + local + 1;
+                    if pos.cmplt(glam::IVec3::ZERO).any()
+                        || pos.cmpge(glam::IVec3::splat(PADDED as i32)).any()
+                    {
+                        continue;
+                    }
+                    voxels[padded_index(pos.as_uvec3())] = id + 1;
+                }
+            }
+        }
+    }
+    any_solid.then_some(voxels)
+}
+
+fn padded_index(pos: glam::UVec3) -> usize {
+    pos.x as usize + pos.y as usize * PADDED + pos.z as usize * PADDED * PADDED
+}
+
+/// Fills `mask` with the material id + 1 of each voxel in a slice whose face in the given
+/// direction is exposed, or 0 where there's no face.
+fn fill_face_mask(voxels: &[u16], axis: usize, positive: bool, slice: usize, mask: &mut [u16]) {
+    let u = (axis + 1) % 3;
+    let v = (axis + 2) % 3;
+    let size = ChunkMesh::SIZE;
+    for j in 0..size {
+        for i in 0..size {
+            let mut pos = glam::UVec3::ZERO;
+            pos[axis] = slice as u32 + 1;
+            pos[u] = i as u32 + 1;
+            pos[v] = j as u32 + 1;
+            let mut neighbour = pos;
+            neighbour[axis] = match positive {
+                true => pos[axis] + 1,
+                false => pos[axis] - 1,
+            };
+            let voxel = voxels[padded_index(pos)];
+            mask[i + j * size] = match voxels[padded_index(neighbour)] {
+                0 => voxel,
+                _ => 0,
+            };
+        }
+    }
+}
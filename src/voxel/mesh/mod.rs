@@ -0,0 +1,5 @@
+mod mesher;
+mod renderer;
+
+pub use mesher::{ChunkMesh, MeshVertex};
+pub use renderer::MeshRenderer;
@@ -0,0 +1,389 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+use super::{ChunkMesh, MeshVertex};
+use crate::{
+    core::{CameraController, Lighting},
+    gfx::{self, BulkBufferBuilder},
+    voxel::{
+        brickmap::Atmosphere,
+        renderer::VoxelRenderer,
+        world::{MaterialTable, WorldId, WorldManager},
+    },
+};
+
+/// How many sections out from the camera get meshed and drawn
+const VIEW_DISTANCE: i32 = 8;
+/// How long each update gets to spend meshing sections that haven't been meshed yet
+const MESH_BUDGET: Duration = Duration::from_millis(4);
+const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct MeshParams {
+    sky_color: [f32; 3],
+    _pad: f32,
+}
+
+/// A section's mesh on the GPU.
+#[derive(Debug)]
+struct GpuMesh {
+    vertex_buffer: wgpu::Buffer,
+    index_buffer: wgpu::Buffer,
+    index_count: u32,
+}
+
+/// Draws the world as greedy meshed triangles with a plain vertex and fragment pipeline,
+/// as a baseline to compare the raycasters against and for devices with weak compute.
+/// Sections around the camera are meshed on the CPU a few at a time and dropped once
+/// they're out of range. There's only direct sun and ambient sky light, without shadows.
+#[derive(Debug)]
+pub struct MeshRenderer {
+    /// Where to mesh around, in bricks
+    focus: glam::Vec3,
+    world_id: Option<WorldId>,
+    material_version: Option<u64>,
+    /// Every section in range that's been meshed, `None` for sections with nothing in them
+    meshes: HashMap<glam::IVec3, Option<GpuMesh>>,
+    material_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    depth_texture: wgpu::Texture,
+    depth_view: wgpu::TextureView,
+    pipeline: wgpu::RenderPipeline,
+}
+
+impl MeshRenderer {
+    pub fn new(
+        context: &gfx::Context,
+        camera_controller: &CameraController,
+        lighting: &Lighting,
+    ) -> Result<Self> {
+        log::info!("Creating mesh renderer...");
+        let params = MeshParams {
+            sky_color: Atmosphere::default().sky_color.to_array(),
+            _pad: 0.0,
+        };
+        let mut buffers = BulkBufferBuilder::new()
+            .set_usage(wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST)
+            .with_init_buffer_bm("Mesh Params", &[params])
+            .set_usage(wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST)
+            .with_buffer(
+                "Mesh Materials",
+                MaterialTable::MAX_MATERIALS as u64 * 4,
+                false,
+            )
+            .build(context);
+        let params_buffer = buffers.remove(0);
+        let material_buffer = buffers.remove(0);
+
+        let visibility = wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT;
+        let layout = gfx::BindGroupLayoutBuilder::new()
+            .with_label("Mesh BGL")
+            .with_uniform_entry(visibility)
+            .with_uniform_entry(visibility)
+            .with_uniform_entry(visibility)
+            .with_ro_storage_entry(visibility)
+            .build(context);
+        let bind_group = gfx::BindGroupBuilder::new()
+            .with_label("Mesh BG")
+            .with_layout(&layout)
+            .with_entry(camera_controller.get_buffer().as_entire_binding())
+            .with_entry(lighting.get_buffer().as_entire_binding())
+            .with_entry(params_buffer.as_entire_binding())
+            .with_entry(material_buffer.as_entire_binding())
+            .build(context)?;
+
+        // TODO: Load the shader better
+        let shader = context
+            .device
+            .create_shader_module(wgpu::include_wgsl!("../../../assets/shaders/mesh.wgsl"));
+        let pipeline_layout =
+            context
+                .device
+                .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: Some("Mesh PL"),
+                    bind_group_layouts: &[&layout],
+                    push_constant_ranges: &[],
+                });
+        let pipeline = context
+            .device
+            .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("Mesh Pipeline"),
+                layout: Some(&pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vertex",
+                    buffers: &[wgpu::VertexBufferLayout {
+                        array_stride: std::mem::size_of::<MeshVertex>() as u64,
+                        step_mode: wgpu::VertexStepMode::Vertex,
+                        attributes: &wgpu::vertex_attr_array![
+                            0 => Float32x3,
+                            1 => Uint32,
+                            2 => Uint32
+                        ],
+                    }],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fragment",
+                    targets: &[Some(context.surface_config.format.into())],
+                }),
+                primitive: wgpu::PrimitiveState {
+                    cull_mode: Some(wgpu::Face::Back),
+                    ..Default::default()
+                },
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: DEPTH_FORMAT,
+                    depth_write_enabled: true,
+                    depth_compare: wgpu::CompareFunction::Less,
+                    stencil: wgpu::StencilState::default(),
+                    bias: wgpu::DepthBiasState::default(),
+                }),
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            });
+
+        let (depth_texture, depth_view) = Self::create_depth(context);
+        Ok(Self {
+            focus: camera_controller.get_position(),
+            world_id: None,
+            material_version: None,
+            meshes: HashMap::new(),
+            material_buffer,
+            bind_group,
+            depth_texture,
+            depth_view,
+            pipeline,
+        })
+    }
+
+    /// Sections meshed so far, and how many triangles they have between them.
+    pub fn get_mesh_stats(&self) -> (usize, u64) {
+        let triangles = self
+            .meshes
+            .values()
+            .flatten()
+            .map(|mesh| mesh.index_count as u64 / 3)
+            .sum();
+        (self.meshes.len(), triangles)
+    }
+
+    fn create_depth(context: &gfx::Context) -> (wgpu::Texture, wgpu::TextureView) {
+        let texture = context.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Mesh Depth"),
+            size: wgpu::Extent3d {
+                width: context.size.width,
+                height: context.size.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: DEPTH_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        (texture, view)
+    }
+
+    fn upload_mesh(context: &gfx::Context, mesh: &ChunkMesh) -> Option<GpuMesh> {
+        if mesh.is_empty() {
+            return None;
+        }
+        let mut buffers = BulkBufferBuilder::new()
+            .set_usage(wgpu::BufferUsages::VERTEX)
+            .with_init_buffer_bm("Mesh Vertices", &mesh.vertices)
+            .set_usage(wgpu::BufferUsages::INDEX)
+            .with_init_buffer_bm("Mesh Indices", &mesh.indices)
+            .build(context);
+        Some(GpuMesh {
+            vertex_buffer: buffers.remove(0),
+            index_buffer: buffers.remove(0),
+            index_count: mesh.indices.len() as u32,
+        })
+    }
+
+    fn upload_materials(&mut self, context: &gfx::Context, world: &WorldManager) {
+        let table = world.get_materials();
+        if self.material_version == Some(table.get_version()) {
+            return;
+        }
+        let albedos: Vec<u32> = table
+            .get_materials()
+            .iter()
+            .map(|material| material.get_packed_albedo())
+            .collect();
+        context
+            .queue
+            .write_buffer(&self.material_buffer, 0, bytemuck::cast_slice(&albedos));
+        self.material_version = Some(table.get_version());
+    }
+
+    /// Sections that need meshing again after their bricks were edited. Edits on a
+    /// section's edge can expose faces in the section next to it too.
+    fn get_edited_sections(world: &mut WorldManager) -> HashSet<glam::IVec3> {
+        let mut sections = HashSet::new();
+        for block in world.take_dirty_blocks() {
+            let section = block.div_euclid(glam::IVec3::splat(ChunkMesh::SIZE_BRICKS));
+            let local = block.rem_euclid(glam::IVec3::splat(ChunkMesh::SIZE_BRICKS));
+            sections.insert(section);
+            for axis in 0..3 {
+                let mut offset = glam::IVec3::ZERO;
+                offset[axis] = 1;
+                if local[axis] == 0 {
+                    sections.insert(section - offset);
+                }
+                if local[axis] == ChunkMesh::SIZE_BRICKS - 1 {
+                    sections.insert(section + offset);
+                }
+            }
+        }
+        sections
+    }
+}
+
+impl VoxelRenderer for MeshRenderer {
+    fn update(
+        &mut self,
+        _dt: &Duration,
+        context: &gfx::Context,
+        world: &mut WorldManager,
+    ) -> Result<()> {
+        if self.world_id != Some(world.get_id()) {
+            self.meshes.clear();
+            self.material_version = None;
+            self.world_id = Some(world.get_id());
+        }
+        self.upload_materials(context, world);
+
+        // Edited sections are remeshed straight away so they don't flicker out of view.
+        // Ones that haven't been meshed yet are left for the loop below
+        for section in Self::get_edited_sections(world) {
+            if self.meshes.contains_key(&section) {
+                let mesh = ChunkMesh::build(world, section);
+                self.meshes
+                    .insert(section, Self::upload_mesh(context, &mesh));
+            }
+        }
+
+        let center = (self.focus / ChunkMesh::SIZE_BRICKS as f32)
+            .floor()
+            .as_ivec3();
+        self.meshes
+            .retain(|pos, _| (*pos - center).abs().max_element() <= VIEW_DISTANCE + 1);
+
+        // Mesh whatever's missing nearest first, for as long as the budget allows
+        let mut missing = Vec::new();
+        for z in -VIEW_DISTANCE..=VIEW_DISTANCE {
+            for y in -VIEW_DISTANCE..=VIEW_DISTANCE {
+                for x in -VIEW_DISTANCE..=VIEW_DISTANCE {
+                    let pos = center + glam::ivec3(x, y, z);
+                    if !self.meshes.contains_key(&pos) {
+                        missing.push(pos);
+                    }
+                }
+            }
+        }
+        missing.sort_by_key(|pos| (*pos - center).length_squared());
+        let start = Instant::now();
+        for pos in missing {
+            if start.elapsed() > MESH_BUDGET {
+                break;
+            }
+            let mesh = ChunkMesh::build(world, pos);
+            self.meshes.insert(pos, Self::upload_mesh(context, &mesh));
+        }
+        Ok(())
+    }
+
+    fn render(&self, context: &gfx::Context) -> Result<()> {
+        // There's nothing to draw to while the app is suspended
+        let Some(frame) = context.get_current_frame()? else {
+            return Ok(());
+        };
+        let view = &frame.view;
+        let mut encoder = context
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+        {
+            let sky = Atmosphere::default().sky_color.as_dvec3();
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Mesh Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color {
+                            r: sky.x,
+                            g: sky.y,
+                            b: sky.z,
+                            a: 1.0,
+                        }),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &self.depth_view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: wgpu::StoreOp::Discard,
+                    }),
+                    stencil_ops: None,
+                }),
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+            render_pass.set_pipeline(&self.pipeline);
+            render_pass.set_bind_group(0, &self.bind_group, &[]);
+            for mesh in self.meshes.values().flatten() {
+                render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+                render_pass
+                    .set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                render_pass.draw_indexed(0..mesh.index_count, 0, 0..1);
+            }
+        }
+
+        context.queue.submit(Some(encoder.finish()));
+        frame.present();
+        Ok(())
+    }
+
+    fn resize(
+        &mut self,
+        context: &gfx::Context,
+        _camera_controller: &CameraController,
+    ) -> Result<()> {
+        let size = self.depth_texture.size();
+        if size.width == context.size.width && size.height == context.size.height {
+            return Ok(());
+        }
+        log::info!(
+            "Resizing mesh depth buffer to {}x{}",
+            context.size.width,
+            context.size.height
+        );
+        (self.depth_texture, self.depth_view) = Self::create_depth(context);
+        Ok(())
+    }
+
+    fn rebuild(
+        &mut self,
+        context: &gfx::Context,
+        camera_controller: &CameraController,
+        lighting: &Lighting,
+    ) -> Result<()> {
+        let focus = self.focus;
+        *self = Self::new(context, camera_controller, lighting)?;
+        self.focus = focus;
+        Ok(())
+    }
+
+    fn set_focus(&mut self, position: glam::Vec3) {
+        self.focus = position;
+    }
+}
@@ -1,5 +1,8 @@
 pub mod brickmap;
+pub mod io;
+pub mod mesh;
 mod renderer;
+pub mod svo;
 pub mod world;
 
 pub use renderer::VoxelRenderer;
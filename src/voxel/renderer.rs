@@ -2,10 +2,41 @@ use std::time::Duration;
 
 use anyhow::Result;
 
-use super::world::WorldManager;
-use crate::gfx::Context;
+use super::{brickmap::BrickmapRenderer, world::WorldManager};
+use crate::{
+    core::{CameraController, Lighting},
+    gfx::{Context, FrameImage},
+};
 
 pub trait VoxelRenderer {
     fn update(&mut self, dt: &Duration, context: &Context, world: &mut WorldManager) -> Result<()>;
     fn render(&self, context: &Context) -> Result<()>;
+    /// Renders a frame and copies it back to the CPU, for headless contexts (see
+    /// `Context::new_headless`).
+    fn render_image(&self, context: &Context) -> Result<FrameImage> {
+        self.render(context)?;
+        context.read_frame()
+    }
+    /// Recreates anything sized to the screen to match the surface. Does nothing if the
+    /// size hasn't changed.
+    fn resize(&mut self, context: &Context, camera_controller: &CameraController) -> Result<()>;
+    /// Recreates every GPU resource on the context's current device, for when the old
+    /// device was lost or replaced (see `Context::recreate_device`). Anything that only
+    /// lived on the GPU, e.g. meshes or streamed bricks, is rebuilt from the world again.
+    fn rebuild(
+        &mut self,
+        context: &Context,
+        camera_controller: &CameraController,
+        lighting: &Lighting,
+    ) -> Result<()>;
+    /// Tells renderers that only keep the world around the camera where it is, in bricks.
+    fn set_focus(&mut self, _position: glam::Vec3) {}
+    /// The brickmap renderer, for everything only it can do (e.g. picking, lights and
+    /// its debug settings). The other renderers are only there to compare against.
+    fn as_brickmap(&self) -> Option<&BrickmapRenderer> {
+        None
+    }
+    fn as_brickmap_mut(&mut self) -> Option<&mut BrickmapRenderer> {
+        None
+    }
 }
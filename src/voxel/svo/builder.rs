@@ -0,0 +1,154 @@
+use std::time::Instant;
+
+use anyhow::{bail, Result};
+
+use crate::voxel::{brickmap, world::WorldManager};
+
+/// A sparse voxel octree over a cube of the world, holding only its surface voxels.
+///
+/// Nodes are packed into a flat array of words. A node's word is its child mask in the
+/// low 8 bits and the index of its first child above, with only the children that exist
+/// stored, next to each other in child order. Children are numbered x | y << 1 | z << 2,
+/// with a set bit meaning the upper half along that axis. At the bottom level the
+/// "nodes" are the voxels themselves, each word holding the voxel's material id. The
+/// root is always the first word.
+#[derive(Debug, Clone)]
+pub struct Svo {
+    /// Voxel the octree's lower corner is at
+    origin: glam::IVec3,
+    /// Levels below the root, so the octree is 2^depth voxels across
+    depth: u32,
+    nodes: Vec<u32>,
+}
+
+impl Svo {
+    /// Most words the octree can have before child indices stop fitting in a node
+    pub const MAX_NODES: usize = 1 << 24;
+    /// A brick is 2^3 voxels across, so octrees have to be at least that deep
+    pub const MIN_DEPTH: u32 = 3;
+
+    /// Builds an octree over the cube of bricks starting at `origin` (in bricks) that's
+    /// 2^depth voxels across, generating any chunks it covers that aren't loaded yet.
+    pub fn build(world: &mut WorldManager, origin: glam::IVec3, depth: u32) -> Result<Self> {
+        if depth < Self::MIN_DEPTH {
+            bail!("Octrees need a depth of at least {}", Self::MIN_DEPTH);
+        }
+
+        let start = Instant::now();
+        // The root goes first, and is only known once everything under it is built
+        let mut nodes = vec![0];
+        let size_bricks = 1 << (depth - Self::MIN_DEPTH);
+        let root = build_node(&mut nodes, world, origin, size_bricks)?;
+        nodes[0] = root.unwrap_or(0);
+        log::info!(
+            "Built {} voxel octree at {} with {} nodes in {:.1}ms",
+            1 << depth,
+            origin,
+            nodes.len(),
+            start.elapsed().as_secs_f32() * 1000.0
+        );
+
+        Ok(Self {
+            origin: origin * 8,
+            depth,
+            nodes,
+        })
+    }
+
+    pub fn get_origin(&self) -> glam::IVec3 {
+        self.origin
+    }
+
+    pub fn get_depth(&self) -> u32 {
+        self.depth
+    }
+
+    /// Width of the octree in voxels.
+    pub fn get_size(&self) -> u32 {
+        1 << self.depth
+    }
+
+    pub fn get_nodes(&self) -> &[u32] {
+        &self.nodes
+    }
+}
+
+/// Builds the node covering `size` bricks from `min` (in bricks), returning its word or
+/// `None` if there's nothing in it.
+fn build_node(
+    nodes: &mut Vec<u32>,
+    world: &mut WorldManager,
+    min: glam::IVec3,
+    size: i32,
+) -> Result<Option<u32>> {
+    if size == 1 {
+        return build_brick(nodes, world, min);
+    }
+
+    let half = size / 2;
+    let mut children = [None; 8];
+    for (i, child) in children.iter_mut().enumerate() {
+        let offset = glam::ivec3(i as i32 & 1, (i as i32 >> 1) & 1, (i as i32 >> 2) & 1);
+        *child = build_node(nodes, world, min + offset * half, half)?;
+    }
+    push_children(nodes, children)
+}
+
+/// Builds the three levels of nodes within a brick from its surface voxels.
+fn build_brick(
+    nodes: &mut Vec<u32>,
+    world: &mut WorldManager,
+    grid_pos: glam::IVec3,
+) -> Result<Option<u32>> {
+    if world.is_block_empty(grid_pos) {
+        return Ok(None);
+    }
+
+    // Interior voxels can never be seen, so they're left out like they are for bricks
+    let (bitmask, shading, _, _) = brickmap::cull_interior_voxels(world, grid_pos);
+    let mut materials = [None; 512];
+    let mut shading = shading.iter();
+    for (i, material) in materials.iter_mut().enumerate() {
+        if bitmask[i / 32] & (1 << (i % 32)) != 0 {
+            *material = shading.next().map(|data| data & 0xFFFF);
+        }
+    }
+    build_voxels(nodes, &materials, glam::UVec3::ZERO, 8)
+}
+
+fn build_voxels(
+    nodes: &mut Vec<u32>,
+    materials: &[Option<u32>; 512],
+    min: glam::UVec3,
+    size: u32,
+) -> Result<Option<u32>> {
+    if size == 1 {
+        return Ok(materials[(min.x + min.y * 8 + min.z * 64) as usize]);
+    }
+
+    let half = size / 2;
+    let mut children = [None; 8];
+    for (i, child) in children.iter_mut().enumerate() {
+        let offset = glam::uvec3(i as u32 & 1, (i as u32 >> 1) & 1, (i as u32 >> 2) & 1);
+        *child = build_voxels(nodes, materials, min + offset * half, half)?;
+    }
+    push_children(nodes, children)
+}
+
+/// Stores whichever children exist next to each other, returning their parent's word.
+fn push_children(nodes: &mut Vec<u32>, children: [Option<u32>; 8]) -> Result<Option<u32>> {
+    let mut mask = 0;
+    for (i, child) in children.iter().enumerate() {
+        mask |= (child.is_some() as u32) << i;
+    }
+    if mask == 0 {
+        return Ok(None);
+    }
+
+    let first = nodes.len();
+    if first + mask.count_ones() as usize > Svo::MAX_NODES {
+        bail!("Octree has more than {} nodes", Svo::MAX_NODES);
+    }
+    nodes.extend(children.iter().flatten());
+    Ok(Some(((first as u32) << 8) | mask))
+}
@@ -0,0 +1,5 @@
+mod builder;
+mod renderer;
+
+pub use builder::Svo;
+pub use renderer::SvoRenderer;
@@ -0,0 +1,385 @@
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+
+use super::Svo;
+use crate::{
+    core::{CameraController, Lighting},
+    gfx::{self, BulkBufferBuilder},
+    voxel::{
+        brickmap::Atmosphere,
+        renderer::VoxelRenderer,
+        world::{MaterialTable, WorldId, WorldManager},
+    },
+};
+
+/// Cells a ray can step through before giving up
+const MAX_STEPS: u32 = 256;
+/// How far the camera can get from the middle of the octree before it's rebuilt around
+/// it, as a fraction of the octree's width
+const REBUILD_DISTANCE: f32 = 0.25;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct SvoParams {
+    origin: [i32; 3],
+    depth: u32,
+    sky_color: [f32; 3],
+    max_steps: u32,
+}
+
+/// Draws the world by tracing rays through a sparse voxel octree, to compare against the
+/// brickmap renderer. The octree covers a fixed size cube of the world around the camera
+/// and is rebuilt on the CPU whenever the camera strays too far from its middle or the
+/// world is edited. Nothing is streamed, so anything outside the cube isn't drawn.
+#[derive(Debug)]
+pub struct SvoRenderer {
+    depth: u32,
+    svo: Option<Svo>,
+    /// Where the octree should be centred, in bricks
+    focus: glam::Vec3,
+    world_id: Option<WorldId>,
+    material_version: Option<u64>,
+    params_buffer: wgpu::Buffer,
+    node_buffer: wgpu::Buffer,
+    material_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    output: gfx::Texture,
+    output_layout: wgpu::BindGroupLayout,
+    output_bind_group: wgpu::BindGroup,
+    trace_pipeline: wgpu::ComputePipeline,
+    blit_pipeline: wgpu::RenderPipeline,
+}
+
+impl SvoRenderer {
+    /// Default depth, an octree 256 voxels or 32 bricks across
+    pub const DEFAULT_DEPTH: u32 = 8;
+    /// Deepest octree the renderer accepts, 2048 voxels across. Deeper ones take far too
+    /// long to rebuild whenever the camera moves
+    pub const MAX_DEPTH: u32 = 11;
+
+    /// Creates a renderer for octrees `depth` levels deep, so 2^depth voxels across.
+    pub fn new(
+        context: &gfx::Context,
+        camera_controller: &CameraController,
+        lighting: &Lighting,
+        depth: u32,
+    ) -> Result<Self> {
+        log::info!("Creating SVO renderer...");
+        if !(Svo::MIN_DEPTH..=Self::MAX_DEPTH).contains(&depth) {
+            bail!(
+                "Octree depth has to be between {} and {}",
+                Svo::MIN_DEPTH,
+                Self::MAX_DEPTH
+            );
+        }
+
+        // The node buffer is made big enough for the largest octree up front, so it never
+        // needs to be recreated along with the bind group
+        let node_buffer_size = u64::min(
+            Svo::MAX_NODES as u64 * 4,
+            context.limits.max_storage_buffer_binding_size as u64,
+        );
+        let mut buffers = BulkBufferBuilder::new()
+            .set_usage(wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST)
+            .with_init_buffer_bm("SVO Params", &[SvoParams::default()])
+            .set_usage(wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST)
+            .with_buffer("SVO Nodes", node_buffer_size, false)
+            .with_buffer(
+                "SVO Materials",
+                MaterialTable::MAX_MATERIALS as u64 * 4,
+                false,
+            )
+            .build(context);
+        let params_buffer = buffers.remove(0);
+        let node_buffer = buffers.remove(0);
+        let material_buffer = buffers.remove(0);
+
+        let layout = gfx::BindGroupLayoutBuilder::new()
+            .with_label("SVO BGL")
+            .with_uniform_entry(wgpu::ShaderStages::COMPUTE)
+            .with_uniform_entry(wgpu::ShaderStages::COMPUTE)
+            .with_uniform_entry(wgpu::ShaderStages::COMPUTE)
+            .with_ro_storage_entry(wgpu::ShaderStages::COMPUTE)
+            .with_ro_storage_entry(wgpu::ShaderStages::COMPUTE)
+            .build(context);
+        let bind_group = gfx::BindGroupBuilder::new()
+            .with_label("SVO BG")
+            .with_layout(&layout)
+            .with_entry(camera_controller.get_buffer().as_entire_binding())
+            .with_entry(lighting.get_buffer().as_entire_binding())
+            .with_entry(params_buffer.as_entire_binding())
+            .with_entry(node_buffer.as_entire_binding())
+            .with_entry(material_buffer.as_entire_binding())
+            .build(context)?;
+
+        // The output gets its own group so resizing doesn't touch the rest
+        let output_layout = gfx::BindGroupLayoutBuilder::new()
+            .with_label("SVO Output BGL")
+            .with_entry(
+                wgpu::ShaderStages::COMPUTE,
+                wgpu::BindingType::StorageTexture {
+                    access: wgpu::StorageTextureAccess::WriteOnly,
+                    format: wgpu::TextureFormat::Rgba8Unorm,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                },
+                None,
+            )
+            .build(context);
+        let output = Self::create_output(context)?;
+        let output_bind_group = Self::create_output_bind_group(context, &output_layout, &output)?;
+
+        // TODO: Load the shaders better
+        let trace_shader = context
+            .device
+            .create_shader_module(wgpu::include_wgsl!("../../../assets/shaders/svo.wgsl"));
+        let trace_layout = context
+            .device
+            .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("SVO Trace PL"),
+                bind_group_layouts: &[&layout, &output_layout],
+                push_constant_ranges: &[],
+            });
+        let trace_pipeline =
+            context
+                .device
+                .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                    label: Some("SVO Trace Pipeline"),
+                    layout: Some(&trace_layout),
+                    module: &trace_shader,
+                    entry_point: "main",
+                });
+
+        let blit_shader = context
+            .device
+            .create_shader_module(wgpu::include_wgsl!("../../../assets/shaders/svo_blit.wgsl"));
+        let blit_layout = context
+            .device
+            .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("SVO Blit PL"),
+                bind_group_layouts: &[&output.bind_group_layout],
+                push_constant_ranges: &[],
+            });
+        let blit_pipeline =
+            context
+                .device
+                .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some("SVO Blit Pipeline"),
+                    layout: Some(&blit_layout),
+                    vertex: wgpu::VertexState {
+                        module: &blit_shader,
+                        entry_point: "vertex",
+                        buffers: &[],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &blit_shader,
+                        entry_point: "fragment",
+                        targets: &[Some(context.surface_config.format.into())],
+                    }),
+                    primitive: wgpu::PrimitiveState::default(),
+                    depth_stencil: None,
+                    multisample: wgpu::MultisampleState::default(),
+                    multiview: None,
+                });
+
+        Ok(Self {
+            depth,
+            svo: None,
+            focus: camera_controller.get_position(),
+            world_id: None,
+            material_version: None,
+            params_buffer,
+            node_buffer,
+            material_buffer,
+            bind_group,
+            output,
+            output_layout,
+            output_bind_group,
+            trace_pipeline,
+            blit_pipeline,
+        })
+    }
+
+    pub fn get_svo(&self) -> Option<&Svo> {
+        self.svo.as_ref()
+    }
+
+    fn create_output(context: &gfx::Context) -> Result<gfx::Texture> {
+        gfx::TextureBuilder::new()
+            .with_size(context.size.width, context.size.height, 1)
+            .with_format(wgpu::TextureFormat::Rgba8Unorm)
+            .with_usage(wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::STORAGE_BINDING)
+            .with_shader_visibility(wgpu::ShaderStages::FRAGMENT)
+            .build(context)
+    }
+
+    fn create_output_bind_group(
+        context: &gfx::Context,
+        layout: &wgpu::BindGroupLayout,
+        output: &gfx::Texture,
+    ) -> Result<wgpu::BindGroup> {
+        gfx::BindGroupBuilder::new()
+            .with_label("SVO Output BG")
+            .with_layout(layout)
+            .with_entry(wgpu::BindingResource::TextureView(&output.view))
+            .build(context)
+    }
+
+    /// Whether the octree needs building again around the focus.
+    fn needs_rebuild(&self, world: &WorldManager) -> bool {
+        let Some(svo) = &self.svo else {
+            return true;
+        };
+        if self.world_id != Some(world.get_id()) {
+            return true;
+        }
+        let size = svo.get_size() as f32 / 8.0;
+        let center = svo.get_origin().as_vec3() / 8.0 + size / 2.0;
+        (self.focus - center).abs().max_element() > size * REBUILD_DISTANCE
+    }
+
+    fn upload_materials(&mut self, context: &gfx::Context, world: &WorldManager) {
+        let table = world.get_materials();
+        if self.material_version == Some(table.get_version()) {
+            return;
+        }
+        let albedos: Vec<u32> = table
+            .get_materials()
+            .iter()
+            .map(|material| material.get_packed_albedo())
+            .collect();
+        context
+            .queue
+            .write_buffer(&self.material_buffer, 0, bytemuck::cast_slice(&albedos));
+        self.material_version = Some(table.get_version());
+    }
+}
+
+impl VoxelRenderer for SvoRenderer {
+    fn update(
+        &mut self,
+        _dt: &Duration,
+        context: &gfx::Context,
+        world: &mut WorldManager,
+    ) -> Result<()> {
+        // Edits anywhere in the octree mean building all of it again, there's no patching
+        // it in place
+        let edited = !world.take_dirty_blocks().is_empty();
+        if edited || self.needs_rebuild(world) {
+            if self.world_id != Some(world.get_id()) {
+                self.material_version = None;
+            }
+            let half_size = 1 << (self.depth - Svo::MIN_DEPTH - 1);
+            let origin = self.focus.floor().as_ivec3() - half_size;
+            let svo = Svo::build(world, origin, self.depth)?;
+            let nodes = svo.get_nodes();
+            if nodes.len() as u64 * 4 > self.node_buffer.size() {
+                bail!(
+                    "Octree has {} nodes, more than the node buffer can hold",
+                    nodes.len()
+                );
+            }
+            context
+                .queue
+                .write_buffer(&self.node_buffer, 0, bytemuck::cast_slice(nodes));
+
+            let params = SvoParams {
+                origin: svo.get_origin().to_array(),
+                depth: svo.get_depth(),
+                sky_color: Atmosphere::default().sky_color.to_array(),
+                max_steps: MAX_STEPS,
+            };
+            context
+                .queue
+                .write_buffer(&self.params_buffer, 0, bytemuck::cast_slice(&[params]));
+            self.svo = Some(svo);
+            self.world_id = Some(world.get_id());
+        }
+        self.upload_materials(context, world);
+        Ok(())
+    }
+
+    fn render(&self, context: &gfx::Context) -> Result<()> {
+        // There's nothing to draw to while the app is suspended
+        let Some(frame) = context.get_current_frame()? else {
+            return Ok(());
+        };
+        let view = &frame.view;
+        let mut encoder = context
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+
+        let size = self.output.attributes.size;
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("SVO Trace"),
+                timestamp_writes: None,
+            });
+            compute_pass.set_pipeline(&self.trace_pipeline);
+            compute_pass.set_bind_group(0, &self.bind_group, &[]);
+            compute_pass.set_bind_group(1, &self.output_bind_group, &[]);
+            compute_pass.dispatch_workgroups(size.width.div_ceil(8), size.height.div_ceil(8), 1);
+        }
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("SVO Blit"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+            render_pass.set_pipeline(&self.blit_pipeline);
+            render_pass.set_bind_group(0, &self.output.bind_group, &[]);
+            render_pass.draw(0..6, 0..1);
+        }
+
+        context.queue.submit(Some(encoder.finish()));
+        frame.present();
+        Ok(())
+    }
+
+    fn resize(
+        &mut self,
+        context: &gfx::Context,
+        _camera_controller: &CameraController,
+    ) -> Result<()> {
+        let size = self.output.attributes.size;
+        if size.width == context.size.width && size.height == context.size.height {
+            return Ok(());
+        }
+        log::info!(
+            "Resizing SVO output to {}x{}",
+            context.size.width,
+            context.size.height
+        );
+        self.output = Self::create_output(context)?;
+        self.output_bind_group =
+            Self::create_output_bind_group(context, &self.output_layout, &self.output)?;
+        Ok(())
+    }
+
+    /// The octree is only rebuilt around the focus once it's far enough from the current
+    /// octree's middle.
+    fn rebuild(
+        &mut self,
+        context: &gfx::Context,
+        camera_controller: &CameraController,
+        lighting: &Lighting,
+    ) -> Result<()> {
+        let focus = self.focus;
+        *self = Self::new(context, camera_controller, lighting, self.depth)?;
+        self.focus = focus;
+        Ok(())
+    }
+
+    fn set_focus(&mut self, position: glam::Vec3) {
+        self.focus = position;
+    }
+}
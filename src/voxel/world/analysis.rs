@@ -0,0 +1,289 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::Path,
+};
+
+use anyhow::{bail, Result};
+
+use crate::{math, voxel::brickmap::BrickmapCache};
+
+use super::{manager::MATERIALS_FILE, MaterialId, MaterialTable, Voxel, WorldStorage};
+
+/// Bits of a brick's solid mask slice with x at 0 or 7, where a slice is a z level of
+/// voxels with one bit per x + y * 8
+const SLICE_X0: u64 = 0x0101010101010101;
+const SLICE_X7: u64 = 0x8080808080808080;
+
+/// Bytes a brick's detail takes in the renderer's detail texture, 2 bits per voxel
+const BRICK_DETAIL_SIZE: u64 = 128;
+
+/// How full one of a world's regions is.
+#[derive(Debug, Clone, Copy)]
+pub struct RegionStats {
+    pub pos: glam::IVec3,
+    pub chunks: usize,
+    /// Bricks that were generated and saved, empty or not
+    pub stored_bricks: usize,
+    /// Saved bricks with at least one solid voxel
+    pub occupied_bricks: usize,
+}
+
+/// What's in a saved world and roughly what it would take to view it, found by scanning
+/// every region on disk. Only saved bricks are counted, anything that was never generated
+/// gets generated from noise as usual and isn't part of the save.
+///
+/// Surface area counts every voxel face that isn't against another solid voxel, with
+/// bricks that weren't saved counting as empty space, the same as the renderer treats
+/// chunks that aren't loaded.
+#[derive(Debug, Clone)]
+pub struct WorldStats {
+    chunk_dims: glam::UVec3,
+    voxel_size: f32,
+    materials: MaterialTable,
+    regions: Vec<RegionStats>,
+    /// Solid voxels of each material
+    material_counts: BTreeMap<MaterialId, u64>,
+    /// Bricks with at least one surface voxel, which are what the renderer uploads
+    surface_bricks: u64,
+    surface_voxels: u64,
+    exposed_faces: u64,
+    /// Corners of the box around every occupied brick, in bricks
+    bounds: Option<(glam::IVec3, glam::IVec3)>,
+}
+
+impl WorldStats {
+    /// Scans the world saved in `directory`. Regions saved before voxels had materials
+    /// need to be upgraded by loading the world in the app first.
+    pub fn scan(directory: &Path, chunk_dims: glam::UVec3, voxel_size: f32) -> Result<Self> {
+        if !directory.is_dir() {
+            bail!("No world at {:?}", directory);
+        }
+        let storage = WorldStorage::new(directory)?;
+        let materials_path = directory.join(MATERIALS_FILE);
+        let materials = match materials_path.exists() {
+            true => MaterialTable::load(&materials_path)?,
+            false => MaterialTable::new(),
+        };
+
+        // Keeping the voxels of the whole world around would take far too much memory,
+        // so only which voxels are solid is kept for finding the surface afterwards
+        let mut region_positions = storage.get_region_positions()?;
+        region_positions.sort_by_key(|p| (p.y, p.z, p.x));
+        let mut solid_masks: HashMap<glam::IVec3, [u64; 8]> = HashMap::new();
+        let mut material_counts = BTreeMap::new();
+        let mut regions = Vec::with_capacity(region_positions.len());
+        for region_pos in region_positions {
+            let chunks = storage.load_region(region_pos)?;
+            let mut region = RegionStats {
+                pos: region_pos,
+                chunks: chunks.len(),
+                stored_bricks: 0,
+                occupied_bricks: 0,
+            };
+
+            for (chunk_pos, blocks) in chunks {
+                for (block_idx, voxels) in blocks {
+                    region.stored_bricks += 1;
+                    let mut mask = [0u64; 8];
+                    for (voxel_idx, voxel) in voxels.iter().enumerate() {
+                        if let Voxel::Material(id) = voxel {
+                            mask[voxel_idx / 64] |= 1 << (voxel_idx % 64);
+                            *material_counts.entry(*id).or_insert(0) += 1;
+                        }
+                    }
+                    if mask.iter().any(|slice| *slice != 0) {
+                        region.occupied_bricks += 1;
+                        let block_pos = math::to_3d_index(block_idx, chunk_dims).as_ivec3();
+                        let grid_pos = chunk_pos * chunk_dims.as_ivec3() + block_pos;
+                        solid_masks.insert(grid_pos, mask);
+                    }
+                }
+            }
+            regions.push(region);
+        }
+
+        let mut stats = Self {
+            chunk_dims,
+            voxel_size,
+            materials,
+            regions,
+            material_counts,
+            surface_bricks: 0,
+            surface_voxels: 0,
+            exposed_faces: 0,
+            bounds: None,
+        };
+        for (grid_pos, mask) in &solid_masks {
+            let neighbour = |offset: glam::IVec3| {
+                solid_masks
+                    .get(&(*grid_pos + offset))
+                    .copied()
+                    .unwrap_or_default()
+            };
+            let (faces, voxels) = count_surface(
+                mask,
+                [
+                    neighbour(glam::IVec3::X),
+                    neighbour(glam::IVec3::NEG_X),
+                    neighbour(glam::IVec3::Y),
+                    neighbour(glam::IVec3::NEG_Y),
+                    neighbour(glam::IVec3::Z),
+                    neighbour(glam::IVec3::NEG_Z),
+                ],
+            );
+            stats.exposed_faces += faces;
+            stats.surface_voxels += voxels;
+            stats.surface_bricks += (voxels > 0) as u64;
+            stats.bounds = Some(match stats.bounds {
+                Some((min, max)) => (min.min(*grid_pos), max.max(*grid_pos)),
+                None => (*grid_pos, *grid_pos),
+            });
+        }
+
+        Ok(stats)
+    }
+
+    pub fn get_regions(&self) -> &[RegionStats] {
+        &self.regions
+    }
+
+    /// Solid voxels of each material, by material id.
+    pub fn get_material_counts(&self) -> &BTreeMap<MaterialId, u64> {
+        &self.material_counts
+    }
+
+    pub fn get_solid_voxel_count(&self) -> u64 {
+        self.material_counts.values().sum()
+    }
+
+    /// Area of every exposed voxel face in square metres.
+    pub fn get_surface_area(&self) -> f64 {
+        self.exposed_faces as f64 * (self.voxel_size as f64).powi(2)
+    }
+
+    /// Fraction of a region's bricks that have anything in them.
+    pub fn get_region_density(&self, region: &RegionStats) -> f64 {
+        let capacity =
+            WorldStorage::CHUNKS_PER_REGION as f64 * self.chunk_dims.element_product() as f64;
+        region.occupied_bricks as f64 / capacity
+    }
+
+    /// Bytes the renderer would need to hold the whole world at once, as {brickmaps and
+    /// their detail, shading table, brickgrid}. The shading table's buckets and the
+    /// brickgrid's mips add a bit on top.
+    pub fn get_gpu_memory_estimate(&self) -> (u64, u64, u64) {
+        let brickmaps =
+            self.surface_bricks * (BrickmapCache::BRICKMAP_SIZE as u64 + BRICK_DETAIL_SIZE);
+        let shading = self.surface_voxels * 4;
+        let brickgrid = match self.bounds {
+            Some((min, max)) => (max - min + 1).as_u64vec3().element_product() * 4,
+            None => 0,
+        };
+        (brickmaps, shading, brickgrid)
+    }
+}
+
+impl fmt::Display for WorldStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let solid = self.get_solid_voxel_count();
+        let stored: usize = self.regions.iter().map(|r| r.stored_bricks).sum();
+        let occupied: usize = self.regions.iter().map(|r| r.occupied_bricks).sum();
+        writeln!(
+            f,
+            "{} regions, {} stored bricks, {} occupied, {} with a surface",
+            self.regions.len(),
+            stored,
+            occupied,
+            self.surface_bricks
+        )?;
+        if let Some((min, max)) = self.bounds {
+            writeln!(f, "Bounds: {} to {} bricks", min, max + 1)?;
+        }
+        writeln!(
+            f,
+            "Surface: {} faces on {} voxels, {:.1} m^2",
+            self.exposed_faces,
+            self.surface_voxels,
+            self.get_surface_area()
+        )?;
+
+        writeln!(f, "\n{} solid voxels by material:", solid)?;
+        let mut counts: Vec<_> = self.material_counts.iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(a.1));
+        for (id, count) in counts {
+            let [r, g, b] = self.materials.get(*id).albedo;
+            writeln!(
+                f,
+                "  {:>4} #{:02x}{:02x}{:02x} {:>14} {:>6.2}%",
+                id,
+                r,
+                g,
+                b,
+                count,
+                *count as f64 * 100.0 / solid.max(1) as f64
+            )?;
+        }
+
+        writeln!(f, "\nOccupied brick density by region:")?;
+        for region in &self.regions {
+            writeln!(
+                f,
+                "  {:>14} {:>4} chunks {:>9} stored {:>9} occupied {:>7.3}%",
+                format!("{}", region.pos),
+                region.chunks,
+                region.stored_bricks,
+                region.occupied_bricks,
+                self.get_region_density(region) * 100.0
+            )?;
+        }
+
+        let (brickmaps, shading, brickgrid) = self.get_gpu_memory_estimate();
+        let mib = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+        writeln!(
+            f,
+            "\nGPU memory to view it all: {:.1} MiB ({:.1} brickmaps, {:.1} shading, {:.1} brickgrid)",
+            mib(brickmaps + shading + brickgrid),
+            mib(brickmaps),
+            mib(shading),
+            mib(brickgrid)
+        )
+    }
+}
+
+/// Counts the exposed faces and surface voxels of a brick from its solid mask and those
+/// of its neighbours in +x, -x, +y, -y, +z, -z order.
+fn count_surface(mask: &[u64; 8], neighbours: [[u64; 8]; 6]) -> (u64, u64) {
+    let mut faces = 0;
+    let mut voxels = 0;
+    for z in 0..8 {
+        let slice = mask[z];
+        if slice == 0 {
+            continue;
+        }
+        // Whether the voxel next to each one in each direction is solid
+        let covered = [
+            (slice >> 1) & !SLICE_X7 | (neighbours[0][z] & SLICE_X0) << 7,
+            (slice << 1) & !SLICE_X0 | (neighbours[1][z] & SLICE_X7) >> 7,
+            slice >> 8 | neighbours[2][z] << 56,
+            slice << 8 | neighbours[3][z] >> 56,
+            if z == 7 {
+                neighbours[4][0]
+            } else {
+                mask[z + 1]
+            },
+            if z == 0 {
+                neighbours[5][7]
+            } else {
+                mask[z - 1]
+            },
+        ];
+        faces += covered
+            .iter()
+            .map(|c| (slice & !c).count_ones() as u64)
+            .sum::<u64>();
+        let interior = covered.iter().fold(slice, |acc, c| acc & c);
+        voxels += (slice & !interior).count_ones() as u64;
+    }
+    (faces, voxels)
+}
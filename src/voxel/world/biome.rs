@@ -0,0 +1,83 @@
+use super::{generator::FBM_NORMALIZE, BuiltinMaterial, GenerationSettings};
+
+/// Climate changes over much larger distances than the ground does
+const CLIMATE_FREQUENCY_SCALE: f32 = 0.1;
+/// Colder than this is tundra
+const COLD: f32 = -0.35;
+/// Hotter than this can be desert, if it's dry enough
+const HOT: f32 = 0.1;
+/// Drier than this is desert when hot and rocky otherwise
+const DRY: f32 = -0.1;
+
+/// What a column of the world looks like, picked from its temperature and moisture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Biome {
+    Grassland,
+    Desert,
+    Tundra,
+    Rocky,
+}
+
+impl Biome {
+    /// Picks the biome for a climate, both roughly in `-1.0..=1.0`.
+    pub fn from_climate(climate: glam::Vec2) -> Self {
+        let (temperature, moisture) = (climate.x, climate.y);
+        match temperature {
+            t if t < COLD => Self::Tundra,
+            _ if moisture < DRY && temperature > HOT => Self::Desert,
+            _ if moisture < DRY => Self::Rocky,
+            _ => Self::Grassland,
+        }
+    }
+
+    /// The thin top layer of the ground.
+    pub fn get_surface(&self) -> BuiltinMaterial {
+        match self {
+            Self::Grassland => BuiltinMaterial::Grass,
+            Self::Desert => BuiltinMaterial::Sand,
+            Self::Tundra => BuiltinMaterial::Snow,
+            Self::Rocky => BuiltinMaterial::Stone,
+        }
+    }
+
+    /// The layer between the surface and the stone underneath.
+    pub fn get_soil(&self) -> BuiltinMaterial {
+        match self {
+            Self::Grassland | Self::Tundra => BuiltinMaterial::Dirt,
+            Self::Desert => BuiltinMaterial::Sand,
+            Self::Rocky => BuiltinMaterial::Stone,
+        }
+    }
+}
+
+/// Temperature (x) and moisture (y) at every block corner column in the x/z of
+/// `noise_dims` from `origin` (in blocks), laid out x first then z like the chunk's
+/// noise. Each is its own noise field, seeded apart from the terrain so biomes don't
+/// follow the hills.
+pub fn gen_climate(
+    origin: glam::IVec3,
+    settings: GenerationSettings,
+    noise_dims: glam::UVec3,
+) -> Vec<glam::Vec2> {
+    let field = |seed: i32| {
+        simdnoise::NoiseBuilder::fbm_2d_offset(
+            origin.x as f32,
+            noise_dims.x as usize,
+            origin.z as f32,
+            noise_dims.z as usize,
+        )
+        .with_seed(seed)
+        .with_freq(settings.frequency * CLIMATE_FREQUENCY_SCALE)
+        .with_octaves(2)
+        .generate()
+        .0
+    };
+
+    let temperature = field(settings.seed.wrapping_add(1));
+    let moisture = field(settings.seed.wrapping_add(2));
+    temperature
+        .into_iter()
+        .zip(moisture)
+        .map(|(t, m)| glam::vec2(t, m) * FBM_NORMALIZE)
+        .collect()
+}
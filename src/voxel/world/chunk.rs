@@ -1,81 +1,223 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+
 use crate::math;
 
-use super::Voxel;
+use super::{
+    storage::ChunkBlocks, BlockContext, ChunkGenTiming, GenerationSettings, StageData, Voxel,
+    WorldGenerator,
+};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Chunk {
     pos: glam::IVec3,
-    noise: Vec<f32>,
+    /// Generates blocks as they're first looked at
+    generator: Arc<WorldGenerator>,
+    /// What each of the generator's stages prepared for this chunk, in stage order
+    stage_data: Vec<Option<StageData>>,
     blocks: Vec<Vec<Voxel>>,
+    version: u64,
+    timing: ChunkGenTiming,
 }
 
 impl Chunk {
-    pub fn new(pos: glam::IVec3, noise: Vec<f32>, blocks: Vec<Vec<Voxel>>) -> Self {
-        Self { pos, noise, blocks }
+    pub fn new(
+        pos: glam::IVec3,
+        generator: Arc<WorldGenerator>,
+        stage_data: Vec<Option<StageData>>,
+        blocks: Vec<Vec<Voxel>>,
+        settings: GenerationSettings,
+        noise_time: Duration,
+    ) -> Self {
+        Self {
+            pos,
+            generator,
+            stage_data,
+            blocks,
+            version: 0,
+            timing: ChunkGenTiming::new(pos, settings, noise_time),
+        }
+    }
+
+    pub fn get_pos(&self) -> glam::IVec3 {
+        self.pos
+    }
+
+    pub fn get_timing(&self) -> &ChunkGenTiming {
+        &self.timing
+    }
+
+    /// Version of the chunk's contents, so anything derived from it can tell when it's out
+    /// of date. Edits don't bump it, they're tracked per block by the world instead.
+    pub fn get_version(&self) -> u64 {
+        self.version
+    }
+
+    /// A block's voxels, generating the block if it hasn't been yet.
+    pub fn get_block_voxels(
+        &mut self,
+        block_pos: glam::UVec3,
+        chunk_dims: glam::UVec3,
+    ) -> &[Voxel] {
+        let block_idx = self.ensure_block(block_pos, chunk_dims);
+        &self.blocks[block_idx]
+    }
+
+    /// A block's voxels, or `None` if the block hasn't been generated yet.
+    pub fn get_generated_voxels(
+        &self,
+        block_pos: glam::UVec3,
+        chunk_dims: glam::UVec3,
+    ) -> Option<&[Voxel]> {
+        let block = &self.blocks[math::to_1d_index(block_pos, chunk_dims)];
+        (!block.is_empty()).then_some(block.as_slice())
     }
 
     pub fn get_block(&mut self, block_pos: glam::UVec3, chunk_dims: glam::UVec3) -> Vec<Voxel> {
+        let block_idx = self.ensure_block(block_pos, chunk_dims);
+        self.blocks[block_idx].to_owned()
+    }
+
+    /// Whether every voxel in a block is empty, generating the block if it hasn't been yet.
+    pub fn is_block_empty(&mut self, block_pos: glam::UVec3, chunk_dims: glam::UVec3) -> bool {
+        let block_idx = self.ensure_block(block_pos, chunk_dims);
+        self.blocks[block_idx].iter().all(|v| *v == Voxel::Empty)
+    }
+
+    /// Panics if `voxel_idx` is outside of the block
+    pub fn get_voxel(
+        &mut self,
+        block_pos: glam::UVec3,
+        voxel_idx: usize,
+        chunk_dims: glam::UVec3,
+    ) -> Voxel {
+        let block_idx = self.ensure_block(block_pos, chunk_dims);
+        self.blocks[block_idx][voxel_idx]
+    }
+
+    /// Returns whether the voxel changed.
+    /// Panics if `voxel_idx` is outside of the block
+    pub fn set_voxel(
+        &mut self,
+        block_pos: glam::UVec3,
+        voxel_idx: usize,
+        voxel: Voxel,
+        chunk_dims: glam::UVec3,
+    ) -> bool {
+        let block_idx = self.ensure_block(block_pos, chunk_dims);
+        let changed = self.blocks[block_idx][voxel_idx] != voxel;
+        self.blocks[block_idx][voxel_idx] = voxel;
+        changed
+    }
+
+    /// Regenerates the voxels in `min..max` (chunk local voxel space) with `generator` and
+    /// `settings`, leaving the rest of the chunk alone. Only the blocks overlapping the
+    /// region are generated, and the chunk wide work is only done again if the generator
+    /// or settings aren't the ones the chunk was made with. Returns the blocks that
+    /// changed and how many voxels did.
+    pub fn regenerate_region(
+        &mut self,
+        generator: &Arc<WorldGenerator>,
+        settings: GenerationSettings,
+        min: glam::UVec3,
+        max: glam::UVec3,
+        chunk_dims: glam::UVec3,
+    ) -> (Vec<glam::UVec3>, usize) {
+        let (generator, stage_data) =
+            if Arc::ptr_eq(generator, &self.generator) && settings == self.timing.settings {
+                (self.generator.clone(), self.stage_data.clone())
+            } else {
+                let stage_data = generator.prepare(self.pos, settings, chunk_dims);
+                (generator.clone(), stage_data)
+            };
+
+        let mut changed_blocks = Vec::new();
+        let mut changed = 0;
+        let (min_block, max_block) = (min / 8, (max - 1) / 8);
+        for z in min_block.z..=max_block.z {
+            for y in min_block.y..=max_block.y {
+                for x in min_block.x..=max_block.x {
+                    let block_pos = glam::uvec3(x, y, z);
+                    let mut context = BlockContext::new(block_pos, chunk_dims);
+                    generator.gen_block(&mut context, &stage_data);
+
+                    // The part of the region inside this block, in block local voxels
+                    let block_min = block_pos * 8;
+                    let from = min.max(block_min) - block_min;
+                    let to = max.min(block_min + 8) - block_min;
+                    let block_idx = self.ensure_block(block_pos, chunk_dims);
+                    let block = &mut self.blocks[block_idx];
+                    let mut block_changed = 0;
+                    for vz in from.z..to.z {
+                        for vy in from.y..to.y {
+                            for vx in from.x..to.x {
+                                let idx = math::to_1d_index(
+                                    glam::uvec3(vx, vy, vz),
+                                    glam::uvec3(8, 8, 8),
+                                );
+                                if block[idx] != context.voxels[idx] {
+                                    block[idx] = context.voxels[idx];
+                                    block_changed += 1;
+                                }
+                            }
+                        }
+                    }
+
+                    if block_changed > 0 {
+                        changed_blocks.push(block_pos);
+                        changed += block_changed;
+                    }
+                }
+            }
+        }
+        (changed_blocks, changed)
+    }
+
+    /// Copies out every block that has been generated, for saving.
+    pub fn get_generated_blocks(&self) -> ChunkBlocks {
+        self.blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, block)| !block.is_empty())
+            .map(|(idx, block)| (idx, block.clone()))
+            .collect()
+    }
+
+    /// Replaces blocks with previously saved ones.
+    pub fn restore_blocks(&mut self, blocks: ChunkBlocks) -> Result<()> {
+        for (block_idx, voxels) in blocks {
+            if block_idx >= self.blocks.len() {
+                bail!("Block index {} outside of chunk", block_idx);
+            }
+            self.blocks[block_idx] = voxels;
+        }
+        Ok(())
+    }
+
+    /// Generates a block if it hasn't been already, returning its index.
+    fn ensure_block(&mut self, block_pos: glam::UVec3, chunk_dims: glam::UVec3) -> usize {
         assert_eq!(
             self.blocks.len(),
             (chunk_dims.x * chunk_dims.y * chunk_dims.z) as usize
         );
 
         let block_idx = math::to_1d_index(block_pos, chunk_dims);
-        let mut block = &self.blocks[block_idx];
-        if block.is_empty() {
+        if self.blocks[block_idx].is_empty() {
+            let start = Instant::now();
             self.gen_block(block_pos, block_idx, chunk_dims);
-            block = &self.blocks[block_idx]
+            self.timing.record_block(start.elapsed());
         }
 
-        block.to_owned()
+        block_idx
     }
 
-    pub fn gen_block(&mut self, block_pos: glam::UVec3, block_idx: usize, chunk_dims: glam::UVec3) {
-        let block = &mut self.blocks[block_idx];
-        let noise_dims = chunk_dims + glam::uvec3(1, 1, 1);
-
-        // Extract relevant noise values from the chunk
-        let mut noise_vals = Vec::new();
-        let mut block_sign = 0.0;
-        for z in 0..2 {
-            for y in 0..2 {
-                for x in 0..2 {
-                    let noise_pos = glam::uvec3(x, y, z) + block_pos;
-                    let noise_idx = math::to_1d_index(noise_pos, noise_dims);
-                    let val = self.noise[noise_idx];
-                    noise_vals.push(val);
-                    block_sign += val.signum();
-                }
-            }
-        }
-
-        // If all the corners are negative, then all the interpolated values
-        // will be negative too. In that case we can just fill with empty.
-        if block_sign == -8.0 {
-            block.resize(512, Voxel::Empty);
-        } else {
-            let mut vals = [0.0f32; 512];
-            math::tri_lerp_block(&noise_vals, &[8, 8, 8], &mut vals);
-
-            // TODO: Better voxel colours
-            let mut idx = 0;
-            for z in 0..8 {
-                for y in 0..8 {
-                    for x in 0..8 {
-                        let val = vals[idx];
-                        idx += 1;
-
-                        if val > 0.0 {
-                            let r = ((x + 1) * 32 - 1) as u8;
-                            let g = ((y + 1) * 32 - 1) as u8;
-                            let b = ((z + 1) * 32 - 1) as u8;
-                            block.push(Voxel::Color(r, g, b));
-                        } else {
-                            block.push(Voxel::Empty);
-                        }
-                    }
-                }
-            }
-        }
+    fn gen_block(&mut self, block_pos: glam::UVec3, block_idx: usize, chunk_dims: glam::UVec3) {
+        let mut context = BlockContext::new(block_pos, chunk_dims);
+        self.generator.gen_block(&mut context, &self.stage_data);
+        self.blocks[block_idx] = context.voxels.to_vec();
     }
 }
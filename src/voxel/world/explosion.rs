@@ -0,0 +1,130 @@
+use crate::math;
+
+use super::{Voxel, WorldManager};
+
+/// A voxel thrown out by an explosion, in world voxel space.
+#[derive(Debug, Clone, Copy)]
+pub struct Debris {
+    pub position: glam::Vec3,
+    pub velocity: glam::Vec3,
+    pub voxel: Voxel,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ExplosionResult {
+    /// How many voxels were removed
+    pub removed: usize,
+    pub debris: Vec<Debris>,
+}
+
+/// Blows a roughly spherical hole in the world. The edge of the hole is pushed in and out
+/// by noise so craters don't look like perfect spheres.
+#[derive(Debug, Clone, Copy)]
+pub struct Explosion {
+    /// Position in world voxel space
+    pub center: glam::IVec3,
+    pub radius: f32,
+    /// How far the edge can stray from the sphere, as a fraction of the radius
+    pub roughness: f32,
+    pub seed: i32,
+    /// Fraction of removed voxels thrown out as debris, 0 for none
+    pub debris_fraction: f32,
+    pub max_debris: usize,
+    /// Speed debris leaves the center at, in voxels per second
+    pub debris_speed: f32,
+}
+
+impl Explosion {
+    pub fn new(center: glam::IVec3, radius: f32) -> Self {
+        Self {
+            center,
+            radius,
+            roughness: 0.3,
+            seed: 0,
+            debris_fraction: 0.1,
+            max_debris: 256,
+            debris_speed: 40.0,
+        }
+    }
+
+    /// Removes every voxel inside the explosion in a single batched edit, returning what
+    /// was removed along with any debris to simulate.
+    pub fn detonate(&self, world: &mut WorldManager) -> ExplosionResult {
+        // The edge can only reach as far as the radius plus its roughness
+        let extent = (self.radius * (1.0 + self.roughness)).ceil() as i32;
+        let size = (extent * 2 + 1) as usize;
+        let min = self.center - extent;
+        let noise = simdnoise::NoiseBuilder::fbm_3d_offset(
+            min.x as f32,
+            size,
+            min.y as f32,
+            size,
+            min.z as f32,
+            size,
+        )
+        .with_seed(self.seed)
+        .with_freq(0.15)
+        .with_octaves(3)
+        .generate_scaled(-1.0, 1.0);
+
+        let dims = glam::UVec3::splat(size as u32);
+        let mut edits = vec![];
+        let mut debris = vec![];
+        for z in 0..size as u32 {
+            for y in 0..size as u32 {
+                for x in 0..size as u32 {
+                    let local = glam::uvec3(x, y, z);
+                    let pos = min + local.as_ivec3();
+                    let offset = (pos - self.center).as_vec3();
+                    let edge = self.radius
+                        * (1.0 + self.roughness * noise[math::to_1d_index(local, dims)]);
+                    if offset.length() > edge {
+                        continue;
+                    }
+
+                    let voxel = world.get_voxel(pos);
+                    if voxel == Voxel::Empty {
+                        continue;
+                    }
+                    edits.push((pos, Voxel::Empty));
+
+                    if debris.len() < self.max_debris
+                        && hash_unit(pos, self.seed) < self.debris_fraction
+                    {
+                        // Thrown away from the center, with a bit of extra lift so it
+                        // arcs rather than shooting straight into the ground
+                        let direction = (offset.normalize_or_zero() + glam::Vec3::Y).normalize();
+                        let speed = self.debris_speed * (0.5 + hash_unit(pos, self.seed + 1));
+                        debris.push(Debris {
+                            position: pos.as_vec3() + 0.5,
+                            velocity: direction * speed,
+                            voxel,
+                        });
+                    }
+                }
+            }
+        }
+
+        let removed = world.set_voxels(&edits);
+        log::info!(
+            "Explosion at {} (radius {}) removed {} voxels, {} debris",
+            self.center,
+            self.radius,
+            removed,
+            debris.len()
+        );
+        ExplosionResult { removed, debris }
+    }
+}
+
+/// Hashes a position into 0..1, so the same explosion always throws the same debris.
+fn hash_unit(pos: glam::IVec3, seed: i32) -> f32 {
+    let mut h = (pos.x as u32).wrapping_mul(0x8DA6B343)
+        ^ (pos.y as u32).wrapping_mul(0xD8163841)
+        ^ (pos.z as u32).wrapping_mul(0xCB1AB31F)
+        ^ (seed as u32).wrapping_mul(0x165667B1);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x2C1B3C6D);
+    h ^= h >> 12;
+    h as f32 / u32::MAX as f32
+}
@@ -0,0 +1,326 @@
+use std::{
+    collections::HashSet,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Instant,
+};
+
+use super::{
+    storage::ChunkBlocks, Chunk, GenerationSettings, Terrain, WorldGenerator, WorldStorage,
+};
+
+/// simdnoise's fbm isn't normalised. One octave of its simplex noise peaks at about ±0.022
+/// and every further octave adds `gain` times the previous one's range, so the default 3
+/// octaves at a gain of 0.5 peak at about ±0.04. This brings that to roughly ±1. Other
+/// octave and gain settings shift the range a little, which only scales the heights.
+pub(super) const FBM_NORMALIZE: f32 = 25.0;
+/// How fast heightmap density grows per block below the ground. Density doubles as depth
+/// for layering, so this sets how thick the grass and dirt are
+const HEIGHTMAP_DENSITY_SCALE: f32 = 0.1;
+
+/// Everything a worker needs to build a chunk without touching the world.
+struct ChunkJob {
+    pos: glam::IVec3,
+    generator: Arc<WorldGenerator>,
+    settings: GenerationSettings,
+    chunk_dims: glam::UVec3,
+    /// Blocks already read from storage by the IO queue
+    saved: Option<ChunkBlocks>,
+}
+
+/// Generates chunks on a pool of worker threads. Finished chunks wait in a completion queue
+/// until the world drains it, so generation never blocks the frame it was requested in.
+#[derive(Debug)]
+pub struct ChunkGenerator {
+    job_sender: Option<mpsc::Sender<ChunkJob>>,
+    result_receiver: mpsc::Receiver<Chunk>,
+    workers: Vec<thread::JoinHandle<()>>,
+    in_flight: HashSet<glam::IVec3>,
+}
+
+impl ChunkGenerator {
+    pub fn new(worker_count: usize) -> Self {
+        let (job_sender, job_receiver) = mpsc::channel::<ChunkJob>();
+        let (result_sender, result_receiver) = mpsc::channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+
+        let mut workers = Vec::with_capacity(worker_count);
+        for i in 0..worker_count {
+            let job_receiver = job_receiver.clone();
+            let result_sender = result_sender.clone();
+            let worker = thread::Builder::new()
+                .name(format!("chunk generator {}", i))
+                .spawn(move || loop {
+                    // Only hold the lock while waiting for a job so the others can take
+                    // the next one while we're generating
+                    let job = match job_receiver.lock() {
+                        Ok(receiver) => receiver.recv(),
+                        Err(_) => return,
+                    };
+                    let Ok(job) = job else {
+                        return;
+                    };
+                    let chunk = build_chunk(
+                        job.pos,
+                        &job.generator,
+                        job.settings,
+                        job.chunk_dims,
+                        job.saved,
+                    );
+                    if result_sender.send(chunk).is_err() {
+                        return;
+                    }
+                });
+
+            match worker {
+                Ok(worker) => workers.push(worker),
+                Err(e) => log::error!("Failed to spawn chunk generator thread: {}", e),
+            }
+        }
+
+        Self {
+            job_sender: Some(job_sender),
+            result_receiver,
+            workers,
+            in_flight: HashSet::new(),
+        }
+    }
+
+    /// One worker per spare core, leaving the render thread its own.
+    pub fn with_default_workers() -> Self {
+        let cores = thread::available_parallelism().map_or(1, |n| n.get());
+        Self::new(cores.saturating_sub(1).clamp(1, 8))
+    }
+
+    pub fn get_worker_count(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// How many chunks have been queued but not collected yet.
+    pub fn get_pending_count(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    pub fn is_pending(&self, pos: glam::IVec3) -> bool {
+        self.in_flight.contains(&pos)
+    }
+
+    /// Queues a chunk to be generated, with any blocks that were saved for it. Returns
+    /// false if there are no workers to do it, in which case the caller has to generate
+    /// it itself.
+    pub fn queue(
+        &mut self,
+        pos: glam::IVec3,
+        generator: &Arc<WorldGenerator>,
+        settings: GenerationSettings,
+        chunk_dims: glam::UVec3,
+        saved: Option<ChunkBlocks>,
+    ) -> bool {
+        if self.in_flight.contains(&pos) {
+            return true;
+        }
+        let Some(sender) = &self.job_sender else {
+            return false;
+        };
+        if self.workers.is_empty() {
+            return false;
+        }
+
+        let job = ChunkJob {
+            pos,
+            generator: generator.clone(),
+            settings,
+            chunk_dims,
+            saved,
+        };
+        if sender.send(job).is_err() {
+            return false;
+        }
+        self.in_flight.insert(pos);
+        true
+    }
+
+    /// Takes every chunk that has finished generating since the last call.
+    pub fn take_completed(&mut self) -> Vec<Chunk> {
+        let chunks: Vec<Chunk> = self.result_receiver.try_iter().collect();
+        for chunk in &chunks {
+            self.in_flight.remove(&chunk.get_pos());
+        }
+        chunks
+    }
+}
+
+impl Drop for ChunkGenerator {
+    fn drop(&mut self) {
+        // Closing the job queue makes the workers exit once they finish their current job
+        self.job_sender = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Reads a chunk's saved blocks then builds it, right here on the calling thread.
+pub fn load_chunk(
+    pos: glam::IVec3,
+    generator: &Arc<WorldGenerator>,
+    settings: GenerationSettings,
+    chunk_dims: glam::UVec3,
+    storage: &Option<WorldStorage>,
+) -> Chunk {
+    let saved = match storage.as_ref().map(|s| s.load_chunk(pos)) {
+        Some(Ok(blocks)) => blocks,
+        Some(Err(e)) => {
+            log::error!("Failed to load chunk {}, regenerating it: {:#}", pos, e);
+            None
+        }
+        None => None,
+    };
+    build_chunk(pos, generator, settings, chunk_dims, saved)
+}
+
+/// Generates a chunk's base noise, then fills in any blocks that were saved.
+pub fn build_chunk(
+    pos: glam::IVec3,
+    generator: &Arc<WorldGenerator>,
+    settings: GenerationSettings,
+    chunk_dims: glam::UVec3,
+    saved: Option<ChunkBlocks>,
+) -> Chunk {
+    let mut chunk = gen_chunk(pos, generator, settings, chunk_dims);
+    if let Some(blocks) = saved {
+        if let Err(e) = chunk.restore_blocks(blocks) {
+            log::error!("Failed to load chunk {}, regenerating it: {:#}", pos, e);
+            chunk = gen_chunk(pos, generator, settings, chunk_dims);
+        }
+    }
+    chunk
+}
+
+/// Runs every stage's chunk wide work, leaving the blocks to be generated as they're
+/// looked at.
+pub fn gen_chunk(
+    pos: glam::IVec3,
+    generator: &Arc<WorldGenerator>,
+    settings: GenerationSettings,
+    chunk_dims: glam::UVec3,
+) -> Chunk {
+    let start = Instant::now();
+    let stage_data = generator.prepare(pos, settings, chunk_dims);
+
+    let num_blocks = chunk_dims.x * chunk_dims.y * chunk_dims.z;
+    let blocks = vec![vec![]; num_blocks as usize];
+    Chunk::new(
+        pos,
+        generator.clone(),
+        stage_data,
+        blocks,
+        settings,
+        start.elapsed(),
+    )
+}
+
+/// Density at every block corner in `noise_dims` from `origin` (in blocks), positive
+/// inside the ground. Any box of corners gets the same values a chunk would, so single
+/// columns can be looked at without generating the chunk around them.
+pub(super) fn gen_terrain(
+    origin: glam::IVec3,
+    settings: GenerationSettings,
+    noise_dims: glam::UVec3,
+) -> Vec<f32> {
+    match settings.terrain {
+        Terrain::Density => gen_density(origin, settings, noise_dims),
+        Terrain::Heightmap => gen_heightmap_density(origin, settings, noise_dims),
+    }
+}
+
+/// Density straight from 3D noise.
+fn gen_density(
+    origin: glam::IVec3,
+    settings: GenerationSettings,
+    noise_dims: glam::UVec3,
+) -> Vec<f32> {
+    simdnoise::NoiseBuilder::fbm_3d_offset(
+        origin.x as f32,
+        noise_dims.x as usize,
+        origin.y as f32,
+        noise_dims.y as usize,
+        origin.z as f32,
+        noise_dims.z as usize,
+    )
+    .with_seed(settings.seed)
+    .with_freq(settings.frequency)
+    .with_octaves(settings.octaves)
+    .with_gain(settings.gain)
+    .with_lacunarity(settings.lacunarity)
+    .generate()
+    .0
+}
+
+/// Where tunnels run at every block corner, negative inside them, laid out like
+/// `gen_terrain`. The zero crossings of a 3D noise field are sheets, so a tunnel is
+/// wherever two different fields' sheets meet, which gives long winding tubes rather
+/// than the blobs one field would carve.
+pub(super) fn gen_caves(
+    origin: glam::IVec3,
+    settings: GenerationSettings,
+    noise_dims: glam::UVec3,
+) -> Vec<f32> {
+    let field = |seed: i32| {
+        simdnoise::NoiseBuilder::fbm_3d_offset(
+            origin.x as f32,
+            noise_dims.x as usize,
+            origin.y as f32,
+            noise_dims.y as usize,
+            origin.z as f32,
+            noise_dims.z as usize,
+        )
+        .with_seed(seed)
+        .with_freq(settings.cave_frequency)
+        .with_octaves(2)
+        .generate()
+        .0
+    };
+
+    let a = field(settings.seed.wrapping_add(3));
+    let b = field(settings.seed.wrapping_add(4));
+    a.into_iter()
+        .zip(b)
+        .map(|(a, b)| a.abs().max(b.abs()) * FBM_NORMALIZE - settings.cave_width)
+        .collect()
+}
+
+/// Density from how far below a 2D noise heightmap each corner is. Solid everywhere
+/// under the ground and empty everywhere above it.
+fn gen_heightmap_density(
+    origin: glam::IVec3,
+    settings: GenerationSettings,
+    noise_dims: glam::UVec3,
+) -> Vec<f32> {
+    let heights = simdnoise::NoiseBuilder::fbm_2d_offset(
+        origin.x as f32,
+        noise_dims.x as usize,
+        origin.z as f32,
+        noise_dims.z as usize,
+    )
+    .with_seed(settings.seed)
+    .with_freq(settings.frequency)
+    .with_octaves(settings.octaves)
+    .with_gain(settings.gain)
+    .with_lacunarity(settings.lacunarity)
+    .generate()
+    .0;
+
+    let mut density = Vec::with_capacity((noise_dims.x * noise_dims.y * noise_dims.z) as usize);
+    for z in 0..noise_dims.z {
+        for y in 0..noise_dims.y {
+            for x in 0..noise_dims.x {
+                let height = heights[(x + z * noise_dims.x) as usize] * FBM_NORMALIZE;
+                let ground = settings.ground_height + height * settings.height_amplitude;
+                let depth = ground - (origin.y + y as i32) as f32;
+                density.push(depth * HEIGHTMAP_DENSITY_SCALE);
+            }
+        }
+    }
+    density
+}
@@ -0,0 +1,400 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{mpsc, Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+use super::{storage::ChunkBlocks, Chunk, MaterialTable, WorldStorage, MATERIALS_FILE};
+
+/// Loads waiting beyond this are turned away, to be asked for again once the queue drains.
+/// Keeps a camera flying over the world from queueing up loads for places it's long gone
+const MAX_QUEUED_LOADS: usize = 256;
+
+/// What a job is holding up, highest priority first. Workers always take the most
+/// important job waiting, oldest first within a priority.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IoPriority {
+    /// Chunks around the camera, which whatever is on screen is waiting on
+    Nearby,
+    /// Chunks further out, loaded ahead of when they're needed
+    Distant,
+    /// Saves, which nothing waits on
+    Save,
+}
+
+impl IoPriority {
+    pub const COUNT: usize = 3;
+}
+
+enum IoJob {
+    Load(glam::IVec3),
+    Save {
+        chunks: Vec<Arc<Chunk>>,
+        materials: MaterialTable,
+    },
+}
+
+struct QueuedJob {
+    job: IoJob,
+    storage: WorldStorage,
+    queued_at: Instant,
+}
+
+/// What a finished job produced.
+pub enum IoResult {
+    /// A chunk's saved blocks, `None` if it was never saved
+    Loaded {
+        pos: glam::IVec3,
+        blocks: Result<Option<ChunkBlocks>>,
+    },
+    /// Every chunk in a save, along with how many regions were written
+    Saved {
+        chunks: Vec<glam::IVec3>,
+        regions: Result<usize>,
+    },
+}
+
+struct IoCompletion {
+    result: IoResult,
+    waited: Duration,
+    took: Duration,
+}
+
+#[derive(Default)]
+struct IoQueue {
+    jobs: [VecDeque<QueuedJob>; IoPriority::COUNT],
+    /// Saves rewrite whole regions, so only one runs at a time
+    saving: bool,
+    closed: bool,
+}
+
+impl IoQueue {
+    fn take_next(&mut self) -> Option<QueuedJob> {
+        // Once closed the loads aren't wanted, but saves still get finished
+        if self.closed {
+            self.jobs[IoPriority::Nearby as usize].clear();
+            self.jobs[IoPriority::Distant as usize].clear();
+        }
+
+        for jobs in &mut self.jobs {
+            let saving = self.saving;
+            let Some(idx) = jobs
+                .iter()
+                .position(|j| !(saving && matches!(j.job, IoJob::Save { .. })))
+            else {
+                continue;
+            };
+            let job = jobs.remove(idx)?;
+            if matches!(job.job, IoJob::Save { .. }) {
+                self.saving = true;
+            }
+            return Some(job);
+        }
+        None
+    }
+}
+
+/// How the IO queue has been doing since the world was created.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IoStats {
+    /// Jobs waiting for a worker, by `IoPriority`
+    pub queued: [usize; IoPriority::COUNT],
+    /// Jobs a worker is busy with
+    pub running: usize,
+    pub loads: u64,
+    pub saves: u64,
+    pub failures: u64,
+    /// Loads turned away because the queue was full
+    pub rejected: u64,
+    /// Time jobs spent waiting for a worker
+    pub total_wait: Duration,
+    pub max_wait: Duration,
+    /// Time workers spent on jobs
+    pub total_busy: Duration,
+}
+
+impl IoStats {
+    pub fn get_average_wait(&self) -> Duration {
+        let jobs = self.loads + self.saves;
+        match jobs {
+            0 => Duration::ZERO,
+            _ => self.total_wait / jobs as u32,
+        }
+    }
+}
+
+/// Reads and writes chunks on a few IO threads of its own, so disk access never holds
+/// up the frame or the chunk generators. Jobs are run in `IoPriority` order, with no
+/// more running at once than there are workers.
+pub struct IoScheduler {
+    queue: Arc<(Mutex<IoQueue>, Condvar)>,
+    result_receiver: mpsc::Receiver<IoCompletion>,
+    workers: Vec<thread::JoinHandle<()>>,
+    /// Collected while waiting for a save, handed out with the next `take_completed`
+    completed: Vec<IoCompletion>,
+    loading: HashSet<glam::IVec3>,
+    outstanding: usize,
+    outstanding_saves: usize,
+    stats: IoStats,
+}
+
+impl IoScheduler {
+    /// Two workers are enough to keep a disk busy without starving the generators
+    pub const DEFAULT_WORKERS: usize = 2;
+
+    pub fn new(worker_count: usize) -> Self {
+        let queue = Arc::new((Mutex::new(IoQueue::default()), Condvar::new()));
+        let (result_sender, result_receiver) = mpsc::channel();
+
+        let mut workers = Vec::with_capacity(worker_count);
+        for i in 0..worker_count {
+            let queue = queue.clone();
+            let result_sender = result_sender.clone();
+            let worker = thread::Builder::new()
+                .name(format!("world io {}", i))
+                .spawn(move || {
+                    let (lock, ready) = &*queue;
+                    loop {
+                        let job = {
+                            let Ok(mut jobs) = lock.lock() else {
+                                return;
+                            };
+                            loop {
+                                if let Some(job) = jobs.take_next() {
+                                    break job;
+                                }
+                                if jobs.closed {
+                                    return;
+                                }
+                                jobs = match ready.wait(jobs) {
+                                    Ok(jobs) => jobs,
+                                    Err(_) => return,
+                                };
+                            }
+                        };
+
+                        let waited = job.queued_at.elapsed();
+                        let start = Instant::now();
+                        let is_save = matches!(job.job, IoJob::Save { .. });
+                        let result = run_job(job);
+                        if is_save {
+                            if let Ok(mut jobs) = lock.lock() {
+                                jobs.saving = false;
+                            }
+                            ready.notify_all();
+                        }
+
+                        let completion = IoCompletion {
+                            result,
+                            waited,
+                            took: start.elapsed(),
+                        };
+                        if result_sender.send(completion).is_err() {
+                            return;
+                        }
+                    }
+                });
+
+            match worker {
+                Ok(worker) => workers.push(worker),
+                Err(e) => log::error!("Failed to spawn world IO thread: {}", e),
+            }
+        }
+
+        Self {
+            queue,
+            result_receiver,
+            workers,
+            completed: Vec::new(),
+            loading: HashSet::new(),
+            outstanding: 0,
+            outstanding_saves: 0,
+            stats: IoStats::default(),
+        }
+    }
+
+    pub fn get_worker_count(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn is_loading(&self, pos: glam::IVec3) -> bool {
+        self.loading.contains(&pos)
+    }
+
+    /// Whether a save is queued or being written.
+    pub fn is_saving(&self) -> bool {
+        self.outstanding_saves > 0
+    }
+
+    /// Whether any more loads would be turned away.
+    pub fn is_full(&self) -> bool {
+        self.loading.len() >= MAX_QUEUED_LOADS
+    }
+
+    /// Queues a chunk to be read from storage. A chunk that's already queued moves up to
+    /// `priority` if that's more important than it was. Returns false if it couldn't be
+    /// queued, because there are no workers or the queue is full.
+    pub fn queue_load(
+        &mut self,
+        pos: glam::IVec3,
+        priority: IoPriority,
+        storage: &WorldStorage,
+    ) -> bool {
+        let Ok(mut queue) = self.queue.0.lock() else {
+            return false;
+        };
+
+        if self.loading.contains(&pos) {
+            // Only loads still waiting can move, ones already running are nearly done
+            let is_job = |j: &QueuedJob| matches!(j.job, IoJob::Load(p) if p == pos);
+            let less_important = queue.jobs[priority as usize + 1..]
+                .iter_mut()
+                .find_map(|jobs| jobs.iter().position(is_job).and_then(|i| jobs.remove(i)));
+            if let Some(job) = less_important {
+                queue.jobs[priority as usize].push_back(job);
+            }
+            return true;
+        }
+
+        if self.workers.is_empty() {
+            return false;
+        }
+        if self.is_full() {
+            self.stats.rejected += 1;
+            return false;
+        }
+
+        queue.jobs[priority as usize].push_back(QueuedJob {
+            job: IoJob::Load(pos),
+            storage: storage.clone(),
+            queued_at: Instant::now(),
+        });
+        self.queue.1.notify_one();
+        self.loading.insert(pos);
+        self.outstanding += 1;
+        true
+    }
+
+    /// Queues chunks to be written to storage along with the materials they use. The
+    /// chunks are shared rather than copied, so later edits don't end up in the save.
+    /// Returns false if there are no workers to write them.
+    pub fn queue_save(
+        &mut self,
+        chunks: Vec<Arc<Chunk>>,
+        materials: MaterialTable,
+        storage: &WorldStorage,
+    ) -> bool {
+        if self.workers.is_empty() {
+            return false;
+        }
+        let Ok(mut queue) = self.queue.0.lock() else {
+            return false;
+        };
+
+        queue.jobs[IoPriority::Save as usize].push_back(QueuedJob {
+            job: IoJob::Save { chunks, materials },
+            storage: storage.clone(),
+            queued_at: Instant::now(),
+        });
+        self.queue.1.notify_one();
+        self.outstanding += 1;
+        self.outstanding_saves += 1;
+        true
+    }
+
+    /// Blocks until every queued save has been written. Anything else that finishes in
+    /// the meantime is kept for `take_completed`.
+    pub fn wait_for_saves(&mut self) {
+        while self.outstanding_saves > 0 {
+            let Ok(completion) = self.result_receiver.recv() else {
+                return;
+            };
+            self.record(&completion);
+            self.completed.push(completion);
+        }
+    }
+
+    /// Takes the result of every job that has finished since the last call.
+    pub fn take_completed(&mut self) -> Vec<IoResult> {
+        let finished: Vec<IoCompletion> = self.result_receiver.try_iter().collect();
+        for completion in &finished {
+            self.record(completion);
+        }
+        self.completed
+            .drain(..)
+            .chain(finished)
+            .map(|c| c.result)
+            .collect()
+    }
+
+    pub fn get_stats(&self) -> IoStats {
+        let mut stats = self.stats;
+        if let Ok(queue) = self.queue.0.lock() {
+            stats.queued = queue.jobs.each_ref().map(|jobs| jobs.len());
+        }
+        stats.running = self.outstanding - stats.queued.iter().sum::<usize>();
+        stats
+    }
+
+    fn record(&mut self, completion: &IoCompletion) {
+        self.outstanding -= 1;
+        match &completion.result {
+            IoResult::Loaded { pos, blocks } => {
+                self.loading.remove(pos);
+                self.stats.loads += 1;
+                if blocks.is_err() {
+                    self.stats.failures += 1;
+                }
+            }
+            IoResult::Saved { regions, .. } => {
+                self.outstanding_saves -= 1;
+                self.stats.saves += 1;
+                if regions.is_err() {
+                    self.stats.failures += 1;
+                }
+            }
+        }
+        self.stats.total_wait += completion.waited;
+        self.stats.max_wait = self.stats.max_wait.max(completion.waited);
+        self.stats.total_busy += completion.took;
+    }
+}
+
+impl Drop for IoScheduler {
+    fn drop(&mut self) {
+        // Workers finish any saves still queued, then exit
+        if let Ok(mut queue) = self.queue.0.lock() {
+            queue.closed = true;
+        }
+        self.queue.1.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn run_job(job: QueuedJob) -> IoResult {
+    match job.job {
+        IoJob::Load(pos) => IoResult::Loaded {
+            pos,
+            blocks: job.storage.load_chunk(pos),
+        },
+        IoJob::Save { chunks, materials } => {
+            let blocks: Vec<_> = chunks
+                .iter()
+                .map(|chunk| (chunk.get_pos(), chunk.get_generated_blocks()))
+                .collect();
+            let regions = job.storage.save_chunks(&blocks).and_then(|regions| {
+                materials.save(&job.storage.get_directory().join(MATERIALS_FILE))?;
+                Ok(regions)
+            });
+            IoResult::Saved {
+                chunks: chunks.iter().map(|chunk| chunk.get_pos()).collect(),
+                regions,
+            }
+        }
+    }
+}
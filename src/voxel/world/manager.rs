@@ -1,61 +1,799 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
-use super::{Chunk, GenerationSettings, Voxel};
+use anyhow::{bail, Result};
+
+use crate::math;
+
+use super::{
+    generator, profile, Chunk, ChunkGenTiming, ChunkGenerator, GenerationSettings, IoPriority,
+    IoResult, IoScheduler, IoStats, MaterialTable, Voxel, WorldGenerator, WorldStorage,
+};
+
+/// Where a world's material table is saved, next to its regions
+pub(super) const MATERIALS_FILE: &str = "materials.txt";
+/// Chunks this many chunks or fewer from the focus get loaded ahead of the rest
+const NEARBY_CHUNK_RADIUS: i32 = 1;
+
+/// Identifies a world, so anything streaming from one can tell when it's been given
+/// a different world.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WorldId(u64);
+
+static NEXT_WORLD_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A world's voxels as they were at one point in time. Chunks are shared with the world
+/// rather than copied, and only get copied once the world changes them, so taking a
+/// snapshot is cheap and an untouched world costs nothing extra to hold one.
+///
+/// Materials aren't part of a snapshot. They're never removed, so every material the
+/// snapshot's voxels use is still there when it's restored.
+#[derive(Clone)]
+pub struct WorldSnapshot {
+    world: WorldId,
+    chunks: HashMap<glam::IVec3, Arc<Chunk>>,
+    unsaved_chunks: HashSet<glam::IVec3>,
+}
+
+impl WorldSnapshot {
+    pub fn get_world_id(&self) -> WorldId {
+        self.world
+    }
+
+    pub fn get_chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+}
 
 pub struct WorldManager {
+    id: WorldId,
     settings: GenerationSettings,
     chunk_dims: glam::UVec3,
-    chunks: HashMap<glam::IVec3, Chunk>,
+    voxel_size: f32,
+    materials: MaterialTable,
+    chunks: HashMap<glam::IVec3, Arc<Chunk>>,
+    /// Added to the versions of chunks replaced by a restore, so they still look newer
+    /// than whatever was loaded from the copy they replaced
+    version_offsets: HashMap<glam::IVec3, u64>,
+    dirty_blocks: HashSet<glam::IVec3>,
+    storage: Option<WorldStorage>,
+    unsaved_chunks: HashSet<glam::IVec3>,
+    /// Chunks handed to the IO queue to save that it hasn't finished writing
+    saving_chunks: HashMap<glam::IVec3, Arc<Chunk>>,
+    generator: ChunkGenerator,
+    /// What the generator threads run to make new chunks
+    world_generator: Arc<WorldGenerator>,
+    io: IoScheduler,
+    /// Chunk the camera is in, whose neighbourhood gets loaded first
+    focus: glam::IVec3,
 }
 
 impl WorldManager {
+    /// Eight voxels to the metre, so a brick is a metre across
+    pub const DEFAULT_VOXEL_SIZE: f32 = 0.125;
+    /// Chunk size in blocks
+    pub const DEFAULT_CHUNK_DIMS: glam::UVec3 = glam::UVec3::splat(32);
+
     pub fn new(settings: GenerationSettings, chunk_dims: glam::UVec3) -> Self {
         let chunks = HashMap::new();
         Self {
+            id: WorldId(NEXT_WORLD_ID.fetch_add(1, Ordering::Relaxed)),
             settings,
             chunk_dims,
+            voxel_size: Self::DEFAULT_VOXEL_SIZE,
+            materials: MaterialTable::new(),
             chunks,
+            version_offsets: HashMap::new(),
+            dirty_blocks: HashSet::new(),
+            storage: None,
+            unsaved_chunks: HashSet::new(),
+            saving_chunks: HashMap::new(),
+            generator: ChunkGenerator::with_default_workers(),
+            world_generator: Arc::new(WorldGenerator::standard()),
+            io: IoScheduler::new(IoScheduler::DEFAULT_WORKERS),
+            focus: glam::IVec3::ZERO,
         }
     }
 
+    pub fn get_id(&self) -> WorldId {
+        self.id
+    }
+
+    /// Chunks held in memory, whether generated or loaded from storage.
+    pub fn get_chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
     pub fn get_chunk_dims(&self) -> glam::UVec3 {
         self.chunk_dims
     }
 
+    pub fn get_settings(&self) -> GenerationSettings {
+        self.settings
+    }
+
+    /// Width of a voxel in metres.
+    pub fn get_voxel_size(&self) -> f32 {
+        self.voxel_size
+    }
+
+    /// Sets the physical size of the world's voxels. Everything in the world is stored
+    /// and traversed in voxels, this only changes how anything measured in metres (camera
+    /// speed, imported models) maps onto them.
+    pub fn set_voxel_size(&mut self, voxel_size: f32) {
+        assert!(voxel_size > 0.0, "voxel size must be positive");
+        self.voxel_size = voxel_size;
+    }
+
+    /// How many bricks fit in a metre, for converting to the renderer's units.
+    pub fn get_bricks_per_metre(&self) -> f32 {
+        1.0 / (self.voxel_size * 8.0)
+    }
+
+    /// Every material the world's voxels refer to.
+    pub fn get_materials(&self) -> &MaterialTable {
+        &self.materials
+    }
+
+    /// Materials can be added or changed at any time, the renderer picks up changes
+    /// through the table's version.
+    pub fn get_materials_mut(&mut self) -> &mut MaterialTable {
+        &mut self.materials
+    }
+
+    /// Changes the generator settings. Only chunks generated from now on will use them,
+    /// existing terrain can be updated with `regenerate_region`.
+    pub fn set_settings(&mut self, settings: GenerationSettings) {
+        self.settings = settings;
+    }
+
+    /// Changes how chunks are generated. Like `set_settings`, only chunks generated from
+    /// now on will use it.
+    pub fn set_generator(&mut self, generator: WorldGenerator) {
+        self.world_generator = Arc::new(generator);
+    }
+
+    pub fn get_generator(&self) -> &WorldGenerator {
+        &self.world_generator
+    }
+
+    /// Sets where chunks are saved to and loaded from. Chunks already in memory are kept,
+    /// even if there's a saved version of them. The storage's material table replaces
+    /// the world's, and regions saved before materials existed get upgraded to use it.
+    pub fn set_storage(&mut self, storage: Option<WorldStorage>) {
+        if let Some(storage) = &storage {
+            let path = storage.get_directory().join(MATERIALS_FILE);
+            if path.exists() {
+                match MaterialTable::load(&path) {
+                    Ok(materials) => self.materials = materials,
+                    Err(e) => log::warn!("Keeping the current materials: {:#}", e),
+                }
+            }
+
+            match storage.upgrade_regions(&mut self.materials) {
+                Ok(0) => {}
+                Ok(count) => {
+                    log::info!("Upgraded {} regions to use materials", count);
+                    if let Err(e) = self.materials.save(&path) {
+                        log::warn!("Failed to save materials: {:#}", e);
+                    }
+                }
+                Err(e) => log::warn!("Failed to upgrade regions: {:#}", e),
+            }
+        }
+        self.storage = storage;
+    }
+
+    /// Writes every chunk modified since the last save to storage, waiting for it to be
+    /// written. Returns how many chunks were saved, which is 0 if there's no storage.
+    pub fn save(&mut self) -> Result<usize> {
+        // A background save finishing after this one would write older voxels over it
+        self.io.wait_for_saves();
+        self.process_io_results();
+        self.write_unsaved()
+    }
+
+    fn write_unsaved(&mut self) -> Result<usize> {
+        let Some(storage) = &self.storage else {
+            return Ok(0);
+        };
+        if self.unsaved_chunks.is_empty() {
+            return Ok(0);
+        }
+
+        let chunks: Vec<_> = self
+            .unsaved_chunks
+            .iter()
+            .filter_map(|pos| Some((*pos, self.chunks.get(pos)?.get_generated_blocks())))
+            .collect();
+        let regions = storage.save_chunks(&chunks)?;
+        self.materials
+            .save(&storage.get_directory().join(MATERIALS_FILE))?;
+        self.unsaved_chunks.clear();
+
+        log::info!(
+            "Saved {} chunks in {} regions to {:?}",
+            chunks.len(),
+            regions,
+            storage.get_directory()
+        );
+        Ok(chunks.len())
+    }
+
+    /// Like `save`, but the chunks are written by the IO queue in the background once
+    /// there are no loads waiting. Does nothing while an earlier save is still being
+    /// written, the chunks will go in the next one. Returns how many chunks were queued.
+    pub fn queue_save(&mut self) -> Result<usize> {
+        let Some(storage) = &self.storage else {
+            return Ok(0);
+        };
+        if self.unsaved_chunks.is_empty() || self.io.is_saving() {
+            return Ok(0);
+        }
+
+        let chunks: Vec<Arc<Chunk>> = self
+            .unsaved_chunks
+            .iter()
+            .filter_map(|pos| self.chunks.get(pos).cloned())
+            .collect();
+        if !self
+            .io
+            .queue_save(chunks.clone(), self.materials.clone(), storage)
+        {
+            return self.save();
+        }
+
+        self.unsaved_chunks.clear();
+        self.saving_chunks
+            .extend(chunks.iter().map(|chunk| (chunk.get_pos(), chunk.clone())));
+        Ok(chunks.len())
+    }
+
+    /// Moves the point chunks are loaded around first, in world voxel space. Usually
+    /// wherever the camera is.
+    pub fn set_focus(&mut self, pos: glam::Vec3) {
+        let chunk_voxel_dims = self.chunk_dims.as_vec3() * 8.0;
+        self.focus = (pos / chunk_voxel_dims).floor().as_ivec3();
+    }
+
+    pub fn get_io_stats(&self) -> IoStats {
+        self.io.get_stats()
+    }
+
+    /// Makes sure a chunk is loaded, returning whether it's ready to use. Chunks that
+    /// aren't get read from storage by the IO queue then generated in the background,
+    /// call `process_generated_chunks` to collect them. Chunks near the focus are read
+    /// before those further away.
+    pub fn request_chunk(&mut self, chunk_pos: glam::IVec3) -> bool {
+        if self.chunks.contains_key(&chunk_pos) {
+            return true;
+        }
+        // Dropped by a restore while it was being saved, and storage doesn't have it yet
+        if let Some(chunk) = self.saving_chunks.get(&chunk_pos) {
+            self.chunks.insert(chunk_pos, chunk.clone());
+            return true;
+        }
+        if self.generator.is_pending(chunk_pos) {
+            return false;
+        }
+
+        if let Some(storage) = &self.storage {
+            let near_focus = (chunk_pos - self.focus).abs().max_element() <= NEARBY_CHUNK_RADIUS;
+            let priority = match near_focus {
+                true => IoPriority::Nearby,
+                false => IoPriority::Distant,
+            };
+            if self.io.is_loading(chunk_pos) || self.io.is_full() {
+                // Asking again is how a chunk that's come closer jumps the queue
+                self.io.queue_load(chunk_pos, priority, storage);
+                return false;
+            }
+            if self.io.queue_load(chunk_pos, priority, storage) {
+                return false;
+            }
+
+            // No IO workers, so we're stuck reading it ourselves
+            self.get_chunk_mut(chunk_pos);
+            return true;
+        }
+
+        let queued = self.generator.queue(
+            chunk_pos,
+            &self.world_generator,
+            self.settings,
+            self.chunk_dims,
+            None,
+        );
+        if !queued {
+            // No workers to hand it to, so we're stuck generating it ourselves
+            self.get_chunk_mut(chunk_pos);
+        }
+        !queued
+    }
+
+    /// Adds every chunk that's finished generating in the background to the world, and
+    /// hands any the IO queue has finished reading on to the generators. Returns how many
+    /// chunks were added.
+    pub fn process_generated_chunks(&mut self) -> usize {
+        self.process_io_results();
+
+        let mut count = 0;
+        for chunk in self.generator.take_completed() {
+            // The chunk may have been needed before it was done, e.g. by an edit, in which
+            // case it was generated on the spot and this copy is no longer wanted
+            let pos = chunk.get_pos();
+            if self.chunks.contains_key(&pos) {
+                continue;
+            }
+            self.chunks.insert(pos, Arc::new(chunk));
+            count += 1;
+        }
+        count
+    }
+
+    /// How many chunks are currently being generated in the background.
+    pub fn get_pending_chunk_count(&self) -> usize {
+        self.generator.get_pending_count()
+    }
+
     pub fn get_block(&mut self, chunk_pos: glam::IVec3, local_pos: glam::UVec3) -> Vec<Voxel> {
-        // There's no world saving yet, so if a chunk isn't currently loaded we need to
-        // generate it's base noise values
+        self.with_block(chunk_pos, local_pos, |voxels| voxels.to_vec())
+    }
+
+    /// Whether a block is entirely empty, by its position in world block space.
+    pub fn is_block_empty(&mut self, pos: glam::IVec3) -> bool {
+        let chunk_dims = self.chunk_dims;
+        let chunk_pos = pos.div_euclid(chunk_dims.as_ivec3());
+        let local_pos = pos.rem_euclid(chunk_dims.as_ivec3()).as_uvec3();
+        self.with_block(chunk_pos, local_pos, |voxels| {
+            voxels.iter().all(|v| *v == Voxel::Empty)
+        })
+    }
+
+    /// Modification version of a chunk. Chunks that haven't been generated yet can't have
+    /// been modified, so they're always version 0.
+    pub fn get_chunk_version(&self, chunk_pos: glam::IVec3) -> u64 {
+        let version = self.chunks.get(&chunk_pos).map_or(0, |c| c.get_version());
+        let offset = self.version_offsets.get(&chunk_pos).copied().unwrap_or(0);
+        version.wrapping_add(offset)
+    }
+
+    /// Gets a single voxel by its position in world voxel space.
+    pub fn get_voxel(&mut self, pos: glam::IVec3) -> Voxel {
+        let (chunk_pos, block_pos, voxel_idx) = self.split_voxel_pos(pos);
+        self.with_block(chunk_pos, block_pos, |voxels| voxels[voxel_idx])
+    }
+
+    /// Gets a single voxel by its position in world voxel space, or `None` if its chunk
+    /// isn't loaded. Unlike `get_voxel` this never generates a chunk.
+    pub fn try_get_voxel(&mut self, pos: glam::IVec3) -> Option<Voxel> {
+        let (chunk_pos, block_pos, voxel_idx) = self.split_voxel_pos(pos);
+        if !self.chunks.contains_key(&chunk_pos) {
+            return None;
+        }
+        Some(self.with_block(chunk_pos, block_pos, |voxels| voxels[voxel_idx]))
+    }
+
+    /// Sets a single voxel by its position in world voxel space. Returns whether it
+    /// changed, in which case its block is flagged as dirty.
+    pub fn set_voxel(&mut self, pos: glam::IVec3, voxel: Voxel) -> bool {
+        let chunk_dims = self.chunk_dims;
+        let (chunk_pos, block_pos, voxel_idx) = self.split_voxel_pos(pos);
+        let changed = self
+            .get_chunk_mut(chunk_pos)
+            .set_voxel(block_pos, voxel_idx, voxel, chunk_dims);
+        if changed {
+            self.dirty_blocks
+                .insert(pos.div_euclid(glam::IVec3::splat(8)));
+            self.unsaved_chunks.insert(chunk_pos);
+        }
+        changed
+    }
+
+    /// Sets many voxels at once (world voxel space), returning how many changed. Edits are
+    /// grouped by chunk, so large edits spanning several chunks only look each chunk and
+    /// block up once rather than once per voxel.
+    pub fn set_voxels(&mut self, voxels: &[(glam::IVec3, Voxel)]) -> usize {
+        let chunk_dims = self.chunk_dims;
+        let mut chunk_edits: HashMap<glam::IVec3, Vec<(glam::UVec3, usize, Voxel)>> =
+            HashMap::new();
+        for &(pos, voxel) in voxels {
+            let (chunk_pos, block_pos, voxel_idx) = self.split_voxel_pos(pos);
+            chunk_edits
+                .entry(chunk_pos)
+                .or_default()
+                .push((block_pos, voxel_idx, voxel));
+        }
+
+        let mut changed = 0;
+        for (chunk_pos, edits) in chunk_edits {
+            let chunk = self.get_chunk_mut(chunk_pos);
+            let mut changed_blocks = HashSet::new();
+            for (block_pos, voxel_idx, voxel) in edits {
+                if chunk.set_voxel(block_pos, voxel_idx, voxel, chunk_dims) {
+                    changed_blocks.insert(block_pos);
+                    changed += 1;
+                }
+            }
+
+            if !changed_blocks.is_empty() {
+                let chunk_origin = chunk_pos * chunk_dims.as_ivec3();
+                self.dirty_blocks.extend(
+                    changed_blocks
+                        .into_iter()
+                        .map(|block_pos| chunk_origin + block_pos.as_ivec3()),
+                );
+                self.unsaved_chunks.insert(chunk_pos);
+            }
+        }
+        changed
+    }
+
+    /// Sets every voxel in `min..max` (world voxel space). Returns how many voxels changed.
+    pub fn set_region(&mut self, min: glam::IVec3, max: glam::IVec3, voxel: Voxel) -> usize {
+        let mut changed = 0;
+        for z in min.z..max.z {
+            for y in min.y..max.y {
+                for x in min.x..max.x {
+                    if self.set_voxel(glam::ivec3(x, y, z), voxel) {
+                        changed += 1;
+                    }
+                }
+            }
+        }
+        changed
+    }
+
+    /// Takes the positions (in world block space) of every block edited since the last
+    /// call. Blocks map 1:1 to bricks, so these are the bricks that need reloading.
+    pub fn take_dirty_blocks(&mut self) -> Vec<glam::IVec3> {
+        self.dirty_blocks.drain().collect()
+    }
+
+    /// Regenerates the voxels in `min..max` (world voxel space) from `settings`, discarding
+    /// any edits inside the region. Everything outside of it is kept, and the world's own
+    /// settings are left alone, so pass `get_settings()` to regenerate the region as it
+    /// would normally be. Changed blocks are flagged as dirty like any other edit. Chunks
+    /// with edits saved to storage are loaded to have them regenerated over. Returns how
+    /// many voxels changed.
+    pub fn regenerate_region(
+        &mut self,
+        min: glam::IVec3,
+        max: glam::IVec3,
+        settings: GenerationSettings,
+    ) -> usize {
+        if min.cmpge(max).any() {
+            return 0;
+        }
+
+        let chunk_voxel_dims = self.chunk_dims.as_ivec3() * 8;
+        let min_chunk = min.div_euclid(chunk_voxel_dims);
+        let max_chunk = (max - 1).div_euclid(chunk_voxel_dims);
+
+        let mut changed = 0;
+        for z in min_chunk.z..=max_chunk.z {
+            for y in min_chunk.y..=max_chunk.y {
+                for x in min_chunk.x..=max_chunk.x {
+                    let chunk_pos = glam::ivec3(x, y, z);
+
+                    // Chunks that have never been edited will be generated whenever
+                    // they're needed
+                    if !self.chunks.contains_key(&chunk_pos) && !self.load_saved_chunk(chunk_pos) {
+                        continue;
+                    }
+
+                    let origin = chunk_pos * chunk_voxel_dims;
+                    let local_min = (min - origin).max(glam::IVec3::ZERO).as_uvec3();
+                    let local_max = (max - origin).min(chunk_voxel_dims).as_uvec3();
+                    let chunk_dims = self.chunk_dims;
+                    let chunk = Arc::make_mut(self.chunks.get_mut(&chunk_pos).unwrap());
+                    let (changed_blocks, chunk_changed) = chunk.regenerate_region(
+                        &self.world_generator,
+                        settings,
+                        local_min,
+                        local_max,
+                        chunk_dims,
+                    );
+                    if chunk_changed > 0 {
+                        let chunk_origin = chunk_pos * chunk_dims.as_ivec3();
+                        self.dirty_blocks.extend(
+                            changed_blocks
+                                .into_iter()
+                                .map(|block_pos| chunk_origin + block_pos.as_ivec3()),
+                        );
+                        self.unsaved_chunks.insert(chunk_pos);
+                    }
+                    changed += chunk_changed;
+                }
+            }
+        }
+
+        log::info!(
+            "Regenerated region {} to {}, {} voxels changed",
+            min,
+            max,
+            changed
+        );
+        changed
+    }
+
+    /// Captures the world's voxels so they can be put back with `restore`. Nothing is
+    /// copied up front, chunks are only copied as the world modifies them afterwards.
+    pub fn snapshot(&self) -> WorldSnapshot {
+        WorldSnapshot {
+            world: self.id,
+            chunks: self.chunks.clone(),
+            unsaved_chunks: self.unsaved_chunks.clone(),
+        }
+    }
+
+    /// Puts the world's voxels back to how they were when `snapshot` was taken. Storage
+    /// isn't touched, chunks that differ from what's saved are just marked unsaved again.
+    /// Chunks that have been loaded since the snapshot are dropped and loaded again when
+    /// next needed, so they'll pick up anything saved to storage in the meantime. Every
+    /// chunk that changed gets a newer version so its bricks are reloaded. Returns how
+    /// many chunks changed.
+    pub fn restore(&mut self, snapshot: &WorldSnapshot) -> Result<usize> {
+        if snapshot.world != self.id {
+            bail!("Snapshot is of a different world");
+        }
+
+        let mut changed_chunks: Vec<glam::IVec3> = self
+            .chunks
+            .iter()
+            .filter(|(pos, chunk)| {
+                snapshot
+                    .chunks
+                    .get(*pos)
+                    .is_none_or(|old| !Arc::ptr_eq(old, chunk))
+            })
+            .map(|(pos, _)| *pos)
+            .collect();
+        changed_chunks.extend(
+            snapshot
+                .chunks
+                .keys()
+                .filter(|pos| !self.chunks.contains_key(*pos)),
+        );
+
+        for &chunk_pos in &changed_chunks {
+            // Versions are only ever compared for equality, so going one past whatever
+            // was last reported is enough to get everything derived from the chunk redone
+            let next_version = self.get_chunk_version(chunk_pos).wrapping_add(1);
+            let restored_version = snapshot
+                .chunks
+                .get(&chunk_pos)
+                .map_or(0, |c| c.get_version());
+            self.version_offsets
+                .insert(chunk_pos, next_version.wrapping_sub(restored_version));
+        }
+
+        self.chunks = snapshot.chunks.clone();
+        self.unsaved_chunks = snapshot.unsaved_chunks.clone();
+        self.unsaved_chunks.extend(
+            changed_chunks
+                .iter()
+                .filter(|pos| self.chunks.contains_key(*pos)),
+        );
+
+        log::info!("Restored snapshot, {} chunks changed", changed_chunks.len());
+        Ok(changed_chunks.len())
+    }
+
+    /// Generation timings of the `count` chunks that have taken the longest to generate.
+    pub fn get_slowest_chunks(&self, count: usize) -> Vec<ChunkGenTiming> {
+        let mut timings: Vec<ChunkGenTiming> =
+            self.chunks.values().map(|c| *c.get_timing()).collect();
+        timings.sort_by_key(|t| std::cmp::Reverse(t.get_total_time()));
+        timings.truncate(count);
+        timings
+    }
+
+    /// A readable report of the slowest chunks to generate, along with the settings
+    /// they were generated with.
+    pub fn get_generation_report(&self, count: usize) -> String {
+        profile::format_report(&self.get_slowest_chunks(count))
+    }
+
+    /// Splits a world voxel position into chunk position, block position within the
+    /// chunk, and voxel index within the block.
+    fn split_voxel_pos(&self, pos: glam::IVec3) -> (glam::IVec3, glam::UVec3, usize) {
+        let block_pos = pos.div_euclid(glam::IVec3::splat(8));
+        let voxel_pos = pos.rem_euclid(glam::IVec3::splat(8)).as_uvec3();
+        let chunk_dims = self.chunk_dims.as_ivec3();
+        let chunk_pos = block_pos.div_euclid(chunk_dims);
+        let local_pos = block_pos.rem_euclid(chunk_dims).as_uvec3();
+        let voxel_idx = math::to_1d_index(voxel_pos, glam::uvec3(8, 8, 8));
+        (chunk_pos, local_pos, voxel_idx)
+    }
+
+    /// Sends loaded chunks on to be generated and finishes off saves.
+    fn process_io_results(&mut self) {
+        for result in self.io.take_completed() {
+            match result {
+                IoResult::Loaded { pos, blocks } => {
+                    // Edits can't wait for the queue, so they may have loaded it already
+                    if self.chunks.contains_key(&pos) {
+                        continue;
+                    }
+                    let saved = blocks.unwrap_or_else(|e| {
+                        log::error!("Failed to load chunk {}, regenerating it: {:#}", pos, e);
+                        None
+                    });
+                    if self.generator.is_pending(pos) {
+                        continue;
+                    }
+                    if self.generator.get_worker_count() == 0 {
+                        let chunk = generator::build_chunk(
+                            pos,
+                            &self.world_generator,
+                            self.settings,
+                            self.chunk_dims,
+                            saved,
+                        );
+                        self.chunks.insert(pos, Arc::new(chunk));
+                        continue;
+                    }
+                    // If this fails the chunk just gets requested again
+                    self.generator.queue(
+                        pos,
+                        &self.world_generator,
+                        self.settings,
+                        self.chunk_dims,
+                        saved,
+                    );
+                }
+                IoResult::Saved { chunks, regions } => {
+                    for pos in &chunks {
+                        self.saving_chunks.remove(pos);
+                    }
+                    match regions {
+                        Ok(regions) => log::info!(
+                            "Saved {} chunks in {} regions in the background",
+                            chunks.len(),
+                            regions
+                        ),
+                        Err(e) => {
+                            // Try again with the next save
+                            log::error!("Background save failed: {:#}", e);
+                            self.unsaved_chunks.extend(
+                                chunks
+                                    .into_iter()
+                                    .filter(|pos| self.chunks.contains_key(pos)),
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Runs `f` on a block's voxels. Blocks that have already been generated are read in
+    /// place, so reading never copies a chunk that's shared with a snapshot.
+    fn with_block<R>(
+        &mut self,
+        chunk_pos: glam::IVec3,
+        block_pos: glam::UVec3,
+        f: impl FnOnce(&[Voxel]) -> R,
+    ) -> R {
+        let chunk_dims = self.chunk_dims;
+        if let Some(voxels) = self
+            .chunks
+            .get(&chunk_pos)
+            .and_then(|c| c.get_generated_voxels(block_pos, chunk_dims))
+        {
+            return f(voxels);
+        }
+        f(self
+            .get_chunk_mut(chunk_pos)
+            .get_block_voxels(block_pos, chunk_dims))
+    }
+
+    /// Copies the chunk first if it's shared with a snapshot.
+    fn get_chunk_mut(&mut self, chunk_pos: glam::IVec3) -> &mut Chunk {
+        // If a chunk isn't currently loaded we have to generate it right away, even if
+        // it's already queued in the background
         if !self.chunks.contains_key(&chunk_pos) {
-            let new_chunk = self.gen_chunk(chunk_pos);
+            let new_chunk = match self.saving_chunks.get(&chunk_pos) {
+                Some(chunk) => chunk.clone(),
+                None => Arc::new(generator::load_chunk(
+                    chunk_pos,
+                    &self.world_generator,
+                    self.settings,
+                    self.chunk_dims,
+                    &self.storage,
+                )),
+            };
             self.chunks.insert(chunk_pos, new_chunk);
         }
 
-        let chunk = self.chunks.get_mut(&chunk_pos).unwrap();
-        chunk.get_block(local_pos, self.chunk_dims)
-    }
-
-    fn gen_chunk(&mut self, pos: glam::IVec3) -> Chunk {
-        // We use dimensions of `chunk_dims + 1` because the corners on the last chunk
-        // block of each axis step outside of our 0..N bounds, sharing a value with the
-        // neighbouring chunk
-        let noise = simdnoise::NoiseBuilder::fbm_3d_offset(
-            pos.x as f32 * self.chunk_dims.x as f32,
-            self.chunk_dims.x as usize + 1,
-            pos.y as f32 * self.chunk_dims.y as f32,
-            self.chunk_dims.y as usize + 1,
-            pos.z as f32 * self.chunk_dims.z as f32,
-            self.chunk_dims.z as usize + 1,
-        )
-        .with_seed(self.settings.seed)
-        .with_freq(self.settings.frequency)
-        .with_octaves(self.settings.octaves)
-        .with_gain(self.settings.gain)
-        .with_lacunarity(self.settings.lacunarity)
-        .generate()
-        .0;
-
-        let num_blocks = self.chunk_dims.x * self.chunk_dims.y * self.chunk_dims.z;
-        let blocks = vec![vec![]; num_blocks as usize];
-        Chunk::new(pos, noise, blocks)
+        Arc::make_mut(self.chunks.get_mut(&chunk_pos).unwrap())
+    }
+
+    /// Brings a chunk that isn't in memory back in if it has edits, either saved to
+    /// storage or still on their way there. Returns whether it's in memory now.
+    fn load_saved_chunk(&mut self, chunk_pos: glam::IVec3) -> bool {
+        if let Some(chunk) = self.saving_chunks.get(&chunk_pos) {
+            self.chunks.insert(chunk_pos, chunk.clone());
+            return true;
+        }
+        let saved = match self.storage.as_ref().map(|s| s.load_chunk(chunk_pos)) {
+            Some(Ok(Some(blocks))) => blocks,
+            Some(Err(e)) => {
+                log::error!("Failed to load chunk {}: {:#}", chunk_pos, e);
+                return false;
+            }
+            _ => return false,
+        };
+        let chunk = generator::build_chunk(
+            chunk_pos,
+            &self.world_generator,
+            self.settings,
+            self.chunk_dims,
+            Some(saved),
+        );
+        self.chunks.insert(chunk_pos, Arc::new(chunk));
+        true
+    }
+}
+
+impl Drop for WorldManager {
+    fn drop(&mut self) {
+        // A panic unwinding through the app would lose every edit since the last save.
+        // The IO queue may be what panicked, so don't wait on it
+        if std::thread::panicking() && !self.unsaved_chunks.is_empty() {
+            match self.write_unsaved() {
+                Ok(count) => log::info!("Saved {} chunks before crashing", count),
+                Err(e) => log::error!("Failed to save before crashing: {:#}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxel::world::BuiltinMaterial;
+
+    #[test]
+    fn regenerate_region_only_undoes_edits_inside_it() {
+        let mut world = WorldManager::new(GenerationSettings::default(), glam::UVec3::splat(4));
+        let (inside, outside) = (glam::ivec3(3, 3, 3), glam::ivec3(12, 3, 3));
+        let original = world.get_voxel(inside);
+        let edit = match original {
+            Voxel::Empty => Voxel::Material(BuiltinMaterial::Stone.id()),
+            Voxel::Material(_) => Voxel::Empty,
+        };
+        world.set_voxel(inside, edit);
+        world.set_voxel(outside, edit);
+        world.take_dirty_blocks();
+
+        let changed = world.regenerate_region(
+            glam::IVec3::ZERO,
+            glam::IVec3::splat(8),
+            world.get_settings(),
+        );
+        assert_eq!(changed, 1);
+        assert_eq!(world.get_voxel(inside), original);
+        assert_eq!(world.get_voxel(outside), edit);
+        assert_eq!(world.take_dirty_blocks(), vec![glam::IVec3::ZERO]);
+    }
+
+    #[test]
+    fn regenerate_region_leaves_unedited_terrain_alone() {
+        let mut world = WorldManager::new(GenerationSettings::default(), glam::UVec3::splat(4));
+        world.get_voxel(glam::IVec3::ZERO);
+        let changed = world.regenerate_region(
+            glam::IVec3::splat(-16),
+            glam::IVec3::splat(16),
+            world.get_settings(),
+        );
+        assert_eq!(changed, 0);
+        assert!(world.take_dirty_blocks().is_empty());
     }
 }
@@ -0,0 +1,284 @@
+use std::{fmt::Write as _, fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+
+/// Index into a world's material table.
+pub type MaterialId = u16;
+
+/// How a voxel's surface looks. Every voxel with the same material id shares one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Material {
+    pub albedo: [u8; 3],
+    /// Colour of the light given off by the surface, scaled by `emissive_intensity`
+    pub emissive: glam::Vec3,
+    /// 0 for surfaces that don't glow. Emissive surfaces show up regardless of lighting
+    /// and light whatever is around them
+    pub emissive_intensity: f32,
+    /// 0 is a perfect mirror, 1 is completely diffuse
+    pub roughness: f32,
+    /// 0 for dielectrics, 1 for metals, which tint their reflections and have no diffuse
+    pub metallic: f32,
+}
+
+impl Material {
+    /// A plain diffuse material of a colour.
+    pub const fn from_color(r: u8, g: u8, b: u8) -> Self {
+        Self {
+            albedo: [r, g, b],
+            emissive: glam::Vec3::ONE,
+            emissive_intensity: 0.0,
+            roughness: 1.0,
+            metallic: 0.0,
+        }
+    }
+
+    pub fn with_emissive(self, emissive: glam::Vec3, intensity: f32) -> Self {
+        Self {
+            emissive,
+            emissive_intensity: intensity,
+            ..self
+        }
+    }
+
+    pub fn with_roughness(self, roughness: f32) -> Self {
+        Self { roughness, ..self }
+    }
+
+    pub fn with_metallic(self, metallic: f32) -> Self {
+        Self { metallic, ..self }
+    }
+
+    /// Radiance given off by the surface.
+    pub fn get_emission(&self) -> glam::Vec3 {
+        self.emissive * self.emissive_intensity
+    }
+
+    /// The albedo packed as RGBA with full alpha, the way the renderer stores colours.
+    pub fn get_packed_albedo(&self) -> u32 {
+        let [r, g, b] = self.albedo.map(|c| c as u32);
+        (r << 24) | (g << 16) | (b << 8) | 255
+    }
+}
+
+/// Materials every table starts with, so world generation can use them without needing
+/// the table itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuiltinMaterial {
+    Stone,
+    Dirt,
+    Grass,
+    Sand,
+    Snow,
+    Lamp,
+}
+
+impl BuiltinMaterial {
+    pub const ALL: [Self; 6] = [
+        Self::Stone,
+        Self::Dirt,
+        Self::Grass,
+        Self::Sand,
+        Self::Snow,
+        Self::Lamp,
+    ];
+
+    pub const fn id(self) -> MaterialId {
+        self as MaterialId
+    }
+
+    pub fn material(self) -> Material {
+        match self {
+            Self::Stone => Material::from_color(112, 112, 120),
+            Self::Dirt => Material::from_color(121, 85, 58),
+            Self::Grass => Material::from_color(72, 140, 48),
+            Self::Sand => Material::from_color(219, 200, 148),
+            Self::Snow => Material::from_color(240, 244, 250).with_roughness(0.6),
+            Self::Lamp => {
+                Material::from_color(255, 220, 160).with_emissive(glam::vec3(1.0, 0.85, 0.6), 4.0)
+            }
+        }
+    }
+}
+
+/// Every material a world's voxels can use. Materials are only ever added or changed,
+/// never removed, so ids stay valid for as long as the world is around.
+#[derive(Debug, Clone)]
+pub struct MaterialTable {
+    materials: Vec<Material>,
+    /// Bumped on every change, so the renderer knows when to upload it again
+    version: u64,
+}
+
+impl Default for MaterialTable {
+    fn default() -> Self {
+        Self {
+            materials: BuiltinMaterial::ALL.map(|m| m.material()).to_vec(),
+            version: 0,
+        }
+    }
+}
+
+impl MaterialTable {
+    /// Matches the size of the material table in the raycast shader
+    pub const MAX_MATERIALS: usize = 1024;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Unknown ids get the first material rather than panicking, as they can come from
+    /// saves or edits made against a different table.
+    pub fn get(&self, id: MaterialId) -> Material {
+        self.materials
+            .get(id as usize)
+            .copied()
+            .unwrap_or(self.materials[0])
+    }
+
+    pub fn get_materials(&self) -> &[Material] {
+        &self.materials
+    }
+
+    pub fn get_version(&self) -> u64 {
+        self.version
+    }
+
+    /// Adds a material, returning `None` if the table is full.
+    pub fn add(&mut self, material: Material) -> Option<MaterialId> {
+        if self.materials.len() >= Self::MAX_MATERIALS {
+            return None;
+        }
+        self.materials.push(material);
+        self.version += 1;
+        Some((self.materials.len() - 1) as MaterialId)
+    }
+
+    /// Changes an existing material. Every voxel using it changes with it.
+    pub fn set(&mut self, id: MaterialId, material: Material) -> bool {
+        let Some(existing) = self.materials.get_mut(id as usize) else {
+            return false;
+        };
+        *existing = material;
+        self.version += 1;
+        true
+    }
+
+    /// Changes how brightly an existing material glows, keeping its colour.
+    pub fn set_emissive_intensity(&mut self, id: MaterialId, intensity: f32) -> bool {
+        let material = self.get(id);
+        self.set(
+            id,
+            Material {
+                emissive_intensity: intensity.max(0.0),
+                ..material
+            },
+        )
+    }
+
+    /// Whether any material glows, in which case it's worth looking for their light.
+    pub fn has_emissive(&self) -> bool {
+        self.materials
+            .iter()
+            .any(|m| m.get_emission() != glam::Vec3::ZERO)
+    }
+
+    /// Finds a material identical to `material`, adding it if there isn't one. Once the
+    /// table is full this falls back to whichever material has the closest albedo, so
+    /// importing lots of colours degrades gracefully rather than failing.
+    pub fn find_or_add(&mut self, material: Material) -> MaterialId {
+        if let Some(id) = self.materials.iter().position(|m| *m == material) {
+            return id as MaterialId;
+        }
+        if let Some(id) = self.add(material) {
+            return id;
+        }
+
+        let distance = |m: &Material| {
+            m.albedo
+                .iter()
+                .zip(material.albedo)
+                .map(|(a, b)| (*a as i32 - b as i32).pow(2))
+                .sum::<i32>()
+        };
+        self.materials
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, m)| distance(m))
+            .map_or(0, |(id, _)| id as MaterialId)
+    }
+
+    /// A plain diffuse material of a colour, see `find_or_add`.
+    pub fn find_or_add_color(&mut self, r: u8, g: u8, b: u8) -> MaterialId {
+        self.find_or_add(Material::from_color(r, g, b))
+    }
+
+    /// Loads a table written by `save`. Older tables without an emissive intensity have
+    /// their emissive colour taken as the full emission.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+
+        let mut materials = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let values: Vec<f32> = line
+                .split_whitespace()
+                .map(|v| v.parse().ok())
+                .collect::<Option<_>>()
+                .with_context(|| format!("Material line {} isn't all numbers", i + 1))?;
+            let (albedo, emissive, intensity, rest) = match values[..] {
+                [r, g, b, er, eg, eb, intensity, roughness, metallic] => {
+                    ([r, g, b], [er, eg, eb], intensity, [roughness, metallic])
+                }
+                [r, g, b, er, eg, eb, roughness, metallic] => {
+                    ([r, g, b], [er, eg, eb], 1.0, [roughness, metallic])
+                }
+                _ => bail!("Material line {} should have 9 values", i + 1),
+            };
+            materials.push(Material {
+                albedo: albedo.map(|c| c.clamp(0.0, 255.0) as u8),
+                emissive: glam::Vec3::from_array(emissive),
+                emissive_intensity: intensity,
+                roughness: rest[0],
+                metallic: rest[1],
+            });
+        }
+
+        if materials.is_empty() || materials.len() > Self::MAX_MATERIALS {
+            bail!("{} has {} materials", path.display(), materials.len());
+        }
+        Ok(Self {
+            materials,
+            version: 0,
+        })
+    }
+
+    /// Writes the table as one material per line, in id order.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut contents = String::from(
+            "# albedo r g b, emissive r g b, emissive intensity, roughness, metallic. One per id\n",
+        );
+        for material in &self.materials {
+            let [r, g, b] = material.albedo;
+            let emissive = material.emissive;
+            let _ = writeln!(
+                contents,
+                "{} {} {} {:?} {:?} {:?} {:?} {:?} {:?}",
+                r,
+                g,
+                b,
+                emissive.x,
+                emissive.y,
+                emissive.z,
+                material.emissive_intensity,
+                material.roughness,
+                material.metallic
+            );
+        }
+        fs::write(path, contents).with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
@@ -1,19 +1,102 @@
+mod analysis;
+mod biome;
 mod chunk;
+mod explosion;
+mod generator;
+mod io_scheduler;
 mod manager;
+mod material;
+pub mod pipeline;
+mod profile;
+pub mod raycast;
+mod storage;
+mod structure;
 
-pub use {chunk::Chunk, manager::*};
+pub use {
+    analysis::WorldStats,
+    biome::Biome,
+    chunk::Chunk,
+    explosion::{Debris, Explosion},
+    generator::ChunkGenerator,
+    io_scheduler::{IoPriority, IoResult, IoScheduler, IoStats},
+    manager::*,
+    material::{BuiltinMaterial, Material, MaterialId, MaterialTable},
+    pipeline::{BlockContext, StageData, WorldGenerator},
+    profile::ChunkGenTiming,
+    storage::WorldStorage,
+    structure::StructureVoxels,
+};
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Voxel {
     Empty,
-    Color(u8, u8, u8),
+    /// Index into the world's `MaterialTable`
+    Material(MaterialId),
 }
 
-#[derive(Debug, Clone, Copy)]
+/// How the generator shapes the ground.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Terrain {
+    /// 3D density noise. Gives overhangs and caves, but also blobs floating in the sky
+    /// and no real ground level
+    Density,
+    /// 2D noise over x/z giving the height of the ground, with solid rock below it and
+    /// nothing above
+    Heightmap,
+}
+
+impl Terrain {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "density" => Some(Self::Density),
+            "heightmap" => Some(Self::Heightmap),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Density => "density",
+            Self::Heightmap => "heightmap",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct GenerationSettings {
     pub seed: i32,
     pub frequency: f32,
     pub octaves: u8,
     pub gain: f32,
     pub lacunarity: f32,
+    pub terrain: Terrain,
+    /// Average height of the ground for `Terrain::Heightmap`, in blocks
+    pub ground_height: f32,
+    /// How far the heightmap's noise moves the ground up and down, in blocks
+    pub height_amplitude: f32,
+    /// How often tunnels wind around, like `frequency`
+    pub cave_frequency: f32,
+    /// How wide tunnels are, 0.15 making them a couple of blocks across at the default
+    /// `cave_frequency`. 0 for no caves
+    pub cave_width: f32,
+    /// How often trees and boulders turn up, 1 as usual and 0 for none at all
+    pub structure_density: f32,
+}
+
+impl Default for GenerationSettings {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            frequency: 0.04,
+            octaves: 3,
+            gain: 0.5,
+            lacunarity: 2.0,
+            terrain: Terrain::Density,
+            ground_height: 24.0,
+            height_amplitude: 16.0,
+            cave_frequency: 0.03,
+            cave_width: 0.15,
+            structure_density: 1.0,
+        }
+    }
 }
@@ -0,0 +1,330 @@
+use std::{any::Any, sync::Arc};
+
+use crate::math;
+
+use super::{
+    biome, generator, structure, Biome, BuiltinMaterial, GenerationSettings, StructureVoxels, Voxel,
+};
+
+/// Density below this is the grassy top layer of the ground
+const SURFACE_DEPTH: f32 = 0.04;
+/// Density below this and above `SURFACE_DEPTH` is dirt, the rest is stone
+const SOIL_DEPTH: f32 = 0.15;
+
+/// Whatever a stage works out for a whole chunk up front, handed back to it for each
+/// block. Shared rather than copied when a chunk is.
+pub type StageData = Arc<dyn Any + Send + Sync>;
+
+/// A block partway through generation, passed through each stage in turn.
+#[derive(Debug, Clone)]
+pub struct BlockContext {
+    /// In blocks, relative to the chunk
+    pub block_pos: glam::UVec3,
+    pub chunk_dims: glam::UVec3,
+    /// Positive inside the ground and growing with depth, so it doubles as how far below
+    /// the surface a voxel is. Indexed like `voxels`
+    pub density: [f32; 512],
+    /// The biome of each voxel column, x first then z
+    pub biomes: [Biome; 64],
+    /// Indexed x first, then y, then z
+    pub voxels: [Voxel; 512],
+}
+
+impl BlockContext {
+    pub fn new(block_pos: glam::UVec3, chunk_dims: glam::UVec3) -> Self {
+        Self {
+            block_pos,
+            chunk_dims,
+            density: [0.0; 512],
+            biomes: [Biome::Grassland; 64],
+            voxels: [Voxel::Empty; 512],
+        }
+    }
+
+    /// The corner values of this block from a chunk wide field with a value at every
+    /// block corner, like the ones from `generator::gen_terrain`, in the order
+    /// `math::tri_lerp_block` wants them.
+    pub fn get_corners(&self, field: &[f32]) -> [f32; 8] {
+        let noise_dims = self.chunk_dims + glam::uvec3(1, 1, 1);
+        std::array::from_fn(|i| {
+            let corner = glam::uvec3(i as u32 & 1, (i as u32 >> 1) & 1, i as u32 >> 2);
+            field[math::to_1d_index(self.block_pos + corner, noise_dims)]
+        })
+    }
+}
+
+/// One step of generating the world, such as shaping the ground or carving caves out
+/// of it. Stages run in order for every block, each picking up where the last left off.
+pub trait GenerationStage: Send + Sync {
+    /// For logs and reports
+    fn name(&self) -> &'static str;
+
+    /// Works out anything the stage needs across a whole chunk, like noise fields, when
+    /// the chunk is created. Runs on a generator thread.
+    fn prepare(
+        &self,
+        _pos: glam::IVec3,
+        _settings: GenerationSettings,
+        _chunk_dims: glam::UVec3,
+    ) -> Option<StageData> {
+        None
+    }
+
+    /// Does the stage's part of a block, given whatever `prepare` returned for its chunk.
+    fn gen_block(&self, block: &mut BlockContext, data: Option<&(dyn Any + Send + Sync)>);
+}
+
+/// Turns chunk positions into voxels by running a list of stages. Blocks are only
+/// generated once something looks at them, so every stage gets the chance to do its
+/// chunk wide work first.
+#[derive(Clone, Default)]
+pub struct WorldGenerator {
+    stages: Vec<Arc<dyn GenerationStage>>,
+}
+
+impl std::fmt::Debug for WorldGenerator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.stages.iter().map(|s| s.name()))
+            .finish()
+    }
+}
+
+impl WorldGenerator {
+    /// A generator with no stages, which leaves everything empty.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The usual terrain: noise shaped ground, layered by biome, with tunnels through it
+    /// and trees and boulders on top.
+    pub fn standard() -> Self {
+        Self::new()
+            .with_stage(DensityStage)
+            .with_stage(SurfaceStage)
+            .with_stage(CaveStage)
+            .with_stage(StructureStage)
+            .with_stage(ColoringStage)
+    }
+
+    /// Adds a stage to run after the ones already added.
+    pub fn with_stage(mut self, stage: impl GenerationStage + 'static) -> Self {
+        self.stages.push(Arc::new(stage));
+        self
+    }
+
+    pub fn get_stage_names(&self) -> Vec<&'static str> {
+        self.stages.iter().map(|s| s.name()).collect()
+    }
+
+    /// Each stage's chunk wide data, in stage order.
+    pub fn prepare(
+        &self,
+        pos: glam::IVec3,
+        settings: GenerationSettings,
+        chunk_dims: glam::UVec3,
+    ) -> Vec<Option<StageData>> {
+        self.stages
+            .iter()
+            .map(|stage| stage.prepare(pos, settings, chunk_dims))
+            .collect()
+    }
+
+    /// Runs every stage over a block, with the data `prepare` returned for its chunk.
+    pub fn gen_block(&self, block: &mut BlockContext, data: &[Option<StageData>]) {
+        for (stage, data) in self.stages.iter().zip(data) {
+            stage.gen_block(block, data.as_deref());
+        }
+    }
+}
+
+/// Shapes the ground from `GenerationSettings::terrain`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DensityStage;
+
+impl GenerationStage for DensityStage {
+    fn name(&self) -> &'static str {
+        "density"
+    }
+
+    fn prepare(
+        &self,
+        pos: glam::IVec3,
+        settings: GenerationSettings,
+        chunk_dims: glam::UVec3,
+    ) -> Option<StageData> {
+        // We use dimensions of `chunk_dims + 1` because the corners on the last chunk
+        // block of each axis step outside of our 0..N bounds, sharing a value with the
+        // neighbouring chunk
+        let origin = pos * chunk_dims.as_ivec3();
+        let noise_dims = chunk_dims + glam::uvec3(1, 1, 1);
+        Some(Arc::new(generator::gen_terrain(
+            origin, settings, noise_dims,
+        )))
+    }
+
+    fn gen_block(&self, block: &mut BlockContext, data: Option<&(dyn Any + Send + Sync)>) {
+        let Some(noise) = data.and_then(|d| d.downcast_ref::<Vec<f32>>()) else {
+            return;
+        };
+        let corners = block.get_corners(noise);
+        // If all the corners are negative, then all the interpolated values will be
+        // negative too
+        if corners.iter().all(|c| *c < 0.0) {
+            block.density = [-1.0; 512];
+            return;
+        }
+        math::tri_lerp_block(&corners, &[8, 8, 8], &mut block.density);
+    }
+}
+
+/// Picks a biome for each voxel column from the climate.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SurfaceStage;
+
+impl GenerationStage for SurfaceStage {
+    fn name(&self) -> &'static str {
+        "surface"
+    }
+
+    fn prepare(
+        &self,
+        pos: glam::IVec3,
+        settings: GenerationSettings,
+        chunk_dims: glam::UVec3,
+    ) -> Option<StageData> {
+        let origin = pos * chunk_dims.as_ivec3();
+        let noise_dims = chunk_dims + glam::uvec3(1, 1, 1);
+        Some(Arc::new(biome::gen_climate(origin, settings, noise_dims)))
+    }
+
+    fn gen_block(&self, block: &mut BlockContext, data: Option<&(dyn Any + Send + Sync)>) {
+        let Some(climate) = data.and_then(|d| d.downcast_ref::<Vec<glam::Vec2>>()) else {
+            return;
+        };
+
+        // Biomes are picked per voxel column, blending the climate across the block
+        let climate_dims = glam::uvec2(block.chunk_dims.x + 1, block.chunk_dims.z + 1);
+        let climate_at = |x: u32, z: u32| {
+            let pos = glam::uvec2(block.block_pos.x + x, block.block_pos.z + z);
+            climate[(pos.x + pos.y * climate_dims.x) as usize]
+        };
+        let corners = [
+            climate_at(0, 0),
+            climate_at(1, 0),
+            climate_at(0, 1),
+            climate_at(1, 1),
+        ];
+        for (i, biome) in block.biomes.iter_mut().enumerate() {
+            let t = glam::vec2((i % 8) as f32, (i / 8) as f32) / 7.0;
+            let climate = corners[0]
+                .lerp(corners[1], t.x)
+                .lerp(corners[2].lerp(corners[3], t.x), t.y);
+            *biome = Biome::from_climate(climate);
+        }
+    }
+}
+
+/// Carves tunnels out of the ground, see `generator::gen_caves`. Does nothing when
+/// `GenerationSettings::cave_width` is 0.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CaveStage;
+
+impl GenerationStage for CaveStage {
+    fn name(&self) -> &'static str {
+        "caves"
+    }
+
+    fn prepare(
+        &self,
+        pos: glam::IVec3,
+        settings: GenerationSettings,
+        chunk_dims: glam::UVec3,
+    ) -> Option<StageData> {
+        if settings.cave_width <= 0.0 {
+            return None;
+        }
+        let origin = pos * chunk_dims.as_ivec3();
+        let noise_dims = chunk_dims + glam::uvec3(1, 1, 1);
+        Some(Arc::new(generator::gen_caves(origin, settings, noise_dims)))
+    }
+
+    fn gen_block(&self, block: &mut BlockContext, data: Option<&(dyn Any + Send + Sync)>) {
+        let Some(caves) = data.and_then(|d| d.downcast_ref::<Vec<f32>>()) else {
+            return;
+        };
+        // Nothing to carve out of air
+        if block.density.iter().all(|d| *d <= 0.0) {
+            return;
+        }
+
+        let mut tunnels = [0.0f32; 512];
+        math::tri_lerp_block(&block.get_corners(caves), &[8, 8, 8], &mut tunnels);
+        for (density, tunnel) in block.density.iter_mut().zip(tunnels) {
+            if tunnel < 0.0 {
+                *density = density.min(tunnel);
+            }
+        }
+    }
+}
+
+/// Stands trees and boulders on the ground, see `structure::gen_structures`. They're
+/// placed on the standard terrain and caves, whatever the stages before this made of
+/// them, and only fill voxels that are still air.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StructureStage;
+
+impl GenerationStage for StructureStage {
+    fn name(&self) -> &'static str {
+        "structures"
+    }
+
+    fn prepare(
+        &self,
+        pos: glam::IVec3,
+        settings: GenerationSettings,
+        chunk_dims: glam::UVec3,
+    ) -> Option<StageData> {
+        let structures = structure::gen_structures(pos, settings, chunk_dims);
+        (!structures.is_empty()).then(|| Arc::new(structures) as StageData)
+    }
+
+    fn gen_block(&self, block: &mut BlockContext, data: Option<&(dyn Any + Send + Sync)>) {
+        let Some(structures) = data.and_then(|d| d.downcast_ref::<StructureVoxels>()) else {
+            return;
+        };
+        let block_idx = math::to_1d_index(block.block_pos, block.chunk_dims);
+        for (voxel_idx, voxel) in structures.get(&block_idx).into_iter().flatten() {
+            if block.density[*voxel_idx] <= 0.0 && block.voxels[*voxel_idx] == Voxel::Empty {
+                block.voxels[*voxel_idx] = *voxel;
+            }
+        }
+    }
+}
+
+/// Fills the ground with materials, in layers by depth: the biome's surface, then its
+/// soil, then stone. Anything an earlier stage has already filled is left alone.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ColoringStage;
+
+impl GenerationStage for ColoringStage {
+    fn name(&self) -> &'static str {
+        "coloring"
+    }
+
+    fn gen_block(&self, block: &mut BlockContext, _data: Option<&(dyn Any + Send + Sync)>) {
+        for (i, voxel) in block.voxels.iter_mut().enumerate() {
+            let density = block.density[i];
+            if density <= 0.0 || *voxel != Voxel::Empty {
+                continue;
+            }
+            let biome = block.biomes[i % 8 + (i / 64) * 8];
+            let material = match density {
+                d if d < SURFACE_DEPTH => biome.get_surface(),
+                d if d < SOIL_DEPTH => biome.get_soil(),
+                _ => BuiltinMaterial::Stone,
+            };
+            *voxel = Voxel::Material(material.id());
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use std::{fmt::Write, time::Duration};
+
+use super::GenerationSettings;
+
+/// How long a chunk has spent in the generator, so slow generator settings can be found.
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkGenTiming {
+    pub chunk_pos: glam::IVec3,
+    /// The generator settings the chunk was generated with
+    pub settings: GenerationSettings,
+    /// Time spent generating the chunk wide noise
+    pub noise_time: Duration,
+    /// Total time spent generating the chunk's blocks
+    pub block_time: Duration,
+    pub blocks_generated: u32,
+    /// The slowest single block generated in the chunk
+    pub slowest_block: Duration,
+}
+
+impl ChunkGenTiming {
+    pub fn new(chunk_pos: glam::IVec3, settings: GenerationSettings, noise_time: Duration) -> Self {
+        Self {
+            chunk_pos,
+            settings,
+            noise_time,
+            block_time: Duration::ZERO,
+            blocks_generated: 0,
+            slowest_block: Duration::ZERO,
+        }
+    }
+
+    pub fn record_block(&mut self, time: Duration) {
+        self.block_time += time;
+        self.blocks_generated += 1;
+        self.slowest_block = self.slowest_block.max(time);
+    }
+
+    pub fn get_total_time(&self) -> Duration {
+        self.noise_time + self.block_time
+    }
+
+    pub fn get_average_block_time(&self) -> Duration {
+        if self.blocks_generated == 0 {
+            return Duration::ZERO;
+        }
+        self.block_time / self.blocks_generated
+    }
+}
+
+/// Formats a table of chunk timings, expected to already be sorted.
+pub fn format_report(timings: &[ChunkGenTiming]) -> String {
+    let mut report = format!("Slowest {} chunks:\n", timings.len());
+    for timing in timings {
+        // Writing to a String can't fail
+        let _ = writeln!(
+            report,
+            "  {}: total {:.2}ms, noise {:.2}ms, {} blocks at {:.1}us avg ({:.1}us max) \
+             with {:?}",
+            timing.chunk_pos,
+            timing.get_total_time().as_secs_f64() * 1000.0,
+            timing.noise_time.as_secs_f64() * 1000.0,
+            timing.blocks_generated,
+            timing.get_average_block_time().as_secs_f64() * 1_000_000.0,
+            timing.slowest_block.as_secs_f64() * 1_000_000.0,
+            timing.settings,
+        );
+    }
+    report
+}
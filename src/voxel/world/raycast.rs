@@ -0,0 +1,142 @@
+use super::{Voxel, WorldManager};
+
+#[derive(Debug, Clone, Copy)]
+pub struct RaycastHit {
+    /// World voxel space position of the voxel that was hit
+    pub position: glam::IVec3,
+    /// Normal of the voxel face the ray entered through
+    pub normal: glam::IVec3,
+    pub voxel: Voxel,
+    pub distance: f32,
+}
+
+/// Walks the cells of a grid in the order a ray passes through them. Positions and
+/// distances are in voxels, with cells `cell_size` voxels wide.
+#[derive(Debug)]
+struct GridWalk {
+    cell: glam::IVec3,
+    step: glam::IVec3,
+    delta: glam::Vec3,
+    side_dist: glam::Vec3,
+    /// Normal of the face the ray entered the current cell through
+    normal: glam::IVec3,
+    /// Distance along the ray to where it entered the current cell
+    distance: f32,
+}
+
+impl GridWalk {
+    fn new(
+        origin: glam::Vec3,
+        direction: glam::Vec3,
+        cell_size: f32,
+        cell: glam::IVec3,
+        normal: glam::IVec3,
+        distance: f32,
+    ) -> Self {
+        let step = direction.signum().as_ivec3();
+
+        // Distance along the ray to cross one cell on each axis, and to the first crossing
+        let delta = (cell_size / direction).abs();
+        let next_boundary = (cell + step.max(glam::IVec3::ZERO)).as_vec3() * cell_size;
+        let side_dist = (next_boundary - origin) / direction;
+        let side_dist = glam::Vec3::select(direction.cmpeq(glam::Vec3::ZERO), delta, side_dist);
+
+        Self {
+            cell,
+            step,
+            delta,
+            side_dist,
+            normal,
+            distance,
+        }
+    }
+
+    /// Moves into the next cell along whichever axis has the nearest boundary.
+    fn step(&mut self) {
+        let side_dist = self.side_dist;
+        let axis = if side_dist.x < side_dist.y && side_dist.x < side_dist.z {
+            0
+        } else if side_dist.y < side_dist.z {
+            1
+        } else {
+            2
+        };
+        self.distance = side_dist[axis];
+        self.side_dist[axis] += self.delta[axis];
+        self.cell[axis] += self.step[axis];
+        self.normal = glam::IVec3::ZERO;
+        self.normal[axis] = -self.step[axis];
+    }
+}
+
+impl WorldManager {
+    /// Casts a ray through world voxel space until it hits something solid or travels
+    /// `max_distance` voxels. `direction` doesn't need to be normalised.
+    ///
+    /// Runs entirely on the CPU against the world's chunks, so it works whether or not
+    /// anything is loaded on the GPU. The ray steps a block at a time and only walks
+    /// individual voxels inside blocks that aren't empty. Like `get_voxel`, any chunk the
+    /// ray passes through gets generated if it isn't loaded yet.
+    pub fn raycast(
+        &mut self,
+        origin: glam::Vec3,
+        direction: glam::Vec3,
+        max_distance: f32,
+    ) -> Option<RaycastHit> {
+        let direction = direction.try_normalize()?;
+        let block = (origin / 8.0).floor().as_ivec3();
+        let mut blocks = GridWalk::new(origin, direction, 8.0, block, glam::IVec3::ZERO, 0.0);
+
+        while blocks.distance <= max_distance {
+            if !self.is_block_empty(blocks.cell) {
+                if let Some(hit) = self.raycast_block(origin, direction, &blocks, max_distance) {
+                    return Some(hit);
+                }
+            }
+            blocks.step();
+        }
+
+        None
+    }
+
+    /// Walks the voxels of the block the ray is currently in.
+    fn raycast_block(
+        &mut self,
+        origin: glam::Vec3,
+        direction: glam::Vec3,
+        blocks: &GridWalk,
+        max_distance: f32,
+    ) -> Option<RaycastHit> {
+        // Floating point error can put the entry point just outside of the block
+        let min = blocks.cell * 8;
+        let max = min + 7;
+        let entry = origin + direction * blocks.distance;
+        let voxel = entry.floor().as_ivec3().clamp(min, max);
+        let mut voxels = GridWalk::new(
+            origin,
+            direction,
+            1.0,
+            voxel,
+            blocks.normal,
+            blocks.distance,
+        );
+
+        while voxels.distance <= max_distance
+            && voxels.cell.cmpge(min).all()
+            && voxels.cell.cmple(max).all()
+        {
+            let voxel = self.get_voxel(voxels.cell);
+            if voxel != Voxel::Empty {
+                return Some(RaycastHit {
+                    position: voxels.cell,
+                    normal: voxels.normal,
+                    voxel,
+                    distance: voxels.distance,
+                });
+            }
+            voxels.step();
+        }
+
+        None
+    }
+}
@@ -0,0 +1,485 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::math;
+
+use super::{MaterialTable, Voxel};
+
+/// The generated blocks of a chunk, as {block index, voxels}. Blocks that were never
+/// generated aren't stored, they get generated from noise as usual.
+pub type ChunkBlocks = Vec<(usize, Vec<Voxel>)>;
+
+const REGION_MAGIC: &[u8; 4] = b"VXRG";
+/// Version 2 stores material ids rather than colours
+const REGION_VERSION: u32 = 2;
+/// Stored voxels as RGB, they get turned into materials by `upgrade_regions`
+const LEGACY_REGION_VERSION: u32 = 1;
+
+/// Chunks per region along each axis
+const REGION_SIZE: i32 = 8;
+const REGION_CHUNKS: usize = (REGION_SIZE * REGION_SIZE * REGION_SIZE) as usize;
+
+/// Magic, version, then an {offset, length} table entry per chunk
+const HEADER_SIZE: usize = 8 + REGION_CHUNKS * 8;
+
+/// Stores chunks on disk in region files, each holding an 8x8x8 group of chunks. A region
+/// file is a header with the location of each chunk in the file, followed by the chunks.
+/// Chunk voxels are run length encoded, as they're mostly long stretches of empty space or
+/// solid ground.
+#[derive(Debug, Clone)]
+pub struct WorldStorage {
+    directory: PathBuf,
+}
+
+impl WorldStorage {
+    /// How many chunks a region file can hold
+    pub const CHUNKS_PER_REGION: usize = REGION_CHUNKS;
+
+    pub fn new(directory: &Path) -> Result<Self> {
+        fs::create_dir_all(directory)
+            .with_context(|| format!("Failed to create world directory {:?}", directory))?;
+        Ok(Self {
+            directory: directory.to_owned(),
+        })
+    }
+
+    pub fn get_directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Loads a chunk's blocks, or `None` if it was never saved.
+    pub fn load_chunk(&self, chunk_pos: glam::IVec3) -> Result<Option<ChunkBlocks>> {
+        let (region_pos, chunk_idx) = split_chunk_pos(chunk_pos);
+        let path = self.get_region_path(region_pos);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let mut file =
+            fs::File::open(&path).with_context(|| format!("Failed to open {:?}", path))?;
+        let mut header = vec![0u8; HEADER_SIZE];
+        file.read_exact(&mut header)?;
+        let (version, table) = parse_header(&header)?;
+        if version != REGION_VERSION {
+            bail!(
+                "Region {:?} is version {} and needs upgrading",
+                path,
+                version
+            );
+        }
+        let (offset, length) = table[chunk_idx];
+        if length == 0 {
+            return Ok(None);
+        }
+
+        let mut data = vec![0u8; length as usize];
+        file.seek(SeekFrom::Start(offset as u64))?;
+        file.read_exact(&mut data)?;
+        decode_chunk(&data, decode_voxel)
+            .map(Some)
+            .with_context(|| format!("Corrupt chunk {} in {:?}", chunk_pos, path))
+    }
+
+    /// Positions of every region saved in the directory.
+    pub fn get_region_positions(&self) -> Result<Vec<glam::IVec3>> {
+        let entries = fs::read_dir(&self.directory)
+            .with_context(|| format!("Failed to list {:?}", self.directory))?;
+
+        let mut positions = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|e| e != "region") {
+                continue;
+            }
+            match path
+                .file_stem()
+                .and_then(|s| parse_region_name(s.to_str()?))
+            {
+                Some(pos) => positions.push(pos),
+                None => log::warn!("Skipping oddly named region {:?}", path),
+            }
+        }
+        Ok(positions)
+    }
+
+    /// Loads every chunk saved in a region along with its position.
+    pub fn load_region(&self, region_pos: glam::IVec3) -> Result<Vec<(glam::IVec3, ChunkBlocks)>> {
+        let path = self.get_region_path(region_pos);
+        let (version, payloads) = read_region(&path)?;
+        if version != REGION_VERSION {
+            bail!(
+                "Region {:?} is version {} and needs upgrading",
+                path,
+                version
+            );
+        }
+
+        let region_dims = glam::UVec3::splat(REGION_SIZE as u32);
+        payloads
+            .iter()
+            .enumerate()
+            .filter(|(_, payload)| !payload.is_empty())
+            .map(|(chunk_idx, payload)| {
+                let local_pos = math::to_3d_index(chunk_idx, region_dims).as_ivec3();
+                let chunk_pos = region_pos * REGION_SIZE + local_pos;
+                let blocks = decode_chunk(payload, decode_voxel)
+                    .with_context(|| format!("Corrupt chunk {} in {:?}", chunk_pos, path))?;
+                Ok((chunk_pos, blocks))
+            })
+            .collect()
+    }
+
+    /// Writes chunks to disk, rewriting each region they're in. Returns how many regions
+    /// were written.
+    pub fn save_chunks(&self, chunks: &[(glam::IVec3, ChunkBlocks)]) -> Result<usize> {
+        let mut regions: HashMap<glam::IVec3, Vec<(usize, &ChunkBlocks)>> = HashMap::new();
+        for (chunk_pos, blocks) in chunks {
+            let (region_pos, chunk_idx) = split_chunk_pos(*chunk_pos);
+            regions
+                .entry(region_pos)
+                .or_default()
+                .push((chunk_idx, blocks));
+        }
+
+        for (region_pos, region_chunks) in &regions {
+            let path = self.get_region_path(*region_pos);
+            let mut payloads = match path.exists() {
+                true => match read_region(&path)? {
+                    (REGION_VERSION, payloads) => payloads,
+                    (version, _) => bail!("Can't save into version {} region {:?}", version, path),
+                },
+                false => vec![vec![]; REGION_CHUNKS],
+            };
+            for (chunk_idx, blocks) in region_chunks {
+                payloads[*chunk_idx] = encode_chunk(blocks);
+            }
+            write_region(&path, &payloads)?;
+        }
+
+        Ok(regions.len())
+    }
+
+    /// Rewrites any regions saved before voxels had materials, adding a material to
+    /// `materials` for each colour they used. Returns how many regions were upgraded.
+    pub fn upgrade_regions(&self, materials: &mut MaterialTable) -> Result<usize> {
+        let entries = fs::read_dir(&self.directory)
+            .with_context(|| format!("Failed to list {:?}", self.directory))?;
+
+        let mut upgraded = 0;
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|e| e != "region") {
+                continue;
+            }
+            let (version, payloads) = read_region(&path)?;
+            if version != LEGACY_REGION_VERSION {
+                continue;
+            }
+
+            let mut decode = |value: u32| match value & 0xFF {
+                0 => Voxel::Empty,
+                _ => Voxel::Material(materials.find_or_add_color(
+                    (value >> 24) as u8,
+                    (value >> 16) as u8,
+                    (value >> 8) as u8,
+                )),
+            };
+            let payloads = payloads
+                .iter()
+                .map(|payload| match payload.is_empty() {
+                    true => Ok(vec![]),
+                    false => Ok(encode_chunk(&decode_chunk(payload, &mut decode)?)),
+                })
+                .collect::<Result<Vec<_>>>()
+                .with_context(|| format!("Failed to upgrade {:?}", path))?;
+            write_region(&path, &payloads)?;
+            upgraded += 1;
+        }
+        Ok(upgraded)
+    }
+
+    fn get_region_path(&self, region_pos: glam::IVec3) -> PathBuf {
+        self.directory.join(format!(
+            "r.{}.{}.{}.region",
+            region_pos.x, region_pos.y, region_pos.z
+        ))
+    }
+}
+
+/// Reads a region's position back out of its file name, minus the extension.
+fn parse_region_name(name: &str) -> Option<glam::IVec3> {
+    let mut parts = name.strip_prefix("r.")?.split('.');
+    let mut next = || parts.next()?.parse().ok();
+    let pos = glam::ivec3(next()?, next()?, next()?);
+    parts.next().is_none().then_some(pos)
+}
+
+/// Splits a chunk position into the region it's in and its index within the region.
+fn split_chunk_pos(chunk_pos: glam::IVec3) -> (glam::IVec3, usize) {
+    let region_dims = glam::IVec3::splat(REGION_SIZE);
+    let region_pos = chunk_pos.div_euclid(region_dims);
+    let local_pos = chunk_pos.rem_euclid(region_dims).as_uvec3();
+    let chunk_idx = math::to_1d_index(local_pos, region_dims.as_uvec3());
+    (region_pos, chunk_idx)
+}
+
+/// Returns the region's version along with its table.
+fn parse_header(header: &[u8]) -> Result<(u32, Vec<(u32, u32)>)> {
+    if &header[0..4] != REGION_MAGIC {
+        bail!("Not a region file");
+    }
+    let version = read_u32(header, 4);
+    if version != REGION_VERSION && version != LEGACY_REGION_VERSION {
+        bail!("Unsupported region version {}", version);
+    }
+
+    let table = (0..REGION_CHUNKS)
+        .map(|i| (read_u32(header, 8 + i * 8), read_u32(header, 12 + i * 8)))
+        .collect();
+    Ok((version, table))
+}
+
+/// Reads the region's version and the encoded payload of every chunk in it, empty for
+/// missing chunks.
+fn read_region(path: &Path) -> Result<(u32, Vec<Vec<u8>>)> {
+    let data = fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+    if data.len() < HEADER_SIZE {
+        bail!("Truncated region file {:?}", path);
+    }
+
+    let (version, table) = parse_header(&data[..HEADER_SIZE])?;
+    let payloads = table
+        .iter()
+        .map(|&(offset, length)| {
+            let payload = offset
+                .checked_add(length)
+                .and_then(|end| data.get(offset as usize..end as usize));
+            match payload {
+                Some(payload) => Ok(payload.to_vec()),
+                None => bail!("Chunk outside of region file {:?}", path),
+            }
+        })
+        .collect::<Result<_>>()?;
+    Ok((version, payloads))
+}
+
+fn write_region(path: &Path, payloads: &[Vec<u8>]) -> Result<()> {
+    let mut data = Vec::with_capacity(HEADER_SIZE);
+    data.extend_from_slice(REGION_MAGIC);
+    data.extend_from_slice(&REGION_VERSION.to_le_bytes());
+
+    let mut offset = HEADER_SIZE as u32;
+    for payload in payloads {
+        let length = payload.len() as u32;
+        let entry_offset = if length == 0 { 0 } else { offset };
+        data.extend_from_slice(&entry_offset.to_le_bytes());
+        data.extend_from_slice(&length.to_le_bytes());
+        offset += length;
+    }
+    for payload in payloads {
+        data.extend_from_slice(payload);
+    }
+
+    // Write to a temporary file first so a crash mid-save can't corrupt the region
+    let temp_path = path.with_extension("region.tmp");
+    fs::write(&temp_path, &data).with_context(|| format!("Failed to write {:?}", temp_path))?;
+    fs::rename(&temp_path, path).with_context(|| format!("Failed to replace {:?}", path))?;
+    Ok(())
+}
+
+fn encode_voxel(voxel: Voxel) -> u32 {
+    match voxel {
+        Voxel::Empty => 0,
+        Voxel::Material(id) => ((id as u32) << 8) | 1,
+    }
+}
+
+fn decode_voxel(value: u32) -> Voxel {
+    match value & 0xFF {
+        0 => Voxel::Empty,
+        _ => Voxel::Material((value >> 8) as u16),
+    }
+}
+
+/// {block count, blocks[]}, where each block is {block index, run count, runs[]} and each
+/// run is {length, voxel}
+fn encode_chunk(blocks: &ChunkBlocks) -> Vec<u8> {
+    let mut words = vec![blocks.len() as u32];
+    for (block_idx, voxels) in blocks {
+        let mut runs: Vec<(u32, u32)> = vec![];
+        for voxel in voxels {
+            let value = encode_voxel(*voxel);
+            match runs.last_mut() {
+                Some((length, last)) if *last == value => *length += 1,
+                _ => runs.push((1, value)),
+            }
+        }
+
+        words.push(*block_idx as u32);
+        words.push(runs.len() as u32);
+        for (length, value) in runs {
+            words.push(length);
+            words.push(value);
+        }
+    }
+
+    words.iter().flat_map(|w| w.to_le_bytes()).collect()
+}
+
+fn decode_chunk(data: &[u8], mut decode_voxel: impl FnMut(u32) -> Voxel) -> Result<ChunkBlocks> {
+    if !data.len().is_multiple_of(4) {
+        bail!("Chunk length isn't a whole number of words");
+    }
+    let word_count = data.len() / 4;
+    let mut words = (0..word_count).map(|i| read_u32(data, i * 4));
+    let mut next = || words.next().context("Unexpected end of chunk");
+
+    // Every block takes at least its index and run count, so a corrupt count can't make
+    // us allocate more than the chunk could possibly hold
+    let block_count = next()? as usize;
+    if block_count > (word_count - 1) / 2 {
+        bail!(
+            "Chunk has {} blocks but only {} words",
+            block_count,
+            word_count
+        );
+    }
+    let mut blocks = Vec::with_capacity(block_count);
+    for _ in 0..block_count {
+        let block_idx = next()? as usize;
+        let run_count = next()?;
+        let mut voxels = Vec::with_capacity(512);
+        for _ in 0..run_count {
+            let length = next()? as usize;
+            if voxels.len() + length > 512 {
+                bail!("Block {} has more than 512 voxels", block_idx);
+            }
+            let voxel = decode_voxel(next()?);
+            voxels.extend(std::iter::repeat_n(voxel, length));
+        }
+        if voxels.len() != 512 {
+            bail!("Block {} has {} voxels", block_idx, voxels.len());
+        }
+        blocks.push((block_idx, voxels));
+    }
+    Ok(blocks)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_bytes(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn chunk_round_trips() {
+        let mut mixed = vec![Voxel::Empty; 512];
+        mixed[0] = Voxel::Material(3);
+        mixed[200..300].fill(Voxel::Material(1000));
+        mixed[511] = Voxel::Material(u16::MAX);
+        let blocks: ChunkBlocks = vec![
+            (0, vec![Voxel::Empty; 512]),
+            (7, mixed),
+            (32767, vec![Voxel::Material(2); 512]),
+        ];
+
+        let decoded = decode_chunk(&encode_chunk(&blocks), decode_voxel).unwrap();
+        assert_eq!(decoded, blocks);
+        assert!(decode_chunk(&encode_chunk(&vec![]), decode_voxel)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn chunk_with_too_many_voxels_is_rejected() {
+        // One block with runs of 500 and 500 voxels
+        let data = to_bytes(&[1, 0, 2, 500, 1, 500, 1]);
+        assert!(decode_chunk(&data, decode_voxel).is_err());
+
+        // A single run long enough to exhaust memory if it were trusted
+        let data = to_bytes(&[1, 0, 1, u32::MAX, 1]);
+        assert!(decode_chunk(&data, decode_voxel).is_err());
+    }
+
+    #[test]
+    fn chunk_with_too_few_voxels_is_rejected() {
+        let data = to_bytes(&[1, 0, 1, 511, 1]);
+        assert!(decode_chunk(&data, decode_voxel).is_err());
+    }
+
+    #[test]
+    fn chunk_block_count_is_capped_by_length() {
+        let data = to_bytes(&[u32::MAX, 0, 1, 512, 0]);
+        assert!(decode_chunk(&data, decode_voxel).is_err());
+        assert!(decode_chunk(&[1, 2, 3], decode_voxel).is_err());
+    }
+
+    #[test]
+    fn region_name_round_trips() {
+        let storage = WorldStorage {
+            directory: PathBuf::new(),
+        };
+        for pos in [
+            glam::IVec3::ZERO,
+            glam::ivec3(1, -2, 3),
+            glam::IVec3::splat(i32::MIN),
+        ] {
+            let path = storage.get_region_path(pos);
+            let name = path.file_stem().unwrap().to_str().unwrap();
+            assert_eq!(parse_region_name(name), Some(pos));
+        }
+    }
+
+    #[test]
+    fn bad_region_names_are_rejected() {
+        for name in ["", "r.1.2", "r.1.2.3.4", "x.1.2.3", "r.1.a.3", "r..2.3"] {
+            assert_eq!(parse_region_name(name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn chunk_positions_split_into_regions() {
+        assert_eq!(split_chunk_pos(glam::IVec3::ZERO), (glam::IVec3::ZERO, 0));
+        assert_eq!(
+            split_chunk_pos(glam::ivec3(1, 0, 0)),
+            (glam::IVec3::ZERO, 1)
+        );
+        assert_eq!(
+            split_chunk_pos(glam::ivec3(0, 0, 1)),
+            (glam::IVec3::ZERO, (REGION_SIZE * REGION_SIZE) as usize)
+        );
+        assert_eq!(
+            split_chunk_pos(glam::ivec3(-1, 8, 17)),
+            (glam::ivec3(-1, 1, 2), 7 + 64)
+        );
+
+        // Every chunk in a region gets its own index
+        let mut seen = vec![false; REGION_CHUNKS];
+        for z in -8..0 {
+            for y in 16..24 {
+                for x in 8..16 {
+                    let (region_pos, chunk_idx) = split_chunk_pos(glam::ivec3(x, y, z));
+                    assert_eq!(region_pos, glam::ivec3(1, 2, -1));
+                    assert!(!std::mem::replace(&mut seen[chunk_idx], true));
+                }
+            }
+        }
+    }
+}
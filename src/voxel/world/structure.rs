@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+
+use crate::math;
+
+use super::{biome, generator, Biome, BuiltinMaterial, GenerationSettings, Voxel};
+
+/// The world is split into cells this many blocks across, each with at most one structure
+const CELL_BLOCKS: i32 = 4;
+/// How far a structure can reach sideways from its anchor, in voxels. Chunks look this far
+/// past their edges for structures that spill into them
+const MAX_RADIUS: i32 = 10;
+/// How far a structure can reach above its anchor, in voxels
+const MAX_HEIGHT: i32 = 33;
+/// How far a structure can sink below its anchor, in voxels
+const MAX_DEPTH: i32 = 5;
+
+/// Every voxel structures add to a chunk, as voxel indices by block index. They're only
+/// stamped over empty voxels, once the block's terrain has been generated.
+pub type StructureVoxels = HashMap<usize, Vec<(usize, Voxel)>>;
+
+/// Small prefabs scattered over the ground after the terrain is generated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Structure {
+    Tree,
+    /// A snow capped conifer, for the cold
+    Pine,
+    Boulder,
+}
+
+impl Structure {
+    /// Picks what, if anything, stands in a cell of a biome from a roll in `0.0..1.0`.
+    pub fn pick(biome: Biome, roll: f32) -> Option<Self> {
+        let chances: &[(Self, f32)] = match biome {
+            Biome::Grassland => &[(Self::Tree, 0.45), (Self::Boulder, 0.08)],
+            Biome::Tundra => &[(Self::Pine, 0.25), (Self::Boulder, 0.08)],
+            Biome::Rocky => &[(Self::Boulder, 0.35)],
+            Biome::Desert => &[(Self::Boulder, 0.05)],
+        };
+
+        let mut total = 0.0;
+        for (structure, chance) in chances {
+            total += chance;
+            if roll < total {
+                return Some(*structure);
+            }
+        }
+        None
+    }
+
+    /// The structure's voxels as offsets from its anchor, the first empty voxel above
+    /// the ground. `variant` picks its size and shape, the same variant always giving
+    /// the same voxels.
+    pub fn gen_voxels(&self, variant: i32) -> Vec<(glam::IVec3, BuiltinMaterial)> {
+        let roll = |i: i32| hash_unit(glam::ivec3(i, 0, 0), variant);
+        let mut voxels = Vec::new();
+
+        match self {
+            Self::Tree => {
+                let trunk_height = 14 + (roll(0) * 10.0) as i32;
+                gen_trunk(trunk_height, &mut voxels);
+
+                // A lumpy, slightly squashed ball of leaves around the top of the trunk
+                let radius = 5.0 + roll(1) * 3.0;
+                let centre = glam::vec3(1.0, trunk_height as f32, 1.0);
+                let reach = radius.ceil() as i32;
+                for p in box_iter(glam::IVec3::splat(-reach), glam::IVec3::splat(reach + 2)) {
+                    let p = p + glam::ivec3(0, trunk_height, 0);
+                    let d = (p.as_vec3() + 0.5 - centre) * glam::vec3(1.0, 1.25, 1.0);
+                    if d.length() <= radius * (0.8 + 0.2 * hash_unit(p, variant)) {
+                        voxels.push((p, BuiltinMaterial::Grass));
+                    }
+                }
+            }
+            Self::Pine => {
+                let trunk_height = 20 + (roll(0) * 8.0) as i32;
+                gen_trunk(trunk_height, &mut voxels);
+
+                // Tiers of needles narrowing to a point, with snow on whatever faces up
+                let radius = 5.0 + roll(1) * 2.0;
+                let (bottom, top) = (6, trunk_height + 4);
+                let in_cone = |p: glam::IVec3| {
+                    if p.y < bottom || p.y >= top {
+                        return false;
+                    }
+                    let t = (p.y - bottom) as f32 / (top - bottom) as f32;
+                    let tier = if (p.y - bottom) % 4 < 2 { 1.0 } else { 0.7 };
+                    let d = glam::vec2(p.x as f32, p.z as f32) + 0.5 - 1.0;
+                    d.length() <= radius * (1.0 - t) * tier + 0.5
+                };
+                let reach = radius.ceil() as i32 + 1;
+                let min = glam::ivec3(-reach, bottom, -reach);
+                let max = glam::ivec3(reach + 2, top, reach + 2);
+                for p in box_iter(min, max).filter(|p| in_cone(*p)) {
+                    let material = match in_cone(p + glam::IVec3::Y) {
+                        true => BuiltinMaterial::Grass,
+                        false => BuiltinMaterial::Snow,
+                    };
+                    voxels.push((p, material));
+                }
+            }
+            Self::Boulder => {
+                // Partly buried so it sits in the ground rather than on it
+                let radius = 3.0 + roll(0) * 4.0;
+                let centre = glam::vec3(0.5, radius * 0.3, 0.5);
+                let reach = radius.ceil() as i32;
+                for p in box_iter(glam::IVec3::splat(-reach), glam::IVec3::splat(reach + 1)) {
+                    let d = (p.as_vec3() + 0.5 - centre) * glam::vec3(1.0, 1.25, 1.0);
+                    if d.length() <= radius * (0.85 + 0.15 * hash_unit(p, variant)) {
+                        voxels.push((p, BuiltinMaterial::Stone));
+                    }
+                }
+            }
+        }
+
+        // Anything further out could be missed by the chunks next door
+        voxels.retain(|(p, _)| {
+            p.x.abs() <= MAX_RADIUS
+                && p.z.abs() <= MAX_RADIUS
+                && (-MAX_DEPTH..=MAX_HEIGHT).contains(&p.y)
+        });
+        voxels
+    }
+}
+
+/// A structure standing somewhere in the world.
+#[derive(Debug, Clone, Copy)]
+struct Placement {
+    structure: Structure,
+    /// World voxel position of the first empty voxel above the ground
+    anchor: glam::IVec3,
+    variant: i32,
+}
+
+/// The voxels of every structure reaching into a chunk. Placement only depends on the
+/// seed and the terrain, never on which chunks exist, so a structure spilling over a
+/// chunk boundary is cut the same way from both sides.
+pub fn gen_structures(
+    pos: glam::IVec3,
+    settings: GenerationSettings,
+    chunk_dims: glam::UVec3,
+) -> StructureVoxels {
+    let min = pos * chunk_dims.as_ivec3() * 8;
+    let max = min + chunk_dims.as_ivec3() * 8;
+
+    let mut structures = StructureVoxels::new();
+    for placement in plan_structures(min, max, settings) {
+        for (offset, material) in placement.structure.gen_voxels(placement.variant) {
+            let p = placement.anchor + offset;
+            if p.cmplt(min).any() || p.cmpge(max).any() {
+                continue;
+            }
+            let local = (p - min).as_uvec3();
+            let block_idx = math::to_1d_index(local / 8, chunk_dims);
+            let voxel_idx = math::to_1d_index(local % 8, glam::uvec3(8, 8, 8));
+            structures
+                .entry(block_idx)
+                .or_default()
+                .push((voxel_idx, Voxel::Material(material.id())));
+        }
+    }
+    structures
+}
+
+/// Finds every structure that could reach into the voxels in `min..max`. Each cell rolls
+/// for a structure and picks a block corner in itself to put it on, then the structure
+/// stands on every piece of open ground in that column. Block corners are where the
+/// terrain's voxels are exactly its noise, so the ground found here is always the ground
+/// the chunk generates.
+fn plan_structures(
+    min: glam::IVec3,
+    max: glam::IVec3,
+    settings: GenerationSettings,
+) -> Vec<Placement> {
+    let mut placements = Vec::new();
+    if settings.structure_density <= 0.0 {
+        return placements;
+    }
+
+    let seed = settings.seed.wrapping_add(5);
+    let cell_voxels = CELL_BLOCKS * 8;
+    let cell_min = (min - MAX_RADIUS).div_euclid(glam::IVec3::splat(cell_voxels));
+    let cell_max = (max + MAX_RADIUS - 1).div_euclid(glam::IVec3::splat(cell_voxels));
+
+    // Anchors in here can reach the chunk, the ground under them is one voxel lower
+    let (lo, hi) = (min.y - MAX_HEIGHT, max.y + MAX_DEPTH);
+    let (first_block, last_block) = ((lo - 1).div_euclid(8), (hi - 1).div_euclid(8));
+    let column_dims = glam::uvec3(1, (last_block - first_block + 2) as u32, 1);
+
+    for cz in cell_min.z..=cell_max.z {
+        for cx in cell_min.x..=cell_max.x {
+            let cell = glam::ivec3(cx, 0, cz);
+            let h = hash(cell, seed) as i32;
+            let offset = glam::ivec3(
+                h.rem_euclid(CELL_BLOCKS),
+                0,
+                (h >> 8).rem_euclid(CELL_BLOCKS),
+            );
+            let block = cell * CELL_BLOCKS + offset;
+            let anchor = block * 8;
+            if anchor.x < min.x - MAX_RADIUS
+                || anchor.x >= max.x + MAX_RADIUS
+                || anchor.z < min.z - MAX_RADIUS
+                || anchor.z >= max.z + MAX_RADIUS
+            {
+                continue;
+            }
+
+            let climate = biome::gen_climate(block, settings, glam::UVec3::ONE)[0];
+            let roll = hash_unit(cell + glam::IVec3::Y, seed) / settings.structure_density;
+            let Some(structure) = Structure::pick(Biome::from_climate(climate), roll) else {
+                continue;
+            };
+
+            let column_origin = glam::ivec3(block.x, first_block, block.z);
+            let density = generator::gen_terrain(column_origin, settings, column_dims);
+            let caves = match settings.cave_width > 0.0 {
+                true => generator::gen_caves(column_origin, settings, column_dims),
+                false => vec![],
+            };
+            // The same interpolation a block does, at the corner of its voxel column
+            let value = |values: &[f32], y: i32| {
+                let b = (y.div_euclid(8) - first_block) as usize;
+                let d = y.rem_euclid(8) as f32 / 7.0;
+                values[b] + (values[b + 1] - values[b]) * d
+            };
+            let is_solid =
+                |y: i32| value(&density, y) > 0.0 && (caves.is_empty() || value(&caves, y) >= 0.0);
+
+            let mut below = is_solid(lo - 1);
+            for y in lo..hi {
+                let solid = is_solid(y);
+                // Open sky above the ground, rather than a tunnel floor
+                if below && value(&density, y) <= 0.0 {
+                    placements.push(Placement {
+                        structure,
+                        anchor: glam::ivec3(anchor.x, y, anchor.z),
+                        variant: hash(glam::ivec3(cx, y, cz), seed.wrapping_add(1)) as i32,
+                    });
+                }
+                below = solid;
+            }
+        }
+    }
+    placements
+}
+
+/// A 2x2 voxel trunk going straight up from the anchor.
+fn gen_trunk(height: i32, voxels: &mut Vec<(glam::IVec3, BuiltinMaterial)>) {
+    for p in box_iter(glam::IVec3::ZERO, glam::ivec3(2, height, 2)) {
+        voxels.push((p, BuiltinMaterial::Dirt));
+    }
+}
+
+/// Every position in `min..max`, x first then y then z.
+fn box_iter(min: glam::IVec3, max: glam::IVec3) -> impl Iterator<Item = glam::IVec3> {
+    (min.z..max.z).flat_map(move |z| {
+        (min.y..max.y).flat_map(move |y| (min.x..max.x).map(move |x| glam::ivec3(x, y, z)))
+    })
+}
+
+/// Hashes a position, so the same seed always grows the same structures in the same
+/// places.
+fn hash(pos: glam::IVec3, seed: i32) -> u32 {
+    let mut h = (pos.x as u32).wrapping_mul(0x8DA6B343)
+        ^ (pos.y as u32).wrapping_mul(0xD8163841)
+        ^ (pos.z as u32).wrapping_mul(0xCB1AB31F)
+        ^ (seed as u32).wrapping_mul(0x165667B1);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x2C1B3C6D);
+    h ^= h >> 12;
+    h
+}
+
+/// `hash` mapped to `0.0..=1.0`.
+fn hash_unit(pos: glam::IVec3, seed: i32) -> f32 {
+    hash(pos, seed) as f32 / u32::MAX as f32
+}